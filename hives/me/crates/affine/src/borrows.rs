@@ -0,0 +1,435 @@
+// Borrow checking
+// A borrow lasts while a live binding holds it: its place is not written, moved
+// or borrowed in a conflicting way meanwhile, and it does not outlive the binding
+// it borrows from or the lifetimes the function signature promises
+
+use crate::cfg::{Cfg, Event, LoanId, Origin, Place};
+use my_lang_ast::Span;
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_resolve::DefId;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+
+type Live = BTreeSet<DefId>;
+
+/// Borrow facts at one program point, each true on at least one path to it
+#[derive(Debug, Clone, Default, PartialEq)]
+struct State {
+    /// References each binding may hold: loans, and the parameters they came in through
+    holds: BTreeMap<DefId, BTreeSet<Origin>>,
+    /// Loans made in the current statement that no binding holds
+    temps: BTreeSet<LoanId>,
+}
+
+impl State {
+    /// Merge facts from another path, returning whether anything changed
+    fn join(&mut self, other: &State) -> bool {
+        let before = self.clone();
+        for (local, origins) in &other.holds {
+            self.holds.entry(*local).or_default().extend(origins);
+        }
+        self.temps.extend(&other.temps);
+        *self != before
+    }
+
+    /// The loans and parameters the references from `origins` point into
+    fn resolve(&self, origins: &[Origin]) -> BTreeSet<Origin> {
+        let mut resolved = BTreeSet::new();
+        for origin in origins {
+            match origin {
+                Origin::Loan(_) => {
+                    resolved.insert(*origin);
+                }
+                Origin::Local(local) => resolved.extend(self.holds.get(local).into_iter().flatten()),
+            }
+        }
+        resolved
+    }
+
+    /// Loans still in use after a point where `live` are the live bindings,
+    /// with a binding holding each
+    fn live_loans(&self, live: &Live) -> Vec<(LoanId, Option<DefId>)> {
+        let mut loans: Vec<(LoanId, Option<DefId>)> = self.temps.iter().map(|&loan| (loan, None)).collect();
+        for local in live {
+            for origin in self.holds.get(local).into_iter().flatten() {
+                if let Origin::Loan(loan) = origin {
+                    loans.push((*loan, Some(*local)));
+                }
+            }
+        }
+        loans
+    }
+}
+
+pub fn check(cfg: &Cfg, diags: &mut Vec<Diagnostic>) {
+    let live_in = liveness(cfg);
+    let states = solve(cfg);
+    let mut reporter = Reporter { cfg, diags, seen: HashSet::new() };
+    for node in cfg.graph.node_indices() {
+        let Some(state) = &states[node.index()] else {
+            continue;
+        };
+        let mut state = state.clone();
+        let live_after = live_after_each(cfg, node, &live_in);
+        for (event, live) in cfg.graph[node].events.iter().zip(&live_after) {
+            reporter.event(&state, event, live);
+            transfer(&mut state, event);
+        }
+    }
+    reporter.shared_mutations();
+}
+
+// ========== Liveness ==========
+
+/// Bindings live on entry to each block: read on some path before being reassigned
+fn liveness(cfg: &Cfg) -> Vec<Live> {
+    let mut live_in = vec![Live::new(); cfg.graph.node_count()];
+    let mut worklist: VecDeque<NodeIndex> = cfg.graph.node_indices().collect();
+    while let Some(node) = worklist.pop_front() {
+        let mut live = live_out(cfg, node, &live_in);
+        for event in cfg.graph[node].events.iter().rev() {
+            step_back(cfg, &mut live, event);
+        }
+        if live != live_in[node.index()] {
+            live_in[node.index()] = live;
+            for pred in cfg.graph.neighbors_directed(node, Direction::Incoming) {
+                if !worklist.contains(&pred) {
+                    worklist.push_back(pred);
+                }
+            }
+        }
+    }
+    live_in
+}
+
+fn live_out(cfg: &Cfg, node: NodeIndex, live_in: &[Live]) -> Live {
+    cfg.graph.edges(node).flat_map(|edge| live_in[edge.target().index()].iter().copied()).collect()
+}
+
+/// Bindings live just after each event of a block
+fn live_after_each(cfg: &Cfg, node: NodeIndex, live_in: &[Live]) -> Vec<Live> {
+    let events = &cfg.graph[node].events;
+    let mut live = live_out(cfg, node, live_in);
+    let mut after = vec![Live::new(); events.len()];
+    for (i, event) in events.iter().enumerate().rev() {
+        after[i] = live.clone();
+        step_back(cfg, &mut live, event);
+    }
+    after
+}
+
+fn step_back(cfg: &Cfg, live: &mut Live, event: &Event) {
+    let read = |live: &mut Live, origins: &[Origin]| {
+        live.extend(origins.iter().filter_map(|origin| match origin {
+            Origin::Local(local) => Some(*local),
+            Origin::Loan(_) => None,
+        }))
+    };
+    match event {
+        Event::Move(place, _) | Event::Use(place, _) | Event::Release(place, _) => {
+            live.insert(place.local);
+        }
+        // Writing a whole binding does not read it
+        Event::Init(place, _) | Event::Write(place, _) => {
+            if !place.projection.is_empty() {
+                live.insert(place.local);
+            }
+        }
+        Event::Borrow(loan) => {
+            live.insert(cfg.loans[*loan].place.local);
+        }
+        Event::Store { local, origins, replace } => {
+            if *replace {
+                live.remove(local);
+            }
+            read(live, origins);
+        }
+        Event::Return(origins, _) | Event::Scope { result: origins, .. } => read(live, origins),
+        Event::Expire(_) | Event::EndStatement => {}
+    }
+}
+
+// ========== Borrows ==========
+
+/// State on entry to every block reachable from the entry, iterated to a fixpoint
+fn solve(cfg: &Cfg) -> Vec<Option<State>> {
+    let mut entry = State::default();
+    for (def, local) in &cfg.locals {
+        if local.is_param {
+            entry.holds.insert(*def, BTreeSet::from([Origin::Local(*def)]));
+        }
+    }
+    let mut states: Vec<Option<State>> = vec![None; cfg.graph.node_count()];
+    states[cfg.entry.index()] = Some(entry);
+    let mut worklist: VecDeque<NodeIndex> = VecDeque::from([cfg.entry]);
+    while let Some(node) = worklist.pop_front() {
+        let Some(mut state) = states[node.index()].clone() else {
+            continue;
+        };
+        for event in &cfg.graph[node].events {
+            transfer(&mut state, event);
+        }
+        for edge in cfg.graph.edges(node) {
+            let target = edge.target();
+            let changed = match &mut states[target.index()] {
+                Some(existing) => existing.join(&state),
+                slot @ None => {
+                    *slot = Some(state.clone());
+                    true
+                }
+            };
+            if changed && !worklist.contains(&target) {
+                worklist.push_back(target);
+            }
+        }
+    }
+    states
+}
+
+fn transfer(state: &mut State, event: &Event) {
+    match event {
+        Event::Borrow(loan) => {
+            state.temps.insert(*loan);
+        }
+        Event::Expire(loans) => {
+            for loan in loans {
+                state.temps.remove(loan);
+            }
+        }
+        Event::Store { local, origins, replace } => {
+            let resolved = state.resolve(origins);
+            if *replace {
+                state.holds.insert(*local, resolved);
+            } else {
+                state.holds.entry(*local).or_default().extend(resolved);
+            }
+        }
+        Event::EndStatement => state.temps.clear(),
+        _ => {}
+    }
+}
+
+struct Reporter<'c, 'd> {
+    cfg: &'c Cfg,
+    diags: &'d mut Vec<Diagnostic>,
+    /// Spans already reported, so each conflict is reported once
+    seen: HashSet<Span>,
+}
+
+impl Reporter<'_, '_> {
+    fn name(&self, place: &Place) -> String {
+        self.cfg.locals[&place.local].display(&place.projection)
+    }
+
+    fn event(&mut self, state: &State, event: &Event, live: &Live) {
+        match event {
+            Event::Borrow(loan) => {
+                let new = &self.cfg.loans[*loan];
+                let conflict = state.live_loans(live).into_iter().find(|&(old, _)| {
+                    let old = &self.cfg.loans[old];
+                    (new.mutable || old.mutable) && old.place.overlaps(&new.place)
+                });
+                if let Some((old, holder)) = conflict {
+                    self.conflicting_borrow(*loan, old, holder);
+                }
+            }
+            Event::Use(place, span) | Event::Release(place, span) => {
+                let conflict = state.live_loans(live).into_iter().find(|&(loan, _)| {
+                    let loan = &self.cfg.loans[loan];
+                    loan.mutable && loan.place.overlaps(place)
+                });
+                if let Some((loan, holder)) = conflict {
+                    let message = format!("cannot use `{}` because it was mutably borrowed", self.name(place));
+                    self.borrowed(message, *span, "use of borrowed value", loan, holder);
+                }
+            }
+            Event::Write(place, span) | Event::Move(place, span) => {
+                // Assigning a new reference leaves what the old one borrowed alone
+                let conflict = state.live_loans(live).into_iter().find(|&(loan, _)| {
+                    let loan = &self.cfg.loans[loan];
+                    loan.place.overlaps(place)
+                        && !loan.place.projection.iter().skip(place.projection.len()).any(|p| p == "*")
+                });
+                if let Some((loan, holder)) = conflict {
+                    let name = self.name(place);
+                    let (message, label) = match event {
+                        Event::Write(..) => {
+                            (format!("cannot assign to `{}` because it is borrowed", name), "assigned here")
+                        }
+                        _ => (format!("cannot move out of `{}` because it is borrowed", name), "moved here"),
+                    };
+                    self.borrowed(message, *span, label, loan, holder);
+                }
+            }
+            Event::Scope { dead, result } => {
+                let mut live_loans = state.live_loans(live);
+                live_loans.retain(|(_, holder)| holder.is_some());
+                for origin in state.resolve(result) {
+                    if let Origin::Loan(loan) = origin {
+                        live_loans.push((loan, None));
+                    }
+                }
+                for local in dead {
+                    let escaping = live_loans.iter().find(|&&(loan, _)| {
+                        let place = &self.cfg.loans[loan].place;
+                        place.local == *local && !place.projection.iter().any(|p| p == "*")
+                    });
+                    if let Some(&(loan, holder)) = escaping {
+                        self.outlives(*local, loan, holder);
+                    }
+                }
+            }
+            Event::Return(origins, span) => {
+                for origin in state.resolve(origins) {
+                    match origin {
+                        Origin::Loan(loan) => {
+                            let place = &self.cfg.loans[loan].place;
+                            if !place.projection.iter().any(|p| p == "*") {
+                                self.returns_local(loan, *span);
+                            }
+                        }
+                        Origin::Local(param) => {
+                            if !self.cfg.returnable.contains(&param) {
+                                self.lifetime_mismatch(param, *span);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Where a conflicting borrow is still used, as a note
+    fn held_by(&self, holder: Option<DefId>) -> String {
+        match holder {
+            Some(local) => format!("the borrow is used later through `{}`", self.cfg.locals[&local].name),
+            None => "the borrow is still in use in this statement".to_string(),
+        }
+    }
+
+    fn conflicting_borrow(&mut self, new: LoanId, old: LoanId, holder: Option<DefId>) {
+        let (new, old_id) = (&self.cfg.loans[new], old);
+        let old = &self.cfg.loans[old];
+        if !self.seen.insert(new.span) {
+            return;
+        }
+        let name = self.name(&new.place);
+        let (message, new_label, old_label) = match (new.mutable, old.mutable) {
+            (true, true) => (
+                format!("cannot borrow `{}` as mutable more than once at a time", name),
+                "second mutable borrow occurs here",
+                "first mutable borrow occurs here",
+            ),
+            (true, false) => (
+                format!("cannot borrow `{}` as mutable because it is also borrowed as immutable", name),
+                "mutable borrow occurs here",
+                "immutable borrow occurs here",
+            ),
+            _ => (
+                format!("cannot borrow `{}` as immutable because it is also borrowed as mutable", name),
+                "immutable borrow occurs here",
+                "mutable borrow occurs here",
+            ),
+        };
+        let mut diag =
+            Diagnostic::error(message).with_code(codes::CONFLICTING_BORROW).with_primary(new.span, new_label);
+        if self.cfg.loans[old_id].span == new.span {
+            diag = diag.with_note(format!("{}, in the previous iteration of the loop", old_label));
+        } else {
+            diag = diag.with_secondary(old.span, old_label);
+        }
+        let note = self.held_by(holder);
+        self.diags.push(diag.with_note(note));
+    }
+
+    fn borrowed(&mut self, message: String, span: Span, label: &str, loan: LoanId, holder: Option<DefId>) {
+        if !self.seen.insert(span) {
+            return;
+        }
+        let loan = &self.cfg.loans[loan];
+        let diag = Diagnostic::error(message)
+            .with_code(codes::CONFLICTING_BORROW)
+            .with_primary(span, label)
+            .with_secondary(loan.span, format!("`{}` is borrowed here", self.name(&loan.place)))
+            .with_note(self.held_by(holder));
+        self.diags.push(diag);
+    }
+
+    fn outlives(&mut self, local: DefId, loan: LoanId, holder: Option<DefId>) {
+        let loan = &self.cfg.loans[loan];
+        if !self.seen.insert(loan.span) {
+            return;
+        }
+        let declared = &self.cfg.locals[&local];
+        let note = match holder {
+            Some(holder) => format!(
+                "`{}` goes out of scope at the end of its block, while `{}` still refers to it",
+                declared.name, self.cfg.locals[&holder].name
+            ),
+            None => format!(
+                "`{}` goes out of scope at the end of its block, while the block's value refers to it",
+                declared.name
+            ),
+        };
+        self.diags.push(
+            Diagnostic::error(format!("`{}` does not live long enough", declared.name))
+                .with_code(codes::BORROW_OUTLIVES_VALUE)
+                .with_primary(loan.span, "borrowed value does not live long enough")
+                .with_secondary(declared.span, format!("`{}` is declared here", declared.name))
+                .with_note(note),
+        );
+    }
+
+    fn returns_local(&mut self, loan: LoanId, span: Span) {
+        if !self.seen.insert(span) {
+            return;
+        }
+        let loan = &self.cfg.loans[loan];
+        let local = &self.cfg.locals[&loan.place.local];
+        let kind = if local.is_param { "function parameter" } else { "local variable" };
+        let mut diag = Diagnostic::error(format!("cannot return reference to {} `{}`", kind, local.name))
+            .with_code(codes::BORROW_OUTLIVES_VALUE)
+            .with_primary(span, "returns a reference to data owned by the current function");
+        if loan.span != span {
+            diag = diag.with_secondary(loan.span, format!("`{}` is borrowed here", self.name(&loan.place)));
+        }
+        self.diags.push(diag);
+    }
+
+    fn lifetime_mismatch(&mut self, param: DefId, span: Span) {
+        if !self.seen.insert(span) {
+            return;
+        }
+        let name = &self.cfg.locals[&param].name;
+        self.diags.push(
+            Diagnostic::error("lifetime may not live long enough")
+                .with_code(codes::LIFETIME_MISMATCH)
+                .with_primary(span, format!("this value borrows from `{}`", name))
+                .with_note(format!("the lifetime of the return type does not cover the lifetime of `{}`", name))
+                .with_note(format!("give `{}` and the return type the same named lifetime", name)),
+        );
+    }
+
+    fn shared_mutations(&mut self) {
+        for mutation in &self.cfg.shared_mutations {
+            let name = self.cfg.locals[&mutation.place.local].display(&mutation.place.projection);
+            let (message, label) = if mutation.borrow {
+                (
+                    format!("cannot borrow `{}` as mutable, as it is behind a `&` reference", name),
+                    "cannot be borrowed as mutable",
+                )
+            } else {
+                (format!("cannot assign to `{}`, which is behind a `&` reference", name), "cannot be assigned")
+            };
+            self.diags.push(
+                Diagnostic::error(message)
+                    .with_code(codes::MUTATION_THROUGH_SHARED)
+                    .with_primary(mutation.span, label)
+                    .with_note("data behind a `&` reference cannot be written; take a `&mut` reference instead"),
+            );
+        }
+    }
+}
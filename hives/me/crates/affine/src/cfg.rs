@@ -0,0 +1,766 @@
+// Control-flow graphs
+// Lowers a function body to basic blocks of ownership and borrow events, one graph per function
+
+use crate::{lifetimes, Affinity};
+use my_lang_ast::*;
+use my_lang_resolve::{DefId, DefKind, Res};
+use my_lang_typechecker::Ty;
+use petgraph::graph::{DiGraph, NodeIndex};
+use std::collections::HashMap;
+
+/// A local, or a path inside one. Projections are field names, `*` for a
+/// dereference and `[]` for an element.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Place {
+    pub local: DefId,
+    pub projection: Vec<String>,
+}
+
+impl Place {
+    pub fn is_prefix_of(&self, other: &Place) -> bool {
+        self.local == other.local && other.projection.starts_with(&self.projection)
+    }
+
+    /// Whether one place contains the other
+    pub fn overlaps(&self, other: &Place) -> bool {
+        self.is_prefix_of(other) || other.is_prefix_of(self)
+    }
+}
+
+/// Index of a loan in [`Cfg::loans`]
+pub type LoanId = usize;
+
+/// A borrow of a place, made by `&`, `&mut` or a method's `&self` receiver
+#[derive(Debug, Clone)]
+pub struct Loan {
+    pub place: Place,
+    pub mutable: bool,
+    pub span: Span,
+}
+
+/// Where the references held by a value come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Origin {
+    Loan(LoanId),
+    /// Whatever the local holds when the value is computed
+    Local(DefId),
+}
+
+/// What happens to a place at one point of the function
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The value is moved out, leaving the place unusable
+    Move(Place, Span),
+    /// The value is read or borrowed
+    Use(Place, Span),
+    /// The value is handed to a library method whose receiver is not known:
+    /// it no longer needs consuming, but stays usable
+    Release(Place, Span),
+    /// The place receives a value, by a binding or an assignment
+    Init(Place, Span),
+    /// The place is assigned to, as opposed to bound
+    Write(Place, Span),
+    /// The place is borrowed
+    Borrow(LoanId),
+    /// Borrows made for a call that its result does not hold end
+    Expire(Vec<LoanId>),
+    /// The local now holds references from `origins`, instead of what it held
+    /// before when `replace` is set
+    Store { local: DefId, origins: Vec<Origin>, replace: bool },
+    /// The function returns a value holding references from these origins
+    Return(Vec<Origin>, Span),
+    /// A block's bindings go out of scope, while its value holds `result`
+    Scope { dead: Vec<DefId>, result: Vec<Origin> },
+    /// A statement ends, along with the borrows no binding holds
+    EndStatement,
+}
+
+#[derive(Debug, Default)]
+pub struct BasicBlock {
+    pub events: Vec<Event>,
+}
+
+/// A binding of the function
+#[derive(Debug, Clone)]
+pub struct Local {
+    pub name: String,
+    /// The binding's type, for messages
+    pub ty: String,
+    /// Paths to the affine values the binding holds; an empty path when it is one itself
+    pub resources: Vec<Vec<String>>,
+    pub span: Span,
+    pub is_param: bool,
+}
+
+impl Local {
+    pub fn display(&self, projection: &[String]) -> String {
+        let mut name = self.name.clone();
+        for (i, elem) in projection.iter().enumerate() {
+            match elem.as_str() {
+                "*" if i + 1 == projection.len() => name = format!("*{}", name),
+                "*" => {}
+                "[]" => name.push_str("[..]"),
+                field => {
+                    name.push('.');
+                    name.push_str(field);
+                }
+            }
+        }
+        name
+    }
+}
+
+/// A write or `&mut` borrow through a `&` reference
+#[derive(Debug, Clone)]
+pub struct SharedMutation {
+    pub place: Place,
+    pub span: Span,
+    /// A `&mut` borrow rather than an assignment
+    pub borrow: bool,
+}
+
+#[derive(Debug)]
+pub struct Cfg {
+    pub graph: DiGraph<BasicBlock, ()>,
+    pub entry: NodeIndex,
+    /// Reached by returning; blocks that diverge have no path here
+    pub exit: NodeIndex,
+    pub locals: HashMap<DefId, Local>,
+    pub loans: Vec<Loan>,
+    /// Parameters whose borrows the function may return
+    pub returnable: Vec<DefId>,
+    pub shared_mutations: Vec<SharedMutation>,
+}
+
+/// How an expression's value is consumed by its context
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Move,
+    Borrow,
+    Release,
+}
+
+pub fn build(cx: &Affinity, func: &Function) -> Cfg {
+    let mut graph = DiGraph::new();
+    let entry = graph.add_node(BasicBlock::default());
+    let exit = graph.add_node(BasicBlock::default());
+    let mut builder = Builder {
+        cx,
+        graph,
+        current: entry,
+        exit,
+        loops: Vec::new(),
+        locals: HashMap::new(),
+        loans: Vec::new(),
+        shared_mutations: Vec::new(),
+    };
+
+    let mut params = Vec::new();
+    for param in &func.params {
+        let def = cx.res.def_of_node(param.id);
+        if let Some(def) = def {
+            let annotated = matches!(param.ty.kind, TypeKind::Affine(_));
+            builder.bind(def, &param.name, param.id, annotated, param.span);
+            if let Some(local) = builder.locals.get_mut(&def) {
+                local.is_param = true;
+            }
+        }
+        params.push(def);
+    }
+    // The body's own bindings are checked by what it returns, not by its scope ending
+    let (result, _) = builder.statements(&func.body, Mode::Move);
+    let span = func.body.expr.as_ref().map_or(func.body.span, |expr| expr.span);
+    builder.emit(Event::Return(result, span));
+    builder.goto(exit);
+
+    let returnable = lifetimes::flows(cx, func).into_iter().filter_map(|i| params.get(i).copied().flatten()).collect();
+    Cfg {
+        graph: builder.graph,
+        entry,
+        exit,
+        locals: builder.locals,
+        loans: builder.loans,
+        returnable,
+        shared_mutations: builder.shared_mutations,
+    }
+}
+
+struct Builder<'c, 'a> {
+    cx: &'c Affinity<'a>,
+    graph: DiGraph<BasicBlock, ()>,
+    current: NodeIndex,
+    exit: NodeIndex,
+    /// `continue` and `break` targets of each enclosing loop
+    loops: Vec<(NodeIndex, NodeIndex)>,
+    locals: HashMap<DefId, Local>,
+    loans: Vec<Loan>,
+    shared_mutations: Vec<SharedMutation>,
+}
+
+impl Builder<'_, '_> {
+    // ========== Graph ==========
+
+    fn new_block(&mut self) -> NodeIndex {
+        self.graph.add_node(BasicBlock::default())
+    }
+
+    fn goto(&mut self, target: NodeIndex) {
+        self.graph.add_edge(self.current, target, ());
+    }
+
+    /// Continue in a block nothing jumps to, after control left for good
+    fn diverge(&mut self) {
+        self.current = self.new_block();
+    }
+
+    fn emit(&mut self, event: Event) {
+        self.graph[self.current].events.push(event);
+    }
+
+    // ========== Bindings ==========
+
+    /// Track `def`; `annotated` bindings were declared `affine T` and are affine themselves
+    fn bind(&mut self, def: DefId, name: &str, node: NodeId, annotated: bool, span: Span) {
+        let ty = self.cx.types.type_of(node).cloned().unwrap_or(Ty::Error);
+        let resources = if annotated { vec![Vec::new()] } else { self.cx.resources(&ty) };
+        let ty = ty.display(self.cx.res).to_string();
+        self.locals.insert(def, Local { name: name.to_string(), ty, resources, span, is_param: false });
+        self.emit(Event::Init(Place { local: def, projection: Vec::new() }, span));
+    }
+
+    /// Bind every name in `pat` to a part of a value holding references from
+    /// `origins`, returning the bindings
+    fn bind_pattern(&mut self, pat: &Pattern, annotated: bool, origins: &[Origin]) -> Vec<DefId> {
+        match &pat.kind {
+            PatternKind::Identifier(name) => {
+                let Some(def) = self.cx.res.def_of_node(pat.id) else {
+                    return Vec::new();
+                };
+                self.bind(def, name, pat.id, annotated, pat.span);
+                self.emit(Event::Store { local: def, origins: origins.to_vec(), replace: true });
+                vec![def]
+            }
+            PatternKind::Tuple(elems) | PatternKind::TupleStruct { elems, .. } => {
+                elems.iter().flat_map(|elem| self.bind_pattern(elem, false, origins)).collect()
+            }
+            PatternKind::Struct { fields, .. } => {
+                fields.iter().flat_map(|(_, field)| self.bind_pattern(field, false, origins)).collect()
+            }
+            PatternKind::Wildcard | PatternKind::Literal(_) | PatternKind::Path(_) => Vec::new(),
+        }
+    }
+
+    // ========== Statements ==========
+
+    /// A block whose bindings go out of scope at its end, returning the origins of its value
+    fn block(&mut self, block: &Block, mode: Mode) -> Vec<Origin> {
+        let (result, dead) = self.statements(block, mode);
+        if !dead.is_empty() {
+            self.emit(Event::Scope { dead, result: result.clone() });
+        }
+        result
+    }
+
+    /// The statements of a block, returning the origins of its value and the bindings it declared
+    fn statements(&mut self, block: &Block, mode: Mode) -> (Vec<Origin>, Vec<DefId>) {
+        let mut bound = Vec::new();
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StatementKind::Let { pattern, ty, init, .. } => {
+                    let origins = match init {
+                        Some(init) => self.expr(init, Mode::Move),
+                        None => Vec::new(),
+                    };
+                    let annotated = ty.as_ref().is_some_and(|t| matches!(t.kind, TypeKind::Affine(_)));
+                    bound.extend(self.bind_pattern(pattern, annotated, &origins));
+                }
+                StatementKind::Expression(expr) => {
+                    self.expr(expr, Mode::Move);
+                }
+                StatementKind::Item(_) | StatementKind::Error => {}
+            }
+            self.emit(Event::EndStatement);
+        }
+        let result = match &block.expr {
+            Some(expr) => self.expr(expr, mode),
+            None => Vec::new(),
+        };
+        (result, bound)
+    }
+
+    // ========== Places ==========
+
+    fn ty(&self, expr: &Expression) -> Option<&Ty> {
+        self.cx.types.type_of(expr.id)
+    }
+
+    fn is_ref(&self, expr: &Expression) -> bool {
+        matches!(self.ty(expr), Some(Ty::Ref { .. }))
+    }
+
+    /// The place `expr` names, if it is a path to a local, a field of one or a dereference.
+    /// Fields of a reference are reached through it.
+    fn place(&self, expr: &Expression) -> Option<Place> {
+        match &expr.kind {
+            ExpressionKind::Identifier(_) => match self.cx.res.res(expr.id) {
+                Some(Res::Def(def)) if self.locals.contains_key(def) => {
+                    Some(Place { local: *def, projection: Vec::new() })
+                }
+                _ => None,
+            },
+            ExpressionKind::Field { expr: base, field } => {
+                let mut place = self.place(base)?;
+                if self.is_ref(base) {
+                    place.projection.push("*".to_string());
+                }
+                place.projection.push(field.clone());
+                Some(place)
+            }
+            ExpressionKind::Unary { op: UnaryOp::Deref, expr: inner } => {
+                let mut place = self.place(inner)?;
+                place.projection.push("*".to_string());
+                Some(place)
+            }
+            _ => None,
+        }
+    }
+
+    /// The place borrowed or assigned by `expr`, which may also be an element of a place
+    fn target(&mut self, expr: &Expression) -> Option<Place> {
+        let ExpressionKind::Index { expr: base, index } = &expr.kind else {
+            return self.place(expr);
+        };
+        let mut place = self.place(base)?;
+        self.expr(index, Mode::Move);
+        if self.is_ref(base) {
+            place.projection.push("*".to_string());
+        }
+        place.projection.push("[]".to_string());
+        Some(place)
+    }
+
+    /// Whether `expr` is reached through a `&` reference, and so cannot be written
+    fn behind_shared(&self, expr: &Expression) -> bool {
+        match &expr.kind {
+            ExpressionKind::Field { expr: base, .. }
+            | ExpressionKind::Index { expr: base, .. }
+            | ExpressionKind::Unary { op: UnaryOp::Deref, expr: base } => {
+                matches!(self.ty(base), Some(Ty::Ref { mutable: false, .. })) || self.behind_shared(base)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether moving out of `place` moves an affine value, rather than copying
+    fn holds_resource(&self, place: &Place) -> bool {
+        if place.projection.iter().any(|p| p == "*") {
+            return false;
+        }
+        let resources = &self.locals[&place.local].resources;
+        resources.iter().any(|r| r.starts_with(&place.projection) || place.projection.starts_with(r))
+    }
+
+    fn borrow(&mut self, place: Place, mutable: bool, span: Span) -> Vec<Origin> {
+        let id = self.loans.len();
+        let mut origins = vec![Origin::Loan(id)];
+        // A reborrow through a reference also holds what the reference borrows
+        if place.projection.iter().any(|p| p == "*") {
+            origins.push(Origin::Local(place.local));
+        }
+        self.loans.push(Loan { place, mutable, span });
+        self.emit(Event::Borrow(id));
+        origins
+    }
+
+    fn mutate_through_shared(&mut self, place: &Place, span: Span, borrow: bool) {
+        self.shared_mutations.push(SharedMutation { place: place.clone(), span, borrow });
+    }
+
+    // ========== Expressions ==========
+
+    /// Lower `expr`, returning where the references its value holds come from
+    fn expr(&mut self, expr: &Expression, mode: Mode) -> Vec<Origin> {
+        if let Some(place) = self.place(expr) {
+            let holds_refs = self.ty(expr).is_some_and(|ty| self.cx.holds_refs(ty));
+            let local = place.local;
+            let event = match mode {
+                _ if !self.holds_resource(&place) => Event::Use(place, expr.span),
+                Mode::Move => Event::Move(place, expr.span),
+                Mode::Borrow => Event::Use(place, expr.span),
+                Mode::Release => Event::Release(place, expr.span),
+            };
+            self.emit(event);
+            return if holds_refs { vec![Origin::Local(local)] } else { Vec::new() };
+        }
+
+        match &expr.kind {
+            ExpressionKind::Literal(_) | ExpressionKind::Identifier(_) | ExpressionKind::Path(_) => Vec::new(),
+            // Evaluated during compilation: a constant by the time the function runs
+            ExpressionKind::Comptime(_) => Vec::new(),
+            ExpressionKind::Binary { left, op: BinaryOp::Assign, right } => {
+                let origins = self.expr(right, Mode::Move);
+                let Some(place) = self.target(left) else {
+                    self.expr(left, Mode::Borrow);
+                    return Vec::new();
+                };
+                if self.behind_shared(left) {
+                    self.mutate_through_shared(&place, left.span, false);
+                }
+                self.emit(Event::Write(place.clone(), left.span));
+                self.emit(Event::Init(place.clone(), left.span));
+                if !place.projection.iter().any(|p| p == "*") {
+                    let replace = place.projection.is_empty();
+                    self.emit(Event::Store { local: place.local, origins, replace });
+                }
+                Vec::new()
+            }
+            ExpressionKind::Binary { left, op, right } => {
+                let mode = match op {
+                    BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => {
+                        Mode::Borrow
+                    }
+                    _ => Mode::Move,
+                };
+                self.expr(left, mode);
+                self.expr(right, mode);
+                Vec::new()
+            }
+            ExpressionKind::Unary { op: op @ (UnaryOp::Ref | UnaryOp::RefMut), expr: inner } => {
+                let mutable = *op == UnaryOp::RefMut;
+                let Some(place) = self.target(inner) else {
+                    return self.expr(inner, Mode::Borrow);
+                };
+                if mutable && self.behind_shared(inner) {
+                    self.mutate_through_shared(&place, expr.span, true);
+                }
+                self.borrow(place, mutable, expr.span)
+            }
+            ExpressionKind::Unary { op, expr: inner } => {
+                let mode = match op {
+                    UnaryOp::Deref => Mode::Borrow,
+                    _ => Mode::Move,
+                };
+                let origins = self.expr(inner, mode);
+                if *op == UnaryOp::Deref {
+                    origins
+                } else {
+                    Vec::new()
+                }
+            }
+            ExpressionKind::Call { func, args } => {
+                // Prelude functions format their arguments by reference, except `drop` and `dbg`
+                let prelude = match self.cx.res.definition_of(func.id) {
+                    Some(def) if def.is_prelude() && def.kind == DefKind::Function => Some(def.name.as_str()),
+                    _ => None,
+                };
+                self.expr(func, Mode::Borrow);
+                let mode = match prelude {
+                    Some("drop" | "dbg") | None => Mode::Move,
+                    Some(_) => Mode::Borrow,
+                };
+                let first_loan = self.loans.len();
+                let args: Vec<Vec<Origin>> = args.iter().map(|arg| self.expr(arg, mode)).collect();
+                let callee = match self.cx.res.res(func.id) {
+                    Some(Res::Def(def)) => Some(*def),
+                    _ => None,
+                };
+                let result = self.call_result(expr, callee, args);
+                self.expire(first_loan, &result);
+                self.diverge_if_never(expr);
+                result
+            }
+            ExpressionKind::MethodCall { receiver, args, .. } => self.method_call(expr, receiver, args),
+            ExpressionKind::If { cond, then_block, else_block } => {
+                self.expr(cond, Mode::Move);
+                let branch = self.current;
+                let join = self.new_block();
+                self.current = self.new_block();
+                self.graph.add_edge(branch, self.current, ());
+                let mut result = self.block(then_block, mode);
+                self.goto(join);
+                self.current = self.new_block();
+                self.graph.add_edge(branch, self.current, ());
+                if let Some(block) = else_block {
+                    result.extend(self.block(block, mode));
+                }
+                self.goto(join);
+                self.current = join;
+                result
+            }
+            ExpressionKind::Match { expr: scrutinee, arms } => {
+                let binds = arms.iter().any(|arm| binds_anything(self.cx, &arm.pattern));
+                let origins = self.expr(scrutinee, if binds { Mode::Move } else { Mode::Borrow });
+                let branch = self.current;
+                let join = self.new_block();
+                let mut result = Vec::new();
+                for arm in arms {
+                    self.current = self.new_block();
+                    self.graph.add_edge(branch, self.current, ());
+                    self.bind_pattern(&arm.pattern, false, &origins);
+                    if let Some(guard) = &arm.guard {
+                        self.expr(guard, Mode::Move);
+                    }
+                    result.extend(self.expr(&arm.body, mode));
+                    self.goto(join);
+                }
+                if arms.is_empty() {
+                    self.goto(join);
+                }
+                self.current = join;
+                result
+            }
+            ExpressionKind::Loop(body) => {
+                let header = self.new_block();
+                let after = self.new_block();
+                self.goto(header);
+                self.current = header;
+                self.loops.push((header, after));
+                self.block(body, Mode::Move);
+                self.goto(header);
+                self.loops.pop();
+                self.current = after;
+                Vec::new()
+            }
+            ExpressionKind::While { cond, body } => {
+                let header = self.new_block();
+                let after = self.new_block();
+                self.goto(header);
+                self.current = header;
+                self.expr(cond, Mode::Move);
+                self.goto(after);
+                self.loop_body(header, after, None, body);
+                Vec::new()
+            }
+            ExpressionKind::For { pattern, iter, body } => {
+                let origins = self.expr(iter, Mode::Move);
+                let header = self.new_block();
+                let after = self.new_block();
+                self.goto(header);
+                self.current = header;
+                self.goto(after);
+                self.loop_body(header, after, Some((pattern, origins)), body);
+                Vec::new()
+            }
+            ExpressionKind::Return(value) => {
+                if let Some(value) = value {
+                    let origins = self.expr(value, Mode::Move);
+                    self.emit(Event::Return(origins, value.span));
+                }
+                self.goto(self.exit);
+                self.diverge();
+                Vec::new()
+            }
+            ExpressionKind::Break(value) => {
+                if let Some(value) = value {
+                    self.expr(value, Mode::Move);
+                }
+                if let Some(&(_, after)) = self.loops.last() {
+                    self.goto(after);
+                }
+                self.diverge();
+                Vec::new()
+            }
+            ExpressionKind::Continue => {
+                if let Some(&(header, _)) = self.loops.last() {
+                    self.goto(header);
+                }
+                self.diverge();
+                Vec::new()
+            }
+            ExpressionKind::Block(block) => self.block(block, mode),
+            ExpressionKind::Tuple(elems) | ExpressionKind::Array(elems) => {
+                elems.iter().flat_map(|elem| self.expr(elem, Mode::Move)).collect()
+            }
+            ExpressionKind::Index { expr: base, index } => {
+                let origins = self.expr(base, Mode::Borrow);
+                self.expr(index, Mode::Move);
+                if self.ty(expr).is_some_and(|ty| self.cx.holds_refs(ty)) {
+                    origins
+                } else {
+                    Vec::new()
+                }
+            }
+            ExpressionKind::Field { expr: base, .. } => self.expr(base, mode),
+            ExpressionKind::Struct { fields, .. }
+            | ExpressionKind::Intent { options: fields, .. }
+            | ExpressionKind::Spawn { config: fields, .. } => {
+                fields.iter().flat_map(|(_, value)| self.expr(value, Mode::Move)).collect()
+            }
+            ExpressionKind::Synth { config, expr: inner } | ExpressionKind::Verify { config, expr: inner } => {
+                for (_, value) in config {
+                    self.expr(value, Mode::Move);
+                }
+                self.expr(inner, Mode::Move)
+            }
+            ExpressionKind::Hybrid { symbolic, neural, .. } => {
+                self.expr(symbolic, Mode::Move);
+                self.expr(neural, Mode::Move);
+                Vec::new()
+            }
+            ExpressionKind::Send { message, recipient } => {
+                self.expr(message, Mode::Move);
+                self.expr(recipient, Mode::Move);
+                Vec::new()
+            }
+            ExpressionKind::Receive { filter, timeout } => {
+                for e in filter.iter().chain(timeout) {
+                    self.expr(e, Mode::Move);
+                }
+                Vec::new()
+            }
+            ExpressionKind::Broadcast { message, .. } => {
+                self.expr(message, Mode::Move);
+                Vec::new()
+            }
+            // Captures are taken when the closure is made, and affine ones move
+            // into it whether or not it is `move`. The body runs later.
+            ExpressionKind::Closure { .. } => {
+                let mut origins = Vec::new();
+                for &local in self.cx.res.captures.get(&expr.id).into_iter().flatten() {
+                    if !self.locals.contains_key(&local) {
+                        continue;
+                    }
+                    let node = self.cx.res.def(local).node;
+                    let holds_refs = self.cx.types.type_of(node).is_some_and(|ty| self.cx.holds_refs(ty));
+                    let place = Place { local, projection: Vec::new() };
+                    let event = if self.holds_resource(&place) {
+                        Event::Move(place, expr.span)
+                    } else {
+                        Event::Use(place, expr.span)
+                    };
+                    self.emit(event);
+                    if holds_refs {
+                        origins.push(Origin::Local(local));
+                    }
+                }
+                origins
+            }
+        }
+    }
+
+    /// A method call. A receiver the method takes by reference is borrowed once
+    /// the arguments are evaluated, so `v.set(v.get())` only borrows `v` mutably
+    /// after the shared borrow for `get` ended.
+    fn method_call(&mut self, expr: &Expression, receiver: &Expression, args: &[Expression]) -> Vec<Origin> {
+        let mode = self.receiver_mode(expr);
+        let first_loan = self.loans.len();
+        let autoref = self.autoref(expr).zip(self.place(receiver));
+        let mut origins = vec![match autoref {
+            Some(_) => Vec::new(),
+            None => self.expr(receiver, mode),
+        }];
+        for arg in args {
+            origins.push(self.expr(arg, Mode::Move));
+        }
+        if let Some((mutable, mut place)) = autoref {
+            if self.is_ref(receiver) {
+                place.projection.push("*".to_string());
+            }
+            let shared = matches!(self.ty(receiver), Some(Ty::Ref { mutable: false, .. }));
+            if mutable && (shared || self.behind_shared(receiver)) {
+                self.mutate_through_shared(&place, receiver.span, true);
+            }
+            origins[0] = self.borrow(place, mutable, receiver.span);
+        }
+
+        // A method on a receiver holding references may keep its arguments, as `v.push(&x)` does
+        let receiver_holds_refs = self.ty(receiver).is_some_and(|ty| self.cx.holds_refs(ty));
+        if mode != Mode::Move && receiver_holds_refs {
+            let kept: Vec<Origin> = origins[1..].concat();
+            if let Some(place) = self.place(receiver).filter(|p| !p.projection.iter().any(|p| p == "*")) {
+                if !kept.is_empty() {
+                    self.emit(Event::Store { local: place.local, origins: kept, replace: false });
+                }
+            }
+        }
+
+        let callee = self.cx.types.method_calls.get(&expr.id).copied();
+        let result = self.call_result(expr, callee, origins);
+        self.expire(first_loan, &result);
+        self.diverge_if_never(expr);
+        result
+    }
+
+    /// Origins of a call's result: the arguments its signature lets the result
+    /// borrow from, or every argument for a callee whose signature is unknown
+    fn call_result(&self, call: &Expression, callee: Option<DefId>, args: Vec<Vec<Origin>>) -> Vec<Origin> {
+        let holds_refs = self.ty(call).is_some_and(|ty| self.cx.holds_refs(ty));
+        match callee.and_then(|def| self.cx.signatures.get(&def)) {
+            Some(flows) if !flows.is_empty() || !holds_refs => {
+                flows.iter().filter_map(|&i| args.get(i)).flatten().copied().collect()
+            }
+            _ if holds_refs => args.into_iter().flatten().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// End the borrows made since `first_loan` that a call's result does not hold
+    fn expire(&mut self, first_loan: LoanId, result: &[Origin]) {
+        let ended: Vec<LoanId> =
+            (first_loan..self.loans.len()).filter(|&id| !result.contains(&Origin::Loan(id))).collect();
+        if !ended.is_empty() {
+            self.emit(Event::Expire(ended));
+        }
+    }
+
+    /// The body of a `while` or `for` loop entered from `header`, which also exits to `after`
+    fn loop_body(
+        &mut self,
+        header: NodeIndex,
+        after: NodeIndex,
+        pattern: Option<(&Pattern, Vec<Origin>)>,
+        body: &Block,
+    ) {
+        self.current = self.new_block();
+        self.graph.add_edge(header, self.current, ());
+        if let Some((pattern, origins)) = pattern {
+            self.bind_pattern(pattern, false, &origins);
+        }
+        self.loops.push((header, after));
+        self.block(body, Mode::Move);
+        self.goto(header);
+        self.loops.pop();
+        self.current = after;
+    }
+
+    /// Methods taking `self` by value consume their receiver. Library methods
+    /// release it, so `handle.close()` discharges the handle while
+    /// `socket.write(data)` leaves the socket usable.
+    fn receiver_mode(&self, call: &Expression) -> Mode {
+        match self.cx.types.method_calls.get(&call.id).and_then(|def| self.cx.types.sigs.get(def)) {
+            Some(sig) if sig.has_self => match sig.inputs.first() {
+                Some(Ty::Ref { .. }) => Mode::Borrow,
+                _ => Mode::Move,
+            },
+            Some(_) => Mode::Borrow,
+            None => Mode::Release,
+        }
+    }
+
+    /// Whether the method called takes its receiver by `&mut` or `&` reference
+    fn autoref(&self, call: &Expression) -> Option<bool> {
+        let sig = self.cx.types.method_calls.get(&call.id).and_then(|def| self.cx.types.sigs.get(def))?;
+        match sig.inputs.first() {
+            Some(Ty::Ref { mutable, .. }) if sig.has_self => Some(*mutable),
+            _ => None,
+        }
+    }
+
+    fn diverge_if_never(&mut self, expr: &Expression) {
+        if matches!(self.cx.types.type_of(expr.id), Some(Ty::Never)) {
+            self.diverge();
+        }
+    }
+}
+
+/// Whether a pattern binds any part of the value it matches
+fn binds_anything(cx: &Affinity, pat: &Pattern) -> bool {
+    match &pat.kind {
+        PatternKind::Identifier(_) => cx.res.def_of_node(pat.id).is_some(),
+        PatternKind::Tuple(elems) | PatternKind::TupleStruct { elems, .. } => {
+            elems.iter().any(|p| binds_anything(cx, p))
+        }
+        PatternKind::Struct { fields, .. } => fields.iter().any(|(_, p)| binds_anything(cx, p)),
+        PatternKind::Wildcard | PatternKind::Literal(_) | PatternKind::Path(_) => false,
+    }
+}
@@ -1 +1,403 @@
-// Affine type checker stub - implementation pending
+// Affine type and borrow checking
+// Values holding `affine` fields are moved rather than copied: each is used at most
+// once and consumed or dropped on every path. References obey the aliasing rules
+// and outlive nothing they borrow from. Both are checked over a control-flow graph
+// per function.
+
+mod borrows;
+mod cfg;
+mod lifetimes;
+mod moves;
+
+use my_lang_ast::*;
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_resolve::{DefId, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults};
+use std::collections::{HashMap, HashSet};
+
+/// Check every function of a type-checked crate, returning every error found
+pub fn check_crate(programs: &[&Program], res: &Resolution, types: &TypeckResults) -> Vec<Diagnostic> {
+    let mut cx = Affinity { res, types, fields: HashSet::new(), signatures: HashMap::new() };
+    for program in programs {
+        cx.collect_fields(&program.items);
+    }
+    for program in programs {
+        cx.collect_signatures(&program.items);
+    }
+    let mut diags = Vec::new();
+    for program in programs {
+        check_items(&cx, &program.items, &mut diags);
+    }
+    diags
+}
+
+fn check_items(cx: &Affinity, items: &[Item], diags: &mut Vec<Diagnostic>) {
+    for item in items {
+        match &item.kind {
+            ItemKind::Function(func) => check_fn(cx, func, diags),
+            ItemKind::Impl(imp) => {
+                check_copy_impl(cx, imp, diags);
+                for item in &imp.items {
+                    if let ImplItem::Function(func) = item {
+                        check_fn(cx, func, diags);
+                    }
+                }
+            }
+            ItemKind::Trait(t) => {
+                for item in &t.items {
+                    if let TraitItem::Function(func) = item {
+                        check_fn(cx, func, diags);
+                    }
+                }
+            }
+            ItemKind::Module(module) => check_items(cx, &module.items, diags),
+            _ => {}
+        }
+    }
+}
+
+fn check_fn(cx: &Affinity, func: &Function, diags: &mut Vec<Diagnostic>) {
+    lifetimes::check_signature(cx, func, diags);
+    let cfg = cfg::build(cx, func);
+    moves::check(&cfg, diags);
+    borrows::check(&cfg, diags);
+}
+
+/// `Copy` and `Clone` would duplicate the resource an affine type holds
+fn check_copy_impl(cx: &Affinity, imp: &Impl, diags: &mut Vec<Diagnostic>) {
+    let Some(trait_def) = cx.res.definition_of(imp.id) else {
+        return;
+    };
+    if !trait_def.is_prelude() || !matches!(trait_def.name.as_str(), "Copy" | "Clone") {
+        return;
+    }
+    let Some(&self_def) = cx.res.impl_self.get(&imp.id) else {
+        return;
+    };
+    let Some(adt) = cx.types.adts.get(&self_def) else {
+        return;
+    };
+    let ty = Ty::Adt(self_def, adt.generics.iter().map(|&g| Ty::Param(g)).collect());
+    if cx.resources(&ty).is_empty() {
+        return;
+    }
+    diags.push(
+        Diagnostic::error(format!(
+            "the trait `{}` cannot be implemented for `{}`",
+            trait_def.name,
+            cx.res.def(self_def).name
+        ))
+        .with_code(codes::AFFINE_COPY)
+        .with_primary(imp.self_ty.span, "this type holds an affine value")
+        .with_note("affine values are moved, never copied"),
+    );
+}
+
+/// Which types hold affine values
+pub(crate) struct Affinity<'a> {
+    pub res: &'a Resolution,
+    pub types: &'a TypeckResults,
+    /// Fields declared `affine T`, by struct or variant
+    fields: HashSet<(DefId, String)>,
+    /// For each function, the parameters its result may borrow from
+    pub signatures: HashMap<DefId, Vec<usize>>,
+}
+
+impl Affinity<'_> {
+    fn collect_fields(&mut self, items: &[Item]) {
+        for item in items {
+            match &item.kind {
+                ItemKind::Struct(s) => {
+                    if let Some(def) = self.res.def_of_node(s.id) {
+                        self.add_fields(def, s.fields.iter().map(|f| (f.name.clone(), &f.ty)));
+                    }
+                }
+                ItemKind::Enum(e) => {
+                    for variant in &e.variants {
+                        let Some(def) = self.res.def_of_node(variant.id) else {
+                            continue;
+                        };
+                        match &variant.data {
+                            VariantData::Unit => {}
+                            VariantData::Tuple(tys) => {
+                                self.add_fields(def, tys.iter().enumerate().map(|(i, ty)| (i.to_string(), ty)))
+                            }
+                            VariantData::Struct(fields) => {
+                                self.add_fields(def, fields.iter().map(|f| (f.name.clone(), &f.ty)))
+                            }
+                        }
+                    }
+                }
+                ItemKind::Module(module) => self.collect_fields(&module.items),
+                _ => {}
+            }
+        }
+    }
+
+    fn collect_signatures(&mut self, items: &[Item]) {
+        let add = |cx: &mut Self, func: &Function| {
+            if let Some(def) = cx.res.def_of_node(func.id) {
+                let flows = lifetimes::flows(cx, func);
+                cx.signatures.insert(def, flows);
+            }
+        };
+        for item in items {
+            match &item.kind {
+                ItemKind::Function(func) => add(self, func),
+                ItemKind::Impl(imp) => {
+                    for item in &imp.items {
+                        if let ImplItem::Function(func) = item {
+                            add(self, func);
+                        }
+                    }
+                }
+                ItemKind::Trait(t) => {
+                    for item in &t.items {
+                        if let TraitItem::Function(func) | TraitItem::Signature(func) = item {
+                            add(self, func);
+                        }
+                    }
+                }
+                ItemKind::Module(module) => self.collect_signatures(&module.items),
+                _ => {}
+            }
+        }
+    }
+
+    fn add_fields<'t>(&mut self, owner: DefId, fields: impl Iterator<Item = (String, &'t Type)>) {
+        for (name, ty) in fields {
+            if matches!(ty.kind, TypeKind::Affine(_)) {
+                self.fields.insert((owner, name));
+            }
+        }
+    }
+
+    /// Field paths from a value of type `ty` to the affine values inside it.
+    /// An empty path means the value is affine as a whole.
+    pub fn resources(&self, ty: &Ty) -> Vec<Vec<String>> {
+        self.resources_in(ty, &mut Vec::new())
+    }
+
+    fn resources_in(&self, ty: &Ty, visiting: &mut Vec<DefId>) -> Vec<Vec<String>> {
+        match ty {
+            Ty::Adt(def, args) => {
+                let Some(adt) = self.types.adts.get(def) else {
+                    return Vec::new();
+                };
+                if visiting.contains(def) {
+                    return Vec::new();
+                }
+                visiting.push(*def);
+                let map = adt.generics.iter().copied().zip(args.iter().cloned()).collect();
+                let mut paths = Vec::new();
+                for variant in &adt.variants {
+                    for field in &variant.fields {
+                        let inner = if self.fields.contains(&(variant.def, field.name.clone())) {
+                            vec![Vec::new()]
+                        } else {
+                            self.resources_in(&field.ty.subst(&map), visiting)
+                        };
+                        paths.extend(
+                            inner
+                                .into_iter()
+                                .map(|path| std::iter::once(field.name.clone()).chain(path).collect::<Vec<_>>()),
+                        );
+                    }
+                }
+                visiting.pop();
+                // Fields of a variant cannot be moved out one at a time
+                if adt.variants.len() != 1 || adt.variants[0].def != *def {
+                    return if paths.is_empty() { paths } else { vec![Vec::new()] };
+                }
+                paths
+            }
+            Ty::Tuple(elems) => elems
+                .iter()
+                .enumerate()
+                .flat_map(|(i, elem)| {
+                    self.resources_in(elem, visiting)
+                        .into_iter()
+                        .map(move |path| std::iter::once(i.to_string()).chain(path).collect())
+                })
+                .collect(),
+            Ty::Array(elem, _) if !self.resources_in(elem, visiting).is_empty() => vec![Vec::new()],
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILES: &str = "import std::io::File;\n\
+        struct FileHandle { path: str, handle: affine File }\n\
+        impl FileHandle {\n\
+            fn open(path: str) -> FileHandle { FileHandle { path, handle: File::open(path) } }\n\
+            fn read_all(self) -> str { self.handle.read_to_string() }\n\
+            fn name(&self) -> str { self.path }\n\
+        }\n";
+
+    fn check_source(source: &str) -> Vec<Diagnostic> {
+        let source = format!("{}{}", FILES, source);
+        let program = my_lang_parser::parse(&source).expect("test source parses");
+        let (res, diags) = my_lang_resolve::resolve(&program);
+        assert!(diags.is_empty(), "{:?}", diags);
+        let (types, diags) = my_lang_typechecker::check_crate(&[&program], &res);
+        assert!(diags.is_empty(), "{:?}", diags.iter().map(|d| &d.message).collect::<Vec<_>>());
+        check_crate(&[&program], &res, &types)
+    }
+
+    fn codes_of(diags: &[Diagnostic]) -> Vec<&str> {
+        diags.iter().filter_map(|d| d.code).map(|c| c.as_str()).collect()
+    }
+
+    #[test]
+    fn test_consuming_methods_and_drop() {
+        let diags = check_source(
+            "fn read() -> str { let f = FileHandle::open(\"a\"); let n = f.name(); f.read_all() }\n\
+             fn discard(c: bool) { let f = FileHandle::open(\"a\"); if c { f.read_all(); } else { drop(f); } }\n\
+             fn give(f: FileHandle) -> FileHandle { f }\n\
+             fn fail(f: FileHandle) { panic(\"gave up\"); }",
+        );
+        assert!(diags.is_empty(), "{:?}", diags.iter().map(|d| &d.message).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_use_after_move_points_at_both_uses() {
+        let diags = check_source("fn main() { let f = FileHandle::open(\"a\"); f.read_all(); let n = f.name(); }");
+        assert_eq!(codes_of(&diags), ["E0300"]);
+        assert_eq!(diags[0].message, "use of moved value: `f`");
+        let labels: Vec<(&str, u32)> = diags[0].labels.iter().map(|l| (l.message.as_str(), l.span.column)).collect();
+        assert_eq!(labels, [("value used here after move", 66), ("value moved here", 44)]);
+
+        let diags = check_source(
+            "fn each(f: FileHandle) { loop { f.read_all(); } }\n\
+             fn partial(f: FileHandle) -> FileHandle { let h = f.handle; f }",
+        );
+        assert_eq!(codes_of(&diags), ["E0300", "E0300"]);
+        assert_eq!(diags[0].notes[0], "value moved here, in the previous iteration of the loop");
+        assert_eq!(diags[1].message, "use of partially moved value: `f`");
+    }
+
+    #[test]
+    fn test_closures_take_affine_captures() {
+        let diags = check_source(
+            "fn later() -> str { let f = FileHandle::open(\"a\"); let read = || f.read_all(); read() }\n\
+             fn copies() -> i32 { let n = 1; let add = move |x: i32| x + n; add(n) }",
+        );
+        assert!(diags.is_empty(), "{:?}", diags.iter().map(|d| &d.message).collect::<Vec<_>>());
+
+        let diags = check_source(
+            "fn main() { let f = FileHandle::open(\"a\"); let read = move || f.read_all(); let n = f.name(); }",
+        );
+        assert_eq!(codes_of(&diags), ["E0300"]);
+        assert_eq!(diags[0].message, "use of moved value: `f`");
+        let labels: Vec<(&str, u32)> = diags[0].labels.iter().map(|l| (l.message.as_str(), l.span.column)).collect();
+        assert_eq!(labels, [("value used here after move", 85), ("value moved here", 55)]);
+    }
+
+    #[test]
+    fn test_values_consumed_on_every_path() {
+        let diags = check_source(
+            "fn leak() { let f = FileHandle::open(\"a\"); let n = f.name(); }\n\
+             fn branch(c: bool) { let f = FileHandle::open(\"a\"); if c { f.read_all(); } }\n\
+             fn reassign() { let mut f = FileHandle::open(\"a\"); f = FileHandle::open(\"b\"); f.read_all(); }",
+        );
+        let messages: Vec<&str> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "affine value `f` is never consumed",
+                "affine value `f` is not consumed on every path",
+                "affine value `f.handle` is overwritten before it is consumed",
+            ]
+        );
+        assert!(codes_of(&diags).iter().all(|c| *c == "E0301"));
+    }
+
+    #[test]
+    fn test_affine_types_cannot_be_copied() {
+        let diags =
+            check_source("impl Clone for FileHandle { fn clone(&self) -> FileHandle { FileHandle::open(self.path) } }");
+        assert_eq!(codes_of(&diags), ["E0302"]);
+        assert_eq!(diags[0].message, "the trait `Clone` cannot be implemented for `FileHandle`");
+    }
+
+    fn messages(diags: &[Diagnostic]) -> Vec<&str> {
+        diags.iter().map(|d| d.message.as_str()).collect()
+    }
+
+    #[test]
+    fn test_conflicting_borrows() {
+        let diags = check_source(
+            "struct Counter { count: i32 }\n\
+             impl Counter { fn bump(&mut self) { self.count = self.count + 1; } }\n\
+             impl Counter { fn peek(&self) -> &i32 { &self.count } }\n\
+             fn twice() { let mut x = 1; let a = &mut x; let b = &mut x; *a = 2; }\n\
+             fn peeked() { let mut c = Counter { count: 0 }; let p = c.peek(); c.bump(); let v = *p; }\n\
+             fn assigned() { let mut x = 1; let r = &x; x = 2; let y = *r; }\n\
+             fn moved() { let f = FileHandle::open(\"a\"); let r = &f; drop(f); let n = r.name(); }\n\
+             fn sequential() {\n\
+                 let mut c = Counter { count: 0 }; let r = &mut c; r.bump(); let p = c.peek(); c.bump();\n\
+             }",
+        );
+        assert_eq!(
+            messages(&diags),
+            [
+                "cannot borrow `x` as mutable more than once at a time",
+                "cannot borrow `c` as mutable because it is also borrowed as immutable",
+                "cannot assign to `x` because it is borrowed",
+                "cannot move out of `f` because it is borrowed",
+            ]
+        );
+        assert!(codes_of(&diags).iter().all(|c| *c == "E0303"));
+        let labels: Vec<&str> = diags[0].labels.iter().map(|l| l.message.as_str()).collect();
+        assert_eq!(labels, ["second mutable borrow occurs here", "first mutable borrow occurs here"]);
+        assert_eq!(diags[0].notes, ["the borrow is used later through `a`"]);
+    }
+
+    #[test]
+    fn test_mutation_through_shared_reference() {
+        let diags = check_source(
+            "struct Counter { count: i32 }\n\
+             impl Counter { fn bump(&mut self) { self.count = self.count + 1; } fn reset(&self) { self.count = 0; } }\n\
+             fn poke(c: &Counter, d: &mut Counter) { c.count = 3; c.bump(); let r = &mut c.count; d.bump(); }",
+        );
+        assert_eq!(
+            messages(&diags),
+            [
+                "cannot assign to `self.count`, which is behind a `&` reference",
+                "cannot assign to `c.count`, which is behind a `&` reference",
+                "cannot borrow `*c` as mutable, as it is behind a `&` reference",
+                "cannot borrow `c.count` as mutable, as it is behind a `&` reference",
+            ]
+        );
+        assert!(codes_of(&diags).iter().all(|c| *c == "E0304"));
+    }
+
+    #[test]
+    fn test_references_outlive_their_referent() {
+        let diags = check_source(
+            "fn dangling() -> i32 { let r = &0; if true { let x = 5; r = &x; } let v = *r; v }\n\
+             fn local() -> &i32 { let x = 1; &x }\n\
+             fn wrong<'a>(x: &'a str, y: &str) -> &'a str { y }\n\
+             fn right<'a>(x: &'a str, y: &str) -> &'a str { x }\n\
+             fn forever(x: &str) -> &'static str { x }\n\
+             fn ambiguous(x: &str, y: &str) -> &str { x }\n\
+             fn elided(f: &FileHandle) -> &str { &f.path }",
+        );
+        assert_eq!(
+            messages(&diags),
+            [
+                "`x` does not live long enough",
+                "cannot return reference to local variable `x`",
+                "lifetime may not live long enough",
+                "lifetime may not live long enough",
+                "missing lifetime specifier",
+            ]
+        );
+        assert_eq!(codes_of(&diags), ["E0305", "E0305", "E0306", "E0306", "E0307"]);
+        assert_eq!(diags[0].notes, ["`x` goes out of scope at the end of its block, while `r` still refers to it"]);
+    }
+}
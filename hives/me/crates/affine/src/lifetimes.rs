@@ -0,0 +1,139 @@
+// Lifetime signatures
+// Which parameters a function's returned references may borrow from, read off
+// the lifetimes written on its reference types or implied by elision
+
+use crate::Affinity;
+use my_lang_ast::*;
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_typechecker::Ty;
+
+/// A reference lifetime as written: `None` when elided
+type Lifetime = Option<String>;
+
+/// Indices of the parameters, `self` included, whose borrows the result of
+/// `func` may hold. Empty when the result holds no references.
+pub fn flows(cx: &Affinity, func: &Function) -> Vec<usize> {
+    let returned = match &func.return_type {
+        Some(ty) => lifetimes_in(ty),
+        None => Vec::new(),
+    };
+    let elided = returned.iter().any(Option::is_none) || (returned.is_empty() && returns_refs(cx, func));
+    let named: Vec<&String> = returned.iter().flatten().filter(|l| *l != "static").collect();
+
+    let params: Vec<Vec<Lifetime>> = func.params.iter().map(|p| lifetimes_in(&p.ty)).collect();
+    let mut flows: Vec<usize> =
+        (0..params.len()).filter(|&i| params[i].iter().flatten().any(|l| named.contains(&l))).collect();
+    // A lifetime no parameter names may still be held by a type whose lifetime arguments are implied
+    if named.iter().any(|l| !params.iter().flatten().flatten().any(|p| p == *l)) {
+        flows.extend((0..params.len()).filter(|&i| params[i].is_empty() && param_holds_refs(cx, &func.params[i])));
+    }
+    if elided {
+        flows.extend(elision_sources(cx, func));
+    }
+    flows.sort_unstable();
+    flows.dedup();
+    flows
+}
+
+/// Parameters an elided return lifetime borrows from: `self` when it is a
+/// reference, otherwise every parameter holding references
+fn elision_sources(cx: &Affinity, func: &Function) -> Vec<usize> {
+    if let Some(first) = func.params.first() {
+        if first.name == "self" && matches!(first.ty.kind, TypeKind::Reference { .. }) {
+            return vec![0];
+        }
+    }
+    (0..func.params.len()).filter(|&i| param_holds_refs(cx, &func.params[i])).collect()
+}
+
+/// Reject an elided return lifetime that could come from more than one parameter
+pub fn check_signature(cx: &Affinity, func: &Function, diags: &mut Vec<Diagnostic>) {
+    let Some(ret) = &func.return_type else {
+        return;
+    };
+    if !lifetimes_in(ret).iter().any(Option::is_none) {
+        return;
+    }
+    let sources = elision_sources(cx, func);
+    if sources.len() < 2 {
+        return;
+    }
+    let names: Vec<String> = sources.iter().map(|&i| format!("`{}`", func.params[i].name)).collect();
+    diags.push(
+        Diagnostic::error("missing lifetime specifier")
+            .with_code(codes::MISSING_LIFETIME)
+            .with_primary(ret.span, "expected a named lifetime parameter")
+            .with_note(format!(
+                "the return type borrows a value, but the signature does not say whether it is borrowed from {}",
+                names.join(" or ")
+            ))
+            .with_note("name a lifetime, as in `fn f<'a>(x: &'a T, y: &T) -> &'a T`"),
+    );
+}
+
+fn param_holds_refs(cx: &Affinity, param: &Param) -> bool {
+    !lifetimes_in(&param.ty).is_empty() || cx.types.type_of(param.id).is_some_and(|ty| cx.holds_refs(ty))
+}
+
+fn returns_refs(cx: &Affinity, func: &Function) -> bool {
+    let Some(def) = cx.res.def_of_node(func.id) else {
+        return false;
+    };
+    cx.types.sigs.get(&def).is_some_and(|sig| cx.holds_refs(&sig.output))
+}
+
+/// Lifetimes of the references written in a type, outside function types
+fn lifetimes_in(ty: &Type) -> Vec<Lifetime> {
+    let mut found = Vec::new();
+    collect(ty, &mut found);
+    found
+}
+
+fn collect(ty: &Type, found: &mut Vec<Lifetime>) {
+    match &ty.kind {
+        TypeKind::Reference { ty, lifetime, .. } => {
+            found.push(lifetime.clone());
+            collect(ty, found);
+        }
+        TypeKind::Generic { args, .. } | TypeKind::Path { args, .. } | TypeKind::Tuple(args) => {
+            for arg in args {
+                collect(arg, found);
+            }
+        }
+        TypeKind::Array { elem: ty, .. } | TypeKind::Affine(ty) | TypeKind::Fuzzy(ty) => collect(ty, found),
+        _ => {}
+    }
+}
+
+impl Affinity<'_> {
+    /// Whether a value of type `ty` may hold a reference
+    pub fn holds_refs(&self, ty: &Ty) -> bool {
+        self.holds_refs_in(ty, &mut Vec::new())
+    }
+
+    fn holds_refs_in(&self, ty: &Ty, visiting: &mut Vec<my_lang_resolve::DefId>) -> bool {
+        match ty {
+            Ty::Ref { .. } => true,
+            Ty::Tuple(elems) => elems.iter().any(|elem| self.holds_refs_in(elem, visiting)),
+            Ty::Array(elem, _) => self.holds_refs_in(elem, visiting),
+            Ty::Adt(def, args) => {
+                let Some(adt) = self.types.adts.get(def) else {
+                    return args.iter().any(|arg| self.holds_refs_in(arg, visiting));
+                };
+                if visiting.contains(def) {
+                    return false;
+                }
+                visiting.push(*def);
+                let map = adt.generics.iter().copied().zip(args.iter().cloned()).collect();
+                let holds = adt
+                    .variants
+                    .iter()
+                    .flat_map(|variant| &variant.fields)
+                    .any(|field| self.holds_refs_in(&field.ty.subst(&map), visiting));
+                visiting.pop();
+                holds
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,209 @@
+// Move analysis
+// Forward dataflow over a function's CFG: affine values are used at most once
+// and consumed on every path that returns
+
+use crate::cfg::{Cfg, Event, Local, Place};
+use my_lang_ast::Span;
+use my_lang_diagnostics::{codes, Diagnostic};
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+/// Ownership facts at one program point, each true on at least one path to it
+#[derive(Debug, Clone, Default, PartialEq)]
+struct State {
+    /// Places moved out of, with where they were moved
+    moved: BTreeMap<Place, Span>,
+    /// Affine values not yet consumed, with where they were bound or assigned
+    owned: BTreeMap<Place, Span>,
+}
+
+impl State {
+    /// Merge facts from another path, returning whether anything changed
+    fn join(&mut self, other: &State) -> bool {
+        let before = (self.moved.len(), self.owned.len());
+        for (place, span) in &other.moved {
+            self.moved.entry(place.clone()).or_insert(*span);
+        }
+        for (place, span) in &other.owned {
+            self.owned.entry(place.clone()).or_insert(*span);
+        }
+        before != (self.moved.len(), self.owned.len())
+    }
+}
+
+pub fn check(cfg: &Cfg, diags: &mut Vec<Diagnostic>) {
+    let states = solve(cfg);
+    let mut reporter = Reporter { cfg, diags, seen: HashSet::new() };
+    for node in cfg.graph.node_indices() {
+        let Some(state) = &states[node.index()] else {
+            continue;
+        };
+        let mut state = state.clone();
+        for event in &cfg.graph[node].events {
+            transfer(&mut state, event, cfg, Some(&mut reporter));
+        }
+    }
+    if let Some(exit) = &states[cfg.exit.index()] {
+        reporter.unconsumed(exit);
+    }
+}
+
+/// State on entry to every block reachable from the entry, iterated to a fixpoint
+fn solve(cfg: &Cfg) -> Vec<Option<State>> {
+    let mut states: Vec<Option<State>> = vec![None; cfg.graph.node_count()];
+    states[cfg.entry.index()] = Some(State::default());
+    let mut worklist: VecDeque<NodeIndex> = VecDeque::from([cfg.entry]);
+    while let Some(node) = worklist.pop_front() {
+        let Some(mut state) = states[node.index()].clone() else {
+            continue;
+        };
+        for event in &cfg.graph[node].events {
+            transfer(&mut state, event, cfg, None);
+        }
+        for edge in cfg.graph.edges(node) {
+            let target = edge.target();
+            let changed = match &mut states[target.index()] {
+                Some(existing) => existing.join(&state),
+                slot @ None => {
+                    *slot = Some(state.clone());
+                    true
+                }
+            };
+            if changed && !worklist.contains(&target) {
+                worklist.push_back(target);
+            }
+        }
+    }
+    states
+}
+
+fn transfer(state: &mut State, event: &Event, cfg: &Cfg, reporter: Option<&mut Reporter>) {
+    let borrowed;
+    let event = match event {
+        Event::Borrow(loan) => {
+            let loan = &cfg.loans[*loan];
+            borrowed = Event::Use(loan.place.clone(), loan.span);
+            &borrowed
+        }
+        _ => event,
+    };
+    match event {
+        Event::Use(place, span) | Event::Move(place, span) | Event::Release(place, span) => {
+            if let Some(reporter) = reporter {
+                if let Some((moved, at)) = state.moved.iter().find(|(moved, _)| moved.overlaps(place)) {
+                    reporter.use_after_move(place, moved, *at, *span);
+                }
+            }
+            if let Event::Move(..) = event {
+                state.moved.insert(place.clone(), *span);
+            }
+            if let Event::Move(..) | Event::Release(..) = event {
+                state.owned.retain(|owned, _| !owned.overlaps(place));
+            }
+        }
+        Event::Init(place, span) => {
+            if let Some(reporter) = reporter {
+                if let Some((owned, at)) = state.owned.iter().find(|(owned, _)| place.is_prefix_of(owned)) {
+                    reporter.overwritten(owned, *at, *span);
+                }
+            }
+            state.moved.retain(|moved, _| !place.is_prefix_of(moved));
+            state.owned.retain(|owned, _| !place.is_prefix_of(owned));
+            let local = &cfg.locals[&place.local];
+            for resource in &local.resources {
+                let resource = Place { local: place.local, projection: resource.clone() };
+                if place.is_prefix_of(&resource) {
+                    state.owned.insert(resource, *span);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+struct Reporter<'c, 'd> {
+    cfg: &'c Cfg,
+    diags: &'d mut Vec<Diagnostic>,
+    /// Spans already reported, so each use is reported once
+    seen: HashSet<Span>,
+}
+
+impl Reporter<'_, '_> {
+    fn local(&self, place: &Place) -> &Local {
+        &self.cfg.locals[&place.local]
+    }
+
+    fn use_after_move(&mut self, place: &Place, moved: &Place, moved_at: Span, used_at: Span) {
+        if !self.seen.insert(used_at) {
+            return;
+        }
+        let local = self.local(place);
+        let name = local.display(&place.projection);
+        let partly = moved.projection.len() > place.projection.len();
+        let message = if partly {
+            format!("use of partially moved value: `{}`", name)
+        } else {
+            format!("use of moved value: `{}`", name)
+        };
+        let moved_label = if moved_at == used_at {
+            "value moved here, in the previous iteration of the loop".to_string()
+        } else if partly {
+            format!("`{}` moved here", local.display(&moved.projection))
+        } else {
+            "value moved here".to_string()
+        };
+        let mut diag = Diagnostic::error(message)
+            .with_code(codes::USE_AFTER_MOVE)
+            .with_primary(used_at, "value used here after move");
+        if moved_at != used_at {
+            diag = diag.with_secondary(moved_at, moved_label);
+        } else {
+            diag = diag.with_note(moved_label);
+        }
+        self.diags.push(diag.with_note(format!(
+            "`{}` has type `{}`, which holds an affine value and cannot be copied",
+            local.name, local.ty
+        )));
+    }
+
+    fn overwritten(&mut self, owned: &Place, bound_at: Span, assigned_at: Span) {
+        if !self.seen.insert(assigned_at) {
+            return;
+        }
+        let name = self.local(owned).display(&owned.projection);
+        let mut diag = Diagnostic::error(format!("affine value `{}` is overwritten before it is consumed", name))
+            .with_code(codes::AFFINE_NOT_CONSUMED)
+            .with_primary(assigned_at, "the previous value is lost here");
+        if bound_at != assigned_at {
+            diag = diag.with_secondary(bound_at, "value assigned here");
+        }
+        self.diags.push(diag.with_note(format!("consume `{}` first, or call `drop({})`", name, name)));
+    }
+
+    /// Affine values still owned when the function returns, reported once per binding
+    fn unconsumed(&mut self, exit: &State) {
+        let mut reported = HashSet::new();
+        for (owned, bound_at) in &exit.owned {
+            if !reported.insert(owned.local) {
+                continue;
+            }
+            let local = self.local(owned);
+            let sometimes = exit.moved.keys().any(|moved| moved.overlaps(owned));
+            let (message, label) = if sometimes {
+                (format!("affine value `{}` is not consumed on every path", local.name), "not consumed on some paths")
+            } else {
+                (format!("affine value `{}` is never consumed", local.name), "this value must be consumed or dropped")
+            };
+            let mut diag =
+                Diagnostic::error(message).with_code(codes::AFFINE_NOT_CONSUMED).with_primary(*bound_at, label);
+            if !owned.projection.is_empty() {
+                diag = diag.with_note(format!("`{}` is affine", local.display(&owned.projection)));
+            }
+            self.diags.push(diag.with_note(format!(
+                "pass `{}` to a function that takes it by value, or call `drop({})`",
+                local.name, local.name
+            )));
+        }
+    }
+}
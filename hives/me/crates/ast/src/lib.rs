@@ -1,15 +1,61 @@
 // Abstract Syntax Tree for Solo/Duet/Ensemble
 
+pub use my_lang_lexer::{FileId, Span};
+
 use serde::{Deserialize, Serialize};
-use std::fmt;
+
+/// Identifies an AST node; assigned by the parser in source order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub struct NodeId(pub u32);
+
+impl NodeId {
+    /// Id for nodes synthesized after parsing
+    pub const DUMMY: NodeId = NodeId(u32::MAX);
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Program {
+    pub id: NodeId,
+    pub span: Span,
     pub items: Vec<Item>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum Item {
+pub struct Item {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: ItemKind,
+}
+
+impl Item {
+    pub fn new(id: NodeId, kind: ItemKind, span: Span) -> Self {
+        Self { id, span, kind }
+    }
+}
+
+impl ItemKind {
+    /// Declared visibility; `None` for impl and `comptime` blocks, which have none, and for error placeholders
+    pub fn visibility(&self) -> Option<Visibility> {
+        match self {
+            ItemKind::Function(f) => Some(f.visibility),
+            ItemKind::Struct(s) => Some(s.visibility),
+            ItemKind::Enum(e) => Some(e.visibility),
+            ItemKind::Trait(t) => Some(t.visibility),
+            ItemKind::Module(m) => Some(m.visibility),
+            ItemKind::Import(i) => Some(i.visibility),
+            ItemKind::Const(c) => Some(c.visibility),
+            ItemKind::Type(alias) => Some(alias.visibility),
+            ItemKind::SynthFunction(sf) => Some(sf.func.visibility),
+            ItemKind::VerifyFunction(vf) => Some(vf.func.visibility),
+            ItemKind::Agent(a) => Some(a.visibility),
+            ItemKind::Workflow(w) => Some(w.visibility),
+            ItemKind::Impl(_) | ItemKind::Comptime(_) | ItemKind::Error => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ItemKind {
     Function(Function),
     Struct(Struct),
     Enum(Enum),
@@ -19,17 +65,25 @@ pub enum Item {
     Import(Import),
     Const(Const),
     Type(TypeAlias),
+    /// A top-level `comptime { ... }` block, run once during compilation for
+    /// its effects such as assertions. Holds the `comptime` expression.
+    Comptime(Expression),
     // Duet-specific
     SynthFunction(SynthFunction),
     VerifyFunction(VerifyFunction),
     // Ensemble-specific
     Agent(Agent),
     Workflow(Workflow),
+    /// Placeholder for an item that failed to parse
+    Error,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Function {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
+    pub visibility: Visibility,
     pub generics: Vec<Generic>,
     pub params: Vec<Param>,
     pub return_type: Option<Type>,
@@ -43,6 +97,8 @@ pub struct Function {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Param {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
     pub ty: Type,
     pub is_mut: bool,
@@ -50,12 +106,27 @@ pub struct Param {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Block {
+    pub id: NodeId,
+    pub span: Span,
     pub stmts: Vec<Statement>,
     pub expr: Option<Box<Expression>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum Statement {
+pub struct Statement {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: StatementKind,
+}
+
+impl Statement {
+    pub fn new(id: NodeId, kind: StatementKind, span: Span) -> Self {
+        Self { id, span, kind }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StatementKind {
     Let {
         pattern: Pattern,
         ty: Option<Type>,
@@ -64,12 +135,29 @@ pub enum Statement {
     },
     Expression(Expression),
     Item(Box<Item>),
+    /// Placeholder for a statement that failed to parse
+    Error,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum Expression {
+pub struct Expression {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: ExpressionKind,
+}
+
+impl Expression {
+    pub fn new(id: NodeId, kind: ExpressionKind, span: Span) -> Self {
+        Self { id, span, kind }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ExpressionKind {
     Literal(Literal),
     Identifier(String),
+    /// A path with more than one segment, such as `io::println` or `Color::Red`
+    Path(Vec<String>),
     Binary {
         left: Box<Expression>,
         op: BinaryOp,
@@ -122,9 +210,19 @@ pub enum Expression {
         field: String,
     },
     Struct {
-        name: String,
+        path: Vec<String>,
         fields: Vec<(String, Expression)>,
     },
+    /// `comptime expr` or `comptime { ... }`, evaluated during compilation
+    Comptime(Box<Expression>),
+    /// `|a, b: i32| a + b` or `move || { ... }`. Parameters written without a
+    /// type have type `Inferred`.
+    Closure {
+        params: Vec<Param>,
+        ret: Option<Type>,
+        body: Box<Expression>,
+        is_move: bool,
+    },
     // Duet-specific
     Intent {
         description: String,
@@ -163,17 +261,36 @@ pub enum Expression {
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum Type {
+pub struct Type {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: TypeKind,
+}
+
+impl Type {
+    pub fn new(id: NodeId, kind: TypeKind, span: Span) -> Self {
+        Self { id, span, kind }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TypeKind {
     Primitive(PrimitiveType),
     Named(String),
     Generic {
         name: String,
         args: Vec<Type>,
     },
+    /// A type named by a path with more than one segment, such as `io::File<T>`
+    Path {
+        path: Vec<String>,
+        args: Vec<Type>,
+    },
     Tuple(Vec<Type>),
+    /// `[T; size]`, where the size is any constant expression, or `[T]`
     Array {
         elem: Box<Type>,
-        size: Option<usize>,
+        size: Option<Box<Expression>>,
     },
     Reference {
         ty: Box<Type>,
@@ -191,7 +308,7 @@ pub enum Type {
     Fuzzy(Box<Type>),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum PrimitiveType {
     I8, I16, I32, I64, I128, Isize,
     U8, U16, U32, U64, U128, Usize,
@@ -228,19 +345,41 @@ pub enum UnaryOp {
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum Pattern {
+pub struct Pattern {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: PatternKind,
+}
+
+impl Pattern {
+    pub fn new(id: NodeId, kind: PatternKind, span: Span) -> Self {
+        Self { id, span, kind }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PatternKind {
     Wildcard,
     Identifier(String),
     Literal(Literal),
     Tuple(Vec<Pattern>),
     Struct {
-        name: String,
+        path: Vec<String>,
         fields: Vec<(String, Pattern)>,
     },
+    /// A tuple struct or variant, such as `Some(x)`
+    TupleStruct {
+        path: Vec<String>,
+        elems: Vec<Pattern>,
+    },
+    /// A unit variant or constant named by a path, such as `Color::Red`
+    Path(Vec<String>),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MatchArm {
+    pub id: NodeId,
+    pub span: Span,
     pub pattern: Pattern,
     pub guard: Option<Expression>,
     pub body: Expression,
@@ -248,6 +387,8 @@ pub struct MatchArm {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Contract {
+    pub id: NodeId,
+    pub span: Span,
     pub preconditions: Vec<Expression>,
     pub postconditions: Vec<Expression>,
     pub invariants: Vec<Expression>,
@@ -255,7 +396,10 @@ pub struct Contract {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Struct {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
+    pub visibility: Visibility,
     pub generics: Vec<Generic>,
     pub fields: Vec<Field>,
     pub where_clause: Option<WhereClause>,
@@ -263,6 +407,8 @@ pub struct Struct {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Field {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
     pub ty: Type,
     pub visibility: Visibility,
@@ -270,13 +416,18 @@ pub struct Field {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Enum {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
+    pub visibility: Visibility,
     pub generics: Vec<Generic>,
     pub variants: Vec<Variant>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Variant {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
     pub data: VariantData,
 }
@@ -290,23 +441,34 @@ pub enum VariantData {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Trait {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
+    pub visibility: Visibility,
     pub generics: Vec<Generic>,
     pub items: Vec<TraitItem>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TraitItem {
+    /// A method with a default body
     Function(Function),
+    /// A method declared as `fn name(..);`, which every impl must provide; its body is empty
+    Signature(Function),
     Type(String),
     Const(Const),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Impl {
+    pub id: NodeId,
+    pub span: Span,
     pub generics: Vec<Generic>,
     pub trait_name: Option<String>,
     pub self_ty: Type,
+    /// `where invariant ...` clauses, checked on exit from every public method
+    /// taking `&mut self` or `self`. Only `invariants` is ever set.
+    pub contract: Option<Contract>,
     pub items: Vec<ImplItem>,
 }
 
@@ -319,63 +481,143 @@ pub enum ImplItem {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Module {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
+    pub visibility: Visibility,
+    /// False for `mod name;`, whose items are loaded from `name.solo` or `name/mod.solo`
+    pub inline: bool,
     pub items: Vec<Item>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Import {
-    pub path: Vec<String>,
-    pub alias: Option<String>,
+    pub id: NodeId,
+    pub span: Span,
+    pub visibility: Visibility,
+    pub tree: ImportTree,
+}
+
+impl Import {
+    /// Every name the import binds, with its full path. A `self` closing a
+    /// group path, as in `a::{self, b}`, imports the group's prefix.
+    pub fn leaves(&self) -> Vec<(Vec<String>, &ImportTree)> {
+        let mut leaves = Vec::new();
+        self.tree.collect_leaves(&[], &mut leaves);
+        leaves
+    }
+}
+
+/// `a::b`, `a::b as c` or `a::{b, c as d}`; groups may nest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportTree {
+    pub id: NodeId,
+    pub span: Span,
+    /// Path segments before the alias or group
+    pub prefix: Vec<String>,
+    pub kind: ImportTreeKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ImportTreeKind {
+    /// Binds the last segment of the path, or the alias
+    Simple { alias: Option<String> },
+    Group(Vec<ImportTree>),
+}
+
+impl ImportTree {
+    pub fn alias(&self) -> Option<&str> {
+        match &self.kind {
+            ImportTreeKind::Simple { alias } => alias.as_deref(),
+            ImportTreeKind::Group(_) => None,
+        }
+    }
+
+    fn collect_leaves<'a>(&'a self, parent: &[String], leaves: &mut Vec<(Vec<String>, &'a ImportTree)>) {
+        let mut path = parent.to_vec();
+        path.extend(self.prefix.iter().cloned());
+        match &self.kind {
+            ImportTreeKind::Simple { .. } => {
+                if path.len() > 1 && path.last().is_some_and(|s| s == "self") && !parent.is_empty() {
+                    path.pop();
+                }
+                leaves.push((path, self));
+            }
+            ImportTreeKind::Group(trees) => {
+                for tree in trees {
+                    tree.collect_leaves(&path, leaves);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Const {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
+    pub visibility: Visibility,
     pub ty: Type,
     pub value: Expression,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TypeAlias {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
+    pub visibility: Visibility,
     pub generics: Vec<Generic>,
     pub ty: Type,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Generic {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
     pub bounds: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WhereClause {
+    pub id: NodeId,
+    pub span: Span,
     pub predicates: Vec<WherePredicate>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WherePredicate {
+    pub id: NodeId,
+    pub span: Span,
     pub ty: Type,
     pub bounds: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Attribute {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
     pub args: Vec<(String, Expression)>,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Visibility {
+    /// `pub`
     Public,
+    /// No qualifier: visible in the defining module and its descendants
     Private,
+    /// `pub(crate)`
     Crate,
 }
 
 // Duet-specific AST nodes
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SynthFunction {
+    pub id: NodeId,
+    pub span: Span,
     pub func: Function,
     pub spec: Option<Expression>,
     pub examples: Vec<(Expression, Expression)>,
@@ -383,6 +625,8 @@ pub struct SynthFunction {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VerifyFunction {
+    pub id: NodeId,
+    pub span: Span,
     pub func: Function,
     pub property: Expression,
     pub method: String,
@@ -399,7 +643,10 @@ pub enum FusionStrategy {
 // Ensemble-specific AST nodes
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Agent {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
+    pub visibility: Visibility,
     pub state: Vec<StateField>,
     pub capabilities: Vec<Capability>,
     pub goals: Vec<Goal>,
@@ -408,6 +655,8 @@ pub struct Agent {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct StateField {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
     pub ty: Type,
     pub persistence: Persistence,
@@ -422,6 +671,8 @@ pub enum Persistence {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Capability {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
     pub params: Vec<Param>,
     pub return_type: Type,
@@ -439,6 +690,8 @@ pub enum CapabilityKind {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Goal {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
     pub expr: Expression,
     pub priority: Priority,
@@ -453,12 +706,27 @@ pub enum Priority {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CommunicationHandler {
+    pub id: NodeId,
+    pub span: Span,
     pub pattern: MessagePattern,
     pub handler: Block,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum MessagePattern {
+pub struct MessagePattern {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: MessagePatternKind,
+}
+
+impl MessagePattern {
+    pub fn new(id: NodeId, kind: MessagePatternKind, span: Span) -> Self {
+        Self { id, span, kind }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MessagePatternKind {
     Type(String),
     From(String),
     Any,
@@ -466,24 +734,53 @@ pub enum MessagePattern {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Workflow {
+    pub id: NodeId,
+    pub span: Span,
     pub name: String,
+    pub visibility: Visibility,
     pub stages: Vec<Stage>,
     pub coordination: Vec<CoordinationRule>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum Stage {
+pub struct Stage {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: StageKind,
+}
+
+impl Stage {
+    pub fn new(id: NodeId, kind: StageKind, span: Span) -> Self {
+        Self { id, span, kind }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StageKind {
     Agent(String),
     Parallel(Vec<Stage>),
     Conditional {
-        cond: Expression,
+        cond: Box<Expression>,
         then_stage: Box<Stage>,
         else_stage: Option<Box<Stage>>,
     },
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum CoordinationRule {
+pub struct CoordinationRule {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: CoordinationRuleKind,
+}
+
+impl CoordinationRule {
+    pub fn new(id: NodeId, kind: CoordinationRuleKind, span: Span) -> Self {
+        Self { id, span, kind }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CoordinationRuleKind {
     Consensus {
         threshold: f64,
         on: Expression,
@@ -0,0 +1,1347 @@
+// Bytecode
+// The register bytecode the virtual machine runs, and its versioned file
+// format. Structs, variants and functions keep the ids name resolution gave
+// them, so values built from a file compare the way the crate's do.
+
+use my_lang_ast::{BinaryOp, PrimitiveType, UnaryOp};
+use std::fmt;
+use thiserror::Error;
+
+/// First bytes of every bytecode file
+pub const MAGIC: [u8; 4] = *b"MLBC";
+
+/// Format version, raised whenever the encoding changes
+pub const VERSION: u16 = 2;
+
+/// Index of a register in the frame of the running function
+pub type Reg = u32;
+
+/// A compiled crate
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Module {
+    /// Source files, which locations refer to by index
+    pub files: Vec<String>,
+    /// Literals and names the code refers to
+    pub consts: Vec<Const>,
+    /// Static types that library calls consult, such as the target of `collect`
+    pub types: Vec<Type>,
+    /// Structs and enum variants
+    pub ctors: Vec<Ctor>,
+    pub patterns: Vec<Pattern>,
+    /// Method calls, with what type checking knew about them
+    pub sites: Vec<MethodSite>,
+    pub functions: Vec<Function>,
+    /// Constant items, computed on first use
+    pub globals: Vec<Global>,
+    /// Functions of impls, for calls dispatched on the receiver's value
+    pub methods: Vec<ImplMethod>,
+    /// Functions declared by traits
+    pub trait_fns: Vec<TraitFn>,
+    /// Library types impls may be written for, by name
+    pub library: Vec<(String, u32)>,
+    /// `Some`, `None`, `Ok` and `Err`
+    pub variants: [u32; 4],
+    /// Index of `main` in `functions`
+    pub entry: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Const {
+    Unit,
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    Char(char),
+    Str(String),
+}
+
+/// A type as the backends see it, with type parameters left `Unknown`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Type {
+    Unknown,
+    Prim(PrimitiveType),
+    Tuple(Vec<Type>),
+    Array(Box<Type>),
+    Ref(Box<Type>),
+    Fn,
+    /// A struct or enum of the crate or the library, with its type arguments
+    Adt {
+        def: u32,
+        name: String,
+        args: Vec<Type>,
+    },
+}
+
+impl Type {
+    pub fn unit() -> Type {
+        Type::Tuple(Vec::new())
+    }
+
+    /// The primitive type behind any references
+    pub fn prim(&self) -> Option<PrimitiveType> {
+        match self {
+            Type::Prim(prim) => Some(*prim),
+            Type::Ref(inner) => inner.prim(),
+            _ => None,
+        }
+    }
+}
+
+/// A struct or enum variant
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ctor {
+    pub def: u32,
+    pub name: String,
+    /// Field names in declaration order; positions for tuple structs and variants
+    pub fields: Vec<String>,
+    /// The enum of a variant
+    pub parent: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Wildcard,
+    /// Store the matched value in a register, by reference when reached through one
+    Bind(Reg),
+    Literal(u32),
+    /// Equal to constant item `globals[index]`
+    Global(u32),
+    Tuple(Vec<Pattern>),
+    /// A struct or variant, with the number of fields a tuple pattern names
+    Ctor {
+        def: u32,
+        fields: Vec<(String, Pattern)>,
+        arity: Option<u32>,
+    },
+}
+
+/// What type checking knew about a method call
+#[derive(Debug, Clone, PartialEq)]
+pub struct MethodSite {
+    pub name: String,
+    /// Function the call resolved to, when the crate defines it
+    pub resolved: Option<u32>,
+    /// Impls that apply to the receiver's static type, most specific first
+    pub keys: Vec<TypeKey>,
+    /// Static type of the result, as `types[ty]`
+    pub ty: u32,
+}
+
+/// What impls are keyed by: a struct or enum of the crate or the library, or a primitive type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TypeKey {
+    Def(u32),
+    Prim(PrimitiveType),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// `Type::method` for functions of impls and traits, for backtraces
+    pub name: String,
+    pub def: u32,
+    pub params: u32,
+    pub registers: u32,
+    /// For a closure, the number of registers after the parameters that hold its captures
+    pub captures: Option<u32>,
+    /// Whether the first parameter is a reference, so that method calls pass their receiver's place
+    pub self_ref: bool,
+    pub code: Vec<Instr>,
+    /// Source location of each instruction
+    pub locs: Vec<Loc>,
+    /// Where the function is declared
+    pub loc: Loc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Loc {
+    pub file: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub def: u32,
+    pub name: String,
+    /// Function without parameters computing the value
+    pub init: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImplMethod {
+    pub key: TypeKey,
+    pub trait_def: Option<u32>,
+    pub name: String,
+    pub def: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraitFn {
+    pub def: u32,
+    pub trait_def: u32,
+    pub name: String,
+}
+
+/// One instruction. Argument lists are `count` consecutive registers from `start`.
+/// Places are registers holding a reference to them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    /// `dst = consts[index]`
+    Const {
+        dst: Reg,
+        index: u32,
+    },
+    Move {
+        dst: Reg,
+        src: Reg,
+    },
+    /// A function, struct or variant of the crate as a value
+    Def {
+        dst: Reg,
+        def: u32,
+    },
+    /// A library function as a value, named by its path in `consts`
+    Library {
+        dst: Reg,
+        path: u32,
+    },
+    /// Value of constant item `globals[index]`
+    Global {
+        dst: Reg,
+        index: u32,
+    },
+    Tuple {
+        dst: Reg,
+        start: Reg,
+        count: u32,
+    },
+    List {
+        dst: Reg,
+        start: Reg,
+        count: u32,
+    },
+    /// Struct or variant `ctors[ctor]`, its fields in declaration order
+    Adt {
+        dst: Reg,
+        ctor: u32,
+        start: Reg,
+        count: u32,
+    },
+    /// `Neg` or `Not`, with the type of the result when it is primitive
+    Unary {
+        op: UnaryOp,
+        ty: Option<PrimitiveType>,
+        dst: Reg,
+        src: Reg,
+    },
+    /// Arithmetic, bitwise or comparison, with the type of the result when it is primitive
+    Binary {
+        op: BinaryOp,
+        ty: Option<PrimitiveType>,
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Jump {
+        target: u32,
+    },
+    JumpIf {
+        cond: Reg,
+        target: u32,
+    },
+    JumpUnless {
+        cond: Reg,
+        target: u32,
+    },
+    /// Call `functions[func]`
+    Call {
+        dst: Reg,
+        func: u32,
+        start: Reg,
+        count: u32,
+    },
+    /// Call the function value in `callee`
+    CallValue {
+        dst: Reg,
+        callee: Reg,
+        start: Reg,
+        count: u32,
+    },
+    /// Call a prelude or library function by its path in `consts`, returning `types[ty]`
+    CallLibrary {
+        dst: Reg,
+        path: u32,
+        ty: u32,
+        start: Reg,
+        count: u32,
+    },
+    /// Method call `sites[site]` on the place in `recv`
+    Method {
+        dst: Reg,
+        site: u32,
+        recv: Reg,
+        start: Reg,
+        count: u32,
+    },
+    Return {
+        src: Reg,
+    },
+    /// Reference to register `src`
+    Addr {
+        dst: Reg,
+        src: Reg,
+    },
+    /// Reference to a copy of `src`, for a temporary
+    Temp {
+        dst: Reg,
+        src: Reg,
+    },
+    /// Reference to field `consts[name]` of the place in `base`
+    FieldAddr {
+        dst: Reg,
+        base: Reg,
+        name: u32,
+    },
+    /// Reference to an element or map entry of the place in `base`
+    IndexAddr {
+        dst: Reg,
+        base: Reg,
+        index: Reg,
+    },
+    /// Reference to what the place in `src` refers to
+    Deref {
+        dst: Reg,
+        src: Reg,
+    },
+    /// Value at the place in `src`
+    Load {
+        dst: Reg,
+        src: Reg,
+    },
+    /// Write `src` to the place in `dst`
+    Store {
+        dst: Reg,
+        src: Reg,
+    },
+    /// Whether the value at the place in `place` matches `patterns[pattern]`, binding its registers
+    Match {
+        dst: Reg,
+        place: Reg,
+        pattern: u32,
+    },
+    /// Start a `for` loop over `src`
+    Iter {
+        dst: Reg,
+        src: Reg,
+    },
+    /// Next item of the loop in `iter`, or a jump to `target` when it is done
+    Next {
+        dst: Reg,
+        iter: Reg,
+        target: u32,
+    },
+    /// Stop on something the machine cannot run, explained in `consts`
+    Unsupported {
+        message: u32,
+    },
+    /// Closure `functions[func]`, sharing the registers in `captures` with this frame
+    Closure {
+        dst: Reg,
+        func: u32,
+        captures: Vec<Reg>,
+    },
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    #[error("not a bytecode file")]
+    BadMagic,
+
+    #[error("bytecode version {found} is not supported; this build reads version {expected}")]
+    UnsupportedVersion { found: u16, expected: u16 },
+
+    #[error("bytecode file ends unexpectedly")]
+    Truncated,
+
+    #[error("malformed bytecode: {0}")]
+    Invalid(String),
+}
+
+/// Whether `bytes` start like a bytecode file
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// The values an integer type holds, or `None` for types that are not integers
+pub fn int_range(prim: PrimitiveType) -> Option<(i128, i128)> {
+    Some(match prim {
+        PrimitiveType::I8 => (i8::MIN.into(), i8::MAX.into()),
+        PrimitiveType::I16 => (i16::MIN.into(), i16::MAX.into()),
+        PrimitiveType::I32 => (i32::MIN.into(), i32::MAX.into()),
+        PrimitiveType::I64 | PrimitiveType::Isize => (i64::MIN.into(), i64::MAX.into()),
+        PrimitiveType::I128 => (i128::MIN, i128::MAX),
+        PrimitiveType::U8 => (0, u8::MAX.into()),
+        PrimitiveType::U16 => (0, u16::MAX.into()),
+        PrimitiveType::U32 => (0, u32::MAX.into()),
+        PrimitiveType::U64 | PrimitiveType::Usize => (0, u64::MAX.into()),
+        PrimitiveType::U128 => (0, i128::MAX),
+        _ => return None,
+    })
+}
+
+// ========== Encoding ==========
+
+const PRIMS: [PrimitiveType; 19] = [
+    PrimitiveType::I8,
+    PrimitiveType::I16,
+    PrimitiveType::I32,
+    PrimitiveType::I64,
+    PrimitiveType::I128,
+    PrimitiveType::Isize,
+    PrimitiveType::U8,
+    PrimitiveType::U16,
+    PrimitiveType::U32,
+    PrimitiveType::U64,
+    PrimitiveType::U128,
+    PrimitiveType::Usize,
+    PrimitiveType::F32,
+    PrimitiveType::F64,
+    PrimitiveType::Bool,
+    PrimitiveType::Char,
+    PrimitiveType::Str,
+    PrimitiveType::Unit,
+    PrimitiveType::Never,
+];
+
+const BINARY_OPS: [BinaryOp; 19] = [
+    BinaryOp::Add,
+    BinaryOp::Sub,
+    BinaryOp::Mul,
+    BinaryOp::Div,
+    BinaryOp::Mod,
+    BinaryOp::Eq,
+    BinaryOp::Ne,
+    BinaryOp::Lt,
+    BinaryOp::Gt,
+    BinaryOp::Le,
+    BinaryOp::Ge,
+    BinaryOp::And,
+    BinaryOp::Or,
+    BinaryOp::BitAnd,
+    BinaryOp::BitOr,
+    BinaryOp::BitXor,
+    BinaryOp::Shl,
+    BinaryOp::Shr,
+    BinaryOp::Assign,
+];
+
+const UNARY_OPS: [UnaryOp; 5] = [UnaryOp::Neg, UnaryOp::Not, UnaryOp::Deref, UnaryOp::Ref, UnaryOp::RefMut];
+
+/// Appends values to a byte buffer. Integers are LEB128, signed ones zigzagged first.
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn byte(&mut self, b: u8) {
+        self.bytes.push(b);
+    }
+
+    fn uint(&mut self, mut n: u128) {
+        loop {
+            let low = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                self.byte(low);
+                return;
+            }
+            self.byte(low | 0x80);
+        }
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.uint(n.into());
+    }
+
+    fn len(&mut self, n: usize) {
+        self.uint(n as u128);
+    }
+
+    fn int(&mut self, n: i128) {
+        self.uint(((n << 1) ^ (n >> 127)) as u128);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn opt(&mut self, n: Option<u32>) {
+        match n {
+            Some(n) => self.uint(u128::from(n) + 1),
+            None => self.byte(0),
+        }
+    }
+
+    fn prim(&mut self, prim: PrimitiveType) {
+        self.byte(PRIMS.iter().position(|p| *p == prim).unwrap_or(0) as u8);
+    }
+
+    fn opt_prim(&mut self, prim: Option<PrimitiveType>) {
+        match prim {
+            Some(prim) => {
+                self.byte(1);
+                self.prim(prim);
+            }
+            None => self.byte(0),
+        }
+    }
+
+    fn list<T>(&mut self, items: &[T], mut each: impl FnMut(&mut Self, &T)) {
+        self.len(items.len());
+        for item in items {
+            each(self, item);
+        }
+    }
+
+    fn constant(&mut self, c: &Const) {
+        match c {
+            Const::Unit => self.byte(0),
+            Const::Bool(b) => {
+                self.byte(1);
+                self.byte(*b as u8);
+            }
+            Const::Int(n) => {
+                self.byte(2);
+                self.int(*n);
+            }
+            Const::Float(x) => {
+                self.byte(3);
+                self.bytes.extend_from_slice(&x.to_le_bytes());
+            }
+            Const::Char(c) => {
+                self.byte(4);
+                self.u32(*c as u32);
+            }
+            Const::Str(s) => {
+                self.byte(5);
+                self.str(s);
+            }
+        }
+    }
+
+    fn ty(&mut self, ty: &Type) {
+        match ty {
+            Type::Unknown => self.byte(0),
+            Type::Prim(prim) => {
+                self.byte(1);
+                self.prim(*prim);
+            }
+            Type::Tuple(elems) => {
+                self.byte(2);
+                self.list(elems, Self::ty);
+            }
+            Type::Array(elem) => {
+                self.byte(3);
+                self.ty(elem);
+            }
+            Type::Ref(inner) => {
+                self.byte(4);
+                self.ty(inner);
+            }
+            Type::Fn => self.byte(5),
+            Type::Adt { def, name, args } => {
+                self.byte(6);
+                self.u32(*def);
+                self.str(name);
+                self.list(args, Self::ty);
+            }
+        }
+    }
+
+    fn key(&mut self, key: &TypeKey) {
+        match key {
+            TypeKey::Def(def) => {
+                self.byte(0);
+                self.u32(*def);
+            }
+            TypeKey::Prim(prim) => {
+                self.byte(1);
+                self.prim(*prim);
+            }
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Wildcard => self.byte(0),
+            Pattern::Bind(reg) => {
+                self.byte(1);
+                self.u32(*reg);
+            }
+            Pattern::Literal(index) => {
+                self.byte(2);
+                self.u32(*index);
+            }
+            Pattern::Global(index) => {
+                self.byte(3);
+                self.u32(*index);
+            }
+            Pattern::Tuple(elems) => {
+                self.byte(4);
+                self.list(elems, Self::pattern);
+            }
+            Pattern::Ctor { def, fields, arity } => {
+                self.byte(5);
+                self.u32(*def);
+                self.list(fields, |w, (name, pattern)| {
+                    w.str(name);
+                    w.pattern(pattern);
+                });
+                self.opt(*arity);
+            }
+        }
+    }
+
+    fn regs(&mut self, regs: &[u32]) {
+        for reg in regs {
+            self.u32(*reg);
+        }
+    }
+
+    fn instr(&mut self, instr: &Instr) {
+        use Instr::*;
+        match instr {
+            Const { dst, index } => {
+                self.byte(0);
+                self.regs(&[*dst, *index]);
+            }
+            Move { dst, src } => {
+                self.byte(1);
+                self.regs(&[*dst, *src]);
+            }
+            Def { dst, def } => {
+                self.byte(2);
+                self.regs(&[*dst, *def]);
+            }
+            Library { dst, path } => {
+                self.byte(3);
+                self.regs(&[*dst, *path]);
+            }
+            Global { dst, index } => {
+                self.byte(4);
+                self.regs(&[*dst, *index]);
+            }
+            Tuple { dst, start, count } => {
+                self.byte(5);
+                self.regs(&[*dst, *start, *count]);
+            }
+            List { dst, start, count } => {
+                self.byte(6);
+                self.regs(&[*dst, *start, *count]);
+            }
+            Adt { dst, ctor, start, count } => {
+                self.byte(7);
+                self.regs(&[*dst, *ctor, *start, *count]);
+            }
+            Unary { op, ty, dst, src } => {
+                self.byte(8);
+                self.byte(UNARY_OPS.iter().position(|o| o == op).unwrap_or(0) as u8);
+                self.opt_prim(*ty);
+                self.regs(&[*dst, *src]);
+            }
+            Binary { op, ty, dst, lhs, rhs } => {
+                self.byte(9);
+                self.byte(BINARY_OPS.iter().position(|o| o == op).unwrap_or(0) as u8);
+                self.opt_prim(*ty);
+                self.regs(&[*dst, *lhs, *rhs]);
+            }
+            Jump { target } => {
+                self.byte(10);
+                self.u32(*target);
+            }
+            JumpIf { cond, target } => {
+                self.byte(11);
+                self.regs(&[*cond, *target]);
+            }
+            JumpUnless { cond, target } => {
+                self.byte(12);
+                self.regs(&[*cond, *target]);
+            }
+            Call { dst, func, start, count } => {
+                self.byte(13);
+                self.regs(&[*dst, *func, *start, *count]);
+            }
+            CallValue { dst, callee, start, count } => {
+                self.byte(14);
+                self.regs(&[*dst, *callee, *start, *count]);
+            }
+            CallLibrary { dst, path, ty, start, count } => {
+                self.byte(15);
+                self.regs(&[*dst, *path, *ty, *start, *count]);
+            }
+            Method { dst, site, recv, start, count } => {
+                self.byte(16);
+                self.regs(&[*dst, *site, *recv, *start, *count]);
+            }
+            Return { src } => {
+                self.byte(17);
+                self.u32(*src);
+            }
+            Addr { dst, src } => {
+                self.byte(18);
+                self.regs(&[*dst, *src]);
+            }
+            Temp { dst, src } => {
+                self.byte(19);
+                self.regs(&[*dst, *src]);
+            }
+            FieldAddr { dst, base, name } => {
+                self.byte(20);
+                self.regs(&[*dst, *base, *name]);
+            }
+            IndexAddr { dst, base, index } => {
+                self.byte(21);
+                self.regs(&[*dst, *base, *index]);
+            }
+            Deref { dst, src } => {
+                self.byte(22);
+                self.regs(&[*dst, *src]);
+            }
+            Load { dst, src } => {
+                self.byte(23);
+                self.regs(&[*dst, *src]);
+            }
+            Store { dst, src } => {
+                self.byte(24);
+                self.regs(&[*dst, *src]);
+            }
+            Match { dst, place, pattern } => {
+                self.byte(25);
+                self.regs(&[*dst, *place, *pattern]);
+            }
+            Iter { dst, src } => {
+                self.byte(26);
+                self.regs(&[*dst, *src]);
+            }
+            Next { dst, iter, target } => {
+                self.byte(27);
+                self.regs(&[*dst, *iter, *target]);
+            }
+            Unsupported { message } => {
+                self.byte(28);
+                self.u32(*message);
+            }
+            Closure { dst, func, captures } => {
+                self.byte(29);
+                self.regs(&[*dst, *func]);
+                self.list(captures, |w, reg| w.u32(*reg));
+            }
+        }
+    }
+
+    fn loc(&mut self, loc: &Loc) {
+        self.regs(&[loc.file, loc.line, loc.column]);
+    }
+
+    fn function(&mut self, func: &Function) {
+        self.str(&func.name);
+        self.regs(&[func.def, func.params, func.registers]);
+        self.opt(func.captures);
+        self.byte(func.self_ref as u8);
+        self.loc(&func.loc);
+        self.list(&func.code, Self::instr);
+        // Locations repeat from one instruction to the next, so only changes are written
+        let mut last = Loc::default();
+        self.list(&func.locs, |w, loc| {
+            w.int(i128::from(loc.line) - i128::from(last.line));
+            w.int(i128::from(loc.column) - i128::from(last.column));
+            w.u32(loc.file);
+            last = *loc;
+        });
+    }
+}
+
+/// Encode `module` as a bytecode file
+pub fn encode(module: &Module) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.bytes.extend_from_slice(&MAGIC);
+    w.bytes.extend_from_slice(&VERSION.to_le_bytes());
+    w.list(&module.files, |w, f| w.str(f));
+    w.list(&module.consts, Writer::constant);
+    w.list(&module.types, Writer::ty);
+    w.list(&module.ctors, |w, ctor| {
+        w.u32(ctor.def);
+        w.str(&ctor.name);
+        w.list(&ctor.fields, |w, f| w.str(f));
+        w.opt(ctor.parent);
+    });
+    w.list(&module.patterns, Writer::pattern);
+    w.list(&module.sites, |w, site| {
+        w.str(&site.name);
+        w.opt(site.resolved);
+        w.list(&site.keys, Writer::key);
+        w.u32(site.ty);
+    });
+    w.list(&module.functions, Writer::function);
+    w.list(&module.globals, |w, global| {
+        w.u32(global.def);
+        w.str(&global.name);
+        w.u32(global.init);
+    });
+    w.list(&module.methods, |w, method| {
+        w.key(&method.key);
+        w.opt(method.trait_def);
+        w.str(&method.name);
+        w.u32(method.def);
+    });
+    w.list(&module.trait_fns, |w, f| {
+        w.u32(f.def);
+        w.u32(f.trait_def);
+        w.str(&f.name);
+    });
+    w.list(&module.library, |w, (name, def)| {
+        w.str(name);
+        w.u32(*def);
+    });
+    w.regs(&module.variants);
+    w.opt(module.entry);
+    w.bytes
+}
+
+// ========== Decoding ==========
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+fn invalid<T>(what: impl Into<String>) -> Result<T, DecodeError> {
+    Err(DecodeError::Invalid(what.into()))
+}
+
+impl<'b> Reader<'b> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let b = *self.bytes.get(self.pos).ok_or(DecodeError::Truncated)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'b [u8], DecodeError> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.bytes.len()).ok_or(DecodeError::Truncated)?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn uint(&mut self) -> Result<u128, DecodeError> {
+        let mut n: u128 = 0;
+        for shift in (0..128).step_by(7) {
+            let b = self.byte()?;
+            n |= u128::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        invalid("integer too long")
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        u32::try_from(self.uint()?).or_else(|_| invalid("index out of range"))
+    }
+
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        let n = usize::try_from(self.uint()?).or_else(|_| invalid("length out of range"))?;
+        // Every item takes at least a byte, which bounds what a corrupt length can allocate
+        if n > self.bytes.len() - self.pos {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(n)
+    }
+
+    fn int(&mut self) -> Result<i128, DecodeError> {
+        let n = self.uint()?;
+        Ok((n >> 1) as i128 ^ -((n & 1) as i128))
+    }
+
+    fn str(&mut self) -> Result<String, DecodeError> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec()).or_else(|_| invalid("string is not UTF-8"))
+    }
+
+    fn opt(&mut self) -> Result<Option<u32>, DecodeError> {
+        match self.uint()? {
+            0 => Ok(None),
+            n => u32::try_from(n - 1).map(Some).or_else(|_| invalid("index out of range")),
+        }
+    }
+
+    fn prim(&mut self) -> Result<PrimitiveType, DecodeError> {
+        let index = usize::from(self.byte()?);
+        PRIMS.get(index).copied().ok_or_else(|| DecodeError::Invalid(format!("unknown primitive type {}", index)))
+    }
+
+    fn opt_prim(&mut self) -> Result<Option<PrimitiveType>, DecodeError> {
+        match self.byte()? {
+            0 => Ok(None),
+            _ => self.prim().map(Some),
+        }
+    }
+
+    fn list<T>(&mut self, mut each: impl FnMut(&mut Self) -> Result<T, DecodeError>) -> Result<Vec<T>, DecodeError> {
+        let len = self.len()?;
+        (0..len).map(|_| each(self)).collect()
+    }
+
+    fn constant(&mut self) -> Result<Const, DecodeError> {
+        Ok(match self.byte()? {
+            0 => Const::Unit,
+            1 => Const::Bool(self.byte()? != 0),
+            2 => Const::Int(self.int()?),
+            3 => {
+                let bytes = self.take(8)?;
+                Const::Float(f64::from_le_bytes(bytes.try_into().expect("eight bytes")))
+            }
+            4 => Const::Char(char::from_u32(self.u32()?).ok_or(DecodeError::Invalid("invalid char".into()))?),
+            5 => Const::Str(self.str()?),
+            tag => return invalid(format!("unknown constant tag {}", tag)),
+        })
+    }
+
+    fn ty(&mut self) -> Result<Type, DecodeError> {
+        Ok(match self.byte()? {
+            0 => Type::Unknown,
+            1 => Type::Prim(self.prim()?),
+            2 => Type::Tuple(self.list(Self::ty)?),
+            3 => Type::Array(Box::new(self.ty()?)),
+            4 => Type::Ref(Box::new(self.ty()?)),
+            5 => Type::Fn,
+            6 => Type::Adt { def: self.u32()?, name: self.str()?, args: self.list(Self::ty)? },
+            tag => return invalid(format!("unknown type tag {}", tag)),
+        })
+    }
+
+    fn key(&mut self) -> Result<TypeKey, DecodeError> {
+        Ok(match self.byte()? {
+            0 => TypeKey::Def(self.u32()?),
+            1 => TypeKey::Prim(self.prim()?),
+            tag => return invalid(format!("unknown type key tag {}", tag)),
+        })
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, DecodeError> {
+        Ok(match self.byte()? {
+            0 => Pattern::Wildcard,
+            1 => Pattern::Bind(self.u32()?),
+            2 => Pattern::Literal(self.u32()?),
+            3 => Pattern::Global(self.u32()?),
+            4 => Pattern::Tuple(self.list(Self::pattern)?),
+            5 => Pattern::Ctor {
+                def: self.u32()?,
+                fields: self.list(|r| Ok((r.str()?, r.pattern()?)))?,
+                arity: self.opt()?,
+            },
+            tag => return invalid(format!("unknown pattern tag {}", tag)),
+        })
+    }
+
+    fn instr(&mut self) -> Result<Instr, DecodeError> {
+        use Instr::*;
+        let opcode = self.byte()?;
+        let mut r = || self.u32();
+        Ok(match opcode {
+            0 => Const { dst: r()?, index: r()? },
+            1 => Move { dst: r()?, src: r()? },
+            2 => Def { dst: r()?, def: r()? },
+            3 => Library { dst: r()?, path: r()? },
+            4 => Global { dst: r()?, index: r()? },
+            5 => Tuple { dst: r()?, start: r()?, count: r()? },
+            6 => List { dst: r()?, start: r()?, count: r()? },
+            7 => Adt { dst: r()?, ctor: r()?, start: r()?, count: r()? },
+            8 => {
+                let op = usize::from(self.byte()?);
+                let op = UNARY_OPS.get(op).cloned().ok_or(DecodeError::Invalid("unknown unary operator".into()))?;
+                let ty = self.opt_prim()?;
+                Unary { op, ty, dst: self.u32()?, src: self.u32()? }
+            }
+            9 => {
+                let op = usize::from(self.byte()?);
+                let op = BINARY_OPS.get(op).cloned().ok_or(DecodeError::Invalid("unknown binary operator".into()))?;
+                let ty = self.opt_prim()?;
+                Binary { op, ty, dst: self.u32()?, lhs: self.u32()?, rhs: self.u32()? }
+            }
+            10 => Jump { target: r()? },
+            11 => JumpIf { cond: r()?, target: r()? },
+            12 => JumpUnless { cond: r()?, target: r()? },
+            13 => Call { dst: r()?, func: r()?, start: r()?, count: r()? },
+            14 => CallValue { dst: r()?, callee: r()?, start: r()?, count: r()? },
+            15 => CallLibrary { dst: r()?, path: r()?, ty: r()?, start: r()?, count: r()? },
+            16 => Method { dst: r()?, site: r()?, recv: r()?, start: r()?, count: r()? },
+            17 => Return { src: r()? },
+            18 => Addr { dst: r()?, src: r()? },
+            19 => Temp { dst: r()?, src: r()? },
+            20 => FieldAddr { dst: r()?, base: r()?, name: r()? },
+            21 => IndexAddr { dst: r()?, base: r()?, index: r()? },
+            22 => Deref { dst: r()?, src: r()? },
+            23 => Load { dst: r()?, src: r()? },
+            24 => Store { dst: r()?, src: r()? },
+            25 => Match { dst: r()?, place: r()?, pattern: r()? },
+            26 => Iter { dst: r()?, src: r()? },
+            27 => Next { dst: r()?, iter: r()?, target: r()? },
+            28 => Unsupported { message: r()? },
+            29 => Closure { dst: r()?, func: r()?, captures: self.list(Self::u32)? },
+            _ => return invalid(format!("unknown opcode {}", opcode)),
+        })
+    }
+
+    fn function(&mut self) -> Result<Function, DecodeError> {
+        let name = self.str()?;
+        let (def, params, registers) = (self.u32()?, self.u32()?, self.u32()?);
+        let captures = self.opt()?;
+        let self_ref = self.byte()? != 0;
+        let loc = Loc { file: self.u32()?, line: self.u32()?, column: self.u32()? };
+        let code = self.list(Self::instr)?;
+        let mut last = Loc::default();
+        let locs = self.list(|r| {
+            let line = i128::from(last.line) + r.int()?;
+            let column = i128::from(last.column) + r.int()?;
+            let (Ok(line), Ok(column)) = (u32::try_from(line), u32::try_from(column)) else {
+                return invalid("location out of range");
+            };
+            last = Loc { file: r.u32()?, line, column };
+            Ok(last)
+        })?;
+        if locs.len() != code.len() {
+            return invalid(format!("`{}` has {} instructions but {} locations", name, code.len(), locs.len()));
+        }
+        Ok(Function { name, def, params, registers, captures, self_ref, code, locs, loc })
+    }
+}
+
+/// Read a bytecode file
+pub fn decode(bytes: &[u8]) -> Result<Module, DecodeError> {
+    if !is_bytecode(bytes) {
+        return Err(DecodeError::BadMagic);
+    }
+    let mut r = Reader { bytes, pos: MAGIC.len() };
+    let version = u16::from_le_bytes(r.take(2)?.try_into().expect("two bytes"));
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion { found: version, expected: VERSION });
+    }
+    let module = Module {
+        files: r.list(Reader::str)?,
+        consts: r.list(Reader::constant)?,
+        types: r.list(Reader::ty)?,
+        ctors: r
+            .list(|r| Ok(Ctor { def: r.u32()?, name: r.str()?, fields: r.list(Reader::str)?, parent: r.opt()? }))?,
+        patterns: r.list(Reader::pattern)?,
+        sites: r.list(|r| {
+            Ok(MethodSite { name: r.str()?, resolved: r.opt()?, keys: r.list(Reader::key)?, ty: r.u32()? })
+        })?,
+        functions: r.list(Reader::function)?,
+        globals: r.list(|r| Ok(Global { def: r.u32()?, name: r.str()?, init: r.u32()? }))?,
+        methods: r.list(|r| Ok(ImplMethod { key: r.key()?, trait_def: r.opt()?, name: r.str()?, def: r.u32()? }))?,
+        trait_fns: r.list(|r| Ok(TraitFn { def: r.u32()?, trait_def: r.u32()?, name: r.str()? }))?,
+        library: r.list(|r| Ok((r.str()?, r.u32()?)))?,
+        variants: [r.u32()?, r.u32()?, r.u32()?, r.u32()?],
+        entry: r.opt()?,
+    };
+    if r.pos != bytes.len() {
+        return invalid("trailing bytes after the module");
+    }
+    module.validate()?;
+    Ok(module)
+}
+
+impl Module {
+    /// Check that every index an instruction holds is in range, so that running cannot go out of bounds
+    fn validate(&self) -> Result<(), DecodeError> {
+        let check = |what: &str, index: u32, len: usize| match (index as usize) < len {
+            true => Ok(()),
+            false => invalid(format!("{} {} out of range", what, index)),
+        };
+        if let Some(entry) = self.entry {
+            check("function", entry, self.functions.len())?;
+        }
+        for global in &self.globals {
+            check("function", global.init, self.functions.len())?;
+        }
+        for site in &self.sites {
+            check("type", site.ty, self.types.len())?;
+        }
+        for func in &self.functions {
+            let regs = func.registers as usize;
+            let reg = |r: Reg| check("register", r, regs);
+            let span = |start: Reg, count: u32| match start.checked_add(count) {
+                Some(end) if end as usize <= regs => Ok(()),
+                _ => invalid(format!("registers {}..{} out of range in `{}`", start, start + count, func.name)),
+            };
+            let target = |t: u32| check("jump target", t, func.code.len() + 1);
+            let konst = |i: u32| check("constant", i, self.consts.len());
+            if func.params.saturating_add(func.captures.unwrap_or(0)) > func.registers {
+                return invalid(format!("`{}` has more parameters than registers", func.name));
+            }
+            for instr in &func.code {
+                use Instr::*;
+                match instr {
+                    Const { dst, index } => {
+                        reg(*dst)?;
+                        konst(*index)?;
+                    }
+                    Library { dst, path: index } => {
+                        reg(*dst)?;
+                        konst(*index)?;
+                    }
+                    Def { dst, .. } => reg(*dst)?,
+                    Global { dst, index } => {
+                        reg(*dst)?;
+                        check("global", *index, self.globals.len())?;
+                    }
+                    Move { dst, src }
+                    | Addr { dst, src }
+                    | Temp { dst, src }
+                    | Deref { dst, src }
+                    | Load { dst, src }
+                    | Store { dst, src }
+                    | Iter { dst, src }
+                    | Unary { dst, src, .. } => {
+                        reg(*dst)?;
+                        reg(*src)?;
+                    }
+                    Tuple { dst, start, count } | List { dst, start, count } => {
+                        reg(*dst)?;
+                        span(*start, *count)?;
+                    }
+                    Adt { dst, ctor, start, count } => {
+                        reg(*dst)?;
+                        check("constructor", *ctor, self.ctors.len())?;
+                        span(*start, *count)?;
+                    }
+                    Binary { dst, lhs, rhs, .. } => {
+                        reg(*dst)?;
+                        reg(*lhs)?;
+                        reg(*rhs)?;
+                    }
+                    Jump { target: t } => target(*t)?,
+                    JumpIf { cond, target: t } | JumpUnless { cond, target: t } => {
+                        reg(*cond)?;
+                        target(*t)?;
+                    }
+                    Call { dst, func: f, start, count } => {
+                        reg(*dst)?;
+                        check("function", *f, self.functions.len())?;
+                        span(*start, *count)?;
+                    }
+                    CallValue { dst, callee, start, count } => {
+                        reg(*dst)?;
+                        reg(*callee)?;
+                        span(*start, *count)?;
+                    }
+                    CallLibrary { dst, path, ty, start, count } => {
+                        reg(*dst)?;
+                        konst(*path)?;
+                        check("type", *ty, self.types.len())?;
+                        span(*start, *count)?;
+                    }
+                    Method { dst, site, recv, start, count } => {
+                        reg(*dst)?;
+                        check("method site", *site, self.sites.len())?;
+                        reg(*recv)?;
+                        span(*start, *count)?;
+                    }
+                    Return { src } => reg(*src)?,
+                    FieldAddr { dst, base, name } => {
+                        reg(*dst)?;
+                        reg(*base)?;
+                        konst(*name)?;
+                    }
+                    IndexAddr { dst, base, index } => {
+                        reg(*dst)?;
+                        reg(*base)?;
+                        reg(*index)?;
+                    }
+                    Match { dst, place, pattern } => {
+                        reg(*dst)?;
+                        reg(*place)?;
+                        check("pattern", *pattern, self.patterns.len())?;
+                        self.patterns[*pattern as usize].validate(&reg, &konst)?;
+                    }
+                    Next { dst, iter, target: t } => {
+                        reg(*dst)?;
+                        reg(*iter)?;
+                        target(*t)?;
+                    }
+                    Unsupported { message } => konst(*message)?,
+                    Closure { dst, func: f, captures } => {
+                        reg(*dst)?;
+                        check("function", *f, self.functions.len())?;
+                        captures.iter().try_for_each(|r| reg(*r))?;
+                        let closure = &self.functions[*f as usize];
+                        match closure.captures {
+                            Some(n) if n as usize == captures.len() => {}
+                            Some(n) => {
+                                return invalid(format!(
+                                    "`{}` captures {} registers, not {}",
+                                    closure.name,
+                                    n,
+                                    captures.len()
+                                ))
+                            }
+                            None => return invalid(format!("`{}` is not a closure", closure.name)),
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Pattern {
+    fn validate(
+        &self,
+        reg: &dyn Fn(Reg) -> Result<(), DecodeError>,
+        konst: &dyn Fn(u32) -> Result<(), DecodeError>,
+    ) -> Result<(), DecodeError> {
+        match self {
+            Pattern::Wildcard | Pattern::Global(_) => Ok(()),
+            Pattern::Bind(r) => reg(*r),
+            Pattern::Literal(index) => konst(*index),
+            Pattern::Tuple(elems) => elems.iter().try_for_each(|p| p.validate(reg, konst)),
+            Pattern::Ctor { fields, .. } => fields.iter().try_for_each(|(_, p)| p.validate(reg, konst)),
+        }
+    }
+}
+
+// ========== Listing ==========
+
+impl fmt::Display for Module {
+    /// A listing of every function's code, for reading compiled output
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, func) in self.functions.iter().enumerate() {
+            writeln!(f, "fn #{} {} (params {}, registers {}):", i, func.name, func.params, func.registers)?;
+            for (pc, instr) in func.code.iter().enumerate() {
+                writeln!(f, "  {:>4}  {:?}", pc, instr)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Module {
+        Module {
+            files: vec!["main.solo".into()],
+            consts: vec![
+                Const::Unit,
+                Const::Bool(true),
+                Const::Int(-170_141_183_460_469_231_731_687_303_715_884_105_728),
+                Const::Float(2.5),
+                Const::Char('é'),
+                Const::Str("hello".into()),
+            ],
+            types: vec![Type::Adt {
+                def: 7,
+                name: "Vec".into(),
+                args: vec![Type::Ref(Box::new(Type::Prim(PrimitiveType::U8)))],
+            }],
+            ctors: vec![Ctor { def: 12, name: "Point".into(), fields: vec!["x".into(), "y".into()], parent: None }],
+            patterns: vec![Pattern::Ctor {
+                def: 12,
+                fields: vec![("x".into(), Pattern::Bind(1)), ("y".into(), Pattern::Literal(2))],
+                arity: None,
+            }],
+            sites: vec![MethodSite { name: "len".into(), resolved: None, keys: vec![TypeKey::Def(7)], ty: 0 }],
+            functions: vec![
+                Function {
+                    name: "main".into(),
+                    def: 20,
+                    params: 0,
+                    registers: 4,
+                    captures: None,
+                    self_ref: false,
+                    code: vec![
+                        Instr::Const { dst: 0, index: 2 },
+                        Instr::Binary { op: BinaryOp::Add, ty: Some(PrimitiveType::I64), dst: 1, lhs: 0, rhs: 0 },
+                        Instr::Match { dst: 2, place: 0, pattern: 0 },
+                        Instr::Closure { dst: 3, func: 1, captures: vec![0, 1] },
+                        Instr::Return { src: 1 },
+                    ],
+                    locs: vec![
+                        Loc { file: 0, line: 3, column: 5 },
+                        Loc { file: 0, line: 3, column: 9 },
+                        Loc { file: 0, line: 2, column: 1 },
+                        Loc { file: 0, line: 5, column: 13 },
+                        Loc { file: 0, line: 4, column: 1 },
+                    ],
+                    loc: Loc { file: 0, line: 2, column: 1 },
+                },
+                // `|n| n + a + b`, with `a` and `b` after its parameter
+                Function {
+                    name: "<closure>".into(),
+                    def: 20,
+                    params: 1,
+                    registers: 4,
+                    captures: Some(2),
+                    self_ref: false,
+                    code: vec![
+                        Instr::Binary { op: BinaryOp::Add, ty: None, dst: 3, lhs: 0, rhs: 1 },
+                        Instr::Binary { op: BinaryOp::Add, ty: None, dst: 3, lhs: 3, rhs: 2 },
+                        Instr::Return { src: 3 },
+                    ],
+                    locs: vec![Loc { file: 0, line: 5, column: 17 }; 3],
+                    loc: Loc { file: 0, line: 5, column: 13 },
+                },
+            ],
+            globals: Vec::new(),
+            methods: vec![ImplMethod {
+                key: TypeKey::Prim(PrimitiveType::Str),
+                trait_def: Some(3),
+                name: "f".into(),
+                def: 4,
+            }],
+            trait_fns: vec![TraitFn { def: 4, trait_def: 3, name: "f".into() }],
+            library: vec![("Vec".into(), 7)],
+            variants: [1, 2, 3, 4],
+            entry: Some(0),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let module = sample();
+        let bytes = encode(&module);
+        assert!(is_bytecode(&bytes));
+        assert_eq!(decode(&bytes), Ok(module));
+    }
+
+    #[test]
+    fn test_rejects_other_files_and_versions() {
+        assert_eq!(decode(b"fn main() {}"), Err(DecodeError::BadMagic));
+
+        let mut bytes = encode(&sample());
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(decode(&bytes), Err(DecodeError::UnsupportedVersion { found: VERSION + 1, expected: VERSION }));
+    }
+
+    #[test]
+    fn test_rejects_damaged_files() {
+        let bytes = encode(&sample());
+        for len in MAGIC.len() + 2..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "decoded a file cut at {} bytes", len);
+        }
+
+        let mut module = sample();
+        module.functions[0].code.push(Instr::Move { dst: 9, src: 0 });
+        module.functions[0].locs.push(Loc::default());
+        assert_eq!(decode(&encode(&module)), Err(DecodeError::Invalid("register 9 out of range".into())));
+
+        let mut module = sample();
+        module.functions[0].code[3] = Instr::Closure { dst: 3, func: 1, captures: vec![0] };
+        assert_eq!(decode(&encode(&module)), Err(DecodeError::Invalid("`<closure>` captures 2 registers, not 1".into())));
+    }
+}
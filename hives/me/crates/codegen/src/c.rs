@@ -0,0 +1,2645 @@
+// C backend
+// Emits a C99 header and source from the IR of a checked crate. Structs and
+// enums become tagged unions, contracts become asserts and `drop` runs explicit
+// cleanup. Each block of a function is a label, and values read once where they
+// are made are written into the expression that reads them.
+// Integer arithmetic is checked, and panics on overflow and division by zero.
+
+use crate::ir::{self, Base, Block, Callee, Const, FuncId, Lowering, Op, Place, Proj, Target, Terminator, Value};
+use crate::mono::{self, is_unsigned, prim_name, Crate, LibraryCall};
+use crate::template::{parse_template, Piece, Spec};
+use my_lang_ast as ast;
+use my_lang_ast::{BinaryOp, PrimitiveType, Span, UnaryOp};
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_resolve::{DefId, DefKind, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults};
+use std::collections::{HashMap, HashSet};
+
+/// Support code every program carries, copied into the header
+const RUNTIME: &str = include_str!("c/runtime.h");
+
+/// Names C, its library or the support code already use
+const RESERVED: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum", "extern", "float",
+    "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return", "short", "signed", "sizeof",
+    "static", "struct", "switch", "typedef", "union", "unsigned", "void", "volatile", "while", "bool", "true", "false",
+    "main", "exit", "abort", "free", "malloc", "realloc", "calloc", "printf", "puts", "fputs", "assert", "errno",
+    "stdin", "stdout", "stderr", "index", "abs", "div", "time", "rand", "log", "exp", "sqrt", "pow", "floor", "ceil",
+    "round", "fabs", "fmod", "fmin", "fmax", "sin", "cos", "tan", "memcpy", "memmove", "memset", "memcmp", "strlen",
+    "qsort", "argc", "argv", "NULL", "EOF", "FILE", "remove", "rename", "signal", "y0", "y1", "j0", "j1",
+];
+
+/// A generated header and the source file that includes it
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CProgram {
+    pub header: String,
+    pub source: String,
+}
+
+/// Emit C for the crate whose `main` is in the first of `programs`. `name` is
+/// what the header is called, without `.h`; `files` are the names of the
+/// source files spans refer to, which panics report.
+pub fn emit(
+    programs: &[&ast::Program],
+    res: &Resolution,
+    types: &TypeckResults,
+    files: &[String],
+    name: &str,
+) -> (CProgram, Vec<Diagnostic>) {
+    let krate = Crate::new(res, types, programs);
+    let mut emitter = Emitter::new(krate, Lowering::new(programs, res, types), files);
+    if let Some(id) = emitter.lowering.module().entry {
+        emitter.entry(id);
+    }
+    // Support code asks for instances of its own, such as `Drop` impls, which are lowered in the next round
+    let mut done = 0;
+    loop {
+        emitter.lowering.run();
+        let count = emitter.lowering.module().functions.len();
+        if done == count {
+            break;
+        }
+        for id in done..count {
+            emitter.function(FuncId(id as u32));
+        }
+        done = count;
+    }
+    let program = emitter.program(name);
+    let (_, mut diags) = emitter.lowering.finish();
+    diags.extend(emitter.diags);
+    (program, diags)
+}
+
+/// How far the text of a value reaches beyond the values it reads, which
+/// decides what it can be moved past on its way to where it is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+enum Effect {
+    #[default]
+    Pure,
+    /// Reads memory that a store or a call may change
+    Reads,
+    /// Calls a function
+    Writes,
+}
+
+/// The function being emitted
+#[derive(Default)]
+struct Frame {
+    out: String,
+    depth: usize,
+    /// Counter for temporaries, which are all named `_tN`
+    temps: u32,
+    /// C names taken by locals
+    names: HashSet<String>,
+    /// Declarations of every local, which come first so that no label is followed by one
+    decls: Vec<String>,
+    /// Return type, or `None` when the C function returns `void`
+    ret: Option<Ty>,
+    span: Span,
+    values: Vec<Ty>,
+    /// C name and type of each slot
+    slots: Vec<(String, Ty)>,
+    params: Vec<Vec<Value>>,
+    /// The local each value was bound to
+    bound: HashMap<Value, String>,
+    defs: HashMap<Value, Op>,
+    /// How many times each value is read, and where, for those read once
+    uses: Vec<usize>,
+    used_at: Vec<(Block, usize)>,
+    /// C text of each value made so far
+    texts: HashMap<Value, String>,
+    /// Values read once later in the block, whose text waits to be written there
+    pending: Vec<(Value, Effect)>,
+    /// The strongest effect of the pending values the current instruction has read
+    taken: Effect,
+    /// The enum each `Tag` value was read from, whose comparisons name the variant
+    tags: HashMap<Value, Ty>,
+    labels: HashSet<Block>,
+    block: Block,
+    /// Index of the instruction being emitted; the terminator's is the number of instructions
+    position: usize,
+    next: Option<Block>,
+    /// Whether what was written last never returns
+    diverged: bool,
+}
+
+impl Frame {
+    fn read(&mut self, value: Value, block: Block, position: usize) {
+        self.uses[value.0 as usize] += 1;
+        self.used_at[value.0 as usize] = (block, position);
+    }
+}
+
+struct Emitter<'a> {
+    res: &'a Resolution,
+    types: &'a TypeckResults,
+    files: &'a [String],
+    krate: Crate<'a>,
+    lowering: Lowering<'a>,
+    /// Names of items, which locals stay clear of
+    item_names: HashSet<String>,
+    /// Names given to anything at file scope
+    taken: HashSet<String>,
+    type_names: HashMap<Ty, String>,
+    forward: Vec<String>,
+    type_defs: Vec<String>,
+    helpers: HashSet<String>,
+    helper_protos: Vec<String>,
+    helper_defs: Vec<String>,
+    fn_names: HashMap<FuncId, String>,
+    prototypes: Vec<String>,
+    functions: Vec<String>,
+    main: String,
+    diags: Vec<Diagnostic>,
+    reported: HashSet<(String, Span)>,
+    frame: Frame,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(krate: Crate<'a>, lowering: Lowering<'a>, files: &'a [String]) -> Self {
+        let (res, types) = (krate.res, krate.types);
+        let item_names = res
+            .defs
+            .iter()
+            .filter(|d| !d.is_prelude())
+            .filter(|d| matches!(d.kind, DefKind::Function | DefKind::Const | DefKind::Struct | DefKind::Enum))
+            .map(|d| d.name.clone())
+            .collect();
+        Self {
+            res,
+            types,
+            files,
+            krate,
+            lowering,
+            item_names,
+            taken: HashSet::new(),
+            type_names: HashMap::new(),
+            forward: Vec::new(),
+            type_defs: Vec::new(),
+            helpers: HashSet::new(),
+            helper_protos: Vec::new(),
+            helper_defs: Vec::new(),
+            fn_names: HashMap::new(),
+            prototypes: Vec::new(),
+            functions: Vec::new(),
+            main: String::new(),
+            diags: Vec::new(),
+            reported: HashSet::new(),
+            frame: Frame::default(),
+        }
+    }
+
+    // ========== Items ==========
+
+    /// The C `main`, which calls the program's
+    fn entry(&mut self, id: FuncId) {
+        let (def, types) = (self.lowering.module().function(id).def, self.types);
+        let Some(sig) = types.sigs.get(&def) else { return };
+        let span = self.krate.fns.get(&def).map_or_else(Span::default, |func| func.span);
+        let name = self.fn_name(id);
+        let mut body = String::from("    ml_argc = argc;\n    ml_argv = argv;\n    signal(SIGABRT, ml_on_abort);\n");
+        let args = match sig.inputs.first() {
+            Some(ty) => format!("{}()", self.env_args(ty, span)),
+            None => String::new(),
+        };
+        let call = format!("{}({})", name, args);
+        match &sig.output {
+            Ty::Adt(result, _) if self.krate.is_prelude(*result, "Result") => {
+                let ty = self.c_type(&sig.output, span);
+                let (err, err_ty) = self.krate.variant_named(&sig.output, "Err");
+                let fields = self.krate.fields_of(&sig.output, err_ty);
+                body.push_str(&format!("    {} = {};\n", decl(&ty, "result"), call));
+                body.push_str(&format!("    if (result.tag == {}) {{\n", self.tag(&ty, err)));
+                body.push_str("        ml_buf b = ml_buf_new();\n        ml_buf_lit(&b, \"Error: \");\n");
+                if let Some((_, field_ty)) = fields.first() {
+                    let show = self.fmt_value("&b", "result.as.Err._0", field_ty, "ML_PLAIN", true, span);
+                    body.push_str(&format!("        {};\n", show));
+                }
+                body.push_str(
+                    "        ml_buf_lit(&b, \"\\n\");\n        ml_print(stderr, &b);\n        return 1;\n    }\n",
+                );
+            }
+            _ => body.push_str(&format!("    {};\n", call)),
+        }
+        body.push_str("    return 0;\n");
+        self.main = format!("int main(int argc, char **argv) {{\n{}}}\n", body);
+    }
+
+    /// A function of the module; a constant whose value is not a literal becomes a `static` one
+    fn function(&mut self, id: FuncId) {
+        let func = self.lowering.module().function(id).clone();
+        let name = self.fn_name(id);
+        let span = func.span;
+        self.frame = Frame {
+            depth: 1,
+            ret: (!is_void(&func.ret)).then(|| func.ret.clone()),
+            span,
+            values: func.values.clone(),
+            params: func.blocks.iter().map(|block| block.params.clone()).collect(),
+            bound: func.names.clone(),
+            uses: vec![0; func.values.len()],
+            used_at: vec![(Block(0), 0); func.values.len()],
+            ..Frame::default()
+        };
+        self.count_uses(&func);
+        // Every type a value has is laid out, whether or not the value gets a variable
+        for inst in func.blocks.iter().flat_map(|block| &block.insts) {
+            if let Some(ty) = inst.result.map(|result| self.value_ty(result)).filter(|ty| !is_void(ty)) {
+                self.c_type(&ty, inst.span);
+            }
+        }
+        let mut params = Vec::new();
+        for &param in func.blocks.first().map_or(&[][..], |entry| &entry.params[..]) {
+            let ty = self.value_ty(param);
+            let t = self.c_type(&ty, span);
+            let local = self.variable_name(param);
+            params.push(decl(&t, &local));
+            self.frame.texts.insert(param, local);
+        }
+        for slot in &func.slots {
+            let local = match slot.name.is_empty() {
+                true => self.temp_name(),
+                false => self.local(&slot.name),
+            };
+            if !is_void(&slot.ty) {
+                let t = self.c_type(&slot.ty, span);
+                self.frame.decls.push(format!("{};", decl(&t, &local)));
+            }
+            self.frame.slots.push((local, slot.ty.clone()));
+        }
+        for block in func.blocks.iter().skip(1) {
+            for &param in &block.params {
+                self.declare_param(param);
+            }
+        }
+        for (index, block) in func.blocks.iter().enumerate() {
+            self.frame.block = Block(index as u32);
+            self.frame.next = (index + 1 < func.blocks.len()).then(|| Block(index as u32 + 1));
+            self.frame.diverged = false;
+            if index > 0 {
+                self.frame.out.push_str(&format!("bb{}:\n", index));
+            }
+            for (position, inst) in block.insts.iter().enumerate() {
+                self.frame.position = position;
+                self.frame.span = inst.span;
+                self.inst(inst.result, &inst.op, inst.span);
+            }
+            self.frame.position = block.insts.len();
+            self.terminator(&block.term);
+        }
+        let mut body: String = self.frame.decls.iter().map(|d| format!("    {}\n", d)).collect();
+        // Labels no `goto` names are dropped, as `-Wall` asks
+        for line in self.frame.out.lines() {
+            let unused = line
+                .strip_prefix("bb")
+                .and_then(|label| label.strip_suffix(':'))
+                .and_then(|label| label.parse().ok())
+                .is_some_and(|block| !self.frame.labels.contains(&Block(block)));
+            if !unused {
+                body.push_str(line);
+                body.push('\n');
+            }
+        }
+        let ret = self.ret_type(&func.ret, span);
+        match self.res.def(func.def).kind {
+            DefKind::Const => {
+                let proto = format!("static {}(void)", decl(&ret, &name));
+                self.helper_protos.push(format!("{};", proto));
+                self.functions.push(format!("{} {{\n{}}}\n", proto, body));
+            }
+            _ => {
+                let params = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+                let proto = format!("{}({})", decl(&ret, &name), params);
+                self.prototypes.push(format!("{};", proto));
+                self.functions.push(format!("{} {{\n{}}}\n", proto, body));
+            }
+        }
+    }
+
+    fn program(&self, name: &str) -> CProgram {
+        let guard = format!("{}_H", sanitize(name).to_uppercase());
+        let mut header = format!(
+            "/* {}.h: generated from Solo source by my-lang; do not edit. */\n\n#ifndef {}\n#define {}\n\n{}",
+            name, guard, guard, RUNTIME
+        );
+        if !self.forward.is_empty() {
+            header.push_str("\n/* ========== Types ========== */\n\n");
+            header.push_str(&self.forward.join("\n"));
+            header.push_str("\n\n");
+            header.push_str(&self.type_defs.join("\n\n"));
+            header.push('\n');
+        } else if !self.type_defs.is_empty() {
+            header.push_str("\n/* ========== Types ========== */\n\n");
+            header.push_str(&self.type_defs.join("\n\n"));
+            header.push('\n');
+        }
+        header.push_str("\n/* ========== Functions ========== */\n\n");
+        header.push_str(&self.prototypes.join("\n"));
+        header.push_str("\n\n#endif\n");
+
+        let mut source = format!("/* {}.c: generated from Solo source by my-lang; do not edit. */\n\n", name);
+        source.push_str(&format!("#include \"{}.h\"\n", name));
+        if !self.helper_protos.is_empty() {
+            source.push_str("\n/* ========== Support ========== */\n\n");
+            source.push_str(&self.helper_protos.join("\n"));
+            source.push('\n');
+            for helper in &self.helper_defs {
+                source.push('\n');
+                source.push_str(helper);
+            }
+        }
+        source.push_str("\n/* ========== Functions ========== */\n");
+        for function in &self.functions {
+            source.push('\n');
+            source.push_str(function);
+        }
+        source.push('\n');
+        source.push_str(&self.main);
+        CProgram { header, source }
+    }
+
+    // ========== Names ==========
+
+    /// A file-scope name no other takes
+    fn reserve(&mut self, base: &str) -> String {
+        let mut name = sanitize(base);
+        if RESERVED.contains(&name.as_str()) {
+            name.push('_');
+        } else if name.starts_with("ml_") || name.starts_with('_') {
+            name.insert(0, 'u');
+        }
+        let mut candidate = name.clone();
+        let mut n = 2;
+        while !self.taken.insert(candidate.clone()) {
+            candidate = format!("{}_{}", name, n);
+            n += 1;
+        }
+        candidate
+    }
+
+    /// A name for a local, clear of every item it could hide
+    fn local(&mut self, base: &str) -> String {
+        let mut name = sanitize(base);
+        if RESERVED.contains(&name.as_str()) {
+            name.push('_');
+        } else if name.starts_with("ml_") || name.starts_with('_') {
+            name.insert(0, 'u');
+        }
+        let mut candidate = name.clone();
+        let mut n = 2;
+        while self.frame.names.contains(&candidate)
+            || self.item_names.contains(&candidate)
+            || self.taken.contains(&candidate)
+        {
+            candidate = format!("{}_{}", name, n);
+            n += 1;
+        }
+        self.frame.names.insert(candidate.clone());
+        candidate
+    }
+
+    fn temp_name(&mut self) -> String {
+        let name = format!("_t{}", self.frame.temps);
+        self.frame.temps += 1;
+        name
+    }
+
+    /// Declare a temporary of type `ty`
+    fn temp(&mut self, ty: &Ty, init: Option<&str>, span: Span) -> String {
+        let name = self.temp_name();
+        let t = self.c_type(ty, span);
+        self.frame.decls.push(format!("{};", decl(&t, &name)));
+        if let Some(value) = init {
+            self.line(&format!("{} = {};", name, unwrap_parens(value)));
+        }
+        name
+    }
+
+    /// The C name of a function of the module
+    fn fn_name(&mut self, id: FuncId) -> String {
+        if let Some(name) = self.fn_names.get(&id) {
+            return name.clone();
+        }
+        let base = self.lowering.module().function(id).name.clone();
+        let name = self.reserve(&base);
+        self.fn_names.insert(id, name.clone());
+        name
+    }
+
+    fn loc(&self, span: Span) -> String {
+        let file = self.files.get(span.file.0 as usize).map_or("<unknown>", |f| f.as_str());
+        format!("ML_LOC({}, {}, {})", c_string(file), span.line, span.column)
+    }
+
+    fn unsupported(&mut self, what: impl Into<String>, span: Span) {
+        let message = format!("{} cannot be compiled to C", what.into());
+        if self.reported.insert((message.clone(), span)) {
+            let diag = Diagnostic::error(message)
+                .with_code(codes::UNSUPPORTED_BY_TARGET)
+                .with_primary(span, "not supported by the C backend");
+            self.diags.push(diag);
+        }
+    }
+
+    // ========== Output ==========
+
+    /// Write a statement, after the values still waiting that it could change
+    fn line(&mut self, text: &str) {
+        self.flush();
+        self.write(text);
+    }
+
+    fn write(&mut self, text: &str) {
+        for _ in 0..self.frame.depth {
+            self.frame.out.push_str("    ");
+        }
+        self.frame.out.push_str(text);
+        self.frame.out.push('\n');
+        // Only the support code's calls are known not to return
+        self.frame.diverged = ["ml_panic", "ml_exit"].iter().any(|f| text.starts_with(f));
+    }
+
+    fn open(&mut self, text: &str) {
+        self.line(text);
+        self.frame.depth += 1;
+    }
+
+    fn close(&mut self, text: &str) {
+        self.frame.depth -= 1;
+        self.line(text);
+    }
+
+    // ========== Types ==========
+
+    fn ret_type(&mut self, ty: &Ty, span: Span) -> String {
+        if is_void(ty) {
+            "void".to_string()
+        } else {
+            self.c_type(ty, span)
+        }
+    }
+
+    /// The C type values of `ty` have
+    fn c_type(&mut self, ty: &Ty, span: Span) -> String {
+        match ty {
+            Ty::Prim(prim) => match prim_type(*prim) {
+                Some(t) => t.to_string(),
+                None => {
+                    self.unsupported(format!("`{}`", prim_name(*prim)), span);
+                    "int64_t".to_string()
+                }
+            },
+            Ty::Tuple(elems) if elems.is_empty() => "ml_unit".to_string(),
+            Ty::Never => "ml_unit".to_string(),
+            Ty::Ref { ty: inner, mutable } if !mutable && self.krate.is_str(inner) => "ml_str".to_string(),
+            Ty::Ref { ty: inner, .. } => pointer(self.c_type(inner, span)),
+            Ty::Adt(def, args) if !self.types.adts.contains_key(def) => match self.res.def(*def).name.as_str() {
+                "String" => "ml_str".to_string(),
+                "Box" => match args.first() {
+                    Some(inner) => pointer(self.c_type(inner, span)),
+                    None => "void *".to_string(),
+                },
+                "Vec" => self.named_type(ty, span),
+                name => {
+                    self.unsupported(format!("`{}`", name), span);
+                    "ml_unit".to_string()
+                }
+            },
+            Ty::Tuple(_) | Ty::Array(_, Some(_)) | Ty::Adt(..) | Ty::Fn(..) => self.named_type(ty, span),
+            Ty::Array(_, None) => {
+                self.unsupported("a slice", span);
+                "ml_unit".to_string()
+            }
+            _ => {
+                self.unsupported(format!("the type `{}`", ty.display(self.res)), span);
+                "ml_unit".to_string()
+            }
+        }
+    }
+
+    /// A struct, or a function pointer, defined in the header the first time it is used
+    fn named_type(&mut self, ty: &Ty, span: Span) -> String {
+        if let Some(name) = self.type_names.get(ty) {
+            return name.clone();
+        }
+        let name = self.reserve(&self.krate.mangle(ty));
+        self.type_names.insert(ty.clone(), name.clone());
+        if let Ty::Fn(params, ret) = ty {
+            let params: Vec<String> = params.iter().map(|p| self.c_type(p, span)).collect();
+            let params = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+            let ret = self.ret_type(ret, span);
+            self.type_defs.push(format!("typedef {};", decl(&ret, &format!("(*{})({})", name, params))));
+            return name;
+        }
+        self.forward.push(format!("typedef struct {0} {0};", name));
+        // Types held by value are defined first
+        let body = self.type_body(ty, &name, span);
+        self.type_defs.push(body);
+        name
+    }
+
+    fn type_body(&mut self, ty: &Ty, name: &str, span: Span) -> String {
+        let mut fields = Vec::new();
+        match ty {
+            Ty::Tuple(elems) => {
+                for (i, elem) in elems.iter().enumerate() {
+                    fields.push(decl(&self.c_type(elem, span), &format!("_{}", i)));
+                }
+            }
+            Ty::Array(elem, Some(len)) => fields.push(decl(&self.c_type(elem, span), &format!("at[{}]", len.max(&1)))),
+            Ty::Adt(def, args) if self.res.def(*def).kind == DefKind::Enum => {
+                let adt = &self.types.adts[def];
+                let tags: Vec<String> = adt.variants.iter().map(|v| self.tag(name, v.def)).collect();
+                let mut members = Vec::new();
+                for (index, variant) in adt.variants.iter().enumerate() {
+                    let variant_fields = self.krate.fields_of(ty, index);
+                    if variant_fields.is_empty() {
+                        continue;
+                    }
+                    let decls: Vec<String> = variant_fields
+                        .iter()
+                        .map(|(field, field_ty)| format!("{};", decl(&self.c_type(field_ty, span), &field_name(field))))
+                        .collect();
+                    members.push(format!(
+                        "        struct {{ {} }} {};",
+                        decls.join(" "),
+                        field_name(&self.res.def(variant.def).name)
+                    ));
+                }
+                let _ = args;
+                let mut body = format!("enum {}_tag {{ {} }};\n\nstruct {} {{\n", name, tags.join(", "), name);
+                body.push_str(&format!("    enum {}_tag tag;\n", name));
+                if !members.is_empty() {
+                    body.push_str(&format!("    union {{\n{}\n    }} as;\n", members.join("\n")));
+                }
+                body.push_str("};");
+                return body;
+            }
+            Ty::Adt(def, args) if !self.types.adts.contains_key(def) => {
+                // `Vec`, the only library type with a layout of its own
+                let elem = args.first().cloned().unwrap_or_else(Ty::unit);
+                let elem = self.c_type(&elem, span);
+                fields.push(decl(&pointer(elem), "data"));
+                fields.push("size_t len".to_string());
+                fields.push("size_t cap".to_string());
+            }
+            Ty::Adt(..) => {
+                for (field, field_ty) in self.krate.fields_of(ty, 0) {
+                    fields.push(decl(&self.c_type(&field_ty, span), &field_name(&field)));
+                }
+                if fields.is_empty() {
+                    fields.push("ml_unit _".to_string());
+                }
+            }
+            _ => {}
+        }
+        let fields: Vec<String> = fields.iter().map(|f| format!("    {};\n", f)).collect();
+        format!("struct {} {{\n{}}};", name, fields.concat())
+    }
+
+    /// The enumerator of a variant's tag
+    fn tag(&self, type_name: &str, variant: DefId) -> String {
+        format!("{}_{}", type_name, self.res.def(variant).name)
+    }
+
+    /// The value behind a pointer of type `ty`
+    fn deref(&self, value: String, ty: &Ty) -> String {
+        if !self.krate.is_pointer(ty) {
+            return value;
+        }
+        match value.strip_prefix('&') {
+            Some(place) if is_postfix(place) => place.to_string(),
+            _ => format!("(*{})", value),
+        }
+    }
+
+    /// Follow every pointer in `value` of type `ty`
+    fn peel_value(&self, mut value: String, ty: &Ty) -> String {
+        let mut ty = ty.clone();
+        while let Some(inner) = self.krate.pointee(&ty) {
+            value = self.deref(value, &ty);
+            ty = inner;
+        }
+        value
+    }
+
+    // ========== Instances ==========
+
+    /// C name of `def` with the generic arguments `args`, lowering it if it is new
+    fn instance(&mut self, def: DefId, args: Vec<Ty>) -> String {
+        let id = self.lowering.instance(def, args);
+        self.fn_name(id)
+    }
+
+    // ========== Values ==========
+
+    fn value_ty(&self, value: Value) -> Ty {
+        self.frame.values.get(value.0 as usize).cloned().unwrap_or(Ty::Error)
+    }
+
+    /// Count the reads of each value. An argument is read only where its
+    /// parameter is, and a branch passes its arguments after the condition.
+    fn count_uses(&mut self, func: &ir::Function) {
+        let mut edges = Vec::new();
+        for (index, block) in func.blocks.iter().enumerate() {
+            let at = Block(index as u32);
+            for (position, inst) in block.insts.iter().enumerate() {
+                // What a check would report is not shown
+                let reads = match &inst.op {
+                    Op::Check(check) => vec![check.cond],
+                    op => op.values(),
+                };
+                for value in reads {
+                    self.frame.read(value, at, position);
+                }
+                if let Some(result) = inst.result {
+                    self.frame.defs.insert(result, inst.op.clone());
+                }
+            }
+            let end = block.insts.len();
+            match &block.term {
+                Terminator::Branch { cond: value, .. } | Terminator::Return(value) => self.frame.read(*value, at, end),
+                Terminator::Jump(_) | Terminator::Unreachable => {}
+            }
+            let position = if matches!(block.term, Terminator::Branch { .. }) { usize::MAX } else { end };
+            for target in block.term.targets() {
+                for (&arg, &param) in target.args.iter().zip(&func.block(target.block).params) {
+                    if arg != param {
+                        edges.push((arg, param, at, position));
+                    }
+                }
+            }
+        }
+        let mut counted = vec![false; edges.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, &(arg, param, at, position)) in edges.iter().enumerate() {
+                if !counted[i] && self.frame.uses[param.0 as usize] > 0 {
+                    counted[i] = true;
+                    changed = true;
+                    self.frame.read(arg, at, position);
+                }
+            }
+        }
+    }
+
+    /// A variable for a parameter of a block other than the entry, which edges assign
+    fn declare_param(&mut self, param: Value) {
+        if self.frame.uses[param.0 as usize] == 0 {
+            return;
+        }
+        let ty = self.value_ty(param);
+        if is_void(&ty) {
+            self.frame.texts.insert(param, "ML_UNIT".to_string());
+            return;
+        }
+        let name = self.variable(param);
+        self.frame.texts.insert(param, name);
+    }
+
+    /// Declare a variable for `value`, named after the local it was bound to
+    fn variable(&mut self, value: Value) -> String {
+        let name = self.variable_name(value);
+        let t = self.c_type(&self.value_ty(value), self.frame.span);
+        self.frame.decls.push(format!("{};", decl(&t, &name)));
+        name
+    }
+
+    fn variable_name(&mut self, value: Value) -> String {
+        match self.frame.bound.get(&value).cloned() {
+            Some(name) => self.local(&name),
+            None => self.temp_name(),
+        }
+    }
+
+    /// Give `value` the text `text`: a value no one reads is written as a
+    /// statement if it has effects, one read once later in the block waits to
+    /// be written where it is read, and anything else gets a variable
+    fn define(&mut self, value: Value, text: String, effect: Effect) {
+        let ty = self.value_ty(value);
+        let calls = if has_call(&text) { Effect::Writes } else { Effect::Pure };
+        let effect = effect.max(std::mem::take(&mut self.frame.taken)).max(calls);
+        if is_void(&ty) {
+            if has_call(&text) {
+                self.line(&statement(&text));
+            }
+            self.frame.texts.insert(value, "ML_UNIT".to_string());
+            return;
+        }
+        let (uses, (block, position)) = (self.frame.uses[value.0 as usize], self.frame.used_at[value.0 as usize]);
+        if uses == 0 {
+            if effect == Effect::Writes {
+                self.line(&statement(&text));
+            }
+            return;
+        }
+        // A call stays in order only if what reads it comes next
+        let waits =
+            uses == 1 && block == self.frame.block && (effect < Effect::Writes || position == self.frame.position + 1);
+        if !waits {
+            self.materialize(value, &text);
+            return;
+        }
+        if effect == Effect::Writes {
+            self.flush();
+        }
+        self.frame.texts.insert(value, text);
+        if effect > Effect::Pure {
+            self.frame.pending.push((value, effect));
+        }
+    }
+
+    /// Put `value` in a variable of its own
+    fn materialize(&mut self, value: Value, text: &str) -> String {
+        let name = self.variable(value);
+        self.line(&format!("{} = {};", name, unwrap_parens(text)));
+        self.frame.texts.insert(value, name.clone());
+        name
+    }
+
+    /// Write out the values waiting to be read, in the order they were made,
+    /// before a statement that could change what they read
+    fn flush(&mut self) {
+        for (value, _) in std::mem::take(&mut self.frame.pending) {
+            let text = self.frame.texts.get(&value).cloned().unwrap_or_default();
+            self.materialize(value, &text);
+        }
+    }
+
+    /// The text of `value` where it is read
+    fn operand(&mut self, value: Value) -> String {
+        if let Some(i) = self.frame.pending.iter().position(|(pending, _)| *pending == value) {
+            let (_, effect) = self.frame.pending.remove(i);
+            self.frame.taken = self.frame.taken.max(effect);
+        }
+        self.frame.texts.get(&value).cloned().unwrap_or_else(|| "0".to_string())
+    }
+
+    /// `value` as something C can take the address of and read twice
+    fn lvalue(&mut self, value: Value) -> String {
+        let text = self.operand(value);
+        if is_lvalue(&text) {
+            return text;
+        }
+        let name = self.variable(value);
+        self.line(&format!("{} = {};", name, unwrap_parens(&text)));
+        name
+    }
+
+    /// The C text of the string a value holds, when it is a literal
+    fn literal_text(&self, value: Value) -> Option<String> {
+        match self.frame.defs.get(&value) {
+            Some(Op::Const(Const::Str(text))) => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    // ========== Instructions ==========
+
+    fn inst(&mut self, result: Option<Value>, op: &Op, span: Span) {
+        self.frame.taken = Effect::Pure;
+        let (text, effect) = match op {
+            Op::Const(value) => {
+                if let Some(result) = result {
+                    let text = self.constant(value, &self.value_ty(result));
+                    self.frame.texts.insert(result, text);
+                }
+                return;
+            }
+            Op::Func(id) => {
+                let name = self.fn_name(*id);
+                if let Some(result) = result {
+                    self.frame.texts.insert(result, name);
+                }
+                return;
+            }
+            Op::Unary(op, value) => (self.unary(op, *value, span), Effect::Pure),
+            Op::Binary(op, left, right) => (self.binary(op, *left, *right, span), Effect::Pure),
+            Op::Call(callee, args) => {
+                let ty = result.map_or_else(Ty::unit, |result| self.value_ty(result));
+                (self.call(callee, args, &ty, span), Effect::Reads)
+            }
+            Op::Tuple(values) if values.is_empty() => ("ML_UNIT".to_string(), Effect::Pure),
+            Op::Tuple(values) => {
+                let ty = result.map_or(Ty::Error, |result| self.value_ty(result));
+                let elems = match &ty {
+                    Ty::Tuple(elems) => elems.clone(),
+                    _ => Vec::new(),
+                };
+                let values = self.args(values, &elems);
+                (format!("({}){{ {} }}", self.c_type(&ty, span), values.join(", ")), Effect::Pure)
+            }
+            Op::Array(values) => {
+                let ty = result.map_or(Ty::Error, |result| self.value_ty(result));
+                let elem = ir::element(&ty, self.res).unwrap_or(Ty::Error);
+                let values = self.args(values, &vec![elem; values.len()]);
+                (format!("({}){{ {{ {} }} }}", self.c_type(&ty, span), values.join(", ")), Effect::Pure)
+            }
+            Op::Construct(def, values) => {
+                let ty = result.map_or(Ty::Error, |result| self.value_ty(result));
+                let index = self.krate.variant_of(*def).map_or(0, |(_, index)| index);
+                let (names, tys): (Vec<String>, Vec<Ty>) = self.krate.fields_of(&ty, index).into_iter().unzip();
+                let values = self.args(values, &tys);
+                (self.construct(*def, &ty, names.into_iter().zip(values).collect(), span), Effect::Pure)
+            }
+            Op::Tag(place) => {
+                let (text, ty) = self.place(place);
+                if let Some(result) = result {
+                    self.frame.tags.insert(result, ty);
+                }
+                (member(&text, "tag"), Effect::Reads)
+            }
+            Op::Load(place) => match self.place(place) {
+                (_, ty) if is_void(&ty) => ("ML_UNIT".to_string(), Effect::Pure),
+                (text, _) => (text, Effect::Reads),
+            },
+            Op::Ref(place, mutable) => {
+                // The address of a slot, or of a field in one, never changes
+                let fixed = matches!(place.base, Base::Slot(_))
+                    && place.proj.iter().all(|proj| matches!(proj, Proj::Field { .. }));
+                match self.place(place) {
+                    (text, ty) if !mutable && self.krate.is_str(&ty) => (text, Effect::Reads),
+                    (text, _) if fixed => {
+                        if let Some(result) = result {
+                            self.frame.texts.insert(result, address(&text));
+                        }
+                        return;
+                    }
+                    (text, _) => (address(&text), Effect::Pure),
+                }
+            }
+            Op::Store(place, value) => return self.store(place, *value, span),
+            Op::Drop(place) => return self.drop_place(place, span),
+            Op::Check(check) => {
+                let message = format!("{} `{}` of `{}` violated", check.kind.describe(), check.clause, check.function);
+                let cond = self.operand(check.cond);
+                return self.line(&format!("assert({} && {});", paren(&cond), c_string(&message)));
+            }
+        };
+        match result {
+            Some(result) => self.define(result, text, effect),
+            None if has_call(&text) => self.line(&statement(&text)),
+            None => {}
+        }
+    }
+
+    fn constant(&self, value: &Const, ty: &Ty) -> String {
+        match value {
+            Const::Int(n) => match ty {
+                Ty::Prim(PrimitiveType::F32) => format!("{:?}f", *n as f64),
+                Ty::Prim(PrimitiveType::F64) => format!("{:?}", *n as f64),
+                _ if i32::try_from(*n).is_ok() => n.to_string(),
+                Ty::Prim(PrimitiveType::U64 | PrimitiveType::Usize) => format!("UINT64_C({})", n),
+                _ => format!("INT64_C({})", n),
+            },
+            Const::Float(x) if *ty == Ty::Prim(PrimitiveType::F32) => format!("{:?}f", x),
+            Const::Float(x) => format!("{:?}", x),
+            Const::Str(s) => format!("ML_STR({})", c_string(s)),
+            Const::Char(c) => char_literal(*c),
+            Const::Bool(b) => b.to_string(),
+            Const::Unit => "ML_UNIT".to_string(),
+        }
+    }
+
+    fn unary(&mut self, op: &UnaryOp, value: Value, span: Span) -> String {
+        let ty = self.value_ty(value);
+        let text = self.operand(value);
+        let text = self.peel_value(text, &ty);
+        let base = self.krate.peel(&ty);
+        match op {
+            UnaryOp::Neg => {
+                // Negated literals are constants, which cannot overflow
+                let literal = matches!(self.frame.defs.get(&value), Some(Op::Const(_)));
+                match base {
+                    Ty::Prim(prim) if base.is_integral() && !literal => {
+                        format!("ml_neg_{}({}, {})", prim_name(prim), text, self.loc(span))
+                    }
+                    _ if text.starts_with('-') => format!("(-({}))", text),
+                    _ => format!("(-{})", text),
+                }
+            }
+            _ if base == Ty::bool() => negate(&text),
+            _ => format!("(~{})", text),
+        }
+    }
+
+    fn binary(&mut self, op: &BinaryOp, left: Value, right: Value, span: Span) -> String {
+        let (left_ty, right_ty) = (self.value_ty(left), self.value_ty(right));
+        let l = self.operand(left);
+        let mut r = self.operand(right);
+        // A variant's index is compared with the tag by the variant's name
+        let index = match self.frame.defs.get(&right) {
+            Some(Op::Const(Const::Int(n))) => usize::try_from(*n).ok(),
+            _ => None,
+        };
+        if let (Some(Ty::Adt(def, args)), Some(index)) = (self.frame.tags.get(&left).cloned(), index) {
+            if let Some(variant) = self.types.adts.get(&def).and_then(|adt| adt.variants.get(index)) {
+                let t = self.c_type(&Ty::Adt(def, args), span);
+                r = self.tag(&t, variant.def);
+            }
+        }
+        let l = self.peel_value(l, &left_ty);
+        let r = self.peel_value(r, &right_ty);
+        let (left_ty, right_ty) = (self.krate.peel(&left_ty), self.krate.peel(&right_ty));
+        // Adding anything else to a string appends its text
+        if *op == BinaryOp::Add && self.krate.is_str(&left_ty) && !self.krate.is_str(&right_ty) {
+            let buf = self.buffer();
+            self.line(&format!("ml_buf_str(&{}, {});", buf, l));
+            let show = self.fmt_value(&format!("&{}", buf), &r, &right_ty, "ML_PLAIN", false, span);
+            self.line(&format!("{};", show));
+            return format!("ml_buf_finish(&{})", buf);
+        }
+        self.operate(op, l, r, &left_ty, span)
+    }
+
+    fn call(&mut self, callee: &Callee, args: &[Value], ty: &Ty, span: Span) -> String {
+        match callee {
+            Callee::Fn(id) => {
+                let func = self.lowering.module().function(*id);
+                let (def, generics) = (func.def, func.generics.clone());
+                // The callee may not be lowered yet, so its parameters come from its signature
+                let params = match self.types.sigs.get(&def) {
+                    Some(sig) => {
+                        let subst: HashMap<DefId, Ty> = sig.generics.iter().copied().zip(generics).collect();
+                        sig.inputs.iter().map(|input| input.subst(&subst)).collect()
+                    }
+                    None => Vec::new(),
+                };
+                let name = self.fn_name(*id);
+                let values = self.args(args, &params);
+                format!("{}({})", name, values.join(", "))
+            }
+            Callee::Value(function) => {
+                let params = match self.value_ty(*function) {
+                    Ty::Fn(params, _) => params,
+                    _ => Vec::new(),
+                };
+                let function = self.operand(*function);
+                let values = self.args(args, &params);
+                format!("{}({})", function, values.join(", "))
+            }
+            Callee::Library(path) => self.library_call(path, args, ty, span),
+        }
+    }
+
+    /// Texts of `args`, as the types `params` they are passed as
+    fn args(&mut self, args: &[Value], params: &[Ty]) -> Vec<String> {
+        let mut values = Vec::new();
+        for (i, &arg) in args.iter().enumerate() {
+            let from = self.value_ty(arg);
+            let value = self.operand(arg);
+            values.push(match params.get(i) {
+                Some(to) => self.coerce(value, &from, to),
+                None => value,
+            });
+        }
+        values
+    }
+
+    fn store(&mut self, place: &Place, value: Value, span: Span) {
+        let from = self.value_ty(value);
+        let text = self.operand(value);
+        let (target, ty) = self.place(place);
+        let text = self.coerce(text, &from, &ty);
+        if is_void(&ty) {
+            if has_call(&text) {
+                self.line(&statement(&text));
+            }
+            return;
+        }
+        // C leaves open which side of an assignment is evaluated first
+        let text = match has_call(&target) && has_call(&text) {
+            true => self.temp(&ty, Some(&text), span),
+            false => text,
+        };
+        self.line(&format!("{} = {};", target, unwrap_parens(&text)));
+    }
+
+    fn drop_place(&mut self, place: &Place, span: Span) {
+        let (text, ty) = self.place(place);
+        if is_void(&ty) {
+            return;
+        }
+        match self.cleanup_fn(&ty, span) {
+            Some(cleanup) => self.line(&format!("{}({});", cleanup, address(&text))),
+            None => self.line(&format!("(void){};", text)),
+        }
+    }
+
+    // ========== Places ==========
+
+    /// Where `place` is, as a C lvalue, and the type of what is there
+    fn place(&mut self, place: &Place) -> (String, Ty) {
+        let (mut text, mut ty) = match place.base {
+            Base::Slot(slot) => self.frame.slots[slot.0 as usize].clone(),
+            // A pointer need not be a variable to be followed
+            Base::Value(value) if place.proj.first() == Some(&Proj::Deref) => {
+                (self.operand(value), self.value_ty(value))
+            }
+            Base::Value(value) => (self.lvalue(value), self.value_ty(value)),
+        };
+        for proj in &place.proj {
+            match proj {
+                Proj::Deref => {
+                    text = self.deref(text, &ty);
+                    ty = ir::pointee(&ty, self.res).unwrap_or(Ty::Error);
+                }
+                Proj::Field { variant, index } => {
+                    let (name, field_ty) =
+                        ir::field(&ty, *variant, *index, self.types).unwrap_or_else(|| (index.to_string(), Ty::Error));
+                    let name = match &ty {
+                        Ty::Adt(def, _) if self.krate.is_enum(&ty) => {
+                            let variant = self.types.adts[def].variants[*variant].def;
+                            format!("as.{}.{}", field_name(&self.res.def(variant).name), field_name(&name))
+                        }
+                        _ => field_name(&name),
+                    };
+                    text = member(&text, &name);
+                    ty = field_ty;
+                }
+                Proj::Index(index) => {
+                    let index = self.operand(*index);
+                    text = self.element(text, &ty, &index, self.frame.span);
+                    ty = ir::element(&ty, self.res).unwrap_or(Ty::Error);
+                }
+            }
+        }
+        (text, ty)
+    }
+
+    // ========== Terminators ==========
+
+    fn terminator(&mut self, term: &Terminator) {
+        self.frame.taken = Effect::Pure;
+        match term {
+            Terminator::Jump(target) => self.edge(target, true),
+            Terminator::Branch { cond, then, otherwise } => {
+                let c = self.operand(*cond);
+                // The edge that does not fall through to the next block is taken under the condition
+                let (c, first, second) =
+                    match (self.frame.next == Some(then.block), self.frame.next == Some(otherwise.block)) {
+                        (true, false) => (negate(&c), otherwise, then),
+                        _ => (c, then, otherwise),
+                    };
+                self.open(&format!("if ({}) {{", unwrap_parens(&c)));
+                self.edge(first, false);
+                self.close("}");
+                self.edge(second, true);
+            }
+            Terminator::Return(value) => {
+                let from = self.value_ty(*value);
+                let text = self.operand(*value);
+                match self.frame.ret.clone() {
+                    Some(ret) => {
+                        let text = self.coerce(text, &from, &ret);
+                        self.line(&format!("return {};", unwrap_parens(&text)));
+                    }
+                    None => {
+                        if has_call(&text) {
+                            self.line(&statement(&text));
+                        }
+                        // The last block runs off the end, unless nothing follows its label
+                        if self.frame.next.is_some() || self.frame.out.ends_with(":\n") {
+                            self.line("return;");
+                        }
+                    }
+                }
+            }
+            Terminator::Unreachable => {
+                if !self.frame.diverged {
+                    let loc = self.loc(self.frame.span);
+                    self.line(&format!("ml_panic({}, ML_STR(\"internal error: entered unreachable code\"));", loc));
+                }
+            }
+        }
+    }
+
+    /// Pass `target` its arguments and go there, unless it comes next and `fall` allows running into it
+    fn edge(&mut self, target: &Target, fall: bool) {
+        let params = self.frame.params[target.block.0 as usize].clone();
+        let mut copies = Vec::new();
+        for (&arg, &param) in target.args.iter().zip(&params) {
+            if arg == param || self.frame.uses[param.0 as usize] == 0 {
+                continue;
+            }
+            let text = self.operand(arg);
+            match self.frame.texts.get(&param).cloned() {
+                Some(name) if name != "ML_UNIT" => copies.push((name, text, param)),
+                _ if has_call(&text) => self.line(&statement(&text)),
+                _ => {}
+            }
+        }
+        // The parameters take their arguments at once, so none is overwritten before a later argument reads it
+        let clobbers = copies
+            .iter()
+            .enumerate()
+            .any(|(i, (name, _, _))| copies[i + 1..].iter().any(|(_, text, _)| mentions(text, name)));
+        if clobbers {
+            for (_, text, param) in &mut copies {
+                *text = self.temp(&self.value_ty(*param), Some(text), self.frame.span);
+            }
+        }
+        for (name, text, _) in copies {
+            if name != text {
+                self.line(&format!("{} = {};", name, unwrap_parens(&text)));
+            }
+        }
+        if !fall || self.frame.next != Some(target.block) {
+            self.frame.labels.insert(target.block);
+            self.line(&format!("goto bb{};", target.block.0));
+        }
+    }
+
+    // ========== Expressions ==========
+
+    /// A struct or variant literal of type `ty`, given the values of its fields
+    fn construct(&mut self, def: DefId, ty: &Ty, fields: Vec<(String, String)>, span: Span) -> String {
+        let t = self.c_type(ty, span);
+        let inits: Vec<String> =
+            fields.iter().map(|(name, value)| format!(".{} = {}", field_name(name), value)).collect();
+        if self.krate.is_enum(ty) {
+            let tag = self.tag(&t, def);
+            if inits.is_empty() {
+                return format!("({}){{ .tag = {} }}", t, tag);
+            }
+            let variant = field_name(&self.res.def(def).name);
+            return format!("({}){{ .tag = {}, .as.{} = {{ {} }} }}", t, tag, variant, inits.join(", "));
+        }
+        match inits.is_empty() {
+            true => format!("({}){{ 0 }}", t),
+            false => format!("({}){{ {} }}", t, inits.join(", ")),
+        }
+    }
+
+    fn operate(&mut self, op: &BinaryOp, l: String, r: String, ty: &Ty, span: Span) -> String {
+        let symbol = c_op(op);
+        if self.krate.is_str(ty) {
+            return match op {
+                BinaryOp::Add => format!("ml_str_concat({}, {})", l, r),
+                BinaryOp::Eq => format!("ml_str_eq({}, {})", l, r),
+                BinaryOp::Ne => format!("!ml_str_eq({}, {})", l, r),
+                BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => {
+                    format!("(ml_str_cmp({}, {}) {} 0)", l, r, symbol)
+                }
+                _ => {
+                    self.unsupported(format!("`{}` on strings", symbol), span);
+                    "0".to_string()
+                }
+            };
+        }
+        let checked = match op {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Mod => "rem",
+            _ => "",
+        };
+        match (ty, op) {
+            (Ty::Prim(prim), _) if ty.is_integral() && !checked.is_empty() => {
+                format!("ml_{}_{}({}, {}, {})", checked, prim_name(*prim), l, r, self.loc(span))
+            }
+            (Ty::Prim(PrimitiveType::F64), BinaryOp::Mod) => format!("fmod({}, {})", l, r),
+            (Ty::Prim(PrimitiveType::F32), BinaryOp::Mod) => format!("fmodf({}, {})", l, r),
+            (Ty::Prim(_), _) => format!("({} {} {})", l, symbol, r),
+            (_, BinaryOp::Eq) => self.eq(&l, &r, ty, span),
+            (_, BinaryOp::Ne) => format!("!{}", self.eq(&l, &r, ty, span)),
+            _ => {
+                self.unsupported(format!("`{}` on `{}`", symbol, ty.display(self.res)), span);
+                "0".to_string()
+            }
+        }
+    }
+
+    /// Element `index` of the vector or array `base`, checked against its length
+    fn element(&mut self, base: String, ty: &Ty, index: &str, span: Span) -> String {
+        let mut base = self.peel_value(base, ty);
+        let sequence = self.krate.peel(ty);
+        // The length check reads the collection a second time
+        if has_call(&base) {
+            let pointer_ty = Ty::Ref { ty: Box::new(sequence.clone()), mutable: true };
+            let target = self.temp(&pointer_ty, Some(&format!("&{}", base)), span);
+            base = format!("(*{})", target);
+        }
+        let loc = self.loc(span);
+        let index = unwrap_parens(index);
+        match &sequence {
+            Ty::Array(_, Some(len)) => format!("{}[ml_index({}, {}, {})]", member(&base, "at"), index, len, loc),
+            _ if self.krate.is_vec(&sequence) => {
+                format!("{}[ml_index({}, {}, {})]", member(&base, "data"), index, member(&base, "len"), loc)
+            }
+            _ => {
+                self.unsupported(format!("indexing `{}`", sequence.display(self.res)), span);
+                "0".to_string()
+            }
+        }
+    }
+
+    /// A pointer to a value that is not a place: compound literals have one, anything else goes in a temporary
+    fn address_of_value(&mut self, value: String, ty: &Ty, span: Span) -> String {
+        let t = self.c_type(ty, span);
+        if value.starts_with(&format!("({}){{", t)) {
+            return format!("&{}", value);
+        }
+        let target = self.temp(ty, Some(&value), span);
+        format!("&{}", target)
+    }
+
+    /// `value` of type `from` as a `to`, following a reference the checker let stand for its value
+    fn coerce(&mut self, value: String, from: &Ty, to: &Ty) -> String {
+        match (from, to) {
+            (Ty::Ref { ty: a, mutable: true }, Ty::Ref { ty: b, mutable: false }) if a == b && self.krate.is_str(b) => {
+                format!("(*{})", value)
+            }
+            (Ty::Ref { ty: inner, .. }, _) if **inner == *to => self.deref(value, from),
+            _ => value,
+        }
+    }
+
+    // ========== Calls ==========
+
+    /// Library functions named by path, such as `String::from` or `process::exit`
+    fn library_call(&mut self, path: &str, args: &[Value], ty: &Ty, span: Span) -> String {
+        match mono::library_call(path, args) {
+            LibraryCall::StringNew => "ML_STR(\"\")".to_string(),
+            LibraryCall::StringFrom(text) => {
+                let value = self.operand(*text);
+                self.peel_value(value, &self.value_ty(*text))
+            }
+            LibraryCall::VecNew(capacity) => {
+                if let Some(capacity) = capacity {
+                    let capacity = self.operand(*capacity);
+                    if has_call(&capacity) {
+                        self.line(&statement(&capacity));
+                    }
+                }
+                format!("({}){{ NULL, 0, 0 }}", self.c_type(ty, span))
+            }
+            LibraryCall::VecFrom(items) => {
+                let Ty::Adt(_, elems) = ty else { return "0".to_string() };
+                let elem = elems.first().cloned().unwrap_or(Ty::Error);
+                let from = self.vec_from(ty, &elem, span);
+                let items_ty = self.value_ty(*items);
+                let len = match self.krate.peel(&items_ty) {
+                    Ty::Array(_, Some(len)) => len,
+                    _ => return self.unsupported_method("from", ty, span),
+                };
+                let array = match self.krate.is_pointer(&items_ty) {
+                    true => self.operand(*items),
+                    false => self.lvalue(*items),
+                };
+                let array = self.peel_value(array, &items_ty);
+                format!("{}({}, {})", from, member(&array, "at"), len)
+            }
+            LibraryCall::BoxNew(value) => {
+                let inner = match ty {
+                    Ty::Adt(_, args) => args.first().cloned().unwrap_or(Ty::Error),
+                    _ => Ty::Error,
+                };
+                let from = self.value_ty(*value);
+                let value = self.operand(*value);
+                let value = self.coerce(value, &from, &inner);
+                let pointer = self.address_of_value(value, &inner, span);
+                let t = self.c_type(ty, span);
+                format!("({})ml_box({}, sizeof({}))", t, pointer, self.c_type(&inner, span))
+            }
+            LibraryCall::Exit(code) => format!("ml_exit((int){})", self.operand(*code)),
+            LibraryCall::Args => format!("{}()", self.env_args(ty, span)),
+            LibraryCall::Swap(a, b) => {
+                let (a_ty, b_ty) = (self.value_ty(*a), self.value_ty(*b));
+                let pointee = self.krate.pointee(&a_ty).unwrap_or(Ty::Error);
+                let (a, b) = (self.operand(*a), self.operand(*b));
+                let a = self.target(a, &a_ty, span);
+                let b = self.target(b, &b_ty, span);
+                let old = self.temp(&pointee, Some(&a), span);
+                self.line(&format!("{} = {};", a, b));
+                self.line(&format!("{} = {};", b, old));
+                "ML_UNIT".to_string()
+            }
+            LibraryCall::Replace(dest, value) => {
+                let (dest_ty, from) = (self.value_ty(*dest), self.value_ty(*value));
+                let pointee = self.krate.pointee(&dest_ty).unwrap_or(Ty::Error);
+                let (dest, value) = (self.operand(*dest), self.operand(*value));
+                let value = self.coerce(value, &from, &pointee);
+                let dest = self.target(dest, &dest_ty, span);
+                let old = self.temp(&pointee, Some(&dest), span);
+                self.line(&format!("{} = {};", dest, unwrap_parens(&value)));
+                old
+            }
+            LibraryCall::Prelude(name) => self.prelude_call(name, args, ty, span),
+            // A method is named under the type of its receiver
+            LibraryCall::Unknown
+                if args.first().is_some_and(|&recv| {
+                    path.starts_with(&format!("{}::", self.krate.owner(&self.value_ty(recv))))
+                }) =>
+            {
+                self.library_method(path, args, ty, span)
+            }
+            LibraryCall::ReadFile(_) | LibraryCall::WriteFile(..) | LibraryCall::Unknown => {
+                self.unsupported(format!("`{}`", path), span);
+                "0".to_string()
+            }
+        }
+    }
+
+    /// What the pointer `value` points to, safe to assign and to read more than once
+    fn target(&mut self, value: String, ty: &Ty, span: Span) -> String {
+        let value = match has_call(&value) {
+            true => self.temp(ty, Some(&value), span),
+            false => value,
+        };
+        self.deref(value, ty)
+    }
+
+    /// A pointer to the receiver of a method that changes it or reads it in place
+    fn receiver_pointer(&mut self, value: String, ty: &Ty, span: Span) -> String {
+        if !self.krate.is_pointer(ty) {
+            return match is_lvalue(&value) {
+                true => address(&value),
+                false => self.address_of_value(value, ty, span),
+            };
+        }
+        let (mut value, mut ty) = (value, ty.clone());
+        while let Some(inner) = self.krate.pointee(&ty).filter(|inner| self.krate.is_pointer(inner)) {
+            value = self.deref(value, &ty);
+            ty = inner;
+        }
+        value
+    }
+
+    /// Methods of library types, such as `Vec::push`, with the receiver first among `args`
+    fn library_method(&mut self, path: &str, args: &[Value], ty: &Ty, span: Span) -> String {
+        let method = path.rsplit("::").next().unwrap_or(path);
+        let (receiver, args) = (args[0], &args[1..]);
+        let receiver_ty = self.value_ty(receiver);
+        let base = self.krate.peel(&receiver_ty);
+        let in_place = self.krate.is_vec(&base) || self.krate.is_str(&base) && matches!(method, "push" | "push_str");
+        let recv = self.operand(receiver);
+        let recv = match in_place {
+            true => self.receiver_pointer(recv, &receiver_ty, span),
+            false => self.peel_value(recv, &receiver_ty),
+        };
+        let mut values = Vec::new();
+        for &arg in args {
+            let value = self.operand(arg);
+            values.push(self.peel_value(value, &self.value_ty(arg)));
+        }
+        let arg_tys: Vec<Ty> = args.iter().map(|&arg| self.krate.peel(&self.value_ty(arg))).collect();
+        match (&base, method, values.as_slice()) {
+            (_, "clone" | "to_owned", []) if !self.krate.is_vec(&base) => recv,
+            (base, _, _) if self.krate.is_str(base) => match (method, values.as_slice()) {
+                ("len", []) => member(&recv, "len"),
+                ("is_empty", []) => format!("({} == 0)", member(&recv, "len")),
+                ("to_string" | "as_str" | "into", []) => recv,
+                ("chars", []) => format!("{}({})", self.chars_fn(ty, span), recv),
+                ("trim", []) => format!("ml_str_trim({})", recv),
+                ("to_uppercase", []) => format!("ml_str_map_case({}, true)", recv),
+                ("to_lowercase", []) => format!("ml_str_map_case({}, false)", recv),
+                ("eq", [other]) => format!("ml_str_eq({}, {})", recv, other),
+                ("contains" | "starts_with" | "ends_with", [part]) => {
+                    let part = match arg_tys[0] {
+                        Ty::Prim(PrimitiveType::Char) => format!("ml_str_from_char({})", part),
+                        _ => part.clone(),
+                    };
+                    format!("ml_str_{}({}, {})", method, recv, part)
+                }
+                ("push_str", [part]) => format!("ml_str_push({}, {})", recv, part),
+                ("push", [c]) => format!("ml_str_push_char({}, {})", recv, c),
+                _ => self.unsupported_method(method, base, span),
+            },
+            (Ty::Prim(prim), _, _) => match (prim, method, values.as_slice()) {
+                (PrimitiveType::F32 | PrimitiveType::F64, _, _) => {
+                    let suffix = if *prim == PrimitiveType::F32 { "f" } else { "" };
+                    match (method, values.as_slice()) {
+                        ("sqrt" | "floor" | "ceil" | "round" | "sin" | "cos" | "tan" | "exp" | "ln", []) => {
+                            let name = if method == "ln" { "log" } else { method };
+                            format!("{}{}({})", name, suffix, recv)
+                        }
+                        ("abs", []) => format!("fabs{}({})", suffix, recv),
+                        ("powi" | "powf", [n]) => format!("pow{}({}, {})", suffix, recv, n),
+                        ("min" | "max", [other]) => format!("f{}{}({}, {})", method, suffix, recv, other),
+                        ("to_string", []) => format!("ml_f64_to_str({})", recv),
+                        _ => self.unsupported_method(method, &base, span),
+                    }
+                }
+                (_, "to_string", []) => match prim {
+                    PrimitiveType::Bool => format!("({} ? ML_STR(\"true\") : ML_STR(\"false\"))", recv),
+                    PrimitiveType::Char => format!("ml_str_from_char({})", recv),
+                    prim if is_unsigned(*prim) => format!("ml_u64_to_str({})", recv),
+                    _ => format!("ml_i64_to_str({})", recv),
+                },
+                (_, "abs", []) if !is_unsigned(*prim) => {
+                    let value = self.pure(recv, &base, span);
+                    format!("({0} < 0 ? -{0} : {0})", value)
+                }
+                (_, "min" | "max", [other]) => {
+                    let (a, b) = (self.pure(recv, &base, span), self.pure(other.clone(), &base, span));
+                    let symbol = if method == "min" { "<" } else { ">" };
+                    format!("({0} {1} {2} ? {0} : {2})", a, symbol, b)
+                }
+                (_, "pow", [n]) => format!("{}({}, {})", self.pow_fn(&base, span), recv, n),
+                _ => self.unsupported_method(method, &base, span),
+            },
+            (Ty::Adt(_, elems), _, _) if self.krate.is_vec(&base) => {
+                let elem = elems.first().cloned().unwrap_or(Ty::Error);
+                match (method, values.as_slice()) {
+                    ("len", []) => through(&recv, "len"),
+                    ("is_empty", []) => format!("({} == 0)", through(&recv, "len")),
+                    ("clear", []) => {
+                        self.line(&format!("{} = 0;", through(&recv, "len")));
+                        "ML_UNIT".to_string()
+                    }
+                    ("push", [value]) => {
+                        let push = self.vec_push(&base, &elem, span);
+                        format!("{}({}, {})", push, recv, value)
+                    }
+                    ("pop", []) => format!("{}({})", self.vec_pop(&base, &elem, ty, span), recv),
+                    ("get", [index]) => format!("{}({}, {})", self.vec_get(&base, ty, span), recv, index),
+                    ("first", []) => format!("{}({}, 0)", self.vec_get(&base, ty, span), recv),
+                    ("last", []) => {
+                        let get = self.vec_get(&base, ty, span);
+                        let pointer_ty = Ty::Ref { ty: Box::new(base.clone()), mutable: true };
+                        let recv = self.pure(recv, &pointer_ty, span);
+                        format!("{}({}, (int64_t){} - 1)", get, recv, through(&recv, "len"))
+                    }
+                    ("contains", [value]) => format!("{}({}, {})", self.vec_contains(&base, &elem, span), recv, value),
+                    ("clone", []) => format!("{}({})", self.vec_clone(&base, &elem, span), recv),
+                    ("insert", [index, value]) => {
+                        let insert = self.vec_insert(&base, &elem, span);
+                        let loc = self.loc(span);
+                        format!("{}({}, {}, {}, {})", insert, recv, index, value, loc)
+                    }
+                    ("remove", [index]) => {
+                        format!("{}({}, {}, {})", self.vec_remove(&base, &elem, span), recv, index, self.loc(span))
+                    }
+                    ("sort", []) => {
+                        let sort = self.vec_sort(&base, &elem, span);
+                        format!("{}({})", sort, recv)
+                    }
+                    ("reverse", []) => {
+                        let reverse = self.vec_reverse(&base, &elem, span);
+                        format!("{}({})", reverse, recv)
+                    }
+                    _ => self.unsupported_method(method, &base, span),
+                }
+            }
+            (Ty::Array(_, Some(len)), "len" | "is_empty", []) => {
+                if has_call(&recv) {
+                    self.line(&format!("{};", recv));
+                }
+                match method {
+                    "len" => len.to_string(),
+                    _ => (*len == 0).to_string(),
+                }
+            }
+            (Ty::Adt(def, _), _, _)
+                if self.krate.is_prelude(*def, "Option") || self.krate.is_prelude(*def, "Result") =>
+            {
+                let t = self.c_type(&base, span);
+                let (present, _) =
+                    self.krate.variant_named(&base, if self.krate.is_prelude(*def, "Option") { "Some" } else { "Ok" });
+                let tag = self.tag(&t, present);
+                match (method, values.as_slice()) {
+                    ("is_some" | "is_ok", []) => format!("({}.tag == {})", recv, tag),
+                    ("is_none" | "is_err", []) => format!("({}.tag != {})", recv, tag),
+                    ("unwrap", []) => format!("{}({}, {})", self.unwrap_fn(&base, span), recv, self.loc(span)),
+                    ("expect", [message]) => {
+                        format!("{}({}, {}, {})", self.expect_fn(&base, span), recv, message, self.loc(span))
+                    }
+                    ("unwrap_or", [default]) => {
+                        let value = self.pure(recv, &base, span);
+                        let field = if self.krate.is_prelude(*def, "Option") { "Some" } else { "Ok" };
+                        format!("({0}.tag == {1} ? {0}.as.{2}._0 : {3})", value, tag, field, default)
+                    }
+                    _ => self.unsupported_method(method, &base, span),
+                }
+            }
+            _ => self.unsupported_method(method, &base, span),
+        }
+    }
+
+    fn unsupported_method(&mut self, method: &str, ty: &Ty, span: Span) -> String {
+        self.unsupported(format!("method `{}` of `{}`", method, ty.display(self.res)), span);
+        "0".to_string()
+    }
+
+    /// `value` if reading it twice is harmless, or else a temporary holding it
+    fn pure(&mut self, value: String, ty: &Ty, span: Span) -> String {
+        match is_name(&value) {
+            true => value,
+            false => self.temp(ty, Some(unwrap_parens(&value)), span),
+        }
+    }
+
+    /// Functions of the prelude, which the support code and generated helpers implement
+    fn prelude_call(&mut self, name: &str, args: &[Value], ty: &Ty, span: Span) -> String {
+        match name {
+            "print" | "println" | "eprint" | "eprintln" => {
+                let out = if name.starts_with('e') { "stderr" } else { "stdout" };
+                let newline = if name.ends_with("ln") { "\n" } else { "" };
+                // Text with nothing to fill in is written as it is
+                let plain = match args {
+                    [] => Some(String::new()),
+                    [first] => self.literal_text(*first),
+                    _ => None,
+                };
+                if let Some(text) = plain.filter(|text| !text.contains('\0')) {
+                    return format!("ml_puts({}, {})", out, c_string(&format!("{}{}", text, newline)));
+                }
+                let (template, values) = self.format_args(args, span);
+                let buf = self.buffer();
+                self.format_into(&buf, template.as_deref(), &values, "", span);
+                if !newline.is_empty() {
+                    self.line(&format!("ml_buf_lit(&{}, \"\\n\");", buf));
+                }
+                format!("ml_print({}, &{})", out, buf)
+            }
+            "format" => {
+                let (template, values) = self.format_args(args, span);
+                let buf = self.buffer();
+                self.format_into(&buf, template.as_deref(), &values, "", span);
+                format!("ml_buf_finish(&{})", buf)
+            }
+            "panic" | "unreachable" | "todo" => {
+                let default = match name {
+                    "panic" => "explicit panic",
+                    "unreachable" => "internal error: entered unreachable code",
+                    _ => "not yet implemented",
+                };
+                let (template, values) = self.format_args(args, span);
+                let buf = self.buffer();
+                self.format_into(&buf, template.as_deref(), &values, default, span);
+                format!("ml_panic_buf({}, &{})", self.loc(span), buf)
+            }
+            "assert" => {
+                let Some((&cond, rest)) = args.split_first() else { return "ML_UNIT".to_string() };
+                let c = self.operand(cond);
+                // The message is made only when the assertion fails
+                let (template, values) = self.format_args(rest, span);
+                self.open(&format!("if ({}) {{", negate(&c)));
+                let buf = self.buffer();
+                self.format_into(&buf, template.as_deref(), &values, "assertion failed", span);
+                self.line(&format!("ml_panic_buf({}, &{});", self.loc(span), buf));
+                self.close("}");
+                "ML_UNIT".to_string()
+            }
+            "assert_eq" | "assert_ne" => {
+                let [left, right, rest @ ..] = args else { return "ML_UNIT".to_string() };
+                let (left_ty, right_ty) = (self.value_ty(*left), self.value_ty(*right));
+                let values = self.args(&[*left, *right], &[left_ty.clone(), right_ty.clone()]);
+                let (template, shown) = self.format_args(rest, span);
+                let l = self.pure(values[0].clone(), &left_ty, span);
+                let r = self.pure(values[1].clone(), &right_ty, span);
+                let (pl, pr) = (self.peel_value(l, &left_ty), self.peel_value(r, &right_ty));
+                let base = self.krate.peel(&left_ty);
+                let equal = self.operate(&BinaryOp::Eq, pl.clone(), pr.clone(), &base, span);
+                let op = if name == "assert_eq" { "==" } else { "!=" };
+                let failed = if name == "assert_eq" { negate(&equal) } else { equal };
+                self.open(&format!("if ({}) {{", unwrap_parens(&failed)));
+                let buf = self.buffer();
+                let default = format!("assertion `left {} right` failed", op);
+                self.format_into(&buf, template.as_deref(), &shown, &default, span);
+                self.line(&format!("ml_buf_lit(&{}, \"\\n  left: \");", buf));
+                let show = self.fmt_value(&format!("&{}", buf), &pl, &base, "ML_PLAIN", true, span);
+                self.line(&format!("{};", show));
+                self.line(&format!("ml_buf_lit(&{}, \"\\n right: \");", buf));
+                let show =
+                    self.fmt_value(&format!("&{}", buf), &pr, &self.krate.peel(&right_ty), "ML_PLAIN", true, span);
+                self.line(&format!("{};", show));
+                self.line(&format!("ml_panic_buf({}, &{});", self.loc(span), buf));
+                self.close("}");
+                "ML_UNIT".to_string()
+            }
+            "dbg" => {
+                let Some(&arg) = args.first() else { return "ML_UNIT".to_string() };
+                let arg_ty = self.value_ty(arg);
+                let value = self.operand(arg);
+                let value = self.pure(value, &arg_ty, span);
+                let buf = self.buffer();
+                let prefix = format!("[{}:{}] ", span.line, span.column);
+                self.line(&format!("ml_buf_lit(&{}, {});", buf, c_string(&prefix)));
+                let shown = self.peel_value(value.clone(), &arg_ty);
+                let show =
+                    self.fmt_value(&format!("&{}", buf), &shown, &self.krate.peel(&arg_ty), "ML_PLAIN", true, span);
+                self.line(&format!("{};", show));
+                self.line(&format!("ml_buf_lit(&{}, \"\\n\");", buf));
+                self.line(&format!("ml_print(stderr, &{});", buf));
+                value
+            }
+            "old" => match args.first() {
+                Some(&arg) => self.operand(arg),
+                None => "ML_UNIT".to_string(),
+            },
+            "contract_violation" => {
+                let texts: Vec<String> = args.iter().filter_map(|&arg| self.literal_text(arg)).collect();
+                let message = match texts.as_slice() {
+                    [kind, function, clause, ..] => format!("{} `{}` of `{}` violated", kind, clause, function),
+                    _ => "contract violated".to_string(),
+                };
+                format!("ml_panic({}, ML_STR({}))", self.loc(span), c_string(&message))
+            }
+            _ => {
+                let _ = ty;
+                self.unsupported(format!("`{}`", name), span);
+                "0".to_string()
+            }
+        }
+    }
+
+    // ========== Formatting ==========
+
+    /// A new buffer for text being built
+    fn buffer(&mut self) -> String {
+        let name = self.temp_name();
+        self.frame.decls.push(format!("ml_buf {};", name));
+        self.line(&format!("{} = ml_buf_new();", name));
+        name
+    }
+
+    /// The template and the values `format` fills it with, read before
+    /// anything is written. A single argument is shown as `{}` shows it.
+    fn format_args(&mut self, args: &[Value], span: Span) -> (Option<String>, Vec<(String, Ty)>) {
+        let template = match args {
+            [first, _, ..] => match self.literal_text(*first) {
+                Some(template) => Some(template),
+                None => {
+                    self.unsupported("a format string that is not a literal", span);
+                    return (None, Vec::new());
+                }
+            },
+            _ => None,
+        };
+        let rest = if template.is_some() { &args[1..] } else { args };
+        let mut values = Vec::new();
+        for &arg in rest {
+            let ty = self.value_ty(arg);
+            let value = self.operand(arg);
+            values.push((self.peel_value(value, &ty), self.krate.peel(&ty)));
+        }
+        (template, values)
+    }
+
+    /// Append `template` filled with `values` to the buffer `buf`; with no
+    /// template, the one value or else `default`
+    fn format_into(&mut self, buf: &str, template: Option<&str>, values: &[(String, Ty)], default: &str, span: Span) {
+        let target = format!("&{}", buf);
+        let Some(template) = template else {
+            match values.first() {
+                Some((value, ty)) => {
+                    let show = self.fmt_value(&target, value, ty, "ML_PLAIN", false, span);
+                    self.line(&format!("{};", show));
+                }
+                None if !default.is_empty() => self.line(&format!("ml_buf_lit({}, {});", target, c_string(default))),
+                None => {}
+            }
+            return;
+        };
+        let mut rest = values.iter();
+        for piece in parse_template(template) {
+            match piece {
+                Piece::Text(text) => self.line(&format!("ml_buf_lit({}, {});", target, c_string(&text))),
+                Piece::Hole { text, spec, debug } => match rest.next() {
+                    Some((value, ty)) => {
+                        let spec = c_spec(&spec);
+                        let show = self.fmt_value(&target, value, ty, &spec, debug, span);
+                        self.line(&format!("{};", show));
+                    }
+                    None => self.line(&format!("ml_buf_lit({}, {});", target, c_string(&text))),
+                },
+            }
+        }
+    }
+
+    /// A call appending `value` to the buffer `buf` points to, as `{}` or `{:?}` shows it
+    fn fmt_value(&mut self, buf: &str, value: &str, ty: &Ty, spec: &str, debug: bool, span: Span) -> String {
+        match ty {
+            Ty::Prim(PrimitiveType::F32 | PrimitiveType::F64) => {
+                format!("ml_fmt_f64({}, {}, {}, {})", buf, value, spec, debug)
+            }
+            Ty::Prim(PrimitiveType::Bool) => format!("ml_fmt_bool({}, {}, {})", buf, value, spec),
+            Ty::Prim(PrimitiveType::Char) => format!("ml_fmt_char({}, {}, {}, {})", buf, value, spec, debug),
+            Ty::Prim(prim) if is_unsigned(*prim) => format!("ml_fmt_u64({}, {}, {})", buf, value, spec),
+            Ty::Prim(PrimitiveType::Str) => format!("ml_fmt_str({}, {}, {}, {})", buf, value, spec, debug),
+            Ty::Prim(_) => format!("ml_fmt_i64({}, {}, {})", buf, value, spec),
+            _ if self.krate.is_str(ty) => format!("ml_fmt_str({}, {}, {}, {})", buf, value, spec, debug),
+            _ if ty.is_unit() => format!("ml_fmt_str({}, ML_STR(\"()\"), {}, false)", buf, spec),
+            Ty::Ref { .. } => {
+                let inner = self.krate.peel(ty);
+                let value = self.peel_value(value.to_string(), ty);
+                self.fmt_value(buf, &value, &inner, spec, debug, span)
+            }
+            _ if debug => format!("{}({}, {})", self.debug_fn(ty, span), buf, value),
+            _ => format!("{}({}, {}, {})", self.display_fn(ty, span), buf, value, spec),
+        }
+    }
+
+    // ========== Helpers ==========
+
+    /// Define a static helper the first time it is asked for. `build` gets
+    /// the name and returns the prototype and the body.
+    fn helper(&mut self, name: String, build: impl FnOnce(&mut Self, &str) -> (String, String)) -> String {
+        if self.helpers.contains(&name) {
+            return name;
+        }
+        self.helpers.insert(name.clone());
+        self.taken.insert(name.clone());
+        let (proto, body) = build(self, &name);
+        self.helper_protos.push(format!("static {};", proto));
+        self.helper_defs.push(format!("static {} {{\n{}}}\n", proto, body));
+        name
+    }
+
+    /// A C expression for whether `a` and `b` of type `ty` are equal
+    fn eq(&mut self, a: &str, b: &str, ty: &Ty, span: Span) -> String {
+        match ty {
+            _ if self.krate.is_str(ty) => format!("ml_str_eq({}, {})", a, b),
+            _ if ty.is_unit() => "true".to_string(),
+            Ty::Prim(_) => format!("({} == {})", a, b),
+            Ty::Ref { .. } => {
+                let (a, b) = (self.peel_value(a.to_string(), ty), self.peel_value(b.to_string(), ty));
+                self.eq(&a, &b, &self.krate.peel(ty), span)
+            }
+            _ => {
+                // A user `eq` decides, when the type has one
+                if let Some(def) = self.krate.method_of(ty, "eq") {
+                    let by_ref = Ty::Ref { ty: Box::new(ty.clone()), mutable: false };
+                    if let Some((def, args)) = self.krate.resolve(def, &[by_ref.clone(), by_ref], &Ty::bool()) {
+                        let name = self.instance(def, args);
+                        let (a, b) = (
+                            self.address_of_value(a.to_string(), ty, span),
+                            self.address_of_value(b.to_string(), ty, span),
+                        );
+                        return format!("{}({}, {})", name, a, b);
+                    }
+                }
+                format!("{}({}, {})", self.eq_fn(ty, span), a, b)
+            }
+        }
+    }
+
+    fn eq_fn(&mut self, ty: &Ty, span: Span) -> String {
+        let t = self.c_type(ty, span);
+        self.helper(format!("eq_{}", t), |s, name| {
+            let proto = format!("bool {}({} a, {} b)", name, t, t);
+            let mut body = String::new();
+            let fields = |s: &mut Self, fields: Vec<(String, Ty)>, a: &str, b: &str| -> String {
+                let tests: Vec<String> = fields
+                    .iter()
+                    .map(|(field, field_ty)| {
+                        let field = field_name(field);
+                        s.eq(&format!("{}.{}", a, field), &format!("{}.{}", b, field), field_ty, span)
+                    })
+                    .collect();
+                if tests.is_empty() {
+                    "true".to_string()
+                } else {
+                    tests.join(" && ")
+                }
+            };
+            match ty {
+                Ty::Tuple(elems) => {
+                    let elems = elems.iter().enumerate().map(|(i, e)| (i.to_string(), e.clone())).collect();
+                    body.push_str(&format!("    return {};\n", fields(s, elems, "a", "b")));
+                }
+                Ty::Array(elem, Some(len)) => {
+                    let test = s.eq("a.at[i]", "b.at[i]", elem, span);
+                    body.push_str(&format!("    for (size_t i = 0; i < {}; i++) {{\n", len));
+                    body.push_str(&format!(
+                        "        if (!{}) {{\n            return false;\n        }}\n    }}\n",
+                        paren(&test)
+                    ));
+                    body.push_str("    return true;\n");
+                }
+                Ty::Adt(_, elems) if s.krate.is_vec(ty) => {
+                    let elem = elems.first().cloned().unwrap_or(Ty::Error);
+                    let test = s.eq("a.data[i]", "b.data[i]", &elem, span);
+                    body.push_str("    if (a.len != b.len) {\n        return false;\n    }\n");
+                    body.push_str("    for (size_t i = 0; i < a.len; i++) {\n");
+                    body.push_str(&format!(
+                        "        if (!{}) {{\n            return false;\n        }}\n    }}\n",
+                        paren(&test)
+                    ));
+                    body.push_str("    return true;\n");
+                }
+                Ty::Adt(def, _) if s.krate.is_enum(ty) => {
+                    let variants = s.types.adts[def].variants.clone();
+                    body.push_str("    if (a.tag != b.tag) {\n        return false;\n    }\n    switch (a.tag) {\n");
+                    for (index, variant) in variants.iter().enumerate() {
+                        let variant_fields = s.krate.fields_of(ty, index);
+                        if variant_fields.is_empty() {
+                            continue;
+                        }
+                        let member_name = field_name(&s.res.def(variant.def).name);
+                        let test = fields(
+                            s,
+                            variant_fields,
+                            &format!("a.as.{}", member_name),
+                            &format!("b.as.{}", member_name),
+                        );
+                        body.push_str(&format!("    case {}:\n        return {};\n", s.tag(&t, variant.def), test));
+                    }
+                    body.push_str("    default:\n        return true;\n    }\n");
+                }
+                _ => {
+                    let struct_fields = s.krate.fields_of(ty, 0);
+                    body.push_str(&format!("    return {};\n", fields(s, struct_fields, "a", "b")));
+                }
+            }
+            (proto, body)
+        })
+    }
+
+    /// `debug_T(b, v)` appends `v` as `{:?}` shows it
+    fn debug_fn(&mut self, ty: &Ty, span: Span) -> String {
+        let t = self.c_type(ty, span);
+        self.helper(format!("debug_{}", t), |s, name| {
+            let proto = format!("void {}(ml_buf *b, {} v)", name, t);
+            let mut body = String::new();
+            let show = |s: &mut Self, value: String, field_ty: &Ty, body: &mut String, indent: &str| {
+                let value = s.peel_value(value, field_ty);
+                let call = s.fmt_value("b", &value, &s.krate.peel(field_ty), "ML_PLAIN", true, span);
+                body.push_str(&format!("{}{};\n", indent, call));
+            };
+            let lit = |body: &mut String, text: &str, indent: &str| {
+                body.push_str(&format!("{}ml_buf_lit(b, {});\n", indent, c_string(text)));
+            };
+            match ty {
+                Ty::Tuple(elems) => {
+                    lit(&mut body, "(", "    ");
+                    for (i, elem) in elems.iter().enumerate() {
+                        if i > 0 {
+                            lit(&mut body, ", ", "    ");
+                        }
+                        show(s, format!("v._{}", i), elem, &mut body, "    ");
+                    }
+                    lit(&mut body, if elems.len() == 1 { ",)" } else { ")" }, "    ");
+                }
+                Ty::Array(elem, len) => {
+                    let len = len.unwrap_or(0);
+                    lit(&mut body, "[", "    ");
+                    body.push_str(&format!("    for (size_t i = 0; i < {}; i++) {{\n", len));
+                    body.push_str("        if (i > 0) {\n            ml_buf_lit(b, \", \");\n        }\n");
+                    show(s, "v.at[i]".to_string(), elem, &mut body, "        ");
+                    body.push_str("    }\n");
+                    lit(&mut body, "]", "    ");
+                }
+                Ty::Adt(_, elems) if s.krate.is_vec(ty) => {
+                    let elem = elems.first().cloned().unwrap_or(Ty::Error);
+                    lit(&mut body, "[", "    ");
+                    body.push_str("    for (size_t i = 0; i < v.len; i++) {\n");
+                    body.push_str("        if (i > 0) {\n            ml_buf_lit(b, \", \");\n        }\n");
+                    show(s, "v.data[i]".to_string(), &elem, &mut body, "        ");
+                    body.push_str("    }\n");
+                    lit(&mut body, "]", "    ");
+                }
+                Ty::Adt(def, _) => {
+                    let adt = s.types.adts.get(def).cloned();
+                    let variants = adt.map(|adt| adt.variants).unwrap_or_default();
+                    let is_enum = s.krate.is_enum(ty);
+                    if is_enum {
+                        body.push_str("    switch (v.tag) {\n");
+                    }
+                    for (index, variant) in variants.iter().enumerate() {
+                        let variant_name = s.res.def(variant.def).name.clone();
+                        let indent = if is_enum { "        " } else { "    " };
+                        let base = match is_enum {
+                            true => format!("v.as.{}", field_name(&variant_name)),
+                            false => "v".to_string(),
+                        };
+                        if is_enum {
+                            body.push_str(&format!("    case {}:\n", s.tag(&t, variant.def)));
+                        }
+                        let variant_fields = s.krate.fields_of(ty, index);
+                        match variant.kind {
+                            my_lang_typechecker::VariantKind::Unit => lit(&mut body, &variant_name, indent),
+                            my_lang_typechecker::VariantKind::Tuple => {
+                                lit(&mut body, &format!("{}(", variant_name), indent);
+                                for (i, (field, field_ty)) in variant_fields.iter().enumerate() {
+                                    if i > 0 {
+                                        lit(&mut body, ", ", indent);
+                                    }
+                                    show(s, format!("{}.{}", base, field_name(field)), field_ty, &mut body, indent);
+                                }
+                                lit(&mut body, ")", indent);
+                            }
+                            my_lang_typechecker::VariantKind::Struct => {
+                                lit(&mut body, &format!("{} {{ ", variant_name), indent);
+                                for (i, (field, field_ty)) in variant_fields.iter().enumerate() {
+                                    let separator = if i > 0 { ", " } else { "" };
+                                    lit(&mut body, &format!("{}{}: ", separator, field), indent);
+                                    show(s, format!("{}.{}", base, field_name(field)), field_ty, &mut body, indent);
+                                }
+                                lit(&mut body, " }", indent);
+                            }
+                        }
+                        if is_enum {
+                            body.push_str("        break;\n");
+                        }
+                    }
+                    if is_enum {
+                        body.push_str("    }\n");
+                    }
+                }
+                _ => {}
+            }
+            (proto, body)
+        })
+    }
+
+    /// `display_T(b, v, spec)` appends `v` as `{}` shows it: through the
+    /// type's `to_string` when it has one, or else as `{:?}` would
+    fn display_fn(&mut self, ty: &Ty, span: Span) -> String {
+        let t = self.c_type(ty, span);
+        self.helper(format!("display_{}", t), |s, name| {
+            let proto = format!("void {}(ml_buf *b, {} v, ml_spec spec)", name, t);
+            let by_ref = Ty::Ref { ty: Box::new(ty.clone()), mutable: false };
+            let to_string = s.krate.method_of(ty, "to_string").and_then(|def| {
+                let output = Ty::Adt(DefId(0), Vec::new());
+                let sig = s.types.sigs.get(&def)?;
+                let input =
+                    if matches!(sig.inputs.first(), Some(Ty::Ref { .. })) { by_ref.clone() } else { ty.clone() };
+                s.krate.resolve(def, &[input], &output).map(|(def, args)| (def, args, sig.inputs.first().cloned()))
+            });
+            let body = match to_string {
+                Some((def, args, input)) => {
+                    let function = s.instance(def, args);
+                    let receiver = if matches!(input, Some(Ty::Ref { .. })) { "&v" } else { "v" };
+                    format!("    ml_fmt_str(b, {}({}), spec, false);\n", function, receiver)
+                }
+                None => {
+                    let debug = s.debug_fn(ty, span);
+                    format!("    size_t start = b->len;\n    {}(b, v);\n    ml_pad(b, start, spec);\n", debug)
+                }
+            };
+            (proto, body)
+        })
+    }
+
+    /// `cleanup_T(&v)` runs the `Drop` impls of `v` and of what it holds,
+    /// outermost first; `None` when there are none
+    fn cleanup_fn(&mut self, ty: &Ty, span: Span) -> Option<String> {
+        if !self.krate.needs_cleanup(ty) {
+            return None;
+        }
+        let t = self.c_type(ty, span);
+        Some(self.helper(format!("cleanup_{}", t), |s, name| {
+            let proto = format!("void {}({} *v)", name, t);
+            let mut body = String::new();
+            let fields = |s: &mut Self, fields: Vec<(String, Ty)>, base: &str, indent: &str, body: &mut String| {
+                for (field, field_ty) in fields {
+                    if let Some(cleanup) = s.cleanup_fn(&field_ty, span) {
+                        body.push_str(&format!("{}{}(&{}.{});\n", indent, cleanup, base, field_name(&field)));
+                    }
+                }
+            };
+            match ty {
+                Ty::Tuple(elems) => {
+                    let elems = elems.iter().enumerate().map(|(i, e)| (i.to_string(), e.clone())).collect();
+                    fields(s, elems, "v->", "    ", &mut body);
+                }
+                Ty::Array(elem, len) => {
+                    if let Some(cleanup) = s.cleanup_fn(elem, span) {
+                        body.push_str(&format!("    for (size_t i = 0; i < {}; i++) {{\n", len.unwrap_or(0)));
+                        body.push_str(&format!("        {}(&v->at[i]);\n    }}\n", cleanup));
+                    }
+                }
+                Ty::Adt(_, elems) if s.krate.is_vec(ty) => {
+                    let elem = elems.first().cloned().unwrap_or(Ty::Error);
+                    if let Some(cleanup) = s.cleanup_fn(&elem, span) {
+                        body.push_str("    for (size_t i = 0; i < v->len; i++) {\n");
+                        body.push_str(&format!("        {}(&v->data[i]);\n    }}\n", cleanup));
+                    }
+                }
+                Ty::Adt(def, _) => {
+                    if let Some(&drop) = s.krate.drops.get(def) {
+                        let by_ref = Ty::Ref { ty: Box::new(ty.clone()), mutable: true };
+                        if let Some((def, args)) = s.krate.resolve(drop, &[by_ref], &Ty::unit()) {
+                            let function = s.instance(def, args);
+                            body.push_str(&format!("    {}(v);\n", function));
+                        }
+                    }
+                    let variants = s.types.adts[def].variants.clone();
+                    if s.krate.is_enum(ty) {
+                        body.push_str("    switch (v->tag) {\n");
+                        for (index, variant) in variants.iter().enumerate() {
+                            let base = format!("v->as.{}", field_name(&s.res.def(variant.def).name));
+                            let mut arm = String::new();
+                            fields(s, s.krate.fields_of(ty, index), &base, "        ", &mut arm);
+                            if !arm.is_empty() {
+                                body.push_str(&format!(
+                                    "    case {}:\n{}        break;\n",
+                                    s.tag(&t, variant.def),
+                                    arm
+                                ));
+                            }
+                        }
+                        body.push_str("    default:\n        break;\n    }\n");
+                    } else {
+                        fields(s, s.krate.fields_of(ty, 0), "(*v)", "    ", &mut body);
+                    }
+                }
+                _ => {}
+            }
+            (proto, body)
+        }))
+    }
+
+    fn pow_fn(&mut self, ty: &Ty, span: Span) -> String {
+        let t = self.c_type(ty, span);
+        self.helper(format!("pow_{}", t), |_, name| {
+            let proto = format!("{} {}({} base, uint32_t exp)", t, name, t);
+            let body = format!(
+                "    {} result = 1;\n    while (exp--) {{\n        result *= base;\n    }}\n    return result;\n",
+                t
+            );
+            (proto, body)
+        })
+    }
+
+    /// `Option::unwrap` and `Result::unwrap` for one type
+    fn unwrap_fn(&mut self, ty: &Ty, span: Span) -> String {
+        let t = self.c_type(ty, span);
+        self.helper(format!("{}_unwrap", t), |s, name| {
+            let is_option = matches!(ty, Ty::Adt(def, _) if s.krate.is_prelude(*def, "Option"));
+            let (present, present_index) = s.krate.variant_named(ty, if is_option { "Some" } else { "Ok" });
+            let value_ty = s.krate.fields_of(ty, present_index).first().map_or(Ty::unit(), |(_, t)| t.clone());
+            let value = s.c_type(&value_ty, span);
+            let proto = format!("{}({} v, ml_loc loc)", decl(&value, name), t);
+            let mut body = format!("    if (v.tag != {}) {{\n", s.tag(&t, present));
+            if is_option {
+                body.push_str("        ml_panic(loc, ML_STR(\"called `Option::unwrap()` on a `None` value\"));\n");
+            } else {
+                let (_, err_index) = s.krate.variant_named(ty, "Err");
+                let err_ty = s.krate.fields_of(ty, err_index).first().map_or(Ty::unit(), |(_, t)| t.clone());
+                body.push_str("        ml_buf b = ml_buf_new();\n");
+                body.push_str("        ml_buf_lit(&b, \"called `Result::unwrap()` on an `Err` value: \");\n");
+                let show = s.fmt_value("&b", "v.as.Err._0", &err_ty, "ML_PLAIN", true, span);
+                body.push_str(&format!("        {};\n        ml_panic_buf(loc, &b);\n", show));
+            }
+            body.push_str(&format!("    }}\n    return v.as.{}._0;\n", if is_option { "Some" } else { "Ok" }));
+            (proto, body)
+        })
+    }
+
+    fn expect_fn(&mut self, ty: &Ty, span: Span) -> String {
+        let t = self.c_type(ty, span);
+        self.helper(format!("{}_expect", t), |s, name| {
+            let is_option = matches!(ty, Ty::Adt(def, _) if s.krate.is_prelude(*def, "Option"));
+            let (present, present_index) = s.krate.variant_named(ty, if is_option { "Some" } else { "Ok" });
+            let value_ty = s.krate.fields_of(ty, present_index).first().map_or(Ty::unit(), |(_, t)| t.clone());
+            let value = s.c_type(&value_ty, span);
+            let proto = format!("{}({} v, ml_str message, ml_loc loc)", decl(&value, name), t);
+            let mut body = format!("    if (v.tag != {}) {{\n", s.tag(&t, present));
+            if is_option {
+                body.push_str("        ml_panic(loc, message);\n");
+            } else {
+                let (_, err_index) = s.krate.variant_named(ty, "Err");
+                let err_ty = s.krate.fields_of(ty, err_index).first().map_or(Ty::unit(), |(_, t)| t.clone());
+                body.push_str("        ml_buf b = ml_buf_new();\n        ml_buf_str(&b, message);\n");
+                body.push_str("        ml_buf_lit(&b, \": \");\n");
+                let show = s.fmt_value("&b", "v.as.Err._0", &err_ty, "ML_PLAIN", true, span);
+                body.push_str(&format!("        {};\n        ml_panic_buf(loc, &b);\n", show));
+            }
+            body.push_str(&format!("    }}\n    return v.as.{}._0;\n", if is_option { "Some" } else { "Ok" }));
+            (proto, body)
+        })
+    }
+
+    /// The program's arguments as a `Vec<String>`
+    fn env_args(&mut self, ty: &Ty, span: Span) -> String {
+        let t = self.c_type(ty, span);
+        let push = self.vec_push(ty, &Ty::Adt(self.string_def(), Vec::new()), span);
+        self.helper("env_args".to_string(), |_, name| {
+            let proto = format!("{} {}(void)", t, name);
+            let body = format!(
+                "    {} args = {{ NULL, 0, 0 }};\n    for (int i = 0; i < ml_argc; i++) {{\n        {}(&args, (ml_str){{ ml_argv[i], strlen(ml_argv[i]) }});\n    }}\n    return args;\n",
+                t, push
+            );
+            (proto, body)
+        })
+    }
+
+    fn string_def(&self) -> DefId {
+        self.res.defs.iter().find(|d| d.is_prelude() && d.name == "String").map_or(DefId(0), |d| d.id)
+    }
+
+    // ========== Vectors ==========
+
+    fn vec_push(&mut self, vec: &Ty, elem: &Ty, span: Span) -> String {
+        let (v, e) = (self.c_type(vec, span), self.c_type(elem, span));
+        self.helper(format!("{}_push", v), |_, name| {
+            let proto = format!("void {}({} *v, {} x)", name, v, e);
+            let body = format!(
+                "    v->data = ml_grow(v->data, &v->cap, v->len, sizeof({}));\n    v->data[v->len++] = x;\n",
+                e
+            );
+            (proto, body)
+        })
+    }
+
+    fn vec_pop(&mut self, vec: &Ty, elem: &Ty, option: &Ty, span: Span) -> String {
+        let (v, o) = (self.c_type(vec, span), self.c_type(option, span));
+        let _ = elem;
+        let (some, _) = self.krate.variant_named(option, "Some");
+        let (none, _) = self.krate.variant_named(option, "None");
+        let (some, none) = (self.tag(&o, some), self.tag(&o, none));
+        self.helper(format!("{}_pop", v), |_, name| {
+            let proto = format!("{} {}({} *v)", o, name, v);
+            let body = format!(
+                "    if (v->len == 0) {{\n        return ({0}){{ .tag = {1} }};\n    }}\n    return ({0}){{ .tag = {2}, .as.Some = {{ ._0 = v->data[--v->len] }} }};\n",
+                o, none, some
+            );
+            (proto, body)
+        })
+    }
+
+    /// `get`, `first` and `last`, which borrow the element
+    fn vec_get(&mut self, vec: &Ty, option: &Ty, span: Span) -> String {
+        let (v, o) = (self.c_type(vec, span), self.c_type(option, span));
+        let (some, _) = self.krate.variant_named(option, "Some");
+        let (none, _) = self.krate.variant_named(option, "None");
+        let (some, none) = (self.tag(&o, some), self.tag(&o, none));
+        self.helper(format!("{}_get", v), |_, name| {
+            let proto = format!("{} {}({} *v, int64_t i)", o, name, v);
+            let body = format!(
+                "    if (i < 0 || (size_t)i >= v->len) {{\n        return ({0}){{ .tag = {1} }};\n    }}\n    return ({0}){{ .tag = {2}, .as.Some = {{ ._0 = &v->data[i] }} }};\n",
+                o, none, some
+            );
+            (proto, body)
+        })
+    }
+
+    fn vec_contains(&mut self, vec: &Ty, elem: &Ty, span: Span) -> String {
+        let (v, e) = (self.c_type(vec, span), self.c_type(elem, span));
+        self.helper(format!("{}_contains", v), |s, name| {
+            let proto = format!("bool {}({} *v, {} x)", name, v, e);
+            let test = s.eq("v->data[i]", "x", elem, span);
+            let body = format!(
+                "    for (size_t i = 0; i < v->len; i++) {{\n        if ({}) {{\n            return true;\n        }}\n    }}\n    return false;\n",
+                unwrap_parens(&test)
+            );
+            (proto, body)
+        })
+    }
+
+    fn vec_clone(&mut self, vec: &Ty, elem: &Ty, span: Span) -> String {
+        let v = self.c_type(vec, span);
+        let push = self.vec_push(vec, elem, span);
+        self.helper(format!("{}_clone", v), |_, name| {
+            let proto = format!("{} {}({} *v)", v, name, v);
+            let body = format!(
+                "    {} copy = {{ NULL, 0, 0 }};\n    for (size_t i = 0; i < v->len; i++) {{\n        {}(&copy, v->data[i]);\n    }}\n    return copy;\n",
+                v, push
+            );
+            (proto, body)
+        })
+    }
+
+    fn vec_from(&mut self, vec: &Ty, elem: &Ty, span: Span) -> String {
+        let (v, e) = (self.c_type(vec, span), self.c_type(elem, span));
+        let push = self.vec_push(vec, elem, span);
+        self.helper(format!("{}_from", v), |_, name| {
+            let proto = format!("{} {}(const {} *items, size_t n)", v, name, e);
+            let body = format!(
+                "    {} v = {{ NULL, 0, 0 }};\n    for (size_t i = 0; i < n; i++) {{\n        {}(&v, items[i]);\n    }}\n    return v;\n",
+                v, push
+            );
+            (proto, body)
+        })
+    }
+
+    fn vec_insert(&mut self, vec: &Ty, elem: &Ty, span: Span) -> String {
+        let (v, e) = (self.c_type(vec, span), self.c_type(elem, span));
+        self.helper(format!("{}_insert", v), |_, name| {
+            let proto = format!("void {}({} *v, int64_t i, {} x, ml_loc loc)", name, v, e);
+            let body = format!(
+                "    if (i < 0 || (size_t)i > v->len) {{\n        ml_buf b = ml_buf_new();\n        ml_buf_lit(&b, \"insertion index (is \");\n        ml_fmt_i64(&b, i, ML_PLAIN);\n        ml_buf_lit(&b, \") should be <= len (is \");\n        ml_fmt_u64(&b, v->len, ML_PLAIN);\n        ml_buf_lit(&b, \")\");\n        ml_panic_buf(loc, &b);\n    }}\n    v->data = ml_grow(v->data, &v->cap, v->len, sizeof({0}));\n    memmove(&v->data[i + 1], &v->data[i], (v->len - (size_t)i) * sizeof({0}));\n    v->data[i] = x;\n    v->len++;\n",
+                e
+            );
+            (proto, body)
+        })
+    }
+
+    fn vec_remove(&mut self, vec: &Ty, elem: &Ty, span: Span) -> String {
+        let (v, e) = (self.c_type(vec, span), self.c_type(elem, span));
+        self.helper(format!("{}_remove", v), |_, name| {
+            let proto = format!("{}({} *v, int64_t i, ml_loc loc)", decl(&e, name), v);
+            let body = format!(
+                "    if (i < 0 || (size_t)i >= v->len) {{\n        ml_buf b = ml_buf_new();\n        ml_buf_lit(&b, \"removal index (is \");\n        ml_fmt_i64(&b, i, ML_PLAIN);\n        ml_buf_lit(&b, \") should be < len (is \");\n        ml_fmt_u64(&b, v->len, ML_PLAIN);\n        ml_buf_lit(&b, \")\");\n        ml_panic_buf(loc, &b);\n    }}\n    {} x = v->data[i];\n    memmove(&v->data[i], &v->data[i + 1], (v->len - (size_t)i - 1) * sizeof({}));\n    v->len--;\n    return x;\n",
+                decl(&e, "").trim_end(),
+                e
+            );
+            (proto, body)
+        })
+    }
+
+    fn vec_sort(&mut self, vec: &Ty, elem: &Ty, span: Span) -> String {
+        let (v, e) = (self.c_type(vec, span), self.c_type(elem, span));
+        let order = match elem {
+            _ if self.krate.is_str(elem) => "ml_str_cmp(x, y)".to_string(),
+            Ty::Prim(_) => "(x > y) - (x < y)".to_string(),
+            _ => {
+                self.unsupported(format!("sorting `{}`", elem.display(self.res)), span);
+                "0".to_string()
+            }
+        };
+        let compare = self.helper(format!("{}_compare", v), |_, name| {
+            let proto = format!("int {}(const void *a, const void *b)", name);
+            let body =
+                format!("    {0} x = *(const {0} *)a;\n    {0} y = *(const {0} *)b;\n    return {1};\n", e, order);
+            (proto, body)
+        });
+        self.helper(format!("{}_sort", v), |_, name| {
+            let proto = format!("void {}({} *v)", name, v);
+            let body = format!(
+                "    if (v->len > 1) {{\n        qsort(v->data, v->len, sizeof({}), {});\n    }}\n",
+                e, compare
+            );
+            (proto, body)
+        })
+    }
+
+    fn vec_reverse(&mut self, vec: &Ty, elem: &Ty, span: Span) -> String {
+        let (v, e) = (self.c_type(vec, span), self.c_type(elem, span));
+        self.helper(format!("{}_reverse", v), |_, name| {
+            let proto = format!("void {}({} *v)", name, v);
+            let body = format!(
+                "    for (size_t i = 0, j = v->len; i + 1 < j; i++, j--) {{\n        {} x = v->data[i];\n        v->data[i] = v->data[j - 1];\n        v->data[j - 1] = x;\n    }}\n",
+                decl(&e, "").trim_end()
+            );
+            (proto, body)
+        })
+    }
+
+    /// The characters of a string, which a `for` loop walks
+    fn chars_fn(&mut self, vec: &Ty, span: Span) -> String {
+        let v = self.c_type(vec, span);
+        let push = self.vec_push(vec, &Ty::Prim(PrimitiveType::Char), span);
+        self.helper(format!("{}_chars", v), |_, name| {
+            let proto = format!("{} {}(ml_str s)", v, name);
+            let body = format!(
+                "    {} v = {{ NULL, 0, 0 }};\n    ml_chars it = {{ s, 0 }};\n    ml_char c;\n    while (ml_chars_next(&it, &c)) {{\n        {}(&v, c);\n    }}\n    return v;\n",
+                v, push
+            );
+            (proto, body)
+        })
+    }
+}
+
+/// The `ml_spec` a hole asks for
+fn c_spec(spec: &Spec) -> String {
+    match spec.is_plain() {
+        true => "ML_PLAIN".to_string(),
+        false => {
+            format!("((ml_spec){{ {}, {}, {} }})", spec.width.unwrap_or(-1), spec.left, spec.precision.unwrap_or(-1))
+        }
+    }
+}
+
+fn prim_type(prim: PrimitiveType) -> Option<&'static str> {
+    use PrimitiveType::*;
+    Some(match prim {
+        I8 => "int8_t",
+        I16 => "int16_t",
+        I32 => "int32_t",
+        I64 => "int64_t",
+        Isize => "intptr_t",
+        U8 => "uint8_t",
+        U16 => "uint16_t",
+        U32 => "uint32_t",
+        U64 => "uint64_t",
+        Usize => "size_t",
+        F32 => "float",
+        F64 => "double",
+        Bool => "bool",
+        Char => "ml_char",
+        Str => "ml_str",
+        Unit => "ml_unit",
+        I128 | U128 | Never => return None,
+    })
+}
+
+/// Types C functions return nothing for
+fn is_void(ty: &Ty) -> bool {
+    ty.is_unit() || *ty == Ty::Never
+}
+
+fn c_op(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Mod => "%",
+        BinaryOp::Eq => "==",
+        BinaryOp::Ne => "!=",
+        BinaryOp::Lt => "<",
+        BinaryOp::Gt => ">",
+        BinaryOp::Le => "<=",
+        BinaryOp::Ge => ">=",
+        BinaryOp::And => "&&",
+        BinaryOp::Or => "||",
+        BinaryOp::BitAnd => "&",
+        BinaryOp::BitOr => "|",
+        BinaryOp::BitXor => "^",
+        BinaryOp::Shl => "<<",
+        BinaryOp::Shr => ">>",
+        BinaryOp::Assign => "=",
+    }
+}
+
+/// A C string literal, ASCII only, with octal escapes for everything else
+fn c_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    let mut last = '\0';
+    for byte in text.bytes() {
+        match byte {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'\r' => out.push_str("\\r"),
+            // `??` starts a trigraph
+            b'?' if last == '?' => out.push_str("\\?"),
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:03o}", byte)),
+        }
+        last = byte as char;
+    }
+    out.push('"');
+    out
+}
+
+fn char_literal(c: char) -> String {
+    match c {
+        '\'' | '\\' => format!("'\\{}'", c),
+        ' '..='~' => format!("'{}'", c),
+        _ => format!("0x{:X}", c as u32),
+    }
+}
+
+/// A struct member name, clear of C's keywords
+fn field_name(name: &str) -> String {
+    if name.chars().next().is_some_and(|c| c.is_ascii_digit()) {
+        return format!("_{}", name);
+    }
+    let name = sanitize(name);
+    match RESERVED.contains(&name.as_str()) {
+        true => format!("{}_", name),
+        false => name,
+    }
+}
+
+/// Keep only what C allows in identifiers
+fn sanitize(name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect();
+    match name.chars().next() {
+        Some(c) if c.is_ascii_digit() => format!("_{}", name),
+        Some(_) => name,
+        None => "_".to_string(),
+    }
+}
+
+/// `T *` for the C type `T`
+fn pointer(t: String) -> String {
+    match t.ends_with('*') {
+        true => format!("{}*", t),
+        false => format!("{} *", t),
+    }
+}
+
+/// Declare `name` with the C type `t`
+fn decl(t: &str, name: &str) -> String {
+    match t.ends_with('*') {
+        true => format!("{}{}", t, name),
+        false => format!("{} {}", t, name),
+    }
+}
+
+/// `base.name`, written `p->name` when `base` is `(*p)`
+fn member(base: &str, name: &str) -> String {
+    match base.strip_prefix("(*").and_then(|inner| inner.strip_suffix(')')) {
+        Some(pointer) if is_name(pointer) => format!("{}->{}", pointer, name),
+        _ => format!("{}.{}", base, name),
+    }
+}
+
+/// Member `name` of what `pointer` points to
+fn through(pointer: &str, name: &str) -> String {
+    match pointer.strip_prefix('&') {
+        Some(place) if is_name(place) => format!("{}.{}", place, name),
+        _ if is_name(pointer) => format!("{}->{}", pointer, name),
+        _ => format!("(*{}).{}", pointer, name),
+    }
+}
+
+/// `&place`, written `p` when the place is `(*p)`
+fn address(place: &str) -> String {
+    match place.strip_prefix("(*").and_then(|inner| inner.strip_suffix(')')) {
+        Some(pointer) if is_name(pointer) => pointer.to_string(),
+        _ => format!("&{}", place),
+    }
+}
+
+/// A variable, or a member of one: reading it twice does nothing twice
+fn is_name(text: &str) -> bool {
+    !text.is_empty()
+        && text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '>'))
+        && !matches!(text, "true" | "false" | "ML_UNIT")
+}
+
+/// Whether C text calls a function, and so may have effects
+fn has_call(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    (1..bytes.len()).any(|i| bytes[i] == b'(' && (bytes[i - 1].is_ascii_alphanumeric() || bytes[i - 1] == b'_'))
+}
+
+/// `text` in parentheses, unless it already is one group
+fn paren(text: &str) -> String {
+    if is_group(text) || is_name(text) {
+        text.to_string()
+    } else {
+        format!("({})", text)
+    }
+}
+
+fn negate(text: &str) -> String {
+    format!("!{}", paren(text))
+}
+
+/// `text` without the parentheses around all of it
+fn unwrap_parens(text: &str) -> &str {
+    match is_group(text) {
+        true => &text[1..text.len() - 1],
+        false => text,
+    }
+}
+
+/// Whether `text` is a single parenthesized group
+fn is_group(text: &str) -> bool {
+    if !text.starts_with('(') || !text.ends_with(')') {
+        return false;
+    }
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 && i + 1 < text.len() {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+    }
+    depth == 0
+}
+
+/// A variable or a member of one, or what a variable points to: something whose address can be taken
+fn is_lvalue(text: &str) -> bool {
+    is_name(text) || text.strip_prefix("(*").and_then(|inner| inner.strip_suffix(')')).is_some_and(is_name)
+}
+
+/// Whether `text` is a name or `(*name)` followed only by members and
+/// elements, which bind tighter than any operator
+fn is_postfix(text: &str) -> bool {
+    let mut depth = 0;
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '(')
+        && text.chars().all(|c| {
+            match c {
+                '(' | '[' => depth += 1,
+                ')' | ']' => depth -= 1,
+                _ => {}
+            }
+            depth > 0 || c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '>' | ')' | ']')
+        })
+}
+
+/// Whether `text` is one call, with nothing around it
+fn is_call(text: &str) -> bool {
+    match text.find('(') {
+        Some(start) => {
+            start > 0
+                && text[..start].chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && is_group(&text[start..])
+        }
+        None => false,
+    }
+}
+
+/// `text` as a statement: a call on its own, anything else cast to `void`
+fn statement(text: &str) -> String {
+    match is_call(text) {
+        true => format!("{};", text),
+        false => format!("(void){};", paren(text)),
+    }
+}
+
+/// Whether `text` reads the variable `name`
+fn mentions(text: &str, name: &str) -> bool {
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(name).any(|(i, _)| !text[..i].ends_with(word) && !text[i + name.len()..].starts_with(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        check_source, missing, DIVIDE_BY_ZERO, DROPS, HASH_MAP, MAIN_ERROR, OUT_OF_BOUNDS, OVERFLOW, SHAPES, TOTALS,
+        WITHDRAW,
+    };
+    use std::process::Command;
+
+    fn emit_source(source: &str) -> (CProgram, Vec<Diagnostic>) {
+        let (program, res, types) = check_source(source);
+        emit(&[&program], &res, &types, &["test.solo".to_string()], "test")
+    }
+
+    /// Exit status, stdout and stderr of the program `cc` builds from
+    /// `source`, or `None` where there is no C compiler
+    fn run(source: &str) -> Option<(i32, String, String)> {
+        let (program, diags) = emit_source(source);
+        assert!(diags.is_empty(), "{:?}", diags);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("test.h"), &program.header).unwrap();
+        std::fs::write(dir.path().join("test.c"), &program.source).unwrap();
+        let binary = dir.path().join("test");
+        let Ok(built) = Command::new("cc")
+            .current_dir(dir.path())
+            .args(["-std=c99", "-Wall", "-Werror", "-o"])
+            .arg(&binary)
+            .args(["test.c", "-lm"])
+            .output()
+        else {
+            return missing("cc");
+        };
+        let stderr = String::from_utf8_lossy(&built.stderr);
+        assert!(built.status.success(), "{}\n{}", stderr, program.source);
+        let ran = Command::new(&binary).output().unwrap();
+        let (out, err) = (String::from_utf8(ran.stdout).unwrap(), String::from_utf8(ran.stderr).unwrap());
+        Some((ran.status.code().unwrap_or(-1), out, err))
+    }
+
+    #[test]
+    fn test_enums_become_tagged_unions() {
+        let (program, _) = emit_source(SHAPES.source);
+        assert!(program.header.contains("enum Shape_tag { Shape_Circle, Shape_Rect, Shape_Empty };"));
+        assert!(program.header.contains("struct { double w; double h; } Rect;"));
+        SHAPES.check(run(SHAPES.source));
+    }
+
+    #[test]
+    fn test_contracts_become_asserts() {
+        let (program, _) = emit_source(WITHDRAW);
+        assert!(program
+            .source
+            .contains("assert((amount <= balance) && \"precondition `amount <= balance` of `withdraw` violated\");"));
+        let Some((status, out, err)) = run(WITHDRAW) else { return };
+        assert_ne!(status, 0);
+        assert_eq!(out, "7\n");
+        assert!(err.contains("precondition `amount <= balance` of `withdraw` violated"), "{}", err);
+    }
+
+    #[test]
+    fn test_drop_runs_cleanup() {
+        let (program, _) = emit_source(DROPS.source);
+        assert!(program.source.contains("cleanup_Holder(&h);"));
+        assert!(program.source.contains("(void)n;"));
+        DROPS.check(run(DROPS.source));
+    }
+
+    #[test]
+    fn test_generics_are_monomorphized() {
+        let (program, _) = emit_source(TOTALS.source);
+        assert!(program.header.contains("int32_t total__i32(Vec_i32 *items);"));
+        assert!(program.header.contains("double total__f64(Vec_f64 *items);"));
+        TOTALS.check(run(TOTALS.source));
+    }
+
+    #[test]
+    fn test_index_out_of_bounds_panics() {
+        OUT_OF_BOUNDS.check(run(OUT_OF_BOUNDS.source));
+    }
+
+    #[test]
+    fn test_integer_arithmetic_is_checked() {
+        OVERFLOW.check(run(OVERFLOW.source));
+        DIVIDE_BY_ZERO.check(run(DIVIDE_BY_ZERO.source));
+    }
+
+    #[test]
+    fn test_main_error_exits_with_failure() {
+        MAIN_ERROR.check(run(MAIN_ERROR.source));
+    }
+
+    #[test]
+    fn test_unsupported_types_are_reported() {
+        let (_, diags) = emit_source(HASH_MAP);
+        assert!(!diags.is_empty());
+        assert!(diags.iter().all(|d| d.code == Some(codes::UNSUPPORTED_BY_TARGET)), "{:?}", diags);
+        assert!(diags.iter().any(|d| d.message == "`HashMap` cannot be compiled to C"), "{:?}", diags);
+    }
+}
@@ -0,0 +1,567 @@
+/* Support code for C generated from Solo: strings, formatting, panics and
+ * growable arrays. Everything is static, so each program carries its own copy. */
+
+#include <assert.h>
+#include <math.h>
+#include <signal.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#ifdef __GNUC__
+#define ML_NORETURN __attribute__((noreturn))
+#define ML_UNUSED __attribute__((unused))
+#else
+#define ML_NORETURN
+#define ML_UNUSED
+#endif
+
+/* `()`, for the places a value of it has to exist */
+typedef uint8_t ml_unit;
+#define ML_UNIT ((ml_unit)0)
+
+/* A Unicode scalar value */
+typedef uint32_t ml_char;
+
+/* Text, which is never changed in place: literals, or heap copies that are never freed */
+typedef struct ml_str {
+    const char *ptr;
+    size_t len;
+} ml_str;
+#define ML_STR(lit) ((ml_str){ (lit), sizeof(lit) - 1 })
+
+/* Where in the source a panic happened */
+typedef struct ml_loc {
+    const char *file;
+    int line;
+    int column;
+} ml_loc;
+#define ML_LOC(file, line, column) ((ml_loc){ (file), (line), (column) })
+
+/* `{:>5}`, `{:<5}` and `{:.2}`; negative means not given */
+typedef struct ml_spec {
+    int width;
+    bool left;
+    int precision;
+} ml_spec;
+#define ML_PLAIN ((ml_spec){ -1, false, -1 })
+
+/* Text being built by `format` and `println` */
+typedef struct ml_buf {
+    char *ptr;
+    size_t len;
+    size_t cap;
+} ml_buf;
+
+static ML_UNUSED int ml_argc;
+static ML_UNUSED char **ml_argv;
+
+/* ========== Memory ========== */
+
+static ML_UNUSED void *ml_alloc(size_t size) {
+    void *p = malloc(size ? size : 1);
+    if (!p) {
+        fputs("memory allocation failed\n", stderr);
+        abort();
+    }
+    return p;
+}
+
+/* `data` with room for one more element of `size` bytes after the first `len` */
+static ML_UNUSED void *ml_grow(void *data, size_t *cap, size_t len, size_t size) {
+    if (len < *cap) {
+        return data;
+    }
+    size_t cap2 = *cap ? *cap * 2 : 4;
+    void *p = realloc(data, cap2 * size);
+    if (!p) {
+        fputs("memory allocation failed\n", stderr);
+        abort();
+    }
+    *cap = cap2;
+    return p;
+}
+
+/* A heap copy of `size` bytes, which is what `Box::new` makes */
+static ML_UNUSED void *ml_box(const void *value, size_t size) {
+    void *p = ml_alloc(size);
+    memcpy(p, value, size);
+    return p;
+}
+
+/* ========== Text ========== */
+
+static ML_UNUSED ml_buf ml_buf_new(void) {
+    return (ml_buf){ NULL, 0, 0 };
+}
+
+static ML_UNUSED void ml_buf_bytes(ml_buf *b, const char *p, size_t n) {
+    if (b->len + n > b->cap) {
+        size_t cap = b->cap ? b->cap : 16;
+        while (cap < b->len + n) {
+            cap *= 2;
+        }
+        char *grown = realloc(b->ptr, cap);
+        if (!grown) {
+            fputs("memory allocation failed\n", stderr);
+            abort();
+        }
+        b->ptr = grown;
+        b->cap = cap;
+    }
+    if (n) {
+        memcpy(b->ptr + b->len, p, n);
+    }
+    b->len += n;
+}
+
+static ML_UNUSED void ml_buf_lit(ml_buf *b, const char *s) {
+    ml_buf_bytes(b, s, strlen(s));
+}
+
+static ML_UNUSED void ml_buf_str(ml_buf *b, ml_str s) {
+    ml_buf_bytes(b, s.ptr, s.len);
+}
+
+static ML_UNUSED void ml_buf_char(ml_buf *b, ml_char c) {
+    char out[4];
+    size_t n;
+    if (c < 0x80) {
+        out[0] = (char)c;
+        n = 1;
+    } else if (c < 0x800) {
+        out[0] = (char)(0xC0 | (c >> 6));
+        out[1] = (char)(0x80 | (c & 0x3F));
+        n = 2;
+    } else if (c < 0x10000) {
+        out[0] = (char)(0xE0 | (c >> 12));
+        out[1] = (char)(0x80 | ((c >> 6) & 0x3F));
+        out[2] = (char)(0x80 | (c & 0x3F));
+        n = 3;
+    } else {
+        out[0] = (char)(0xF0 | (c >> 18));
+        out[1] = (char)(0x80 | ((c >> 12) & 0x3F));
+        out[2] = (char)(0x80 | ((c >> 6) & 0x3F));
+        out[3] = (char)(0x80 | (c & 0x3F));
+        n = 4;
+    }
+    ml_buf_bytes(b, out, n);
+}
+
+/* The text built so far, which the buffer gives up */
+static ML_UNUSED ml_str ml_buf_finish(ml_buf *b) {
+    ml_str s = { b->ptr ? b->ptr : "", b->len };
+    *b = ml_buf_new();
+    return s;
+}
+
+/* Characters, rather than bytes, in UTF-8 text */
+static ML_UNUSED size_t ml_utf8_count(const char *p, size_t n) {
+    size_t count = 0;
+    for (size_t i = 0; i < n; i++) {
+        count += ((unsigned char)p[i] & 0xC0) != 0x80;
+    }
+    return count;
+}
+
+/* Pad what was written since `start` to the width `spec` asks for: on the right by default */
+static ML_UNUSED void ml_pad(ml_buf *b, size_t start, ml_spec spec) {
+    size_t chars = ml_utf8_count(b->ptr + start, b->len - start);
+    if (spec.width < 0 || chars >= (size_t)spec.width) {
+        return;
+    }
+    size_t fill = (size_t)spec.width - chars;
+    size_t end = b->len;
+    for (size_t i = 0; i < fill; i++) {
+        ml_buf_bytes(b, " ", 1);
+    }
+    if (!spec.left) {
+        memmove(b->ptr + start + fill, b->ptr + start, end - start);
+        memset(b->ptr + start, ' ', fill);
+    }
+}
+
+static ML_UNUSED bool ml_str_eq(ml_str a, ml_str b) {
+    return a.len == b.len && (a.len == 0 || memcmp(a.ptr, b.ptr, a.len) == 0);
+}
+
+static ML_UNUSED int ml_str_cmp(ml_str a, ml_str b) {
+    size_t n = a.len < b.len ? a.len : b.len;
+    int c = n ? memcmp(a.ptr, b.ptr, n) : 0;
+    if (c != 0) {
+        return c;
+    }
+    return a.len < b.len ? -1 : a.len > b.len;
+}
+
+static ML_UNUSED ml_str ml_str_concat(ml_str a, ml_str b) {
+    ml_buf buf = ml_buf_new();
+    ml_buf_str(&buf, a);
+    ml_buf_str(&buf, b);
+    return ml_buf_finish(&buf);
+}
+
+/* `s.push_str(t)`: strings are immutable, so the variable gets a longer copy */
+static ML_UNUSED void ml_str_push(ml_str *s, ml_str t) {
+    *s = ml_str_concat(*s, t);
+}
+
+static ML_UNUSED ml_str ml_str_from_char(ml_char c) {
+    ml_buf buf = ml_buf_new();
+    ml_buf_char(&buf, c);
+    return ml_buf_finish(&buf);
+}
+
+static ML_UNUSED void ml_str_push_char(ml_str *s, ml_char c) {
+    *s = ml_str_concat(*s, ml_str_from_char(c));
+}
+
+static ML_UNUSED bool ml_str_starts_with(ml_str s, ml_str prefix) {
+    return s.len >= prefix.len && memcmp(s.ptr, prefix.ptr, prefix.len) == 0;
+}
+
+static ML_UNUSED bool ml_str_ends_with(ml_str s, ml_str suffix) {
+    return s.len >= suffix.len && memcmp(s.ptr + s.len - suffix.len, suffix.ptr, suffix.len) == 0;
+}
+
+static ML_UNUSED bool ml_str_contains(ml_str s, ml_str part) {
+    for (size_t i = 0; i + part.len <= s.len; i++) {
+        if (memcmp(s.ptr + i, part.ptr, part.len) == 0) {
+            return true;
+        }
+    }
+    return false;
+}
+
+static ML_UNUSED ml_str ml_str_trim(ml_str s) {
+    while (s.len && (*s.ptr == ' ' || *s.ptr == '\t' || *s.ptr == '\n' || *s.ptr == '\r')) {
+        s.ptr++;
+        s.len--;
+    }
+    while (s.len && (s.ptr[s.len - 1] == ' ' || s.ptr[s.len - 1] == '\t' || s.ptr[s.len - 1] == '\n' ||
+                     s.ptr[s.len - 1] == '\r')) {
+        s.len--;
+    }
+    return s;
+}
+
+/* ASCII case mapping; other characters are kept */
+static ML_UNUSED ml_str ml_str_map_case(ml_str s, bool upper) {
+    char *p = ml_alloc(s.len);
+    for (size_t i = 0; i < s.len; i++) {
+        char c = s.ptr[i];
+        if (upper && c >= 'a' && c <= 'z') {
+            c = (char)(c - 'a' + 'A');
+        } else if (!upper && c >= 'A' && c <= 'Z') {
+            c = (char)(c - 'A' + 'a');
+        }
+        p[i] = c;
+    }
+    return (ml_str){ p, s.len };
+}
+
+/* Iterating over the characters of a string */
+typedef struct ml_chars {
+    ml_str s;
+    size_t at;
+} ml_chars;
+
+static ML_UNUSED bool ml_chars_next(ml_chars *it, ml_char *out) {
+    if (it->at >= it->s.len) {
+        return false;
+    }
+    const unsigned char *p = (const unsigned char *)it->s.ptr + it->at;
+    size_t n = *p < 0x80 ? 1 : *p < 0xE0 ? 2 : *p < 0xF0 ? 3 : 4;
+    ml_char c = n == 1 ? *p : n == 2 ? *p & 0x1F : n == 3 ? *p & 0x0F : *p & 0x07;
+    for (size_t i = 1; i < n && it->at + i < it->s.len; i++) {
+        c = (c << 6) | (p[i] & 0x3F);
+    }
+    it->at += n;
+    *out = c;
+    return true;
+}
+
+/* ========== Formatting ========== */
+
+static ML_UNUSED void ml_fmt_i64(ml_buf *b, int64_t n, ml_spec spec) {
+    char text[32];
+    size_t start = b->len;
+    snprintf(text, sizeof text, "%lld", (long long)n);
+    ml_buf_lit(b, text);
+    ml_pad(b, start, spec);
+}
+
+static ML_UNUSED void ml_fmt_u64(ml_buf *b, uint64_t n, ml_spec spec) {
+    char text[32];
+    size_t start = b->len;
+    snprintf(text, sizeof text, "%llu", (unsigned long long)n);
+    ml_buf_lit(b, text);
+    ml_pad(b, start, spec);
+}
+
+/* Floats as Rust shows them: the shortest digits that read back the same,
+ * never in exponent form, and with `.0` on whole numbers only for `{:?}` */
+static ML_UNUSED void ml_fmt_f64(ml_buf *b, double x, ml_spec spec, bool debug) {
+    char text[400];
+    size_t start = b->len;
+    if (spec.precision >= 0) {
+        snprintf(text, sizeof text, "%.*f", spec.precision, x);
+        ml_buf_lit(b, text);
+    } else if (isnan(x)) {
+        ml_buf_lit(b, "NaN");
+    } else if (isinf(x)) {
+        ml_buf_lit(b, x < 0 ? "-inf" : "inf");
+    } else {
+        char digits[32];
+        int exp = 0;
+        for (int p = 1; p <= 17; p++) {
+            snprintf(digits, sizeof digits, "%.*e", p - 1, x);
+            if (strtod(digits, NULL) == x) {
+                break;
+            }
+        }
+        char *e = strchr(digits, 'e');
+        exp = atoi(e + 1);
+        *e = '\0';
+        char mantissa[32];
+        size_t m = 0;
+        bool negative = digits[0] == '-';
+        for (char *d = digits + negative; *d; d++) {
+            if (*d != '.') {
+                mantissa[m++] = *d;
+            }
+        }
+        while (m > 1 && mantissa[m - 1] == '0') {
+            m--;
+        }
+        mantissa[m] = '\0';
+        size_t t = 0;
+        if (negative) {
+            text[t++] = '-';
+        }
+        if (exp < 0) {
+            text[t++] = '0';
+            text[t++] = '.';
+            for (int i = 0; i < -exp - 1; i++) {
+                text[t++] = '0';
+            }
+            for (size_t i = 0; i < m; i++) {
+                text[t++] = mantissa[i];
+            }
+        } else {
+            for (int i = 0; i <= exp; i++) {
+                text[t++] = (size_t)i < m ? mantissa[i] : '0';
+            }
+            if ((size_t)exp + 1 < m) {
+                text[t++] = '.';
+                for (size_t i = (size_t)exp + 1; i < m; i++) {
+                    text[t++] = mantissa[i];
+                }
+            } else if (debug) {
+                text[t++] = '.';
+                text[t++] = '0';
+            }
+        }
+        text[t] = '\0';
+        ml_buf_lit(b, text);
+    }
+    ml_pad(b, start, spec);
+}
+
+static ML_UNUSED void ml_fmt_bool(ml_buf *b, bool v, ml_spec spec) {
+    size_t start = b->len;
+    ml_buf_lit(b, v ? "true" : "false");
+    ml_pad(b, start, spec);
+}
+
+/* A character or string as `{:?}` quotes it */
+static ML_UNUSED void ml_escape(ml_buf *b, ml_char c, ml_char quote) {
+    switch (c) {
+    case '\n':
+        ml_buf_lit(b, "\\n");
+        break;
+    case '\t':
+        ml_buf_lit(b, "\\t");
+        break;
+    case '\r':
+        ml_buf_lit(b, "\\r");
+        break;
+    case '\\':
+        ml_buf_lit(b, "\\\\");
+        break;
+    case '\0':
+        ml_buf_lit(b, "\\0");
+        break;
+    default:
+        if (c == quote) {
+            ml_buf_lit(b, "\\");
+        }
+        ml_buf_char(b, c);
+    }
+}
+
+static ML_UNUSED void ml_fmt_char(ml_buf *b, ml_char c, ml_spec spec, bool debug) {
+    size_t start = b->len;
+    if (debug) {
+        ml_buf_lit(b, "'");
+        ml_escape(b, c, '\'');
+        ml_buf_lit(b, "'");
+    } else {
+        ml_buf_char(b, c);
+    }
+    ml_pad(b, start, spec);
+}
+
+static ML_UNUSED void ml_fmt_str(ml_buf *b, ml_str s, ml_spec spec, bool debug) {
+    size_t start = b->len;
+    if (debug) {
+        ml_chars it = { s, 0 };
+        ml_char c;
+        ml_buf_lit(b, "\"");
+        while (ml_chars_next(&it, &c)) {
+            ml_escape(b, c, '"');
+        }
+        ml_buf_lit(b, "\"");
+    } else {
+        ml_buf_str(b, s);
+    }
+    ml_pad(b, start, spec);
+}
+
+static ML_UNUSED ml_str ml_i64_to_str(int64_t n) {
+    ml_buf b = ml_buf_new();
+    ml_fmt_i64(&b, n, ML_PLAIN);
+    return ml_buf_finish(&b);
+}
+
+static ML_UNUSED ml_str ml_u64_to_str(uint64_t n) {
+    ml_buf b = ml_buf_new();
+    ml_fmt_u64(&b, n, ML_PLAIN);
+    return ml_buf_finish(&b);
+}
+
+static ML_UNUSED ml_str ml_f64_to_str(double x) {
+    ml_buf b = ml_buf_new();
+    ml_fmt_f64(&b, x, ML_PLAIN, false);
+    return ml_buf_finish(&b);
+}
+
+/* ========== Output and Exit ========== */
+
+/* Write out and free what `b` holds. Standard output is flushed before
+ * writing to standard error so that the two stay in order. */
+static ML_UNUSED void ml_print(FILE *out, ml_buf *b) {
+    if (out == stderr) {
+        fflush(stdout);
+    }
+    fwrite(b->ptr ? b->ptr : "", 1, b->len, out);
+    free(b->ptr);
+    *b = ml_buf_new();
+}
+
+/* Write out text that needs no formatting */
+static ML_UNUSED void ml_puts(FILE *out, const char *s) {
+    if (out == stderr) {
+        fflush(stdout);
+    }
+    fputs(s, out);
+}
+
+/* Keep what was printed when a contract's assert aborts the program */
+static ML_UNUSED void ml_on_abort(int sig) {
+    (void)sig;
+    fflush(stdout);
+}
+
+static ML_UNUSED ML_NORETURN void ml_exit(int code) {
+    fflush(stdout);
+    exit(code);
+}
+
+/* Stop as a Rust program panicking would, with status 101 */
+static ML_UNUSED ML_NORETURN void ml_panic(ml_loc loc, ml_str message) {
+    fflush(stdout);
+    fprintf(stderr, "thread 'main' panicked at %s:%d:%d:\n%.*s\n", loc.file, loc.line, loc.column, (int)message.len,
+            message.ptr);
+    exit(101);
+}
+
+static ML_UNUSED ML_NORETURN void ml_panic_buf(ml_loc loc, ml_buf *b) {
+    ml_panic(loc, ml_buf_finish(b));
+}
+
+/* `i` if it is below `len`, or else the panic indexing out of bounds causes */
+static ML_UNUSED size_t ml_index(int64_t i, size_t len, ml_loc loc) {
+    if (i < 0 || (uint64_t)i >= len) {
+        ml_buf b = ml_buf_new();
+        ml_buf_lit(&b, "index out of bounds: the len is ");
+        ml_fmt_u64(&b, len, ML_PLAIN);
+        ml_buf_lit(&b, " but the index is ");
+        ml_fmt_i64(&b, i, ML_PLAIN);
+        ml_panic_buf(loc, &b);
+    }
+    return (size_t)i;
+}
+
+/* ========== Arithmetic ========== */
+
+/* Stop as an integer operation that overflows does */
+static ML_UNUSED ML_NORETURN void ml_overflow(const char *verb, ml_loc loc) {
+    ml_buf b = ml_buf_new();
+    ml_buf_lit(&b, "attempt to ");
+    ml_buf_lit(&b, verb);
+    ml_buf_lit(&b, " with overflow");
+    ml_panic_buf(loc, &b);
+}
+
+/* Checked `+`, `-`, `*`, `/`, `%` and negation for the integer type `t`,
+   which panic as the interpreter does rather than wrap or trap. Only the
+   least signed value divided by -1 overflows a division. */
+#define ML_ARITH(t, name)                                                                   \
+    static ML_UNUSED t ml_add_##name(t a, t b, ml_loc loc) {                                \
+        t r;                                                                                \
+        if (__builtin_add_overflow(a, b, &r)) ml_overflow("add", loc);                      \
+        return r;                                                                           \
+    }                                                                                       \
+    static ML_UNUSED t ml_sub_##name(t a, t b, ml_loc loc) {                                \
+        t r;                                                                                \
+        if (__builtin_sub_overflow(a, b, &r)) ml_overflow("subtract", loc);                 \
+        return r;                                                                           \
+    }                                                                                       \
+    static ML_UNUSED t ml_mul_##name(t a, t b, ml_loc loc) {                                \
+        t r;                                                                                \
+        if (__builtin_mul_overflow(a, b, &r)) ml_overflow("multiply", loc);                 \
+        return r;                                                                           \
+    }                                                                                       \
+    static ML_UNUSED t ml_div_##name(t a, t b, ml_loc loc) {                                \
+        t r;                                                                                \
+        if (b == 0) ml_panic(loc, ML_STR("attempt to divide by zero"));                     \
+        if ((t)-1 < 0 && b == (t)-1 && __builtin_sub_overflow((t)0, a, &r)) ml_overflow("divide", loc); \
+        return a / b;                                                                       \
+    }                                                                                       \
+    static ML_UNUSED t ml_rem_##name(t a, t b, ml_loc loc) {                                \
+        t r;                                                                                \
+        if (b == 0) ml_panic(loc, ML_STR("attempt to calculate the remainder with a divisor of zero")); \
+        if ((t)-1 < 0 && b == (t)-1 && __builtin_sub_overflow((t)0, a, &r))                 \
+            ml_overflow("calculate the remainder", loc);                                    \
+        return a % b;                                                                       \
+    }                                                                                       \
+    static ML_UNUSED t ml_neg_##name(t a, ml_loc loc) {                                     \
+        t r;                                                                                \
+        if (__builtin_sub_overflow((t)0, a, &r)) ml_overflow("negate", loc);                \
+        return r;                                                                           \
+    }
+
+ML_ARITH(int8_t, i8)
+ML_ARITH(int16_t, i16)
+ML_ARITH(int32_t, i32)
+ML_ARITH(int64_t, i64)
+ML_ARITH(intptr_t, isize)
+ML_ARITH(uint8_t, u8)
+ML_ARITH(uint16_t, u16)
+ML_ARITH(uint32_t, u32)
+ML_ARITH(uint64_t, u64)
+ML_ARITH(size_t, usize)
@@ -0,0 +1,717 @@
+// Contract lowering
+// Rewrites `pre`, `post` and `invariant` clauses into checks that call
+// `contract_violation` with the function, the clause text and the argument values
+
+use my_lang_ast::*;
+use std::str::FromStr;
+
+/// Which contract clauses are checked at run time, selected with `--contracts`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContractMode {
+    /// No checks
+    Off,
+    /// Preconditions only
+    Pre,
+    /// Preconditions, postconditions and invariants
+    #[default]
+    All,
+}
+
+impl FromStr for ContractMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ContractMode::Off),
+            "pre" => Ok(ContractMode::Pre),
+            "all" => Ok(ContractMode::All),
+            _ => Err(format!("expected `off`, `pre` or `all`, found `{}`", s)),
+        }
+    }
+}
+
+/// Suffix of the function holding a contracted function's original body
+const BODY_SUFFIX: &str = "__contract_body";
+
+/// Lowers the contracts of one module. Preconditions become checks at the top
+/// of the body. A function with postconditions or invariants keeps its name and
+/// signature but moves its body into a helper, so that every `return` passes
+/// through the checks on `result`:
+///
+/// ```text
+/// fn f(a: i32) -> i32 {
+///     if !(pre) { contract_violation("precondition", "f", "pre", "a", a); }
+///     let __old_0 = x;
+///     let result = f__contract_body(a);
+///     if !(post) { contract_violation("postcondition", "f", "post", "a", a, "result", result); }
+///     result
+/// }
+/// ```
+pub struct ContractLowering<'s> {
+    /// Source of the module, for the text of each clause
+    source: &'s str,
+    mode: ContractMode,
+    next_id: u32,
+}
+
+/// Where a function is defined, which decides how its wrapper calls the helper
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Owner {
+    Module,
+    /// An impl or trait, named for messages
+    Type,
+}
+
+impl<'s> ContractLowering<'s> {
+    pub fn new(source: &'s str, mode: ContractMode) -> Self {
+        Self { source, mode, next_id: 0 }
+    }
+
+    /// Number new nodes from `id`, past every node already in the crate
+    pub fn with_first_node_id(mut self, id: NodeId) -> Self {
+        self.next_id = id.0;
+        self
+    }
+
+    /// The id the next new node would get
+    pub fn next_node_id(&self) -> NodeId {
+        NodeId(self.next_id)
+    }
+
+    pub fn lower(&mut self, program: &mut Program) {
+        self.lower_items(&mut program.items);
+    }
+
+    fn lower_items(&mut self, items: &mut Vec<Item>) {
+        let mut added = Vec::new();
+        for item in items.iter_mut() {
+            match &mut item.kind {
+                ItemKind::Function(func) => {
+                    let name = func.name.clone();
+                    if let Some(helper) = self.lower_fn(func, Owner::Module, &name, Vec::new()) {
+                        added.push(Item::new(self.next_id(), ItemKind::Function(helper), item.span));
+                    }
+                }
+                ItemKind::Impl(imp) => {
+                    let self_ty = self.text(imp.self_ty.span).to_string();
+                    let invariants = imp.contract.as_ref().map(|c| c.invariants.clone()).unwrap_or_default();
+                    let mut helpers = Vec::new();
+                    for impl_item in &mut imp.items {
+                        if let ImplItem::Function(func) = impl_item {
+                            let name = format!("{}::{}", self_ty, func.name);
+                            let public = func.visibility == Visibility::Public || imp.trait_name.is_some();
+                            let exit = self.exit_invariants(func, public, &self_ty, &invariants);
+                            helpers.extend(self.lower_fn(func, Owner::Type, &name, exit).map(ImplItem::Function));
+                        }
+                    }
+                    // Helpers go in an inherent impl, since a trait impl may only define the trait's items
+                    if !helpers.is_empty() {
+                        let helper_impl = Impl {
+                            id: self.next_id(),
+                            span: imp.span,
+                            generics: imp.generics.iter().map(|g| self.fresh_generic(g)).collect(),
+                            trait_name: None,
+                            self_ty: self.fresh_type(&imp.self_ty),
+                            contract: None,
+                            items: helpers,
+                        };
+                        added.push(Item::new(self.next_id(), ItemKind::Impl(helper_impl), item.span));
+                    }
+                }
+                ItemKind::Trait(t) => {
+                    let mut helpers = Vec::new();
+                    for trait_item in &mut t.items {
+                        if let TraitItem::Function(func) = trait_item {
+                            let name = format!("{}::{}", t.name, func.name);
+                            helpers
+                                .extend(self.lower_fn(func, Owner::Type, &name, Vec::new()).map(TraitItem::Function));
+                        }
+                    }
+                    t.items.extend(helpers);
+                }
+                ItemKind::Module(module) => self.lower_items(&mut module.items),
+                _ => {}
+            }
+        }
+        items.extend(added);
+    }
+
+    /// Impl invariants to check when `func` returns: on `self` after a public
+    /// `&mut self` method, and on the result of a public `self` method returning
+    /// `Self`. Other methods cannot break them, or leave no value to check.
+    fn exit_invariants(
+        &mut self,
+        func: &Function,
+        public: bool,
+        self_ty: &str,
+        invariants: &[Expression],
+    ) -> Vec<Expression> {
+        if self.mode != ContractMode::All || !public {
+            return Vec::new();
+        }
+        let Some(receiver) = func.params.first().filter(|p| p.name == "self") else {
+            return Vec::new();
+        };
+        match &receiver.ty.kind {
+            TypeKind::Reference { is_mut: true, .. } => invariants.iter().map(|cond| self.fresh_expr(cond)).collect(),
+            TypeKind::Reference { .. } => Vec::new(),
+            _ => {
+                let returns_self = func.return_type.as_ref().is_some_and(|ty| {
+                    matches!(&ty.kind, TypeKind::Named(name) if name == "Self") || self.text(ty.span) == self_ty
+                });
+                if !returns_self {
+                    return Vec::new();
+                }
+                let mut checks: Vec<Expression> = invariants.iter().map(|cond| self.fresh_expr(cond)).collect();
+                checks.iter_mut().for_each(rename_self);
+                checks
+            }
+        }
+    }
+
+    /// Lower the contract of `func` and the impl invariants it must restore,
+    /// returning the helper now holding its body if one is needed
+    fn lower_fn(
+        &mut self,
+        func: &mut Function,
+        owner: Owner,
+        display_name: &str,
+        impl_invariants: Vec<Expression>,
+    ) -> Option<Function> {
+        let contract = func.contract.take();
+        if self.mode == ContractMode::Off || (contract.is_none() && impl_invariants.is_empty()) {
+            return None;
+        }
+        let span = contract.as_ref().map_or(func.span, |c| c.span);
+        let (pre, post, invariants) = match (self.mode, contract) {
+            (_, None) => (Vec::new(), Vec::new(), Vec::new()),
+            (ContractMode::Pre, Some(c)) => (c.preconditions, Vec::new(), Vec::new()),
+            (_, Some(c)) => (c.preconditions, c.postconditions, c.invariants),
+        };
+
+        let mut stmts = Vec::new();
+        for cond in pre {
+            stmts.push(self.check("precondition", display_name, cond, &func.params, None));
+        }
+        for cond in &invariants {
+            let cond = self.fresh_expr(cond);
+            stmts.push(self.check("invariant", display_name, cond, &func.params, None));
+        }
+        if post.is_empty() && invariants.is_empty() && impl_invariants.is_empty() {
+            func.body.stmts.splice(0..0, stmts);
+            return None;
+        }
+
+        // Values `old(expr)` refers to are taken on entry
+        let mut post_checks = Vec::new();
+        for mut cond in post {
+            let mut snapshots = Vec::new();
+            self.take_snapshots(&mut cond, &mut snapshots);
+            for (name, value) in snapshots {
+                stmts.push(self.let_stmt(&name, value, span));
+            }
+            post_checks.push(cond);
+        }
+
+        let helper_name = format!("{}{}", func.name, BODY_SUFFIX);
+        let call = self.call_helper(func, owner, &helper_name, span);
+        stmts.push(self.let_stmt("result", call, span));
+        let result = func.return_type.is_some().then_some("result");
+        // A `self` taken by value has moved into the helper
+        let params: Vec<Param> = func
+            .params
+            .iter()
+            .filter(|p| p.name != "self" || matches!(p.ty.kind, TypeKind::Reference { .. }))
+            .cloned()
+            .collect();
+        for cond in post_checks {
+            stmts.push(self.check("postcondition", display_name, cond, &params, result));
+        }
+        for cond in invariants.into_iter().chain(impl_invariants) {
+            stmts.push(self.check("invariant", display_name, cond, &params, result));
+        }
+
+        let body =
+            Block { id: self.next_id(), span: func.body.span, stmts, expr: Some(Box::new(self.ident("result", span))) };
+        let original = std::mem::replace(&mut func.body, body);
+        Some(Function {
+            id: self.next_id(),
+            span: func.span,
+            name: helper_name,
+            visibility: Visibility::Private,
+            generics: func.generics.iter().map(|g| self.fresh_generic(g)).collect(),
+            params: func.params.iter().map(|p| self.fresh_param(p)).collect(),
+            return_type: func.return_type.as_ref().map(|ty| self.fresh_type(ty)),
+            where_clause: func.where_clause.as_ref().map(|w| self.fresh_where_clause(w)),
+            contract: None,
+            body: original,
+            is_async: func.is_async,
+            is_comptime: func.is_comptime,
+            attributes: Vec::new(),
+        })
+    }
+
+    /// `self.helper(args)`, `Self::helper(args)` or `helper(args)`, passing every parameter on
+    fn call_helper(&mut self, func: &Function, owner: Owner, helper: &str, span: Span) -> Expression {
+        let mut params = func.params.iter().map(|p| p.name.as_str()).peekable();
+        let has_self = params.peek() == Some(&"self");
+        if has_self {
+            params.next();
+        }
+        let args: Vec<Expression> = params.map(|name| self.ident(name, span)).collect::<Vec<_>>();
+        let kind = if has_self {
+            ExpressionKind::MethodCall {
+                receiver: Box::new(self.ident("self", span)),
+                method: helper.to_string(),
+                args,
+            }
+        } else {
+            let func = match owner {
+                Owner::Type => self.expr(ExpressionKind::Path(vec!["Self".to_string(), helper.to_string()]), span),
+                Owner::Module => self.ident(helper, span),
+            };
+            ExpressionKind::Call { func: Box::new(func), args }
+        };
+        self.expr(kind, span)
+    }
+
+    /// `if !(cond) { contract_violation(kind, function, clause, name, value, ...); }`
+    fn check(
+        &mut self,
+        kind: &str,
+        function: &str,
+        cond: Expression,
+        params: &[Param],
+        result: Option<&str>,
+    ) -> Statement {
+        let span = cond.span;
+        let clause = self.text(span).to_string();
+        let mut args = vec![self.string(kind, span), self.string(function, span), self.string(&clause, span)];
+        for name in params.iter().map(|p| p.name.as_str()).chain(result) {
+            args.push(self.string(name, span));
+            args.push(self.ident(name, span));
+        }
+        let violation = ExpressionKind::Call { func: Box::new(self.ident("contract_violation", span)), args };
+        let violation = self.expr(violation, span);
+        let then_block = Block {
+            id: self.next_id(),
+            span,
+            stmts: vec![Statement::new(self.next_id(), StatementKind::Expression(violation), span)],
+            expr: None,
+        };
+        let negated = self.expr(ExpressionKind::Unary { op: UnaryOp::Not, expr: Box::new(cond) }, span);
+        let check = self.expr(ExpressionKind::If { cond: Box::new(negated), then_block, else_block: None }, span);
+        Statement::new(self.next_id(), StatementKind::Expression(check), span)
+    }
+
+    /// Replace each `old(expr)` in `cond` with a binding of `expr` taken on entry
+    fn take_snapshots(&mut self, cond: &mut Expression, snapshots: &mut Vec<(String, Expression)>) {
+        if let ExpressionKind::Call { func, args } = &mut cond.kind {
+            if matches!(&func.kind, ExpressionKind::Identifier(name) if name == "old") && args.len() == 1 {
+                let name = format!("__old_{}", self.next_id);
+                let value = args.remove(0);
+                *cond = self.ident(&name, cond.span);
+                snapshots.push((name, value));
+                return;
+            }
+        }
+        for_each_child(cond, &mut |child| self.take_snapshots(child, snapshots));
+    }
+
+    // ========== Node Construction ==========
+
+    fn next_id(&mut self) -> NodeId {
+        let id = NodeId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn text(&self, span: Span) -> &str {
+        self.source.get(span.start..span.end).unwrap_or("<contract>")
+    }
+
+    fn expr(&mut self, kind: ExpressionKind, span: Span) -> Expression {
+        Expression::new(self.next_id(), kind, span)
+    }
+
+    fn ident(&mut self, name: &str, span: Span) -> Expression {
+        self.expr(ExpressionKind::Identifier(name.to_string()), span)
+    }
+
+    fn string(&mut self, value: &str, span: Span) -> Expression {
+        self.expr(ExpressionKind::Literal(Literal::String(value.to_string())), span)
+    }
+
+    fn let_stmt(&mut self, name: &str, init: Expression, span: Span) -> Statement {
+        let pattern = Pattern::new(self.next_id(), PatternKind::Identifier(name.to_string()), span);
+        let kind = StatementKind::Let { pattern, ty: None, init: Some(init), is_mut: false };
+        Statement::new(self.next_id(), kind, span)
+    }
+
+    // ========== Copies ==========
+    // Copies of signature parts and clauses get fresh ids, since name
+    // resolution records one definition or use per node
+
+    fn fresh_generic(&mut self, generic: &Generic) -> Generic {
+        Generic { id: self.next_id(), ..generic.clone() }
+    }
+
+    fn fresh_param(&mut self, param: &Param) -> Param {
+        Param { id: self.next_id(), ty: self.fresh_type(&param.ty), ..param.clone() }
+    }
+
+    fn fresh_where_clause(&mut self, clause: &WhereClause) -> WhereClause {
+        WhereClause {
+            id: self.next_id(),
+            span: clause.span,
+            predicates: clause
+                .predicates
+                .iter()
+                .map(|p| WherePredicate { id: self.next_id(), ty: self.fresh_type(&p.ty), ..p.clone() })
+                .collect(),
+        }
+    }
+
+    fn fresh_type(&mut self, ty: &Type) -> Type {
+        let mut ty = ty.clone();
+        self.renumber_type(&mut ty);
+        ty
+    }
+
+    fn fresh_expr(&mut self, expr: &Expression) -> Expression {
+        let mut expr = expr.clone();
+        self.renumber_expr(&mut expr);
+        expr
+    }
+
+    fn renumber_type(&mut self, ty: &mut Type) {
+        ty.id = self.next_id();
+        match &mut ty.kind {
+            TypeKind::Generic { args, .. } | TypeKind::Path { args, .. } | TypeKind::Tuple(args) => {
+                for arg in args {
+                    self.renumber_type(arg);
+                }
+            }
+            TypeKind::Array { elem, size } => {
+                self.renumber_type(elem);
+                if let Some(size) = size {
+                    self.renumber_expr(size);
+                }
+            }
+            TypeKind::Reference { ty: inner, .. }
+            | TypeKind::Affine(inner)
+            | TypeKind::Fuzzy(inner) => self.renumber_type(inner),
+            TypeKind::Function { params, ret } => {
+                for param in params {
+                    self.renumber_type(param);
+                }
+                self.renumber_type(ret);
+            }
+            TypeKind::Learned(exprs) => {
+                for expr in exprs {
+                    self.renumber_expr(expr);
+                }
+            }
+            TypeKind::Primitive(_) | TypeKind::Named(_) | TypeKind::Inferred => {}
+        }
+    }
+
+    fn renumber_expr(&mut self, expr: &mut Expression) {
+        expr.id = self.next_id();
+        match &mut expr.kind {
+            ExpressionKind::If { then_block, else_block, .. } => {
+                self.renumber_block(then_block);
+                if let Some(block) = else_block {
+                    self.renumber_block(block);
+                }
+            }
+            ExpressionKind::Match { arms, .. } => {
+                for arm in arms {
+                    arm.id = self.next_id();
+                    self.renumber_pattern(&mut arm.pattern);
+                    if let Some(guard) = &mut arm.guard {
+                        self.renumber_expr(guard);
+                    }
+                    self.renumber_expr(&mut arm.body);
+                }
+            }
+            ExpressionKind::Loop(body) | ExpressionKind::While { body, .. } | ExpressionKind::Block(body) => {
+                self.renumber_block(body)
+            }
+            ExpressionKind::For { pattern, body, .. } => {
+                self.renumber_pattern(pattern);
+                self.renumber_block(body);
+            }
+            ExpressionKind::Closure { params, ret, .. } => {
+                for param in params {
+                    param.id = self.next_id();
+                    self.renumber_type(&mut param.ty);
+                }
+                if let Some(ret) = ret {
+                    self.renumber_type(ret);
+                }
+            }
+            _ => {}
+        }
+        for_each_child(expr, &mut |child| self.renumber_expr(child));
+    }
+
+    fn renumber_block(&mut self, block: &mut Block) {
+        block.id = self.next_id();
+        for stmt in &mut block.stmts {
+            stmt.id = self.next_id();
+            if let StatementKind::Let { pattern, ty, init, .. } = &mut stmt.kind {
+                self.renumber_pattern(pattern);
+                if let Some(ty) = ty {
+                    self.renumber_type(ty);
+                }
+                if let Some(init) = init {
+                    self.renumber_expr(init);
+                }
+            } else if let StatementKind::Expression(expr) = &mut stmt.kind {
+                self.renumber_expr(expr);
+            }
+        }
+        if let Some(expr) = &mut block.expr {
+            self.renumber_expr(expr);
+        }
+    }
+
+    fn renumber_pattern(&mut self, pat: &mut Pattern) {
+        pat.id = self.next_id();
+        match &mut pat.kind {
+            PatternKind::Tuple(elems) | PatternKind::TupleStruct { elems, .. } => {
+                for elem in elems {
+                    self.renumber_pattern(elem);
+                }
+            }
+            PatternKind::Struct { fields, .. } => {
+                for (_, field) in fields {
+                    self.renumber_pattern(field);
+                }
+            }
+            PatternKind::Wildcard | PatternKind::Identifier(_) | PatternKind::Literal(_) | PatternKind::Path(_) => {}
+        }
+    }
+}
+
+/// Point an invariant about `self` at the value a method returns instead
+fn rename_self(expr: &mut Expression) {
+    match &mut expr.kind {
+        ExpressionKind::Identifier(name) if name == "self" => *name = "result".to_string(),
+        _ => for_each_child(expr, &mut rename_self),
+    }
+}
+
+/// Visit the expressions directly inside `expr`, except those in blocks and match arms
+fn for_each_child(expr: &mut Expression, f: &mut impl FnMut(&mut Expression)) {
+    match &mut expr.kind {
+        ExpressionKind::Binary { left, right, .. } => {
+            f(left);
+            f(right);
+        }
+        ExpressionKind::Unary { expr: inner, .. }
+        | ExpressionKind::Comptime(inner)
+        | ExpressionKind::Field { expr: inner, .. }
+        | ExpressionKind::Match { expr: inner, .. }
+        | ExpressionKind::If { cond: inner, .. }
+        | ExpressionKind::While { cond: inner, .. }
+        | ExpressionKind::For { iter: inner, .. }
+        | ExpressionKind::Closure { body: inner, .. } => f(inner),
+        ExpressionKind::Call { func, args } => {
+            f(func);
+            args.iter_mut().for_each(f);
+        }
+        ExpressionKind::MethodCall { receiver, args, .. } => {
+            f(receiver);
+            args.iter_mut().for_each(f);
+        }
+        ExpressionKind::Return(value) | ExpressionKind::Break(value) => {
+            if let Some(value) = value {
+                f(value);
+            }
+        }
+        ExpressionKind::Tuple(elems) | ExpressionKind::Array(elems) => elems.iter_mut().for_each(f),
+        ExpressionKind::Index { expr: base, index } => {
+            f(base);
+            f(index);
+        }
+        ExpressionKind::Struct { fields, .. }
+        | ExpressionKind::Intent { options: fields, .. }
+        | ExpressionKind::Spawn { config: fields, .. } => fields.iter_mut().for_each(|(_, value)| f(value)),
+        ExpressionKind::Synth { config, expr: inner } | ExpressionKind::Verify { config, expr: inner } => {
+            config.iter_mut().for_each(|(_, value)| f(value));
+            f(inner);
+        }
+        ExpressionKind::Hybrid { symbolic, neural, .. } => {
+            f(symbolic);
+            f(neural);
+        }
+        ExpressionKind::Send { message, recipient } => {
+            f(message);
+            f(recipient);
+        }
+        ExpressionKind::Receive { filter, timeout } => {
+            for e in filter.iter_mut().chain(timeout) {
+                f(e);
+            }
+        }
+        ExpressionKind::Broadcast { message, .. } => f(message),
+        ExpressionKind::Literal(_)
+        | ExpressionKind::Identifier(_)
+        | ExpressionKind::Path(_)
+        | ExpressionKind::Loop(_)
+        | ExpressionKind::Block(_)
+        | ExpressionKind::Continue => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lower(source: &str, mode: ContractMode) -> Program {
+        let mut program = my_lang_parser::parse(source).expect("test source parses");
+        ContractLowering::new(source, mode).with_first_node_id(NodeId(10_000)).lower(&mut program);
+        program
+    }
+
+    fn function<'p>(items: &'p [Item], name: &str) -> &'p Function {
+        items
+            .iter()
+            .find_map(|item| match &item.kind {
+                ItemKind::Function(f) if f.name == name => Some(f),
+                _ => None,
+            })
+            .expect("function exists")
+    }
+
+    /// The arguments of the `contract_violation` call in a check statement
+    fn violation_args(stmt: &Statement) -> Vec<String> {
+        let StatementKind::Expression(Expression { kind: ExpressionKind::If { then_block, .. }, .. }) = &stmt.kind
+        else {
+            panic!("expected a check, found {:?}", stmt.kind);
+        };
+        let StatementKind::Expression(Expression { kind: ExpressionKind::Call { args, .. }, .. }) =
+            &then_block.stmts[0].kind
+        else {
+            panic!("expected a call");
+        };
+        args.iter()
+            .map(|arg| match &arg.kind {
+                ExpressionKind::Literal(Literal::String(s)) => format!("{:?}", s),
+                ExpressionKind::Identifier(name) => name.clone(),
+                other => format!("{:?}", other),
+            })
+            .collect()
+    }
+
+    const DIV: &str = "fn div(a: i32, b: i32) -> i32 pre b != 0 post result <= a { a / b }";
+
+    #[test]
+    fn test_modes() {
+        let off = lower(DIV, ContractMode::Off);
+        assert_eq!(off.items.len(), 1);
+        assert!(function(&off.items, "div").body.stmts.is_empty());
+
+        let pre = lower(DIV, ContractMode::Pre);
+        let div = function(&pre.items, "div");
+        assert_eq!(pre.items.len(), 1);
+        assert_eq!(
+            violation_args(&div.body.stmts[0]),
+            [r#""precondition""#, r#""div""#, r#""b != 0""#, r#""a""#, "a", r#""b""#, "b"]
+        );
+
+        let all = lower(DIV, ContractMode::All);
+        let div = function(&all.items, "div");
+        let body = function(&all.items, "div__contract_body");
+        assert!(div.contract.is_none() && body.contract.is_none());
+        assert_eq!(body.visibility, Visibility::Private);
+        assert_eq!(div.body.stmts.len(), 3);
+        assert_eq!(violation_args(&div.body.stmts[2])[..3], [r#""postcondition""#, r#""div""#, r#""result <= a""#]);
+        assert_eq!(violation_args(&div.body.stmts[2])[7..], [r#""result""#, "result"]);
+    }
+
+    #[test]
+    fn test_old_values_are_taken_on_entry() {
+        let program = lower("fn inc(x: i32) -> i32 post result == old(x) + 1 { x + 1 }", ContractMode::All);
+        let inc = function(&program.items, "inc");
+        let StatementKind::Let { pattern, init: Some(init), .. } = &inc.body.stmts[0].kind else {
+            panic!("expected the snapshot");
+        };
+        let PatternKind::Identifier(snapshot) = &pattern.kind else {
+            panic!("expected a binding");
+        };
+        assert!(snapshot.starts_with("__old_"));
+        assert!(matches!(&init.kind, ExpressionKind::Identifier(x) if x == "x"));
+        let post = format!("{:?}", inc.body.stmts[2]);
+        assert!(post.contains(snapshot.as_str()) && !post.contains("\"old\""));
+    }
+
+    #[test]
+    fn test_methods_call_their_body_through_an_inherent_impl() {
+        let program = lower(
+            "struct C { n: i32 }\ntrait T { fn get(&self) -> i32; }\n\
+             impl T for C { fn get(&self) -> i32 invariant self.n >= 0 { self.n } }",
+            ContractMode::All,
+        );
+        assert_eq!(program.items.len(), 4);
+        let ItemKind::Impl(helpers) = &program.items[3].kind else {
+            panic!("expected the helper impl");
+        };
+        assert!(helpers.trait_name.is_none());
+        let ImplItem::Function(helper) = &helpers.items[0] else {
+            panic!("expected a method");
+        };
+        assert_eq!(helper.name, "get__contract_body");
+        assert_eq!(helper.params[0].name, "self");
+    }
+
+    #[test]
+    fn test_mode_from_str() {
+        assert_eq!("pre".parse(), Ok(ContractMode::Pre));
+        assert!("some".parse::<ContractMode>().is_err());
+    }
+
+    #[test]
+    fn test_impl_invariants_are_checked_after_mutating_methods() {
+        let program = lower(
+            "struct A { n: i32 }\n\
+             impl A where invariant self.n >= 0 {\n\
+                 pub fn add(&mut self, by: i32) { self.n = self.n + by; }\n\
+                 pub fn get(&self) -> i32 { self.n }\n\
+                 fn reset(&mut self) { self.n = 0; }\n\
+                 pub fn with(self, n: i32) -> Self { A { n } }\n\
+             }",
+            ContractMode::All,
+        );
+        let ItemKind::Impl(helpers) = &program.items[2].kind else {
+            panic!("expected the helper impl");
+        };
+        let names: Vec<_> = helpers
+            .items
+            .iter()
+            .map(|item| match item {
+                ImplItem::Function(f) => f.name.as_str(),
+                _ => "",
+            })
+            .collect();
+        assert_eq!(names, ["add__contract_body", "with__contract_body"]);
+
+        let ItemKind::Impl(imp) = &program.items[1].kind else {
+            panic!("expected the impl");
+        };
+        let ImplItem::Function(add) = &imp.items[0] else { panic!("expected a method") };
+        let args = violation_args(&add.body.stmts[1]);
+        assert_eq!(args, [r#""invariant""#, r#""A::add""#, r#""self.n >= 0""#, r#""self""#, "self", r#""by""#, "by"]);
+        // The value `with` returns is checked in place of the `self` it consumed
+        let ImplItem::Function(with) = &imp.items[3] else { panic!("expected a method") };
+        let check = format!("{:?}", with.body.stmts[1]);
+        assert!(check.contains("Identifier(\"result\")") && !check.contains("Identifier(\"self\")"));
+
+        assert_eq!(
+            lower("struct A { n: i32 }\nimpl A where invariant self.n >= 0 {}", ContractMode::Pre).items.len(),
+            2
+        );
+    }
+}
@@ -0,0 +1,805 @@
+// Mid-level IR
+// A typed SSA form between checked trees and the backends. Each instance of
+// a function `main` reaches is a graph of basic blocks, whose parameters take
+// the place of φ-nodes. The sugar of the source is gone: loops, `if` and
+// `match` are branches, method calls are calls of the function they resolve
+// to, affine values are dropped where their owner goes out of scope and
+// contracts are checks of their own. Locals that are borrowed mutably or
+// assigned in part live in stack slots; the rest are values.
+
+mod build;
+mod verify;
+
+pub use build::{lower, Lowering};
+pub use verify::VerifyError;
+
+use my_lang_ast::{BinaryOp, Span, UnaryOp};
+use my_lang_resolve::{DefId, DefKind, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A function of a module
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FuncId(pub u32);
+
+/// A basic block of a function; the first is the entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Block(pub u32);
+
+/// A value of a function, defined once by an instruction or as a block parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Value(pub u32);
+
+/// A stack slot of a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Slot(pub u32);
+
+#[derive(Debug, Clone, Default)]
+pub struct Module {
+    pub functions: Vec<Function>,
+    pub entry: Option<FuncId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// Name of the instance, unique in the module
+    pub name: String,
+    pub def: DefId,
+    /// Generic arguments of the instance
+    pub generics: Vec<Ty>,
+    pub ret: Ty,
+    /// Type of each value
+    pub values: Vec<Ty>,
+    pub slots: Vec<SlotData>,
+    /// The entry's parameters are the function's
+    pub blocks: Vec<BlockData>,
+    /// The local each value was first bound to, for backends to name it after
+    pub names: HashMap<Value, String>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct SlotData {
+    /// The local the slot holds, or empty for a temporary
+    pub name: String,
+    pub ty: Ty,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockData {
+    pub params: Vec<Value>,
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+#[derive(Debug, Clone)]
+pub struct Inst {
+    pub result: Option<Value>,
+    pub op: Op,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Const {
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Unit,
+}
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    Const(Const),
+    /// `Neg` or `Not`
+    Unary(UnaryOp, Value),
+    /// Arithmetic, bitwise and comparison operators; `&&` and `||` are branches
+    Binary(BinaryOp, Value, Value),
+    Call(Callee, Vec<Value>),
+    /// A function as a value
+    Func(FuncId),
+    Tuple(Vec<Value>),
+    Array(Vec<Value>),
+    /// A struct or enum variant, given its fields in declaration order
+    Construct(DefId, Vec<Value>),
+    /// Index of the variant of the enum at a place, as a `usize`
+    Tag(Place),
+    Load(Place),
+    Store(Place, Value),
+    /// A reference to a place, mutable or not
+    Ref(Place, bool),
+    /// End the life of the value at a place, running its cleanup
+    Drop(Place),
+    Check(Check),
+}
+
+#[derive(Debug, Clone)]
+pub enum Callee {
+    Fn(FuncId),
+    /// A function or method of the library, by path, such as `Vec::push`
+    Library(String),
+    /// A function value
+    Value(Value),
+}
+
+/// Where a value lives: a slot or a value, and a path into it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Place {
+    pub base: Base,
+    pub proj: Vec<Proj>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Base {
+    Slot(Slot),
+    Value(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Proj {
+    /// What a reference or `Box` points to
+    Deref,
+    /// Field `index` of variant `variant`; structs and tuples have only variant 0
+    Field { variant: usize, index: usize },
+    /// An element of an array or vector
+    Index(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContractKind {
+    Precondition,
+    Postcondition,
+    Invariant,
+}
+
+impl ContractKind {
+    /// How a violation is described, as in "precondition `x > 0` of `f` violated"
+    pub fn describe(self) -> &'static str {
+        match self {
+            ContractKind::Precondition => "precondition",
+            ContractKind::Postcondition => "postcondition",
+            ContractKind::Invariant => "invariant",
+        }
+    }
+}
+
+/// A contract clause checked at run time: when `cond` is false, the
+/// violation is reported with the values of `args` and the program panics
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub kind: ContractKind,
+    pub cond: Value,
+    pub function: String,
+    pub clause: String,
+    pub args: Vec<(String, Value)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub block: Block,
+    pub args: Vec<Value>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Terminator {
+    Jump(Target),
+    Branch {
+        cond: Value,
+        then: Target,
+        otherwise: Target,
+    },
+    Return(Value),
+    /// Control never gets here, as after a call that does not return
+    Unreachable,
+}
+
+impl Place {
+    pub fn slot(slot: Slot) -> Self {
+        Place { base: Base::Slot(slot), proj: Vec::new() }
+    }
+
+    pub fn value(value: Value) -> Self {
+        Place { base: Base::Value(value), proj: Vec::new() }
+    }
+
+    pub fn project(mut self, proj: Proj) -> Self {
+        self.proj.push(proj);
+        self
+    }
+
+    /// Whether the place has an address: it is in a slot or behind a reference
+    pub fn is_addressable(&self) -> bool {
+        matches!(self.base, Base::Slot(_)) || self.proj.contains(&Proj::Deref)
+    }
+
+    pub fn values(&self) -> Vec<Value> {
+        let base = match self.base {
+            Base::Value(value) => Some(value),
+            Base::Slot(_) => None,
+        };
+        base.into_iter()
+            .chain(self.proj.iter().filter_map(|proj| match proj {
+                Proj::Index(index) => Some(*index),
+                _ => None,
+            }))
+            .collect()
+    }
+
+    fn values_mut(&mut self) -> Vec<&mut Value> {
+        let base = match &mut self.base {
+            Base::Value(value) => Some(value),
+            Base::Slot(_) => None,
+        };
+        base.into_iter()
+            .chain(self.proj.iter_mut().filter_map(|proj| match proj {
+                Proj::Index(index) => Some(index),
+                _ => None,
+            }))
+            .collect()
+    }
+}
+
+impl Op {
+    /// The values the instruction reads
+    pub fn values(&self) -> Vec<Value> {
+        match self {
+            Op::Const(_) | Op::Func(_) => Vec::new(),
+            Op::Unary(_, value) => vec![*value],
+            Op::Binary(_, left, right) => vec![*left, *right],
+            Op::Call(callee, args) => {
+                let callee = match callee {
+                    Callee::Value(value) => Some(*value),
+                    _ => None,
+                };
+                callee.into_iter().chain(args.iter().copied()).collect()
+            }
+            Op::Tuple(values) | Op::Array(values) | Op::Construct(_, values) => values.clone(),
+            Op::Tag(place) | Op::Load(place) | Op::Ref(place, _) | Op::Drop(place) => place.values(),
+            Op::Store(place, value) => {
+                let mut values = place.values();
+                values.push(*value);
+                values
+            }
+            Op::Check(check) => std::iter::once(check.cond).chain(check.args.iter().map(|(_, v)| *v)).collect(),
+        }
+    }
+
+    pub(crate) fn values_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Op::Const(_) | Op::Func(_) => Vec::new(),
+            Op::Unary(_, value) => vec![value],
+            Op::Binary(_, left, right) => vec![left, right],
+            Op::Call(callee, args) => {
+                let callee = match callee {
+                    Callee::Value(value) => Some(value),
+                    _ => None,
+                };
+                callee.into_iter().chain(args.iter_mut()).collect()
+            }
+            Op::Tuple(values) | Op::Array(values) | Op::Construct(_, values) => values.iter_mut().collect(),
+            Op::Tag(place) | Op::Load(place) | Op::Ref(place, _) | Op::Drop(place) => place.values_mut(),
+            Op::Store(place, value) => {
+                let mut values = place.values_mut();
+                values.push(value);
+                values
+            }
+            Op::Check(check) => std::iter::once(&mut check.cond).chain(check.args.iter_mut().map(|(_, v)| v)).collect(),
+        }
+    }
+}
+
+impl Terminator {
+    pub fn targets(&self) -> Vec<&Target> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch { then, otherwise, .. } => vec![then, otherwise],
+            Terminator::Return(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    pub(crate) fn targets_mut(&mut self) -> Vec<&mut Target> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch { then, otherwise, .. } => vec![then, otherwise],
+            Terminator::Return(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+
+    /// The values the terminator reads, branch arguments included
+    pub fn values(&self) -> Vec<Value> {
+        let own = match self {
+            Terminator::Branch { cond, .. } => Some(*cond),
+            Terminator::Return(value) => Some(*value),
+            _ => None,
+        };
+        own.into_iter().chain(self.targets().into_iter().flat_map(|t| t.args.iter().copied())).collect()
+    }
+
+    pub(crate) fn values_mut(&mut self) -> Vec<&mut Value> {
+        let (own, targets) = match self {
+            Terminator::Jump(target) => (None, vec![target]),
+            Terminator::Branch { cond, then, otherwise } => (Some(cond), vec![then, otherwise]),
+            Terminator::Return(value) => (Some(value), Vec::new()),
+            Terminator::Unreachable => (None, Vec::new()),
+        };
+        own.into_iter().chain(targets.into_iter().flat_map(|t| t.args.iter_mut())).collect()
+    }
+}
+
+impl Function {
+    pub fn ty(&self, value: Value) -> &Ty {
+        &self.values[value.0 as usize]
+    }
+
+    pub fn block(&self, block: Block) -> &BlockData {
+        &self.blocks[block.0 as usize]
+    }
+
+    /// Types of the parameters, which the entry block takes
+    pub fn params(&self) -> Vec<Ty> {
+        self.blocks.first().map_or(Vec::new(), |entry| entry.params.iter().map(|v| self.ty(*v).clone()).collect())
+    }
+
+    /// The type of the value at `place`, or `None` where a projection does not apply
+    pub fn place_ty(&self, place: &Place, res: &Resolution, types: &TypeckResults) -> Option<Ty> {
+        let mut ty = match place.base {
+            Base::Slot(slot) => self.slots.get(slot.0 as usize)?.ty.clone(),
+            Base::Value(value) => self.values.get(value.0 as usize)?.clone(),
+        };
+        for proj in &place.proj {
+            ty = match proj {
+                Proj::Deref => pointee(&ty, res)?,
+                Proj::Field { variant, index } => field(&ty, *variant, *index, types)?.1,
+                Proj::Index(_) => element(&ty, res)?,
+            };
+        }
+        Some(ty)
+    }
+}
+
+impl Module {
+    pub fn function(&self, id: FuncId) -> &Function {
+        &self.functions[id.0 as usize]
+    }
+
+    /// Check that every function is well formed, as `lower` should leave it
+    /// and every pass that rewrites the IR must
+    pub fn verify(&self, res: &Resolution, types: &TypeckResults) -> Result<(), Vec<VerifyError>> {
+        verify::verify(self, res, types)
+    }
+
+    /// Printable form, naming definitions through `res`
+    pub fn display<'a>(&'a self, res: &'a Resolution, types: &'a TypeckResults) -> DisplayModule<'a> {
+        DisplayModule { module: self, res, types }
+    }
+}
+
+// ========== Types ==========
+
+fn is_prelude(ty: &Ty, name: &str, res: &Resolution) -> bool {
+    matches!(ty, Ty::Adt(def, _) if res.def(*def).is_prelude() && res.def(*def).name == name)
+}
+
+/// What a reference or `Box` points to
+pub(crate) fn pointee(ty: &Ty, res: &Resolution) -> Option<Ty> {
+    match ty {
+        Ty::Ref { ty, .. } => Some((**ty).clone()),
+        Ty::Adt(_, args) if is_prelude(ty, "Box", res) => args.first().cloned(),
+        _ => None,
+    }
+}
+
+/// Elements of an array or vector
+pub(crate) fn element(ty: &Ty, res: &Resolution) -> Option<Ty> {
+    match ty {
+        Ty::Array(elem, _) => Some((**elem).clone()),
+        Ty::Adt(_, args) if is_prelude(ty, "Vec", res) => args.first().cloned(),
+        _ => None,
+    }
+}
+
+/// Name and type of field `index` of variant `variant` of a tuple, struct or enum
+pub(crate) fn field(ty: &Ty, variant: usize, index: usize, types: &TypeckResults) -> Option<(String, Ty)> {
+    match ty {
+        Ty::Tuple(elems) if variant == 0 => elems.get(index).map(|elem| (index.to_string(), elem.clone())),
+        Ty::Adt(def, args) => {
+            let adt = types.adts.get(def)?;
+            let field = adt.variants.get(variant)?.fields.get(index)?;
+            let map = adt.generics.iter().copied().zip(args.iter().cloned()).collect();
+            Some((field.name.clone(), field.ty.subst(&map)))
+        }
+        _ => None,
+    }
+}
+
+// ========== Text ==========
+
+pub struct DisplayModule<'a> {
+    module: &'a Module,
+    res: &'a Resolution,
+    types: &'a TypeckResults,
+}
+
+impl fmt::Display for DisplayModule<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, func) in self.module.functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            self.function(f, func)?;
+        }
+        Ok(())
+    }
+}
+
+impl DisplayModule<'_> {
+    fn function(&self, f: &mut fmt::Formatter, func: &Function) -> fmt::Result {
+        let ty = |ty: &Ty| ty.display(self.res).to_string();
+        let params = func.blocks.first().map_or(&[][..], |entry| &entry.params[..]);
+        let params: Vec<String> = params.iter().map(|v| format!("v{}: {}", v.0, ty(func.ty(*v)))).collect();
+        writeln!(f, "fn @{}({}) -> {} {{", func.name, params.join(", "), ty(&func.ret))?;
+        for (i, slot) in func.slots.iter().enumerate() {
+            match slot.name.is_empty() {
+                true => writeln!(f, "    slot s{}: {}", i, ty(&slot.ty))?,
+                false => writeln!(f, "    slot s{}: {} ({})", i, ty(&slot.ty), slot.name)?,
+            }
+        }
+        for (i, block) in func.blocks.iter().enumerate() {
+            match i == 0 || block.params.is_empty() {
+                true => writeln!(f, "bb{}:", i)?,
+                false => {
+                    let params: Vec<String> =
+                        block.params.iter().map(|v| format!("v{}: {}", v.0, ty(func.ty(*v)))).collect();
+                    writeln!(f, "bb{}({}):", i, params.join(", "))?;
+                }
+            }
+            for inst in &block.insts {
+                f.write_str("    ")?;
+                if let Some(result) = inst.result {
+                    write!(f, "v{}: {} = ", result.0, ty(func.ty(result)))?;
+                }
+                self.op(f, func, &inst.op)?;
+                writeln!(f)?;
+            }
+            f.write_str("    ")?;
+            match &block.term {
+                Terminator::Jump(target) => write!(f, "jump {}", target)?,
+                Terminator::Branch { cond, then, otherwise } => write!(f, "br v{}, {}, {}", cond.0, then, otherwise)?,
+                Terminator::Return(value) => write!(f, "return v{}", value.0)?,
+                Terminator::Unreachable => f.write_str("unreachable")?,
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "}}")
+    }
+
+    fn op(&self, f: &mut fmt::Formatter, func: &Function, op: &Op) -> fmt::Result {
+        let list = |values: &[Value]| values.iter().map(|v| format!("v{}", v.0)).collect::<Vec<_>>().join(", ");
+        let place = |place: &Place| self.place(func, place);
+        match op {
+            Op::Const(Const::Int(n)) => write!(f, "const {}", n),
+            Op::Const(Const::Float(x)) => write!(f, "const {:?}", x),
+            Op::Const(Const::Bool(b)) => write!(f, "const {}", b),
+            Op::Const(Const::Char(c)) => write!(f, "const {:?}", c),
+            Op::Const(Const::Str(s)) => write!(f, "const {:?}", s),
+            Op::Const(Const::Unit) => f.write_str("const ()"),
+            Op::Unary(op, value) => write!(f, "{} v{}", unary_name(op), value.0),
+            Op::Binary(op, left, right) => write!(f, "{} v{}, v{}", binary_name(op), left.0, right.0),
+            Op::Call(Callee::Fn(id), args) => write!(f, "call @{}({})", self.name(*id), list(args)),
+            Op::Call(Callee::Library(path), args) => write!(f, "call extern {}({})", path, list(args)),
+            Op::Call(Callee::Value(callee), args) => write!(f, "call v{}({})", callee.0, list(args)),
+            Op::Func(id) => write!(f, "func @{}", self.name(*id)),
+            Op::Tuple(values) => write!(f, "tuple ({})", list(values)),
+            Op::Array(values) => write!(f, "array [{}]", list(values)),
+            Op::Construct(def, values) => {
+                let definition = self.res.def(*def);
+                match definition.parent.filter(|_| definition.kind == DefKind::Variant) {
+                    Some(parent) => write!(f, "construct {}::{}", self.res.def(parent).name, definition.name)?,
+                    None => write!(f, "construct {}", definition.name)?,
+                }
+                write!(f, "({})", list(values))
+            }
+            Op::Tag(at) => write!(f, "tag {}", place(at)),
+            Op::Load(at) => write!(f, "load {}", place(at)),
+            Op::Store(at, value) => write!(f, "store {}, v{}", place(at), value.0),
+            Op::Ref(at, true) => write!(f, "ref mut {}", place(at)),
+            Op::Ref(at, false) => write!(f, "ref {}", place(at)),
+            Op::Drop(at) => write!(f, "drop {}", place(at)),
+            Op::Check(check) => {
+                write!(
+                    f,
+                    "check {} v{}, {:?}, {:?}",
+                    check.kind.describe(),
+                    check.cond.0,
+                    check.function,
+                    check.clause
+                )?;
+                for (name, value) in &check.args {
+                    write!(f, ", {}: v{}", name, value.0)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn name(&self, id: FuncId) -> &str {
+        self.module.functions.get(id.0 as usize).map_or("?", |func| func.name.as_str())
+    }
+
+    /// `*v0`, `s1.name`, `(*v2).Some.0` or `s3[v4]`, naming fields where the type is known
+    fn place(&self, func: &Function, place: &Place) -> String {
+        let (mut text, mut ty) = match place.base {
+            Base::Slot(slot) => (format!("s{}", slot.0), func.slots.get(slot.0 as usize).map(|s| s.ty.clone())),
+            Base::Value(value) => (format!("v{}", value.0), func.values.get(value.0 as usize).cloned()),
+        };
+        for proj in &place.proj {
+            if !matches!(proj, Proj::Deref) && text.starts_with('*') {
+                text = format!("({})", text);
+            }
+            match proj {
+                Proj::Deref => {
+                    text = format!("*{}", text);
+                    ty = ty.and_then(|ty| pointee(&ty, self.res));
+                }
+                Proj::Field { variant, index } => {
+                    let found = ty.as_ref().and_then(|ty| field(ty, *variant, *index, self.types));
+                    let variant_name = match &ty {
+                        Some(Ty::Adt(def, _)) if self.res.def(*def).kind == DefKind::Enum => {
+                            self.types.adts.get(def).and_then(|adt| adt.variants.get(*variant)).map(|v| v.def)
+                        }
+                        _ => None,
+                    };
+                    if let Some(variant) = variant_name {
+                        text = format!("{}.{}", text, self.res.def(variant).name);
+                    }
+                    match &found {
+                        Some((name, _)) => text = format!("{}.{}", text, name),
+                        None => text = format!("{}.{}", text, index),
+                    }
+                    ty = found.map(|(_, ty)| ty);
+                }
+                Proj::Index(index) => {
+                    text = format!("{}[v{}]", text, index.0);
+                    ty = ty.and_then(|ty| element(&ty, self.res));
+                }
+            }
+        }
+        text
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.block.0)?;
+        if !self.args.is_empty() {
+            let args: Vec<String> = self.args.iter().map(|v| format!("v{}", v.0)).collect();
+            write!(f, "({})", args.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn unary_name(op: &UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Neg => "neg",
+        UnaryOp::Not => "not",
+        UnaryOp::Deref => "deref",
+        UnaryOp::Ref => "ref",
+        UnaryOp::RefMut => "ref mut",
+    }
+}
+
+fn binary_name(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "add",
+        BinaryOp::Sub => "sub",
+        BinaryOp::Mul => "mul",
+        BinaryOp::Div => "div",
+        BinaryOp::Mod => "rem",
+        BinaryOp::Eq => "eq",
+        BinaryOp::Ne => "ne",
+        BinaryOp::Lt => "lt",
+        BinaryOp::Gt => "gt",
+        BinaryOp::Le => "le",
+        BinaryOp::Ge => "ge",
+        BinaryOp::And => "and",
+        BinaryOp::Or => "or",
+        BinaryOp::BitAnd => "bitand",
+        BinaryOp::BitOr => "bitor",
+        BinaryOp::BitXor => "bitxor",
+        BinaryOp::Shl => "shl",
+        BinaryOp::Shr => "shr",
+        BinaryOp::Assign => "assign",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::check_source;
+    use my_lang_diagnostics::Diagnostic;
+
+    /// The IR `source` lowers to, printed, once the verifier accepts it
+    fn lower_source(source: &str) -> (String, Vec<Diagnostic>) {
+        let (module, diags, res, types) = lower_module(source);
+        if let Err(errors) = module.verify(&res, &types) {
+            panic!("{:?}\n{}", errors, module.display(&res, &types));
+        }
+        (module.display(&res, &types).to_string(), diags)
+    }
+
+    fn lower_module(source: &str) -> (Module, Vec<Diagnostic>, Resolution, TypeckResults) {
+        let (program, res, types) = check_source(source);
+        let (module, diags) = lower(&[&program], &res, &types);
+        (module, diags, res, types)
+    }
+
+    #[test]
+    fn test_locals_become_block_parameters() {
+        let (ir, diags) = lower_source(
+            r#"
+            fn count(limit: i32) -> i32 {
+                let mut n = 0;
+                let mut i = 0;
+                while i < limit {
+                    i = i + 1;
+                    if i % 2 == 0 { continue; }
+                    n = n + i;
+                }
+                n
+            }
+
+            fn main() {
+                println("{}", count(5));
+            }
+        "#,
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert!(ir.contains("fn @count(v0: i32) -> i32 {"), "{}", ir);
+        assert!(ir.contains("bb1(v3: i32, v4: i32):"), "{}", ir);
+        // `continue` passes the loop the values of the locals it has then
+        assert!(ir.contains("br v11, bb1(v7, v4), bb3"), "{}", ir);
+        assert!(ir.contains("jump bb1(v7, v12)"), "{}", ir);
+        assert!(ir.contains("return v4"), "{}", ir);
+        assert!(!ir.contains("slot"), "{}", ir);
+    }
+
+    #[test]
+    fn test_methods_are_calls_and_mutable_locals_live_in_slots() {
+        let (ir, diags) = lower_source(
+            r#"
+            struct Counter { n: i32 }
+
+            impl Counter {
+                fn bump(&mut self) { self.n = self.n + 1; }
+            }
+
+            fn main() {
+                let mut c = Counter { n: 0 };
+                c.bump();
+                let mut v: Vec<i32> = Vec::new();
+                v.push(c.n);
+            }
+        "#,
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        for line in [
+            "slot s0: Counter (c)",
+            "v3: &mut Counter = ref mut s0",
+            "call @Counter_bump(v3)",
+            "call extern Vec::push(",
+            "store (*v0).n, v4",
+        ] {
+            assert!(ir.contains(line), "missing `{}` in\n{}", line, ir);
+        }
+    }
+
+    #[test]
+    fn test_contracts_become_checks() {
+        let (ir, diags) = lower_source(
+            r#"
+            fn withdraw(balance: i64, amount: i64) -> i64
+                pre amount <= balance
+            {
+                balance - amount
+            }
+
+            fn main() {
+                println("{}", withdraw(10, 3));
+            }
+        "#,
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert!(
+            ir.contains(r#"check precondition v2, "withdraw", "amount <= balance", balance: v0, amount: v1"#),
+            "{}",
+            ir
+        );
+        assert!(!ir.contains("contract_violation"), "{}", ir);
+    }
+
+    #[test]
+    fn test_affine_values_are_dropped_where_still_owned() {
+        let (ir, diags) = lower_source(
+            r#"
+            import std::io::File;
+
+            struct Handle { handle: affine File }
+
+            impl Handle {
+                fn close(self) { self.handle.close(); }
+            }
+
+            fn maybe_close(c: bool) {
+                let h = Handle { handle: File::open("a") };
+                if c { h.close(); }
+            }
+
+            fn main() {
+                let h = Handle { handle: File::open("b") };
+                drop(h);
+                maybe_close(true);
+            }
+        "#,
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        let main = &ir[ir.find("fn @main").unwrap()..];
+        assert!(main.contains("drop v4"), "{}", ir);
+        // Only the path that did not close the handle drops it
+        let maybe = &ir[ir.find("fn @maybe_close").unwrap()..];
+        let maybe = &maybe[..maybe.find("\n}").unwrap()];
+        assert!(maybe.contains("br v0, bb1, bb2(v2)"), "{}", ir);
+        assert!(maybe.contains("bb2(v8: bool):\n    br v8, bb3, bb4\nbb3:\n    drop v6"), "{}", ir);
+        assert_eq!(maybe.matches("drop v").count(), 1, "{}", ir);
+        let close = &ir[ir.find("fn @Handle_close").unwrap()..];
+        assert!(!close.contains("drop"), "{}", ir);
+    }
+
+    #[test]
+    fn test_prelude_functions_by_path_are_calls_like_any_other() {
+        let (ir, diags) = lower_source(
+            r#"
+            import std::mem;
+
+            fn main() {
+                let v: Vec<i32> = Vec::new();
+                mem::drop(v);
+            }
+        "#,
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert!(ir.contains("v1: Vec<i32> = call extern Vec::new()\n    drop v1\n"), "{}", ir);
+        assert!(!ir.contains("mem::drop"), "{}", ir);
+    }
+
+    #[test]
+    fn test_verifier_rejects_malformed_functions() {
+        let source = r#"
+            fn pick(c: bool) -> i32 {
+                if c { 1 } else { 2 }
+            }
+
+            fn main() {
+                println("{}", pick(true));
+            }
+        "#;
+        let (mut module, _, res, types) = lower_module(source);
+        assert!(module.verify(&res, &types).is_ok());
+        let pick = module.functions.iter_mut().find(|f| f.name == "pick").unwrap();
+        // Pass the join no value, and return the condition
+        let Terminator::Jump(target) = &mut pick.blocks[1].term else { panic!("{:?}", pick.blocks[1].term) };
+        target.args.clear();
+        let last = pick.blocks.len() - 1;
+        pick.blocks[last].term = Terminator::Return(Value(0));
+        let errors: Vec<String> = module.verify(&res, &types).unwrap_err().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "in @pick: bb1 passes 0 arguments to bb3, which takes 1",
+                "in @pick: returned value v0 is `bool` where `i32` is expected",
+            ]
+        );
+    }
+}
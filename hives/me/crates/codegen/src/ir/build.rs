@@ -0,0 +1,2040 @@
+// Lowering to the IR
+// Builds the IR of every instance `main` reaches, a function at a time. Values
+// of locals are put in SSA form as the body is walked, after Braun et al.,
+// "Simple and Efficient Construction of Static Single Assignment Form": a block
+// whose predecessors are not all known yet is left unsealed, and a variable read
+// there becomes a parameter that gets its arguments once it is sealed.
+
+use super::{
+    element, field, pointee, BlockData, Callee, Check, Const, ContractKind, FuncId, Function, Inst, Module, Op, Place,
+    Proj, Slot, SlotData, Target, Terminator, Value,
+};
+use super::{Base, Block};
+use crate::mono::{self, Crate, LibraryCall, Scope};
+use my_lang_ast as ast;
+use my_lang_ast::{
+    BinaryOp, Expression, ExpressionKind, ItemKind, Literal, PatternKind, PrimitiveType, Span, StatementKind, TypeKind,
+    UnaryOp, VariantData,
+};
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_resolve::{DefId, DefKind, Res, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Library methods that change their receiver, which they are given a `&mut` to
+const MUTATING: &[&str] =
+    &["push", "push_str", "pop", "insert", "remove", "clear", "sort", "reverse", "iter_mut", "truncate"];
+
+/// Library methods that take their receiver by value
+const CONSUMING: &[&str] = &["into_iter", "unwrap", "expect", "unwrap_or"];
+
+/// Lower the crate whose `main` is in the first of `programs`. The module has
+/// an instance of each function `main` reaches for each set of generic
+/// arguments, and a function for each constant whose value is not a literal.
+pub fn lower<'a>(
+    programs: &[&'a ast::Program],
+    res: &'a Resolution,
+    types: &'a TypeckResults,
+) -> (Module, Vec<Diagnostic>) {
+    let mut lowering = Lowering::new(programs, res, types);
+    lowering.run();
+    lowering.finish()
+}
+
+/// Lowering a backend drives, asking as it goes for instances its own
+/// support code calls, such as `Drop` impls and `to_string` methods
+pub struct Lowering<'a> {
+    builder: Builder<'a>,
+}
+
+impl<'a> Lowering<'a> {
+    /// Start on the crate whose `main` is in the first of `programs`
+    pub fn new(programs: &[&'a ast::Program], res: &'a Resolution, types: &'a TypeckResults) -> Self {
+        let krate = Crate::new(res, types, programs);
+        let main = krate.main(programs);
+        let mut builder = Builder::new(krate, programs);
+        match main {
+            Some(def) => builder.module.entry = Some(builder.instance(def, Vec::new())),
+            None => {
+                let diag = Diagnostic::error("`main` function not found").with_code(codes::UNSUPPORTED_BY_TARGET);
+                builder.diags.push(diag);
+            }
+        }
+        Self { builder }
+    }
+
+    /// The function for an instance, lowered by the next `run`
+    pub fn instance(&mut self, def: DefId, args: Vec<Ty>) -> FuncId {
+        self.builder.instance(def, args)
+    }
+
+    /// Lower every instance asked for so far, and those they reach
+    pub fn run(&mut self) {
+        while let Some(work) = self.builder.queue.pop_front() {
+            self.builder.work(work);
+        }
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.builder.module
+    }
+
+    pub fn finish(self) -> (Module, Vec<Diagnostic>) {
+        (self.builder.module, self.builder.diags)
+    }
+}
+
+/// A body still to be lowered into its function
+enum Work {
+    Fn { def: DefId, args: Vec<Ty>, id: FuncId },
+    Const { def: DefId, id: FuncId },
+}
+
+/// A variable whose value SSA construction tracks: a local that lives in no
+/// slot, a drop flag or a counter of a `for` loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Var(u32);
+
+#[derive(Clone, Copy)]
+enum Local {
+    Var(Var),
+    Slot(Slot),
+}
+
+struct Loop {
+    /// Where `continue` goes
+    next: Block,
+    exit: Block,
+    /// Whether `break` passes the exit a value
+    value: bool,
+    /// Scopes outside the loop, which `break` and `continue` leave alone
+    depth: usize,
+}
+
+/// How a pattern is walked: testing whether it matches, jumping to the
+/// block given when it does not, or binding its names
+#[derive(Clone, Copy)]
+enum Walk {
+    Test(Block),
+    /// Binding by value moves out of the local given, if any
+    Bind(Option<DefId>),
+}
+
+/// The function being lowered
+struct Frame {
+    scope: Scope,
+    ret: Ty,
+    span: Span,
+    values: Vec<Ty>,
+    slots: Vec<SlotData>,
+    blocks: Vec<BlockData>,
+    preds: Vec<Vec<Block>>,
+    /// Where instructions go, or `None` after control left
+    current: Option<Block>,
+    vars: Vec<Ty>,
+    /// The local each variable holds, if any
+    var_names: HashMap<Var, String>,
+    /// The local each value was bound to
+    names: HashMap<Value, String>,
+    defs: HashMap<(Var, Block), Value>,
+    sealed: HashSet<Block>,
+    /// Parameters of unsealed blocks still to get their arguments
+    incomplete: HashMap<Block, Vec<(Var, Value)>>,
+    /// Parameters found to always get the same value, and that value
+    aliases: HashMap<Value, Value>,
+    locals: HashMap<DefId, Local>,
+    /// Locals borrowed mutably or assigned in part, which live in slots
+    addressed: HashSet<DefId>,
+    /// The drop flag of each local that owns an affine value: whether it still holds it
+    owners: HashMap<DefId, Var>,
+    /// Owners bound in each enclosing scope, in order
+    scopes: Vec<Vec<DefId>>,
+    loops: Vec<Loop>,
+    unit: Value,
+    yes: Value,
+    no: Value,
+}
+
+impl Frame {
+    fn new(scope: Scope, ret: Ty, span: Span) -> Self {
+        Self {
+            scope,
+            ret,
+            span,
+            values: Vec::new(),
+            slots: Vec::new(),
+            blocks: Vec::new(),
+            preds: Vec::new(),
+            current: None,
+            vars: Vec::new(),
+            var_names: HashMap::new(),
+            names: HashMap::new(),
+            defs: HashMap::new(),
+            sealed: HashSet::new(),
+            incomplete: HashMap::new(),
+            aliases: HashMap::new(),
+            locals: HashMap::new(),
+            addressed: HashSet::new(),
+            owners: HashMap::new(),
+            scopes: Vec::new(),
+            loops: Vec::new(),
+            unit: Value(0),
+            yes: Value(0),
+            no: Value(0),
+        }
+    }
+}
+
+struct Builder<'a> {
+    res: &'a Resolution,
+    types: &'a TypeckResults,
+    krate: Crate<'a>,
+    /// Fields declared `affine`, by struct or variant and name
+    affine: HashSet<(DefId, String)>,
+    module: Module,
+    instances: HashMap<(DefId, Vec<Ty>), FuncId>,
+    consts: HashMap<DefId, FuncId>,
+    names: HashSet<String>,
+    queue: VecDeque<Work>,
+    diags: Vec<Diagnostic>,
+    reported: HashSet<(String, Span)>,
+    frame: Frame,
+}
+
+impl<'a> Builder<'a> {
+    fn new(krate: Crate<'a>, programs: &[&ast::Program]) -> Self {
+        let (res, types) = (krate.res, krate.types);
+        let mut builder = Self {
+            res,
+            types,
+            krate,
+            affine: HashSet::new(),
+            module: Module::default(),
+            instances: HashMap::new(),
+            consts: HashMap::new(),
+            names: HashSet::new(),
+            queue: VecDeque::new(),
+            diags: Vec::new(),
+            reported: HashSet::new(),
+            frame: Frame::new(Scope::default(), Ty::unit(), Span::default()),
+        };
+        for program in programs {
+            builder.collect_affine(&program.items);
+        }
+        builder
+    }
+
+    // ========== Items ==========
+
+    fn collect_affine(&mut self, items: &[ast::Item]) {
+        let res = self.res;
+        let affine = &mut self.affine;
+        let mut add = |owner: Option<DefId>, name: String, ty: &ast::Type| {
+            if let (Some(owner), TypeKind::Affine(_)) = (owner, &ty.kind) {
+                affine.insert((owner, name));
+            }
+        };
+        let mut modules = Vec::new();
+        for item in items {
+            match &item.kind {
+                ItemKind::Struct(s) => {
+                    for f in &s.fields {
+                        add(res.def_of_node(s.id), f.name.clone(), &f.ty);
+                    }
+                }
+                ItemKind::Enum(e) => {
+                    for variant in &e.variants {
+                        let def = res.def_of_node(variant.id);
+                        match &variant.data {
+                            VariantData::Unit => {}
+                            VariantData::Tuple(tys) => {
+                                tys.iter().enumerate().for_each(|(i, ty)| add(def, i.to_string(), ty));
+                            }
+                            VariantData::Struct(fields) => fields.iter().for_each(|f| add(def, f.name.clone(), &f.ty)),
+                        }
+                    }
+                }
+                ItemKind::Module(module) => modules.push(module),
+                _ => {}
+            }
+        }
+        for module in modules {
+            self.collect_affine(&module.items);
+        }
+    }
+
+    /// The function for an instance, lowered once it comes off the queue
+    fn instance(&mut self, def: DefId, args: Vec<Ty>) -> FuncId {
+        if let Some(id) = self.instances.get(&(def, args.clone())) {
+            return *id;
+        }
+        let mut base = self.krate.fn_names.get(&def).cloned().unwrap_or_else(|| self.res.def(def).name.clone());
+        if !args.is_empty() {
+            let args: Vec<String> = args.iter().map(|t| self.krate.mangle(t)).collect();
+            base = format!("{}__{}", base, args.join("_"));
+        }
+        let id = self.declare_fn(def, args.clone(), &base);
+        self.instances.insert((def, args.clone()), id);
+        self.queue.push_back(Work::Fn { def, args, id });
+        id
+    }
+
+    fn declare_fn(&mut self, def: DefId, generics: Vec<Ty>, base: &str) -> FuncId {
+        let mut name = base.to_string();
+        let mut n = 2;
+        while !self.names.insert(name.clone()) {
+            name = format!("{}_{}", base, n);
+            n += 1;
+        }
+        let id = FuncId(self.module.functions.len() as u32);
+        self.module.functions.push(Function {
+            name,
+            def,
+            generics,
+            ret: Ty::unit(),
+            values: Vec::new(),
+            slots: Vec::new(),
+            blocks: Vec::new(),
+            names: HashMap::new(),
+            span: Span::default(),
+        });
+        id
+    }
+
+    fn work(&mut self, work: Work) {
+        match work {
+            Work::Fn { def, args, id } => self.function(def, args, id),
+            Work::Const { def, id } => {
+                let c = self.krate.consts[&def];
+                let ty = self.types.type_of(c.value.id).cloned().unwrap_or(Ty::Error);
+                self.frame = Frame::new(Scope::default(), ty.clone(), c.span);
+                self.krate.infer_expr(&mut self.frame.scope, &c.value);
+                self.start();
+                self.frame.scopes.push(Vec::new());
+                if let Some(value) = self.arg(&c.value, &ty) {
+                    self.ret(value);
+                }
+                self.finish(id);
+            }
+        }
+    }
+
+    fn function(&mut self, def: DefId, args: Vec<Ty>, id: FuncId) {
+        let (func, types) = (self.krate.fns[&def], self.types);
+        let sig = &types.sigs[&def];
+        let subst: HashMap<DefId, Ty> = sig.generics.iter().copied().zip(args).collect();
+        let ret = sig.output.subst(&subst);
+        self.frame = Frame::new(Scope { subst, ..Scope::default() }, ret, func.span);
+        self.krate.infer_block(&mut self.frame.scope, &func.body);
+        let mut addressed = HashSet::new();
+        self.scan_block(&func.body, &mut addressed);
+        self.frame.addressed = addressed;
+        self.start();
+        self.frame.scopes.push(Vec::new());
+        // Parameters come before anything binding them puts in the entry
+        let params: Vec<(Value, Ty)> = sig
+            .inputs
+            .iter()
+            .map(|ty| {
+                let ty = ty.subst(&self.frame.scope.subst);
+                (self.param(Block(0), ty.clone()), ty)
+            })
+            .collect();
+        for (param, (value, ty)) in func.params.iter().zip(params) {
+            if let Some(def) = self.res.def_of_node(param.id) {
+                let annotated = matches!(param.ty.kind, TypeKind::Affine(_));
+                self.bind(def, &param.name, value, &ty, annotated);
+            }
+        }
+        if let Some(value) = self.block(&func.body) {
+            self.ret(value);
+        }
+        self.finish(id);
+    }
+
+    /// Open the entry block, with the constants lowering reaches for most
+    fn start(&mut self) {
+        let entry = self.new_block();
+        self.seal(entry);
+        self.frame.current = Some(entry);
+        self.frame.unit = self.emit(Op::Const(Const::Unit), Ty::unit());
+        self.frame.yes = self.emit(Op::Const(Const::Bool(true)), Ty::bool());
+        self.frame.no = self.emit(Op::Const(Const::Bool(false)), Ty::bool());
+    }
+
+    fn unsupported(&mut self, what: impl Into<String>, span: Span) {
+        let message = format!("{} cannot be lowered to the IR", what.into());
+        if self.reported.insert((message.clone(), span)) {
+            let diag = Diagnostic::error(message)
+                .with_code(codes::UNSUPPORTED_BY_TARGET)
+                .with_primary(span, "not supported by the IR");
+            self.diags.push(diag);
+        }
+    }
+
+    // ========== Types ==========
+
+    fn ty(&self, node: ast::NodeId) -> Ty {
+        self.krate.ty(&self.frame.scope, node)
+    }
+
+    /// Whether values of `ty` hold an affine field, and so are moved rather than copied
+    fn is_affine(&self, ty: &Ty) -> bool {
+        self.affine_in(ty, &mut Vec::new())
+    }
+
+    fn affine_in(&self, ty: &Ty, visiting: &mut Vec<DefId>) -> bool {
+        match ty {
+            Ty::Adt(def, args) if self.krate.is_prelude(*def, "Vec") || self.krate.is_prelude(*def, "Box") => {
+                args.iter().any(|arg| self.affine_in(arg, visiting))
+            }
+            Ty::Adt(def, args) => {
+                let Some(adt) = self.types.adts.get(def) else { return false };
+                if visiting.contains(def) {
+                    return false;
+                }
+                visiting.push(*def);
+                let map = adt.generics.iter().copied().zip(args.iter().cloned()).collect();
+                let found = adt.variants.iter().any(|v| {
+                    v.fields.iter().any(|f| {
+                        self.affine.contains(&(v.def, f.name.clone())) || self.affine_in(&f.ty.subst(&map), visiting)
+                    })
+                });
+                visiting.pop();
+                found
+            }
+            Ty::Tuple(elems) => elems.iter().any(|elem| self.affine_in(elem, visiting)),
+            Ty::Array(elem, _) => self.affine_in(elem, visiting),
+            _ => false,
+        }
+    }
+
+    fn place_ty(&self, place: &Place) -> Ty {
+        let mut ty = match place.base {
+            Base::Slot(slot) => self.frame.slots[slot.0 as usize].ty.clone(),
+            Base::Value(value) => self.frame.values[value.0 as usize].clone(),
+        };
+        for proj in &place.proj {
+            let next = match proj {
+                Proj::Deref => pointee(&ty, self.res),
+                Proj::Field { variant, index } => field(&ty, *variant, *index, self.types).map(|(_, ty)| ty),
+                Proj::Index(_) => element(&ty, self.res),
+            };
+            ty = next.unwrap_or(Ty::Error);
+        }
+        ty
+    }
+
+    fn value_ty(&self, value: Value) -> Ty {
+        self.frame.values[value.0 as usize].clone()
+    }
+
+    // ========== Blocks ==========
+
+    fn new_block(&mut self) -> Block {
+        self.frame.blocks.push(BlockData { params: Vec::new(), insts: Vec::new(), term: Terminator::Unreachable });
+        self.frame.preds.push(Vec::new());
+        Block(self.frame.blocks.len() as u32 - 1)
+    }
+
+    fn new_value(&mut self, ty: Ty) -> Value {
+        self.frame.values.push(ty);
+        Value(self.frame.values.len() as u32 - 1)
+    }
+
+    fn param(&mut self, block: Block, ty: Ty) -> Value {
+        let value = self.new_value(ty);
+        self.frame.blocks[block.0 as usize].params.push(value);
+        value
+    }
+
+    /// A parameter for the value of an `if`, `match` or loop of type `ty`, unless it has none
+    fn value_param(&mut self, block: Block, ty: &Ty) -> Option<Value> {
+        (!ty.is_unit() && *ty != Ty::Never).then(|| self.param(block, ty.clone()))
+    }
+
+    /// The block instructions go in. Code after control left goes in a block nothing reaches.
+    fn at(&mut self) -> Block {
+        match self.frame.current {
+            Some(block) => block,
+            None => {
+                let block = self.new_block();
+                self.seal(block);
+                self.frame.current = Some(block);
+                block
+            }
+        }
+    }
+
+    fn emit(&mut self, op: Op, ty: Ty) -> Value {
+        let result = self.new_value(ty);
+        let block = self.at();
+        let span = self.frame.span;
+        self.frame.blocks[block.0 as usize].insts.push(Inst { result: Some(result), op, span });
+        result
+    }
+
+    fn emit_void(&mut self, op: Op) {
+        let block = self.at();
+        let span = self.frame.span;
+        self.frame.blocks[block.0 as usize].insts.push(Inst { result: None, op, span });
+    }
+
+    fn terminate(&mut self, term: Terminator) {
+        let Some(block) = self.frame.current.take() else { return };
+        for target in term.targets() {
+            self.frame.preds[target.block.0 as usize].push(block);
+        }
+        self.frame.blocks[block.0 as usize].term = term;
+    }
+
+    fn jump(&mut self, block: Block, args: Vec<Value>) {
+        self.terminate(Terminator::Jump(Target { block, args }));
+    }
+
+    fn branch(&mut self, cond: Value, then: Block, otherwise: Block) {
+        let (then, otherwise) =
+            (Target { block: then, args: Vec::new() }, Target { block: otherwise, args: Vec::new() });
+        self.terminate(Terminator::Branch { cond, then, otherwise });
+    }
+
+    /// Pass the value of a branch to the block joining them
+    fn join(&mut self, join: Block, result: Option<Value>, value: Value, ty: &Ty) {
+        let args = match result {
+            Some(_) => vec![self.coerce(value, ty)],
+            None => Vec::new(),
+        };
+        self.jump(join, args);
+    }
+
+    /// Continue in `join` once every branch reached it, with the value they passed
+    fn enter(&mut self, join: Block, result: Option<Value>) -> Option<Value> {
+        if self.frame.preds[join.0 as usize].is_empty() {
+            self.frame.current = None;
+            return None;
+        }
+        self.frame.current = Some(join);
+        Some(result.unwrap_or(self.frame.unit))
+    }
+
+    // ========== SSA ==========
+
+    fn var(&mut self, ty: Ty) -> Var {
+        self.frame.vars.push(ty);
+        Var(self.frame.vars.len() as u32 - 1)
+    }
+
+    fn def_var(&mut self, var: Var, value: Value) {
+        let block = self.at();
+        self.frame.defs.insert((var, block), value);
+        self.name_value(var, value);
+    }
+
+    /// Name `value` after the local `var` holds, unless it is one of the constants every local shares
+    fn name_value(&mut self, var: Var, value: Value) {
+        let interned = [self.frame.unit, self.frame.yes, self.frame.no];
+        if let (Some(name), false) = (self.frame.var_names.get(&var), interned.contains(&value)) {
+            self.frame.names.entry(value).or_insert_with(|| name.clone());
+        }
+    }
+
+    fn use_var(&mut self, var: Var) -> Value {
+        let block = self.at();
+        self.use_var_in(var, block)
+    }
+
+    fn use_var_in(&mut self, var: Var, block: Block) -> Value {
+        if let Some(value) = self.frame.defs.get(&(var, block)) {
+            return self.resolve(*value);
+        }
+        let ty = self.frame.vars[var.0 as usize].clone();
+        let preds = self.frame.preds[block.0 as usize].clone();
+        let value = if !self.frame.sealed.contains(&block) {
+            let param = self.param(block, ty);
+            self.frame.incomplete.entry(block).or_default().push((var, param));
+            self.name_value(var, param);
+            param
+        } else if let [pred] = preds.as_slice() {
+            self.use_var_in(var, *pred)
+        } else if preds.is_empty() && block == Block(0) {
+            self.unsupported("a variable read before it is assigned", self.frame.span);
+            self.emit(Op::Const(Const::Unit), ty)
+        } else {
+            let param = self.param(block, ty);
+            self.frame.defs.insert((var, block), param);
+            self.name_value(var, param);
+            self.add_operands(var, param, block)
+        };
+        self.frame.defs.insert((var, block), value);
+        value
+    }
+
+    /// Give `param` of `block` the value of `var` at the end of each predecessor
+    fn add_operands(&mut self, var: Var, param: Value, block: Block) -> Value {
+        for pred in self.frame.preds[block.0 as usize].clone() {
+            let value = self.use_var_in(var, pred);
+            let position = self.frame.blocks[block.0 as usize].params.iter().position(|p| *p == param);
+            let Some(position) = position else { continue };
+            let term = &mut self.frame.blocks[pred.0 as usize].term;
+            if let Some(target) = term.targets_mut().into_iter().find(|t| t.block == block && t.args.len() == position)
+            {
+                target.args.push(value);
+            }
+        }
+        self.remove_trivial(param, block)
+    }
+
+    /// A parameter that only ever gets one value, or itself, is that value
+    fn remove_trivial(&mut self, param: Value, block: Block) -> Value {
+        let Some(position) = self.frame.blocks[block.0 as usize].params.iter().position(|p| *p == param) else {
+            return self.resolve(param);
+        };
+        let mut same = None;
+        for pred in &self.frame.preds[block.0 as usize] {
+            for target in self.frame.blocks[pred.0 as usize].term.targets() {
+                let Some(arg) = target.args.get(position).filter(|_| target.block == block) else { continue };
+                let arg = self.resolve(*arg);
+                if arg == param || Some(arg) == same {
+                    continue;
+                }
+                if same.is_some() {
+                    return param;
+                }
+                same = Some(arg);
+            }
+        }
+        let Some(same) = same else { return param };
+        self.remove_param(block, position);
+        self.frame.aliases.insert(param, same);
+        same
+    }
+
+    fn remove_param(&mut self, block: Block, position: usize) {
+        self.frame.blocks[block.0 as usize].params.remove(position);
+        for data in &mut self.frame.blocks {
+            for target in data.term.targets_mut() {
+                if target.block == block && target.args.len() > position {
+                    target.args.remove(position);
+                }
+            }
+        }
+    }
+
+    fn resolve(&self, mut value: Value) -> Value {
+        while let Some(next) = self.frame.aliases.get(&value) {
+            value = *next;
+        }
+        value
+    }
+
+    /// Every predecessor of `block` is known
+    fn seal(&mut self, block: Block) {
+        for (var, param) in self.frame.incomplete.remove(&block).unwrap_or_default() {
+            self.add_operands(var, param, block);
+        }
+        self.frame.sealed.insert(block);
+    }
+
+    // ========== Finishing ==========
+
+    /// Tidy the function and put it in the module: parameters found trivial
+    /// once every block is sealed go, branches on constants become jumps,
+    /// empty blocks are jumped over, blocks nothing reaches go, and blocks
+    /// and values are numbered in order
+    fn finish(&mut self, id: FuncId) {
+        let (yes, no) = (self.frame.yes, self.frame.no);
+        loop {
+            let reachable = self.reachable();
+            let mut changed = false;
+            for &block in reachable.iter().skip(1) {
+                let mut position = 0;
+                while position < self.frame.blocks[block.0 as usize].params.len() {
+                    let param = self.frame.blocks[block.0 as usize].params[position];
+                    let mut args = HashSet::new();
+                    for &pred in &reachable {
+                        for target in self.frame.blocks[pred.0 as usize].term.targets() {
+                            if let Some(arg) = target.args.get(position).filter(|_| target.block == block) {
+                                args.insert(self.resolve(*arg));
+                            }
+                        }
+                    }
+                    args.remove(&param);
+                    match args.into_iter().collect::<Vec<_>>().as_slice() {
+                        [same] => {
+                            self.remove_param(block, position);
+                            self.frame.aliases.insert(param, *same);
+                            changed = true;
+                        }
+                        _ => position += 1,
+                    }
+                }
+            }
+            for &block in &reachable {
+                if let Terminator::Branch { cond, then, otherwise } = &self.frame.blocks[block.0 as usize].term {
+                    let taken = match self.resolve(*cond) {
+                        c if c == yes => then.clone(),
+                        c if c == no => otherwise.clone(),
+                        _ => continue,
+                    };
+                    self.frame.blocks[block.0 as usize].term = Terminator::Jump(taken);
+                    changed = true;
+                }
+            }
+            // A block that only jumps on is skipped
+            for &block in reachable.iter().skip(1) {
+                let data = &self.frame.blocks[block.0 as usize];
+                let Terminator::Jump(next) = &data.term else { continue };
+                if !data.params.is_empty() || !data.insts.is_empty() || next.block == block {
+                    continue;
+                }
+                let next = next.clone();
+                for &pred in &reachable {
+                    for target in self.frame.blocks[pred.0 as usize].term.targets_mut() {
+                        if target.block == block {
+                            *target = next.clone();
+                            changed = true;
+                        }
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // Keep the blocks the entry reaches, in order
+        let reachable = self.reachable();
+        let mut numbers = HashMap::new();
+        for (i, block) in reachable.iter().enumerate() {
+            numbers.insert(*block, Block(i as u32));
+        }
+        let mut blocks: Vec<BlockData> = reachable.iter().map(|b| self.frame.blocks[b.0 as usize].clone()).collect();
+        for data in &mut blocks {
+            for target in data.term.targets_mut() {
+                target.block = numbers[&target.block];
+            }
+            for inst in &mut data.insts {
+                for value in inst.op.values_mut() {
+                    *value = self.resolve(*value);
+                }
+            }
+            for value in data.term.values_mut() {
+                *value = self.resolve(*value);
+            }
+        }
+
+        // Drop the constants of the entry nothing uses
+        let mut used = HashSet::new();
+        for data in &blocks {
+            used.extend(data.insts.iter().flat_map(|inst| inst.op.values()));
+            used.extend(data.term.values());
+        }
+        let interned = [self.frame.unit, self.frame.yes, self.frame.no];
+        if let Some(entry) = blocks.first_mut() {
+            entry.insts.retain(|inst| inst.result.is_none_or(|r| !interned.contains(&r) || used.contains(&r)));
+        }
+
+        // Number values in the order they are defined
+        let mut numbers = HashMap::new();
+        let mut values = Vec::new();
+        for data in &blocks {
+            for value in data.params.iter().chain(data.insts.iter().filter_map(|inst| inst.result.as_ref())) {
+                numbers.insert(*value, Value(values.len() as u32));
+                values.push(self.frame.values[value.0 as usize].clone());
+            }
+        }
+        let renumber = |value: &mut Value| *value = numbers.get(value).copied().unwrap_or(*value);
+        for data in &mut blocks {
+            data.params.iter_mut().for_each(renumber);
+            for inst in &mut data.insts {
+                inst.result.iter_mut().for_each(renumber);
+                inst.op.values_mut().into_iter().for_each(renumber);
+            }
+            data.term.values_mut().into_iter().for_each(renumber);
+        }
+
+        // A value bound to several locals is named after the first
+        let mut bound: Vec<(Value, String)> = std::mem::take(&mut self.frame.names).into_iter().collect();
+        bound.sort();
+        let mut names = HashMap::new();
+        for (value, name) in bound {
+            if let Some(value) = numbers.get(&self.resolve(value)) {
+                names.entry(*value).or_insert(name);
+            }
+        }
+
+        let func = &mut self.module.functions[id.0 as usize];
+        func.ret = self.frame.ret.clone();
+        func.values = values;
+        func.slots = std::mem::take(&mut self.frame.slots);
+        func.blocks = blocks;
+        func.names = names;
+        func.span = self.frame.span;
+    }
+
+    /// Blocks the entry reaches, in reverse postorder: each block comes
+    /// before those it jumps to, loops aside
+    fn reachable(&self) -> Vec<Block> {
+        let mut seen = HashSet::from([Block(0)]);
+        let mut order = Vec::new();
+        let mut stack = vec![(Block(0), 0)];
+        while let Some((block, next)) = stack.pop() {
+            // Taking the last successor first puts the first right after its block
+            let targets = self.frame.blocks[block.0 as usize].term.targets();
+            match targets.iter().rev().nth(next) {
+                Some(target) => {
+                    stack.push((block, next + 1));
+                    if seen.insert(target.block) {
+                        stack.push((target.block, 0));
+                    }
+                }
+                None => order.push(block),
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    // ========== Locals ==========
+
+    /// Which locals live in slots: those borrowed mutably, assigned in part,
+    /// or the receiver of a method that changes it
+    fn scan_block(&self, block: &ast::Block, found: &mut HashSet<DefId>) {
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StatementKind::Let { init: Some(init), .. } => self.scan(init, found),
+                StatementKind::Expression(expr) => self.scan(expr, found),
+                _ => {}
+            }
+        }
+        if let Some(tail) = &block.expr {
+            self.scan(tail, found);
+        }
+    }
+
+    fn scan(&self, expr: &Expression, found: &mut HashSet<DefId>) {
+        let root = match &expr.kind {
+            ExpressionKind::Unary { op: UnaryOp::RefMut, expr: operand } => self.root(operand),
+            ExpressionKind::Binary { left, op: BinaryOp::Assign, .. }
+                if !matches!(left.kind, ExpressionKind::Identifier(_)) =>
+            {
+                self.root(left)
+            }
+            ExpressionKind::MethodCall { receiver, method, .. } if self.mutates(expr, receiver, method) => {
+                self.root(receiver)
+            }
+            _ => None,
+        };
+        found.extend(root);
+        match &expr.kind {
+            ExpressionKind::Binary { left, right, .. } | ExpressionKind::Index { expr: left, index: right } => {
+                self.scan(left, found);
+                self.scan(right, found);
+            }
+            ExpressionKind::Unary { expr: inner, .. }
+            | ExpressionKind::Field { expr: inner, .. }
+            | ExpressionKind::Comptime(inner)
+            | ExpressionKind::Return(Some(inner))
+            | ExpressionKind::Break(Some(inner))
+            | ExpressionKind::Synth { expr: inner, .. }
+            | ExpressionKind::Verify { expr: inner, .. }
+            | ExpressionKind::Hybrid { symbolic: inner, .. } => self.scan(inner, found),
+            ExpressionKind::Call { func: first, args } | ExpressionKind::MethodCall { receiver: first, args, .. } => {
+                self.scan(first, found);
+                args.iter().for_each(|arg| self.scan(arg, found));
+            }
+            ExpressionKind::Tuple(elems) | ExpressionKind::Array(elems) => {
+                elems.iter().for_each(|elem| self.scan(elem, found))
+            }
+            ExpressionKind::Struct { fields, .. } => fields.iter().for_each(|(_, value)| self.scan(value, found)),
+            ExpressionKind::If { cond, then_block, else_block } => {
+                self.scan(cond, found);
+                self.scan_block(then_block, found);
+                if let Some(block) = else_block {
+                    self.scan_block(block, found);
+                }
+            }
+            ExpressionKind::Match { expr: scrutinee, arms } => {
+                self.scan(scrutinee, found);
+                for arm in arms {
+                    if let Some(guard) = &arm.guard {
+                        self.scan(guard, found);
+                    }
+                    self.scan(&arm.body, found);
+                }
+            }
+            ExpressionKind::Loop(body) | ExpressionKind::Block(body) => self.scan_block(body, found),
+            ExpressionKind::While { cond: first, body } | ExpressionKind::For { iter: first, body, .. } => {
+                self.scan(first, found);
+                self.scan_block(body, found);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether a method call changes its receiver where it lives
+    fn mutates(&self, expr: &Expression, receiver: &Expression, method: &str) -> bool {
+        if pointee(&self.ty(receiver.id), self.res).is_some() {
+            return false;
+        }
+        let resolved = self.types.method_calls.get(&expr.id).filter(|def| self.krate.is_user_fn(**def));
+        match resolved.and_then(|def| self.types.sigs.get(def)) {
+            Some(sig) => matches!(sig.inputs.first(), Some(Ty::Ref { mutable: true, .. })),
+            None => MUTATING.contains(&method),
+        }
+    }
+
+    /// The local a place expression is part of, not looking through references
+    fn root(&self, expr: &Expression) -> Option<DefId> {
+        match &expr.kind {
+            ExpressionKind::Identifier(_) => match self.res.res(expr.id) {
+                Some(Res::Def(def)) if matches!(self.res.def(*def).kind, DefKind::Local | DefKind::Param) => Some(*def),
+                _ => None,
+            },
+            ExpressionKind::Field { expr: base, .. } | ExpressionKind::Index { expr: base, .. } => {
+                match pointee(&self.ty(base.id), self.res) {
+                    Some(_) => None,
+                    None => self.root(base),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The owner of an affine value that moving out of `expr` moves from
+    fn owner_root(&self, expr: &Expression) -> Option<DefId> {
+        let def = match &expr.kind {
+            ExpressionKind::Identifier(_) => self.root(expr),
+            ExpressionKind::Field { expr: base, .. } if pointee(&self.ty(base.id), self.res).is_none() => {
+                self.owner_root(base)
+            }
+            _ => None,
+        };
+        def.filter(|def| self.frame.owners.contains_key(def))
+    }
+
+    fn slot(&mut self, name: &str, ty: Ty) -> Slot {
+        self.frame.slots.push(SlotData { name: name.to_string(), ty });
+        Slot(self.frame.slots.len() as u32 - 1)
+    }
+
+    /// Bind a local to `value`, in a slot if it needs one
+    fn bind(&mut self, def: DefId, name: &str, value: Value, ty: &Ty, annotated: bool) {
+        let local = match self.frame.addressed.contains(&def) {
+            true => {
+                let slot = self.slot(name, ty.clone());
+                self.emit_void(Op::Store(Place::slot(slot), value));
+                Local::Slot(slot)
+            }
+            false => {
+                let var = self.var(ty.clone());
+                self.frame.var_names.insert(var, name.to_string());
+                self.def_var(var, value);
+                Local::Var(var)
+            }
+        };
+        self.frame.locals.insert(def, local);
+        if annotated || self.is_affine(ty) {
+            self.own(def, true);
+        }
+    }
+
+    /// A local declared without a value
+    fn declare(&mut self, def: DefId, name: &str, ty: &Ty, annotated: bool) {
+        let local = match self.frame.addressed.contains(&def) {
+            true => Local::Slot(self.slot(name, ty.clone())),
+            false => {
+                let var = self.var(ty.clone());
+                self.frame.var_names.insert(var, name.to_string());
+                Local::Var(var)
+            }
+        };
+        self.frame.locals.insert(def, local);
+        if annotated || self.is_affine(ty) {
+            self.own(def, false);
+        }
+    }
+
+    fn own(&mut self, def: DefId, holds: bool) {
+        let flag = self.var(Ty::bool());
+        let value = if holds { self.frame.yes } else { self.frame.no };
+        self.def_var(flag, value);
+        self.frame.owners.insert(def, flag);
+        if let Some(scope) = self.frame.scopes.last_mut() {
+            scope.push(def);
+        }
+    }
+
+    /// The value of an owner moved out of it
+    fn consume(&mut self, def: DefId) {
+        if let Some(&flag) = self.frame.owners.get(&def) {
+            let no = self.frame.no;
+            self.def_var(flag, no);
+        }
+    }
+
+    fn local_place(&mut self, def: DefId) -> Option<Place> {
+        match *self.frame.locals.get(&def)? {
+            Local::Slot(slot) => Some(Place::slot(slot)),
+            Local::Var(var) => Some(Place::value(self.use_var(var))),
+        }
+    }
+
+    /// Drop what an owner holds, if it still holds it: known statically where
+    /// every path agrees, or else tested on its drop flag
+    fn drop_owned(&mut self, def: DefId) {
+        let (Some(&flag), Some(_)) = (self.frame.owners.get(&def), self.frame.current) else { return };
+        let holds = self.use_var(flag);
+        if holds == self.frame.no {
+            return;
+        }
+        let drop = |builder: &mut Self| {
+            if let Some(place) = builder.local_place(def) {
+                builder.emit_void(Op::Drop(place));
+            }
+        };
+        if holds == self.frame.yes {
+            return drop(self);
+        }
+        let (then, next) = (self.new_block(), self.new_block());
+        self.branch(holds, then, next);
+        self.seal(then);
+        self.frame.current = Some(then);
+        drop(self);
+        self.jump(next, Vec::new());
+        self.seal(next);
+        self.frame.current = Some(next);
+    }
+
+    /// Drop the owners of every scope from `depth` in, innermost first, on the way out of them
+    fn drop_scopes(&mut self, depth: usize) {
+        let owners: Vec<DefId> = self.frame.scopes[depth..].iter().flatten().copied().collect();
+        for def in owners.into_iter().rev() {
+            self.drop_owned(def);
+        }
+    }
+
+    fn assign_local(&mut self, def: DefId, value: Value) {
+        self.drop_owned(def);
+        match self.frame.locals[&def] {
+            Local::Var(var) => self.def_var(var, value),
+            Local::Slot(slot) => self.emit_void(Op::Store(Place::slot(slot), value)),
+        }
+        if let Some(&flag) = self.frame.owners.get(&def) {
+            let yes = self.frame.yes;
+            self.def_var(flag, yes);
+        }
+    }
+
+    fn ret(&mut self, value: Value) {
+        let ret = self.frame.ret.clone();
+        let value = self.coerce(value, &ret);
+        self.drop_scopes(0);
+        self.terminate(Terminator::Return(value));
+    }
+
+    // ========== Statements ==========
+
+    /// The value of `block`, or `None` when control leaves it another way
+    fn block(&mut self, block: &ast::Block) -> Option<Value> {
+        self.frame.scopes.push(Vec::new());
+        for stmt in &block.stmts {
+            if self.stmt(stmt).is_none() {
+                self.frame.scopes.pop();
+                return None;
+            }
+        }
+        let value = match &block.expr {
+            Some(tail) => self.expr(tail),
+            None => Some(self.frame.unit),
+        };
+        let scope = self.frame.scopes.pop().unwrap_or_default();
+        let value = value?;
+        for def in scope.into_iter().rev() {
+            self.drop_owned(def);
+        }
+        Some(value)
+    }
+
+    fn stmt(&mut self, stmt: &ast::Statement) -> Option<()> {
+        let outer = std::mem::replace(&mut self.frame.span, stmt.span);
+        let done = match &stmt.kind {
+            StatementKind::Let { pattern, ty, init, .. } => {
+                let annotated = ty.as_ref().is_some_and(|t| matches!(t.kind, TypeKind::Affine(_)));
+                self.let_stmt(pattern, init.as_ref(), annotated)
+            }
+            StatementKind::Expression(expr) => match self.contract_check(expr) {
+                Some(done) => done,
+                None => self.discard(expr),
+            },
+            StatementKind::Item(_) | StatementKind::Error => Some(()),
+        };
+        self.frame.span = outer;
+        done
+    }
+
+    fn let_stmt(&mut self, pattern: &ast::Pattern, init: Option<&Expression>, annotated: bool) -> Option<()> {
+        let ty = self.ty(pattern.id);
+        let binding = match &pattern.kind {
+            PatternKind::Identifier(name) => self.res.def_of_node(pattern.id).map(|def| (name, def)),
+            _ => None,
+        };
+        match (binding, init) {
+            (Some((name, def)), None) => self.declare(def, name, &ty, annotated),
+            (Some((name, def)), Some(init)) => {
+                let value = self.arg(init, &ty)?;
+                self.bind(def, name, value, &ty, annotated);
+            }
+            // `let _ = x` leaves `x` where it is
+            (None, Some(init)) if matches!(pattern.kind, PatternKind::Wildcard) => {
+                if !self.is_place(init) {
+                    self.discard(init)?;
+                }
+            }
+            (None, Some(init)) => {
+                let init_ty = self.ty(init.id);
+                let root = self.owner_root(init);
+                let place = self.operand_place(init)?;
+                self.pattern(pattern, place, &init_ty, Walk::Bind(root));
+            }
+            (None, None) => {}
+        }
+        Some(())
+    }
+
+    /// Evaluate `expr` for its effects, dropping an affine value it leaves
+    fn discard(&mut self, expr: &Expression) -> Option<()> {
+        let ty = self.ty(expr.id);
+        let value = self.expr(expr)?;
+        if self.is_affine(&ty) {
+            self.emit_void(Op::Drop(Place::value(value)));
+        }
+        Some(())
+    }
+
+    /// A lowered contract clause, `if !(cond) { contract_violation(kind, function, clause, ...); }`,
+    /// becomes a check of its own. `None` when `expr` is something else.
+    fn contract_check(&mut self, expr: &Expression) -> Option<Option<()>> {
+        let ExpressionKind::If { cond, then_block, else_block: None } = &expr.kind else { return None };
+        let ExpressionKind::Unary { op: UnaryOp::Not, expr: clause } = &cond.kind else { return None };
+        let ([stmt], None) = (then_block.stmts.as_slice(), &then_block.expr) else { return None };
+        let StatementKind::Expression(call) = &stmt.kind else { return None };
+        let ExpressionKind::Call { func, args } = &call.kind else { return None };
+        let is_violation =
+            matches!(self.res.res(func.id), Some(Res::Def(def)) if self.krate.is_prelude(*def, "contract_violation"));
+        let text = |arg: &Expression| match &arg.kind {
+            ExpressionKind::Literal(Literal::String(text)) => Some(text.clone()),
+            _ => None,
+        };
+        let texts: Vec<String> = args.iter().take(3).filter_map(text).collect();
+        let (true, [kind, function, clause_text]) = (is_violation, texts.as_slice()) else { return None };
+        let kind = match kind.as_str() {
+            "precondition" => ContractKind::Precondition,
+            "postcondition" => ContractKind::Postcondition,
+            "invariant" => ContractKind::Invariant,
+            _ => return None,
+        };
+        let (function, clause_text) = (function.clone(), clause_text.clone());
+        let checked = (|| {
+            let cond = self.expr(clause)?;
+            let cond = self.peel(cond);
+            let mut values = Vec::new();
+            for pair in args[3..].chunks(2) {
+                if let [name, value] = pair {
+                    let Some(name) = text(name) else { continue };
+                    values.push((name, self.read(value)?));
+                }
+            }
+            self.emit_void(Op::Check(Check { kind, cond, function, clause: clause_text, args: values }));
+            Some(())
+        })();
+        Some(checked)
+    }
+
+    // ========== Expressions ==========
+
+    /// The value of `expr`, or `None` when control leaves it another way
+    fn expr(&mut self, expr: &Expression) -> Option<Value> {
+        let outer = std::mem::replace(&mut self.frame.span, expr.span);
+        let value = self.expr_kind(expr);
+        self.frame.span = outer;
+        value
+    }
+
+    fn expr_kind(&mut self, expr: &Expression) -> Option<Value> {
+        let ty = self.ty(expr.id);
+        match &expr.kind {
+            ExpressionKind::Literal(lit) => Some(self.literal(lit, &ty)),
+            ExpressionKind::Identifier(_) | ExpressionKind::Path(_) => self.name(expr, true),
+            ExpressionKind::Binary { left, op, right } => self.binary(left, op, right, &ty),
+            ExpressionKind::Unary { op, expr: operand } => match op {
+                UnaryOp::Neg | UnaryOp::Not => {
+                    let value = self.expr(operand)?;
+                    let value = self.peel(value);
+                    Some(self.emit(Op::Unary(op.clone(), value), ty))
+                }
+                UnaryOp::Deref => self.load_place(expr, true),
+                UnaryOp::Ref | UnaryOp::RefMut => self.borrow(operand, *op == UnaryOp::RefMut),
+            },
+            ExpressionKind::Call { func, args } => self.call(func, args, &ty),
+            ExpressionKind::MethodCall { receiver, method, args } => {
+                self.method_call(expr, receiver, method, args, &ty)
+            }
+            ExpressionKind::If { cond, then_block, else_block } => {
+                self.if_expr(cond, then_block, else_block.as_ref(), &ty)
+            }
+            ExpressionKind::Match { expr: scrutinee, arms } => self.match_expr(scrutinee, arms, &ty),
+            ExpressionKind::Block(block) => self.block(block),
+            ExpressionKind::Loop(body) => self.loop_expr(body, &ty),
+            ExpressionKind::While { cond, body } => self.while_loop(cond, body),
+            ExpressionKind::For { pattern, iter, body } => self.for_loop(pattern, iter, body),
+            ExpressionKind::Return(value) => {
+                let value = match value {
+                    Some(value) => {
+                        let ret = self.frame.ret.clone();
+                        self.arg(value, &ret)?
+                    }
+                    None => self.frame.unit,
+                };
+                self.ret(value);
+                None
+            }
+            ExpressionKind::Break(value) => {
+                let Some((exit, has_value, depth)) = self.frame.loops.last().map(|l| (l.exit, l.value, l.depth)) else {
+                    self.unsupported("`break` outside of a loop", expr.span);
+                    return None;
+                };
+                let mut args = Vec::new();
+                if let Some(value) = value {
+                    let value = self.expr(value)?;
+                    if has_value {
+                        args.push(value);
+                    }
+                }
+                self.drop_scopes(depth);
+                self.jump(exit, args);
+                None
+            }
+            ExpressionKind::Continue => {
+                let Some((next, depth)) = self.frame.loops.last().map(|l| (l.next, l.depth)) else {
+                    self.unsupported("`continue` outside of a loop", expr.span);
+                    return None;
+                };
+                self.drop_scopes(depth);
+                self.jump(next, Vec::new());
+                None
+            }
+            ExpressionKind::Tuple(elems) if elems.is_empty() => Some(self.frame.unit),
+            ExpressionKind::Tuple(elems) => {
+                let elem_tys = match &ty {
+                    Ty::Tuple(elem_tys) => elem_tys.clone(),
+                    _ => Vec::new(),
+                };
+                let values = self.args(elems, &elem_tys)?;
+                Some(self.emit(Op::Tuple(values), ty))
+            }
+            ExpressionKind::Array(elems) => {
+                let elem_ty = element(&ty, self.res).unwrap_or(Ty::Error);
+                let values = self.args(elems, &vec![elem_ty; elems.len()])?;
+                Some(self.emit(Op::Array(values), ty))
+            }
+            ExpressionKind::Index { .. } | ExpressionKind::Field { .. } => self.load_place(expr, true),
+            ExpressionKind::Struct { fields, .. } => self.struct_expr(expr, fields, &ty),
+            ExpressionKind::Comptime(inner)
+            | ExpressionKind::Synth { expr: inner, .. }
+            | ExpressionKind::Verify { expr: inner, .. }
+            | ExpressionKind::Hybrid { symbolic: inner, .. } => self.expr(inner),
+            ExpressionKind::Closure { .. } => {
+                self.unsupported("closures", expr.span);
+                Some(self.placeholder(&ty))
+            }
+            _ => {
+                self.unsupported("this expression", expr.span);
+                Some(self.placeholder(&ty))
+            }
+        }
+    }
+
+    /// Stands in for what could not be lowered, once that is reported
+    fn placeholder(&mut self, ty: &Ty) -> Value {
+        self.emit(Op::Const(Const::Unit), ty.clone())
+    }
+
+    /// The value of `expr` without moving out of it, as arguments of the prelude's functions are read
+    fn read(&mut self, expr: &Expression) -> Option<Value> {
+        match self.is_place(expr) {
+            true => self.load_place(expr, false),
+            false => self.expr(expr),
+        }
+    }
+
+    fn literal(&mut self, lit: &Literal, ty: &Ty) -> Value {
+        let value = match lit {
+            Literal::Int(n) if matches!(ty, Ty::Prim(PrimitiveType::F32 | PrimitiveType::F64)) => {
+                Const::Float(*n as f64)
+            }
+            Literal::Int(n) => Const::Int(*n as i128),
+            Literal::Float(x) => Const::Float(*x),
+            Literal::String(s) => Const::Str(s.clone()),
+            Literal::Char(c) => Const::Char(*c),
+            Literal::Bool(true) => return self.frame.yes,
+            Literal::Bool(false) => return self.frame.no,
+            Literal::Unit => return self.frame.unit,
+        };
+        self.emit(Op::Const(value), ty.clone())
+    }
+
+    /// The value a name stands for
+    fn name(&mut self, expr: &Expression, consume: bool) -> Option<Value> {
+        let ty = self.ty(expr.id);
+        let def = match self.res.res(expr.id) {
+            Some(Res::Def(def)) => *def,
+            Some(Res::Partial { .. }) => match self.krate.partial(expr) {
+                Ok(def) => def,
+                Err(path) => {
+                    self.unsupported(format!("`{}` as a value", path), expr.span);
+                    return Some(self.placeholder(&ty));
+                }
+            },
+            None => return Some(self.placeholder(&ty)),
+        };
+        let definition = self.res.def(def);
+        match definition.kind {
+            DefKind::Local | DefKind::Param => self.load_place(expr, consume),
+            DefKind::Const => self.constant(def, expr.span),
+            DefKind::Variant | DefKind::Struct if !matches!(ty, Ty::Fn(..)) => {
+                Some(self.emit(Op::Construct(def, Vec::new()), ty))
+            }
+            DefKind::Function if self.krate.is_user_fn(def) => {
+                let Ty::Fn(inputs, output) = &ty else { return Some(self.placeholder(&ty)) };
+                match self.krate.resolve(def, inputs, output) {
+                    Some((def, args)) => {
+                        let id = self.instance(def, args);
+                        Some(self.emit(Op::Func(id), ty))
+                    }
+                    None => {
+                        self.unsupported(format!("`{}` without a body for this type", definition.name), expr.span);
+                        Some(self.placeholder(&ty))
+                    }
+                }
+            }
+            kind => {
+                self.unsupported(format!("{} `{}` as a value", kind.describe(), definition.name), expr.span);
+                Some(self.placeholder(&ty))
+            }
+        }
+    }
+
+    /// A literal constant is its value; anything else is computed by a function of its own
+    fn constant(&mut self, def: DefId, span: Span) -> Option<Value> {
+        let Some(c) = self.krate.consts.get(&def).copied() else {
+            self.unsupported(format!("constant `{}`", self.res.def(def).name), span);
+            return Some(self.frame.unit);
+        };
+        let ty = self.types.type_of(c.value.id).cloned().unwrap_or(Ty::Error);
+        if let ExpressionKind::Literal(lit) = &c.value.kind {
+            return Some(self.literal(lit, &ty));
+        }
+        let id = match self.consts.get(&def) {
+            Some(id) => *id,
+            None => {
+                let id = self.declare_fn(def, Vec::new(), &c.name);
+                self.consts.insert(def, id);
+                self.queue.push_back(Work::Const { def, id });
+                id
+            }
+        };
+        self.call_value(Callee::Fn(id), Vec::new(), &ty)
+    }
+
+    fn binary(&mut self, left: &Expression, op: &BinaryOp, right: &Expression, ty: &Ty) -> Option<Value> {
+        match op {
+            BinaryOp::Assign => {
+                self.assign(left, right)?;
+                Some(self.frame.unit)
+            }
+            BinaryOp::And | BinaryOp::Or => self.logic(left, *op == BinaryOp::And, right),
+            // Comparisons read their operands in place
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => {
+                let l = self.read(left)?;
+                let r = self.read(right)?;
+                let (l, r) = (self.peel(l), self.peel(r));
+                Some(self.emit(Op::Binary(op.clone(), l, r), ty.clone()))
+            }
+            _ => {
+                let l = self.expr(left)?;
+                let r = self.expr(right)?;
+                let (l, r) = (self.peel(l), self.peel(r));
+                Some(self.emit(Op::Binary(op.clone(), l, r), ty.clone()))
+            }
+        }
+    }
+
+    /// `&&` and `||` evaluate their right side only when it decides the value
+    fn logic(&mut self, left: &Expression, is_and: bool, right: &Expression) -> Option<Value> {
+        let l = self.expr(left)?;
+        let l = self.peel(l);
+        let (rhs, join) = (self.new_block(), self.new_block());
+        let result = self.param(join, Ty::bool());
+        let (rhs_target, short) = (Target { block: rhs, args: Vec::new() }, Target { block: join, args: vec![l] });
+        let (then, otherwise) = if is_and { (rhs_target, short) } else { (short, rhs_target) };
+        self.terminate(Terminator::Branch { cond: l, then, otherwise });
+        self.seal(rhs);
+        self.frame.current = Some(rhs);
+        if let Some(r) = self.expr(right) {
+            let r = self.peel(r);
+            self.jump(join, vec![r]);
+        }
+        self.seal(join);
+        self.enter(join, Some(result))
+    }
+
+    fn assign(&mut self, left: &Expression, right: &Expression) -> Option<()> {
+        let ty = self.ty(left.id);
+        let value = self.arg(right, &ty)?;
+        if let (ExpressionKind::Identifier(_), Some(Res::Def(def))) = (&left.kind, self.res.res(left.id)) {
+            if self.frame.locals.contains_key(def) {
+                self.assign_local(*def, value);
+                return Some(());
+            }
+        }
+        let place = self.place(left)?;
+        match place.is_addressable() {
+            true => self.emit_void(Op::Store(place, value)),
+            false => self.unsupported("assignment to this expression", left.span),
+        }
+        Some(())
+    }
+
+    fn struct_expr(&mut self, expr: &Expression, fields: &[(String, Expression)], ty: &Ty) -> Option<Value> {
+        let def = match (self.res.res(expr.id), ty) {
+            (Some(Res::Def(def)), _) => *def,
+            (_, Ty::Adt(def, _)) => *def,
+            _ => return Some(self.placeholder(ty)),
+        };
+        let index = self.krate.variant_of(def).map_or(0, |(_, index)| index);
+        let declared = self.krate.fields_of(ty, index);
+        // Fields are evaluated as written and passed as declared
+        let mut given = HashMap::new();
+        for (name, value) in fields {
+            let field_ty = declared.iter().find(|(field, _)| field == name).map_or(Ty::Error, |(_, ty)| ty.clone());
+            given.insert(name.clone(), self.arg(value, &field_ty)?);
+        }
+        let unit = self.frame.unit;
+        let values = declared.iter().map(|(name, _)| given.get(name).copied().unwrap_or(unit)).collect();
+        Some(self.emit(Op::Construct(def, values), ty.clone()))
+    }
+
+    // ========== Places ==========
+
+    fn is_place(&self, expr: &Expression) -> bool {
+        match &expr.kind {
+            ExpressionKind::Identifier(_) => self.root(expr).is_some(),
+            ExpressionKind::Field { .. } | ExpressionKind::Index { .. } => true,
+            ExpressionKind::Unary { op: UnaryOp::Deref, .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Where the value of a place expression is, or `None` when control leaves while finding it
+    fn place(&mut self, expr: &Expression) -> Option<Place> {
+        match &expr.kind {
+            ExpressionKind::Identifier(name) => {
+                let Some(Res::Def(def)) = self.res.res(expr.id) else { return self.operand_place(expr) };
+                match self.local_place(*def) {
+                    Some(place) => Some(place),
+                    None => {
+                        self.unsupported(format!("use of `{}` before it is bound", name), expr.span);
+                        let ty = self.ty(expr.id);
+                        Some(Place::value(self.placeholder(&ty)))
+                    }
+                }
+            }
+            ExpressionKind::Field { expr: base, field } => {
+                let place = self.base_place(base)?;
+                let ty = self.place_ty(&place);
+                let index = match &ty {
+                    Ty::Tuple(_) => field.parse().unwrap_or(0),
+                    _ => self.krate.fields_of(&ty, 0).iter().position(|(name, _)| name == field).unwrap_or(0),
+                };
+                Some(place.project(Proj::Field { variant: 0, index }))
+            }
+            ExpressionKind::Index { expr: base, index } => {
+                let place = self.base_place(base)?;
+                let index = self.expr(index)?;
+                let index = self.peel(index);
+                Some(place.project(Proj::Index(index)))
+            }
+            ExpressionKind::Unary { op: UnaryOp::Deref, expr: inner } => {
+                let place = self.operand_place(inner)?;
+                match pointee(&self.place_ty(&place), self.res) {
+                    Some(_) => Some(place.project(Proj::Deref)),
+                    None => Some(place),
+                }
+            }
+            _ => self.operand_place(expr),
+        }
+    }
+
+    /// Where a place expression is, or else a value holding what any other expression evaluates to
+    fn operand_place(&mut self, expr: &Expression) -> Option<Place> {
+        match self.is_place(expr) {
+            true => self.place(expr),
+            false => Some(Place::value(self.expr(expr)?)),
+        }
+    }
+
+    /// What fields and elements are taken of, through any references
+    fn base_place(&mut self, base: &Expression) -> Option<Place> {
+        let mut place = self.operand_place(base)?;
+        while pointee(&self.place_ty(&place), self.res).is_some() {
+            place = place.project(Proj::Deref);
+        }
+        Some(place)
+    }
+
+    fn load(&mut self, place: Place) -> Value {
+        match (place.base, place.proj.is_empty()) {
+            (Base::Value(value), true) => value,
+            _ => {
+                let ty = self.place_ty(&place);
+                self.emit(Op::Load(place), ty)
+            }
+        }
+    }
+
+    /// The value of a place expression. Unless `consume` is false, reading an
+    /// affine value out of a local moves it out.
+    fn load_place(&mut self, expr: &Expression, consume: bool) -> Option<Value> {
+        let root = self.owner_root(expr).filter(|_| consume);
+        // A read of an element reports the element's span when it is out of bounds
+        let outer = std::mem::replace(&mut self.frame.span, expr.span);
+        let place = self.place(expr);
+        if let (Some(def), Some(place)) = (root, &place) {
+            if matches!(expr.kind, ExpressionKind::Identifier(_)) || self.is_affine(&self.place_ty(place)) {
+                self.consume(def);
+            }
+        }
+        let value = place.map(|place| self.load(place));
+        self.frame.span = outer;
+        value
+    }
+
+    /// A reference to `operand`. What has no address is copied to a slot that has one.
+    fn borrow(&mut self, operand: &Expression, mutable: bool) -> Option<Value> {
+        let place = self.operand_place(operand)?;
+        Some(self.address(place, mutable))
+    }
+
+    fn address(&mut self, place: Place, mutable: bool) -> Value {
+        let ty = self.place_ty(&place);
+        let place = match place.is_addressable() {
+            true => place,
+            false => {
+                let value = self.load(place);
+                let slot = self.slot("", ty.clone());
+                self.emit_void(Op::Store(Place::slot(slot), value));
+                Place::slot(slot)
+            }
+        };
+        self.emit(Op::Ref(place, mutable), Ty::Ref { ty: Box::new(ty), mutable })
+    }
+
+    /// Read through references, down to what they point to; strings stay as they are
+    fn peel(&mut self, mut value: Value) -> Value {
+        loop {
+            let ty = self.value_ty(value);
+            if self.krate.is_str(&ty) || pointee(&ty, self.res).is_none() {
+                return value;
+            }
+            value = self.load(Place::value(value).project(Proj::Deref));
+        }
+    }
+
+    /// `value` where a `to` is wanted, reading through a reference checking looked through
+    fn coerce(&mut self, value: Value, to: &Ty) -> Value {
+        match self.value_ty(value) {
+            Ty::Ref { ty: inner, .. } if *inner == *to => self.load(Place::value(value).project(Proj::Deref)),
+            _ => value,
+        }
+    }
+
+    /// The value of `expr` where a `to` is wanted, borrowing or reading through a reference as checking allowed
+    fn arg(&mut self, expr: &Expression, to: &Ty) -> Option<Value> {
+        let from = self.ty(expr.id);
+        if let Ty::Ref { ty: inner, mutable } = to {
+            if **inner == from {
+                return self.borrow(expr, *mutable);
+            }
+        }
+        let value = self.expr(expr)?;
+        Some(self.coerce(value, to))
+    }
+
+    fn args(&mut self, exprs: &[Expression], tys: &[Ty]) -> Option<Vec<Value>> {
+        let mut values = Vec::new();
+        for (i, expr) in exprs.iter().enumerate() {
+            let ty = tys.get(i).cloned().unwrap_or_else(|| self.ty(expr.id));
+            values.push(self.arg(expr, &ty)?);
+        }
+        Some(values)
+    }
+
+    // ========== Calls ==========
+
+    /// A call's value, or `None` after a call that does not return
+    fn call_value(&mut self, callee: Callee, args: Vec<Value>, ty: &Ty) -> Option<Value> {
+        let value = self.emit(Op::Call(callee, args), ty.clone());
+        if *ty == Ty::Never {
+            self.terminate(Terminator::Unreachable);
+            return None;
+        }
+        Some(value)
+    }
+
+    fn call(&mut self, func: &Expression, args: &[Expression], ty: &Ty) -> Option<Value> {
+        match self.krate.callee(func) {
+            mono::Callee::Fn(def) => {
+                let args: Vec<&Expression> = args.iter().collect();
+                return self.call_fn(def, &args, ty);
+            }
+            mono::Callee::Ctor(def, index) => {
+                let tys: Vec<Ty> = self.krate.fields_of(ty, index).into_iter().map(|(_, ty)| ty).collect();
+                let values = self.args(args, &tys)?;
+                return Some(self.emit(Op::Construct(def, values), ty.clone()));
+            }
+            mono::Callee::Prelude(name) => return self.prelude_call(&name, args, ty),
+            mono::Callee::Library(path) => return self.library_call(path, args, ty),
+            mono::Callee::Value => {}
+        }
+        // A function value
+        let callee = self.expr(func)?;
+        let params = match self.ty(func.id) {
+            Ty::Fn(params, _) => params,
+            _ => Vec::new(),
+        };
+        let values = self.args(args, &params)?;
+        self.call_value(Callee::Value(callee), values, ty)
+    }
+
+    /// Call the instance of `def` the argument types select
+    fn call_fn(&mut self, def: DefId, args: &[&Expression], ty: &Ty) -> Option<Value> {
+        let inputs: Vec<Ty> = args.iter().map(|arg| self.ty(arg.id)).collect();
+        let Some(instance) = self.krate.instance(def, &inputs, ty) else {
+            let name = self.res.def(def).name.clone();
+            self.unsupported(format!("`{}` without a body for this type", name), self.frame.span);
+            return Some(self.placeholder(ty));
+        };
+        let id = self.instance(instance.def, instance.args);
+        let mut values = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            let to = instance.params.get(i).cloned().unwrap_or_else(|| self.ty(arg.id));
+            values.push(self.arg(arg, &to)?);
+        }
+        self.call_value(Callee::Fn(id), values, ty)
+    }
+
+    fn method_call(
+        &mut self,
+        expr: &Expression,
+        receiver: &Expression,
+        method: &str,
+        args: &[Expression],
+        ty: &Ty,
+    ) -> Option<Value> {
+        if let Some(def) = self.krate.user_method(expr) {
+            let mut all = vec![receiver];
+            all.extend(args);
+            let inputs: Vec<Ty> = all.iter().map(|arg| self.ty(arg.id)).collect();
+            if self.krate.resolve(def, &inputs, ty).is_some() {
+                return self.call_fn(def, &all, ty);
+            }
+        }
+        self.library_method(receiver, method, args, ty)
+    }
+
+    /// A method of a library type, called by its path, such as `Vec::push`.
+    /// Methods that change the receiver get a `&mut` to it; the rest get its value.
+    fn library_method(&mut self, receiver: &Expression, method: &str, args: &[Expression], ty: &Ty) -> Option<Value> {
+        let owner = self.krate.owner(&self.ty(receiver.id));
+        let recv = match method {
+            _ if MUTATING.contains(&method) => self.receiver_pointer(receiver)?,
+            _ if CONSUMING.contains(&method) => {
+                let value = self.expr(receiver)?;
+                self.peel(value)
+            }
+            _ => {
+                let value = self.read(receiver)?;
+                self.peel(value)
+            }
+        };
+        // The library takes over an affine receiver, as `handle.close()` does, so its owner no longer drops it
+        if let Some(def) = self.owner_root(receiver).filter(|_| self.holds_affine(receiver)) {
+            self.consume(def);
+        }
+        let mut values = vec![recv];
+        for arg in args {
+            values.push(self.expr(arg)?);
+        }
+        self.call_value(Callee::Library(format!("{}::{}", owner, method)), values, ty)
+    }
+
+    /// Whether `expr` is an affine value or a field declared `affine`
+    fn holds_affine(&self, expr: &Expression) -> bool {
+        if self.is_affine(&self.ty(expr.id)) {
+            return true;
+        }
+        let ExpressionKind::Field { expr: base, field } = &expr.kind else { return false };
+        match self.krate.peel(&self.ty(base.id)) {
+            Ty::Adt(def, _) => self.affine.contains(&(def, field.clone())),
+            _ => false,
+        }
+    }
+
+    /// A `&mut` to the receiver of a method that changes it
+    fn receiver_pointer(&mut self, receiver: &Expression) -> Option<Value> {
+        if pointee(&self.ty(receiver.id), self.res).is_none() {
+            return self.borrow(receiver, true);
+        }
+        let mut value = self.read(receiver)?;
+        while let Some(inner) = pointee(&self.value_ty(value), self.res) {
+            if pointee(&inner, self.res).is_none() {
+                break;
+            }
+            value = self.load(Place::value(value).project(Proj::Deref));
+        }
+        Some(value)
+    }
+
+    /// Functions of the prelude read their arguments in place; `drop` ends the life of its argument
+    fn prelude_call(&mut self, name: &str, args: &[Expression], ty: &Ty) -> Option<Value> {
+        if let ("drop", [arg]) = (name, args) {
+            let root = self.owner_root(arg);
+            let place = self.operand_place(arg)?;
+            if let Some(def) = root {
+                self.consume(def);
+            }
+            self.emit_void(Op::Drop(place));
+            return Some(self.frame.unit);
+        }
+        let mut values = Vec::new();
+        for arg in args {
+            values.push(self.read(arg)?);
+        }
+        self.call_value(Callee::Library(name.to_string()), values, ty)
+    }
+
+    /// Library functions named by path, such as `String::from` or `process::exit`
+    fn library_call(&mut self, path: String, args: &[Expression], ty: &Ty) -> Option<Value> {
+        if let LibraryCall::Prelude(name) = mono::library_call(&path, args) {
+            return self.prelude_call(name, args, ty);
+        }
+        let mut values = Vec::new();
+        for arg in args {
+            values.push(self.expr(arg)?);
+        }
+        self.call_value(Callee::Library(path), values, ty)
+    }
+
+    // ========== Control flow ==========
+
+    fn if_expr(
+        &mut self,
+        cond: &Expression,
+        then_block: &ast::Block,
+        else_block: Option<&ast::Block>,
+        ty: &Ty,
+    ) -> Option<Value> {
+        let c = self.expr(cond)?;
+        let c = self.peel(c);
+        let (then, other, join) = (self.new_block(), self.new_block(), self.new_block());
+        let result = self.value_param(join, ty);
+        self.branch(c, then, other);
+        self.seal(then);
+        self.seal(other);
+        self.frame.current = Some(then);
+        if let Some(value) = self.block(then_block) {
+            self.join(join, result, value, ty);
+        }
+        self.frame.current = Some(other);
+        let value = match else_block {
+            Some(block) => self.block(block),
+            None => Some(self.frame.unit),
+        };
+        if let Some(value) = value {
+            self.join(join, result, value, ty);
+        }
+        self.seal(join);
+        self.enter(join, result)
+    }
+
+    /// Arms are tried in turn: each tests its pattern and guard, going on to
+    /// the next arm when they fail. Checking made the match exhaustive, so
+    /// failing the last arm is unreachable.
+    fn match_expr(&mut self, scrutinee: &Expression, arms: &[ast::MatchArm], ty: &Ty) -> Option<Value> {
+        let scrutinee_ty = self.ty(scrutinee.id);
+        let root = self.owner_root(scrutinee);
+        let place = self.operand_place(scrutinee)?;
+        let join = self.new_block();
+        let result = self.value_param(join, ty);
+        let mut fail: Option<Block> = None;
+        for arm in arms {
+            if let Some(block) = fail {
+                self.seal(block);
+                self.frame.current = Some(block);
+            }
+            let next = self.new_block();
+            self.frame.span = arm.span;
+            self.pattern(&arm.pattern, place.clone(), &scrutinee_ty, Walk::Test(next));
+            self.frame.scopes.push(Vec::new());
+            self.pattern(&arm.pattern, place.clone(), &scrutinee_ty, Walk::Bind(root));
+            if let Some(guard) = &arm.guard {
+                if let Some(g) = self.expr(guard) {
+                    let g = self.peel(g);
+                    let body = self.new_block();
+                    self.branch(g, body, next);
+                    self.seal(body);
+                    self.frame.current = Some(body);
+                }
+            }
+            let value = self.expr(&arm.body);
+            let scope = self.frame.scopes.pop().unwrap_or_default();
+            if let Some(value) = value {
+                for def in scope.into_iter().rev() {
+                    self.drop_owned(def);
+                }
+                self.join(join, result, value, ty);
+            }
+            fail = Some(next);
+        }
+        if let Some(block) = fail {
+            self.seal(block);
+            self.frame.current = Some(block);
+        }
+        self.terminate(Terminator::Unreachable);
+        self.seal(join);
+        self.enter(join, result)
+    }
+
+    /// Test or bind `pattern` against the value of type `ty` at `place`
+    fn pattern(&mut self, pattern: &ast::Pattern, place: Place, ty: &Ty, walk: Walk) {
+        if let PatternKind::Identifier(name) = &pattern.kind {
+            if let Some(def) = self.res.def_of_node(pattern.id) {
+                let Walk::Bind(root) = walk else { return };
+                // Bound by value, or borrowed where matching looked through a reference
+                let bind_ty = self.ty(pattern.id);
+                let value = match &bind_ty {
+                    Ty::Ref { ty: inner, mutable } if **inner == *ty => self.address(place, *mutable),
+                    _ => {
+                        if let Some(root) = root.filter(|_| self.is_affine(&bind_ty)) {
+                            self.consume(root);
+                        }
+                        let value = self.load(place);
+                        self.coerce(value, &bind_ty)
+                    }
+                };
+                self.bind(def, name, value, &bind_ty, false);
+                return;
+            }
+        }
+        // Everything else looks through references
+        let (mut place, mut ty) = (place, ty.clone());
+        while let Some(inner) = pointee(&ty, self.res) {
+            place = place.project(Proj::Deref);
+            ty = inner;
+        }
+        let variant = |builder: &Self| match builder.res.res(pattern.id) {
+            Some(Res::Def(def)) => builder.krate.variant_of(*def),
+            _ => None,
+        };
+        match &pattern.kind {
+            PatternKind::Wildcard => {}
+            PatternKind::Literal(lit) => {
+                let Walk::Test(fail) = walk else { return };
+                let value = self.load(place);
+                let value = self.peel(value);
+                let expected = self.literal(lit, &ty);
+                let cond = self.emit(Op::Binary(BinaryOp::Eq, value, expected), Ty::bool());
+                self.test(cond, fail);
+            }
+            PatternKind::Identifier(_) | PatternKind::Path(_) => {
+                let (Walk::Test(fail), Some(Res::Def(def))) = (walk, self.res.res(pattern.id)) else { return };
+                match self.res.def(*def).kind {
+                    DefKind::Variant => self.variant_test(*def, place, &ty, fail),
+                    DefKind::Const => {
+                        let value = self.load(place);
+                        if let Some(expected) = self.constant(*def, pattern.span) {
+                            let cond = self.emit(Op::Binary(BinaryOp::Eq, value, expected), Ty::bool());
+                            self.test(cond, fail);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            PatternKind::Tuple(elems) => {
+                let elem_tys = match &ty {
+                    Ty::Tuple(elem_tys) => elem_tys.clone(),
+                    _ => Vec::new(),
+                };
+                for (index, (elem, elem_ty)) in elems.iter().zip(&elem_tys).enumerate() {
+                    let place = place.clone().project(Proj::Field { variant: 0, index });
+                    self.pattern(elem, place, elem_ty, walk);
+                }
+            }
+            PatternKind::TupleStruct { elems, .. } => {
+                let Some((_, variant)) = variant(self) else { return };
+                if let (Walk::Test(fail), Some(Res::Def(def))) = (walk, self.res.res(pattern.id)) {
+                    self.variant_test(*def, place.clone(), &ty, fail);
+                }
+                for (index, (elem, (_, field_ty))) in elems.iter().zip(self.krate.fields_of(&ty, variant)).enumerate() {
+                    let place = place.clone().project(Proj::Field { variant, index });
+                    self.pattern(elem, place, &field_ty, walk);
+                }
+            }
+            PatternKind::Struct { fields, .. } => {
+                let Some((_, variant)) = variant(self) else { return };
+                if let (Walk::Test(fail), Some(Res::Def(def))) = (walk, self.res.res(pattern.id)) {
+                    self.variant_test(*def, place.clone(), &ty, fail);
+                }
+                let declared = self.krate.fields_of(&ty, variant);
+                for (name, sub) in fields {
+                    let Some(index) = declared.iter().position(|(field, _)| field == name) else { continue };
+                    let place = place.clone().project(Proj::Field { variant, index });
+                    self.pattern(sub, place, &declared[index].1, walk);
+                }
+            }
+        }
+    }
+
+    /// Go on where `cond` holds, or else to `fail`
+    fn test(&mut self, cond: Value, fail: Block) {
+        let next = self.new_block();
+        self.branch(cond, next, fail);
+        self.seal(next);
+        self.frame.current = Some(next);
+    }
+
+    fn variant_test(&mut self, def: DefId, place: Place, ty: &Ty, fail: Block) {
+        let (true, Some((_, index))) = (self.krate.is_enum(ty), self.krate.variant_of(def)) else { return };
+        let usize = Ty::Prim(PrimitiveType::Usize);
+        let tag = self.emit(Op::Tag(place), usize.clone());
+        let expected = self.emit(Op::Const(Const::Int(index as i128)), usize);
+        let cond = self.emit(Op::Binary(BinaryOp::Eq, tag, expected), Ty::bool());
+        self.test(cond, fail);
+    }
+
+    fn loop_expr(&mut self, body: &ast::Block, ty: &Ty) -> Option<Value> {
+        let (header, exit) = (self.new_block(), self.new_block());
+        let result = self.value_param(exit, ty);
+        self.jump(header, Vec::new());
+        let depth = self.frame.scopes.len();
+        self.frame.loops.push(Loop { next: header, exit, value: result.is_some(), depth });
+        self.frame.current = Some(header);
+        if self.block(body).is_some() {
+            self.jump(header, Vec::new());
+        }
+        self.frame.loops.pop();
+        self.seal(header);
+        self.seal(exit);
+        self.enter(exit, result)
+    }
+
+    fn while_loop(&mut self, cond: &Expression, body: &ast::Block) -> Option<Value> {
+        let (header, inner, exit) = (self.new_block(), self.new_block(), self.new_block());
+        self.jump(header, Vec::new());
+        self.frame.current = Some(header);
+        let depth = self.frame.scopes.len();
+        self.frame.loops.push(Loop { next: header, exit, value: false, depth });
+        if let Some(c) = self.expr(cond) {
+            let c = self.peel(c);
+            self.branch(c, inner, exit);
+            self.seal(inner);
+            self.frame.current = Some(inner);
+            if self.block(body).is_some() {
+                self.jump(header, Vec::new());
+            }
+        }
+        self.frame.loops.pop();
+        self.seal(header);
+        self.seal(exit);
+        self.enter(exit, None)
+    }
+
+    /// `for` over a vector, an array or the characters of a string counts
+    /// through its elements, reading each where it lives
+    fn for_loop(&mut self, pattern: &ast::Pattern, iter: &Expression, body: &ast::Block) -> Option<Value> {
+        let usize = Ty::Prim(PrimitiveType::Usize);
+        let (sequence, elem) = match &iter.kind {
+            ExpressionKind::MethodCall { receiver, method, args } if args.is_empty() && method == "chars" => {
+                let text = self.read(receiver)?;
+                let text = self.peel(text);
+                let char = Ty::Prim(PrimitiveType::Char);
+                let chars = self.krate.prelude_ty("Vec", vec![char.clone()]);
+                let chars = self.emit(Op::Call(Callee::Library("str::chars".to_string()), vec![text]), chars);
+                (Place::value(chars), char)
+            }
+            _ => {
+                let source = match &iter.kind {
+                    ExpressionKind::MethodCall { receiver, method, args }
+                        if args.is_empty() && matches!(method.as_str(), "iter" | "iter_mut" | "into_iter") =>
+                    {
+                        receiver
+                    }
+                    ExpressionKind::Unary { op: UnaryOp::Ref | UnaryOp::RefMut, expr: inner } => inner,
+                    _ => iter,
+                };
+                let sequence = self.base_place(source)?;
+                let sequence_ty = self.place_ty(&sequence);
+                let Some(elem) = element(&sequence_ty, self.res) else {
+                    let source_ty = self.ty(source.id);
+                    self.unsupported(format!("a `for` loop over `{}`", source_ty.display(self.res)), iter.span);
+                    return Some(self.frame.unit);
+                };
+                (sequence, elem)
+            }
+        };
+        let len = match self.place_ty(&sequence) {
+            Ty::Array(_, Some(len)) => self.emit(Op::Const(Const::Int(len as i128)), usize.clone()),
+            _ => {
+                let value = self.load(sequence.clone());
+                self.emit(Op::Call(Callee::Library("Vec::len".to_string()), vec![value]), usize.clone())
+            }
+        };
+        let counter = self.var(usize.clone());
+        let zero = self.emit(Op::Const(Const::Int(0)), usize.clone());
+        self.def_var(counter, zero);
+        let (header, inner, latch, exit) = (self.new_block(), self.new_block(), self.new_block(), self.new_block());
+        self.jump(header, Vec::new());
+        self.frame.current = Some(header);
+        let index = self.use_var(counter);
+        let cond = self.emit(Op::Binary(BinaryOp::Lt, index, len), Ty::bool());
+        self.branch(cond, inner, exit);
+        self.seal(inner);
+        self.frame.current = Some(inner);
+        let depth = self.frame.scopes.len();
+        self.frame.loops.push(Loop { next: latch, exit, value: false, depth });
+        self.frame.scopes.push(Vec::new());
+        self.pattern(pattern, sequence.project(Proj::Index(index)), &elem, Walk::Bind(None));
+        let done = self.block(body).is_some();
+        let scope = self.frame.scopes.pop().unwrap_or_default();
+        if done {
+            for def in scope.into_iter().rev() {
+                self.drop_owned(def);
+            }
+            self.jump(latch, Vec::new());
+        }
+        self.frame.loops.pop();
+        self.seal(latch);
+        if !self.frame.preds[latch.0 as usize].is_empty() {
+            self.frame.current = Some(latch);
+            let index = self.use_var(counter);
+            let one = self.emit(Op::Const(Const::Int(1)), usize.clone());
+            let next = self.emit(Op::Binary(BinaryOp::Add, index, one), usize);
+            self.def_var(counter, next);
+            self.jump(header, Vec::new());
+        }
+        self.seal(header);
+        self.seal(exit);
+        self.enter(exit, None)
+    }
+}
@@ -0,0 +1,446 @@
+// IR verifier
+// Checks the invariants the backends rely on: each value is defined once and
+// before every use along every path, branches pass their targets the
+// parameters they take, and instructions get operands of the types they work
+// on. Types that checking left open, such as those of code already reported
+// as an error, are taken to fit anything.
+
+use super::{element, pointee, Base, Block, Callee, Function, Module, Op, Place, Proj, Target, Terminator, Value};
+use my_lang_ast::{BinaryOp, PrimitiveType, UnaryOp};
+use my_lang_resolve::{DefId, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults};
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("in @{function}: {message}")]
+pub struct VerifyError {
+    pub function: String,
+    pub message: String,
+}
+
+pub(super) fn verify(module: &Module, res: &Resolution, types: &TypeckResults) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::new();
+    if let Some(entry) = module.entry.filter(|id| id.0 as usize >= module.functions.len()) {
+        let message = format!("the entry is function {}, which does not exist", entry.0);
+        errors.push(VerifyError { function: String::new(), message });
+    }
+    for func in &module.functions {
+        let mut verifier = Verifier { module, res, types, func, defs: HashMap::new(), errors: Vec::new() };
+        verifier.function();
+        errors.extend(verifier.errors.into_iter().map(|message| VerifyError { function: func.name.clone(), message }));
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+/// Where a value is defined: its block, and `0` for a parameter or one past the index of its instruction
+type Def = (Block, usize);
+
+struct Verifier<'a> {
+    module: &'a Module,
+    res: &'a Resolution,
+    types: &'a TypeckResults,
+    func: &'a Function,
+    defs: HashMap<Value, Def>,
+    errors: Vec<String>,
+}
+
+impl Verifier<'_> {
+    fn error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    fn function(&mut self) {
+        let func = self.func;
+        if func.blocks.is_empty() {
+            return self.error("has no blocks".to_string());
+        }
+        if !func.blocks[0].params.is_empty() {
+            let declared = self.types.sigs.get(&func.def).map(|sig| sig.inputs.len());
+            if declared.is_some_and(|n| n != func.blocks[0].params.len()) {
+                self.error("the entry takes a different number of parameters than the function".to_string());
+            }
+        }
+
+        // Definitions
+        for (b, data) in func.blocks.iter().enumerate() {
+            let block = Block(b as u32);
+            let results = data.insts.iter().enumerate().filter_map(|(i, inst)| inst.result.map(|v| (v, i + 1)));
+            for (value, position) in data.params.iter().map(|v| (*v, 0)).chain(results) {
+                if value.0 as usize >= func.values.len() {
+                    self.error(format!("v{} is out of range", value.0));
+                } else if self.defs.insert(value, (block, position)).is_some() {
+                    self.error(format!("v{} is defined more than once", value.0));
+                }
+            }
+        }
+
+        // Uses, control flow and types
+        let dominators = dominators(func);
+        for (b, data) in func.blocks.iter().enumerate() {
+            let block = Block(b as u32);
+            for (i, inst) in data.insts.iter().enumerate() {
+                for value in inst.op.values() {
+                    self.used(value, (block, i + 1), &dominators);
+                }
+                self.inst(inst.result, &inst.op);
+            }
+            let end = (block, data.insts.len() + 1);
+            for value in data.term.values() {
+                self.used(value, end, &dominators);
+            }
+            self.terminator(block, &data.term);
+        }
+    }
+
+    /// A use of `value` at `at` must come after its definition, which must
+    /// be earlier in the same block or in a block dominating it
+    fn used(&mut self, value: Value, at: Def, dominators: &[HashSet<Block>]) {
+        let Some(&(block, position)) = self.defs.get(&value) else {
+            return self.error(format!("v{} is used in bb{} but never defined", value.0, at.0 .0));
+        };
+        let before = match block == at.0 {
+            true => position < at.1,
+            false => dominators[at.0 .0 as usize].contains(&block),
+        };
+        if !before {
+            self.error(format!("v{} is used in bb{} where its definition does not dominate", value.0, at.0 .0));
+        }
+    }
+
+    fn ty(&self, value: Value) -> Ty {
+        self.func.values.get(value.0 as usize).cloned().unwrap_or(Ty::Error)
+    }
+
+    fn expect(&mut self, value: Value, expected: &Ty, what: &str) {
+        let actual = self.ty(value);
+        if !fits(&actual, expected, self.res) {
+            self.error(format!(
+                "{} v{} is `{}` where `{}` is expected",
+                what,
+                value.0,
+                actual.display(self.res),
+                expected.display(self.res)
+            ));
+        }
+    }
+
+    fn target(&mut self, from: Block, target: &Target) {
+        let Some(data) = self.func.blocks.get(target.block.0 as usize) else {
+            return self.error(format!("bb{} jumps to bb{}, which does not exist", from.0, target.block.0));
+        };
+        if data.params.len() != target.args.len() {
+            return self.error(format!(
+                "bb{} passes {} arguments to bb{}, which takes {}",
+                from.0,
+                target.args.len(),
+                target.block.0,
+                data.params.len()
+            ));
+        }
+        for (arg, param) in target.args.iter().zip(&data.params) {
+            let expected = self.ty(*param);
+            self.expect(*arg, &expected, "argument");
+        }
+    }
+
+    fn terminator(&mut self, block: Block, term: &Terminator) {
+        for target in term.targets() {
+            self.target(block, target);
+        }
+        match term {
+            Terminator::Branch { cond, .. } => self.expect(*cond, &Ty::bool(), "condition"),
+            Terminator::Return(value) => self.expect(*value, &self.func.ret.clone(), "returned value"),
+            Terminator::Jump(_) | Terminator::Unreachable => {}
+        }
+    }
+
+    /// The type of the value at `place`, once its projections are checked
+    fn place(&mut self, place: &Place) -> Option<Ty> {
+        let mut ty = match place.base {
+            Base::Slot(slot) => match self.func.slots.get(slot.0 as usize) {
+                Some(data) => data.ty.clone(),
+                None => {
+                    self.error(format!("slot s{} does not exist", slot.0));
+                    return None;
+                }
+            },
+            Base::Value(value) => self.ty(value),
+        };
+        for proj in &place.proj {
+            if is_open(&ty) {
+                return Some(Ty::Error);
+            }
+            let next = match proj {
+                Proj::Deref => pointee(&ty, self.res),
+                Proj::Field { variant, index } => super::field(&ty, *variant, *index, self.types).map(|(_, ty)| ty),
+                Proj::Index(index) => {
+                    let index_ty = self.ty(*index);
+                    if !index_ty.is_integral() && !is_open(&index_ty) {
+                        self.error(format!("index v{} is `{}`, not an integer", index.0, index_ty.display(self.res)));
+                    }
+                    element(&ty, self.res)
+                }
+            };
+            let Some(next) = next else {
+                self.error(format!("{:?} does not apply to `{}`", proj, ty.display(self.res)));
+                return None;
+            };
+            ty = next;
+        }
+        Some(ty)
+    }
+
+    fn inst(&mut self, result: Option<Value>, op: &Op) {
+        let has_result = !matches!(op, Op::Store(..) | Op::Drop(_) | Op::Check(_));
+        match (result, has_result) {
+            (Some(value), false) => {
+                return self.error(format!("v{} is the result of an instruction without one", value.0))
+            }
+            (None, true) => return self.error("an instruction that has a result is missing it".to_string()),
+            _ => {}
+        }
+        let ty = result.map_or(Ty::unit(), |value| self.ty(value));
+        match op {
+            Op::Const(_) => {}
+            Op::Unary(UnaryOp::Neg | UnaryOp::Not, operand) => self.expect(*operand, &ty, "operand"),
+            Op::Unary(unary, _) => self.error(format!("`{:?}` is a place operation, not a unary instruction", unary)),
+            Op::Binary(binary, left, right) => self.binary(binary, *left, *right, &ty),
+            Op::Call(callee, args) => self.call(callee, args, &ty),
+            Op::Func(id) => match self.module.functions.get(id.0 as usize) {
+                Some(func) => {
+                    let expected = Ty::Fn(func.params(), Box::new(func.ret.clone()));
+                    if let Some(value) = result {
+                        self.expect(value, &expected, "function value");
+                    }
+                }
+                None => self.error(format!("function {} does not exist", id.0)),
+            },
+            Op::Tuple(values) => match &ty {
+                Ty::Tuple(elems) if elems.len() == values.len() => {
+                    for (value, elem) in values.iter().zip(elems) {
+                        self.expect(*value, elem, "element");
+                    }
+                }
+                _ if is_open(&ty) => {}
+                _ => self.error(format!("a tuple of {} is typed `{}`", values.len(), ty.display(self.res))),
+            },
+            Op::Array(values) => match element(&ty, self.res) {
+                Some(elem) => values.iter().for_each(|value| self.expect(*value, &elem, "element")),
+                None if is_open(&ty) => {}
+                None => self.error(format!("an array is typed `{}`", ty.display(self.res))),
+            },
+            Op::Construct(def, values) => self.construct(*def, values, &ty),
+            Op::Tag(place) => {
+                let Some(place_ty) = self.place(place) else { return };
+                let is_enum = match &place_ty {
+                    Ty::Adt(def, _) => self.types.adts.get(def).is_some_and(|adt| adt.variants.len() > 1),
+                    _ => is_open(&place_ty),
+                };
+                if !is_enum {
+                    self.error(format!("the tag of `{}`, which is not an enum", place_ty.display(self.res)));
+                }
+                if !fits(&ty, &Ty::Prim(PrimitiveType::Usize), self.res) {
+                    self.error("a tag is not a `usize`".to_string());
+                }
+            }
+            Op::Load(place) => {
+                if let (Some(place_ty), Some(value)) = (self.place(place), result) {
+                    self.expect(value, &place_ty, "loaded value");
+                }
+            }
+            Op::Store(place, value) => {
+                if !place.is_addressable() {
+                    self.error("a store to a place without an address".to_string());
+                }
+                if let Some(place_ty) = self.place(place) {
+                    self.expect(*value, &place_ty, "stored value");
+                }
+            }
+            Op::Ref(place, mutable) => {
+                if !place.is_addressable() {
+                    self.error("a reference to a place without an address".to_string());
+                }
+                if let (Some(place_ty), Some(value)) = (self.place(place), result) {
+                    let expected = Ty::Ref { ty: Box::new(place_ty), mutable: *mutable };
+                    self.expect(value, &expected, "reference");
+                }
+            }
+            Op::Drop(place) => {
+                self.place(place);
+            }
+            Op::Check(check) => self.expect(check.cond, &Ty::bool(), "condition"),
+        }
+    }
+
+    fn binary(&mut self, op: &BinaryOp, left: Value, right: Value, ty: &Ty) {
+        let left_ty = self.ty(left);
+        match op {
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => {
+                self.expect(right, &left_ty, "operand");
+                if !fits(ty, &Ty::bool(), self.res) {
+                    self.error("a comparison is not a `bool`".to_string());
+                }
+            }
+            BinaryOp::And | BinaryOp::Or | BinaryOp::Assign => {
+                self.error(format!("`{:?}` is control flow or a store, not a binary instruction", op));
+            }
+            // Shift amounts may be of any integer type, and strings are concatenated with anything
+            BinaryOp::Shl | BinaryOp::Shr => self.expect(left, ty, "operand"),
+            BinaryOp::Add if is_str(ty, self.res) => self.expect(left, ty, "operand"),
+            _ => {
+                self.expect(left, ty, "operand");
+                self.expect(right, ty, "operand");
+            }
+        }
+    }
+
+    fn call(&mut self, callee: &Callee, args: &[Value], ty: &Ty) {
+        match callee {
+            Callee::Fn(id) => {
+                let Some(func) = self.module.functions.get(id.0 as usize) else {
+                    return self.error(format!("function {} does not exist", id.0));
+                };
+                let params = func.params();
+                if params.len() != args.len() && !func.blocks.is_empty() {
+                    return self.error(format!(
+                        "@{} takes {} arguments but is given {}",
+                        func.name,
+                        params.len(),
+                        args.len()
+                    ));
+                }
+                for (arg, param) in args.iter().zip(&params) {
+                    self.expect(*arg, param, "argument");
+                }
+                if !fits(&func.ret, ty, self.res) {
+                    self.error(format!(
+                        "@{} returns `{}` but its call is typed `{}`",
+                        func.name,
+                        func.ret.display(self.res),
+                        ty.display(self.res)
+                    ));
+                }
+            }
+            Callee::Value(value) => match self.ty(*value) {
+                Ty::Fn(params, _) if params.len() != args.len() => {
+                    self.error(format!("v{} takes {} arguments but is given {}", value.0, params.len(), args.len()));
+                }
+                Ty::Fn(params, _) => {
+                    for (arg, param) in args.iter().zip(&params) {
+                        self.expect(*arg, param, "argument");
+                    }
+                }
+                callee_ty if is_open(&callee_ty) => {}
+                callee_ty => self.error(format!("v{} of type `{}` is called", value.0, callee_ty.display(self.res))),
+            },
+            // The library's functions are typed by the programs calling them
+            Callee::Library(_) => {}
+        }
+    }
+
+    fn construct(&mut self, def: DefId, values: &[Value], ty: &Ty) {
+        let Ty::Adt(adt_def, args) = ty else {
+            if !is_open(ty) {
+                self.error(format!("`{}` is constructed as `{}`", self.res.def(def).name, ty.display(self.res)));
+            }
+            return;
+        };
+        let Some(adt) = self.types.adts.get(adt_def) else { return };
+        let Some(variant) =
+            adt.variants.iter().position(|v| v.def == def || (*adt_def == def && adt.variants.len() == 1))
+        else {
+            return self.error(format!("`{}` is not a variant of `{}`", self.res.def(def).name, ty.display(self.res)));
+        };
+        let fields = &adt.variants[variant].fields;
+        if fields.len() != values.len() {
+            return self.error(format!(
+                "`{}` has {} fields but is given {}",
+                self.res.def(def).name,
+                fields.len(),
+                values.len()
+            ));
+        }
+        let map = adt.generics.iter().copied().zip(args.iter().cloned()).collect();
+        for (value, field) in values.iter().zip(fields) {
+            self.expect(*value, &field.ty.subst(&map), "field");
+        }
+    }
+}
+
+/// The blocks dominating each block, itself included. Blocks the entry does
+/// not reach are dominated by every block, so nothing in them is checked for it.
+fn dominators(func: &Function) -> Vec<HashSet<Block>> {
+    let all: HashSet<Block> = (0..func.blocks.len() as u32).map(Block).collect();
+    let mut preds: Vec<Vec<Block>> = vec![Vec::new(); func.blocks.len()];
+    for (b, data) in func.blocks.iter().enumerate() {
+        for target in data.term.targets() {
+            if let Some(list) = preds.get_mut(target.block.0 as usize) {
+                list.push(Block(b as u32));
+            }
+        }
+    }
+    let mut doms = vec![all; func.blocks.len()];
+    doms[0] = HashSet::from([Block(0)]);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in 1..func.blocks.len() {
+            let mut next: Option<HashSet<Block>> = None;
+            for pred in &preds[b] {
+                let pred = &doms[pred.0 as usize];
+                next = Some(match next {
+                    Some(set) => set.intersection(pred).copied().collect(),
+                    None => pred.clone(),
+                });
+            }
+            let Some(mut next) = next else { continue };
+            next.insert(Block(b as u32));
+            if next != doms[b] {
+                doms[b] = next;
+                changed = true;
+            }
+        }
+    }
+    doms
+}
+
+/// Whether checking left part of `ty` open, so that it fits anything
+fn is_open(ty: &Ty) -> bool {
+    let mut open = false;
+    ty.walk(&mut |ty| open |= matches!(ty, Ty::Error | Ty::Infer(_) | Ty::Param(_) | Ty::Assoc { .. }));
+    open
+}
+
+fn is_str(ty: &Ty, res: &Resolution) -> bool {
+    match ty {
+        Ty::Prim(PrimitiveType::Str) => true,
+        Ty::Ref { ty, mutable: false } => is_str(ty, res),
+        Ty::Adt(def, _) => res.def(*def).is_prelude() && res.def(*def).name == "String",
+        _ => false,
+    }
+}
+
+/// Whether a value of type `actual` may be used where `expected` is
+fn fits(actual: &Ty, expected: &Ty, res: &Resolution) -> bool {
+    if actual == expected || *actual == Ty::Never || is_open(actual) || is_open(expected) {
+        return true;
+    }
+    match (actual, expected) {
+        _ if is_str(actual, res) && is_str(expected, res) => true,
+        (Ty::Ref { ty: a, mutable: true }, Ty::Ref { ty: e, mutable: false }) => fits(a, e, res),
+        (Ty::Ref { ty: a, mutable: m }, Ty::Ref { ty: e, mutable: n }) if m == n => fits(a, e, res),
+        (Ty::Tuple(a), Ty::Tuple(e)) => a.len() == e.len() && a.iter().zip(e).all(|(a, e)| fits(a, e, res)),
+        (Ty::Adt(a_def, a), Ty::Adt(e_def, e)) => {
+            a_def == e_def && a.len() == e.len() && a.iter().zip(e).all(|(a, e)| fits(a, e, res))
+        }
+        (Ty::Array(a, n), Ty::Array(e, m)) => (n == m || n.is_none() || m.is_none()) && fits(a, e, res),
+        (Ty::Fn(ap, ar), Ty::Fn(ep, er)) => {
+            ap.len() == ep.len() && ap.iter().zip(ep).all(|(a, e)| fits(a, e, res)) && fits(ar, er, res)
+        }
+        _ => false,
+    }
+}
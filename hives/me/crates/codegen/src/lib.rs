@@ -1 +1,19 @@
-// codegen stub - implementation pending
+// Code generation
+// Lowering passes between checking and the backends, the typed mid-level IR,
+// the bytecode the virtual machine runs, and the C, WebAssembly and native
+// backends
+
+pub mod bytecode;
+pub mod c;
+pub mod contracts;
+pub mod ir;
+pub mod lower;
+pub mod mono;
+pub mod native;
+mod template;
+#[cfg(test)]
+mod test_support;
+pub mod wasm;
+
+pub use contracts::{ContractLowering, ContractMode};
+pub use lower::{compile, lower_ty};
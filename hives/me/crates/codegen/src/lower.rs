@@ -0,0 +1,1004 @@
+// Lowering to bytecode
+// Compiles a checked crate into the register bytecode the virtual machine
+// runs. Evaluation order and the library calls made follow the interpreter,
+// so that a program behaves the same whichever runs it.
+
+use crate::bytecode::{
+    Const, Ctor, Function, Global, ImplMethod, Instr, Loc, MethodSite, Module, Pattern, Reg, TraitFn, Type, TypeKey,
+};
+use crate::mono::{self, Key, SelfTypes};
+use my_lang_ast as ast;
+use my_lang_ast::{
+    BinaryOp, Block, Expression, ExpressionKind, ImplItem, ItemKind, Literal, PatternKind, Span, StatementKind,
+    TraitItem, TypeKind, UnaryOp,
+};
+use my_lang_resolve::{DefId, DefKind, Res, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults};
+use std::collections::HashMap;
+
+impl From<Key> for TypeKey {
+    fn from(key: Key) -> Self {
+        match key {
+            Key::Def(def) => TypeKey::Def(def.0),
+            Key::Prim(prim) => TypeKey::Prim(prim),
+        }
+    }
+}
+
+/// The type `ty` as the backends see it
+pub fn lower_ty(ty: &Ty, res: &Resolution) -> Type {
+    match ty {
+        Ty::Prim(prim) => Type::Prim(*prim),
+        Ty::Tuple(elems) => Type::Tuple(elems.iter().map(|t| lower_ty(t, res)).collect()),
+        Ty::Array(elem, _) => Type::Array(Box::new(lower_ty(elem, res))),
+        Ty::Ref { ty, .. } => Type::Ref(Box::new(lower_ty(ty, res))),
+        Ty::Fn(..) => Type::Fn,
+        Ty::Adt(def, args) => Type::Adt {
+            def: def.0,
+            name: res.def(*def).name.clone(),
+            args: args.iter().map(|t| lower_ty(t, res)).collect(),
+        },
+        Ty::Param(_) | Ty::Assoc { .. } | Ty::Infer(_) | Ty::Never | Ty::Error => Type::Unknown,
+    }
+}
+
+/// Compile the crate whose `main` is in the first of `programs`. The
+/// caller fills in `files` with the names of the source files spans refer to.
+pub fn compile(programs: &[&ast::Program], res: &Resolution, types: &TypeckResults) -> Module {
+    let mut lowering = Lowering::new(res, types);
+    for program in programs {
+        lowering.collect(&program.items);
+    }
+    lowering.module.entry = programs.first().and_then(|program| {
+        program.items.iter().find_map(|item| match &item.kind {
+            ItemKind::Function(func) if func.name == "main" => {
+                res.def_of_node(func.id).and_then(|def| lowering.fns.get(&def)).map(|(index, _)| *index)
+            }
+            _ => None,
+        })
+    });
+    for index in 0..lowering.bodies.len() {
+        let func = lowering.body(index);
+        lowering.module.functions[index] = func;
+    }
+    lowering.module
+}
+
+/// Source of a function in the module
+#[derive(Clone, Copy)]
+enum Body<'a> {
+    Fn(&'a ast::Function),
+    /// The initializer of a constant item
+    Const(&'a ast::Const),
+}
+
+/// Jumps out of the innermost loop
+struct Loop {
+    /// Where a `loop` puts the value of `break`; `while` and `for` discard it
+    dst: Option<Reg>,
+    continue_to: u32,
+    breaks: Vec<usize>,
+}
+
+/// The function being compiled
+#[derive(Default)]
+struct Frame {
+    code: Vec<Instr>,
+    locs: Vec<Loc>,
+    locals: HashMap<DefId, Reg>,
+    /// First free register; temporaries are released by resetting it
+    next: Reg,
+    registers: Reg,
+    loops: Vec<Loop>,
+}
+
+struct Lowering<'a> {
+    res: &'a Resolution,
+    types: &'a TypeckResults,
+    module: Module,
+    /// Functions with bodies, by index in `module.functions`
+    bodies: Vec<Body<'a>>,
+    fns: HashMap<DefId, (u32, &'a ast::Function)>,
+    /// Constant items, by index in `module.globals`
+    consts: HashMap<DefId, u32>,
+    self_types: SelfTypes,
+    const_pool: HashMap<String, u32>,
+    type_pool: HashMap<Type, u32>,
+    ctor_pool: HashMap<(u32, Vec<String>), u32>,
+    frame: Frame,
+}
+
+impl<'a> Lowering<'a> {
+    fn new(res: &'a Resolution, types: &'a TypeckResults) -> Self {
+        let mut lowering = Self {
+            res,
+            types,
+            module: Module::default(),
+            bodies: Vec::new(),
+            fns: HashMap::new(),
+            consts: HashMap::new(),
+            self_types: SelfTypes::default(),
+            const_pool: HashMap::new(),
+            type_pool: HashMap::new(),
+            ctor_pool: HashMap::new(),
+            frame: Frame::default(),
+        };
+        lowering.module.library = mono::library_types(res).map(|(name, def)| (name.to_string(), def.0)).collect();
+        for definition in &res.defs {
+            if let Some(parent) = definition.parent.filter(|p| res.def(*p).kind == DefKind::Trait) {
+                if definition.kind == DefKind::Function {
+                    let name = definition.name.clone();
+                    lowering.module.trait_fns.push(TraitFn { def: definition.id.0, trait_def: parent.0, name });
+                }
+            }
+        }
+        // Library methods build these, so the machine needs to know them whether or not the crate names them
+        for (i, name) in ["Some", "None", "Ok", "Err"].into_iter().enumerate() {
+            let def = res.defs.iter().find(|d| d.is_prelude() && d.kind == DefKind::Variant && d.name == name);
+            if let Some(def) = def {
+                let fields = if name == "None" { Vec::new() } else { vec!["0".to_string()] };
+                lowering.ctor(def.id, fields);
+                lowering.module.variants[i] = def.id.0;
+            }
+        }
+        lowering
+    }
+
+    // ========== Items ==========
+
+    fn collect(&mut self, items: &'a [ast::Item]) {
+        for item in items {
+            match &item.kind {
+                ItemKind::Function(func) => self.add_fn(func),
+                ItemKind::Const(c) => self.add_const(c),
+                ItemKind::Impl(imp) => self.add_impl(imp),
+                ItemKind::Trait(t) => {
+                    for item in &t.items {
+                        match item {
+                            TraitItem::Function(func) => self.add_fn(func),
+                            TraitItem::Const(c) => self.add_const(c),
+                            _ => {}
+                        }
+                    }
+                }
+                ItemKind::Module(module) => self.collect(&module.items),
+                _ => {}
+            }
+        }
+    }
+
+    /// Reserve a slot in `module.functions`, filled in once every item is known
+    fn reserve(&mut self, body: Body<'a>) -> u32 {
+        let index = self.module.functions.len() as u32;
+        self.bodies.push(body);
+        self.module.functions.push(Function {
+            name: String::new(),
+            def: 0,
+            params: 0,
+            registers: 0,
+            captures: None,
+            self_ref: false,
+            code: Vec::new(),
+            locs: Vec::new(),
+            loc: Loc::default(),
+        });
+        index
+    }
+
+    fn add_fn(&mut self, func: &'a ast::Function) {
+        if let Some(def) = self.res.def_of_node(func.id) {
+            let index = self.reserve(Body::Fn(func));
+            self.fns.insert(def, (index, func));
+        }
+        // Items declared inside the body
+        for stmt in &func.body.stmts {
+            if let StatementKind::Item(item) = &stmt.kind {
+                self.collect(std::slice::from_ref(item));
+            }
+        }
+    }
+
+    fn add_const(&mut self, c: &'a ast::Const) {
+        if let Some(def) = self.res.def_of_node(c.id) {
+            let init = self.reserve(Body::Const(c));
+            self.consts.insert(def, self.module.globals.len() as u32);
+            self.module.globals.push(Global { def: def.0, name: c.name.clone(), init });
+        }
+    }
+
+    fn add_impl(&mut self, imp: &'a ast::Impl) {
+        let trait_def = match self.res.res(imp.id) {
+            Some(Res::Def(def)) => Some(def.0),
+            _ => None,
+        };
+        let key = self.self_types.add_impl(self.res, imp).map(TypeKey::from);
+        for item in &imp.items {
+            match item {
+                ImplItem::Function(func) => {
+                    self.add_fn(func);
+                    if let (Some(key), Some(def)) = (key, self.res.def_of_node(func.id)) {
+                        let method = ImplMethod { key, trait_def, name: func.name.clone(), def: def.0 };
+                        self.module.methods.push(method);
+                    }
+                }
+                ImplItem::Const(c) => self.add_const(c),
+                ImplItem::Type(_) => {}
+            }
+        }
+    }
+
+    fn find_method(&self, key: TypeKey, name: &str) -> Option<u32> {
+        self.module.methods.iter().find(|m| m.key == key && m.name == name).map(|m| m.def)
+    }
+
+    fn trait_of(&self, def: DefId) -> Option<DefId> {
+        self.res.def(def).parent.filter(|parent| self.res.def(*parent).kind == DefKind::Trait)
+    }
+
+    /// `Type::method` for functions of impls and traits, the bare name otherwise
+    fn fn_name(&self, def: DefId) -> String {
+        let definition = self.res.def(def);
+        match definition.parent.map(|p| self.res.def(p)) {
+            Some(parent) if matches!(parent.kind, DefKind::Struct | DefKind::Enum | DefKind::Trait) => {
+                format!("{}::{}", parent.name, definition.name)
+            }
+            _ => definition.name.clone(),
+        }
+    }
+
+    fn body(&mut self, index: usize) -> Function {
+        self.frame = Frame::default();
+        let (name, def, params, self_ref, span) = match self.bodies[index] {
+            Body::Fn(func) => {
+                let def = self.res.def_of_node(func.id).unwrap_or(DefId(0));
+                for param in &func.params {
+                    let reg = self.temp();
+                    if let Some(def) = self.res.def_of_node(param.id) {
+                        self.frame.locals.insert(def, reg);
+                    }
+                }
+                let self_ref = matches!(func.params.first().map(|p| &p.ty.kind), Some(TypeKind::Reference { .. }));
+                let dst = self.temp();
+                self.block(&func.body, dst);
+                self.emit(Instr::Return { src: dst }, func.body.span);
+                (self.fn_name(def), def, func.params.len() as u32, self_ref, func.span)
+            }
+            Body::Const(c) => {
+                let def = self.res.def_of_node(c.id).unwrap_or(DefId(0));
+                let dst = self.temp();
+                self.expr(&c.value, dst);
+                self.emit(Instr::Return { src: dst }, c.value.span);
+                (c.name.clone(), def, 0, false, c.span)
+            }
+        };
+        let frame = std::mem::take(&mut self.frame);
+        Function {
+            name,
+            def: def.0,
+            params,
+            registers: frame.registers,
+            captures: None,
+            self_ref,
+            code: frame.code,
+            locs: frame.locs,
+            loc: loc(span),
+        }
+    }
+
+    // ========== Pools ==========
+
+    fn konst(&mut self, value: Const) -> u32 {
+        let key = format!("{:?}", value);
+        if let Some(&index) = self.const_pool.get(&key) {
+            return index;
+        }
+        let index = self.module.consts.len() as u32;
+        self.module.consts.push(value);
+        self.const_pool.insert(key, index);
+        index
+    }
+
+    fn str(&mut self, text: &str) -> u32 {
+        self.konst(Const::Str(text.to_string()))
+    }
+
+    fn ty(&mut self, ty: Type) -> u32 {
+        if let Some(&index) = self.type_pool.get(&ty) {
+            return index;
+        }
+        let index = self.module.types.len() as u32;
+        self.module.types.push(ty.clone());
+        self.type_pool.insert(ty, index);
+        index
+    }
+
+    /// Index of the static type of `expr` in `module.types`
+    fn type_of(&mut self, expr: &Expression) -> u32 {
+        let ty = self.types.type_of(expr.id).map_or(Type::Unknown, |ty| lower_ty(ty, self.res));
+        self.ty(ty)
+    }
+
+    fn prim_of(&self, expr: &Expression) -> Option<ast::PrimitiveType> {
+        match self.types.type_of(expr.id) {
+            Some(Ty::Prim(prim)) => Some(*prim),
+            _ => None,
+        }
+    }
+
+    fn ctor(&mut self, def: DefId, fields: Vec<String>) -> u32 {
+        let key = (def.0, fields);
+        if let Some(&index) = self.ctor_pool.get(&key) {
+            return index;
+        }
+        let definition = self.res.def(def);
+        let parent = definition.parent.filter(|_| definition.kind == DefKind::Variant).map(|p| p.0);
+        let index = self.module.ctors.len() as u32;
+        self.module.ctors.push(Ctor { def: def.0, name: definition.name.clone(), fields: key.1.clone(), parent });
+        self.ctor_pool.insert(key, index);
+        index
+    }
+
+    // ========== Code ==========
+
+    fn emit(&mut self, instr: Instr, span: Span) -> usize {
+        self.frame.code.push(instr);
+        self.frame.locs.push(loc(span));
+        self.frame.code.len() - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.frame.code.len() as u32
+    }
+
+    /// Point the jump at `at` to the next instruction
+    fn patch(&mut self, at: usize) {
+        let here = self.here();
+        match &mut self.frame.code[at] {
+            Instr::Jump { target } | Instr::JumpIf { target, .. } | Instr::JumpUnless { target, .. } => *target = here,
+            Instr::Next { target, .. } => *target = here,
+            _ => {}
+        }
+    }
+
+    fn temp(&mut self) -> Reg {
+        self.temps(1)
+    }
+
+    /// `count` consecutive registers
+    fn temps(&mut self, count: u32) -> Reg {
+        let start = self.frame.next;
+        self.frame.next += count;
+        self.frame.registers = self.frame.registers.max(self.frame.next);
+        start
+    }
+
+    fn unit(&mut self, dst: Reg, span: Span) {
+        let index = self.konst(Const::Unit);
+        self.emit(Instr::Const { dst, index }, span);
+    }
+
+    fn unsupported(&mut self, message: &str, span: Span) {
+        let message = self.str(message);
+        self.emit(Instr::Unsupported { message }, span);
+    }
+
+    /// Panic with `template`, filling its `{:?}` with the value at `place`
+    fn panic_with(&mut self, template: &str, place: Reg, span: Span) {
+        let args = self.temps(2);
+        let index = self.str(template);
+        self.emit(Instr::Const { dst: args, index }, span);
+        self.emit(Instr::Load { dst: args + 1, src: place }, span);
+        let (path, ty) = (self.str("panic"), self.ty(Type::Unknown));
+        self.emit(Instr::CallLibrary { dst: args, path, ty, start: args, count: 2 }, span);
+    }
+
+    fn block(&mut self, block: &Block, dst: Reg) {
+        let mark = self.frame.next;
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StatementKind::Let { pattern, init, .. } => match init {
+                    Some(init) => self.bind(pattern, init),
+                    // A declaration without a value gets a register for a later assignment to fill
+                    None => {
+                        if let Some(def) = self.res.def_of_node(pattern.id) {
+                            let reg = self.temp();
+                            self.frame.locals.insert(def, reg);
+                            self.unit(reg, pattern.span);
+                        }
+                    }
+                },
+                StatementKind::Expression(expr) => {
+                    let mark = self.frame.next;
+                    let scratch = self.temp();
+                    self.expr(expr, scratch);
+                    self.frame.next = mark;
+                }
+                StatementKind::Item(_) | StatementKind::Error => {}
+            }
+        }
+        match &block.expr {
+            Some(expr) => self.expr(expr, dst),
+            None => self.unit(dst, block.span),
+        }
+        self.frame.next = mark;
+    }
+
+    /// `let pattern = init;`, leaving the bindings' registers allocated
+    fn bind(&mut self, pattern: &ast::Pattern, init: &Expression) {
+        if let (PatternKind::Identifier(_), Some(def)) = (&pattern.kind, self.res.def_of_node(pattern.id)) {
+            let reg = self.temp();
+            self.expr(init, reg);
+            self.frame.locals.insert(def, reg);
+            return;
+        }
+        let compiled = self.pattern(pattern);
+        let mark = self.frame.next;
+        let value = self.temp();
+        self.expr(init, value);
+        self.bind_value(compiled, value, pattern.span);
+        self.frame.next = mark;
+    }
+
+    /// Match the value in `value` against a pattern that must match
+    fn bind_value(&mut self, pattern: Pattern, value: Reg, span: Span) {
+        let (place, matched) = (self.temp(), self.temp());
+        self.emit(Instr::Temp { dst: place, src: value }, span);
+        let pattern = self.push_pattern(pattern);
+        self.emit(Instr::Match { dst: matched, place, pattern }, span);
+        let jump = self.emit(Instr::JumpIf { cond: matched, target: 0 }, span);
+        self.panic_with("pattern does not match `{:?}`", place, span);
+        self.patch(jump);
+    }
+
+    fn push_pattern(&mut self, pattern: Pattern) -> u32 {
+        self.module.patterns.push(pattern);
+        self.module.patterns.len() as u32 - 1
+    }
+
+    // ========== Expressions ==========
+
+    /// Compile `expr`, leaving its value in `dst`
+    fn expr(&mut self, expr: &Expression, dst: Reg) {
+        let span = expr.span;
+        match &expr.kind {
+            ExpressionKind::Literal(lit) => {
+                let index = self.konst(literal(lit));
+                self.emit(Instr::Const { dst, index }, span);
+            }
+            ExpressionKind::Identifier(_) | ExpressionKind::Path(_) => self.name(expr, dst),
+            ExpressionKind::Binary { left, op, right } => self.binary(expr, left, op, right, dst),
+            ExpressionKind::Unary { op, expr: operand } => match op {
+                UnaryOp::Deref => {
+                    let place = self.temp();
+                    self.place(expr, place);
+                    self.emit(Instr::Load { dst, src: place }, span);
+                }
+                UnaryOp::Ref | UnaryOp::RefMut => self.place(operand, dst),
+                UnaryOp::Neg | UnaryOp::Not => {
+                    let src = self.temp();
+                    self.expr(operand, src);
+                    self.emit(Instr::Unary { op: op.clone(), ty: self.prim_of(expr), dst, src }, span);
+                }
+            },
+            ExpressionKind::Call { func, args } => self.call(expr, func, args, dst),
+            ExpressionKind::MethodCall { receiver, method, args } => {
+                let recv = self.temp();
+                self.place(receiver, recv);
+                let (start, count) = self.args(args);
+                let resolved = self.types.method_calls.get(&expr.id).copied().filter(|def| self.is_user_fn(*def));
+                let site = MethodSite {
+                    name: method.clone(),
+                    resolved: resolved.map(|def| def.0),
+                    keys: self.static_keys(receiver),
+                    ty: self.type_of(expr),
+                };
+                self.module.sites.push(site);
+                let site = self.module.sites.len() as u32 - 1;
+                self.emit(Instr::Method { dst, site, recv, start, count }, span);
+            }
+            ExpressionKind::If { cond, then_block, else_block } => {
+                let flag = self.temp();
+                self.expr(cond, flag);
+                let to_else = self.emit(Instr::JumpUnless { cond: flag, target: 0 }, cond.span);
+                self.block(then_block, dst);
+                let to_end = self.emit(Instr::Jump { target: 0 }, span);
+                self.patch(to_else);
+                match else_block {
+                    Some(block) => self.block(block, dst),
+                    None => self.unit(dst, span),
+                }
+                self.patch(to_end);
+            }
+            ExpressionKind::Match { expr: scrutinee, arms } => {
+                let place = self.temp();
+                self.place(scrutinee, place);
+                let mut ends = Vec::new();
+                for arm in arms {
+                    let mark = self.frame.next;
+                    let pattern = self.pattern(&arm.pattern);
+                    let pattern = self.push_pattern(pattern);
+                    let matched = self.temp();
+                    self.emit(Instr::Match { dst: matched, place, pattern }, arm.pattern.span);
+                    let mut skips = vec![self.emit(Instr::JumpUnless { cond: matched, target: 0 }, arm.span)];
+                    if let Some(guard) = &arm.guard {
+                        self.expr(guard, matched);
+                        skips.push(self.emit(Instr::JumpUnless { cond: matched, target: 0 }, guard.span));
+                    }
+                    self.expr(&arm.body, dst);
+                    ends.push(self.emit(Instr::Jump { target: 0 }, arm.span));
+                    for skip in skips {
+                        self.patch(skip);
+                    }
+                    self.frame.next = mark;
+                }
+                self.panic_with("no match arm matched `{:?}`", place, span);
+                for end in ends {
+                    self.patch(end);
+                }
+            }
+            ExpressionKind::Loop(body) => {
+                let start = self.here();
+                self.frame.loops.push(Loop { dst: Some(dst), continue_to: start, breaks: Vec::new() });
+                let scratch = self.temp();
+                self.block(body, scratch);
+                self.emit(Instr::Jump { target: start }, span);
+                self.end_loop();
+            }
+            ExpressionKind::While { cond, body } => {
+                let start = self.here();
+                let flag = self.temp();
+                self.expr(cond, flag);
+                let exit = self.emit(Instr::JumpUnless { cond: flag, target: 0 }, cond.span);
+                self.frame.loops.push(Loop { dst: None, continue_to: start, breaks: vec![exit] });
+                self.block(body, flag);
+                self.emit(Instr::Jump { target: start }, span);
+                self.end_loop();
+                self.unit(dst, span);
+            }
+            ExpressionKind::For { pattern, iter, body } => {
+                let items = self.temp();
+                self.expr(iter, items);
+                self.emit(Instr::Iter { dst: items, src: items }, iter.span);
+                let start = self.here();
+                let item = self.temp();
+                let next = self.emit(Instr::Next { dst: item, iter: items, target: 0 }, iter.span);
+                self.frame.loops.push(Loop { dst: None, continue_to: start, breaks: vec![next] });
+                let mark = self.frame.next;
+                match (&pattern.kind, self.res.def_of_node(pattern.id)) {
+                    (PatternKind::Identifier(_), Some(def)) => {
+                        self.frame.locals.insert(def, item);
+                    }
+                    _ => {
+                        let compiled = self.pattern(pattern);
+                        self.bind_value(compiled, item, pattern.span);
+                    }
+                }
+                let scratch = self.temp();
+                self.block(body, scratch);
+                self.frame.next = mark;
+                self.emit(Instr::Jump { target: start }, span);
+                self.end_loop();
+                self.unit(dst, span);
+            }
+            ExpressionKind::Return(value) => {
+                match value {
+                    Some(value) => self.expr(value, dst),
+                    None => self.unit(dst, span),
+                }
+                self.emit(Instr::Return { src: dst }, span);
+            }
+            ExpressionKind::Break(value) => {
+                let target = self.frame.loops.last().map(|l| l.dst);
+                let value_dst = target.flatten().unwrap_or(dst);
+                match value {
+                    Some(value) => self.expr(value, value_dst),
+                    None => self.unit(value_dst, span),
+                }
+                match target {
+                    Some(_) => {
+                        let jump = self.emit(Instr::Jump { target: 0 }, span);
+                        self.frame.loops.last_mut().expect("inside a loop").breaks.push(jump);
+                    }
+                    // Outside any loop, `break` leaves the function with `()`
+                    None => {
+                        self.unit(dst, span);
+                        self.emit(Instr::Return { src: dst }, span);
+                    }
+                }
+            }
+            ExpressionKind::Continue => match self.frame.loops.last() {
+                Some(l) => {
+                    let target = l.continue_to;
+                    self.emit(Instr::Jump { target }, span);
+                }
+                None => {
+                    self.unit(dst, span);
+                    self.emit(Instr::Return { src: dst }, span);
+                }
+            },
+            ExpressionKind::Block(block) => self.block(block, dst),
+            ExpressionKind::Comptime(inner) => self.expr(inner, dst),
+            ExpressionKind::Tuple(elems) => {
+                let (start, count) = self.args(elems);
+                self.emit(Instr::Tuple { dst, start, count }, span);
+            }
+            ExpressionKind::Array(elems) => {
+                let (start, count) = self.args(elems);
+                self.emit(Instr::List { dst, start, count }, span);
+            }
+            ExpressionKind::Index { .. } | ExpressionKind::Field { .. } => {
+                let place = self.temp();
+                self.place(expr, place);
+                self.emit(Instr::Load { dst, src: place }, span);
+            }
+            ExpressionKind::Struct { fields, .. } => {
+                let Some(Res::Def(def)) = self.res.res(expr.id).cloned() else {
+                    return self.unsupported("cannot build this struct", span);
+                };
+                // Fields in declaration order, so that equal values compare equal
+                let mut names: Vec<&String> = fields.iter().map(|(name, _)| name).collect();
+                if let Some(declared) = self.res.fields.get(&def) {
+                    names.sort_by_key(|name| declared.iter().position(|f| f.name == **name));
+                }
+                let names: Vec<String> = names.into_iter().cloned().collect();
+                let start = self.temps(names.len() as u32);
+                for (name, value) in fields {
+                    let position = names.iter().position(|n| n == name).unwrap_or(0) as u32;
+                    self.expr(value, start + position);
+                }
+                let count = names.len() as u32;
+                let ctor = self.ctor(def, names);
+                self.emit(Instr::Adt { dst, ctor, start, count }, span);
+            }
+            // Without a model to consult, the symbolic side is the answer
+            ExpressionKind::Synth { expr: inner, .. } | ExpressionKind::Verify { expr: inner, .. } => {
+                self.expr(inner, dst)
+            }
+            ExpressionKind::Hybrid { symbolic, .. } => self.expr(symbolic, dst),
+            ExpressionKind::Intent { .. } => {
+                self.unsupported("`intent` needs a model to synthesize from, which compiled code does not have", span)
+            }
+            ExpressionKind::Spawn { .. }
+            | ExpressionKind::Send { .. }
+            | ExpressionKind::Receive { .. }
+            | ExpressionKind::Broadcast { .. } => self.unsupported("agents are not supported by the bytecode VM", span),
+            ExpressionKind::Closure { params, body, is_move, .. } => self.closure(expr, params, body, *is_move, dst),
+        }
+    }
+
+    /// Compile a closure's body as a function of its own, whose registers
+    /// after the parameters hold the captured locals
+    fn closure(&mut self, expr: &Expression, params: &[ast::Param], body: &Expression, is_move: bool, dst: Reg) {
+        let span = expr.span;
+        let defs = self.res.captures.get(&expr.id).cloned().unwrap_or_default();
+        let mut captures = Vec::with_capacity(defs.len());
+        for def in &defs {
+            let Some(&src) = self.frame.locals.get(def) else {
+                let message = format!("use of uninitialized `{}`", self.res.def(*def).name);
+                return self.unsupported(&message, span);
+            };
+            // A `move` closure takes a copy, where others share the local's register
+            let reg = match is_move {
+                true => {
+                    let copy = self.temp();
+                    self.emit(Instr::Move { dst: copy, src }, span);
+                    copy
+                }
+                false => src,
+            };
+            captures.push(reg);
+        }
+
+        let outer = std::mem::take(&mut self.frame);
+        for param in params {
+            let reg = self.temp();
+            if let Some(def) = self.res.def_of_node(param.id) {
+                self.frame.locals.insert(def, reg);
+            }
+        }
+        for def in &defs {
+            let reg = self.temp();
+            self.frame.locals.insert(*def, reg);
+        }
+        let result = self.temp();
+        self.expr(body, result);
+        self.emit(Instr::Return { src: result }, body.span);
+        let frame = std::mem::replace(&mut self.frame, outer);
+        let func = self.module.functions.len() as u32;
+        self.module.functions.push(Function {
+            name: "<closure>".to_string(),
+            def: 0,
+            params: params.len() as u32,
+            registers: frame.registers,
+            captures: Some(defs.len() as u32),
+            self_ref: false,
+            code: frame.code,
+            locs: frame.locs,
+            loc: loc(span),
+        });
+        self.emit(Instr::Closure { dst, func, captures }, span);
+    }
+
+    /// Evaluate `exprs` into consecutive registers
+    fn args(&mut self, exprs: &[Expression]) -> (Reg, u32) {
+        let start = self.temps(exprs.len() as u32);
+        for (i, expr) in exprs.iter().enumerate() {
+            self.expr(expr, start + i as u32);
+        }
+        (start, exprs.len() as u32)
+    }
+
+    fn end_loop(&mut self) {
+        let done = self.frame.loops.pop().expect("a loop was entered");
+        for jump in done.breaks {
+            self.patch(jump);
+        }
+    }
+
+    fn binary(&mut self, expr: &Expression, left: &Expression, op: &BinaryOp, right: &Expression, dst: Reg) {
+        let span = expr.span;
+        match op {
+            BinaryOp::Assign => {
+                let (value, place) = (self.temp(), self.temp());
+                self.expr(right, value);
+                self.place(left, place);
+                self.emit(Instr::Store { dst: place, src: value }, span);
+                self.unit(dst, span);
+            }
+            BinaryOp::And | BinaryOp::Or => {
+                self.expr(left, dst);
+                let skip = match op {
+                    BinaryOp::And => self.emit(Instr::JumpUnless { cond: dst, target: 0 }, left.span),
+                    _ => self.emit(Instr::JumpIf { cond: dst, target: 0 }, left.span),
+                };
+                self.expr(right, dst);
+                self.patch(skip);
+            }
+            _ => {
+                let (lhs, rhs) = (self.temp(), self.temp());
+                self.expr(left, lhs);
+                self.expr(right, rhs);
+                self.emit(Instr::Binary { op: op.clone(), ty: self.prim_of(expr), dst, lhs, rhs }, span);
+            }
+        }
+    }
+
+    /// A local, constant, function or unit variant
+    fn name(&mut self, expr: &Expression, dst: Reg) {
+        let span = expr.span;
+        match self.res.res(expr.id).cloned() {
+            Some(Res::Def(def)) => {
+                let definition = self.res.def(def);
+                match definition.kind {
+                    DefKind::Local | DefKind::Param => match self.frame.locals.get(&def) {
+                        Some(&src) => {
+                            self.emit(Instr::Move { dst, src }, span);
+                        }
+                        None => self.unsupported(&format!("use of uninitialized `{}`", definition.name), span),
+                    },
+                    DefKind::Const => match self.consts.get(&def) {
+                        Some(&index) => {
+                            self.emit(Instr::Global { dst, index }, span);
+                        }
+                        None => self.unsupported(&format!("constant `{}` has no value", definition.name), span),
+                    },
+                    DefKind::Variant | DefKind::Struct if !matches!(self.types.type_of(expr.id), Some(Ty::Fn(..))) => {
+                        let ctor = self.ctor(def, Vec::new());
+                        self.emit(Instr::Adt { dst, ctor, start: 0, count: 0 }, span);
+                    }
+                    DefKind::Variant | DefKind::Struct => {
+                        self.ctor(def, Vec::new());
+                        self.emit(Instr::Def { dst, def: def.0 }, span);
+                    }
+                    // The prelude's functions run in the library, which knows them by name
+                    DefKind::Function if definition.is_prelude() => {
+                        let path = self.str(&definition.name);
+                        self.emit(Instr::Library { dst, path }, span);
+                    }
+                    DefKind::Function => {
+                        self.emit(Instr::Def { dst, def: def.0 }, span);
+                    }
+                    DefKind::External => {
+                        let path = self.str(&definition.name);
+                        self.emit(Instr::Library { dst, path }, span);
+                    }
+                    kind => {
+                        let message = format!("cannot use {} `{}` as a value", kind.describe(), definition.name);
+                        self.unsupported(&message, span)
+                    }
+                }
+            }
+            Some(Res::Partial { .. }) => match self.partial(expr) {
+                Ok(def) => {
+                    self.emit(Instr::Def { dst, def: def.0 }, span);
+                }
+                Err(path) => {
+                    let path = self.str(&path);
+                    self.emit(Instr::Library { dst, path }, span);
+                }
+            },
+            None => self.unsupported("this name did not resolve", span),
+        }
+    }
+
+    /// What a path that resolved only in part names: a function of the crate, or else a library path
+    fn partial(&self, expr: &Expression) -> Result<DefId, String> {
+        mono::resolve_partial(self.res, self.types, &self.self_types, expr, |key, name| {
+            self.find_method(key.into(), name).map(DefId)
+        })
+    }
+
+    fn is_user_fn(&self, def: DefId) -> bool {
+        self.fns.contains_key(&def) || self.trait_of(def).is_some()
+    }
+
+    /// Impl keys the static type of `receiver` picks out, or none when only its value can tell
+    fn static_keys(&self, receiver: &Expression) -> Vec<TypeKey> {
+        let mut ty = self.types.type_of(receiver.id);
+        while let Some(Ty::Ref { ty: inner, .. }) = ty {
+            ty = Some(inner);
+        }
+        match ty {
+            Some(Ty::Prim(prim)) => vec![TypeKey::Prim(*prim)],
+            Some(Ty::Adt(def, _)) => vec![TypeKey::Def(def.0)],
+            _ => Vec::new(),
+        }
+    }
+
+    fn call(&mut self, expr: &Expression, func: &Expression, args: &[Expression], dst: Reg) {
+        let span = expr.span;
+        let def = match self.res.res(func.id) {
+            Some(Res::Def(def)) => Some(*def),
+            Some(Res::Partial { .. }) => self.partial(func).ok(),
+            None => None,
+        };
+        let definition = def.map(|def| self.res.def(def));
+        match definition {
+            // A function that is not a trait's runs without dispatch
+            Some(d) if d.kind == DefKind::Function && self.trait_of(d.id).is_none() && self.fns.contains_key(&d.id) => {
+                let func = self.fns[&d.id].0;
+                let (start, count) = self.args(args);
+                self.emit(Instr::Call { dst, func, start, count }, span);
+            }
+            Some(d)
+                if matches!(d.kind, DefKind::Variant | DefKind::Struct)
+                    && matches!(self.types.type_of(func.id), Some(Ty::Fn(..))) =>
+            {
+                let (start, count) = self.args(args);
+                let ctor = self.ctor(d.id, (0..count).map(|i| i.to_string()).collect());
+                self.emit(Instr::Adt { dst, ctor, start, count }, span);
+            }
+            _ => {
+                let library = match definition {
+                    Some(d) if d.is_prelude() && d.kind == DefKind::Function => Some(d.name.clone()),
+                    Some(d) if d.kind == DefKind::External => Some(d.name.clone()),
+                    None if matches!(self.res.res(func.id), Some(Res::Partial { .. })) => self.partial(func).err(),
+                    _ => None,
+                };
+                match library {
+                    Some(path) => {
+                        let (start, count) = self.args(args);
+                        let (path, ty) = (self.str(&path), self.type_of(expr));
+                        self.emit(Instr::CallLibrary { dst, path, ty, start, count }, span);
+                    }
+                    None => {
+                        let callee = self.temp();
+                        self.expr(func, callee);
+                        let (start, count) = self.args(args);
+                        self.emit(Instr::CallValue { dst, callee, start, count }, span);
+                    }
+                }
+            }
+        }
+    }
+
+    // ========== Places ==========
+
+    /// Put a reference to where `expr` lives in `dst`: a local or a part of
+    /// one, what a reference points to, or a temporary holding its value
+    fn place(&mut self, expr: &Expression, dst: Reg) {
+        let span = expr.span;
+        match &expr.kind {
+            ExpressionKind::Identifier(_) => match self.res.res(expr.id) {
+                Some(Res::Def(def)) if matches!(self.res.def(*def).kind, DefKind::Local | DefKind::Param) => {
+                    let src = match self.frame.locals.get(def) {
+                        Some(&src) => src,
+                        // Assigned before any `let`, which checking rules out; give it a register anyway
+                        None => {
+                            let src = self.temp();
+                            self.frame.locals.insert(*def, src);
+                            src
+                        }
+                    };
+                    self.emit(Instr::Addr { dst, src }, span);
+                }
+                _ => {
+                    self.expr(expr, dst);
+                    self.emit(Instr::Temp { dst, src: dst }, span);
+                }
+            },
+            ExpressionKind::Field { expr: base, field } => {
+                let place = self.temp();
+                self.place(base, place);
+                let name = self.str(field);
+                self.emit(Instr::FieldAddr { dst, base: place, name }, span);
+            }
+            ExpressionKind::Index { expr: base, index } => {
+                let (place, key) = (self.temp(), self.temp());
+                self.place(base, place);
+                self.expr(index, key);
+                self.emit(Instr::IndexAddr { dst, base: place, index: key }, span);
+            }
+            // A `Box` is its contents, so dereferencing anything else stays in place
+            ExpressionKind::Unary { op: UnaryOp::Deref, expr: inner } => {
+                let place = self.temp();
+                self.place(inner, place);
+                self.emit(Instr::Deref { dst, src: place }, span);
+            }
+            _ => {
+                self.expr(expr, dst);
+                self.emit(Instr::Temp { dst, src: dst }, span);
+            }
+        }
+    }
+
+    // ========== Patterns ==========
+
+    /// Compile a pattern, giving each name it binds a register
+    fn pattern(&mut self, pattern: &ast::Pattern) -> Pattern {
+        match &pattern.kind {
+            PatternKind::Wildcard => Pattern::Wildcard,
+            PatternKind::Identifier(_) | PatternKind::Path(_) => match self.res.res(pattern.id).cloned() {
+                Some(Res::Def(def)) if self.res.def(def).kind == DefKind::Const => match self.consts.get(&def) {
+                    Some(&index) => Pattern::Global(index),
+                    None => Pattern::Wildcard,
+                },
+                Some(Res::Def(def)) if matches!(self.res.def(def).kind, DefKind::Variant | DefKind::Struct) => {
+                    self.ctor(def, Vec::new());
+                    Pattern::Ctor { def: def.0, fields: Vec::new(), arity: None }
+                }
+                _ => match self.res.def_of_node(pattern.id) {
+                    Some(def) => {
+                        let reg = self.temp();
+                        self.frame.locals.insert(def, reg);
+                        Pattern::Bind(reg)
+                    }
+                    None => Pattern::Wildcard,
+                },
+            },
+            PatternKind::Literal(lit) => Pattern::Literal(self.konst(literal(lit))),
+            PatternKind::Tuple(pats) => Pattern::Tuple(pats.iter().map(|p| self.pattern(p)).collect()),
+            PatternKind::TupleStruct { elems, .. } => match self.res.res(pattern.id).cloned() {
+                Some(Res::Def(def)) => Pattern::Ctor {
+                    def: def.0,
+                    fields: elems.iter().enumerate().map(|(i, p)| (i.to_string(), self.pattern(p))).collect(),
+                    arity: Some(elems.len() as u32),
+                },
+                _ => Pattern::Wildcard,
+            },
+            PatternKind::Struct { fields, .. } => match self.res.res(pattern.id).cloned() {
+                Some(Res::Def(def)) => Pattern::Ctor {
+                    def: def.0,
+                    fields: fields.iter().map(|(name, p)| (name.clone(), self.pattern(p))).collect(),
+                    arity: None,
+                },
+                _ => Pattern::Wildcard,
+            },
+        }
+    }
+}
+
+fn literal(lit: &Literal) -> Const {
+    match lit {
+        Literal::Int(n) => Const::Int(*n as i128),
+        Literal::Float(x) => Const::Float(*x),
+        Literal::String(s) => Const::Str(s.clone()),
+        Literal::Char(c) => Const::Char(*c),
+        Literal::Bool(b) => Const::Bool(*b),
+        Literal::Unit => Const::Unit,
+    }
+}
+
+fn loc(span: Span) -> Loc {
+    Loc { file: span.file.0, line: span.line, column: span.column }
+}
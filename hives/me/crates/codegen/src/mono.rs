@@ -0,0 +1,882 @@
+// Monomorphization
+// What the backends that lay values out themselves share: the crate's
+// functions and impls, which instance of a generic or trait function a call
+// runs, and the types of expressions checking left open.
+
+use my_lang_ast as ast;
+use my_lang_ast::{
+    BinaryOp, Block, Expression, ExpressionKind, ImplItem, ItemKind, NodeId, PatternKind, PrimitiveType, StatementKind,
+    TraitItem, TypeKind, UnaryOp,
+};
+use my_lang_resolve::{DefId, DefKind, Res, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults};
+use std::collections::HashMap;
+
+/// What an impl is for, to find methods by the type of a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    Def(DefId),
+    Prim(PrimitiveType),
+}
+
+/// Type each `Self` that is not a struct or enum stands for
+#[derive(Debug, Default)]
+pub struct SelfTypes(HashMap<DefId, Key>);
+
+impl SelfTypes {
+    /// Key the methods of `imp` are found under: its struct or enum, or the
+    /// primitive type it is for, which its `Self` then stands for
+    pub fn add_impl(&mut self, res: &Resolution, imp: &ast::Impl) -> Option<Key> {
+        match res.impl_self.get(&imp.id) {
+            Some(&def) if matches!(res.def(def).kind, DefKind::Struct | DefKind::Enum) => Some(Key::Def(def)),
+            self_def => {
+                let key = match &imp.self_ty.kind {
+                    TypeKind::Primitive(prim) => Some(Key::Prim(*prim)),
+                    _ => None,
+                };
+                if let (Some(&self_def), Some(key)) = (self_def, key) {
+                    self.0.insert(self_def, key);
+                }
+                key
+            }
+        }
+    }
+
+    /// The type `Self` stands for when `def` is the `Self` of an impl for a primitive type
+    pub fn get(&self, def: DefId) -> Option<Key> {
+        self.0.get(&def).copied()
+    }
+}
+
+/// Library types by name, for dispatching on strings and collections
+pub fn library_types(res: &Resolution) -> impl Iterator<Item = (&str, DefId)> {
+    res.defs
+        .iter()
+        .filter(|d| d.is_prelude() && matches!(d.kind, DefKind::Struct | DefKind::Enum))
+        .map(|d| (d.name.as_str(), d.id))
+}
+
+/// What a path that resolved only in part names: a function of the crate,
+/// or else a library path. `method` finds a method of a primitive type by
+/// name, for `Self::f` in an impl for one.
+pub fn resolve_partial(
+    res: &Resolution,
+    types: &TypeckResults,
+    self_types: &SelfTypes,
+    expr: &Expression,
+    method: impl Fn(Key, &str) -> Option<DefId>,
+) -> Result<DefId, String> {
+    let Some(Res::Partial { base, rest }) = res.res(expr.id) else {
+        return Err(String::new());
+    };
+    // `T::f` through a bound, which type checking resolved to the trait's function
+    if let Some(def) = types.method_calls.get(&expr.id) {
+        return Ok(*def);
+    }
+    // `Self::f` in an impl for a primitive type
+    if let (Some(key), [name]) = (self_types.get(*base), rest.as_slice()) {
+        if let Some(def) = method(key, name) {
+            return Ok(def);
+        }
+    }
+    let mut path = vec![res.def(*base).name.clone()];
+    path.extend(rest.iter().cloned());
+    Err(path.join("::"))
+}
+
+pub(crate) struct Method {
+    pub key: Key,
+    pub trait_def: Option<DefId>,
+    pub name: String,
+    pub def: DefId,
+}
+
+/// The instance of a function a call runs
+pub(crate) struct Instance {
+    pub def: DefId,
+    /// Generic arguments, in the order of the function's generics
+    pub args: Vec<Ty>,
+    pub params: Vec<Ty>,
+    pub output: Ty,
+}
+
+/// What the callee of a call expression names
+pub(crate) enum Callee {
+    /// A function of the crate, whose instance the argument types select
+    Fn(DefId),
+    /// A struct or variant built from the arguments, with the variant's index
+    Ctor(DefId, usize),
+    /// A function of the prelude, by name
+    Prelude(String),
+    /// A library function, by path
+    Library(String),
+    /// A function value
+    Value,
+}
+
+/// A library function called by path, with its arguments
+pub(crate) enum LibraryCall<'p, A> {
+    StringNew,
+    StringFrom(A),
+    /// `Vec::new`, `VecDeque::new` or `with_capacity`, whose capacity is only evaluated
+    VecNew(Option<A>),
+    VecFrom(A),
+    BoxNew(A),
+    Exit(A),
+    Args,
+    Swap(A, A),
+    Replace(A, A),
+    ReadFile(A),
+    WriteFile(A, A),
+    /// A function of the prelude reached by a path
+    Prelude(&'p str),
+    Unknown,
+}
+
+/// Which library function `path` names, given the arguments of the call,
+/// whether those are expressions or the values of them
+pub(crate) fn library_call<'p, 'e, T>(path: &'p str, args: &'e [T]) -> LibraryCall<'p, &'e T> {
+    let segments: Vec<&str> = path.split("::").collect();
+    let (owner, name) = match segments.as_slice() {
+        [.., owner, name] => (*owner, *name),
+        [name] => ("", *name),
+        [] => ("", ""),
+    };
+    match (owner, name, args) {
+        ("String", "new", []) => LibraryCall::StringNew,
+        ("String", "from", [text]) => LibraryCall::StringFrom(text),
+        ("Vec" | "VecDeque", "new", []) => LibraryCall::VecNew(None),
+        ("Vec" | "VecDeque", "with_capacity", [capacity]) => LibraryCall::VecNew(Some(capacity)),
+        ("Vec", "from", [items]) => LibraryCall::VecFrom(items),
+        ("Box", "new", [value]) => LibraryCall::BoxNew(value),
+        ("process", "exit", [code]) => LibraryCall::Exit(code),
+        ("env", "args", []) => LibraryCall::Args,
+        ("mem", "swap", [a, b]) => LibraryCall::Swap(a, b),
+        ("mem", "replace", [dest, value]) => LibraryCall::Replace(dest, value),
+        ("fs", "read_to_string", [path]) => LibraryCall::ReadFile(path),
+        ("fs", "write", [path, contents]) => LibraryCall::WriteFile(path, contents),
+        (_, name, _) if my_lang_resolve::prelude::FUNCTIONS.contains(&name) => LibraryCall::Prelude(name),
+        _ => LibraryCall::Unknown,
+    }
+}
+
+/// How types read in the body being compiled
+#[derive(Default)]
+pub(crate) struct Scope {
+    /// Generic arguments of the instance
+    pub subst: HashMap<DefId, Ty>,
+    /// Types of nodes checking left open, such as the results of library methods
+    pub inferred: HashMap<NodeId, Ty>,
+}
+
+/// The items of a checked crate that code is generated for
+pub(crate) struct Crate<'a> {
+    pub res: &'a Resolution,
+    pub types: &'a TypeckResults,
+    pub fns: HashMap<DefId, &'a ast::Function>,
+    pub consts: HashMap<DefId, &'a ast::Const>,
+    pub methods: Vec<Method>,
+    pub self_types: SelfTypes,
+    /// `Type_method` for functions of impls and traits, the bare name otherwise
+    pub fn_names: HashMap<DefId, String>,
+    /// The `drop` of each type's `Drop` impl
+    pub drops: HashMap<DefId, DefId>,
+    /// Whether values of a type run code when dropped
+    cleanups: HashMap<Ty, bool>,
+}
+
+impl<'a> Crate<'a> {
+    pub(crate) fn new(res: &'a Resolution, types: &'a TypeckResults, programs: &[&'a ast::Program]) -> Self {
+        let mut krate = Self {
+            res,
+            types,
+            fns: HashMap::new(),
+            consts: HashMap::new(),
+            methods: Vec::new(),
+            self_types: SelfTypes::default(),
+            fn_names: HashMap::new(),
+            drops: HashMap::new(),
+            cleanups: HashMap::new(),
+        };
+        for program in programs {
+            krate.collect(&program.items);
+        }
+        krate
+    }
+
+    /// The `main` of the first of `programs`
+    pub(crate) fn main(&self, programs: &[&ast::Program]) -> Option<DefId> {
+        programs.first().and_then(|program| {
+            program.items.iter().find_map(|item| match &item.kind {
+                ItemKind::Function(func) if func.name == "main" => self.res.def_of_node(func.id),
+                _ => None,
+            })
+        })
+    }
+
+    // ========== Items ==========
+
+    fn collect(&mut self, items: &'a [ast::Item]) {
+        for item in items {
+            match &item.kind {
+                ItemKind::Function(func) => self.add_fn(func, None),
+                ItemKind::Const(c) => self.add_const(c),
+                ItemKind::Impl(imp) => self.add_impl(imp),
+                ItemKind::Trait(t) => {
+                    for item in &t.items {
+                        match item {
+                            TraitItem::Function(func) => self.add_fn(func, Some(&t.name)),
+                            TraitItem::Const(c) => self.add_const(c),
+                            _ => {}
+                        }
+                    }
+                }
+                ItemKind::Module(module) => self.collect(&module.items),
+                _ => {}
+            }
+        }
+    }
+
+    fn add_fn(&mut self, func: &'a ast::Function, owner: Option<&str>) {
+        if let Some(def) = self.res.def_of_node(func.id) {
+            self.fns.insert(def, func);
+            let name = match owner {
+                Some(owner) => format!("{}_{}", owner, func.name),
+                None => func.name.clone(),
+            };
+            self.fn_names.insert(def, name);
+        }
+        // Items declared inside the body
+        for stmt in &func.body.stmts {
+            if let StatementKind::Item(item) = &stmt.kind {
+                self.collect(std::slice::from_ref(item));
+            }
+        }
+    }
+
+    fn add_const(&mut self, c: &'a ast::Const) {
+        if let Some(def) = self.res.def_of_node(c.id) {
+            self.consts.insert(def, c);
+        }
+    }
+
+    fn add_impl(&mut self, imp: &'a ast::Impl) {
+        let trait_def = match self.res.res(imp.id) {
+            Some(Res::Def(def)) => Some(*def),
+            _ => None,
+        };
+        let key = self.self_types.add_impl(self.res, imp);
+        let owner = key.map(|key| match key {
+            Key::Def(def) => self.res.def(def).name.clone(),
+            Key::Prim(prim) => prim_name(prim).to_string(),
+        });
+        let is_drop = trait_def.is_some_and(|t| self.res.def(t).is_prelude() && self.res.def(t).name == "Drop");
+        for item in &imp.items {
+            match item {
+                ImplItem::Function(func) => {
+                    self.add_fn(func, owner.as_deref());
+                    if let (Some(key), Some(def)) = (key, self.res.def_of_node(func.id)) {
+                        self.methods.push(Method { key, trait_def, name: func.name.clone(), def });
+                        if let (true, "drop", Key::Def(adt)) = (is_drop, func.name.as_str(), key) {
+                            self.drops.insert(adt, def);
+                        }
+                    }
+                }
+                ImplItem::Const(c) => self.add_const(c),
+                ImplItem::Type(_) => {}
+            }
+        }
+    }
+
+    pub(crate) fn trait_of(&self, def: DefId) -> Option<DefId> {
+        self.res.def(def).parent.filter(|parent| self.res.def(*parent).kind == DefKind::Trait)
+    }
+
+    pub(crate) fn is_user_fn(&self, def: DefId) -> bool {
+        self.fns.contains_key(&def) || self.trait_of(def).is_some()
+    }
+
+    // ========== Types ==========
+
+    pub(crate) fn ty(&self, scope: &Scope, node: NodeId) -> Ty {
+        let ty = self.types.type_of(node).map_or(Ty::Error, |ty| ty.subst(&scope.subst));
+        match has_infer(&ty) {
+            true => scope.inferred.get(&node).cloned().unwrap_or(ty),
+            false => ty,
+        }
+    }
+
+    pub(crate) fn is_prelude(&self, def: DefId, name: &str) -> bool {
+        let definition = self.res.def(def);
+        definition.is_prelude() && definition.name == name
+    }
+
+    pub(crate) fn prelude_def(&self, name: &str) -> Option<DefId> {
+        self.res.defs.iter().find(|d| d.is_prelude() && d.name == name).map(|d| d.id)
+    }
+
+    /// A library type of the prelude, such as `Option<T>`
+    pub(crate) fn prelude_ty(&self, name: &str, args: Vec<Ty>) -> Ty {
+        self.prelude_def(name).map_or(Ty::Error, |def| Ty::Adt(def, args))
+    }
+
+    /// A name for `ty` made of the names of its parts
+    pub(crate) fn mangle(&self, ty: &Ty) -> String {
+        let join = |tys: &[Ty]| tys.iter().map(|t| self.mangle(t)).collect::<Vec<_>>().join("_");
+        match ty {
+            Ty::Prim(prim) => prim_name(*prim).to_string(),
+            Ty::Tuple(elems) if elems.is_empty() => "unit".to_string(),
+            Ty::Tuple(elems) => format!("tuple{}_{}", elems.len(), join(elems)),
+            Ty::Array(elem, len) => format!("arr{}_{}", len.unwrap_or(0), self.mangle(elem)),
+            Ty::Ref { ty, mutable } => format!("{}_{}", if *mutable { "mut" } else { "ref" }, self.mangle(ty)),
+            Ty::Fn(params, ret) => format!("fn{}_{}_{}", params.len(), join(params), self.mangle(ret)),
+            Ty::Adt(def, args) if args.is_empty() => self.res.def(*def).name.clone(),
+            Ty::Adt(def, args) => format!("{}_{}", self.res.def(*def).name, join(args)),
+            Ty::Never => "never".to_string(),
+            _ => "unknown".to_string(),
+        }
+    }
+
+    /// What a library method of `ty` is named under, as `str` is in `str::len`
+    pub(crate) fn owner(&self, ty: &Ty) -> String {
+        let base = self.peel(ty);
+        match &base {
+            _ if self.is_str(&base) => "str".to_string(),
+            Ty::Prim(prim) => prim_name(*prim).to_string(),
+            Ty::Array(..) => "array".to_string(),
+            Ty::Adt(def, _) => self.res.def(*def).name.clone(),
+            _ => self.mangle(&base),
+        }
+    }
+
+    /// The struct or enum a struct or variant belongs to, and the variant's index in it
+    pub(crate) fn variant_of(&self, def: DefId) -> Option<(DefId, usize)> {
+        let definition = self.res.def(def);
+        let adt = match definition.kind {
+            DefKind::Struct => def,
+            _ => definition.parent?,
+        };
+        let index = self.types.adts.get(&adt)?.variants.iter().position(|v| v.def == def)?;
+        Some((adt, index))
+    }
+
+    /// The variant of the enum `ty` called `name`, and its index
+    pub(crate) fn variant_named(&self, ty: &Ty, name: &str) -> (DefId, usize) {
+        let Ty::Adt(def, _) = ty else { return (DefId(0), 0) };
+        let variants = self.types.adts.get(def).map_or(&[][..], |adt| &adt.variants);
+        variants
+            .iter()
+            .enumerate()
+            .find(|(_, v)| self.res.def(v.def).name == name)
+            .map_or((DefId(0), 0), |(index, v)| (v.def, index))
+    }
+
+    /// Names and types of the fields of variant `index` of the struct or enum `ty`
+    pub(crate) fn fields_of(&self, ty: &Ty, index: usize) -> Vec<(String, Ty)> {
+        let Ty::Adt(def, args) = ty else { return Vec::new() };
+        let Some(adt) = self.types.adts.get(def) else { return Vec::new() };
+        let map: HashMap<DefId, Ty> = adt.generics.iter().copied().zip(args.iter().cloned()).collect();
+        adt.variants
+            .get(index)
+            .map(|v| v.fields.iter().map(|f| (f.name.clone(), f.ty.subst(&map))).collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether dropping a value of `ty` runs code: a `Drop` impl of its own or of something it holds
+    pub(crate) fn needs_cleanup(&mut self, ty: &Ty) -> bool {
+        if let Some(needed) = self.cleanups.get(ty) {
+            return *needed;
+        }
+        // A type holding itself, through a vector, is settled by its other parts
+        self.cleanups.insert(ty.clone(), false);
+        let needed = match ty {
+            Ty::Tuple(elems) => elems.iter().any(|e| self.needs_cleanup(e)),
+            Ty::Array(elem, _) => self.needs_cleanup(elem),
+            Ty::Adt(_, elems) if self.is_vec(ty) => elems.first().is_some_and(|e| self.needs_cleanup(e)),
+            Ty::Adt(def, _) if self.types.adts.contains_key(def) => {
+                let count = self.types.adts[def].variants.len();
+                self.drops.contains_key(def)
+                    || (0..count).any(|i| self.fields_of(ty, i).iter().any(|(_, field_ty)| self.needs_cleanup(field_ty)))
+            }
+            _ => false,
+        };
+        self.cleanups.insert(ty.clone(), needed);
+        needed
+    }
+
+    pub(crate) fn is_enum(&self, ty: &Ty) -> bool {
+        matches!(ty, Ty::Adt(def, _) if self.res.def(*def).kind == DefKind::Enum && self.types.adts.contains_key(def))
+    }
+
+    pub(crate) fn is_vec(&self, ty: &Ty) -> bool {
+        matches!(ty, Ty::Adt(def, _) if self.is_prelude(*def, "Vec"))
+    }
+
+    /// `str`, `String` and shared references to them, which are all `ml_str`
+    pub(crate) fn is_str(&self, ty: &Ty) -> bool {
+        match ty {
+            Ty::Prim(PrimitiveType::Str) => true,
+            Ty::Ref { ty, mutable: false } => self.is_str(ty),
+            Ty::Adt(def, _) => self.is_prelude(*def, "String"),
+            _ => false,
+        }
+    }
+
+    /// Whether `ty` is represented by a C pointer
+    pub(crate) fn is_pointer(&self, ty: &Ty) -> bool {
+        match ty {
+            Ty::Ref { ty, mutable } => *mutable || !self.is_str(ty),
+            Ty::Adt(def, _) => self.is_prelude(*def, "Box"),
+            _ => false,
+        }
+    }
+
+    /// What a pointer type points to
+    pub(crate) fn pointee(&self, ty: &Ty) -> Option<Ty> {
+        match ty {
+            Ty::Ref { ty: inner, .. } if self.is_pointer(ty) => Some((**inner).clone()),
+            Ty::Adt(_, args) if self.is_pointer(ty) => args.first().cloned(),
+            _ => None,
+        }
+    }
+
+    /// Strip references from a type, leaving `str` for `&str`
+    pub(crate) fn peel(&self, ty: &Ty) -> Ty {
+        match ty {
+            Ty::Ref { ty, .. } => self.peel(ty),
+            Ty::Adt(_, args) if self.is_pointer(ty) => args.first().map_or(Ty::Error, |inner| self.peel(inner)),
+            _ => ty.clone(),
+        }
+    }
+
+    // ========== Inference ==========
+
+    // Checking leaves the results of library calls open, since the library
+    // has no signatures. The backends need them, so they are filled in from
+    // what each call is known to return, in the order the body runs.
+
+    pub(crate) fn infer_block(&self, scope: &mut Scope, block: &Block) {
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StatementKind::Let { pattern, init: Some(init), .. } => {
+                    self.infer_expr(scope, init);
+                    if has_infer(&self.ty(scope, pattern.id)) {
+                        let ty = self.ty(scope, init.id);
+                        scope.inferred.insert(pattern.id, ty);
+                    }
+                }
+                StatementKind::Expression(expr) => self.infer_expr(scope, expr),
+                _ => {}
+            }
+        }
+        if let Some(tail) = &block.expr {
+            self.infer_expr(scope, tail);
+        }
+    }
+
+    pub(crate) fn infer_expr(&self, scope: &mut Scope, expr: &Expression) {
+        match &expr.kind {
+            ExpressionKind::Binary { left, right, .. } => {
+                self.infer_expr(scope, left);
+                self.infer_expr(scope, right);
+            }
+            ExpressionKind::Unary { expr: inner, .. }
+            | ExpressionKind::Field { expr: inner, .. }
+            | ExpressionKind::Comptime(inner) => self.infer_expr(scope, inner),
+            ExpressionKind::Return(Some(inner)) | ExpressionKind::Break(Some(inner)) => self.infer_expr(scope, inner),
+            ExpressionKind::Call { func, args } => {
+                self.infer_expr(scope, func);
+                args.iter().for_each(|arg| self.infer_expr(scope, arg));
+            }
+            ExpressionKind::MethodCall { receiver, args, .. } => {
+                self.infer_expr(scope, receiver);
+                args.iter().for_each(|arg| self.infer_expr(scope, arg));
+            }
+            ExpressionKind::Tuple(elems) | ExpressionKind::Array(elems) => {
+                elems.iter().for_each(|elem| self.infer_expr(scope, elem));
+            }
+            ExpressionKind::Index { expr: base, index } => {
+                self.infer_expr(scope, base);
+                self.infer_expr(scope, index);
+            }
+            ExpressionKind::Struct { fields, .. } => fields.iter().for_each(|(_, value)| self.infer_expr(scope, value)),
+            ExpressionKind::If { cond, then_block, else_block } => {
+                self.infer_expr(scope, cond);
+                self.infer_block(scope, then_block);
+                if let Some(block) = else_block {
+                    self.infer_block(scope, block);
+                }
+            }
+            ExpressionKind::Match { expr: scrutinee, arms } => {
+                self.infer_expr(scope, scrutinee);
+                let ty = self.ty(scope, scrutinee.id);
+                for arm in arms {
+                    self.infer_pattern(scope, &arm.pattern, &ty);
+                    if let Some(guard) = &arm.guard {
+                        self.infer_expr(scope, guard);
+                    }
+                    self.infer_expr(scope, &arm.body);
+                }
+            }
+            ExpressionKind::Loop(body) | ExpressionKind::Block(body) => self.infer_block(scope, body),
+            ExpressionKind::While { cond, body } => {
+                self.infer_expr(scope, cond);
+                self.infer_block(scope, body);
+            }
+            ExpressionKind::For { pattern, iter, body } => {
+                self.infer_expr(scope, iter);
+                if has_infer(&self.ty(scope, pattern.id)) {
+                    if let Some(ty) = self.element_ty(scope, iter) {
+                        scope.inferred.insert(pattern.id, ty);
+                    }
+                }
+                self.infer_block(scope, body);
+            }
+            _ => {}
+        }
+        if has_infer(&self.ty(scope, expr.id)) {
+            if let Some(ty) = self.synth(scope, expr) {
+                scope.inferred.insert(expr.id, ty);
+            }
+        }
+    }
+
+    /// Types of the names `pattern` binds, when matching a value of `ty` checking left open
+    fn infer_pattern(&self, scope: &mut Scope, pattern: &ast::Pattern, ty: &Ty) {
+        let ty = self.peel(ty);
+        if has_infer(&ty) {
+            return;
+        }
+        let variant = || match self.res.res(pattern.id) {
+            Some(Res::Def(def)) => self.variant_of(*def).map_or(0, |(_, index)| index),
+            _ => 0,
+        };
+        match &pattern.kind {
+            PatternKind::Identifier(_)
+                if self.res.def_of_node(pattern.id).is_some() && has_infer(&self.ty(scope, pattern.id)) =>
+            {
+                scope.inferred.insert(pattern.id, ty);
+            }
+            PatternKind::Tuple(elems) => {
+                if let Ty::Tuple(elem_tys) = &ty {
+                    for (elem, elem_ty) in elems.iter().zip(elem_tys) {
+                        self.infer_pattern(scope, elem, elem_ty);
+                    }
+                }
+            }
+            PatternKind::TupleStruct { elems, .. } => {
+                for (elem, (_, field_ty)) in elems.iter().zip(self.fields_of(&ty, variant())) {
+                    self.infer_pattern(scope, elem, &field_ty);
+                }
+            }
+            PatternKind::Struct { fields, .. } => {
+                let field_tys = self.fields_of(&ty, variant());
+                for (name, sub) in fields {
+                    if let Some((_, field_ty)) = field_tys.iter().find(|(field, _)| field == name) {
+                        self.infer_pattern(scope, sub, field_ty);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// What a `for` loop over `iter` binds: references for `iter()` and `&v`, values otherwise
+    fn element_ty(&self, scope: &Scope, iter: &Expression) -> Option<Ty> {
+        let (source, borrow) = match &iter.kind {
+            ExpressionKind::MethodCall { receiver, method, args } if args.is_empty() => match method.as_str() {
+                "iter" => (&**receiver, Some(false)),
+                "iter_mut" => (&**receiver, Some(true)),
+                "into_iter" => (&**receiver, None),
+                "chars" => return Some(Ty::Prim(PrimitiveType::Char)),
+                _ => return None,
+            },
+            ExpressionKind::Unary { op: UnaryOp::Ref, expr } => (&**expr, Some(false)),
+            ExpressionKind::Unary { op: UnaryOp::RefMut, expr } => (&**expr, Some(true)),
+            _ => (iter, None),
+        };
+        let source_ty = self.ty(scope, source.id);
+        let elem = match self.peel(&source_ty) {
+            Ty::Array(elem, _) => *elem,
+            Ty::Adt(_, args) if self.is_vec(&self.peel(&source_ty)) => args.first()?.clone(),
+            _ => return None,
+        };
+        let borrow = borrow.or_else(|| matches!(source_ty, Ty::Ref { mutable, .. } if !mutable).then_some(false));
+        let borrow = borrow.or(match source_ty {
+            Ty::Ref { mutable: true, .. } => Some(true),
+            _ => None,
+        });
+        Some(match borrow {
+            Some(mutable) => Ty::Ref { ty: Box::new(elem), mutable },
+            None => elem,
+        })
+    }
+
+    /// The type of an expression checking left open
+    fn synth(&self, scope: &Scope, expr: &Expression) -> Option<Ty> {
+        let string = || self.prelude_ty("String", Vec::new());
+        match &expr.kind {
+            ExpressionKind::Identifier(_) => match self.res.res(expr.id) {
+                Some(Res::Def(def)) => Some(self.ty(scope, self.res.def(*def).node)),
+                _ => None,
+            },
+            ExpressionKind::Field { expr: base, field } => {
+                let base = self.peel(&self.ty(scope, base.id));
+                match &base {
+                    Ty::Tuple(elems) => elems.get(field.parse::<usize>().ok()?).cloned(),
+                    _ => self.fields_of(&base, 0).into_iter().find(|(name, _)| name == field).map(|(_, ty)| ty),
+                }
+            }
+            ExpressionKind::Index { expr: base, .. } => match self.peel(&self.ty(scope, base.id)) {
+                Ty::Array(elem, _) => Some(*elem),
+                Ty::Adt(_, args) => args.first().cloned(),
+                _ => None,
+            },
+            ExpressionKind::Unary { op, expr: inner } => {
+                let ty = self.ty(scope, inner.id);
+                match op {
+                    UnaryOp::Deref => self.pointee(&ty),
+                    UnaryOp::Ref => Some(Ty::Ref { ty: Box::new(ty), mutable: false }),
+                    UnaryOp::RefMut => Some(Ty::Ref { ty: Box::new(ty), mutable: true }),
+                    _ => Some(ty),
+                }
+            }
+            ExpressionKind::Binary { left, op, .. } => match op {
+                BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => {
+                    Some(Ty::bool())
+                }
+                BinaryOp::Assign => Some(Ty::unit()),
+                _ => Some(self.peel(&self.ty(scope, left.id))),
+            },
+            ExpressionKind::Call { func, args } => {
+                let path = match self.res.res(func.id) {
+                    Some(Res::Partial { .. }) => self.partial(func).err()?,
+                    Some(Res::Def(def)) if self.res.def(*def).kind == DefKind::External => {
+                        self.res.def(*def).name.clone()
+                    }
+                    _ => return None,
+                };
+                let owner_name: Vec<&str> = path.rsplit("::").take(2).collect();
+                match owner_name.as_slice() {
+                    ["new" | "from", "String"] => Some(string()),
+                    ["args", "env"] => Some(self.prelude_ty("Vec", vec![string()])),
+                    ["exit", "process"] => Some(Ty::Never),
+                    ["new", "Box"] => Some(self.prelude_ty("Box", vec![self.ty(scope, args.first()?.id)])),
+                    ["from", "Vec"] => match self.peel(&self.ty(scope, args.first()?.id)) {
+                        Ty::Array(elem, _) => Some(self.prelude_ty("Vec", vec![*elem])),
+                        _ => None,
+                    },
+                    ["read_to_string", "fs"] | ["read_to_string"] => {
+                        Some(self.prelude_ty("Result", vec![string(), string()]))
+                    }
+                    ["write", "fs"] | ["write"] => Some(self.prelude_ty("Result", vec![Ty::unit(), string()])),
+                    ["swap", "mem"] => Some(Ty::unit()),
+                    ["replace", "mem"] => Some(self.ty(scope, args.get(1)?.id)),
+                    _ => None,
+                }
+            }
+            ExpressionKind::MethodCall { receiver, method, .. } => {
+                let base = self.peel(&self.ty(scope, receiver.id));
+                self.method_ty(&base, method)
+            }
+            _ => None,
+        }
+    }
+
+    /// What a method of a library type returns
+    fn method_ty(&self, base: &Ty, method: &str) -> Option<Ty> {
+        let string = || self.prelude_ty("String", Vec::new());
+        let usize = Ty::Prim(PrimitiveType::Usize);
+        match method {
+            "clone" | "to_owned" => return Some(base.clone()),
+            "is_empty" | "contains" | "starts_with" | "ends_with" | "is_some" | "is_none" | "is_ok" | "is_err"
+            | "eq" => return Some(Ty::bool()),
+            "push" | "push_str" | "clear" | "insert" | "sort" | "reverse" => return Some(Ty::unit()),
+            _ => {}
+        }
+        if self.is_str(base) {
+            return match method {
+                "len" => Some(usize),
+                "trim" | "as_str" => Some(Ty::Ref { ty: Box::new(Ty::Prim(PrimitiveType::Str)), mutable: false }),
+                "to_string" | "to_uppercase" | "to_lowercase" | "into" => Some(string()),
+                _ => None,
+            };
+        }
+        match base {
+            Ty::Array(..) if method == "len" => Some(usize),
+            Ty::Prim(_) if method == "to_string" => Some(string()),
+            Ty::Prim(_) => Some(base.clone()),
+            Ty::Adt(_, elems) if self.is_vec(base) => {
+                let elem = elems.first()?.clone();
+                let borrowed = Ty::Ref { ty: Box::new(elem.clone()), mutable: false };
+                match method {
+                    "len" => Some(usize),
+                    "pop" => Some(self.prelude_ty("Option", vec![elem])),
+                    "get" | "first" | "last" => Some(self.prelude_ty("Option", vec![borrowed])),
+                    "remove" => Some(elem),
+                    _ => None,
+                }
+            }
+            Ty::Adt(def, elems) if self.is_prelude(*def, "Option") || self.is_prelude(*def, "Result") => match method {
+                "unwrap" | "expect" | "unwrap_or" => elems.first().cloned(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // ========== Instances ==========
+
+    /// The function a call of `def` runs and its generic arguments, given
+    /// the types of the arguments and the result. A trait's function runs
+    /// the impl for the type `Self` turns out to be, or else its default body.
+    pub(crate) fn resolve(&self, def: DefId, inputs: &[Ty], output: &Ty) -> Option<(DefId, Vec<Ty>)> {
+        let sig = self.types.sigs.get(&def)?;
+        let mut map = HashMap::new();
+        for (param, arg) in sig.inputs.iter().zip(inputs) {
+            bind(param, arg, &mut map);
+        }
+        bind(&sig.output, output, &mut map);
+        if let Some(trait_def) = self.trait_of(def) {
+            let self_ty = sig.generics.first().and_then(|param| map.get(param));
+            if let Some(key) = self_ty.and_then(|ty| self.key_of(ty)) {
+                let name = &self.res.def(def).name;
+                let method =
+                    self.methods.iter().find(|m| m.key == key && m.trait_def == Some(trait_def) && &m.name == name);
+                if let Some(method) = method {
+                    return self.resolve(method.def, inputs, output);
+                }
+            }
+            // A signature without a default body
+            if !self.fns.contains_key(&def) {
+                return None;
+            }
+        }
+        let args = sig.generics.iter().map(|g| map.get(g).cloned().unwrap_or_else(Ty::unit)).collect();
+        Some((def, args))
+    }
+
+    /// The instance of `def` that arguments of types `inputs` and a result of
+    /// type `output` select, with its parameter and result types
+    pub(crate) fn instance(&self, def: DefId, inputs: &[Ty], output: &Ty) -> Option<Instance> {
+        let (def, args) = self.resolve(def, inputs, output)?;
+        let sig = &self.types.sigs[&def];
+        let map: HashMap<DefId, Ty> = sig.generics.iter().copied().zip(args.iter().cloned()).collect();
+        let params = sig.inputs.iter().map(|t| t.subst(&map)).collect();
+        let output = sig.output.subst(&map);
+        Some(Instance { def, args, params, output })
+    }
+
+    /// The crate's function method call `call` resolved to, if any
+    pub(crate) fn user_method(&self, call: &Expression) -> Option<DefId> {
+        self.types.method_calls.get(&call.id).copied().filter(|def| self.is_user_fn(*def))
+    }
+
+    /// What the callee of a call names
+    pub(crate) fn callee(&self, func: &Expression) -> Callee {
+        let def = match self.res.res(func.id) {
+            Some(Res::Def(def)) => *def,
+            Some(Res::Partial { .. }) => match self.partial(func) {
+                Ok(def) => def,
+                Err(path) => return Callee::Library(path),
+            },
+            None => return Callee::Value,
+        };
+        let definition = self.res.def(def);
+        match definition.kind {
+            DefKind::Function if self.is_user_fn(def) => Callee::Fn(def),
+            DefKind::Variant | DefKind::Struct => Callee::Ctor(def, self.variant_of(def).map_or(0, |(_, index)| index)),
+            DefKind::Function if definition.is_prelude() => Callee::Prelude(definition.name.clone()),
+            DefKind::External => Callee::Library(definition.name.clone()),
+            _ => Callee::Value,
+        }
+    }
+
+    fn key_of(&self, ty: &Ty) -> Option<Key> {
+        match self.peel(ty) {
+            Ty::Prim(prim) => Some(Key::Prim(prim)),
+            Ty::Adt(def, _) => Some(Key::Def(def)),
+            _ => None,
+        }
+    }
+
+    /// A method of the type of a value, by name, whichever impl has it
+    pub(crate) fn method_of(&self, ty: &Ty, name: &str) -> Option<DefId> {
+        let key = self.key_of(ty)?;
+        self.methods.iter().find(|m| m.key == key && m.name == name).map(|m| m.def)
+    }
+
+    /// What a path that resolved only in part names: a function of the crate, or else a library path
+    pub(crate) fn partial(&self, expr: &Expression) -> Result<DefId, String> {
+        resolve_partial(self.res, self.types, &self.self_types, expr, |key, name| {
+            self.methods.iter().find(|m| m.key == key && m.name == name).map(|m| m.def)
+        })
+    }
+}
+
+/// Bind the generic parameters in `pattern` to the parts of `actual` they
+/// stand for, looking through the references calls add or remove
+pub(crate) fn bind(pattern: &Ty, actual: &Ty, map: &mut HashMap<DefId, Ty>) {
+    match (pattern, actual) {
+        (Ty::Param(param), _) => {
+            map.entry(*param).or_insert_with(|| actual.clone());
+        }
+        (Ty::Ref { ty: p, .. }, Ty::Ref { ty: a, .. }) => bind(p, a, map),
+        (Ty::Ref { ty: p, .. }, _) => bind(p, actual, map),
+        (_, Ty::Ref { ty: a, .. }) => bind(pattern, a, map),
+        (Ty::Tuple(ps), Ty::Tuple(actuals)) | (Ty::Adt(_, ps), Ty::Adt(_, actuals)) => {
+            for (p, a) in ps.iter().zip(actuals) {
+                bind(p, a, map);
+            }
+        }
+        (Ty::Array(p, _), Ty::Array(a, _)) => bind(p, a, map),
+        (Ty::Fn(ps, p), Ty::Fn(actuals, a)) => {
+            for (p, a) in ps.iter().zip(actuals) {
+                bind(p, a, map);
+            }
+            bind(p, a, map);
+        }
+        _ => {}
+    }
+}
+
+pub(crate) fn prim_name(prim: PrimitiveType) -> &'static str {
+    use PrimitiveType::*;
+    match prim {
+        I8 => "i8",
+        I16 => "i16",
+        I32 => "i32",
+        I64 => "i64",
+        I128 => "i128",
+        Isize => "isize",
+        U8 => "u8",
+        U16 => "u16",
+        U32 => "u32",
+        U64 => "u64",
+        U128 => "u128",
+        Usize => "usize",
+        F32 => "f32",
+        F64 => "f64",
+        Bool => "bool",
+        Char => "char",
+        Str => "str",
+        Unit => "unit",
+        Never => "never",
+    }
+}
+
+pub(crate) fn is_unsigned(prim: PrimitiveType) -> bool {
+    use PrimitiveType::*;
+    matches!(prim, U8 | U16 | U32 | U64 | U128 | Usize)
+}
+
+/// Whether checking left part of `ty` open
+pub(crate) fn has_infer(ty: &Ty) -> bool {
+    match ty {
+        Ty::Infer(_) | Ty::Error => true,
+        Ty::Tuple(elems) | Ty::Adt(_, elems) => elems.iter().any(has_infer),
+        Ty::Array(elem, _) | Ty::Ref { ty: elem, .. } => has_infer(elem),
+        Ty::Fn(params, ret) => params.iter().any(has_infer) || has_infer(ret),
+        _ => false,
+    }
+}
@@ -0,0 +1,235 @@
+// Native backend
+// Compiles a crate to an object file with Cranelift and links it into an
+// executable with the system linker. The module the WebAssembly backend
+// emits is translated function by function: its linear memory becomes a
+// region mapped at start, and the WASI calls it imports are answered by a
+// small C runtime over libc, compiled as the executable is linked, so
+// programs behave as they do under a WASI runtime.
+// Statements keep the lines they came from, which go out as DWARF line
+// tables. Executables are for the host, which must be 64-bit Linux.
+
+mod dwarf;
+mod translate;
+
+use crate::wasm;
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_module::default_libcall_names;
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use my_lang_ast as ast;
+use my_lang_diagnostics::Diagnostic;
+use my_lang_resolve::Resolution;
+use my_lang_typechecker::TypeckResults;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use thiserror::Error;
+
+/// How much work Cranelift puts into the code, as `-O` chooses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    /// Code as it is translated, fastest to compile
+    #[default]
+    None,
+    Speed,
+    SpeedAndSize,
+}
+
+impl OptLevel {
+    /// The value of Cranelift's `opt_level` setting
+    pub fn setting(self) -> &'static str {
+        match self {
+            OptLevel::None => "none",
+            OptLevel::Speed => "speed",
+            OptLevel::SpeedAndSize => "speed_and_size",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LinkError {
+    #[error("cannot run the linker `{0}`: {1}")]
+    Spawn(String, std::io::Error),
+    #[error("linking failed:\n{0}")]
+    Failed(String),
+}
+
+/// Compile the crate whose `main` is in the first of `programs` to an object
+/// file for the host. `files` are the names of the source files spans refer
+/// to, which panics report and the line tables name. The object is only
+/// made when there are no errors; warnings come back beside it.
+pub fn emit(
+    programs: &[&ast::Program],
+    res: &Resolution,
+    types: &TypeckResults,
+    files: &[String],
+    opt: OptLevel,
+) -> (Vec<u8>, Vec<Diagnostic>) {
+    let (module, lines, mut diags) = wasm::emit_with_lines(programs, res, types, files);
+    if diags.iter().any(Diagnostic::is_error) {
+        return (Vec::new(), diags);
+    }
+    match compile(&module.binary, &lines, files, opt) {
+        Ok(object) => (object, diags),
+        Err(message) => {
+            diags.push(Diagnostic::error(format!("internal error: {}", message)));
+            (Vec::new(), diags)
+        }
+    }
+}
+
+/// The support code objects are linked with
+const RUNTIME: &str = include_str!("native/runtime.c");
+
+/// Link `object` and the runtime into the executable `output` with the
+/// system C compiler's driver, which knows where the C library and start
+/// files are. `CC` chooses another driver.
+pub fn link(object: &Path, output: &Path) -> Result<(), LinkError> {
+    let linker = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let spawn = |e| LinkError::Spawn(linker.clone(), e);
+    // The runtime is read from standard input as C, the object as itself
+    let mut child = Command::new(&linker)
+        .args(["-x", "c", "-", "-x", "none"])
+        .arg(object)
+        .arg("-o")
+        .arg(output)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(spawn)?;
+    child.stdin.take().expect("stdin is piped").write_all(RUNTIME.as_bytes()).map_err(spawn)?;
+    let linked = child.wait_with_output().map_err(spawn)?;
+    if !linked.status.success() {
+        return Err(LinkError::Failed(String::from_utf8_lossy(&linked.stderr).into_owned()));
+    }
+    Ok(())
+}
+
+fn compile(binary: &[u8], lines: &[ast::Span], files: &[String], opt: OptLevel) -> Result<Vec<u8>, String> {
+    let wasm = translate::Module::parse(binary)?;
+    let builder = ObjectBuilder::new(host(opt)?, "solo", default_libcall_names()).map_err(|e| e.to_string())?;
+    let mut object = ObjectModule::new(builder);
+    let env = translate::Env::declare(&mut object, &wasm)?;
+    let mut compiled = Vec::new();
+    for index in 0..wasm.bodies.len() {
+        compiled.push(translate::define(&mut object, &wasm, &env, index)?);
+    }
+    let mut product = object.finish();
+    dwarf::write(&mut product, files, lines, &compiled)?;
+    product.emit().map_err(|e| e.to_string())
+}
+
+/// The host's instruction set, with `opt_level` set
+fn host(opt: OptLevel) -> Result<OwnedTargetIsa, String> {
+    let mut flags = settings::builder();
+    flags.set("opt_level", opt.setting()).map_err(|e| e.to_string())?;
+    // Executables are position independent by default on Linux
+    flags.set("is_pic", "true").map_err(|e| e.to_string())?;
+    let isa = cranelift_native::builder().map_err(|e| e.to_string())?;
+    isa.finish(settings::Flags::new(flags)).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{check_source, missing, SHAPES, TOTALS};
+
+    fn emit_source(source: &str, opt: OptLevel) -> (Vec<u8>, Vec<Diagnostic>) {
+        let (program, res, types) = check_source(source);
+        emit(&[&program], &res, &types, &["test.solo".to_string()], opt)
+    }
+
+    /// Exit status, stdout and stderr of the executable linked from
+    /// `source`, run with `args` in a directory of its own, or `None` where
+    /// there is no C compiler to link with
+    fn run(source: &str, opt: OptLevel, args: &[&str]) -> Option<(i32, String, String)> {
+        let (object, diags) = emit_source(source, opt);
+        assert!(diags.is_empty(), "{:?}", diags);
+        let dir = tempfile::tempdir().unwrap();
+        let (object_path, binary) = (dir.path().join("test.o"), dir.path().join("test"));
+        std::fs::write(&object_path, &object).unwrap();
+        match link(&object_path, &binary) {
+            Ok(()) => {}
+            Err(LinkError::Spawn(linker, _)) => return missing(&linker),
+            Err(e) => panic!("{}", e),
+        }
+        let ran = Command::new(&binary).current_dir(dir.path()).args(args).output().unwrap();
+        let (out, err) = (String::from_utf8(ran.stdout).unwrap(), String::from_utf8(ran.stderr).unwrap());
+        Some((ran.status.code().unwrap_or(-1), out, err))
+    }
+
+    #[test]
+    fn test_object_has_line_tables() {
+        let (object, diags) = emit_source("fn main() { println(\"hello\"); }", OptLevel::None);
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert!(object.starts_with(b"\x7fELF"));
+        assert!(object.windows(11).any(|w| w == b".debug_line"));
+        let Some((status, out, _)) = run("fn main() { println(\"hello\"); }", OptLevel::None, &[]) else { return };
+        assert_eq!(status, 0);
+        assert_eq!(out, "hello\n");
+    }
+
+    #[test]
+    fn test_enums_and_formatting_at_each_level() {
+        for opt in [OptLevel::None, OptLevel::Speed, OptLevel::SpeedAndSize] {
+            SHAPES.check(run(SHAPES.source, opt, &[]));
+        }
+    }
+
+    #[test]
+    fn test_generics_and_vectors() {
+        TOTALS.check(run(TOTALS.source, OptLevel::Speed, &[]));
+    }
+
+    #[test]
+    fn test_panics_report_location_and_exit_status() {
+        let source = r#"
+            fn withdraw(balance: i64, amount: i64) -> i64
+                pre amount <= balance
+            {
+                balance - amount
+            }
+
+            fn main() {
+                let v: Vec<i32> = Vec::from([4, 5, 6]);
+                println("{} {}", withdraw(10, 3), v[1]);
+                println("{}", v[3]);
+            }
+        "#;
+        let Some((status, out, err)) = run(source, OptLevel::Speed, &[]) else { return };
+        assert_eq!((status, out.as_str()), (101, "7 5\n"));
+        assert_eq!(
+            err,
+            "thread 'main' panicked at test.solo:11:31:\nindex out of bounds: the len is 3 but the index is 3\n"
+        );
+    }
+
+    #[test]
+    fn test_files_and_arguments() {
+        let source = r#"
+            import std::env;
+            import std::fs;
+
+            fn main() {
+                let args: Vec<String> = env::args();
+                println("{} {}", args.len(), args[1]);
+                match fs::write("out.txt", "saved") {
+                    Ok(_) => println("wrote"),
+                    Err(e) => println("{}", e),
+                }
+                match fs::read_to_string("out.txt") {
+                    Ok(text) => println("read {}", text),
+                    Err(e) => println("{}", e),
+                }
+                match fs::read_to_string("missing.txt") {
+                    Ok(text) => println("read {}", text),
+                    Err(e) => println("{}", e),
+                }
+            }
+        "#;
+        let Some((status, out, _)) = run(source, OptLevel::None, &["hello"]) else { return };
+        assert_eq!(status, 0);
+        assert_eq!(out, "2 hello\nwrote\nread saved\nNo such file or directory (os error 2)\n");
+    }
+}
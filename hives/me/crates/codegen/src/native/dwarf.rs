@@ -0,0 +1,178 @@
+// DWARF line tables for native objects
+// One compilation unit covers every function, with a subprogram for each and
+// a row for each statement whose code Cranelift kept apart. Addresses are
+// relative to the functions' symbols and offsets to the debug sections', so
+// the object carries relocations the linker resolves.
+
+use super::translate::Compiled;
+use cranelift_object::ObjectProduct;
+use gimli::write::{
+    Address, AttributeValue, DwarfUnit, EndianVec, LineProgram, LineString, Range, RangeList, Sections, Writer,
+};
+use gimli::{Encoding, Format, LineEncoding, RunTimeEndian, SectionId};
+use my_lang_ast as ast;
+use object::write::{Relocation, SymbolId};
+use object::{RelocationEncoding, RelocationFlags, RelocationKind, SectionKind};
+use std::collections::HashMap;
+
+/// Add debug sections to `product` giving the source lines of `compiled`,
+/// whose marks are indices into `lines`
+pub(super) fn write(
+    product: &mut ObjectProduct,
+    files: &[String],
+    lines: &[ast::Span],
+    compiled: &[Compiled],
+) -> Result<(), String> {
+    let encoding = Encoding { format: Format::Dwarf32, version: 4, address_size: 8 };
+    let dir = std::env::current_dir().map(|dir| dir.display().to_string()).unwrap_or_else(|_| ".".to_string());
+    let main = files.first().cloned().unwrap_or_else(|| "<unknown>".to_string());
+    let mut dwarf = DwarfUnit::new(encoding);
+    let mut program = LineProgram::new(
+        encoding,
+        LineEncoding::default(),
+        LineString::String(dir.clone().into_bytes()),
+        LineString::String(main.clone().into_bytes()),
+        None,
+    );
+    let directory = program.default_directory();
+    let file_ids: Vec<_> = files
+        .iter()
+        .map(|file| program.add_file(LineString::String(file.clone().into_bytes()), directory, None))
+        .collect();
+
+    // Address::Symbol refers to functions by their place in `compiled`
+    let symbols: Vec<SymbolId> = compiled.iter().map(|c| product.function_symbol(c.func)).collect();
+    let mut ranges = Vec::new();
+    let root = dwarf.unit.root();
+    for (symbol, function) in compiled.iter().enumerate() {
+        let start = Address::Symbol { symbol, addend: 0 };
+        program.begin_sequence(Some(start));
+        for (offset, _, mark) in &function.lines {
+            let Some(span) = lines.get(*mark as usize) else { continue };
+            let Some(file) = file_ids.get(span.file.0 as usize) else { continue };
+            let row = program.row();
+            row.address_offset = *offset as u64;
+            row.file = *file;
+            row.line = span.line as u64;
+            row.column = span.column as u64;
+            program.generate_row();
+        }
+        program.end_sequence(function.size as u64);
+        ranges.push(Range::StartLength { begin: start, length: function.size as u64 });
+
+        let die = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+        let entry = dwarf.unit.get_mut(die);
+        entry.set(gimli::DW_AT_name, AttributeValue::String(function.name.clone().into_bytes()));
+        entry.set(gimli::DW_AT_low_pc, AttributeValue::Address(start));
+        entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(function.size as u64));
+    }
+    dwarf.unit.line_program = program;
+    let ranges = dwarf.unit.ranges.add(RangeList(ranges));
+    let entry = dwarf.unit.get_mut(root);
+    entry.set(gimli::DW_AT_producer, AttributeValue::String(b"my-lang".to_vec()));
+    entry.set(gimli::DW_AT_name, AttributeValue::String(main.into_bytes()));
+    entry.set(gimli::DW_AT_comp_dir, AttributeValue::String(dir.into_bytes()));
+    entry.set(gimli::DW_AT_low_pc, AttributeValue::Address(Address::Constant(0)));
+    entry.set(gimli::DW_AT_ranges, AttributeValue::RangeListRef(ranges));
+
+    let mut sections = Sections::new(Relocating::default());
+    dwarf.write(&mut sections).map_err(|e| e.to_string())?;
+
+    let object = &mut product.object;
+    let mut ids = HashMap::new();
+    sections.for_each(|id, section| -> Result<(), String> {
+        if !section.data.slice().is_empty() {
+            let section = object.add_section(Vec::new(), id.name().as_bytes().to_vec(), SectionKind::Debug);
+            ids.insert(id, section);
+        }
+        Ok(())
+    })?;
+    sections.for_each(|id, section| -> Result<(), String> {
+        let Some(&out) = ids.get(&id) else { return Ok(()) };
+        object.append_section_data(out, section.data.slice(), 1);
+        for reloc in &section.relocs {
+            let symbol = match reloc.target {
+                Target::Symbol(index) => symbols[index],
+                Target::Section(id) => object.section_symbol(ids[&id]),
+            };
+            let flags = RelocationFlags::Generic {
+                kind: RelocationKind::Absolute,
+                encoding: RelocationEncoding::Generic,
+                size: reloc.size * 8,
+            };
+            object
+                .add_relocation(out, Relocation { offset: reloc.offset, symbol, addend: reloc.addend, flags })
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    })
+}
+
+/// A section being written, and the relocations in it
+#[derive(Clone)]
+struct Relocating {
+    data: EndianVec<RunTimeEndian>,
+    relocs: Vec<Reloc>,
+}
+
+impl Default for Relocating {
+    fn default() -> Self {
+        Relocating { data: EndianVec::new(RunTimeEndian::Little), relocs: Vec::new() }
+    }
+}
+
+#[derive(Clone)]
+struct Reloc {
+    offset: u64,
+    size: u8,
+    target: Target,
+    addend: i64,
+}
+
+#[derive(Clone, Copy)]
+enum Target {
+    Symbol(usize),
+    Section(SectionId),
+}
+
+impl Writer for Relocating {
+    type Endian = RunTimeEndian;
+
+    fn endian(&self) -> RunTimeEndian {
+        self.data.endian()
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write(bytes)
+    }
+
+    fn write_at(&mut self, offset: usize, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write_at(offset, bytes)
+    }
+
+    fn write_address(&mut self, address: Address, size: u8) -> gimli::write::Result<()> {
+        match address {
+            Address::Constant(value) => self.write_udata(value, size),
+            Address::Symbol { symbol, addend } => {
+                let offset = self.len() as u64;
+                self.relocs.push(Reloc { offset, size, target: Target::Symbol(symbol), addend });
+                self.write_udata(0, size)
+            }
+        }
+    }
+
+    fn write_offset(&mut self, val: usize, section: SectionId, size: u8) -> gimli::write::Result<()> {
+        let offset = self.len() as u64;
+        self.relocs.push(Reloc { offset, size, target: Target::Section(section), addend: val as i64 });
+        self.write_udata(0, size)
+    }
+
+    fn write_offset_at(&mut self, offset: usize, val: usize, section: SectionId, size: u8) -> gimli::write::Result<()> {
+        self.relocs.push(Reloc { offset: offset as u64, size, target: Target::Section(section), addend: val as i64 });
+        self.write_udata_at(offset, 0, size)
+    }
+}
@@ -0,0 +1,195 @@
+/* Support code for native executables made from Solo: the linear memory the
+ * translated module addresses, and the WASI calls it imports, answered with
+ * libc. The object supplies solo_start, the memory's initial pages and the
+ * image of its data segments.
+ *
+ * Linear memory is a reservation large enough for any 32-bit index plus any
+ * 32-bit offset, of which only the pages in use are accessible. File
+ * descriptors 3 and 4 are preopened on `.` and `/`, as a WASI runtime given
+ * both directories would. */
+
+#include <errno.h>
+#include <fcntl.h>
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/mman.h>
+#include <unistd.h>
+
+#define PAGE 65536u
+#define MAX_PAGES 65536u
+#define RESERVED (2 * (uint64_t)MAX_PAGES * PAGE)
+
+uint8_t *solo_memory;
+extern uint32_t solo_pages;
+extern const uint8_t solo_image[];
+extern const uint32_t solo_image_at;
+extern const uint32_t solo_image_len;
+void solo_start(void);
+
+static int solo_argc;
+static char **solo_argv;
+
+static uint32_t load32(uint32_t at) {
+    uint32_t value;
+    memcpy(&value, solo_memory + at, 4);
+    return value;
+}
+
+static void store32(uint32_t at, uint32_t value) {
+    memcpy(solo_memory + at, &value, 4);
+}
+
+/* The WASI error number for a host one, which the module turns back into the
+   host's number when it shows the error */
+static int32_t wasi_errno(int err) {
+    switch (err) {
+    case EACCES: return 2;
+    case EBADF: return 8;
+    case EEXIST: return 20;
+    case EINVAL: return 28;
+    case EISDIR: return 31;
+    case ENAMETOOLONG: return 37;
+    case ENOENT: return 44;
+    case ENOSPC: return 51;
+    case ENOTDIR: return 54;
+    case EPERM: return 63;
+    default: return 29; /* EIO */
+    }
+}
+
+int32_t solo_memory_grow(int32_t delta) {
+    uint32_t old = solo_pages;
+    uint64_t pages = (uint64_t)old + (uint32_t)delta;
+    if (pages > MAX_PAGES) return -1;
+    if (mprotect(solo_memory + (uint64_t)old * PAGE, (uint64_t)(uint32_t)delta * PAGE, PROT_READ | PROT_WRITE) != 0)
+        return -1;
+    solo_pages = (uint32_t)pages;
+    return (int32_t)old;
+}
+
+int32_t solo_wasi_fd_write(int32_t fd, int32_t iovs, int32_t count, int32_t written) {
+    uint32_t total = 0;
+    for (int32_t i = 0; i < count; i++) {
+        uint32_t buf = load32(iovs + 8 * i);
+        uint32_t len = load32(iovs + 8 * i + 4);
+        while (len > 0) {
+            ssize_t n = write(fd, solo_memory + buf, len);
+            if (n < 0) {
+                if (errno == EINTR) continue;
+                return wasi_errno(errno);
+            }
+            buf += n;
+            len -= n;
+            total += n;
+        }
+    }
+    store32(written, total);
+    return 0;
+}
+
+int32_t solo_wasi_fd_read(int32_t fd, int32_t iovs, int32_t count, int32_t read_) {
+    uint32_t total = 0;
+    for (int32_t i = 0; i < count; i++) {
+        uint32_t buf = load32(iovs + 8 * i);
+        uint32_t len = load32(iovs + 8 * i + 4);
+        ssize_t n;
+        do n = read(fd, solo_memory + buf, len);
+        while (n < 0 && errno == EINTR);
+        if (n < 0) return wasi_errno(errno);
+        total += n;
+        if ((uint32_t)n < len) break;
+    }
+    store32(read_, total);
+    return 0;
+}
+
+int32_t solo_wasi_fd_close(int32_t fd) {
+    return close(fd) == 0 ? 0 : wasi_errno(errno);
+}
+
+int32_t solo_wasi_fd_prestat_get(int32_t fd, int32_t prestat) {
+    if (fd != 3 && fd != 4) return 8;
+    solo_memory[prestat] = 0;
+    store32(prestat + 4, 1);
+    return 0;
+}
+
+int32_t solo_wasi_fd_prestat_dir_name(int32_t fd, int32_t path, int32_t len) {
+    if (fd != 3 && fd != 4) return 8;
+    if (len < 1) return 28;
+    solo_memory[path] = fd == 3 ? '.' : '/';
+    return 0;
+}
+
+int32_t solo_wasi_path_open(int32_t dir, int32_t dirflags, int32_t path, int32_t len, int32_t oflags, int64_t rights,
+                            int64_t inheriting, int32_t fdflags, int32_t opened) {
+    (void)dirflags;
+    (void)inheriting;
+    (void)fdflags;
+    /* fd_read and fd_write */
+    int readable = (rights & 2) != 0, writable = (rights & 64) != 0;
+    int flags = readable && writable ? O_RDWR : writable ? O_WRONLY : O_RDONLY;
+    if (oflags & 1) flags |= O_CREAT;
+    if (oflags & 2) flags |= O_DIRECTORY;
+    if (oflags & 4) flags |= O_EXCL;
+    if (oflags & 8) flags |= O_TRUNC;
+    char *name = malloc((uint32_t)len + 1);
+    if (!name) return 48; /* ENOMEM */
+    memcpy(name, solo_memory + (uint32_t)path, (uint32_t)len);
+    name[len] = 0;
+    int fd = openat(dir, name, flags | O_CLOEXEC, 0666);
+    int err = errno;
+    free(name);
+    if (fd < 0) return wasi_errno(err);
+    store32(opened, fd);
+    return 0;
+}
+
+int32_t solo_wasi_args_sizes_get(int32_t count, int32_t size) {
+    uint32_t total = 0;
+    for (int i = 0; i < solo_argc; i++) total += strlen(solo_argv[i]) + 1;
+    store32(count, solo_argc);
+    store32(size, total);
+    return 0;
+}
+
+int32_t solo_wasi_args_get(int32_t argv, int32_t buf) {
+    for (int i = 0; i < solo_argc; i++) {
+        uint32_t len = strlen(solo_argv[i]) + 1;
+        store32(argv + 4 * i, buf);
+        memcpy(solo_memory + (uint32_t)buf, solo_argv[i], len);
+        buf += len;
+    }
+    return 0;
+}
+
+void solo_wasi_proc_exit(int32_t code) {
+    exit(code);
+}
+
+/* Open `path` as descriptor `fd` */
+static void preopen(const char *path, int fd) {
+    int opened = open(path, O_RDONLY | O_DIRECTORY | O_CLOEXEC);
+    if (opened < 0 || opened == fd) return;
+    dup2(opened, fd);
+    close(opened);
+}
+
+int main(int argc, char **argv) {
+    solo_argc = argc;
+    solo_argv = argv;
+    void *memory = mmap(NULL, RESERVED, PROT_NONE, MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0);
+    if (memory == MAP_FAILED ||
+        mprotect(memory, (uint64_t)solo_pages * PAGE, PROT_READ | PROT_WRITE) != 0) {
+        static const char message[] = "memory allocation failed\n";
+        write(2, message, sizeof message - 1);
+        return 1;
+    }
+    solo_memory = memory;
+    memcpy(solo_memory + solo_image_at, solo_image, solo_image_len);
+    preopen(".", 3);
+    preopen("/", 4);
+    solo_start();
+    return 0;
+}
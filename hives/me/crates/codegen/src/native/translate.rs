@@ -0,0 +1,1012 @@
+// Translation of the WebAssembly backend's modules to Cranelift IR
+// Every function of the module becomes one in the object. Linear memory is
+// addressed from the base the runtime maps it at, which each function loads
+// on entry; the mapping reserves room for any 32-bit index plus offset and
+// only the pages in use are accessible, so accesses need no bounds checks.
+// A constant followed by a call to `ml.line` marks where a statement starts;
+// it becomes the source location of the code that follows and leaves none
+// of its own.
+
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::immediates::{Ieee32, Ieee64};
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, FuncRef, GlobalValue, Inst, InstBuilder, JumpTableData, MemFlags, SigRef, Signature,
+    SourceLoc, TrapCode, Type, Value,
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_module::{DataDescription, DataId, FuncId, Linkage, Module as _};
+use cranelift_object::ObjectModule;
+use std::collections::HashMap;
+use wasmparser::{
+    BlockType, ConstExpr, DataKind, ElementItems, ElementKind, ExternalKind, FuncType, FunctionBody, KnownCustom,
+    MemArg, Name, Operator, Parser, Payload, TypeRef, ValType,
+};
+
+/// What `unreachable` and failed conversions and calls stop with
+const TRAP: TrapCode = TrapCode::unwrap_user(1);
+
+/// The parts of a module the backend's modules use
+#[derive(Default)]
+pub(super) struct Module<'a> {
+    pub types: Vec<FuncType>,
+    /// The type of every function, imports first
+    pub funcs: Vec<u32>,
+    /// The module and name of every imported function
+    pub imports: Vec<(&'a str, &'a str)>,
+    pub names: HashMap<u32, &'a str>,
+    pub globals: Vec<Global>,
+    /// Pages of linear memory at start
+    pub pages: u32,
+    /// The function in each slot of the table
+    pub table: Vec<Option<u32>>,
+    /// The bytes of the data segments, which start at `image_at`
+    pub image: Vec<u8>,
+    pub image_at: u32,
+    pub bodies: Vec<FunctionBody<'a>>,
+    /// The function exported as `_start`
+    pub start: u32,
+}
+
+pub(super) struct Global {
+    pub ty: ValType,
+    pub mutable: bool,
+    /// The bits of the initial value
+    pub init: u64,
+}
+
+impl<'a> Module<'a> {
+    pub(super) fn parse(binary: &'a [u8]) -> Result<Self, String> {
+        let mut module = Module::default();
+        let mut start = None;
+        let mut segments = Vec::new();
+        for payload in Parser::new(0).parse_all(binary) {
+            match payload.map_err(error)? {
+                Payload::TypeSection(types) => {
+                    for ty in types.into_iter_err_on_gc_types() {
+                        module.types.push(ty.map_err(error)?);
+                    }
+                }
+                Payload::ImportSection(imports) => {
+                    for import in imports {
+                        let import = import.map_err(error)?;
+                        let TypeRef::Func(ty) = import.ty else {
+                            return Err(format!("unsupported import `{}.{}`", import.module, import.name));
+                        };
+                        module.funcs.push(ty);
+                        module.imports.push((import.module, import.name));
+                    }
+                }
+                Payload::FunctionSection(funcs) => {
+                    for ty in funcs {
+                        module.funcs.push(ty.map_err(error)?);
+                    }
+                }
+                Payload::TableSection(tables) => {
+                    for table in tables {
+                        module.table = vec![None; table.map_err(error)?.ty.initial as usize];
+                    }
+                }
+                Payload::MemorySection(memories) => {
+                    for memory in memories {
+                        module.pages = memory.map_err(error)?.initial as u32;
+                    }
+                }
+                Payload::GlobalSection(globals) => {
+                    for global in globals {
+                        let global = global.map_err(error)?;
+                        module.globals.push(Global {
+                            ty: global.ty.content_type,
+                            mutable: global.ty.mutable,
+                            init: constant(&global.init_expr)?,
+                        });
+                    }
+                }
+                Payload::ExportSection(exports) => {
+                    for export in exports {
+                        let export = export.map_err(error)?;
+                        if export.name == "_start" && export.kind == ExternalKind::Func {
+                            start = Some(export.index);
+                        }
+                    }
+                }
+                Payload::ElementSection(elements) => {
+                    for element in elements {
+                        let element = element.map_err(error)?;
+                        let (ElementKind::Active { offset_expr, .. }, ElementItems::Functions(funcs)) =
+                            (element.kind, element.items)
+                        else {
+                            return Err("unsupported element segment".to_string());
+                        };
+                        let at = constant(&offset_expr)? as usize;
+                        for (i, func) in funcs.into_iter().enumerate() {
+                            let slot = module
+                                .table
+                                .get_mut(at + i)
+                                .ok_or_else(|| "element segment out of bounds".to_string())?;
+                            *slot = Some(func.map_err(error)?);
+                        }
+                    }
+                }
+                Payload::DataSection(data) => {
+                    for segment in data {
+                        let segment = segment.map_err(error)?;
+                        let DataKind::Active { offset_expr, .. } = segment.kind else {
+                            return Err("unsupported passive data segment".to_string());
+                        };
+                        segments.push((constant(&offset_expr)? as u32, segment.data));
+                    }
+                }
+                Payload::CodeSectionEntry(body) => module.bodies.push(body),
+                Payload::CustomSection(section) => {
+                    if let KnownCustom::Name(names) = section.as_known() {
+                        for name in names {
+                            if let Name::Function(map) = name.map_err(error)? {
+                                for naming in map {
+                                    let naming = naming.map_err(error)?;
+                                    module.names.insert(naming.index, naming.name);
+                                }
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        module.start = start.ok_or_else(|| "the module exports no `_start`".to_string())?;
+        module.image_at = segments.iter().map(|(at, _)| *at).min().unwrap_or(0);
+        for (at, bytes) in segments {
+            let from = (at - module.image_at) as usize;
+            if module.image.len() < from + bytes.len() {
+                module.image.resize(from + bytes.len(), 0);
+            }
+            module.image[from..from + bytes.len()].copy_from_slice(bytes);
+        }
+        Ok(module)
+    }
+
+    /// The name of function `index`, as the name section gives it
+    pub(super) fn name(&self, index: u32) -> String {
+        match self.names.get(&index) {
+            Some(name) => name.to_string(),
+            None => format!("f{}", index),
+        }
+    }
+
+    fn func_type(&self, index: u32) -> &FuncType {
+        &self.types[self.funcs[index as usize] as usize]
+    }
+}
+
+/// The bits of the value of a constant expression
+fn constant(expr: &ConstExpr) -> Result<u64, String> {
+    let mut reader = expr.get_operators_reader();
+    let value = match reader.read().map_err(error)? {
+        Operator::I32Const { value } => value as u32 as u64,
+        Operator::I64Const { value } => value as u64,
+        Operator::F32Const { value } => value.bits() as u64,
+        Operator::F64Const { value } => value.bits(),
+        op => return Err(format!("unsupported constant expression {:?}", op)),
+    };
+    Ok(value)
+}
+
+fn error(e: impl std::fmt::Display) -> String {
+    e.to_string()
+}
+
+fn clif(ty: ValType) -> Result<Type, String> {
+    match ty {
+        ValType::I32 => Ok(types::I32),
+        ValType::I64 => Ok(types::I64),
+        ValType::F32 => Ok(types::F32),
+        ValType::F64 => Ok(types::F64),
+        ty => Err(format!("unsupported value type {}", ty)),
+    }
+}
+
+fn signature(object: &ObjectModule, ty: &FuncType) -> Result<Signature, String> {
+    let mut sig = object.make_signature();
+    for param in ty.params() {
+        sig.params.push(AbiParam::new(clif(*param)?));
+    }
+    for result in ty.results() {
+        sig.returns.push(AbiParam::new(clif(*result)?));
+    }
+    Ok(sig)
+}
+
+/// What the object has for the module besides its code
+pub(super) struct Env {
+    /// Every function, imports first; `ml.line` has none
+    pub funcs: Vec<Option<FuncId>>,
+    /// The index of `ml.line`
+    pub line: Option<u32>,
+    /// Where the runtime keeps the address of linear memory
+    pub memory: DataId,
+    pub pages: DataId,
+    /// The runtime's `memory.grow`
+    pub grow: FuncId,
+    /// The address of the function in each slot of the table
+    pub table: DataId,
+    /// Where each mutable global lives
+    pub globals: Vec<Option<DataId>>,
+}
+
+impl Env {
+    /// Declare every function of the module in `object`, with the runtime's
+    /// for the ones it imports, and define its data
+    pub(super) fn declare(object: &mut ObjectModule, wasm: &Module) -> Result<Env, String> {
+        let mut funcs = Vec::new();
+        let mut line = None;
+        for index in 0..wasm.funcs.len() as u32 {
+            let sig = signature(object, wasm.func_type(index))?;
+            let id = match wasm.imports.get(index as usize) {
+                Some(("ml", "line")) => {
+                    line = Some(index);
+                    None
+                }
+                Some(("wasi_snapshot_preview1", name)) => {
+                    Some(object.declare_function(&format!("solo_wasi_{}", name), Linkage::Import, &sig))
+                }
+                Some((module, name)) => return Err(format!("unknown import `{}.{}`", module, name)),
+                None if index == wasm.start => Some(object.declare_function("solo_start", Linkage::Export, &sig)),
+                None => Some(object.declare_function(&format!("solo.{}", wasm.name(index)), Linkage::Local, &sig)),
+            };
+            funcs.push(id.transpose().map_err(error)?);
+        }
+
+        let mut grow_sig = object.make_signature();
+        grow_sig.params.push(AbiParam::new(types::I32));
+        grow_sig.returns.push(AbiParam::new(types::I32));
+        let grow = object.declare_function("solo_memory_grow", Linkage::Import, &grow_sig).map_err(error)?;
+        let memory = object.declare_data("solo_memory", Linkage::Import, true, false).map_err(error)?;
+
+        let pages = define_data(object, "solo_pages", Linkage::Export, true, &wasm.pages.to_le_bytes())?;
+        define_data(object, "solo_image", Linkage::Export, false, &wasm.image)?;
+        define_data(object, "solo_image_at", Linkage::Export, false, &wasm.image_at.to_le_bytes())?;
+        define_data(object, "solo_image_len", Linkage::Export, false, &(wasm.image.len() as u32).to_le_bytes())?;
+
+        let table = object.declare_data("solo.table", Linkage::Local, false, false).map_err(error)?;
+        let mut desc = DataDescription::new();
+        desc.define(vec![0; wasm.table.len().max(1) * 8].into());
+        desc.set_align(8);
+        for (slot, func) in wasm.table.iter().enumerate() {
+            if let Some(func) = func {
+                let id = funcs[*func as usize].ok_or_else(|| "an import is in the table".to_string())?;
+                let func = object.declare_func_in_data(id, &mut desc);
+                desc.write_function_addr(slot as u32 * 8, func);
+            }
+        }
+        object.define_data(table, &desc).map_err(error)?;
+
+        let mut globals = Vec::new();
+        for (i, global) in wasm.globals.iter().enumerate() {
+            let id = if global.mutable {
+                let name = format!("solo.global.{}", i);
+                Some(define_data(object, &name, Linkage::Local, true, &global.init.to_le_bytes())?)
+            } else {
+                None
+            };
+            globals.push(id);
+        }
+        Ok(Env { funcs, line, memory, pages, grow, table, globals })
+    }
+}
+
+fn define_data(
+    object: &mut ObjectModule,
+    name: &str,
+    linkage: Linkage,
+    writable: bool,
+    bytes: &[u8],
+) -> Result<DataId, String> {
+    let id = object.declare_data(name, linkage, writable, false).map_err(error)?;
+    let mut desc = DataDescription::new();
+    desc.define(bytes.into());
+    desc.set_align(8);
+    object.define_data(id, &desc).map_err(error)?;
+    Ok(id)
+}
+
+/// A function defined in the object
+pub(super) struct Compiled {
+    pub func: FuncId,
+    pub name: String,
+    /// Bytes of machine code
+    pub size: u32,
+    /// The start and end of the code of each marked statement, and which
+    /// mark it is, from the start of the function
+    pub lines: Vec<(u32, u32, u32)>,
+}
+
+/// Translate body `index` of the module and define it in `object`
+pub(super) fn define(object: &mut ObjectModule, wasm: &Module, env: &Env, index: usize) -> Result<Compiled, String> {
+    let func = (wasm.imports.len() + index) as u32;
+    let id = env.funcs[func as usize].expect("defined functions are declared");
+    let mut ctx = object.make_context();
+    ctx.func.signature = signature(object, wasm.func_type(func))?;
+    let mut fctx = FunctionBuilderContext::new();
+    let builder = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+    let mut translator = Translator::new(builder, object, wasm, env, func, &wasm.bodies[index])?;
+    translator.translate(&wasm.bodies[index])?;
+    translator.builder.seal_all_blocks();
+    translator.builder.finalize();
+    object.define_function(id, &mut ctx).map_err(|e| match e {
+        cranelift_module::ModuleError::Compilation(e) => cranelift_codegen::print_errors::pretty_error(&ctx.func, e),
+        e => e.to_string(),
+    })?;
+    let code = ctx.compiled_code().expect("defined functions are compiled");
+    let lines = code
+        .buffer
+        .get_srclocs_sorted()
+        .iter()
+        .filter(|loc| !loc.loc.is_default())
+        .map(|loc| (loc.start, loc.end, loc.loc.bits()))
+        .collect();
+    Ok(Compiled { func: id, name: wasm.name(func), size: code.code_buffer().len() as u32, lines })
+}
+
+/// A block, loop or if being translated
+struct Frame {
+    kind: Kind,
+    /// The block control reaches at the end
+    next: Block,
+    params: usize,
+    results: usize,
+    /// The height of the stack below the parameters
+    height: usize,
+    /// Whether anything goes to `next`
+    reached: bool,
+}
+
+enum Kind {
+    Block,
+    /// Branches go back to the header
+    Loop(Block),
+    /// The else block, until `else` is reached, and the parameters it gets
+    If(Option<Block>, Vec<Value>),
+}
+
+struct Translator<'a, 'f> {
+    builder: FunctionBuilder<'f>,
+    object: &'a mut ObjectModule,
+    wasm: &'a Module<'a>,
+    env: &'a Env,
+    /// The address of linear memory
+    memory: Value,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    reachable: bool,
+    /// Blocks opened since code became unreachable and not yet ended
+    dead: u32,
+    funcs: HashMap<FuncId, FuncRef>,
+    data: HashMap<DataId, GlobalValue>,
+    sigs: HashMap<u32, SigRef>,
+}
+
+impl<'a, 'f> Translator<'a, 'f> {
+    fn new(
+        mut builder: FunctionBuilder<'f>,
+        object: &'a mut ObjectModule,
+        wasm: &'a Module<'a>,
+        env: &'a Env,
+        func: u32,
+        body: &FunctionBody,
+    ) -> Result<Self, String> {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let ty = wasm.func_type(func);
+        let mut count = 0;
+        for (i, param) in ty.params().iter().enumerate() {
+            let var = Variable::from_u32(count);
+            builder.declare_var(var, clif(*param)?);
+            let value = builder.block_params(entry)[i];
+            builder.def_var(var, value);
+            count += 1;
+        }
+        for local in body.get_locals_reader().map_err(error)? {
+            let (n, ty) = local.map_err(error)?;
+            let ty = clif(ty)?;
+            for _ in 0..n {
+                let var = Variable::from_u32(count);
+                builder.declare_var(var, ty);
+                let zero = match ty {
+                    types::F32 => builder.ins().f32const(0.0),
+                    types::F64 => builder.ins().f64const(0.0),
+                    _ => builder.ins().iconst(ty, 0),
+                };
+                builder.def_var(var, zero);
+                count += 1;
+            }
+        }
+
+        let mut results = Vec::new();
+        for result in ty.results() {
+            results.push(clif(*result)?);
+        }
+        let exit = block_with(&mut builder, &results);
+        let frame =
+            Frame { kind: Kind::Block, next: exit, params: 0, results: results.len(), height: 0, reached: false };
+
+        let base = object.declare_data_in_func(env.memory, builder.func);
+        let addr = builder.ins().global_value(types::I64, base);
+        let memory = builder.ins().load(types::I64, MemFlags::trusted(), addr, 0);
+        Ok(Translator {
+            builder,
+            object,
+            wasm,
+            env,
+            memory,
+            stack: Vec::new(),
+            frames: vec![frame],
+            reachable: true,
+            dead: 0,
+            funcs: HashMap::new(),
+            data: HashMap::from([(env.memory, base)]),
+            sigs: HashMap::new(),
+        })
+    }
+
+    fn translate(&mut self, body: &FunctionBody) -> Result<(), String> {
+        let mut reader = body.get_operators_reader().map_err(error)?;
+        let mut ops = Vec::new();
+        while !reader.eof() {
+            ops.push(reader.read().map_err(error)?);
+        }
+        let mut ops = ops.into_iter().peekable();
+        while let Some(op) = ops.next() {
+            if let (Operator::I32Const { value }, Some(Operator::Call { function_index })) = (&op, ops.peek()) {
+                if Some(*function_index) == self.env.line {
+                    self.builder.set_srcloc(SourceLoc::new(*value as u32));
+                    ops.next();
+                    continue;
+                }
+            }
+            self.operator(op)?;
+        }
+        Ok(())
+    }
+
+    // ========== Helpers ==========
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("operand stack underflow")
+    }
+
+    fn pop_n(&mut self, n: usize) -> Vec<Value> {
+        self.stack.split_off(self.stack.len() - n)
+    }
+
+    fn top(&self, n: usize) -> Vec<Value> {
+        self.stack[self.stack.len() - n..].to_vec()
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn func_ref(&mut self, id: FuncId) -> FuncRef {
+        if let Some(func) = self.funcs.get(&id) {
+            return *func;
+        }
+        let func = self.object.declare_func_in_func(id, self.builder.func);
+        self.funcs.insert(id, func);
+        func
+    }
+
+    /// The address of a data object
+    fn data_addr(&mut self, id: DataId) -> Value {
+        let gv = match self.data.get(&id) {
+            Some(gv) => *gv,
+            None => {
+                let gv = self.object.declare_data_in_func(id, self.builder.func);
+                self.data.insert(id, gv);
+                gv
+            }
+        };
+        self.builder.ins().global_value(types::I64, gv)
+    }
+
+    fn sig_ref(&mut self, ty: u32) -> Result<SigRef, String> {
+        if let Some(sig) = self.sigs.get(&ty) {
+            return Ok(*sig);
+        }
+        let sig = signature(self.object, &self.wasm.types[ty as usize])?;
+        let sig = self.builder.import_signature(sig);
+        self.sigs.insert(ty, sig);
+        Ok(sig)
+    }
+
+    fn block_type(&self, ty: BlockType) -> Result<(Vec<Type>, Vec<Type>), String> {
+        match ty {
+            BlockType::Empty => Ok((Vec::new(), Vec::new())),
+            BlockType::Type(ty) => Ok((Vec::new(), vec![clif(ty)?])),
+            BlockType::FuncType(index) => {
+                let ty = &self.wasm.types[index as usize];
+                let params = ty.params().iter().map(|ty| clif(*ty)).collect::<Result<_, _>>()?;
+                let results = ty.results().iter().map(|ty| clif(*ty)).collect::<Result<_, _>>()?;
+                Ok((params, results))
+            }
+        }
+    }
+
+    /// The block a branch `depth` frames out goes to and what it passes
+    fn target(&mut self, depth: u32) -> (Block, Vec<Value>) {
+        let at = self.frames.len() - 1 - depth as usize;
+        let frame = &mut self.frames[at];
+        let (block, n) = match frame.kind {
+            Kind::Loop(header) => (header, frame.params),
+            _ => {
+                frame.reached = true;
+                (frame.next, frame.results)
+            }
+        };
+        (block, self.top(n))
+    }
+
+    /// The address in linear memory an access with `memarg` goes to, and
+    /// the offset from it, given the index on the stack
+    fn address(&mut self, memarg: &MemArg) -> (Value, i32) {
+        let index = self.pop();
+        let addr = self.heap(index);
+        match i32::try_from(memarg.offset) {
+            Ok(offset) => (addr, offset),
+            Err(_) => (self.builder.ins().iadd_imm(addr, memarg.offset as i64), 0),
+        }
+    }
+
+    /// The address of `index` in linear memory
+    fn heap(&mut self, index: Value) -> Value {
+        let index = self.builder.ins().uextend(types::I64, index);
+        self.builder.ins().iadd(self.memory, index)
+    }
+
+    fn load(&mut self, memarg: &MemArg, f: impl FnOnce(&mut FunctionBuilder, MemFlags, Value, i32) -> Value) {
+        let (addr, offset) = self.address(memarg);
+        let value = f(&mut self.builder, MemFlags::new(), addr, offset);
+        self.push(value);
+    }
+
+    fn store(&mut self, memarg: &MemArg, f: impl FnOnce(&mut FunctionBuilder, MemFlags, Value, Value, i32) -> Inst) {
+        let value = self.pop();
+        let (addr, offset) = self.address(memarg);
+        f(&mut self.builder, MemFlags::new(), value, addr, offset);
+    }
+
+    fn unary(&mut self, f: impl FnOnce(&mut FunctionBuilder, Value) -> Value) {
+        let x = self.pop();
+        let value = f(&mut self.builder, x);
+        self.push(value);
+    }
+
+    fn binary(&mut self, f: impl FnOnce(&mut FunctionBuilder, Value, Value) -> Value) {
+        let y = self.pop();
+        let x = self.pop();
+        let value = f(&mut self.builder, x, y);
+        self.push(value);
+    }
+
+    fn icmp(&mut self, cc: IntCC) {
+        self.binary(|b, x, y| {
+            let cmp = b.ins().icmp(cc, x, y);
+            b.ins().uextend(types::I32, cmp)
+        });
+    }
+
+    fn fcmp(&mut self, cc: FloatCC) {
+        self.binary(|b, x, y| {
+            let cmp = b.ins().fcmp(cc, x, y);
+            b.ins().uextend(types::I32, cmp)
+        });
+    }
+
+    fn extend(&mut self, from: Type, to: Type) {
+        self.unary(|b, x| {
+            let narrow = b.ins().ireduce(from, x);
+            b.ins().sextend(to, narrow)
+        });
+    }
+
+    // ========== Operators ==========
+
+    fn operator(&mut self, op: Operator) -> Result<(), String> {
+        use types::{F32, F64, I32, I64};
+        use Operator as O;
+        if !self.reachable {
+            match op {
+                O::Block { .. } | O::Loop { .. } | O::If { .. } => {
+                    self.dead += 1;
+                    return Ok(());
+                }
+                O::Else if self.dead == 0 => {}
+                O::End if self.dead == 0 => {}
+                O::End => {
+                    self.dead -= 1;
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            }
+        }
+        match op {
+            O::Nop => {}
+            O::Unreachable => {
+                self.builder.ins().trap(TRAP);
+                self.reachable = false;
+            }
+            O::Block { blockty } => {
+                let (params, results) = self.block_type(blockty)?;
+                let next = block_with(&mut self.builder, &results);
+                self.frames.push(Frame {
+                    kind: Kind::Block,
+                    next,
+                    params: params.len(),
+                    results: results.len(),
+                    height: self.stack.len() - params.len(),
+                    reached: false,
+                });
+            }
+            O::Loop { blockty } => {
+                let (params, results) = self.block_type(blockty)?;
+                let header = block_with(&mut self.builder, &params);
+                let next = block_with(&mut self.builder, &results);
+                let args = self.pop_n(params.len());
+                self.builder.ins().jump(header, &args);
+                self.builder.switch_to_block(header);
+                let height = self.stack.len();
+                self.stack.extend_from_slice(self.builder.block_params(header));
+                self.frames.push(Frame {
+                    kind: Kind::Loop(header),
+                    next,
+                    params: params.len(),
+                    results: results.len(),
+                    height,
+                    reached: false,
+                });
+            }
+            O::If { blockty } => {
+                let (params, results) = self.block_type(blockty)?;
+                let cond = self.pop();
+                let then = self.builder.create_block();
+                let otherwise = self.builder.create_block();
+                let next = block_with(&mut self.builder, &results);
+                self.builder.ins().brif(cond, then, &[], otherwise, &[]);
+                self.builder.switch_to_block(then);
+                self.frames.push(Frame {
+                    kind: Kind::If(Some(otherwise), self.top(params.len())),
+                    next,
+                    params: params.len(),
+                    results: results.len(),
+                    height: self.stack.len() - params.len(),
+                    reached: false,
+                });
+            }
+            O::Else => {
+                let frame = self.frames.last_mut().expect("else outside if");
+                if self.reachable {
+                    let args = self.stack[self.stack.len() - frame.results..].to_vec();
+                    self.builder.ins().jump(frame.next, &args);
+                    frame.reached = true;
+                }
+                self.stack.truncate(frame.height);
+                let Kind::If(Some(otherwise), params) = std::mem::replace(&mut frame.kind, Kind::Block) else {
+                    return Err("else outside if".to_string());
+                };
+                self.builder.switch_to_block(otherwise);
+                self.stack.extend(params);
+                self.reachable = true;
+            }
+            O::End => {
+                let mut frame = self.frames.pop().expect("end outside block");
+                if self.reachable {
+                    let args = self.pop_n(frame.results);
+                    self.builder.ins().jump(frame.next, &args);
+                    frame.reached = true;
+                }
+                self.stack.truncate(frame.height);
+                if let Kind::If(Some(otherwise), params) = frame.kind {
+                    self.builder.switch_to_block(otherwise);
+                    self.builder.ins().jump(frame.next, &params);
+                    frame.reached = true;
+                }
+                self.reachable = frame.reached;
+                if frame.reached {
+                    self.builder.switch_to_block(frame.next);
+                    self.stack.extend_from_slice(self.builder.block_params(frame.next));
+                    if self.frames.is_empty() {
+                        let results = self.pop_n(frame.results);
+                        self.builder.ins().return_(&results);
+                        self.reachable = false;
+                    }
+                }
+            }
+            O::Br { relative_depth } => {
+                let (block, args) = self.target(relative_depth);
+                self.builder.ins().jump(block, &args);
+                self.reachable = false;
+            }
+            O::BrIf { relative_depth } => {
+                let cond = self.pop();
+                let (block, args) = self.target(relative_depth);
+                let next = self.builder.create_block();
+                self.builder.ins().brif(cond, block, &args, next, &[]);
+                self.builder.switch_to_block(next);
+            }
+            O::BrTable { targets } => {
+                let index = self.pop();
+                let mut depths = Vec::new();
+                for depth in targets.targets() {
+                    depths.push(depth.map_err(error)?);
+                }
+                depths.push(targets.default());
+                // Jump tables pass no arguments, so branches that carry
+                // values go through a block of their own
+                let mut dests: HashMap<u32, Block> = HashMap::new();
+                let mut passes = Vec::new();
+                for depth in &depths {
+                    if dests.contains_key(depth) {
+                        continue;
+                    }
+                    let (block, args) = self.target(*depth);
+                    if args.is_empty() {
+                        dests.insert(*depth, block);
+                    } else {
+                        let pass = self.builder.create_block();
+                        dests.insert(*depth, pass);
+                        passes.push((pass, block, args));
+                    }
+                }
+                let default = depths.pop().expect("br_table has a default");
+                let default = self.builder.func.dfg.block_call(dests[&default], &[]);
+                let table: Vec<_> =
+                    depths.iter().map(|depth| self.builder.func.dfg.block_call(dests[depth], &[])).collect();
+                let table = self.builder.create_jump_table(JumpTableData::new(default, &table));
+                self.builder.ins().br_table(index, table);
+                for (pass, block, args) in passes {
+                    self.builder.switch_to_block(pass);
+                    self.builder.ins().jump(block, &args);
+                }
+                self.reachable = false;
+            }
+            O::Return => {
+                let results = self.top(self.frames[0].results);
+                self.builder.ins().return_(&results);
+                self.reachable = false;
+            }
+            O::Call { function_index } => {
+                let n = self.wasm.func_type(function_index).params().len();
+                let id = self.env.funcs[function_index as usize]
+                    .ok_or_else(|| format!("cannot call function {}", function_index))?;
+                let func = self.func_ref(id);
+                let args = self.pop_n(n);
+                let call = self.builder.ins().call(func, &args);
+                self.stack.extend_from_slice(self.builder.inst_results(call));
+            }
+            O::CallIndirect { type_index, .. } => {
+                let slot = self.pop();
+                let n = self.wasm.types[type_index as usize].params().len();
+                let args = self.pop_n(n);
+                let sig = self.sig_ref(type_index)?;
+                let out =
+                    self.builder.ins().icmp_imm(IntCC::UnsignedGreaterThanOrEqual, slot, self.wasm.table.len() as i64);
+                self.builder.ins().trapnz(out, TRAP);
+                let table = self.data_addr(self.env.table);
+                let slot = self.builder.ins().uextend(I64, slot);
+                let offset = self.builder.ins().imul_imm(slot, 8);
+                let addr = self.builder.ins().iadd(table, offset);
+                let callee = self.builder.ins().load(I64, MemFlags::trusted().with_readonly(), addr, 0);
+                let call = self.builder.ins().call_indirect(sig, callee, &args);
+                self.stack.extend_from_slice(self.builder.inst_results(call));
+            }
+            O::Drop => {
+                self.pop();
+            }
+            O::Select | O::TypedSelect { .. } => {
+                let cond = self.pop();
+                self.binary(|b, x, y| b.ins().select(cond, x, y));
+            }
+            O::LocalGet { local_index } => {
+                let value = self.builder.use_var(Variable::from_u32(local_index));
+                self.push(value);
+            }
+            O::LocalSet { local_index } => {
+                let value = self.pop();
+                self.builder.def_var(Variable::from_u32(local_index), value);
+            }
+            O::LocalTee { local_index } => {
+                let value = *self.stack.last().expect("operand stack underflow");
+                self.builder.def_var(Variable::from_u32(local_index), value);
+            }
+            O::GlobalGet { global_index } => {
+                let global = &self.wasm.globals[global_index as usize];
+                let ty = clif(global.ty)?;
+                let value = match self.env.globals[global_index as usize] {
+                    Some(id) => {
+                        let addr = self.data_addr(id);
+                        self.builder.ins().load(ty, MemFlags::trusted(), addr, 0)
+                    }
+                    None => match ty {
+                        F32 => self.builder.ins().f32const(Ieee32::with_bits(global.init as u32)),
+                        F64 => self.builder.ins().f64const(Ieee64::with_bits(global.init)),
+                        _ => self.builder.ins().iconst(ty, global.init as i64),
+                    },
+                };
+                self.push(value);
+            }
+            O::GlobalSet { global_index } => {
+                let id = self.env.globals[global_index as usize]
+                    .ok_or_else(|| format!("global {} is immutable", global_index))?;
+                let value = self.pop();
+                let addr = self.data_addr(id);
+                self.builder.ins().store(MemFlags::trusted(), value, addr, 0);
+            }
+
+            O::I32Load { memarg } => self.load(&memarg, |b, f, a, o| b.ins().load(I32, f, a, o)),
+            O::I64Load { memarg } => self.load(&memarg, |b, f, a, o| b.ins().load(I64, f, a, o)),
+            O::F32Load { memarg } => self.load(&memarg, |b, f, a, o| b.ins().load(F32, f, a, o)),
+            O::F64Load { memarg } => self.load(&memarg, |b, f, a, o| b.ins().load(F64, f, a, o)),
+            O::I32Load8S { memarg } => self.load(&memarg, |b, f, a, o| b.ins().sload8(I32, f, a, o)),
+            O::I32Load8U { memarg } => self.load(&memarg, |b, f, a, o| b.ins().uload8(I32, f, a, o)),
+            O::I32Load16S { memarg } => self.load(&memarg, |b, f, a, o| b.ins().sload16(I32, f, a, o)),
+            O::I32Load16U { memarg } => self.load(&memarg, |b, f, a, o| b.ins().uload16(I32, f, a, o)),
+            O::I64Load8S { memarg } => self.load(&memarg, |b, f, a, o| b.ins().sload8(I64, f, a, o)),
+            O::I64Load8U { memarg } => self.load(&memarg, |b, f, a, o| b.ins().uload8(I64, f, a, o)),
+            O::I64Load16S { memarg } => self.load(&memarg, |b, f, a, o| b.ins().sload16(I64, f, a, o)),
+            O::I64Load16U { memarg } => self.load(&memarg, |b, f, a, o| b.ins().uload16(I64, f, a, o)),
+            O::I64Load32S { memarg } => self.load(&memarg, |b, f, a, o| b.ins().sload32(f, a, o)),
+            O::I64Load32U { memarg } => self.load(&memarg, |b, f, a, o| b.ins().uload32(f, a, o)),
+            O::I32Store { memarg } | O::I64Store { memarg } | O::F32Store { memarg } | O::F64Store { memarg } => {
+                self.store(&memarg, |b, f, v, a, o| b.ins().store(f, v, a, o))
+            }
+            O::I32Store8 { memarg } | O::I64Store8 { memarg } => {
+                self.store(&memarg, |b, f, v, a, o| b.ins().istore8(f, v, a, o))
+            }
+            O::I32Store16 { memarg } | O::I64Store16 { memarg } => {
+                self.store(&memarg, |b, f, v, a, o| b.ins().istore16(f, v, a, o))
+            }
+            O::I64Store32 { memarg } => self.store(&memarg, |b, f, v, a, o| b.ins().istore32(f, v, a, o)),
+            O::MemorySize { .. } => {
+                let addr = self.data_addr(self.env.pages);
+                let pages = self.builder.ins().load(I32, MemFlags::trusted(), addr, 0);
+                self.push(pages);
+            }
+            O::MemoryGrow { .. } => {
+                let delta = self.pop();
+                let grow = self.func_ref(self.env.grow);
+                let call = self.builder.ins().call(grow, &[delta]);
+                let old = self.builder.inst_results(call)[0];
+                self.push(old);
+            }
+            O::MemoryCopy { .. } => {
+                let len = self.pop();
+                let src = self.pop();
+                let dst = self.pop();
+                let (dst, src) = (self.heap(dst), self.heap(src));
+                let len = self.builder.ins().uextend(I64, len);
+                let config = self.object.target_config();
+                self.builder.call_memmove(config, dst, src, len);
+            }
+            O::MemoryFill { .. } => {
+                let len = self.pop();
+                let byte = self.pop();
+                let dst = self.pop();
+                let dst = self.heap(dst);
+                let byte = self.builder.ins().ireduce(types::I8, byte);
+                let len = self.builder.ins().uextend(I64, len);
+                let config = self.object.target_config();
+                self.builder.call_memset(config, dst, byte, len);
+            }
+
+            O::I32Const { value } => {
+                let value = self.builder.ins().iconst(I32, value as u32 as i64);
+                self.push(value);
+            }
+            O::I64Const { value } => {
+                let value = self.builder.ins().iconst(I64, value);
+                self.push(value);
+            }
+            O::F32Const { value } => {
+                let value = self.builder.ins().f32const(Ieee32::with_bits(value.bits()));
+                self.push(value);
+            }
+            O::F64Const { value } => {
+                let value = self.builder.ins().f64const(Ieee64::with_bits(value.bits()));
+                self.push(value);
+            }
+
+            O::I32Eqz | O::I64Eqz => self.unary(|b, x| {
+                let cmp = b.ins().icmp_imm(IntCC::Equal, x, 0);
+                b.ins().uextend(I32, cmp)
+            }),
+            O::I32Eq | O::I64Eq => self.icmp(IntCC::Equal),
+            O::I32Ne | O::I64Ne => self.icmp(IntCC::NotEqual),
+            O::I32LtS | O::I64LtS => self.icmp(IntCC::SignedLessThan),
+            O::I32LtU | O::I64LtU => self.icmp(IntCC::UnsignedLessThan),
+            O::I32GtS | O::I64GtS => self.icmp(IntCC::SignedGreaterThan),
+            O::I32GtU | O::I64GtU => self.icmp(IntCC::UnsignedGreaterThan),
+            O::I32LeS | O::I64LeS => self.icmp(IntCC::SignedLessThanOrEqual),
+            O::I32LeU | O::I64LeU => self.icmp(IntCC::UnsignedLessThanOrEqual),
+            O::I32GeS | O::I64GeS => self.icmp(IntCC::SignedGreaterThanOrEqual),
+            O::I32GeU | O::I64GeU => self.icmp(IntCC::UnsignedGreaterThanOrEqual),
+            O::F32Eq | O::F64Eq => self.fcmp(FloatCC::Equal),
+            O::F32Ne | O::F64Ne => self.fcmp(FloatCC::NotEqual),
+            O::F32Lt | O::F64Lt => self.fcmp(FloatCC::LessThan),
+            O::F32Gt | O::F64Gt => self.fcmp(FloatCC::GreaterThan),
+            O::F32Le | O::F64Le => self.fcmp(FloatCC::LessThanOrEqual),
+            O::F32Ge | O::F64Ge => self.fcmp(FloatCC::GreaterThanOrEqual),
+
+            O::I32Clz | O::I64Clz => self.unary(|b, x| b.ins().clz(x)),
+            O::I32Ctz | O::I64Ctz => self.unary(|b, x| b.ins().ctz(x)),
+            O::I32Popcnt | O::I64Popcnt => self.unary(|b, x| b.ins().popcnt(x)),
+            O::I32Add | O::I64Add => self.binary(|b, x, y| b.ins().iadd(x, y)),
+            O::I32Sub | O::I64Sub => self.binary(|b, x, y| b.ins().isub(x, y)),
+            O::I32Mul | O::I64Mul => self.binary(|b, x, y| b.ins().imul(x, y)),
+            O::I32DivS | O::I64DivS => self.binary(|b, x, y| b.ins().sdiv(x, y)),
+            O::I32DivU | O::I64DivU => self.binary(|b, x, y| b.ins().udiv(x, y)),
+            O::I32RemS | O::I64RemS => self.binary(|b, x, y| b.ins().srem(x, y)),
+            O::I32RemU | O::I64RemU => self.binary(|b, x, y| b.ins().urem(x, y)),
+            O::I32And | O::I64And => self.binary(|b, x, y| b.ins().band(x, y)),
+            O::I32Or | O::I64Or => self.binary(|b, x, y| b.ins().bor(x, y)),
+            O::I32Xor | O::I64Xor => self.binary(|b, x, y| b.ins().bxor(x, y)),
+            O::I32Shl | O::I64Shl => self.binary(|b, x, y| b.ins().ishl(x, y)),
+            O::I32ShrS | O::I64ShrS => self.binary(|b, x, y| b.ins().sshr(x, y)),
+            O::I32ShrU | O::I64ShrU => self.binary(|b, x, y| b.ins().ushr(x, y)),
+            O::I32Rotl | O::I64Rotl => self.binary(|b, x, y| b.ins().rotl(x, y)),
+            O::I32Rotr | O::I64Rotr => self.binary(|b, x, y| b.ins().rotr(x, y)),
+
+            O::F32Abs | O::F64Abs => self.unary(|b, x| b.ins().fabs(x)),
+            O::F32Neg | O::F64Neg => self.unary(|b, x| b.ins().fneg(x)),
+            O::F32Ceil | O::F64Ceil => self.unary(|b, x| b.ins().ceil(x)),
+            O::F32Floor | O::F64Floor => self.unary(|b, x| b.ins().floor(x)),
+            O::F32Trunc | O::F64Trunc => self.unary(|b, x| b.ins().trunc(x)),
+            O::F32Nearest | O::F64Nearest => self.unary(|b, x| b.ins().nearest(x)),
+            O::F32Sqrt | O::F64Sqrt => self.unary(|b, x| b.ins().sqrt(x)),
+            O::F32Add | O::F64Add => self.binary(|b, x, y| b.ins().fadd(x, y)),
+            O::F32Sub | O::F64Sub => self.binary(|b, x, y| b.ins().fsub(x, y)),
+            O::F32Mul | O::F64Mul => self.binary(|b, x, y| b.ins().fmul(x, y)),
+            O::F32Div | O::F64Div => self.binary(|b, x, y| b.ins().fdiv(x, y)),
+            O::F32Min | O::F64Min => self.binary(|b, x, y| b.ins().fmin(x, y)),
+            O::F32Max | O::F64Max => self.binary(|b, x, y| b.ins().fmax(x, y)),
+            O::F32Copysign | O::F64Copysign => self.binary(|b, x, y| b.ins().fcopysign(x, y)),
+
+            O::I32WrapI64 => self.unary(|b, x| b.ins().ireduce(I32, x)),
+            O::I64ExtendI32S => self.unary(|b, x| b.ins().sextend(I64, x)),
+            O::I64ExtendI32U => self.unary(|b, x| b.ins().uextend(I64, x)),
+            O::I32Extend8S => self.extend(types::I8, I32),
+            O::I32Extend16S => self.extend(types::I16, I32),
+            O::I64Extend8S => self.extend(types::I8, I64),
+            O::I64Extend16S => self.extend(types::I16, I64),
+            O::I64Extend32S => self.extend(I32, I64),
+            O::I32TruncF32S | O::I32TruncF64S => self.unary(|b, x| b.ins().fcvt_to_sint(I32, x)),
+            O::I32TruncF32U | O::I32TruncF64U => self.unary(|b, x| b.ins().fcvt_to_uint(I32, x)),
+            O::I64TruncF32S | O::I64TruncF64S => self.unary(|b, x| b.ins().fcvt_to_sint(I64, x)),
+            O::I64TruncF32U | O::I64TruncF64U => self.unary(|b, x| b.ins().fcvt_to_uint(I64, x)),
+            O::I32TruncSatF32S | O::I32TruncSatF64S => self.unary(|b, x| b.ins().fcvt_to_sint_sat(I32, x)),
+            O::I32TruncSatF32U | O::I32TruncSatF64U => self.unary(|b, x| b.ins().fcvt_to_uint_sat(I32, x)),
+            O::I64TruncSatF32S | O::I64TruncSatF64S => self.unary(|b, x| b.ins().fcvt_to_sint_sat(I64, x)),
+            O::I64TruncSatF32U | O::I64TruncSatF64U => self.unary(|b, x| b.ins().fcvt_to_uint_sat(I64, x)),
+            O::F32ConvertI32S | O::F32ConvertI64S => self.unary(|b, x| b.ins().fcvt_from_sint(F32, x)),
+            O::F32ConvertI32U | O::F32ConvertI64U => self.unary(|b, x| b.ins().fcvt_from_uint(F32, x)),
+            O::F64ConvertI32S | O::F64ConvertI64S => self.unary(|b, x| b.ins().fcvt_from_sint(F64, x)),
+            O::F64ConvertI32U | O::F64ConvertI64U => self.unary(|b, x| b.ins().fcvt_from_uint(F64, x)),
+            O::F32DemoteF64 => self.unary(|b, x| b.ins().fdemote(F32, x)),
+            O::F64PromoteF32 => self.unary(|b, x| b.ins().fpromote(F64, x)),
+            O::I32ReinterpretF32 => self.unary(|b, x| b.ins().bitcast(I32, MemFlags::new(), x)),
+            O::I64ReinterpretF64 => self.unary(|b, x| b.ins().bitcast(I64, MemFlags::new(), x)),
+            O::F32ReinterpretI32 => self.unary(|b, x| b.ins().bitcast(F32, MemFlags::new(), x)),
+            O::F64ReinterpretI64 => self.unary(|b, x| b.ins().bitcast(F64, MemFlags::new(), x)),
+
+            op => return Err(format!("unsupported instruction {:?}", op)),
+        }
+        Ok(())
+    }
+}
+
+/// A new block taking values of `types`
+fn block_with(builder: &mut FunctionBuilder, types: &[Type]) -> Block {
+    let block = builder.create_block();
+    for ty in types {
+        builder.append_block_param(block, *ty);
+    }
+    block
+}
@@ -0,0 +1,70 @@
+// Format strings
+// How the backends split the template of `println`, `format` and the like
+// into text and the holes arguments fill, the way the interpreter reads it.
+
+/// Width, alignment and precision of a hole, such as `{:<8.2}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Spec {
+    pub width: Option<i32>,
+    /// Pad on the right, for `<`
+    pub left: bool,
+    pub precision: Option<i32>,
+}
+
+impl Spec {
+    pub(crate) fn is_plain(&self) -> bool {
+        self.width.is_none() && self.precision.is_none()
+    }
+}
+
+/// A piece of a format string
+pub(crate) enum Piece {
+    Text(String),
+    /// A `{}` to fill in, with its text for when no argument is left
+    Hole { text: String, spec: Spec, debug: bool },
+}
+
+/// Split a format string the way the interpreter reads it
+pub(crate) fn parse_template(template: &str) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find(['{', '}']) {
+        text.push_str(&rest[..open]);
+        rest = &rest[open..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            text.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        let (true, Some(close)) = (rest.starts_with('{'), rest.find('}')) else {
+            text.push_str(&rest[..1]);
+            rest = &rest[1..];
+            continue;
+        };
+        let spec = rest[1..close].split_once(':').map_or("", |(_, spec)| spec);
+        let (spec, debug) = match spec.strip_suffix('?') {
+            Some(spec) => (spec, true),
+            None => (spec, false),
+        };
+        let (width, precision) = match spec.split_once('.') {
+            Some((width, precision)) => (width, precision.parse::<i32>().ok()),
+            None => (spec, None),
+        };
+        let (left, digits) = match width.strip_prefix('<') {
+            Some(digits) => (true, digits),
+            None => (false, width.strip_prefix('>').unwrap_or(width)),
+        };
+        let spec = Spec { width: digits.parse::<i32>().ok(), left, precision };
+        if !text.is_empty() {
+            pieces.push(Piece::Text(std::mem::take(&mut text)));
+        }
+        pieces.push(Piece::Hole { text: rest[..=close].to_string(), spec, debug });
+        rest = &rest[close + 1..];
+    }
+    text.push_str(rest);
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    pieces
+}
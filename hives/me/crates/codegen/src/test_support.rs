@@ -0,0 +1,263 @@
+// Test support
+// The front end every backend's tests run a source file through before
+// generating code from it.
+
+use crate::{ContractLowering, ContractMode};
+use my_lang_ast::Program;
+use my_lang_parser::Parser;
+use my_lang_resolve::{Resolution, Resolver};
+use my_lang_typechecker::TypeckResults;
+
+/// Parse, lower contracts in, resolve and check `source`, which must have no errors
+pub(crate) fn check_source(source: &str) -> (Program, Resolution, TypeckResults) {
+    let mut parser = Parser::new(source);
+    let (mut program, errors) = parser.parse_program();
+    assert!(errors.is_empty(), "{:?}", errors);
+    ContractLowering::new(source, ContractMode::All).with_first_node_id(parser.next_node_id()).lower(&mut program);
+    let mut resolver = Resolver::new();
+    resolver.add_module(&[], &program);
+    let (res, errors) = resolver.finish();
+    assert!(errors.is_empty(), "{:?}", errors);
+    let (types, errors) = my_lang_typechecker::check_crate(&[&program], &res);
+    assert!(errors.is_empty(), "{:?}", errors);
+    (program, res, types)
+}
+
+/// A program and what running it does, the same whichever backend built it
+pub(crate) struct Fixture {
+    pub source: &'static str,
+    pub status: i32,
+    pub out: &'static str,
+    pub err: &'static str,
+}
+
+/// Give up on running a program because `tool` is not installed. Under CI,
+/// where every backend's toolchain must be present, the test fails instead.
+pub(crate) fn missing<T>(tool: &str) -> Option<T> {
+    assert!(std::env::var_os("CI").is_none(), "`{}` is not installed, but CI runs need it", tool);
+    eprintln!("skipped running the program: `{}` is not installed", tool);
+    None
+}
+
+impl Fixture {
+    /// Check a run of the program, where `None` means it was skipped for want of a toolchain
+    pub(crate) fn check(&self, ran: Option<(i32, String, String)>) {
+        let Some((status, out, err)) = ran else { return };
+        assert_eq!((status, out.as_str(), err.as_str()), (self.status, self.out, self.err));
+    }
+}
+
+pub(crate) const SHAPES: Fixture = Fixture {
+    source: r#"
+        enum Shape {
+            Circle(f64),
+            Rect { w: f64, h: f64 },
+            Empty,
+        }
+
+        fn area(s: &Shape) -> f64 {
+            match s {
+                Shape::Circle(r) => 3.0 * *r * *r,
+                Shape::Rect { w, h } => *w * *h,
+                Shape::Empty => 0.0,
+            }
+        }
+
+        fn main() {
+            let shapes: Vec<Shape> = Vec::from([Shape::Circle(1.0), Shape::Rect { w: 2.0, h: 1.5 }, Shape::Empty]);
+            for s in shapes.iter() {
+                println("{:.1} {:?}", area(s), s);
+            }
+            println("[{:>4}|{:<3}|{}]", 42, true, -7);
+        }
+    "#,
+    status: 0,
+    out: "3.0 Circle(1.0)\n3.0 Rect { w: 2.0, h: 1.5 }\n0.0 Empty\n[  42|true|-7]\n",
+    err: "",
+};
+
+/// Fails its precondition on the second call, after printing `7`
+pub(crate) const WITHDRAW: &str = r#"
+    fn withdraw(balance: i64, amount: i64) -> i64
+        pre amount <= balance
+    {
+        balance - amount
+    }
+
+    fn main() {
+        println("{}", withdraw(10, 3));
+        withdraw(5, 8);
+    }
+"#;
+
+pub(crate) const DROPS: Fixture = Fixture {
+    source: r#"
+        struct Resource {
+            id: i32,
+        }
+
+        impl Drop for Resource {
+            fn drop(&mut self) {
+                println("dropping {}", self.id);
+            }
+        }
+
+        struct Holder {
+            first: Resource,
+            second: Resource,
+        }
+
+        fn main() {
+            let h = Holder { first: Resource { id: 1 }, second: Resource { id: 2 } };
+            drop(h);
+            let n = 3;
+            drop(n);
+            println("done");
+        }
+    "#,
+    status: 0,
+    out: "dropping 1\ndropping 2\ndone\n",
+    err: "",
+};
+
+pub(crate) const TOTALS: Fixture = Fixture {
+    source: r#"
+        trait Zero {
+            fn zero() -> Self;
+        }
+
+        impl Zero for i32 {
+            fn zero() -> i32 { 0 }
+        }
+
+        impl Zero for f64 {
+            fn zero() -> f64 { 0.0 }
+        }
+
+        fn total<T: Zero + Copy + Add>(items: &Vec<T>) -> T {
+            let mut acc = T::zero();
+            for x in items.iter() {
+                acc = acc + *x;
+            }
+            acc
+        }
+
+        fn main() {
+            let mut xs: Vec<i32> = Vec::new();
+            let mut i = 0;
+            while i < 5 {
+                xs.push(i * 3 % 7);
+                i = i + 1;
+            }
+            xs.sort();
+            let ys: Vec<f64> = Vec::from([0.5, 0.25]);
+            println("{:?} {} {}", xs, total(&xs), total(&ys));
+            println("{} {}", xs.contains(&5), xs.contains(&4));
+        }
+    "#,
+    status: 0,
+    out: "[0, 2, 3, 5, 6] 16 0.75\ntrue false\n",
+    err: "",
+};
+
+pub(crate) const OUT_OF_BOUNDS: Fixture = Fixture {
+    source: r#"
+        fn main() {
+            let v: Vec<i32> = Vec::from([4, 5, 6]);
+            println("{}", v[1]);
+            println("{}", v[3]);
+        }
+    "#,
+    status: 101,
+    out: "5\n",
+    err: "thread 'main' panicked at test.solo:5:27:\nindex out of bounds: the len is 3 but the index is 3\n",
+};
+
+pub(crate) const OVERFLOW: Fixture = Fixture {
+    source: r#"
+        fn add(a: i32, b: i32) -> i32 {
+            a + b
+        }
+
+        fn main() {
+            let n: i32 = 2147483600;
+            println("{} {}", -7 / 2, -7 % 2);
+            println("{}", add(n, 47));
+            println("{}", add(n, 48));
+        }
+    "#,
+    status: 101,
+    out: "-3 -1\n2147483647\n",
+    err: "thread 'main' panicked at test.solo:3:13:\nattempt to add with overflow\n",
+};
+
+pub(crate) const DIVIDE_BY_ZERO: Fixture = Fixture {
+    source: r#"
+        fn ratio(a: u8, b: u8) -> u8 {
+            a / b
+        }
+
+        fn main() {
+            println("{}", ratio(6, 3));
+            println("{}", ratio(6, 0));
+        }
+    "#,
+    status: 101,
+    out: "2\n",
+    err: "thread 'main' panicked at test.solo:3:13:\nattempt to divide by zero\n",
+};
+
+pub(crate) const FILES: Fixture = Fixture {
+    source: r#"
+        import std::fs;
+
+        fn main() {
+            match fs::write("out.txt", "saved") {
+                Ok(_) => println("wrote"),
+                Err(e) => println("{}", e),
+            }
+            match fs::read_to_string("out.txt") {
+                Ok(text) => println("read {}", text),
+                Err(e) => println("{}", e),
+            }
+            match fs::read_to_string("missing.txt") {
+                Ok(text) => println("read {}", text),
+                Err(e) => println("{}", e),
+            }
+        }
+    "#,
+    status: 0,
+    out: "wrote\nread saved\nNo such file or directory (os error 2)\n",
+    err: "",
+};
+
+pub(crate) const MAIN_ERROR: Fixture = Fixture {
+    source: r#"
+        fn check(v: &Vec<i32>) -> Result<i32, String> {
+            if v.len() > 2 {
+                return Err(format("too long: {}", v.len()));
+            }
+            Ok(v[0])
+        }
+
+        fn main() -> Result<(), String> {
+            let v: Vec<i32> = Vec::from([4, 5, 6]);
+            println("{:?}", check(&v));
+            match check(&v) {
+                Ok(n) => println("{}", n),
+                Err(e) => return Err(e),
+            }
+            Ok(())
+        }
+    "#,
+    status: 1,
+    out: "Err(\"too long: 3\")\n",
+    err: "Error: \"too long: 3\"\n",
+};
+
+/// Uses a library type neither the C nor the WebAssembly backend lays out
+pub(crate) const HASH_MAP: &str = r#"
+    fn main() {
+        let mut counts: HashMap<String, i32> = HashMap::new();
+    }
+"#;
@@ -0,0 +1,3680 @@
+// WebAssembly backend
+// Emits a WebAssembly module for a checked crate, as text and assembled.
+// Printing, arguments, files and exit are WASI imports, so the module runs
+// under any WASI runtime. Locals live in frames on a shadow stack in linear
+// memory and aggregates are handled by address. Integer overflow wraps;
+// division by zero and indexing out of bounds panic as Rust's do.
+
+use crate::bytecode::int_range;
+use crate::mono::{self, has_infer, is_unsigned, prim_name, Callee, Crate, LibraryCall, Scope};
+use crate::template::{parse_template, Piece, Spec};
+use my_lang_ast as ast;
+use my_lang_ast::{
+    BinaryOp, Block, Expression, ExpressionKind, Literal, NodeId, PatternKind, PrimitiveType, Span, StatementKind,
+    UnaryOp,
+};
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_resolve::{DefId, DefKind, Res, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults, VariantKind};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Support code every module carries: the WASI imports and what generated code calls
+const RUNTIME: &str = include_str!("wasm/runtime.wat");
+
+/// Where string literals start; the support code keeps what is below
+const DATA_START: u32 = 8192;
+
+/// Bytes of shadow stack, which frames come from
+const STACK_SIZE: u32 = 8 << 20;
+
+/// A module in the text format, and assembled
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WasmModule {
+    pub text: String,
+    pub binary: Vec<u8>,
+}
+
+/// Emit WebAssembly for the crate whose `main` is in the first of `programs`.
+/// `files` are the names of the source files spans refer to, which panics
+/// report. The module is only assembled when there are no errors.
+pub fn emit(
+    programs: &[&ast::Program],
+    res: &Resolution,
+    types: &TypeckResults,
+    files: &[String],
+) -> (WasmModule, Vec<Diagnostic>) {
+    let (module, _, diags) = emit_module(programs, res, types, files, false);
+    (module, diags)
+}
+
+/// Emit WebAssembly for the native backend, which also wants to know where
+/// statements came from: each is preceded by `i32.const n` and a call to the
+/// import `ml.line`, where `n` indexes the spans returned.
+pub(crate) fn emit_with_lines(
+    programs: &[&ast::Program],
+    res: &Resolution,
+    types: &TypeckResults,
+    files: &[String],
+) -> (WasmModule, Vec<Span>, Vec<Diagnostic>) {
+    emit_module(programs, res, types, files, true)
+}
+
+fn emit_module(
+    programs: &[&ast::Program],
+    res: &Resolution,
+    types: &TypeckResults,
+    files: &[String],
+    lines: bool,
+) -> (WasmModule, Vec<Span>, Vec<Diagnostic>) {
+    let krate = Crate::new(res, types, programs);
+    let main = krate.main(programs);
+    let mut emitter = Emitter::new(krate, files);
+    emitter.lines = lines.then(Vec::new);
+    match main {
+        Some(def) => emitter.entry(def),
+        None => {
+            let diag = Diagnostic::error("`main` function not found").with_code(codes::UNSUPPORTED_BY_TARGET);
+            emitter.diags.push(diag);
+        }
+    }
+    while let Some(work) = emitter.queue.pop_front() {
+        emitter.work(work);
+    }
+    let text = emitter.module();
+    let mut diags = emitter.diags;
+    let mut binary = Vec::new();
+    if !diags.iter().any(Diagnostic::is_error) {
+        match assemble(&text) {
+            Ok(bytes) => binary = bytes,
+            Err(message) => diags.push(Diagnostic::error(format!("internal error: {}", message))),
+        }
+    }
+    (WasmModule { text, binary }, emitter.lines.unwrap_or_default(), diags)
+}
+
+/// The binary for `text`, checked by a validator
+fn assemble(text: &str) -> Result<Vec<u8>, String> {
+    let binary = wat::parse_str(text).map_err(|e| e.to_string())?;
+    wasmparser::Validator::new().validate_all(&binary).map_err(|e| e.to_string())?;
+    Ok(binary)
+}
+
+/// A body still to be emitted
+enum Work {
+    Fn {
+        def: DefId,
+        args: Vec<Ty>,
+        name: String,
+    },
+    /// A constant whose initializer is not a literal, which becomes a function
+    Const {
+        def: DefId,
+        name: String,
+    },
+}
+
+/// How values of a type are held on the operand stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repr {
+    /// Nothing at all: `()`, `!` and empty tuples
+    Unit,
+    I32,
+    I64,
+    F32,
+    F64,
+    /// Aggregates, held as the address where they are
+    Mem,
+}
+
+impl Repr {
+    fn val(self) -> Option<&'static str> {
+        match self {
+            Repr::Unit => None,
+            Repr::I32 | Repr::Mem => Some("i32"),
+            Repr::I64 => Some("i64"),
+            Repr::F32 => Some("f32"),
+            Repr::F64 => Some("f64"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    size: u32,
+    align: u32,
+    repr: Repr,
+}
+
+impl Layout {
+    const UNIT: Layout = Layout { size: 0, align: 1, repr: Repr::Unit };
+
+    fn scalar(size: u32, repr: Repr) -> Layout {
+        Layout { size, align: size, repr }
+    }
+}
+
+/// The function being emitted
+struct Frame {
+    out: String,
+    depth: usize,
+    /// Bytes of shadow stack the frame takes, at `$fp`
+    size: u32,
+    /// Types of the scratch locals, which are all named `$tN`
+    scratch: Vec<&'static str>,
+    /// Where in the frame each local lives
+    locals: HashMap<DefId, u32>,
+    /// Labels `break` and `continue` go to in each enclosing loop, and the loop's type
+    loops: Vec<(String, String, Ty)>,
+    labels: u32,
+    scope: Scope,
+    ret: Ty,
+    /// Whether the result goes to memory the caller passes as `$sret`
+    sret: bool,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame {
+            out: String::new(),
+            depth: 0,
+            size: 0,
+            scratch: Vec::new(),
+            locals: HashMap::new(),
+            loops: Vec::new(),
+            labels: 0,
+            scope: Scope::default(),
+            ret: Ty::unit(),
+            sret: false,
+        }
+    }
+}
+
+struct Emitter<'a> {
+    res: &'a Resolution,
+    types: &'a TypeckResults,
+    files: &'a [String],
+    krate: Crate<'a>,
+    /// Names given to functions
+    taken: HashSet<String>,
+    type_names: HashMap<Ty, String>,
+    layouts: HashMap<Ty, Layout>,
+    helpers: HashSet<String>,
+    instances: HashMap<(DefId, Vec<Ty>), String>,
+    /// Functions computing constants that are not literals
+    const_fns: HashMap<DefId, String>,
+    queue: VecDeque<Work>,
+    functions: Vec<String>,
+    /// Functions called through values, in the order of the table
+    table: Vec<String>,
+    table_slots: HashMap<String, u32>,
+    /// Bytes of the data segment, from `DATA_START`
+    data: Vec<u8>,
+    strings: HashMap<String, u32>,
+    start: String,
+    diags: Vec<Diagnostic>,
+    reported: HashSet<(String, Span)>,
+    /// Where the statements marked with `ml.line` came from, when asked for
+    lines: Option<Vec<Span>>,
+    frame: Frame,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(krate: Crate<'a>, files: &'a [String]) -> Self {
+        let (res, types) = (krate.res, krate.types);
+        Self {
+            res,
+            types,
+            files,
+            krate,
+            taken: HashSet::new(),
+            type_names: HashMap::new(),
+            layouts: HashMap::new(),
+            helpers: HashSet::new(),
+            instances: HashMap::new(),
+            const_fns: HashMap::new(),
+            queue: VecDeque::new(),
+            functions: Vec::new(),
+            table: Vec::new(),
+            table_slots: HashMap::new(),
+            data: Vec::new(),
+            strings: HashMap::new(),
+            start: String::new(),
+            diags: Vec::new(),
+            reported: HashSet::new(),
+            lines: None,
+            frame: Frame::default(),
+        }
+    }
+
+    // ========== Items ==========
+
+    /// `_start`, which calls the program's `main`
+    fn entry(&mut self, def: DefId) {
+        let types = self.types;
+        let Some(sig) = types.sigs.get(&def) else { return };
+        let span = self.krate.fns.get(&def).map_or_else(Span::default, |func| func.span);
+        let name = self.instance(def, Vec::new());
+        self.frame = Frame { depth: 2, ..Frame::default() };
+        let args = sig.inputs.first().map(|ty| {
+            let slot = self.slot(ty, span);
+            self.addr(slot);
+            self.line("call $ml_args");
+            slot
+        });
+        let output = sig.output.clone();
+        let result = (self.layout(&output, span).repr == Repr::Mem).then(|| self.slot(&output, span));
+        if let Some(slot) = result {
+            self.addr(slot);
+        }
+        if let Some(slot) = args {
+            self.addr(slot);
+        }
+        self.line(&format!("call ${}", name));
+        match (&output, result) {
+            (Ty::Adt(def, _), Some(slot)) if self.krate.is_prelude(*def, "Result") => {
+                self.line("drop");
+                let (_, err) = self.krate.variant_named(&output, "Err");
+                self.addr(slot);
+                self.line("i32.load");
+                self.line(&format!("i32.const {}", err));
+                self.line("i32.eq");
+                self.open("if");
+                let buf = self.buffer();
+                self.text(&buf, "Error: ");
+                if let Some((_, field_ty, offset)) = self.fields(&output, err, span).first().cloned() {
+                    self.get(&buf);
+                    self.addr(slot + offset);
+                    self.load(&field_ty, span);
+                    self.peel_value(&field_ty);
+                    self.fmt_value(&self.krate.peel(&field_ty), &Spec::default(), true, span);
+                }
+                self.byte(&buf, b'\n');
+                self.line("i32.const 2");
+                self.get(&buf);
+                self.line("call $ml_print");
+                self.line("i32.const 1");
+                self.line("call $ml_exit");
+                self.close("end");
+            }
+            _ => self.discard(&output, span),
+        }
+        self.start = self.finish("_start", &[], None);
+    }
+
+    fn work(&mut self, work: Work) {
+        match work {
+            Work::Fn { def, args, name } => self.function(def, args, &name),
+            Work::Const { def, name } => {
+                let c = self.krate.consts[&def];
+                let ty = self.types.type_of(c.value.id).cloned().unwrap_or(Ty::Error);
+                let sret = self.layout(&ty, c.span).repr == Repr::Mem;
+                self.frame = Frame { depth: 2, ret: ty.clone(), sret, ..Frame::default() };
+                self.krate.infer_expr(&mut self.frame.scope, &c.value);
+                let block_type = self.block_type(&ty, c.span);
+                self.open(&format!("block $exit{}", block_type));
+                self.arg(&c.value, &ty);
+                self.close("end");
+                let result = self.result_type(&ty, c.span);
+                let text = self.finish(&name, &[], result);
+                self.functions.push(text);
+            }
+        }
+    }
+
+    fn function(&mut self, def: DefId, args: Vec<Ty>, name: &str) {
+        let (func, types) = (self.krate.fns[&def], self.types);
+        let sig = &types.sigs[&def];
+        let subst: HashMap<DefId, Ty> = sig.generics.iter().copied().zip(args).collect();
+        let output = sig.output.subst(&subst);
+        let sret = self.layout(&output, func.span).repr == Repr::Mem;
+        self.frame = Frame { depth: 2, scope: Scope { subst, ..Scope::default() }, ret: output.clone(), sret, ..Frame::default() };
+        let mut params = Vec::new();
+        for (i, (param, ty)) in func.params.iter().zip(&sig.inputs).enumerate() {
+            let ty = ty.subst(&self.frame.scope.subst);
+            let slot = self.slot(&ty, param.span);
+            if let Some(val) = self.layout(&ty, param.span).repr.val() {
+                let local = format!("$p{}", i);
+                self.addr(slot);
+                self.get(&local);
+                self.store(&ty, param.span);
+                params.push((local, val));
+            }
+            if let Some(def) = self.res.def_of_node(param.id) {
+                self.frame.locals.insert(def, slot);
+            }
+        }
+        self.krate.infer_block(&mut self.frame.scope, &func.body);
+        let block_type = self.block_type(&output, func.span);
+        self.open(&format!("block $exit{}", block_type));
+        self.block_as(&func.body, &output);
+        self.close("end");
+        let result = self.result_type(&output, func.span);
+        let text = self.finish(name, &params, result);
+        self.functions.push(text);
+    }
+
+    /// The text of the function whose body is in the frame, which ends with
+    /// its result on the stack. The frame is made on entry and given back on
+    /// exit; a result in memory is copied to where `$sret` points.
+    fn finish(&mut self, name: &str, params: &[(String, &'static str)], result: Option<&'static str>) -> String {
+        let frame = std::mem::take(&mut self.frame);
+        let size = align_up(frame.size, 8);
+        let ret_size = match frame.sret {
+            true => self.layout(&frame.ret, Span::default()).size,
+            false => 0,
+        };
+        let mut text = format!("  (func ${}", name);
+        if frame.sret {
+            text.push_str(" (param $sret i32)");
+        }
+        for (param, val) in params {
+            text.push_str(&format!(" (param {} {})", param, val));
+        }
+        if let Some(val) = result {
+            text.push_str(&format!(" (result {})", val));
+        }
+        text.push('\n');
+        text.push_str("    (local $fp i32)");
+        if frame.sret {
+            text.push_str(" (local $ret i32)");
+        }
+        for (i, val) in frame.scratch.iter().enumerate() {
+            text.push_str(&format!(" (local $t{} {})", i, val));
+        }
+        text.push('\n');
+        if size > 0 {
+            text.push_str(&format!("    i32.const {}\n    call $ml_enter\n    local.set $fp\n", size));
+        }
+        text.push_str(&frame.out);
+        if frame.sret {
+            text.push_str(&format!(
+                "    local.set $ret\n    local.get $sret\n    local.get $ret\n    i32.const {}\n    memory.copy\n",
+                ret_size
+            ));
+        }
+        if size > 0 {
+            text.push_str(&format!("    local.get $fp\n    i32.const {}\n    call $ml_leave\n", size));
+        }
+        if frame.sret {
+            text.push_str("    local.get $sret\n");
+        }
+        text.push_str("  )\n");
+        text
+    }
+
+    fn module(&self) -> String {
+        let stack_limit = align_up(DATA_START + self.data.len() as u32, 16);
+        let stack_top = stack_limit + STACK_SIZE;
+        let pages = stack_top.div_ceil(65536) + 1;
+        let mut text = String::from(";; Generated from Solo source by my-lang; do not edit.\n(module\n");
+        if self.lines.is_some() {
+            text.push_str("  (import \"ml\" \"line\" (func $ml_line (param i32)))\n");
+        }
+        text.push_str(RUNTIME);
+        text.push_str("\n  ;; ========== Memory ==========\n\n");
+        text.push_str(&format!("  (memory (export \"memory\") {})\n", pages));
+        text.push_str(&format!("  (global $ml_stack_limit i32 (i32.const {}))\n", stack_limit));
+        text.push_str(&format!("  (global $ml_sp (mut i32) (i32.const {}))\n", stack_top));
+        text.push_str(&format!("  (global $ml_heap (mut i32) (i32.const {}))\n", stack_top));
+        text.push_str(&format!("  (table $ml_fns {} funcref)\n", self.table.len()));
+        if !self.table.is_empty() {
+            let names: Vec<String> = self.table.iter().map(|name| format!("${}", name)).collect();
+            text.push_str(&format!("  (elem (table $ml_fns) (i32.const 0) func {})\n", names.join(" ")));
+        }
+        if !self.data.is_empty() {
+            text.push_str(&format!("  (data (i32.const {}) \"{}\")\n", DATA_START, wat_bytes(&self.data)));
+        }
+        text.push_str("\n  ;; ========== Functions ==========\n");
+        for function in &self.functions {
+            text.push('\n');
+            text.push_str(function);
+        }
+        text.push('\n');
+        text.push_str(&self.start);
+        text.push_str("  (export \"_start\" (func $_start))\n)\n");
+        text
+    }
+
+    // ========== Names ==========
+
+    /// A function name no other takes
+    fn reserve(&mut self, base: &str) -> String {
+        let mut name = sanitize(base);
+        if name.starts_with("ml_") || name.starts_with("wasi_") || name.starts_with('_') {
+            name.insert(0, 'u');
+        }
+        let mut candidate = name.clone();
+        let mut n = 2;
+        while !self.taken.insert(candidate.clone()) {
+            candidate = format!("{}_{}", name, n);
+            n += 1;
+        }
+        candidate
+    }
+
+    /// A name for `ty` in the names of the helpers for it
+    fn type_name(&mut self, ty: &Ty) -> String {
+        if let Some(name) = self.type_names.get(ty) {
+            return name.clone();
+        }
+        let mut name = sanitize(&self.krate.mangle(ty));
+        let mut n = 2;
+        while self.type_names.values().any(|taken| *taken == name) {
+            name = format!("{}_{}", sanitize(&self.krate.mangle(ty)), n);
+            n += 1;
+        }
+        self.type_names.insert(ty.clone(), name.clone());
+        name
+    }
+
+    /// The address of a string in the data segment holding `text`
+    fn string(&mut self, text: &str) -> u32 {
+        if let Some(addr) = self.strings.get(text) {
+            return *addr;
+        }
+        while !self.data.len().is_multiple_of(4) {
+            self.data.push(0);
+        }
+        let addr = DATA_START + self.data.len() as u32;
+        self.data.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(text.as_bytes());
+        self.strings.insert(text.to_string(), addr);
+        addr
+    }
+
+    /// The text `file:line:column` panics at `span` report
+    fn loc(&mut self, span: Span) -> u32 {
+        let file = self.files.get(span.file.0 as usize).map_or("<unknown>", |f| f.as_str());
+        self.string(&format!("{}:{}:{}", file, span.line, span.column))
+    }
+
+    /// Where `name` is in the function table, adding it the first time
+    fn table_slot(&mut self, name: &str) -> u32 {
+        if let Some(slot) = self.table_slots.get(name) {
+            return *slot;
+        }
+        let slot = self.table.len() as u32;
+        self.table.push(name.to_string());
+        self.table_slots.insert(name.to_string(), slot);
+        slot
+    }
+
+    fn unsupported(&mut self, what: impl Into<String>, span: Span) {
+        let message = format!("{} cannot be compiled to WebAssembly", what.into());
+        if self.reported.insert((message.clone(), span)) {
+            let diag = Diagnostic::error(message)
+                .with_code(codes::UNSUPPORTED_BY_TARGET)
+                .with_primary(span, "not supported by the WebAssembly backend");
+            self.diags.push(diag);
+        }
+    }
+
+    // ========== Output ==========
+
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.frame.depth {
+            self.frame.out.push_str("  ");
+        }
+        self.frame.out.push_str(text);
+        self.frame.out.push('\n');
+    }
+
+    fn open(&mut self, text: &str) {
+        self.line(text);
+        self.frame.depth += 1;
+    }
+
+    fn close(&mut self, text: &str) {
+        self.frame.depth -= 1;
+        self.line(text);
+    }
+
+    /// `else`, between the arms of an `if`
+    fn reopen(&mut self, text: &str) {
+        self.frame.depth -= 1;
+        self.line(text);
+        self.frame.depth += 1;
+    }
+
+    fn label(&mut self, base: &str) -> String {
+        self.frame.labels += 1;
+        format!("${}{}", base, self.frame.labels)
+    }
+
+    /// A new local of the value type `val`
+    fn scratch(&mut self, val: &'static str) -> String {
+        self.frame.scratch.push(val);
+        format!("$t{}", self.frame.scratch.len() - 1)
+    }
+
+    /// A new local set to the value on top of the stack
+    fn spill(&mut self, val: &'static str) -> String {
+        let local = self.scratch(val);
+        self.line(&format!("local.set {}", local));
+        local
+    }
+
+    fn get(&mut self, local: &str) {
+        self.line(&format!("local.get {}", local));
+    }
+
+    /// Room in the frame for a value of type `ty`, returning where it is
+    fn slot(&mut self, ty: &Ty, span: Span) -> u32 {
+        let layout = self.layout(ty, span);
+        let offset = align_up(self.frame.size, layout.align);
+        self.frame.size = offset + layout.size;
+        offset
+    }
+
+    /// Push the address `offset` bytes into the frame
+    fn addr(&mut self, offset: u32) {
+        self.line("local.get $fp");
+        self.offset(offset);
+    }
+
+    /// Add `offset` to the address on top of the stack
+    fn offset(&mut self, offset: u32) {
+        if offset > 0 {
+            self.line(&format!("i32.const {}", offset));
+            self.line("i32.add");
+        }
+    }
+
+    // ========== Types ==========
+
+    fn ty(&self, node: NodeId) -> Ty {
+        self.krate.ty(&self.frame.scope, node)
+    }
+
+    /// The type of `expr`. Printing through a module path, as `io::println`
+    /// does, is not checked, and gives `()` as printing in the prelude does.
+    fn value_ty(&self, expr: &Expression) -> Ty {
+        let ty = self.ty(expr.id);
+        let ExpressionKind::Call { func, .. } = &expr.kind else { return ty };
+        match self.krate.partial(func) {
+            Err(path) if has_infer(&ty) => match path.rsplit("::").next() {
+                Some("print" | "println" | "eprint" | "eprintln") => Ty::unit(),
+                _ => ty,
+            },
+            _ => ty,
+        }
+    }
+
+    /// How values of `ty` are laid out in memory and held on the stack
+    fn layout(&mut self, ty: &Ty, span: Span) -> Layout {
+        if let Some(layout) = self.layouts.get(ty) {
+            return *layout;
+        }
+        let layout = match ty {
+            Ty::Prim(prim) => {
+                use PrimitiveType::*;
+                match prim {
+                    I8 | U8 | Bool => Layout::scalar(1, Repr::I32),
+                    I16 | U16 => Layout::scalar(2, Repr::I32),
+                    I32 | U32 | Char | Str => Layout::scalar(4, Repr::I32),
+                    I64 | U64 | Isize | Usize => Layout::scalar(8, Repr::I64),
+                    F32 => Layout::scalar(4, Repr::F32),
+                    F64 => Layout::scalar(8, Repr::F64),
+                    Unit | Never => Layout::UNIT,
+                    I128 | U128 => {
+                        self.unsupported(format!("`{}`", prim_name(*prim)), span);
+                        Layout::scalar(8, Repr::I64)
+                    }
+                }
+            }
+            Ty::Tuple(elems) if elems.is_empty() => Layout::UNIT,
+            Ty::Never => Layout::UNIT,
+            Ty::Ref { .. } | Ty::Fn(..) => Layout::scalar(4, Repr::I32),
+            Ty::Adt(def, _) if !self.types.adts.contains_key(def) => match self.res.def(*def).name.as_str() {
+                "String" | "Box" => Layout::scalar(4, Repr::I32),
+                "Vec" => Layout { size: 12, align: 4, repr: Repr::Mem },
+                name => {
+                    self.unsupported(format!("`{}`", name), span);
+                    Layout::UNIT
+                }
+            },
+            Ty::Array(elem, Some(len)) => {
+                let elem = self.layout(elem, span);
+                Layout { size: elem.size * *len as u32, align: elem.align, repr: Repr::Mem }
+            }
+            Ty::Array(_, None) => {
+                self.unsupported("a slice", span);
+                Layout::UNIT
+            }
+            Ty::Tuple(_) | Ty::Adt(..) => {
+                let variants = match ty {
+                    Ty::Adt(def, _) => self.types.adts[def].variants.len(),
+                    _ => 1,
+                };
+                let mut align = if self.krate.is_enum(ty) { 4 } else { 1 };
+                let mut size = 0;
+                for index in 0..variants {
+                    for (_, field_ty, offset) in self.fields(ty, index, span) {
+                        let field = self.layout(&field_ty, span);
+                        align = align.max(field.align);
+                        size = size.max(offset + field.size);
+                    }
+                }
+                if self.krate.is_enum(ty) {
+                    size = size.max(4);
+                }
+                Layout { size: align_up(size, align), align, repr: Repr::Mem }
+            }
+            _ => {
+                self.unsupported(format!("the type `{}`", ty.display(self.res)), span);
+                Layout::UNIT
+            }
+        };
+        self.layouts.insert(ty.clone(), layout);
+        layout
+    }
+
+    fn repr(&mut self, ty: &Ty, span: Span) -> Repr {
+        self.layout(ty, span).repr
+    }
+
+    /// ` (result T)` for a block leaving a `ty`, or nothing
+    fn block_type(&mut self, ty: &Ty, span: Span) -> String {
+        match self.result_type(ty, span) {
+            Some(val) => format!(" (result {})", val),
+            None => String::new(),
+        }
+    }
+
+    fn result_type(&mut self, ty: &Ty, span: Span) -> Option<&'static str> {
+        self.repr(ty, span).val()
+    }
+
+    /// Fields of variant `index` of the tuple, struct or enum `ty`, with
+    /// where each is. An enum's tag comes first, then its fields.
+    fn fields(&mut self, ty: &Ty, index: usize, span: Span) -> Vec<(String, Ty, u32)> {
+        let (fields, mut offset) = match ty {
+            Ty::Tuple(elems) => (elems.iter().enumerate().map(|(i, e)| (i.to_string(), e.clone())).collect(), 0),
+            _ if self.krate.is_enum(ty) => (self.krate.fields_of(ty, index), self.payload(ty, span)),
+            _ => (self.krate.fields_of(ty, index), 0),
+        };
+        let mut placed = Vec::new();
+        for (name, field_ty) in fields {
+            let layout = self.layout(&field_ty, span);
+            offset = align_up(offset, layout.align);
+            placed.push((name, field_ty, offset));
+            offset += layout.size;
+        }
+        placed
+    }
+
+    /// Where the fields of an enum's variants start, after its tag
+    fn payload(&mut self, ty: &Ty, span: Span) -> u32 {
+        let Ty::Adt(def, _) = ty else { return 4 };
+        let variants = self.types.adts[def].variants.len();
+        let mut align = 4;
+        for index in 0..variants {
+            for (_, field_ty) in self.krate.fields_of(ty, index) {
+                align = align.max(self.layout(&field_ty, span).align);
+            }
+        }
+        align_up(4, align)
+    }
+
+    /// Field `name` of the tuple or struct `ty`: where it is and its type
+    fn field(&mut self, ty: &Ty, name: &str, span: Span) -> (u32, Ty) {
+        match self.fields(ty, 0, span).into_iter().find(|(field, _, _)| field == name) {
+            Some((_, field_ty, offset)) => (offset, field_ty),
+            None => {
+                self.unsupported(format!("field `{}` of `{}`", name, ty.display(self.res)), span);
+                (0, Ty::Error)
+            }
+        }
+    }
+
+    /// Replace the address on top of the stack with the `ty` there
+    fn load(&mut self, ty: &Ty, span: Span) {
+        let layout = self.layout(ty, span);
+        let signed = matches!(ty, Ty::Prim(PrimitiveType::I8 | PrimitiveType::I16));
+        let op = match (layout.repr, layout.size) {
+            (Repr::Unit, _) => "drop",
+            (Repr::Mem, _) => return,
+            (Repr::I32, 1) if signed => "i32.load8_s",
+            (Repr::I32, 1) => "i32.load8_u",
+            (Repr::I32, 2) if signed => "i32.load16_s",
+            (Repr::I32, 2) => "i32.load16_u",
+            (Repr::I32, _) => "i32.load",
+            (Repr::I64, _) => "i64.load",
+            (Repr::F32, _) => "f32.load",
+            (Repr::F64, _) => "f64.load",
+        };
+        self.line(op);
+    }
+
+    /// Store the `ty` on top of the stack at the address below it
+    fn store(&mut self, ty: &Ty, span: Span) {
+        let layout = self.layout(ty, span);
+        match (layout.repr, layout.size) {
+            (Repr::Unit, _) => self.line("drop"),
+            (Repr::Mem, size) => {
+                self.line(&format!("i32.const {}", size));
+                self.line("memory.copy");
+            }
+            (Repr::I32, 1) => self.line("i32.store8"),
+            (Repr::I32, 2) => self.line("i32.store16"),
+            (Repr::I32, _) => self.line("i32.store"),
+            (Repr::I64, _) => self.line("i64.store"),
+            (Repr::F32, _) => self.line("f32.store"),
+            (Repr::F64, _) => self.line("f64.store"),
+        }
+    }
+
+    /// Push a zero of type `ty`, where an expression could not be compiled
+    fn dummy(&mut self, ty: &Ty, span: Span) {
+        match self.repr(ty, span) {
+            Repr::Unit => {}
+            Repr::I32 | Repr::Mem => self.line("i32.const 0"),
+            Repr::I64 => self.line("i64.const 0"),
+            Repr::F32 => self.line("f32.const 0"),
+            Repr::F64 => self.line("f64.const 0"),
+        }
+    }
+
+    /// Drop the `ty` on top of the stack
+    fn discard(&mut self, ty: &Ty, span: Span) {
+        if self.repr(ty, span) != Repr::Unit {
+            self.line("drop");
+        }
+    }
+
+    /// Replace the pointer of type `ty` on top of the stack with what it points to
+    fn deref(&mut self, ty: &Ty) {
+        if let Some(inner) = self.krate.pointee(ty) {
+            self.load(&inner, Span::default());
+        }
+    }
+
+    /// Follow every pointer in the `ty` on top of the stack
+    fn peel_value(&mut self, ty: &Ty) {
+        let mut ty = ty.clone();
+        while let Some(inner) = self.krate.pointee(&ty) {
+            self.deref(&ty);
+            ty = inner;
+        }
+    }
+
+    /// Keep following pointers while what they point to is a pointer too,
+    /// leaving the address of the value of type `ty` ends at
+    fn peel_pointers(&mut self, ty: &Ty) {
+        let mut ty = ty.clone();
+        while let Some(inner) = self.krate.pointee(&ty).filter(|inner| self.krate.is_pointer(inner)) {
+            self.deref(&ty);
+            ty = inner;
+        }
+    }
+
+    /// Widen the integer of type `ty` on top of the stack to an `i64`
+    fn widen(&mut self, ty: &Ty) {
+        if self.repr(ty, Span::default()) == Repr::I32 {
+            match ty {
+                Ty::Prim(prim) if is_unsigned(*prim) || *prim == PrimitiveType::Char => {
+                    self.line("i64.extend_i32_u")
+                }
+                _ => self.line("i64.extend_i32_s"),
+            }
+        }
+    }
+
+    /// Bring an `i32` result back into the range of the narrow integer `ty`
+    fn normalize(&mut self, ty: &Ty) {
+        match ty {
+            Ty::Prim(PrimitiveType::I8) => self.line("i32.extend8_s"),
+            Ty::Prim(PrimitiveType::I16) => self.line("i32.extend16_s"),
+            Ty::Prim(PrimitiveType::U8) => {
+                self.line("i32.const 255");
+                self.line("i32.and");
+            }
+            Ty::Prim(PrimitiveType::U16) => {
+                self.line("i32.const 65535");
+                self.line("i32.and");
+            }
+            _ => {}
+        }
+    }
+
+    /// Leave the address of the value of type `ty` on top of the stack,
+    /// moving it into the frame when it is held by value
+    fn address_of_value(&mut self, ty: &Ty, span: Span) {
+        match self.repr(ty, span) {
+            Repr::Mem => {}
+            repr => {
+                let slot = self.slot(ty, span);
+                if let Some(val) = repr.val() {
+                    let value = self.spill(val);
+                    self.addr(slot);
+                    self.get(&value);
+                    self.store(ty, span);
+                }
+                self.addr(slot);
+            }
+        }
+    }
+
+    // ========== Instances ==========
+
+    /// Name of `def` with the generic arguments `args`, queueing its body
+    fn instance(&mut self, def: DefId, args: Vec<Ty>) -> String {
+        if let Some(name) = self.instances.get(&(def, args.clone())) {
+            return name.clone();
+        }
+        let mut base = self.krate.fn_names.get(&def).cloned().unwrap_or_else(|| self.res.def(def).name.clone());
+        if !args.is_empty() {
+            let args: Vec<String> = args.iter().map(|t| self.krate.mangle(t)).collect();
+            base = format!("{}__{}", base, args.join("_"));
+        }
+        let name = self.reserve(&base);
+        self.instances.insert((def, args.clone()), name.clone());
+        self.queue.push_back(Work::Fn { def, args, name: name.clone() });
+        name
+    }
+
+    /// The parameters and result of a function from `params` to `ret`, for `call_indirect`
+    fn signature(&mut self, params: &[Ty], ret: &Ty, span: Span) -> String {
+        let mut vals = Vec::new();
+        if self.repr(ret, span) == Repr::Mem {
+            vals.push("i32");
+        }
+        for param in params {
+            if let Some(val) = self.repr(param, span).val() {
+                vals.push(val);
+            }
+        }
+        let mut text = String::new();
+        if !vals.is_empty() {
+            text.push_str(&format!(" (param {})", vals.join(" ")));
+        }
+        text.push_str(&self.block_type(ret, span));
+        text
+    }
+
+    // ========== Statements ==========
+
+    /// Emit `block`, leaving its value as a `to`
+    fn block_as(&mut self, block: &Block, to: &Ty) -> Option<()> {
+        for stmt in &block.stmts {
+            self.mark(stmt.span);
+            // What follows a `return` or a panic is never reached
+            if !self.stmt(stmt) {
+                return None;
+            }
+        }
+        match &block.expr {
+            Some(tail) => {
+                self.mark(tail.span);
+                self.arg(tail, to)
+            }
+            None => Some(()),
+        }
+    }
+
+    /// Record that what follows came from `span`, when lines are asked for
+    fn mark(&mut self, span: Span) {
+        let Some(lines) = &mut self.lines else { return };
+        lines.push(span);
+        let index = lines.len() - 1;
+        self.line(&format!("i32.const {}", index));
+        self.line("call $ml_line");
+    }
+
+    /// Emit a statement, returning whether control can reach past it
+    fn stmt(&mut self, stmt: &ast::Statement) -> bool {
+        match &stmt.kind {
+            StatementKind::Let { pattern, init, .. } => self.let_stmt(pattern, init.as_ref()),
+            StatementKind::Expression(expr) => {
+                let ty = self.value_ty(expr);
+                if self.expr(expr).is_none() {
+                    return false;
+                }
+                if ty == Ty::Never {
+                    self.line("unreachable");
+                    return false;
+                }
+                self.discard(&ty, expr.span);
+                true
+            }
+            StatementKind::Item(_) | StatementKind::Error => true,
+        }
+    }
+
+    fn let_stmt(&mut self, pattern: &ast::Pattern, init: Option<&Expression>) -> bool {
+        let span = pattern.span;
+        let ty = self.ty(pattern.id);
+        let binding = match &pattern.kind {
+            PatternKind::Identifier(_) => self.res.def_of_node(pattern.id),
+            _ => None,
+        };
+        match (binding, init) {
+            (Some(def), None) => {
+                let slot = self.slot(&ty, span);
+                self.frame.locals.insert(def, slot);
+            }
+            (Some(def), Some(init)) => {
+                // The initializer still sees what the name meant before
+                let slot = self.slot(&ty, span);
+                self.addr(slot);
+                if self.arg(init, &ty).is_none() {
+                    return false;
+                }
+                self.store(&ty, span);
+                self.frame.locals.insert(def, slot);
+            }
+            (None, Some(init)) if matches!(pattern.kind, PatternKind::Wildcard) => {
+                let init_ty = self.ty(init.id);
+                if self.expr(init).is_none() {
+                    return false;
+                }
+                self.discard(&init_ty, init.span);
+            }
+            (None, Some(init)) => {
+                let init_ty = self.ty(init.id);
+                if self.expr(init).is_none() {
+                    return false;
+                }
+                self.address_of_value(&init_ty, init.span);
+                let place = self.spill("i32");
+                let (ok, failed) = (self.label("ok"), self.label("f"));
+                self.open(&format!("block {}", ok));
+                self.open(&format!("block {}", failed));
+                self.pattern(pattern, &place, &init_ty, &failed);
+                self.line(&format!("br {}", ok));
+                self.close("end");
+                self.line("unreachable");
+                self.close("end");
+            }
+            (None, None) => {}
+        }
+        true
+    }
+
+    // ========== Expressions ==========
+
+    /// Emit `expr`, leaving its value on the stack, or return `None` when it
+    /// never finishes, in which case what was emitted ends in a branch or a trap
+    fn expr(&mut self, expr: &Expression) -> Option<()> {
+        let span = expr.span;
+        match &expr.kind {
+            ExpressionKind::Literal(lit) => {
+                let ty = self.ty(expr.id);
+                self.literal(lit, &ty);
+                Some(())
+            }
+            ExpressionKind::Identifier(_) | ExpressionKind::Path(_) => self.name(expr),
+            ExpressionKind::Binary { left, op, right } => self.binary(expr, left, op, right),
+            ExpressionKind::Unary { op, expr: operand } => match op {
+                UnaryOp::Neg => {
+                    let ty = self.ty(operand.id);
+                    self.expr(operand)?;
+                    self.peel_value(&ty);
+                    let ty = self.krate.peel(&ty);
+                    match (self.repr(&ty, span), ty) {
+                        (Repr::F32, _) => self.line("f32.neg"),
+                        (Repr::F64, _) => self.line("f64.neg"),
+                        // Negation is subtraction from zero, and overflows where it does
+                        (repr, Ty::Prim(prim)) => {
+                            let val = if repr == Repr::I64 { "i64" } else { "i32" };
+                            let value = self.spill(val);
+                            self.line(&format!("{}.const 0", val));
+                            self.get(&value);
+                            self.checked(&BinaryOp::Sub, prim, val, "negate", span);
+                        }
+                        (_, ty) => self.unsupported(format!("negating `{}`", ty.display(self.res)), span),
+                    }
+                    Some(())
+                }
+                UnaryOp::Not => {
+                    let ty = self.ty(operand.id);
+                    self.expr(operand)?;
+                    self.peel_value(&ty);
+                    let ty = self.krate.peel(&ty);
+                    match self.repr(&ty, span) {
+                        _ if ty == Ty::bool() => self.line("i32.eqz"),
+                        Repr::I64 => {
+                            self.line("i64.const -1");
+                            self.line("i64.xor");
+                        }
+                        _ => {
+                            self.line("i32.const -1");
+                            self.line("i32.xor");
+                            self.normalize(&ty);
+                        }
+                    }
+                    Some(())
+                }
+                UnaryOp::Deref => {
+                    self.expr(operand)?;
+                    self.deref(&self.ty(operand.id));
+                    Some(())
+                }
+                UnaryOp::Ref | UnaryOp::RefMut => self.borrow(operand, *op == UnaryOp::RefMut),
+            },
+            ExpressionKind::Call { func, args } => self.call(expr, func, args),
+            ExpressionKind::MethodCall { receiver, method, args } => self.method_call(expr, receiver, method, args),
+            ExpressionKind::If { cond, then_block, else_block } => {
+                self.if_expr(expr, cond, then_block, else_block.as_ref())
+            }
+            ExpressionKind::Match { expr: scrutinee, arms } => self.match_expr(expr, scrutinee, arms),
+            ExpressionKind::Block(block) => self.block_as(block, &self.ty(expr.id)),
+            ExpressionKind::Loop(body) => self.loop_expr(expr, body),
+            ExpressionKind::While { cond, body } => {
+                self.while_loop(cond, body);
+                Some(())
+            }
+            ExpressionKind::For { pattern, iter, body } => {
+                self.for_loop(pattern, iter, body);
+                Some(())
+            }
+            ExpressionKind::Return(value) => {
+                if let Some(value) = value {
+                    let ret = self.frame.ret.clone();
+                    self.arg(value, &ret)?;
+                }
+                self.line("br $exit");
+                None
+            }
+            ExpressionKind::Break(value) => {
+                let (exit, _, ty) = self.frame.loops.last().cloned()?;
+                if let Some(value) = value {
+                    self.arg(value, &ty)?;
+                }
+                self.line(&format!("br {}", exit));
+                None
+            }
+            ExpressionKind::Continue => {
+                let (_, next, _) = self.frame.loops.last().cloned()?;
+                self.line(&format!("br {}", next));
+                None
+            }
+            ExpressionKind::Tuple(elems) if elems.is_empty() => Some(()),
+            ExpressionKind::Tuple(elems) => {
+                let ty = self.ty(expr.id);
+                let fields: Vec<(String, &Expression)> =
+                    elems.iter().enumerate().map(|(i, elem)| (i.to_string(), elem)).collect();
+                self.aggregate(&ty, 0, &fields, span)
+            }
+            ExpressionKind::Array(elems) => {
+                let ty = self.ty(expr.id);
+                let elem_ty = match &ty {
+                    Ty::Array(elem, _) => (**elem).clone(),
+                    _ => Ty::Error,
+                };
+                let size = self.layout(&elem_ty, span).size;
+                let slot = self.slot(&ty, span);
+                for (i, elem) in elems.iter().enumerate() {
+                    self.addr(slot + i as u32 * size);
+                    self.arg(elem, &elem_ty)?;
+                    self.store(&elem_ty, span);
+                }
+                self.addr(slot);
+                Some(())
+            }
+            ExpressionKind::Index { .. } | ExpressionKind::Field { .. } => {
+                self.place(expr)?;
+                self.load(&self.ty(expr.id), span);
+                Some(())
+            }
+            ExpressionKind::Struct { fields, .. } => {
+                let ty = self.ty(expr.id);
+                let def = match (self.res.res(expr.id), &ty) {
+                    (Some(Res::Def(def)), _) => *def,
+                    (_, Ty::Adt(def, _)) => *def,
+                    _ => {
+                        self.unsupported("this struct literal", span);
+                        self.dummy(&ty, span);
+                        return Some(());
+                    }
+                };
+                let fields: Vec<(String, &Expression)> =
+                    fields.iter().map(|(name, value)| (name.clone(), value)).collect();
+                self.construct(def, &ty, &fields, span)
+            }
+            ExpressionKind::Comptime(inner) => self.expr(inner),
+            ExpressionKind::Closure { .. } => {
+                self.unsupported("closures", span);
+                self.dummy(&self.ty(expr.id), span);
+                Some(())
+            }
+            _ => {
+                self.unsupported("this expression", span);
+                self.dummy(&self.ty(expr.id), span);
+                Some(())
+            }
+        }
+    }
+
+    fn if_expr(
+        &mut self,
+        expr: &Expression,
+        cond: &Expression,
+        then_block: &Block,
+        else_block: Option<&Block>,
+    ) -> Option<()> {
+        let ty = self.ty(expr.id);
+        self.expr(cond)?;
+        let result = self.block_type(&ty, expr.span);
+        self.open(&format!("if{}", result));
+        self.block_as(then_block, &ty);
+        if let Some(else_block) = else_block {
+            self.reopen("else");
+            self.block_as(else_block, &ty);
+        }
+        self.close("end");
+        self.diverges(&ty)
+    }
+
+    /// After a branching expression: a `!` one never gets here
+    fn diverges(&mut self, ty: &Ty) -> Option<()> {
+        match ty {
+            Ty::Never => {
+                self.line("unreachable");
+                None
+            }
+            _ => Some(()),
+        }
+    }
+
+    /// Arms are tried in turn, each in a block its failed tests leave
+    fn match_expr(&mut self, expr: &Expression, scrutinee: &Expression, arms: &[ast::MatchArm]) -> Option<()> {
+        let ty = self.ty(expr.id);
+        let scrutinee_ty = self.ty(scrutinee.id);
+        // Arms test the scrutinee where it lives, or a copy of its value
+        if self.is_place(scrutinee) {
+            self.place(scrutinee)?;
+        } else {
+            self.expr(scrutinee)?;
+            self.address_of_value(&scrutinee_ty, scrutinee.span);
+        }
+        let place = self.spill("i32");
+        let done = self.label("m");
+        let result = self.block_type(&ty, expr.span);
+        self.open(&format!("block {}{}", done, result));
+        for arm in arms {
+            let next = self.label("a");
+            self.open(&format!("block {}", next));
+            self.pattern(&arm.pattern, &place, &scrutinee_ty, &next);
+            if let Some(guard) = &arm.guard {
+                if self.expr(guard).is_some() {
+                    self.line("i32.eqz");
+                    self.line(&format!("br_if {}", next));
+                }
+            }
+            if self.arg(&arm.body, &ty).is_some() {
+                self.line(&format!("br {}", done));
+            }
+            self.close("end");
+        }
+        // Checking made the match exhaustive
+        self.line("unreachable");
+        self.close("end");
+        self.diverges(&ty)
+    }
+
+    fn loop_expr(&mut self, expr: &Expression, body: &Block) -> Option<()> {
+        let ty = self.ty(expr.id);
+        let (exit, next) = (self.label("b"), self.label("c"));
+        let result = self.block_type(&ty, expr.span);
+        self.open(&format!("block {}{}", exit, result));
+        self.open(&format!("loop {}", next));
+        self.frame.loops.push((exit.clone(), next.clone(), ty.clone()));
+        self.body(body);
+        self.frame.loops.pop();
+        self.line(&format!("br {}", next));
+        self.close("end");
+        self.line("unreachable");
+        self.close("end");
+        self.diverges(&ty)
+    }
+
+    /// The body of a loop, whose value is not used
+    fn body(&mut self, body: &Block) {
+        let ty = match &body.expr {
+            Some(tail) => self.ty(tail.id),
+            None => Ty::unit(),
+        };
+        if self.block_as(body, &ty).is_some() {
+            self.discard(&ty, body.span);
+        }
+    }
+
+    fn while_loop(&mut self, cond: &Expression, body: &Block) {
+        let (exit, next) = (self.label("b"), self.label("c"));
+        self.open(&format!("block {}", exit));
+        self.open(&format!("loop {}", next));
+        if self.expr(cond).is_some() {
+            self.line("i32.eqz");
+            self.line(&format!("br_if {}", exit));
+            self.frame.loops.push((exit.clone(), next.clone(), Ty::unit()));
+            self.body(body);
+            self.frame.loops.pop();
+            self.line(&format!("br {}", next));
+        }
+        self.close("end");
+        self.close("end");
+    }
+
+    /// `for` over a vector, an array or the characters of a string
+    fn for_loop(&mut self, pattern: &ast::Pattern, iter: &Expression, body: &Block) {
+        let span = iter.span;
+        let source = match &iter.kind {
+            ExpressionKind::MethodCall { receiver, method, args }
+                if args.is_empty() && matches!(method.as_str(), "iter" | "iter_mut" | "into_iter") =>
+            {
+                receiver
+            }
+            ExpressionKind::MethodCall { receiver, method, args } if args.is_empty() && method == "chars" => {
+                return self.chars_loop(pattern, receiver, body);
+            }
+            ExpressionKind::Unary { op: UnaryOp::Ref | UnaryOp::RefMut, expr: inner } => inner,
+            _ => iter,
+        };
+        let source_ty = self.ty(source.id);
+        let sequence = self.krate.peel(&source_ty);
+        let (elem, len) = match &sequence {
+            Ty::Array(elem, Some(len)) => ((**elem).clone(), Some(*len)),
+            Ty::Adt(_, args) if self.krate.is_vec(&sequence) => (args.first().cloned().unwrap_or(Ty::Error), None),
+            _ => {
+                self.unsupported(format!("a `for` loop over `{}`", source_ty.display(self.res)), span);
+                return;
+            }
+        };
+        // Elements are read where they live, so borrowing them borrows the original
+        if self.container(source).is_none() {
+            return;
+        }
+        let seq = self.spill("i32");
+        let (count, data) = match len {
+            Some(len) => {
+                self.line(&format!("i32.const {}", len));
+                (self.spill("i32"), seq)
+            }
+            None => {
+                self.get(&seq);
+                self.line("i32.load offset=4");
+                let count = self.spill("i32");
+                self.get(&seq);
+                self.line("i32.load");
+                (count, self.spill("i32"))
+            }
+        };
+        let size = self.layout(&elem, span).size;
+        self.repeat(&count, |s, i, exit| {
+            s.get(&data);
+            s.get(i);
+            s.line(&format!("i32.const {}", size));
+            s.line("i32.mul");
+            s.line("i32.add");
+            let at = s.spill("i32");
+            s.pattern(pattern, &at, &elem, exit);
+            s.body(body);
+        });
+    }
+
+    /// Run `body` for each `i` below the `i32` in `count`. `body` gets `i`
+    /// and the label leaving the loop, and may `continue` or `break` it.
+    fn repeat(&mut self, count: &str, body: impl FnOnce(&mut Self, &str, &str)) {
+        let (next_i, i) = (self.scratch("i32"), self.scratch("i32"));
+        let (exit, next) = (self.label("b"), self.label("c"));
+        self.line("i32.const 0");
+        self.line(&format!("local.set {}", next_i));
+        self.open(&format!("block {}", exit));
+        self.open(&format!("loop {}", next));
+        self.get(&next_i);
+        self.get(count);
+        self.line("i32.ge_u");
+        self.line(&format!("br_if {}", exit));
+        self.get(&next_i);
+        self.line(&format!("local.tee {}", i));
+        self.line("i32.const 1");
+        self.line("i32.add");
+        self.line(&format!("local.set {}", next_i));
+        self.frame.loops.push((exit.clone(), next.clone(), Ty::unit()));
+        body(self, &i, &exit);
+        self.frame.loops.pop();
+        self.line(&format!("br {}", next));
+        self.close("end");
+        self.close("end");
+    }
+
+    fn chars_loop(&mut self, pattern: &ast::Pattern, text: &Expression, body: &Block) {
+        let span = text.span;
+        if self.expr(text).is_none() {
+            return;
+        }
+        self.peel_value(&self.ty(text.id));
+        let start = self.spill("i32");
+        self.get(&start);
+        self.line("i32.const 4");
+        self.line("i32.add");
+        let p = self.spill("i32");
+        self.get(&p);
+        self.get(&start);
+        self.line("i32.load");
+        self.line("i32.add");
+        let end = self.spill("i32");
+        let char_ty = Ty::Prim(PrimitiveType::Char);
+        let slot = self.slot(&char_ty, span);
+        let (exit, next) = (self.label("b"), self.label("c"));
+        self.open(&format!("block {}", exit));
+        self.open(&format!("loop {}", next));
+        self.get(&p);
+        self.get(&end);
+        self.line("i32.ge_u");
+        self.line(&format!("br_if {}", exit));
+        self.addr(slot);
+        self.get(&p);
+        self.line("call $ml_char_at");
+        self.line("i32.store");
+        self.get(&p);
+        self.get(&p);
+        self.line("call $ml_char_len");
+        self.line("i32.add");
+        self.line(&format!("local.set {}", p));
+        self.addr(slot);
+        let at = self.spill("i32");
+        self.pattern(pattern, &at, &char_ty, &exit);
+        self.frame.loops.push((exit.clone(), next.clone(), Ty::unit()));
+        self.body(body);
+        self.frame.loops.pop();
+        self.line(&format!("br {}", next));
+        self.close("end");
+        self.close("end");
+    }
+
+    fn literal(&mut self, lit: &Literal, ty: &Ty) {
+        let repr = self.repr(ty, Span::default());
+        match lit {
+            Literal::Int(n) => match repr {
+                Repr::F32 => self.line(&format!("f32.const {:?}", *n as f32)),
+                Repr::F64 => self.line(&format!("f64.const {:?}", *n as f64)),
+                Repr::I64 => self.line(&format!("i64.const {}", n)),
+                _ => self.line(&format!("i32.const {}", *n as i32)),
+            },
+            Literal::Float(x) if repr == Repr::F32 => self.line(&format!("f32.const {:?}", *x as f32)),
+            Literal::Float(x) => self.line(&format!("f64.const {:?}", x)),
+            Literal::String(text) => {
+                let addr = self.string(text);
+                self.line(&format!("i32.const {}", addr));
+            }
+            Literal::Char(c) => self.line(&format!("i32.const {}", *c as u32)),
+            Literal::Bool(b) => self.line(&format!("i32.const {}", *b as i32)),
+            Literal::Unit => {}
+        }
+    }
+
+    /// The value a name stands for
+    fn name(&mut self, expr: &Expression) -> Option<()> {
+        let span = expr.span;
+        let ty = self.ty(expr.id);
+        let def = match self.res.res(expr.id) {
+            Some(Res::Def(def)) => *def,
+            Some(Res::Partial { .. }) => match self.krate.partial(expr) {
+                Ok(def) => def,
+                Err(path) => {
+                    self.unsupported(format!("`{}` as a value", path), span);
+                    self.dummy(&ty, span);
+                    return Some(());
+                }
+            },
+            None => {
+                self.dummy(&ty, span);
+                return Some(());
+            }
+        };
+        let definition = self.res.def(def);
+        match definition.kind {
+            DefKind::Local | DefKind::Param => match self.frame.locals.get(&def).copied() {
+                Some(offset) => {
+                    self.addr(offset);
+                    self.load(&ty, span);
+                }
+                None => {
+                    self.unsupported(format!("use of `{}` before it is bound", definition.name), span);
+                    self.dummy(&ty, span);
+                }
+            },
+            DefKind::Const => self.constant(def, span),
+            DefKind::Variant | DefKind::Struct if !matches!(ty, Ty::Fn(..)) => {
+                return self.construct(def, &ty, &[], span);
+            }
+            DefKind::Function if self.krate.is_user_fn(def) => {
+                let target = match &ty {
+                    Ty::Fn(inputs, output) => self.krate.resolve(def, inputs, output),
+                    _ => None,
+                };
+                match target {
+                    Some((def, args)) => {
+                        let name = self.instance(def, args);
+                        let slot = self.table_slot(&name);
+                        self.line(&format!("i32.const {}", slot));
+                    }
+                    None => {
+                        self.unsupported(format!("`{}` without a body for this type", definition.name), span);
+                        self.dummy(&ty, span);
+                    }
+                }
+            }
+            kind => {
+                self.unsupported(format!("{} `{}` as a value", kind.describe(), definition.name), span);
+                self.dummy(&ty, span);
+            }
+        }
+        Some(())
+    }
+
+    /// A constant: a literal is emitted where it is read, anything else is
+    /// computed by a function
+    fn constant(&mut self, def: DefId, span: Span) {
+        let Some(c) = self.krate.consts.get(&def).copied() else {
+            self.unsupported(format!("constant `{}`", self.res.def(def).name), span);
+            self.line("unreachable");
+            return;
+        };
+        let ty = self.types.type_of(c.value.id).cloned().unwrap_or(Ty::Error);
+        if let Some((lit, negated)) = literal_of(&c.value) {
+            self.literal(lit, &ty);
+            if negated {
+                match self.repr(&ty, span) {
+                    Repr::F32 => self.line("f32.neg"),
+                    Repr::F64 => self.line("f64.neg"),
+                    Repr::I64 => {
+                        self.line("i64.const -1");
+                        self.line("i64.mul");
+                    }
+                    _ => {
+                        self.line("i32.const -1");
+                        self.line("i32.mul");
+                        self.normalize(&ty);
+                    }
+                }
+            }
+            return;
+        }
+        let name = match self.const_fns.get(&def) {
+            Some(name) => name.clone(),
+            None => {
+                let name = self.reserve(&c.name);
+                self.const_fns.insert(def, name.clone());
+                self.queue.push_back(Work::Const { def, name: name.clone() });
+                name
+            }
+        };
+        if self.repr(&ty, span) == Repr::Mem {
+            let slot = self.slot(&ty, span);
+            self.addr(slot);
+        }
+        self.line(&format!("call ${}", name));
+    }
+
+    /// A struct or variant literal of type `ty`, from the values of its fields
+    fn construct(&mut self, def: DefId, ty: &Ty, fields: &[(String, &Expression)], span: Span) -> Option<()> {
+        let index = self.krate.variant_of(def).map_or(0, |(_, index)| index);
+        self.aggregate(ty, index, fields, span)
+    }
+
+    /// Variant `index` of `ty` in a new slot, with its fields set in the order given
+    fn aggregate(&mut self, ty: &Ty, index: usize, fields: &[(String, &Expression)], span: Span) -> Option<()> {
+        if self.repr(ty, span) != Repr::Mem {
+            for (_, value) in fields {
+                self.expr(value)?;
+                self.discard(&self.ty(value.id), span);
+            }
+            return Some(());
+        }
+        let slot = self.slot(ty, span);
+        if self.krate.is_enum(ty) {
+            self.addr(slot);
+            self.line(&format!("i32.const {}", index));
+            self.line("i32.store");
+        }
+        let layout = self.fields(ty, index, span);
+        for (name, value) in fields {
+            let Some((_, field_ty, offset)) = layout.iter().find(|(field, _, _)| field == name).cloned() else {
+                self.unsupported(format!("field `{}` of `{}`", name, ty.display(self.res)), span);
+                continue;
+            };
+            self.addr(slot + offset);
+            self.arg(value, &field_ty)?;
+            self.store(&field_ty, span);
+        }
+        self.addr(slot);
+        Some(())
+    }
+
+    fn binary(&mut self, expr: &Expression, left: &Expression, op: &BinaryOp, right: &Expression) -> Option<()> {
+        let span = expr.span;
+        match op {
+            BinaryOp::Assign => {
+                let ty = self.ty(left.id);
+                self.arg(right, &ty)?;
+                let value = self.repr(&ty, span).val().map(|val| self.spill(val));
+                if !self.is_place(left) {
+                    self.unsupported("assignment to this expression", left.span);
+                    return Some(());
+                }
+                self.place(left)?;
+                if let Some(value) = value {
+                    self.get(&value);
+                }
+                self.store(&ty, span);
+                Some(())
+            }
+            BinaryOp::And | BinaryOp::Or => {
+                self.expr(left)?;
+                self.open("if (result i32)");
+                if *op == BinaryOp::And {
+                    self.expr(right);
+                    self.reopen("else");
+                    self.line("i32.const 0");
+                } else {
+                    self.line("i32.const 1");
+                    self.reopen("else");
+                    self.expr(right);
+                }
+                self.close("end");
+                Some(())
+            }
+            _ => {
+                let (left_ty, right_ty) = (self.ty(left.id), self.ty(right.id));
+                // Integers of different widths meet at the wider one, as
+                // with `len() > 2`
+                let (peeled_left, peeled_right) = (self.krate.peel(&left_ty), self.krate.peel(&right_ty));
+                let wide = |s: &mut Self, ty: &Ty| ty.is_integral() && s.repr(ty, span) == Repr::I64;
+                let widen_left = wide(self, &peeled_right) && peeled_left.is_integral() && !wide(self, &peeled_left);
+                let widen_right = wide(self, &peeled_left) && peeled_right.is_integral() && !wide(self, &peeled_right);
+                self.expr(left)?;
+                self.peel_value(&left_ty);
+                if widen_left {
+                    self.widen(&peeled_left);
+                }
+                self.expr(right)?;
+                self.peel_value(&right_ty);
+                if widen_right {
+                    self.widen(&peeled_right);
+                }
+                let (left_ty, right_ty) = match (widen_left, widen_right) {
+                    (true, _) => (peeled_right.clone(), peeled_right),
+                    (_, true) => (peeled_left.clone(), peeled_left),
+                    _ => (peeled_left, peeled_right),
+                };
+                // Adding anything else to a string appends its text
+                if *op == BinaryOp::Add && self.krate.is_str(&left_ty) && !self.krate.is_str(&right_ty) {
+                    let r = self.spill_value(&right_ty, span);
+                    let l = self.spill("i32");
+                    let buf = self.buffer();
+                    self.get(&buf);
+                    self.get(&l);
+                    self.line("call $ml_buf_str");
+                    self.get(&buf);
+                    self.get_value(&r);
+                    self.fmt_value(&right_ty, &Spec::default(), false, span);
+                    self.get(&buf);
+                    self.line("call $ml_buf_finish");
+                    return Some(());
+                }
+                self.operate(op, &left_ty, &right_ty, span);
+                Some(())
+            }
+        }
+    }
+
+    /// Apply `op` to the two values of type `ty` on top of the stack; the
+    /// right one is a `right` for shifts
+    fn operate(&mut self, op: &BinaryOp, ty: &Ty, right: &Ty, span: Span) {
+        if self.krate.is_str(ty) {
+            match op {
+                BinaryOp::Add => self.line("call $ml_str_concat"),
+                BinaryOp::Eq => self.line("call $ml_str_eq"),
+                BinaryOp::Ne => {
+                    self.line("call $ml_str_eq");
+                    self.line("i32.eqz");
+                }
+                BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => {
+                    self.line("call $ml_str_cmp");
+                    self.line("i32.const 0");
+                    self.line(&format!("i32.{}_s", compare_op(op)));
+                }
+                _ => self.unsupported(format!("`{}` on strings", op_symbol(op)), span),
+            }
+            return;
+        }
+        let Ty::Prim(prim) = ty else {
+            match op {
+                _ if ty.is_unit() && matches!(op, BinaryOp::Eq | BinaryOp::Ne) => {
+                    self.line(&format!("i32.const {}", (*op == BinaryOp::Eq) as i32));
+                }
+                BinaryOp::Eq => self.eq(ty, span),
+                BinaryOp::Ne => {
+                    self.eq(ty, span);
+                    self.line("i32.eqz");
+                }
+                _ => self.unsupported(format!("`{}` on `{}`", op_symbol(op), ty.display(self.res)), span),
+            }
+            return;
+        };
+        let Some(val) = self.repr(ty, span).val() else {
+            self.line(&format!("i32.const {}", matches!(op, BinaryOp::Eq | BinaryOp::Le | BinaryOp::Ge) as i32));
+            return;
+        };
+        let float = ty.is_float();
+        let unsigned = is_unsigned(*prim) || matches!(prim, PrimitiveType::Bool | PrimitiveType::Char);
+        let sign = if unsigned { "_u" } else { "_s" };
+        match op {
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul if float => self.line(&format!("{}.{}", val, arith_op(op))),
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul => self.checked(op, *prim, val, overflow_verb(op), span),
+            BinaryOp::Div if float => self.line(&format!("{}.div", val)),
+            BinaryOp::Mod if float => self.line(&format!("call $ml_fmod_{}", val)),
+            BinaryOp::Div | BinaryOp::Mod => {
+                let divisor = self.scratch(val);
+                self.line(&format!("local.tee {}", divisor));
+                self.line(&format!("{}.eqz", val));
+                self.open("if");
+                let loc = self.loc(span);
+                let message = match op {
+                    BinaryOp::Div => "attempt to divide by zero",
+                    _ => "attempt to calculate the remainder with a divisor of zero",
+                };
+                let message = self.string(message);
+                self.line(&format!("i32.const {}", loc));
+                self.line(&format!("i32.const {}", message));
+                self.line("call $ml_panic");
+                self.close("end");
+                self.get(&divisor);
+                self.checked(op, *prim, val, overflow_verb(op), span);
+            }
+            BinaryOp::Eq | BinaryOp::Ne => self.line(&format!("{}.{}", val, compare_op(op))),
+            BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge if float => {
+                self.line(&format!("{}.{}", val, compare_op(op)))
+            }
+            BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => {
+                self.line(&format!("{}.{}{}", val, compare_op(op), sign))
+            }
+            BinaryOp::BitAnd => self.line(&format!("{}.and", val)),
+            BinaryOp::BitOr => self.line(&format!("{}.or", val)),
+            BinaryOp::BitXor => self.line(&format!("{}.xor", val)),
+            BinaryOp::Shl | BinaryOp::Shr => {
+                match (val, self.repr(right, span)) {
+                    ("i64", Repr::I32) => self.line("i64.extend_i32_u"),
+                    ("i32", Repr::I64) => self.line("i32.wrap_i64"),
+                    _ => {}
+                }
+                match op {
+                    BinaryOp::Shl => self.line(&format!("{}.shl", val)),
+                    _ => self.line(&format!("{}.shr{}", val, sign)),
+                }
+                self.normalize(ty);
+            }
+            BinaryOp::And | BinaryOp::Or | BinaryOp::Assign => {}
+        }
+    }
+
+    /// Apply the arithmetic `op` to the two integers of type `prim` on top of
+    /// the stack, panicking as the interpreter does, with `verb` in the
+    /// message, when the result does not fit. Narrower integers are computed
+    /// exactly in 64 bits and then checked against their range.
+    fn checked(&mut self, op: &BinaryOp, prim: PrimitiveType, val: &str, verb: &str, span: Span) {
+        let loc = self.loc(span);
+        let message = self.string(&format!("attempt to {} with overflow", verb));
+        let sign = if is_unsigned(prim) { "_u" } else { "_s" };
+        let name = match op {
+            BinaryOp::Div => "div",
+            BinaryOp::Mod => "rem",
+            op => arith_op(op),
+        };
+        if val == "i64" {
+            self.line(&format!("i32.const {}", loc));
+            self.line(&format!("i32.const {}", message));
+            self.line(&format!("call $ml_{}_{}64", name, &sign[1..]));
+            return;
+        }
+        let right = self.spill("i32");
+        self.line(&format!("i64.extend_i32{}", sign));
+        self.get(&right);
+        self.line(&format!("i64.extend_i32{}", sign));
+        match op {
+            BinaryOp::Div | BinaryOp::Mod => self.line(&format!("i64.{}{}", name, sign)),
+            _ => self.line(&format!("i64.{}", name)),
+        }
+        let (lo, hi) = int_range(prim).unwrap_or((i64::MIN.into(), i64::MAX.into()));
+        self.line(&format!("i64.const {}", lo));
+        self.line(&format!("i64.const {}", hi));
+        self.line(&format!("i32.const {}", loc));
+        self.line(&format!("i32.const {}", message));
+        self.line("call $ml_fit");
+        self.line("i32.wrap_i64");
+    }
+
+    // ========== Places ==========
+
+    /// Whether `expr` names memory that can be read and written in place
+    fn is_place(&self, expr: &Expression) -> bool {
+        match &expr.kind {
+            ExpressionKind::Identifier(_) => matches!(
+                self.res.res(expr.id),
+                Some(Res::Def(def)) if matches!(self.res.def(*def).kind, DefKind::Local | DefKind::Param)
+            ),
+            ExpressionKind::Field { expr: base, .. } | ExpressionKind::Index { expr: base, .. } => {
+                self.krate.is_pointer(&self.ty(base.id)) || self.is_place(base)
+            }
+            ExpressionKind::Unary { op: UnaryOp::Deref, expr: inner } => {
+                self.krate.is_pointer(&self.ty(inner.id)) || self.is_place(inner)
+            }
+            _ => false,
+        }
+    }
+
+    /// Push the address of `expr`: where it lives when it is a place, or else
+    /// a slot holding its value
+    fn place(&mut self, expr: &Expression) -> Option<()> {
+        let span = expr.span;
+        match &expr.kind {
+            ExpressionKind::Identifier(_) if self.is_place(expr) => {
+                let def = match self.res.res(expr.id) {
+                    Some(Res::Def(def)) => *def,
+                    _ => return Some(()),
+                };
+                match self.frame.locals.get(&def).copied() {
+                    Some(offset) => self.addr(offset),
+                    None => {
+                        self.unsupported(format!("use of `{}` before it is bound", self.res.def(def).name), span);
+                        self.line("i32.const 0");
+                    }
+                }
+            }
+            ExpressionKind::Field { expr: base, field } => {
+                let ty = self.container(base)?;
+                let (offset, _) = self.field(&ty, field, span);
+                self.offset(offset);
+            }
+            ExpressionKind::Index { expr: base, index } => self.element(base, index, span)?,
+            ExpressionKind::Unary { op: UnaryOp::Deref, expr: inner } if self.krate.is_pointer(&self.ty(inner.id)) => {
+                self.expr(inner)?
+            }
+            ExpressionKind::Unary { op: UnaryOp::Deref, expr: inner } if self.is_place(inner) => self.place(inner)?,
+            _ => {
+                self.expr(expr)?;
+                self.address_of_value(&self.ty(expr.id), span);
+            }
+        }
+        Some(())
+    }
+
+    /// Push the address of the tuple, struct, array or vector `base`,
+    /// through any pointers, returning its type
+    fn container(&mut self, base: &Expression) -> Option<Ty> {
+        let ty = self.ty(base.id);
+        if self.krate.is_pointer(&ty) {
+            self.expr(base)?;
+            self.peel_pointers(&ty);
+        } else {
+            self.place(base)?;
+        }
+        Some(self.krate.peel(&ty))
+    }
+
+    /// The address of element `index` of the vector or array `base`, checked against its length
+    fn element(&mut self, base: &Expression, index: &Expression, span: Span) -> Option<()> {
+        let sequence = self.container(base)?;
+        let seq = self.spill("i32");
+        let index_ty = self.ty(index.id);
+        self.expr(index)?;
+        self.peel_value(&index_ty);
+        self.widen(&self.krate.peel(&index_ty));
+        let elem = match &sequence {
+            Ty::Array(elem, Some(len)) => {
+                self.line(&format!("i32.const {}", len));
+                (**elem).clone()
+            }
+            Ty::Adt(_, args) if self.krate.is_vec(&sequence) => {
+                self.get(&seq);
+                self.line("i32.load offset=4");
+                args.first().cloned().unwrap_or(Ty::Error)
+            }
+            _ => {
+                self.unsupported(format!("indexing `{}`", sequence.display(self.res)), span);
+                return Some(());
+            }
+        };
+        let loc = self.loc(span);
+        self.line(&format!("i32.const {}", loc));
+        self.line("call $ml_index");
+        let size = self.layout(&elem, span).size;
+        self.line(&format!("i32.const {}", size));
+        self.line("i32.mul");
+        self.get(&seq);
+        if self.krate.is_vec(&sequence) {
+            self.line("i32.load");
+        }
+        self.line("i32.add");
+        Some(())
+    }
+
+    /// Push a pointer to `operand`, or the string itself for `&str`
+    fn borrow(&mut self, operand: &Expression, mutable: bool) -> Option<()> {
+        let ty = self.ty(operand.id);
+        if !mutable && self.krate.is_str(&ty) {
+            return self.expr(operand);
+        }
+        self.place(operand)
+    }
+
+    /// Push the value of `expr` as a `to`, borrowing or dereferencing as checking allowed
+    fn arg(&mut self, expr: &Expression, to: &Ty) -> Option<()> {
+        let from = self.ty(expr.id);
+        if let Ty::Ref { ty: inner, mutable } = to {
+            if **inner == from {
+                return self.borrow(expr, *mutable);
+            }
+        }
+        self.expr(expr)?;
+        self.coerce(&from, to);
+        Some(())
+    }
+
+    fn coerce(&mut self, from: &Ty, to: &Ty) {
+        match (from, to) {
+            (Ty::Ref { ty: a, mutable: true }, Ty::Ref { ty: b, mutable: false }) if a == b && self.krate.is_str(b) => {
+                self.line("i32.load")
+            }
+            (Ty::Ref { ty: inner, .. }, _) if **inner == *to => self.deref(from),
+            _ => {}
+        }
+    }
+
+    /// Keep the value of type `ty` on top of the stack in a new local, unless it is `()`
+    fn spill_value(&mut self, ty: &Ty, span: Span) -> Option<String> {
+        let val = self.repr(ty, span).val()?;
+        Some(self.spill(val))
+    }
+
+    fn get_value(&mut self, local: &Option<String>) {
+        if let Some(local) = local {
+            self.get(local);
+        }
+    }
+
+    // ========== Calls ==========
+
+    /// After a call: one of a `!` function never returns
+    fn result(&mut self, ty: &Ty) -> Option<()> {
+        match ty {
+            Ty::Never => {
+                self.line("unreachable");
+                None
+            }
+            _ => Some(()),
+        }
+    }
+
+    fn call(&mut self, expr: &Expression, func: &Expression, args: &[Expression]) -> Option<()> {
+        let span = expr.span;
+        let ty = self.value_ty(expr);
+        match self.krate.callee(func) {
+            Callee::Fn(def) => {
+                let args: Vec<&Expression> = args.iter().collect();
+                return self.call_fn(def, &args, &ty, span);
+            }
+            Callee::Ctor(def, index) => {
+                let names = self.krate.fields_of(&ty, index).into_iter().map(|(name, _)| name);
+                let fields: Vec<(String, &Expression)> = names.zip(args).collect();
+                return self.construct(def, &ty, &fields, span);
+            }
+            Callee::Prelude(name) => return self.prelude_call(&name, args, &ty, span),
+            Callee::Library(path) => return self.library_call(&path, args, &ty, span),
+            Callee::Value => {}
+        }
+        // A function value, called through the table
+        let (params, output) = match self.ty(func.id) {
+            Ty::Fn(params, output) => (params, *output),
+            _ => (Vec::new(), ty.clone()),
+        };
+        self.expr(func)?;
+        let callee = self.spill("i32");
+        if self.repr(&output, span) == Repr::Mem {
+            let slot = self.slot(&output, span);
+            self.addr(slot);
+        }
+        for (arg, param) in args.iter().zip(&params) {
+            self.arg(arg, param)?;
+        }
+        self.get(&callee);
+        let signature = self.signature(&params, &output, span);
+        self.line(&format!("call_indirect $ml_fns{}", signature));
+        self.result(&ty)
+    }
+
+    /// Call the instance of `def` the argument types select
+    fn call_fn(&mut self, def: DefId, args: &[&Expression], ty: &Ty, span: Span) -> Option<()> {
+        let inputs: Vec<Ty> = args.iter().map(|arg| self.ty(arg.id)).collect();
+        let Some(instance) = self.krate.instance(def, &inputs, ty) else {
+            let name = self.res.def(def).name.clone();
+            self.unsupported(format!("`{}` without a body for this type", name), span);
+            self.dummy(ty, span);
+            return Some(());
+        };
+        let name = self.instance(instance.def, instance.args);
+        if self.repr(&instance.output, span) == Repr::Mem {
+            let slot = self.slot(&instance.output, span);
+            self.addr(slot);
+        }
+        for (arg, param) in args.iter().zip(&instance.params) {
+            self.arg(arg, param)?;
+        }
+        self.line(&format!("call ${}", name));
+        self.result(ty)
+    }
+
+    fn method_call(&mut self, expr: &Expression, receiver: &Expression, method: &str, args: &[Expression]) -> Option<()> {
+        let ty = self.ty(expr.id);
+        if let Some(def) = self.krate.user_method(expr) {
+            let mut all = vec![receiver];
+            all.extend(args);
+            let inputs: Vec<Ty> = all.iter().map(|arg| self.ty(arg.id)).collect();
+            if self.krate.resolve(def, &inputs, &ty).is_some() {
+                return self.call_fn(def, &all, &ty, expr.span);
+            }
+        }
+        self.library_method(expr, receiver, method, args, &ty)
+    }
+
+    /// Push a pointer to the receiver of a method that changes it, or reads it in place
+    fn receiver_pointer(&mut self, receiver: &Expression) -> Option<()> {
+        let ty = self.ty(receiver.id);
+        if !self.krate.is_pointer(&ty) {
+            return self.borrow(receiver, true);
+        }
+        self.expr(receiver)?;
+        self.peel_pointers(&ty);
+        Some(())
+    }
+
+    fn library_method(
+        &mut self,
+        expr: &Expression,
+        receiver: &Expression,
+        method: &str,
+        args: &[Expression],
+        ty: &Ty,
+    ) -> Option<()> {
+        let span = expr.span;
+        let receiver_ty = self.ty(receiver.id);
+        let base = self.krate.peel(&receiver_ty);
+        let is_vec = self.krate.is_vec(&base);
+        let in_place = is_vec || self.krate.is_str(&base) && matches!(method, "push" | "push_str");
+        let recv = match in_place {
+            true => {
+                self.receiver_pointer(receiver)?;
+                self.spill("i32")
+            }
+            false => {
+                self.expr(receiver)?;
+                self.peel_value(&receiver_ty);
+                match self.spill_value(&base, span) {
+                    Some(local) => local,
+                    None => self.scratch("i32"),
+                }
+            }
+        };
+        // Elements going into a vector, and the default of `unwrap_or`, keep their own type
+        let elem = match &base {
+            Ty::Adt(_, elems) if is_vec => elems.first().cloned().unwrap_or(Ty::Error),
+            _ => ty.clone(),
+        };
+        let mut values = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            let arg_ty = match (is_vec, method, i) {
+                (true, "push" | "contains", 0) | (true, "insert", 1) | (false, "unwrap_or", 0) => {
+                    self.arg(arg, &elem)?;
+                    elem.clone()
+                }
+                // Positions in a vector are `i64`s, whatever integer they were checked as
+                (true, "get" | "insert" | "remove", 0) => {
+                    let arg_ty = self.ty(arg.id);
+                    self.expr(arg)?;
+                    self.peel_value(&arg_ty);
+                    self.widen(&self.krate.peel(&arg_ty));
+                    Ty::Prim(PrimitiveType::Usize)
+                }
+                _ => {
+                    let arg_ty = self.ty(arg.id);
+                    self.expr(arg)?;
+                    self.peel_value(&arg_ty);
+                    self.krate.peel(&arg_ty)
+                }
+            };
+            let local = self.spill_value(&arg_ty, arg.span).unwrap_or_default();
+            values.push((local, arg_ty));
+        }
+        let arg = |i: usize| values.get(i).map_or(String::new(), |(local, _)| local.clone());
+        match (&base, method, args.len()) {
+            (_, "clone" | "to_owned", 0) if !is_vec => self.get(&recv),
+            (base, _, _) if self.krate.is_str(base) => match (method, args.len()) {
+                ("len", 0) => {
+                    self.get(&recv);
+                    self.line("i32.load");
+                    self.length(ty);
+                }
+                ("is_empty", 0) => {
+                    self.get(&recv);
+                    self.line("i32.load");
+                    self.line("i32.eqz");
+                }
+                ("to_string" | "as_str" | "into", 0) => self.get(&recv),
+                ("trim", 0) => {
+                    self.get(&recv);
+                    self.line("call $ml_str_trim");
+                }
+                ("to_uppercase" | "to_lowercase", 0) => {
+                    self.get(&recv);
+                    self.line(&format!("i32.const {}", (method == "to_uppercase") as i32));
+                    self.line("call $ml_str_map_case");
+                }
+                ("eq", 1) => {
+                    self.get(&recv);
+                    self.get(&arg(0));
+                    self.line("call $ml_str_eq");
+                }
+                ("contains" | "starts_with" | "ends_with", 1) => {
+                    self.get(&recv);
+                    self.get(&arg(0));
+                    if values[0].1 == Ty::Prim(PrimitiveType::Char) {
+                        self.line("call $ml_str_from_char");
+                    }
+                    self.line(&format!("call $ml_str_{}", method));
+                }
+                ("push_str" | "push", 1) => {
+                    self.get(&recv);
+                    self.get(&recv);
+                    self.line("i32.load");
+                    self.get(&arg(0));
+                    if method == "push" {
+                        self.line("call $ml_str_from_char");
+                    }
+                    self.line("call $ml_str_concat");
+                    self.line("i32.store");
+                }
+                _ => self.unsupported_method(method, base, ty, span),
+            },
+            (Ty::Prim(prim), _, _) if base.is_float() => {
+                let val = if *prim == PrimitiveType::F32 { "f32" } else { "f64" };
+                match (method, args.len()) {
+                    ("sqrt" | "floor" | "ceil" | "abs" | "trunc", 0) => {
+                        self.get(&recv);
+                        self.line(&format!("{}.{}", val, method));
+                    }
+                    ("round", 0) => {
+                        self.get(&recv);
+                        self.line(&format!("{}.abs", val));
+                        self.line(&format!("{}.const 0.5", val));
+                        self.line(&format!("{}.add", val));
+                        self.line(&format!("{}.floor", val));
+                        self.get(&recv);
+                        self.line(&format!("{}.copysign", val));
+                    }
+                    ("min" | "max", 1) => {
+                        self.get(&recv);
+                        self.get(&arg(0));
+                        self.line(&format!("{}.{}", val, method));
+                    }
+                    ("powi", 1) => {
+                        self.get(&recv);
+                        self.get(&arg(0));
+                        self.line(&format!("call $ml_powi_{}", val));
+                    }
+                    ("to_string", 0) => {
+                        self.get(&recv);
+                        self.line(&format!("call $ml_{}_to_str", val));
+                    }
+                    _ => self.unsupported_method(method, &base, ty, span),
+                }
+            }
+            (Ty::Prim(prim), _, _) => {
+                let val = self.repr(&base, span).val().unwrap_or("i32");
+                let unsigned = is_unsigned(*prim);
+                match (method, args.len()) {
+                    ("to_string", 0) => match prim {
+                        PrimitiveType::Bool => {
+                            self.line("global.get $ml_text_true");
+                            self.line("global.get $ml_text_false");
+                            self.get(&recv);
+                            self.line("select");
+                        }
+                        PrimitiveType::Char => {
+                            self.get(&recv);
+                            self.line("call $ml_str_from_char");
+                        }
+                        _ => {
+                            self.get(&recv);
+                            self.widen(&base);
+                            self.line(if unsigned { "call $ml_u64_to_str" } else { "call $ml_i64_to_str" });
+                        }
+                    },
+                    ("abs", 0) if !unsigned => {
+                        self.line(&format!("{}.const 0", val));
+                        self.get(&recv);
+                        self.line(&format!("{}.sub", val));
+                        self.get(&recv);
+                        self.get(&recv);
+                        self.line(&format!("{}.const 0", val));
+                        self.line(&format!("{}.lt_s", val));
+                        self.line("select");
+                        self.normalize(&base);
+                    }
+                    ("min" | "max", 1) => {
+                        self.get(&recv);
+                        self.get(&arg(0));
+                        self.get(&recv);
+                        self.get(&arg(0));
+                        let test = if method == "min" { "lt" } else { "gt" };
+                        self.line(&format!("{}.{}{}", val, test, if unsigned { "_u" } else { "_s" }));
+                        self.line("select");
+                    }
+                    ("pow", 1) => {
+                        self.get(&recv);
+                        self.get(&arg(0));
+                        if values[0].1 != Ty::Prim(PrimitiveType::U32) {
+                            self.line("i32.wrap_i64");
+                        }
+                        self.line(&format!("call $ml_pow_{}", val));
+                        self.normalize(&base);
+                    }
+                    _ => self.unsupported_method(method, &base, ty, span),
+                }
+            }
+            (Ty::Adt(..), _, _) if is_vec => self.vec_method(&recv, &base, &elem, method, &arg, ty, span),
+            (Ty::Array(_, Some(len)), "len" | "is_empty", 0) => match method {
+                "len" => {
+                    self.line(&format!("i32.const {}", len));
+                    self.length(ty);
+                }
+                _ => self.line(&format!("i32.const {}", (*len == 0) as i32)),
+            },
+            (Ty::Adt(def, _), _, _)
+                if self.krate.is_prelude(*def, "Option") || self.krate.is_prelude(*def, "Result") =>
+            {
+                let is_option = self.krate.is_prelude(*def, "Option");
+                let (_, present) = self.krate.variant_named(&base, if is_option { "Some" } else { "Ok" });
+                let test = |s: &mut Self, op: &str| {
+                    s.get(&recv);
+                    s.line("i32.load");
+                    s.line(&format!("i32.const {}", present));
+                    s.line(&format!("i32.{}", op));
+                };
+                let value = self.fields(&base, present, span).first().cloned();
+                match (method, args.len(), value) {
+                    ("is_some" | "is_ok", 0, _) => test(self, "eq"),
+                    ("is_none" | "is_err", 0, _) => test(self, "ne"),
+                    ("unwrap" | "expect", _, Some((_, value_ty, offset))) => {
+                        test(self, "ne");
+                        self.open("if");
+                        let loc = self.loc(span);
+                        let message = (method == "expect").then(|| arg(0));
+                        if is_option {
+                            self.line(&format!("i32.const {}", loc));
+                            match &message {
+                                Some(message) => self.get(message),
+                                None => {
+                                    let text = self.string("called `Option::unwrap()` on a `None` value");
+                                    self.line(&format!("i32.const {}", text));
+                                }
+                            }
+                            self.line("call $ml_panic");
+                        } else {
+                            let buf = self.buffer();
+                            match &message {
+                                Some(message) => {
+                                    self.get(&buf);
+                                    self.get(message);
+                                    self.line("call $ml_buf_str");
+                                    self.text(&buf, ": ");
+                                }
+                                None => self.text(&buf, "called `Result::unwrap()` on an `Err` value: "),
+                            }
+                            let (_, err) = self.krate.variant_named(&base, "Err");
+                            if let Some((_, err_ty, offset)) = self.fields(&base, err, span).first().cloned() {
+                                self.get(&buf);
+                                self.get(&recv);
+                                self.offset(offset);
+                                self.load(&err_ty, span);
+                                self.peel_value(&err_ty);
+                                self.fmt_value(&self.krate.peel(&err_ty), &Spec::default(), true, span);
+                            }
+                            self.line(&format!("i32.const {}", loc));
+                            self.get(&buf);
+                            self.line("call $ml_panic_buf");
+                        }
+                        self.close("end");
+                        self.get(&recv);
+                        self.offset(offset);
+                        self.load(&value_ty, span);
+                    }
+                    ("unwrap_or", 1, Some((_, value_ty, offset))) => {
+                        test(self, "eq");
+                        let result = self.block_type(&value_ty, span);
+                        self.open(&format!("if{}", result));
+                        self.get(&recv);
+                        self.offset(offset);
+                        self.load(&value_ty, span);
+                        self.reopen("else");
+                        self.get(&arg(0));
+                        self.close("end");
+                    }
+                    _ => self.unsupported_method(method, &base, ty, span),
+                }
+            }
+            _ => self.unsupported_method(method, &base, ty, span),
+        }
+        Some(())
+    }
+
+    /// Turn the `i32` length on top of the stack into the `ty` a `len()` call
+    /// has, which is whatever integer it was checked against
+    fn length(&mut self, ty: &Ty) {
+        if self.repr(ty, Span::default()) == Repr::I64 {
+            self.line("i64.extend_i32_u");
+        }
+    }
+
+    fn unsupported_method(&mut self, method: &str, base: &Ty, ty: &Ty, span: Span) {
+        self.unsupported(format!("method `{}` of `{}`", method, base.display(self.res)), span);
+        self.dummy(ty, span);
+    }
+
+    /// Methods of vectors; `vec` holds a pointer to the vector and `arg` gives
+    /// the locals holding the arguments
+    #[allow(clippy::too_many_arguments)]
+    fn vec_method(
+        &mut self,
+        vec: &str,
+        base: &Ty,
+        elem: &Ty,
+        method: &str,
+        arg: &dyn Fn(usize) -> String,
+        ty: &Ty,
+        span: Span,
+    ) {
+        let size = self.layout(elem, span).size;
+        match method {
+            "len" => {
+                self.get(vec);
+                self.line("i32.load offset=4");
+                self.length(ty);
+            }
+            "is_empty" => {
+                self.get(vec);
+                self.line("i32.load offset=4");
+                self.line("i32.eqz");
+            }
+            "clear" => {
+                self.get(vec);
+                self.line("i32.const 0");
+                self.line("i32.store offset=4");
+            }
+            "push" => {
+                self.get(vec);
+                self.line(&format!("i32.const {}", size));
+                self.line("call $ml_vec_push");
+                self.get(&arg(0));
+                self.store(elem, span);
+            }
+            "pop" => {
+                let (_, some) = self.krate.variant_named(ty, "Some");
+                let (_, none) = self.krate.variant_named(ty, "None");
+                let offset = self.fields(ty, some, span).first().map_or(0, |(_, _, offset)| *offset);
+                let slot = self.slot(ty, span);
+                self.get(vec);
+                self.line("i32.load offset=4");
+                self.line("i32.eqz");
+                self.open("if");
+                self.addr(slot);
+                self.line(&format!("i32.const {}", none));
+                self.line("i32.store");
+                self.reopen("else");
+                self.get(vec);
+                self.get(vec);
+                self.line("i32.load offset=4");
+                self.line("i32.const 1");
+                self.line("i32.sub");
+                self.line("i32.store offset=4");
+                self.addr(slot);
+                self.line(&format!("i32.const {}", some));
+                self.line("i32.store");
+                self.addr(slot + offset);
+                self.get(vec);
+                self.line("i32.load");
+                self.get(vec);
+                self.line("i32.load offset=4");
+                self.line(&format!("i32.const {}", size));
+                self.line("i32.mul");
+                self.line("i32.add");
+                self.load(elem, span);
+                self.store(elem, span);
+                self.close("end");
+                self.addr(slot);
+            }
+            "get" | "first" | "last" => {
+                self.get(vec);
+                match method {
+                    "get" => self.get(&arg(0)),
+                    "first" => self.line("i64.const 0"),
+                    _ => {
+                        self.get(vec);
+                        self.line("i32.load offset=4");
+                        self.line("i64.extend_i32_u");
+                        self.line("i64.const 1");
+                        self.line("i64.sub");
+                    }
+                }
+                self.line(&format!("i32.const {}", size));
+                self.line("call $ml_vec_get");
+                let found = self.spill("i32");
+                let (_, some) = self.krate.variant_named(ty, "Some");
+                let (_, none) = self.krate.variant_named(ty, "None");
+                let offset = self.fields(ty, some, span).first().map_or(0, |(_, _, offset)| *offset);
+                let slot = self.slot(ty, span);
+                self.addr(slot);
+                self.line(&format!("i32.const {}", none));
+                self.line(&format!("i32.const {}", some));
+                self.get(&found);
+                self.line("i32.eqz");
+                self.line("select");
+                self.line("i32.store");
+                self.addr(slot + offset);
+                self.get(&found);
+                self.line("i32.store");
+                self.addr(slot);
+            }
+            "contains" => {
+                let contains = self.vec_contains_fn(base, elem, span);
+                self.get(vec);
+                self.get(&arg(0));
+                self.line(&format!("call ${}", contains));
+            }
+            "clone" => {
+                let slot = self.slot(base, span);
+                self.get(vec);
+                self.line(&format!("i32.const {}", size));
+                self.addr(slot);
+                self.line("call $ml_vec_clone");
+                self.addr(slot);
+            }
+            "insert" => {
+                let loc = self.loc(span);
+                self.get(vec);
+                self.get(&arg(0));
+                self.line(&format!("i32.const {}", size));
+                self.line(&format!("i32.const {}", loc));
+                self.line("call $ml_vec_insert");
+                self.get(&arg(1));
+                self.store(elem, span);
+            }
+            "remove" => {
+                let loc = self.loc(span);
+                let slot = self.slot(elem, span);
+                self.get(vec);
+                self.get(&arg(0));
+                self.line(&format!("i32.const {}", size));
+                self.line(&format!("i32.const {}", loc));
+                self.addr(slot);
+                self.line("call $ml_vec_remove");
+                self.addr(slot);
+                self.load(elem, span);
+            }
+            "sort" => {
+                let compare = self.cmp_fn(elem, span);
+                let slot = self.table_slot(&compare);
+                self.get(vec);
+                self.line(&format!("i32.const {}", size));
+                self.line(&format!("i32.const {}", slot));
+                self.line("call $ml_vec_sort");
+            }
+            "reverse" => {
+                self.get(vec);
+                self.line(&format!("i32.const {}", size));
+                self.line("call $ml_vec_reverse");
+            }
+            _ => self.unsupported_method(method, base, ty, span),
+        }
+    }
+
+    /// Functions of the prelude, which the support code and generated helpers implement
+    fn prelude_call(&mut self, name: &str, args: &[Expression], ty: &Ty, span: Span) -> Option<()> {
+        match name {
+            "print" | "println" | "eprint" | "eprintln" => {
+                let fd = if name.starts_with('e') { 2 } else { 1 };
+                let newline = name.ends_with("ln");
+                // Text with nothing to fill in is written as it is
+                let plain = match args {
+                    [] => Some(String::new()),
+                    [first] => match &first.kind {
+                        ExpressionKind::Literal(Literal::String(text)) => Some(text.clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if let Some(text) = plain {
+                    let text = self.string(&format!("{}{}", text, if newline { "\n" } else { "" }));
+                    self.line(&format!("i32.const {}", fd));
+                    self.line(&format!("i32.const {}", text));
+                    self.line("call $ml_write");
+                    return Some(());
+                }
+                let buf = self.buffer();
+                self.format_into(&buf, args, "", span)?;
+                if newline {
+                    self.byte(&buf, b'\n');
+                }
+                self.line(&format!("i32.const {}", fd));
+                self.get(&buf);
+                self.line("call $ml_print");
+                Some(())
+            }
+            "format" => {
+                let buf = self.buffer();
+                self.format_into(&buf, args, "", span)?;
+                self.get(&buf);
+                self.line("call $ml_buf_finish");
+                Some(())
+            }
+            "panic" | "unreachable" | "todo" => {
+                let default = match name {
+                    "panic" => "explicit panic",
+                    "unreachable" => "internal error: entered unreachable code",
+                    _ => "not yet implemented",
+                };
+                let buf = self.buffer();
+                self.format_into(&buf, args, default, span)?;
+                self.panic_buf(&buf, span);
+                None
+            }
+            "assert" => {
+                let (cond, rest) = args.split_first()?;
+                self.expr(cond)?;
+                self.line("i32.eqz");
+                self.open("if");
+                let buf = self.buffer();
+                if self.format_into(&buf, rest, "assertion failed", span).is_some() {
+                    self.panic_buf(&buf, span);
+                }
+                self.close("end");
+                Some(())
+            }
+            "assert_eq" | "assert_ne" => {
+                let [left, right, rest @ ..] = args else { return Some(()) };
+                let (left_ty, right_ty) = (self.ty(left.id), self.ty(right.id));
+                self.expr(left)?;
+                self.peel_value(&left_ty);
+                let (left_ty, right_ty) = (self.krate.peel(&left_ty), right_ty);
+                let l = self.spill_value(&left_ty, span);
+                self.expr(right)?;
+                self.peel_value(&right_ty);
+                let right_ty = self.krate.peel(&right_ty);
+                let r = self.spill_value(&right_ty, span);
+                self.get_value(&l);
+                self.get_value(&r);
+                self.operate(&BinaryOp::Eq, &left_ty, &right_ty, span);
+                if name == "assert_eq" {
+                    self.line("i32.eqz");
+                }
+                self.open("if");
+                let buf = self.buffer();
+                let op = if name == "assert_eq" { "==" } else { "!=" };
+                let default = format!("assertion `left {} right` failed", op);
+                if self.format_into(&buf, rest, &default, span).is_some() {
+                    self.text(&buf, "\n  left: ");
+                    self.get(&buf);
+                    self.get_value(&l);
+                    self.fmt_value(&left_ty, &Spec::default(), true, span);
+                    self.text(&buf, "\n right: ");
+                    self.get(&buf);
+                    self.get_value(&r);
+                    self.fmt_value(&right_ty, &Spec::default(), true, span);
+                    self.panic_buf(&buf, span);
+                }
+                self.close("end");
+                Some(())
+            }
+            "dbg" => {
+                let arg = args.first()?;
+                let arg_ty = self.ty(arg.id);
+                self.expr(arg)?;
+                let value = self.spill_value(&arg_ty, span);
+                let buf = self.buffer();
+                self.text(&buf, &format!("[{}:{}] ", span.line, span.column));
+                self.get(&buf);
+                self.get_value(&value);
+                self.peel_value(&arg_ty);
+                self.fmt_value(&self.krate.peel(&arg_ty), &Spec::default(), true, span);
+                self.byte(&buf, b'\n');
+                self.line("i32.const 2");
+                self.get(&buf);
+                self.line("call $ml_print");
+                self.get_value(&value);
+                Some(())
+            }
+            "drop" => {
+                let arg = args.first()?;
+                let arg_ty = self.ty(arg.id);
+                match self.cleanup_fn(&arg_ty, span) {
+                    Some(cleanup) => {
+                        self.borrow(arg, true)?;
+                        self.line(&format!("call ${}", cleanup));
+                    }
+                    None => {
+                        self.expr(arg)?;
+                        self.discard(&arg_ty, span);
+                    }
+                }
+                Some(())
+            }
+            "old" => self.expr(args.first()?),
+            "contract_violation" => {
+                let texts: Vec<&str> = args
+                    .iter()
+                    .take(3)
+                    .filter_map(|arg| match &arg.kind {
+                        ExpressionKind::Literal(Literal::String(text)) => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect();
+                let message = match texts.as_slice() {
+                    [kind, function, clause] => format!("{} `{}` of `{}` violated", kind, clause, function),
+                    _ => "contract violated".to_string(),
+                };
+                let buf = self.buffer();
+                self.text(&buf, &message);
+                // The values the clause mentions follow as notes
+                for pair in args.get(3..).unwrap_or_default().chunks(2) {
+                    let [name, value] = pair else { continue };
+                    let ExpressionKind::Literal(Literal::String(name)) = &name.kind else { continue };
+                    self.text(&buf, &format!("\n  {} = ", name));
+                    let value_ty = self.ty(value.id);
+                    self.get(&buf);
+                    self.expr(value)?;
+                    self.peel_value(&value_ty);
+                    self.fmt_value(&self.krate.peel(&value_ty), &Spec::default(), false, span);
+                }
+                self.panic_buf(&buf, span);
+                None
+            }
+            _ => {
+                self.unsupported(format!("`{}`", name), span);
+                self.dummy(ty, span);
+                Some(())
+            }
+        }
+    }
+
+    /// Panic at `span` with the text in `buf`
+    fn panic_buf(&mut self, buf: &str, span: Span) {
+        let loc = self.loc(span);
+        self.line(&format!("i32.const {}", loc));
+        self.get(buf);
+        self.line("call $ml_panic_buf");
+        self.line("unreachable");
+    }
+
+    /// Library functions named by path, such as `String::from` or `process::exit`
+    fn library_call(&mut self, path: &str, args: &[Expression], ty: &Ty, span: Span) -> Option<()> {
+        match mono::library_call(path, args) {
+            LibraryCall::StringNew => self.line("global.get $ml_text_empty"),
+            LibraryCall::StringFrom(text) => {
+                self.expr(text)?;
+                self.peel_value(&self.ty(text.id));
+            }
+            LibraryCall::VecNew(capacity) => {
+                if let Some(capacity) = capacity {
+                    self.expr(capacity)?;
+                    self.discard(&self.ty(capacity.id), span);
+                }
+                let slot = self.slot(ty, span);
+                self.addr(slot);
+                self.line("i32.const 0");
+                self.line("i32.const 12");
+                self.line("memory.fill");
+                self.addr(slot);
+            }
+            LibraryCall::VecFrom(items) => {
+                let elem = match ty {
+                    Ty::Adt(_, elems) => elems.first().cloned().unwrap_or(Ty::Error),
+                    _ => Ty::Error,
+                };
+                let items_ty = self.ty(items.id);
+                let Ty::Array(_, Some(len)) = self.krate.peel(&items_ty) else {
+                    self.unsupported_method("from", ty, ty, span);
+                    return Some(());
+                };
+                let size = self.layout(&elem, span).size;
+                self.container(items)?;
+                let slot = self.slot(ty, span);
+                self.line(&format!("i32.const {}", len));
+                self.line(&format!("i32.const {}", size));
+                self.addr(slot);
+                self.line("call $ml_vec_from");
+                self.addr(slot);
+            }
+            LibraryCall::BoxNew(value) => {
+                let inner = match ty {
+                    Ty::Adt(_, args) => args.first().cloned().unwrap_or(Ty::Error),
+                    _ => Ty::Error,
+                };
+                self.arg(value, &inner)?;
+                self.address_of_value(&inner, span);
+                let size = self.layout(&inner, span).size;
+                self.line(&format!("i32.const {}", size));
+                self.line("call $ml_box");
+            }
+            LibraryCall::Exit(code) => {
+                self.expr(code)?;
+                self.line("call $ml_exit");
+                self.line("unreachable");
+                return None;
+            }
+            LibraryCall::Args => {
+                let slot = self.slot(ty, span);
+                self.addr(slot);
+                self.line("call $ml_args");
+                self.addr(slot);
+            }
+            LibraryCall::Swap(a, b) => {
+                let pointee = self.krate.pointee(&self.ty(a.id)).unwrap_or(Ty::Error);
+                self.arg(a, &self.ty(a.id))?;
+                self.arg(b, &self.ty(b.id))?;
+                let size = self.layout(&pointee, span).size;
+                self.line(&format!("i32.const {}", size));
+                self.line("call $ml_swap");
+            }
+            LibraryCall::Replace(dest, value) => {
+                let pointee = self.krate.pointee(&self.ty(dest.id)).unwrap_or(Ty::Error);
+                self.arg(dest, &self.ty(dest.id))?;
+                let target = self.spill("i32");
+                let old = self.slot(&pointee, span);
+                self.addr(old);
+                self.get(&target);
+                self.load(&pointee, span);
+                self.store(&pointee, span);
+                self.get(&target);
+                self.arg(value, &pointee)?;
+                self.store(&pointee, span);
+                self.addr(old);
+                self.load(&pointee, span);
+            }
+            LibraryCall::ReadFile(path) => self.fs_call(&[path], "$ml_fs_read", ty, span)?,
+            LibraryCall::WriteFile(path, contents) => self.fs_call(&[path, contents], "$ml_fs_write", ty, span)?,
+            LibraryCall::Prelude(name) => return self.prelude_call(name, args, ty, span),
+            LibraryCall::Unknown => {
+                self.unsupported(format!("`{}`", path), span);
+                self.dummy(ty, span);
+            }
+        }
+        Some(())
+    }
+
+    /// `fs::read_to_string` or `fs::write` through `import`, which leaves
+    /// whether it succeeded and the text of the contents or the error
+    fn fs_call(&mut self, args: &[&Expression], import: &str, ty: &Ty, span: Span) -> Option<()> {
+        for arg in args {
+            self.expr(arg)?;
+            self.peel_value(&self.ty(arg.id));
+        }
+        self.line(&format!("call {}", import));
+        let ok = self.spill("i32");
+        let text = self.spill("i32");
+        let (_, ok_index) = self.krate.variant_named(ty, "Ok");
+        let (_, err_index) = self.krate.variant_named(ty, "Err");
+        let slot = self.slot(ty, span);
+        self.addr(slot);
+        self.line(&format!("i32.const {}", ok_index));
+        self.line(&format!("i32.const {}", err_index));
+        self.get(&ok);
+        self.line("select");
+        self.line("i32.store");
+        // Both variants hold their text at the start of the payload
+        let offset = self.payload(ty, span);
+        self.addr(slot + offset);
+        self.get(&text);
+        self.line("i32.store");
+        self.addr(slot);
+        Some(())
+    }
+
+    // ========== Formatting ==========
+
+    /// A new buffer for text being built, in a local
+    fn buffer(&mut self) -> String {
+        self.line("call $ml_buf_new");
+        self.spill("i32")
+    }
+
+    /// Append `text` to the buffer in `buf`
+    fn text(&mut self, buf: &str, text: &str) {
+        let addr = self.string(text);
+        self.get(buf);
+        self.line(&format!("i32.const {}", addr));
+        self.line("call $ml_buf_str");
+    }
+
+    fn byte(&mut self, buf: &str, byte: u8) {
+        self.get(buf);
+        self.line(&format!("i32.const {}", byte));
+        self.line("call $ml_buf_byte");
+    }
+
+    /// Append what `format` makes of `args` to the buffer in `buf`, or
+    /// `default` when there are none. A single argument is shown as `{}` shows it.
+    fn format_into(&mut self, buf: &str, args: &[Expression], default: &str, span: Span) -> Option<()> {
+        let Some((first, rest)) = args.split_first() else {
+            if !default.is_empty() {
+                self.text(buf, default);
+            }
+            return Some(());
+        };
+        let template = match (&first.kind, rest) {
+            (ExpressionKind::Literal(Literal::String(template)), [_, ..]) => template.clone(),
+            (_, []) => return self.show(buf, first, &Spec::default(), false),
+            _ => {
+                self.unsupported("a format string that is not a literal", first.span);
+                return Some(());
+            }
+        };
+        let mut rest = rest.iter();
+        for piece in parse_template(&template) {
+            match piece {
+                Piece::Text(text) => self.text(buf, &text),
+                Piece::Hole { text, spec, debug } => match rest.next() {
+                    Some(arg) => self.show(buf, arg, &spec, debug)?,
+                    None => self.text(buf, &text),
+                },
+            }
+        }
+        let _ = span;
+        Some(())
+    }
+
+    /// Append `arg` to the buffer in `buf` as a hole with `spec` shows it
+    fn show(&mut self, buf: &str, arg: &Expression, spec: &Spec, debug: bool) -> Option<()> {
+        let ty = self.ty(arg.id);
+        self.get(buf);
+        self.expr(arg)?;
+        self.peel_value(&ty);
+        self.fmt_value(&self.krate.peel(&ty), spec, debug, arg.span);
+        Some(())
+    }
+
+    /// Append the `ty` on top of the stack to the buffer below it, as `{}`
+    /// or `{:?}` shows it
+    fn fmt_value(&mut self, ty: &Ty, spec: &Spec, debug: bool, span: Span) {
+        match ty {
+            _ if ty.is_unit() => {
+                let unit = self.string("()");
+                self.line(&format!("i32.const {}", unit));
+                self.spec(spec);
+                self.line("i32.const 0");
+                self.line("call $ml_fmt_str");
+            }
+            Ty::Prim(prim @ (PrimitiveType::F32 | PrimitiveType::F64)) => {
+                self.spec(spec);
+                self.line(&format!("i32.const {}", debug as i32));
+                self.line(&format!("call $ml_fmt_{}", prim_name(*prim)));
+            }
+            Ty::Prim(PrimitiveType::Bool) => {
+                self.spec(spec);
+                self.line("call $ml_fmt_bool");
+            }
+            Ty::Prim(PrimitiveType::Char) => {
+                self.spec(spec);
+                self.line(&format!("i32.const {}", debug as i32));
+                self.line("call $ml_fmt_char");
+            }
+            _ if self.krate.is_str(ty) => {
+                self.spec(spec);
+                self.line(&format!("i32.const {}", debug as i32));
+                self.line("call $ml_fmt_str");
+            }
+            Ty::Prim(prim) => {
+                self.widen(ty);
+                self.spec(spec);
+                self.line(if is_unsigned(*prim) { "call $ml_fmt_u64" } else { "call $ml_fmt_i64" });
+            }
+            _ if self.krate.pointee(ty).is_some() => {
+                self.peel_value(ty);
+                self.fmt_value(&self.krate.peel(ty), spec, debug, span);
+            }
+            _ if debug => {
+                let debug = self.debug_fn(ty, span);
+                self.line(&format!("call ${}", debug));
+            }
+            _ => {
+                let display = self.display_fn(ty, span);
+                self.spec(spec);
+                self.line(&format!("call ${}", display));
+            }
+        }
+    }
+
+    /// Push the width, alignment and precision of a hole
+    fn spec(&mut self, spec: &Spec) {
+        self.line(&format!("i32.const {}", spec.width.unwrap_or(-1)));
+        self.line(&format!("i32.const {}", spec.left as i32));
+        self.line(&format!("i32.const {}", spec.precision.unwrap_or(-1)));
+    }
+
+    // ========== Helpers ==========
+
+    /// Define a helper function the first time it is asked for. `build`
+    /// emits its body, which leaves the result on the stack or branches to
+    /// `$exit` with it.
+    fn helper(
+        &mut self,
+        name: String,
+        params: Vec<(String, &'static str)>,
+        result: Option<&'static str>,
+        build: impl FnOnce(&mut Self),
+    ) -> String {
+        if self.helpers.contains(&name) {
+            return name;
+        }
+        self.helpers.insert(name.clone());
+        let outer = std::mem::replace(&mut self.frame, Frame { depth: 2, ..Frame::default() });
+        let block_type = result.map_or_else(String::new, |val| format!(" (result {})", val));
+        self.open(&format!("block $exit{}", block_type));
+        build(self);
+        self.close("end");
+        let text = self.finish(&name, &params, result);
+        self.frame = outer;
+        self.functions.push(text);
+        name
+    }
+
+    /// Replace the two `ty`s on top of the stack with whether they are equal
+    fn eq(&mut self, ty: &Ty, span: Span) {
+        match ty {
+            _ if self.krate.is_str(ty) => self.line("call $ml_str_eq"),
+            _ if ty.is_unit() => self.line("i32.const 1"),
+            Ty::Prim(_) => match self.repr(ty, span).val() {
+                Some(val) => self.line(&format!("{}.eq", val)),
+                None => self.line("i32.const 1"),
+            },
+            _ if self.krate.pointee(ty).is_some() => {
+                let b = self.spill("i32");
+                self.peel_value(ty);
+                self.get(&b);
+                self.peel_value(ty);
+                self.eq(&self.krate.peel(ty), span);
+            }
+            _ => {
+                // A user `eq` decides, when the type has one
+                if let Some(def) = self.krate.method_of(ty, "eq") {
+                    let by_ref = Ty::Ref { ty: Box::new(ty.clone()), mutable: false };
+                    if let Some((def, args)) = self.krate.resolve(def, &[by_ref.clone(), by_ref], &Ty::bool()) {
+                        let name = self.instance(def, args);
+                        if self.repr(ty, span) != Repr::Mem {
+                            let b = self.spill_value(ty, span);
+                            self.address_of_value(ty, span);
+                            self.get_value(&b);
+                            self.address_of_value(ty, span);
+                        }
+                        self.line(&format!("call ${}", name));
+                        return;
+                    }
+                }
+                let name = self.eq_fn(ty, span);
+                self.line(&format!("call ${}", name));
+            }
+        }
+    }
+
+    /// `ml_eq_T(a, b)` compares tuples, structs, enums, arrays and vectors part by part
+    fn eq_fn(&mut self, ty: &Ty, span: Span) -> String {
+        let name = format!("ml_eq_{}", self.type_name(ty));
+        let val = self.repr(ty, span).val().unwrap_or("i32");
+        let params = vec![("$a".to_string(), val), ("$b".to_string(), val)];
+        self.helper(name, params, Some("i32"), |s| {
+            match ty {
+                _ if s.repr(ty, span) != Repr::Mem => {
+                    s.get("$a");
+                    s.get("$b");
+                    s.line(&format!("{}.eq", val));
+                    return;
+                }
+                Ty::Array(elem, len) => {
+                    s.line(&format!("i32.const {}", len.unwrap_or(0)));
+                    let count = s.spill("i32");
+                    s.elements_eq("$a", "$b", &count, elem, span);
+                }
+                Ty::Adt(_, elems) if s.krate.is_vec(ty) => {
+                    let elem = elems.first().cloned().unwrap_or(Ty::Error);
+                    s.get("$a");
+                    s.line("i32.load offset=4");
+                    let count = s.spill("i32");
+                    s.get(&count);
+                    s.get("$b");
+                    s.line("i32.load offset=4");
+                    s.line("i32.ne");
+                    s.leave_if(0);
+                    s.get("$a");
+                    s.line("i32.load");
+                    let a = s.spill("i32");
+                    s.get("$b");
+                    s.line("i32.load");
+                    let b = s.spill("i32");
+                    s.elements_eq(&a, &b, &count, &elem, span);
+                }
+                Ty::Adt(def, _) if s.krate.is_enum(ty) => {
+                    s.get("$a");
+                    s.line("i32.load");
+                    s.get("$b");
+                    s.line("i32.load");
+                    s.line("i32.ne");
+                    s.leave_if(0);
+                    for index in 0..s.types.adts[def].variants.len() {
+                        let fields = s.fields(ty, index, span);
+                        if fields.is_empty() {
+                            continue;
+                        }
+                        s.get("$a");
+                        s.line("i32.load");
+                        s.line(&format!("i32.const {}", index));
+                        s.line("i32.eq");
+                        s.open("if");
+                        for (_, field_ty, offset) in fields {
+                            s.unless_eq("$a", "$b", offset, &field_ty, span);
+                        }
+                        s.close("end");
+                    }
+                }
+                _ => {
+                    for (_, field_ty, offset) in s.fields(ty, 0, span) {
+                        s.unless_eq("$a", "$b", offset, &field_ty, span);
+                    }
+                }
+            }
+            s.line("i32.const 1");
+        })
+    }
+
+    /// In a helper returning `i32`: return `value` if the `i32` on top of the stack is not 0
+    fn leave_if(&mut self, value: i32) {
+        self.open("if");
+        self.line(&format!("i32.const {}", value));
+        self.line("br $exit");
+        self.close("end");
+    }
+
+    /// In a helper returning `i32`: return 0 unless the `ty`s `offset`
+    /// bytes past the addresses in `a` and `b` are equal
+    fn unless_eq(&mut self, a: &str, b: &str, offset: u32, ty: &Ty, span: Span) {
+        for base in [a, b] {
+            self.get(base);
+            self.offset(offset);
+            self.load(ty, span);
+        }
+        self.eq(ty, span);
+        self.line("i32.eqz");
+        self.leave_if(0);
+    }
+
+    /// In a helper returning `i32`: return 0 unless the `count` elements of
+    /// type `elem` at the addresses in `a` and `b` are equal
+    fn elements_eq(&mut self, a: &str, b: &str, count: &str, elem: &Ty, span: Span) {
+        let size = self.layout(elem, span).size;
+        self.repeat(count, |s, i, _| {
+            let mut at = Vec::new();
+            for base in [a, b] {
+                s.get(base);
+                s.get(i);
+                s.line(&format!("i32.const {}", size));
+                s.line("i32.mul");
+                s.line("i32.add");
+                at.push(s.spill("i32"));
+            }
+            s.unless_eq(&at[0], &at[1], 0, elem, span);
+        });
+    }
+
+    /// `ml_debug_T(b, v)` appends `v` as `{:?}` shows it
+    fn debug_fn(&mut self, ty: &Ty, span: Span) -> String {
+        let name = format!("ml_debug_{}", self.type_name(ty));
+        let val = self.repr(ty, span).val().unwrap_or("i32");
+        let params = vec![("$b".to_string(), "i32"), ("$v".to_string(), val)];
+        self.helper(name, params, None, |s| match ty {
+            Ty::Tuple(elems) => {
+                s.text("$b", "(");
+                for (i, (_, elem, offset)) in s.fields(ty, 0, span).into_iter().enumerate() {
+                    if i > 0 {
+                        s.text("$b", ", ");
+                    }
+                    s.debug_field("$v", offset, &elem, span);
+                }
+                s.text("$b", if elems.len() == 1 { ",)" } else { ")" });
+            }
+            Ty::Array(elem, len) => {
+                s.line(&format!("i32.const {}", len.unwrap_or(0)));
+                let count = s.spill("i32");
+                s.debug_elements("$v", &count, elem, span);
+            }
+            Ty::Adt(_, elems) if s.krate.is_vec(ty) => {
+                let elem = elems.first().cloned().unwrap_or(Ty::Error);
+                s.get("$v");
+                s.line("i32.load offset=4");
+                let count = s.spill("i32");
+                s.get("$v");
+                s.line("i32.load");
+                let data = s.spill("i32");
+                s.debug_elements(&data, &count, &elem, span);
+            }
+            Ty::Adt(def, _) => {
+                let variants = s.types.adts.get(def).map(|adt| adt.variants.clone()).unwrap_or_default();
+                let is_enum = s.krate.is_enum(ty);
+                for (index, variant) in variants.iter().enumerate() {
+                    let variant_name = s.res.def(variant.def).name.clone();
+                    if is_enum {
+                        s.get("$v");
+                        s.line("i32.load");
+                        s.line(&format!("i32.const {}", index));
+                        s.line("i32.eq");
+                        s.open("if");
+                    }
+                    let fields = s.fields(ty, index, span);
+                    match variant.kind {
+                        VariantKind::Unit => s.text("$b", &variant_name),
+                        VariantKind::Tuple => {
+                            s.text("$b", &format!("{}(", variant_name));
+                            for (i, (_, field_ty, offset)) in fields.iter().enumerate() {
+                                if i > 0 {
+                                    s.text("$b", ", ");
+                                }
+                                s.debug_field("$v", *offset, field_ty, span);
+                            }
+                            s.text("$b", ")");
+                        }
+                        VariantKind::Struct => {
+                            s.text("$b", &format!("{} {{ ", variant_name));
+                            for (i, (field, field_ty, offset)) in fields.iter().enumerate() {
+                                let separator = if i > 0 { ", " } else { "" };
+                                s.text("$b", &format!("{}{}: ", separator, field));
+                                s.debug_field("$v", *offset, field_ty, span);
+                            }
+                            s.text("$b", " }");
+                        }
+                    }
+                    if is_enum {
+                        s.close("end");
+                    }
+                }
+            }
+            _ => {}
+        })
+    }
+
+    /// Append the `ty` `offset` bytes past the address in `base` to `$b`, as `{:?}` shows it
+    fn debug_field(&mut self, base: &str, offset: u32, ty: &Ty, span: Span) {
+        self.get("$b");
+        self.get(base);
+        self.offset(offset);
+        self.load(ty, span);
+        self.peel_value(ty);
+        self.fmt_value(&self.krate.peel(ty), &Spec::default(), true, span);
+    }
+
+    /// Append `[a, b, ...]` for the `count` elements at the address in `data` to `$b`
+    fn debug_elements(&mut self, data: &str, count: &str, elem: &Ty, span: Span) {
+        let size = self.layout(elem, span).size;
+        self.text("$b", "[");
+        self.repeat(count, |s, i, _| {
+            s.get(i);
+            s.open("if");
+            s.text("$b", ", ");
+            s.close("end");
+            s.get(data);
+            s.get(i);
+            s.line(&format!("i32.const {}", size));
+            s.line("i32.mul");
+            s.line("i32.add");
+            let at = s.spill("i32");
+            s.debug_field(&at, 0, elem, span);
+        });
+        self.text("$b", "]");
+    }
+
+    /// `ml_display_T(b, v, width, left, precision)` appends `v` as `{}`
+    /// shows it: through the type's `to_string` when it has one, or else as
+    /// `{:?}` would
+    fn display_fn(&mut self, ty: &Ty, span: Span) -> String {
+        let name = format!("ml_display_{}", self.type_name(ty));
+        let val = self.repr(ty, span).val().unwrap_or("i32");
+        let params = vec![
+            ("$b".to_string(), "i32"),
+            ("$v".to_string(), val),
+            ("$width".to_string(), "i32"),
+            ("$left".to_string(), "i32"),
+            ("$precision".to_string(), "i32"),
+        ];
+        self.helper(name, params, None, |s| {
+            let by_ref = Ty::Ref { ty: Box::new(ty.clone()), mutable: false };
+            let to_string = s.krate.method_of(ty, "to_string").and_then(|def| {
+                let output = Ty::Adt(DefId(0), Vec::new());
+                let sig = s.types.sigs.get(&def)?;
+                let input =
+                    if matches!(sig.inputs.first(), Some(Ty::Ref { .. })) { by_ref.clone() } else { ty.clone() };
+                s.krate.resolve(def, &[input], &output).map(|(def, args)| (def, args, sig.inputs.first().cloned()))
+            });
+            match to_string {
+                Some((def, args, input)) => {
+                    let function = s.instance(def, args);
+                    s.get("$b");
+                    s.get("$v");
+                    if matches!(input, Some(Ty::Ref { .. })) {
+                        s.address_of_value(ty, span);
+                    }
+                    s.line(&format!("call ${}", function));
+                    for param in ["$width", "$left", "$precision"] {
+                        s.get(param);
+                    }
+                    s.line("i32.const 0");
+                    s.line("call $ml_fmt_str");
+                }
+                None => {
+                    let debug = s.debug_fn(ty, span);
+                    s.get("$b");
+                    s.line("call $ml_buf_len");
+                    let start = s.spill("i32");
+                    s.get("$b");
+                    s.get("$v");
+                    s.line(&format!("call ${}", debug));
+                    for param in ["$b", &start, "$width", "$left"] {
+                        s.get(param);
+                    }
+                    s.line("call $ml_pad");
+                }
+            }
+        })
+    }
+
+    /// `ml_cleanup_T(v)` runs the `Drop` impls of what `v` points to and of
+    /// what it holds, outermost first; `None` when there are none
+    fn cleanup_fn(&mut self, ty: &Ty, span: Span) -> Option<String> {
+        if !self.krate.needs_cleanup(ty) {
+            return None;
+        }
+        let name = format!("ml_cleanup_{}", self.type_name(ty));
+        Some(self.helper(name, vec![("$v".to_string(), "i32")], None, |s| match ty {
+            Ty::Tuple(_) => s.cleanup_fields(ty, 0, span),
+            Ty::Array(elem, len) => {
+                s.line(&format!("i32.const {}", len.unwrap_or(0)));
+                let count = s.spill("i32");
+                s.get("$v");
+                let data = s.spill("i32");
+                s.cleanup_elements(&data, &count, elem, span);
+            }
+            Ty::Adt(_, elems) if s.krate.is_vec(ty) => {
+                let elem = elems.first().cloned().unwrap_or(Ty::Error);
+                s.get("$v");
+                s.line("i32.load offset=4");
+                let count = s.spill("i32");
+                s.get("$v");
+                s.line("i32.load");
+                let data = s.spill("i32");
+                s.cleanup_elements(&data, &count, &elem, span);
+            }
+            Ty::Adt(def, _) => {
+                if let Some(&drop) = s.krate.drops.get(def) {
+                    let by_ref = Ty::Ref { ty: Box::new(ty.clone()), mutable: true };
+                    if let Some((def, args)) = s.krate.resolve(drop, &[by_ref], &Ty::unit()) {
+                        let function = s.instance(def, args);
+                        s.get("$v");
+                        s.line(&format!("call ${}", function));
+                    }
+                }
+                if !s.krate.is_enum(ty) {
+                    return s.cleanup_fields(ty, 0, span);
+                }
+                for index in 0..s.types.adts[def].variants.len() {
+                    let fields = s.krate.fields_of(ty, index);
+                    if !fields.iter().any(|(_, field_ty)| s.krate.needs_cleanup(field_ty)) {
+                        continue;
+                    }
+                    s.get("$v");
+                    s.line("i32.load");
+                    s.line(&format!("i32.const {}", index));
+                    s.line("i32.eq");
+                    s.open("if");
+                    s.cleanup_fields(ty, index, span);
+                    s.close("end");
+                }
+            }
+            _ => {}
+        }))
+    }
+
+    /// Clean up the fields of variant `index` of the `ty` `$v` points to
+    fn cleanup_fields(&mut self, ty: &Ty, index: usize, span: Span) {
+        for (_, field_ty, offset) in self.fields(ty, index, span) {
+            if let Some(cleanup) = self.cleanup_fn(&field_ty, span) {
+                self.get("$v");
+                self.offset(offset);
+                self.line(&format!("call ${}", cleanup));
+            }
+        }
+    }
+
+    fn cleanup_elements(&mut self, data: &str, count: &str, elem: &Ty, span: Span) {
+        let Some(cleanup) = self.cleanup_fn(elem, span) else { return };
+        let size = self.layout(elem, span).size;
+        self.repeat(count, |s, i, _| {
+            s.get(data);
+            s.get(i);
+            s.line(&format!("i32.const {}", size));
+            s.line("i32.mul");
+            s.line("i32.add");
+            s.line(&format!("call ${}", cleanup));
+        });
+    }
+
+    // ========== Vectors ==========
+
+    /// `ml_contains_V(v, x)`, whether the vector `v` points to holds `x`
+    fn vec_contains_fn(&mut self, vec: &Ty, elem: &Ty, span: Span) -> String {
+        let name = format!("ml_contains_{}", self.type_name(vec));
+        let mut params = vec![("$v".to_string(), "i32")];
+        let value = self.repr(elem, span).val();
+        if let Some(val) = value {
+            params.push(("$x".to_string(), val));
+        }
+        self.helper(name, params, Some("i32"), |s| {
+            let size = s.layout(elem, span).size;
+            s.get("$v");
+            s.line("i32.load offset=4");
+            let count = s.spill("i32");
+            s.repeat(&count, |s, i, _| {
+                s.get("$v");
+                s.line("i32.load");
+                s.get(i);
+                s.line(&format!("i32.const {}", size));
+                s.line("i32.mul");
+                s.line("i32.add");
+                s.load(elem, span);
+                if value.is_some() {
+                    s.get("$x");
+                }
+                s.eq(elem, span);
+                s.leave_if(1);
+            });
+            s.line("i32.const 0");
+        })
+    }
+
+    /// `ml_cmp_T(a, b)` orders the elements at `a` and `b` for `ml_vec_sort`
+    fn cmp_fn(&mut self, elem: &Ty, span: Span) -> String {
+        let name = format!("ml_cmp_{}", self.type_name(elem));
+        let params = vec![("$a".to_string(), "i32"), ("$b".to_string(), "i32")];
+        self.helper(name, params, Some("i32"), |s| {
+            let load = |s: &mut Self, base: &str| {
+                s.get(base);
+                s.load(elem, span);
+            };
+            match elem {
+                _ if s.krate.is_str(elem) => {
+                    load(s, "$a");
+                    load(s, "$b");
+                    s.line("call $ml_str_cmp");
+                }
+                Ty::Prim(prim) if s.repr(elem, span) != Repr::Unit => {
+                    let val = s.repr(elem, span).val().unwrap_or("i32");
+                    let sign = match elem.is_float() {
+                        true => "",
+                        false if is_unsigned(*prim) || *prim == PrimitiveType::Char => "_u",
+                        false => "_s",
+                    };
+                    for test in ["gt", "lt"] {
+                        load(s, "$a");
+                        load(s, "$b");
+                        s.line(&format!("{}.{}{}", val, test, sign));
+                    }
+                    s.line("i32.sub");
+                }
+                _ => {
+                    s.unsupported(format!("sorting `{}`", elem.display(s.res)), span);
+                    s.line("i32.const 0");
+                }
+            }
+        })
+    }
+
+    // ========== Patterns ==========
+
+    /// Test the `ty` at the address in `place` against `pattern`, branching
+    /// to `fail` when it does not match, and bind the names it binds
+    fn pattern(&mut self, pattern: &ast::Pattern, place: &str, ty: &Ty, fail: &str) {
+        let span = pattern.span;
+        match &pattern.kind {
+            PatternKind::Wildcard => return,
+            PatternKind::Identifier(_) => {
+                if let Some(def) = self.res.def_of_node(pattern.id) {
+                    // Bound by value, or borrowed where matching looked through a reference
+                    let bind_ty = self.ty(pattern.id);
+                    let slot = self.slot(&bind_ty, span);
+                    self.addr(slot);
+                    self.get(place);
+                    match (&bind_ty, ty) {
+                        _ if bind_ty == *ty => self.load(ty, span),
+                        (Ty::Ref { ty: inner, mutable }, _) if **inner == *ty => {
+                            // A shared `&str` is the string itself
+                            if !mutable && self.krate.is_str(ty) {
+                                self.load(ty, span);
+                            }
+                        }
+                        _ => {
+                            self.load(ty, span);
+                            self.coerce(ty, &bind_ty);
+                        }
+                    }
+                    self.store(&bind_ty, span);
+                    self.frame.locals.insert(def, slot);
+                    return;
+                }
+            }
+            _ => {}
+        }
+        // Everything else looks through references
+        let mut place = place.to_string();
+        let mut ty = ty.clone();
+        while let Some(inner) = self.krate.pointee(&ty) {
+            self.get(&place);
+            self.load(&ty, span);
+            place = self.spill("i32");
+            ty = inner;
+        }
+        if let Ty::Ref { ty: inner, .. } = &ty {
+            ty = (**inner).clone();
+        }
+        match &pattern.kind {
+            PatternKind::Wildcard => {}
+            PatternKind::Literal(lit) => {
+                self.get(&place);
+                self.load(&ty, span);
+                self.literal(lit, &ty);
+                self.eq(&ty, span);
+                self.line("i32.eqz");
+                self.line(&format!("br_if {}", fail));
+            }
+            PatternKind::Identifier(_) | PatternKind::Path(_) => {
+                if let Some(Res::Def(def)) = self.res.res(pattern.id) {
+                    match self.res.def(*def).kind {
+                        DefKind::Variant => {
+                            self.variant_test(pattern, &place, &ty, fail);
+                        }
+                        DefKind::Const => {
+                            self.get(&place);
+                            self.load(&ty, span);
+                            self.constant(*def, span);
+                            self.eq(&ty, span);
+                            self.line("i32.eqz");
+                            self.line(&format!("br_if {}", fail));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            PatternKind::Tuple(elems) => {
+                let fields = self.fields(&ty, 0, span);
+                for (elem, (_, elem_ty, offset)) in elems.iter().zip(fields) {
+                    self.sub_pattern(elem, &place, offset, &elem_ty, fail);
+                }
+            }
+            PatternKind::TupleStruct { elems, .. } => {
+                let Some(index) = self.variant_test(pattern, &place, &ty, fail) else { return };
+                let fields = self.fields(&ty, index, span);
+                for (elem, (_, field_ty, offset)) in elems.iter().zip(fields) {
+                    self.sub_pattern(elem, &place, offset, &field_ty, fail);
+                }
+            }
+            PatternKind::Struct { fields, .. } => {
+                let Some(index) = self.variant_test(pattern, &place, &ty, fail) else { return };
+                let layout = self.fields(&ty, index, span);
+                for (field, sub) in fields {
+                    let Some((_, field_ty, offset)) = layout.iter().find(|(name, _, _)| name == field).cloned() else {
+                        continue;
+                    };
+                    self.sub_pattern(sub, &place, offset, &field_ty, fail);
+                }
+            }
+        }
+    }
+
+    /// Match the `ty` `offset` bytes past the address in `place` against `pattern`
+    fn sub_pattern(&mut self, pattern: &ast::Pattern, place: &str, offset: u32, ty: &Ty, fail: &str) {
+        if matches!(pattern.kind, PatternKind::Wildcard) {
+            return;
+        }
+        self.get(place);
+        self.offset(offset);
+        let at = self.spill("i32");
+        self.pattern(pattern, &at, ty, fail);
+    }
+
+    /// Test the tag of an enum for the variant `pattern` names, returning the variant's index
+    fn variant_test(&mut self, pattern: &ast::Pattern, place: &str, ty: &Ty, fail: &str) -> Option<usize> {
+        let Some(Res::Def(def)) = self.res.res(pattern.id) else { return None };
+        let index = self.krate.variant_of(*def).map_or(0, |(_, index)| index);
+        if self.krate.is_enum(ty) {
+            self.get(place);
+            self.line("i32.load");
+            self.line(&format!("i32.const {}", index));
+            self.line("i32.ne");
+            self.line(&format!("br_if {}", fail));
+        }
+        Some(index)
+    }
+}
+
+/// `n` rounded up to a multiple of `align`
+fn align_up(n: u32, align: u32) -> u32 {
+    n.div_ceil(align.max(1)) * align.max(1)
+}
+
+/// Keep only letters, digits and underscores in names
+fn sanitize(name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect();
+    match name.is_empty() {
+        true => "_".to_string(),
+        false => name,
+    }
+}
+
+/// The contents of a string in the text format holding `bytes`
+fn wat_bytes(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => out.push_str(&format!("\\{:02x}", byte)),
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:02x}", byte)),
+        }
+    }
+    out
+}
+
+/// The literal a constant is, and whether it is negated
+fn literal_of(value: &Expression) -> Option<(&Literal, bool)> {
+    match &value.kind {
+        ExpressionKind::Literal(lit) => Some((lit, false)),
+        ExpressionKind::Unary { op: UnaryOp::Neg, expr } => match &expr.kind {
+            ExpressionKind::Literal(lit) => Some((lit, true)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The name of the instruction for a comparison, without its sign
+fn compare_op(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Ne => "ne",
+        BinaryOp::Lt => "lt",
+        BinaryOp::Gt => "gt",
+        BinaryOp::Le => "le",
+        BinaryOp::Ge => "ge",
+        _ => "eq",
+    }
+}
+
+/// What the panic for `op` overflowing says it attempted
+fn overflow_verb(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Sub => "subtract",
+        BinaryOp::Mul => "multiply",
+        BinaryOp::Div => "divide",
+        BinaryOp::Mod => "calculate the remainder",
+        _ => "add",
+    }
+}
+
+fn arith_op(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Sub => "sub",
+        BinaryOp::Mul => "mul",
+        _ => "add",
+    }
+}
+
+fn op_symbol(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Mod => "%",
+        BinaryOp::Eq => "==",
+        BinaryOp::Ne => "!=",
+        BinaryOp::Lt => "<",
+        BinaryOp::Gt => ">",
+        BinaryOp::Le => "<=",
+        BinaryOp::Ge => ">=",
+        BinaryOp::And => "&&",
+        BinaryOp::Or => "||",
+        BinaryOp::BitAnd => "&",
+        BinaryOp::BitOr => "|",
+        BinaryOp::BitXor => "^",
+        BinaryOp::Shl => "<<",
+        BinaryOp::Shr => ">>",
+        BinaryOp::Assign => "=",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        check_source, missing, DIVIDE_BY_ZERO, DROPS, FILES, HASH_MAP, MAIN_ERROR, OUT_OF_BOUNDS, OVERFLOW, SHAPES,
+        TOTALS, WITHDRAW,
+    };
+    use std::process::Command;
+
+    /// Runs a module under Node's WASI, with the exit status `proc_exit` gives
+    /// and the working directory open to it
+    const RUNNER: &str = r#"
+        const { WASI } = require("node:wasi");
+        const fs = require("node:fs");
+        const wasi = new WASI({ version: "preview1", args: ["test"], preopens: { ".": "." }, returnOnExit: true });
+        const wasm = new WebAssembly.Module(fs.readFileSync(process.argv[2]));
+        const instance = new WebAssembly.Instance(wasm, wasi.getImportObject());
+        process.exitCode = wasi.start(instance);
+    "#;
+
+    fn emit_source(source: &str) -> (WasmModule, Vec<Diagnostic>) {
+        let (program, res, types) = check_source(source);
+        emit(&[&program], &res, &types, &["test.solo".to_string()])
+    }
+
+    /// Exit status, stdout and stderr of the module built from `source`
+    /// under Node, or `None` where there is no Node
+    fn run(source: &str) -> Option<(i32, String, String)> {
+        let (module, diags) = emit_source(source);
+        assert!(diags.is_empty(), "{:?}\n{}", diags, module.text);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("test.wasm"), &module.binary).unwrap();
+        std::fs::write(dir.path().join("run.cjs"), RUNNER).unwrap();
+        let Ok(ran) =
+            Command::new("node").current_dir(dir.path()).args(["--no-warnings", "run.cjs", "test.wasm"]).output()
+        else {
+            return missing("node");
+        };
+        let (out, err) = (String::from_utf8(ran.stdout).unwrap(), String::from_utf8(ran.stderr).unwrap());
+        Some((ran.status.code().unwrap_or(-1), out, err))
+    }
+
+    #[test]
+    fn test_module_validates_and_imports_wasi() {
+        let (module, diags) = emit_source("fn main() { println(\"hello\"); }");
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert!(module.binary.starts_with(b"\0asm"));
+        assert!(module.text.contains("(import \"wasi_snapshot_preview1\" \"fd_write\""));
+        assert!(module.text.contains("(export \"_start\""));
+        let Some((status, out, _)) = run("fn main() { println(\"hello\"); }") else { return };
+        assert_eq!(status, 0);
+        assert_eq!(out, "hello\n");
+    }
+
+    #[test]
+    fn test_enums_and_formatting() {
+        SHAPES.check(run(SHAPES.source));
+    }
+
+    #[test]
+    fn test_contracts_panic_with_location() {
+        let Some((status, out, err)) = run(WITHDRAW) else { return };
+        assert_ne!(status, 0);
+        assert_eq!(out, "7\n");
+        assert!(err.contains("precondition `amount <= balance` of `withdraw` violated"), "{}", err);
+        assert!(err.contains("test.solo:"), "{}", err);
+    }
+
+    #[test]
+    fn test_drop_runs_cleanup() {
+        let (module, _) = emit_source(DROPS.source);
+        assert!(module.text.contains("call $ml_cleanup_Holder"), "{}", module.text);
+        DROPS.check(run(DROPS.source));
+    }
+
+    #[test]
+    fn test_generics_and_vectors() {
+        let (module, _) = emit_source(TOTALS.source);
+        assert!(module.text.contains("(func $total__i32"), "{}", module.text);
+        assert!(module.text.contains("(func $total__f64"));
+        TOTALS.check(run(TOTALS.source));
+    }
+
+    #[test]
+    fn test_index_out_of_bounds_panics() {
+        OUT_OF_BOUNDS.check(run(OUT_OF_BOUNDS.source));
+    }
+
+    #[test]
+    fn test_integer_arithmetic_is_checked() {
+        OVERFLOW.check(run(OVERFLOW.source));
+        DIVIDE_BY_ZERO.check(run(DIVIDE_BY_ZERO.source));
+    }
+
+    #[test]
+    fn test_main_error_exits_with_failure() {
+        MAIN_ERROR.check(run(MAIN_ERROR.source));
+    }
+
+    #[test]
+    fn test_files_are_written_and_read() {
+        FILES.check(run(FILES.source));
+    }
+
+    #[test]
+    fn test_unsupported_types_are_reported() {
+        let (module, diags) = emit_source(HASH_MAP);
+        assert!(module.binary.is_empty());
+        assert!(!diags.is_empty());
+        assert!(diags.iter().all(|d| d.code == Some(codes::UNSUPPORTED_BY_TARGET)), "{:?}", diags);
+        assert!(diags.iter().any(|d| d.message == "`HashMap` cannot be compiled to WebAssembly"), "{:?}", diags);
+    }
+}
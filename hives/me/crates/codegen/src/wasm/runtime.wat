@@ -0,0 +1,1416 @@
+  ;; Support code for WebAssembly generated from Solo: memory, strings,
+  ;; formatting, panics, and the WASI calls behind printing, arguments and
+  ;; files. The generated module supplies the memory, the function table and
+  ;; the globals $ml_sp, $ml_stack_limit and $ml_heap.
+  ;;
+  ;; A string is the address of its length as an i32, followed by its bytes.
+  ;; Strings are never changed in place; nothing is freed but the last block.
+
+  (import "wasi_snapshot_preview1" "fd_write" (func $wasi_fd_write (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_read" (func $wasi_fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_close" (func $wasi_fd_close (param i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_prestat_get" (func $wasi_fd_prestat_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_prestat_dir_name"
+    (func $wasi_fd_prestat_dir_name (param i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "path_open"
+    (func $wasi_path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "args_sizes_get" (func $wasi_args_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "args_get" (func $wasi_args_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "proc_exit" (func $wasi_proc_exit (param i32)))
+
+  ;; Orders two elements of a vector being sorted, given their addresses
+  (type $ml_compare (func (param i32 i32) (result i32)))
+
+  ;; ========== Layout ==========
+
+  ;; 0..32      results of WASI calls and the iovec they read
+  ;; 96..128    digits of integers being formatted
+  ;; 256..1024  text the support code prints
+  ;; 1024..2048 limbs of the big number floats are formatted with
+  ;; 2048..8192 digits of floats being formatted
+  ;; 8192..     string literals, then the shadow stack, then the heap
+
+  (global $ml_limbs i32 (i32.const 1024))
+  (global $ml_limb_count (mut i32) (i32.const 0))
+  (global $ml_digits_value i32 (i32.const 2048))
+  (global $ml_digits_low i32 (i32.const 3584))
+  (global $ml_digits_high i32 (i32.const 5120))
+  (global $ml_digits_rounded i32 (i32.const 6656))
+
+  ;; Nested calls allowed before the program stops with a stack overflow
+  (global $ml_max_depth i32 (i32.const 10000))
+  (global $ml_depth (mut i32) (i32.const 0))
+
+  (global $ml_text_empty i32 (i32.const 256))
+  (global $ml_text_true i32 (i32.const 260))
+  (global $ml_text_false i32 (i32.const 268))
+  (global $ml_text_nan i32 (i32.const 280))
+  (global $ml_text_inf i32 (i32.const 288))
+  (global $ml_text_panicked i32 (i32.const 296))
+  (global $ml_text_colon_newline i32 (i32.const 328))
+  (global $ml_text_index_len i32 (i32.const 336))
+  (global $ml_text_index_index i32 (i32.const 372))
+  (global $ml_text_overflow i32 (i32.const 396))
+  (global $ml_text_insert_index i32 (i32.const 440))
+  (global $ml_text_insert_len i32 (i32.const 464))
+  (global $ml_text_remove_index i32 (i32.const 492))
+  (global $ml_text_remove_len i32 (i32.const 516))
+  (global $ml_text_os_error i32 (i32.const 544))
+  (global $ml_text_unknown_error i32 (i32.const 560))
+  (global $ml_text_alloc_failed i32 (i32.const 580))
+  (global $ml_text_e2 i32 (i32.const 612))
+  (global $ml_text_e8 i32 (i32.const 636))
+  (global $ml_text_e20 i32 (i32.const 660))
+  (global $ml_text_e28 i32 (i32.const 676))
+  (global $ml_text_e31 i32 (i32.const 696))
+  (global $ml_text_e44 i32 (i32.const 716))
+  (global $ml_text_e54 i32 (i32.const 748))
+  (global $ml_text_e63 i32 (i32.const 768))
+  (global $ml_text_e76 i32 (i32.const 796))
+
+  (data (i32.const 256) "\00\00\00\00")
+  (data (i32.const 260) "\04\00\00\00true")
+  (data (i32.const 268) "\05\00\00\00false")
+  (data (i32.const 280) "\03\00\00\00NaN")
+  (data (i32.const 288) "\03\00\00\00inf")
+  (data (i32.const 296) "\1a\00\00\00thread 'main' panicked at ")
+  (data (i32.const 328) "\02\00\00\00:\0a")
+  (data (i32.const 336) " \00\00\00index out of bounds: the len is ")
+  (data (i32.const 372) "\12\00\00\00 but the index is ")
+  (data (i32.const 396) "(\00\00\00\0athread 'main' has overflowed its stack\0a")
+  (data (i32.const 440) "\14\00\00\00insertion index (is ")
+  (data (i32.const 464) "\17\00\00\00) should be <= len (is ")
+  (data (i32.const 492) "\12\00\00\00removal index (is ")
+  (data (i32.const 516) "\16\00\00\00) should be < len (is ")
+  (data (i32.const 544) "\0b\00\00\00 (os error ")
+  (data (i32.const 560) "\0d\00\00\00Unknown error")
+  (data (i32.const 580) "\19\00\00\00memory allocation failed\0a")
+  (data (i32.const 612) "\11\00\00\00Permission denied")
+  (data (i32.const 636) "\13\00\00\00Bad file descriptor")
+  (data (i32.const 660) "\0b\00\00\00File exists")
+  (data (i32.const 676) "\10\00\00\00Invalid argument")
+  (data (i32.const 696) "\0e\00\00\00Is a directory")
+  (data (i32.const 716) "\19\00\00\00No such file or directory")
+  (data (i32.const 748) "\0f\00\00\00Not a directory")
+  (data (i32.const 768) "\17\00\00\00Operation not permitted")
+  (data (i32.const 796) "\19\00\00\00Capabilities insufficient")
+
+  ;; ========== Memory ==========
+
+  ;; `size` bytes of the heap, 8-aligned, growing memory as needed
+  (func $ml_alloc (param $size i32) (result i32)
+    (local $p i32) (local $end i32) (local $top i32)
+    (local.set $p (i32.and (i32.add (global.get $ml_heap) (i32.const 7)) (i32.const -8)))
+    (local.set $end (i32.add (local.get $p) (local.get $size)))
+    (if (i32.lt_u (local.get $end) (local.get $p))
+      (then (call $ml_write (i32.const 2) (global.get $ml_text_alloc_failed)) (call $ml_exit (i32.const 134))))
+    (local.set $top (i32.shl (memory.size) (i32.const 16)))
+    (if (i32.gt_u (local.get $end) (local.get $top))
+      (then
+        (if (i32.lt_s
+              (memory.grow (i32.shr_u (i32.add (i32.sub (local.get $end) (local.get $top)) (i32.const 65535))
+                                      (i32.const 16)))
+              (i32.const 0))
+          (then (call $ml_write (i32.const 2) (global.get $ml_text_alloc_failed)) (call $ml_exit (i32.const 134))))))
+    (global.set $ml_heap (local.get $end))
+    (local.get $p))
+
+  ;; Give back `size` bytes at `p`, which only the last block allocated can
+  (func $ml_free (param $p i32) (param $size i32)
+    (if (i32.eq (i32.and (i32.add (i32.add (local.get $p) (local.get $size)) (i32.const 7)) (i32.const -8))
+                (i32.and (i32.add (global.get $ml_heap) (i32.const 7)) (i32.const -8)))
+      (then (global.set $ml_heap (local.get $p)))))
+
+  ;; A block of `new` bytes starting with the `old` bytes at `p`, which is
+  ;; extended where it is when it was the last block allocated
+  (func $ml_realloc (param $p i32) (param $old i32) (param $new i32) (result i32)
+    (local $q i32)
+    (if (i32.and (i32.ne (local.get $p) (i32.const 0))
+                 (i32.eq (i32.add (local.get $p) (local.get $old)) (global.get $ml_heap)))
+      (then
+        (global.set $ml_heap (local.get $p))
+        (return (call $ml_alloc (local.get $new)))))
+    (local.set $q (call $ml_alloc (local.get $new)))
+    (memory.copy (local.get $q) (local.get $p) (local.get $old))
+    (local.get $q))
+
+  ;; A heap copy of `size` bytes, which is what `Box::new` makes
+  (func $ml_box (param $value i32) (param $size i32) (result i32)
+    (local $p i32)
+    (local.set $p (call $ml_alloc (local.get $size)))
+    (memory.copy (local.get $p) (local.get $value) (local.get $size))
+    (local.get $p))
+
+  ;; Make room for a frame of `size` bytes on the shadow stack, returning where it starts
+  (func $ml_enter (param $size i32) (result i32)
+    (local $fp i32)
+    (global.set $ml_depth (i32.add (global.get $ml_depth) (i32.const 1)))
+    (local.set $fp (i32.sub (global.get $ml_sp) (local.get $size)))
+    (if (i32.or (i32.gt_u (global.get $ml_depth) (global.get $ml_max_depth))
+                (i32.lt_u (local.get $fp) (global.get $ml_stack_limit)))
+      (then (call $ml_write (i32.const 2) (global.get $ml_text_overflow)) (call $ml_exit (i32.const 134))))
+    (global.set $ml_sp (local.get $fp))
+    (local.get $fp))
+
+  (func $ml_leave (param $fp i32) (param $size i32)
+    (global.set $ml_depth (i32.sub (global.get $ml_depth) (i32.const 1)))
+    (global.set $ml_sp (i32.add (local.get $fp) (local.get $size))))
+
+  ;; Compare `n` bytes: negative, zero or positive
+  (func $ml_memcmp (param $a i32) (param $b i32) (param $n i32) (result i32)
+    (local $i i32) (local $x i32) (local $y i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $n)))
+        (local.set $x (i32.load8_u (i32.add (local.get $a) (local.get $i))))
+        (local.set $y (i32.load8_u (i32.add (local.get $b) (local.get $i))))
+        (if (i32.ne (local.get $x) (local.get $y))
+          (then (return (i32.sub (local.get $x) (local.get $y)))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.const 0))
+
+  ;; ========== Text ==========
+
+  ;; A string of the `n` bytes at `p`
+  (func $ml_str_new (param $p i32) (param $n i32) (result i32)
+    (local $s i32)
+    (local.set $s (call $ml_alloc (i32.add (local.get $n) (i32.const 4))))
+    (i32.store (local.get $s) (local.get $n))
+    (memory.copy (i32.add (local.get $s) (i32.const 4)) (local.get $p) (local.get $n))
+    (local.get $s))
+
+  (func $ml_str_eq (param $a i32) (param $b i32) (result i32)
+    (if (i32.ne (i32.load (local.get $a)) (i32.load (local.get $b)))
+      (then (return (i32.const 0))))
+    (i32.eqz (call $ml_memcmp (i32.add (local.get $a) (i32.const 4)) (i32.add (local.get $b) (i32.const 4))
+                              (i32.load (local.get $a)))))
+
+  (func $ml_str_cmp (param $a i32) (param $b i32) (result i32)
+    (local $la i32) (local $lb i32) (local $c i32)
+    (local.set $la (i32.load (local.get $a)))
+    (local.set $lb (i32.load (local.get $b)))
+    (local.set $c (call $ml_memcmp (i32.add (local.get $a) (i32.const 4)) (i32.add (local.get $b) (i32.const 4))
+                                   (select (local.get $la) (local.get $lb) (i32.lt_u (local.get $la) (local.get $lb)))))
+    (if (local.get $c) (then (return (local.get $c))))
+    (i32.sub (i32.gt_u (local.get $la) (local.get $lb)) (i32.lt_u (local.get $la) (local.get $lb))))
+
+  (func $ml_str_concat (param $a i32) (param $b i32) (result i32)
+    (local $la i32) (local $lb i32) (local $s i32)
+    (local.set $la (i32.load (local.get $a)))
+    (local.set $lb (i32.load (local.get $b)))
+    (local.set $s (call $ml_alloc (i32.add (i32.add (local.get $la) (local.get $lb)) (i32.const 4))))
+    (i32.store (local.get $s) (i32.add (local.get $la) (local.get $lb)))
+    (memory.copy (i32.add (local.get $s) (i32.const 4)) (i32.add (local.get $a) (i32.const 4)) (local.get $la))
+    (memory.copy (i32.add (i32.add (local.get $s) (i32.const 4)) (local.get $la))
+                 (i32.add (local.get $b) (i32.const 4)) (local.get $lb))
+    (local.get $s))
+
+  (func $ml_str_from_char (param $c i32) (result i32)
+    (local $b i32)
+    (local.set $b (call $ml_buf_new))
+    (call $ml_buf_char (local.get $b) (local.get $c))
+    (call $ml_buf_finish (local.get $b)))
+
+  ;; Where `part` first occurs in `s` at or after `from`, or -1
+  (func $ml_str_find (param $s i32) (param $part i32) (param $from i32) (result i32)
+    (local $n i32) (local $m i32) (local $i i32)
+    (local.set $n (i32.load (local.get $s)))
+    (local.set $m (i32.load (local.get $part)))
+    (local.set $i (local.get $from))
+    (block $done
+      (loop $next
+        (br_if $done (i32.gt_s (i32.add (local.get $i) (local.get $m)) (local.get $n)))
+        (if (i32.eqz (call $ml_memcmp (i32.add (i32.add (local.get $s) (i32.const 4)) (local.get $i))
+                                      (i32.add (local.get $part) (i32.const 4)) (local.get $m)))
+          (then (return (local.get $i))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.const -1))
+
+  (func $ml_str_contains (param $s i32) (param $part i32) (result i32)
+    (i32.ge_s (call $ml_str_find (local.get $s) (local.get $part) (i32.const 0)) (i32.const 0)))
+
+  (func $ml_str_starts_with (param $s i32) (param $prefix i32) (result i32)
+    (if (i32.lt_u (i32.load (local.get $s)) (i32.load (local.get $prefix)))
+      (then (return (i32.const 0))))
+    (i32.eqz (call $ml_memcmp (i32.add (local.get $s) (i32.const 4)) (i32.add (local.get $prefix) (i32.const 4))
+                              (i32.load (local.get $prefix)))))
+
+  (func $ml_str_ends_with (param $s i32) (param $suffix i32) (result i32)
+    (local $n i32) (local $m i32)
+    (local.set $n (i32.load (local.get $s)))
+    (local.set $m (i32.load (local.get $suffix)))
+    (if (i32.lt_u (local.get $n) (local.get $m))
+      (then (return (i32.const 0))))
+    (i32.eqz (call $ml_memcmp (i32.add (i32.add (local.get $s) (i32.const 4)) (i32.sub (local.get $n) (local.get $m)))
+                              (i32.add (local.get $suffix) (i32.const 4)) (local.get $m))))
+
+  (func $ml_is_space (param $c i32) (result i32)
+    (i32.or (i32.or (i32.eq (local.get $c) (i32.const 32)) (i32.eq (local.get $c) (i32.const 9)))
+            (i32.or (i32.eq (local.get $c) (i32.const 10)) (i32.eq (local.get $c) (i32.const 13)))))
+
+  (func $ml_str_trim (param $s i32) (result i32)
+    (local $start i32) (local $end i32)
+    (local.set $start (i32.add (local.get $s) (i32.const 4)))
+    (local.set $end (i32.add (local.get $start) (i32.load (local.get $s))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $start) (local.get $end)))
+        (br_if $done (i32.eqz (call $ml_is_space (i32.load8_u (local.get $start)))))
+        (local.set $start (i32.add (local.get $start) (i32.const 1)))
+        (br $next)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $start) (local.get $end)))
+        (br_if $done (i32.eqz (call $ml_is_space (i32.load8_u (i32.sub (local.get $end) (i32.const 1))))))
+        (local.set $end (i32.sub (local.get $end) (i32.const 1)))
+        (br $next)))
+    (call $ml_str_new (local.get $start) (i32.sub (local.get $end) (local.get $start))))
+
+  ;; ASCII case mapping; other characters are kept
+  (func $ml_str_map_case (param $s i32) (param $upper i32) (result i32)
+    (local $t i32) (local $i i32) (local $n i32) (local $c i32)
+    (local.set $n (i32.load (local.get $s)))
+    (local.set $t (call $ml_str_new (i32.add (local.get $s) (i32.const 4)) (local.get $n)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $n)))
+        (local.set $c (i32.load8_u (i32.add (i32.add (local.get $t) (i32.const 4)) (local.get $i))))
+        (if (local.get $upper)
+          (then
+            (if (i32.lt_u (i32.sub (local.get $c) (i32.const 97)) (i32.const 26))
+              (then (local.set $c (i32.sub (local.get $c) (i32.const 32))))))
+          (else
+            (if (i32.lt_u (i32.sub (local.get $c) (i32.const 65)) (i32.const 26))
+              (then (local.set $c (i32.add (local.get $c) (i32.const 32)))))))
+        (i32.store8 (i32.add (i32.add (local.get $t) (i32.const 4)) (local.get $i)) (local.get $c))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (local.get $t))
+
+  ;; Bytes in the UTF-8 character that starts with the byte at `p`
+  (func $ml_char_len (param $p i32) (result i32)
+    (local $c i32)
+    (local.set $c (i32.load8_u (local.get $p)))
+    (if (i32.lt_u (local.get $c) (i32.const 0x80)) (then (return (i32.const 1))))
+    (if (i32.lt_u (local.get $c) (i32.const 0xE0)) (then (return (i32.const 2))))
+    (if (i32.lt_u (local.get $c) (i32.const 0xF0)) (then (return (i32.const 3))))
+    (i32.const 4))
+
+  ;; The character whose UTF-8 starts at `p`
+  (func $ml_char_at (param $p i32) (result i32)
+    (local $n i32) (local $c i32) (local $i i32)
+    (local.set $n (call $ml_char_len (local.get $p)))
+    (local.set $c (i32.load8_u (local.get $p)))
+    (local.set $c
+      (i32.and (local.get $c)
+               (select (i32.const 0x7F)
+                       (select (i32.const 0x1F) (select (i32.const 0x0F) (i32.const 0x07) (i32.eq (local.get $n) (i32.const 3)))
+                               (i32.eq (local.get $n) (i32.const 2)))
+                       (i32.eq (local.get $n) (i32.const 1)))))
+    (local.set $i (i32.const 1))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $n)))
+        (local.set $c (i32.or (i32.shl (local.get $c) (i32.const 6))
+                              (i32.and (i32.load8_u (i32.add (local.get $p) (local.get $i))) (i32.const 0x3F))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (local.get $c))
+
+  ;; ========== Buffers ==========
+
+  ;; Text being built is a buffer: the address of its block, length and
+  ;; capacity. The block keeps four bytes in front for the length the
+  ;; finished string has.
+
+  (func $ml_buf_new (result i32)
+    (local $b i32)
+    (local.set $b (call $ml_alloc (i32.const 12)))
+    (i32.store (local.get $b) (i32.const 0))
+    (i32.store offset=4 (local.get $b) (i32.const 0))
+    (i32.store offset=8 (local.get $b) (i32.const 0))
+    (local.get $b))
+
+  ;; Room for `n` more bytes
+  (func $ml_buf_reserve (param $b i32) (param $n i32)
+    (local $need i32) (local $cap i32) (local $data i32)
+    (local.set $need (i32.add (i32.load offset=4 (local.get $b)) (local.get $n)))
+    (local.set $cap (i32.load offset=8 (local.get $b)))
+    (if (i32.le_u (local.get $need) (local.get $cap)) (then (return)))
+    (local.set $data (i32.load (local.get $b)))
+    (local.set $cap (select (i32.shl (local.get $cap) (i32.const 1)) (i32.const 16) (local.get $cap)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $cap) (local.get $need)))
+        (local.set $cap (i32.shl (local.get $cap) (i32.const 1)))
+        (br $next)))
+    (local.set $data
+      (call $ml_realloc (local.get $data)
+                        (select (i32.add (i32.load offset=8 (local.get $b)) (i32.const 4)) (i32.const 0) (local.get $data))
+                        (i32.add (local.get $cap) (i32.const 4))))
+    (i32.store (local.get $b) (local.get $data))
+    (i32.store offset=8 (local.get $b) (local.get $cap)))
+
+  ;; Where the next byte goes
+  (func $ml_buf_end (param $b i32) (result i32)
+    (i32.add (i32.add (i32.load (local.get $b)) (i32.const 4)) (i32.load offset=4 (local.get $b))))
+
+  (func $ml_buf_bytes (param $b i32) (param $p i32) (param $n i32)
+    (call $ml_buf_reserve (local.get $b) (local.get $n))
+    (memory.copy (call $ml_buf_end (local.get $b)) (local.get $p) (local.get $n))
+    (i32.store offset=4 (local.get $b) (i32.add (i32.load offset=4 (local.get $b)) (local.get $n))))
+
+  (func $ml_buf_byte (param $b i32) (param $c i32)
+    (call $ml_buf_reserve (local.get $b) (i32.const 1))
+    (i32.store8 (call $ml_buf_end (local.get $b)) (local.get $c))
+    (i32.store offset=4 (local.get $b) (i32.add (i32.load offset=4 (local.get $b)) (i32.const 1))))
+
+  (func $ml_buf_str (param $b i32) (param $s i32)
+    (call $ml_buf_bytes (local.get $b) (i32.add (local.get $s) (i32.const 4)) (i32.load (local.get $s))))
+
+  ;; Append the UTF-8 of the character `c`
+  (func $ml_buf_char (param $b i32) (param $c i32)
+    (if (i32.lt_u (local.get $c) (i32.const 0x80))
+      (then (call $ml_buf_byte (local.get $b) (local.get $c)) (return)))
+    (if (i32.lt_u (local.get $c) (i32.const 0x800))
+      (then
+        (call $ml_buf_byte (local.get $b) (i32.or (i32.const 0xC0) (i32.shr_u (local.get $c) (i32.const 6))))
+        (call $ml_buf_byte (local.get $b) (i32.or (i32.const 0x80) (i32.and (local.get $c) (i32.const 0x3F))))
+        (return)))
+    (if (i32.lt_u (local.get $c) (i32.const 0x10000))
+      (then
+        (call $ml_buf_byte (local.get $b) (i32.or (i32.const 0xE0) (i32.shr_u (local.get $c) (i32.const 12))))
+        (call $ml_buf_byte (local.get $b)
+          (i32.or (i32.const 0x80) (i32.and (i32.shr_u (local.get $c) (i32.const 6)) (i32.const 0x3F))))
+        (call $ml_buf_byte (local.get $b) (i32.or (i32.const 0x80) (i32.and (local.get $c) (i32.const 0x3F))))
+        (return)))
+    (call $ml_buf_byte (local.get $b) (i32.or (i32.const 0xF0) (i32.shr_u (local.get $c) (i32.const 18))))
+    (call $ml_buf_byte (local.get $b)
+      (i32.or (i32.const 0x80) (i32.and (i32.shr_u (local.get $c) (i32.const 12)) (i32.const 0x3F))))
+    (call $ml_buf_byte (local.get $b)
+      (i32.or (i32.const 0x80) (i32.and (i32.shr_u (local.get $c) (i32.const 6)) (i32.const 0x3F))))
+    (call $ml_buf_byte (local.get $b) (i32.or (i32.const 0x80) (i32.and (local.get $c) (i32.const 0x3F)))))
+
+  ;; The text built so far, as a string
+  (func $ml_buf_finish (param $b i32) (result i32)
+    (local $data i32)
+    (local.set $data (i32.load (local.get $b)))
+    (if (i32.eqz (local.get $data)) (then (return (global.get $ml_text_empty))))
+    (i32.store (local.get $data) (i32.load offset=4 (local.get $b)))
+    ;; The capacity left over goes back when nothing came after it
+    (if (i32.eq (i32.add (i32.add (local.get $data) (i32.const 4)) (i32.load offset=8 (local.get $b)))
+                (global.get $ml_heap))
+      (then (global.set $ml_heap (call $ml_buf_end (local.get $b)))))
+    (local.get $data))
+
+  ;; Pad what was written since `start` to `width` characters: spaces go in
+  ;; front unless `left`, and a negative width asks for none
+  (func $ml_pad (param $b i32) (param $start i32) (param $width i32) (param $left i32)
+    (local $p i32) (local $end i32) (local $chars i32) (local $fill i32) (local $from i32)
+    (if (i32.lt_s (local.get $width) (i32.const 0)) (then (return)))
+    (local.set $p (i32.add (i32.add (i32.load (local.get $b)) (i32.const 4)) (local.get $start)))
+    (local.set $end (call $ml_buf_end (local.get $b)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $p) (local.get $end)))
+        (local.set $chars (i32.add (local.get $chars)
+                                   (i32.ne (i32.and (i32.load8_u (local.get $p)) (i32.const 0xC0)) (i32.const 0x80))))
+        (local.set $p (i32.add (local.get $p) (i32.const 1)))
+        (br $next)))
+    (if (i32.ge_u (local.get $chars) (local.get $width)) (then (return)))
+    (local.set $fill (i32.sub (local.get $width) (local.get $chars)))
+    (call $ml_buf_reserve (local.get $b) (local.get $fill))
+    (local.set $end (call $ml_buf_end (local.get $b)))
+    (if (local.get $left)
+      (then (memory.fill (local.get $end) (i32.const 32) (local.get $fill)))
+      (else
+        (local.set $from (i32.add (i32.add (i32.load (local.get $b)) (i32.const 4)) (local.get $start)))
+        (memory.copy (i32.add (local.get $from) (local.get $fill)) (local.get $from)
+                     (i32.sub (local.get $end) (local.get $from)))
+        (memory.fill (local.get $from) (i32.const 32) (local.get $fill))))
+    (i32.store offset=4 (local.get $b) (i32.add (i32.load offset=4 (local.get $b)) (local.get $fill))))
+
+  (func $ml_buf_len (param $b i32) (result i32)
+    (i32.load offset=4 (local.get $b)))
+
+  ;; ========== Formatting ==========
+
+  ;; Every `ml_fmt_*` takes the buffer, the value, then the width, whether to
+  ;; align left and the precision, which are -1, 0 and -1 when not given
+
+  (func $ml_put_u64 (param $b i32) (param $n i64)
+    (local $p i32)
+    (local.set $p (i32.const 128))
+    (loop $next
+      (local.set $p (i32.sub (local.get $p) (i32.const 1)))
+      (i32.store8 (local.get $p) (i32.add (i32.const 48) (i32.wrap_i64 (i64.rem_u (local.get $n) (i64.const 10)))))
+      (local.set $n (i64.div_u (local.get $n) (i64.const 10)))
+      (br_if $next (i64.ne (local.get $n) (i64.const 0))))
+    (call $ml_buf_bytes (local.get $b) (local.get $p) (i32.sub (i32.const 128) (local.get $p))))
+
+  (func $ml_fmt_u64 (param $b i32) (param $n i64) (param $width i32) (param $left i32) (param $precision i32)
+    (local $start i32)
+    (local.set $start (call $ml_buf_len (local.get $b)))
+    (call $ml_put_u64 (local.get $b) (local.get $n))
+    (call $ml_pad (local.get $b) (local.get $start) (local.get $width) (local.get $left)))
+
+  (func $ml_fmt_i64 (param $b i32) (param $n i64) (param $width i32) (param $left i32) (param $precision i32)
+    (local $start i32)
+    (local.set $start (call $ml_buf_len (local.get $b)))
+    (if (i64.lt_s (local.get $n) (i64.const 0))
+      (then
+        (call $ml_buf_byte (local.get $b) (i32.const 45))
+        (local.set $n (i64.sub (i64.const 0) (local.get $n)))))
+    (call $ml_put_u64 (local.get $b) (local.get $n))
+    (call $ml_pad (local.get $b) (local.get $start) (local.get $width) (local.get $left)))
+
+  (func $ml_fmt_bool (param $b i32) (param $v i32) (param $width i32) (param $left i32) (param $precision i32)
+    (local $start i32)
+    (local.set $start (call $ml_buf_len (local.get $b)))
+    (call $ml_buf_str (local.get $b) (select (global.get $ml_text_true) (global.get $ml_text_false) (local.get $v)))
+    (call $ml_pad (local.get $b) (local.get $start) (local.get $width) (local.get $left)))
+
+  ;; A character as `{:?}` shows it inside `quote`s
+  (func $ml_escape (param $b i32) (param $c i32) (param $quote i32)
+    (local $e i32)
+    (local.set $e (i32.const -1))
+    (if (i32.eq (local.get $c) (i32.const 10)) (then (local.set $e (i32.const 110))))
+    (if (i32.eq (local.get $c) (i32.const 9)) (then (local.set $e (i32.const 116))))
+    (if (i32.eq (local.get $c) (i32.const 13)) (then (local.set $e (i32.const 114))))
+    (if (i32.eqz (local.get $c)) (then (local.set $e (i32.const 48))))
+    (if (i32.or (i32.eq (local.get $c) (i32.const 92)) (i32.eq (local.get $c) (local.get $quote)))
+      (then (local.set $e (local.get $c))))
+    (if (i32.ge_s (local.get $e) (i32.const 0))
+      (then (call $ml_buf_byte (local.get $b) (i32.const 92)) (call $ml_buf_byte (local.get $b) (local.get $e)))
+      (else (call $ml_buf_char (local.get $b) (local.get $c)))))
+
+  (func $ml_fmt_char
+    (param $b i32) (param $c i32) (param $width i32) (param $left i32) (param $precision i32) (param $debug i32)
+    (local $start i32)
+    (local.set $start (call $ml_buf_len (local.get $b)))
+    (if (local.get $debug)
+      (then
+        (call $ml_buf_byte (local.get $b) (i32.const 39))
+        (call $ml_escape (local.get $b) (local.get $c) (i32.const 39))
+        (call $ml_buf_byte (local.get $b) (i32.const 39)))
+      (else (call $ml_buf_char (local.get $b) (local.get $c))))
+    (call $ml_pad (local.get $b) (local.get $start) (local.get $width) (local.get $left)))
+
+  (func $ml_fmt_str
+    (param $b i32) (param $s i32) (param $width i32) (param $left i32) (param $precision i32) (param $debug i32)
+    (local $start i32) (local $p i32) (local $end i32)
+    (local.set $start (call $ml_buf_len (local.get $b)))
+    (if (local.get $debug)
+      (then
+        (local.set $p (i32.add (local.get $s) (i32.const 4)))
+        (local.set $end (i32.add (local.get $p) (i32.load (local.get $s))))
+        (call $ml_buf_byte (local.get $b) (i32.const 34))
+        (block $done
+          (loop $next
+            (br_if $done (i32.ge_u (local.get $p) (local.get $end)))
+            (call $ml_escape (local.get $b) (call $ml_char_at (local.get $p)) (i32.const 34))
+            (local.set $p (i32.add (local.get $p) (call $ml_char_len (local.get $p))))
+            (br $next)))
+        (call $ml_buf_byte (local.get $b) (i32.const 34)))
+      (else (call $ml_buf_str (local.get $b) (local.get $s))))
+    (call $ml_pad (local.get $b) (local.get $start) (local.get $width) (local.get $left)))
+
+  (func $ml_i64_to_str (param $n i64) (result i32)
+    (local $b i32)
+    (local.set $b (call $ml_buf_new))
+    (call $ml_fmt_i64 (local.get $b) (local.get $n) (i32.const -1) (i32.const 0) (i32.const -1))
+    (call $ml_buf_finish (local.get $b)))
+
+  (func $ml_u64_to_str (param $n i64) (result i32)
+    (local $b i32)
+    (local.set $b (call $ml_buf_new))
+    (call $ml_fmt_u64 (local.get $b) (local.get $n) (i32.const -1) (i32.const 0) (i32.const -1))
+    (call $ml_buf_finish (local.get $b)))
+
+  (func $ml_f64_to_str (param $x f64) (result i32)
+    (local $b i32)
+    (local.set $b (call $ml_buf_new))
+    (call $ml_fmt_f64 (local.get $b) (local.get $x) (i32.const -1) (i32.const 0) (i32.const -1) (i32.const 0))
+    (call $ml_buf_finish (local.get $b)))
+
+  (func $ml_f32_to_str (param $x f32) (result i32)
+    (local $b i32)
+    (local.set $b (call $ml_buf_new))
+    (call $ml_fmt_f32 (local.get $b) (local.get $x) (i32.const -1) (i32.const 0) (i32.const -1) (i32.const 0))
+    (call $ml_buf_finish (local.get $b)))
+
+  ;; ========== Floats ==========
+
+  ;; Floats are shown as Rust shows them: the shortest digits that read back
+  ;; the same, never in exponent form, with `.0` on whole numbers only for
+  ;; `{:?}`, and with a precision rounded half to even. Both work from the
+  ;; exact decimal expansion of the value, made with a big number in base
+  ;; 10^9. Digit arrays hold one digit, not its character, per byte.
+
+  (func $ml_big_set (param $m i64)
+    (local $i i32)
+    (loop $next
+      (i32.store (i32.add (global.get $ml_limbs) (i32.shl (local.get $i) (i32.const 2)))
+                 (i32.wrap_i64 (i64.rem_u (local.get $m) (i64.const 1000000000))))
+      (local.set $m (i64.div_u (local.get $m) (i64.const 1000000000)))
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br_if $next (i64.ne (local.get $m) (i64.const 0))))
+    (global.set $ml_limb_count (local.get $i)))
+
+  ;; Multiply the big number by `k`, which is below 2^31
+  (func $ml_big_mul (param $k i64)
+    (local $i i32) (local $p i32) (local $x i64) (local $carry i64)
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (global.get $ml_limb_count)))
+        (local.set $p (i32.add (global.get $ml_limbs) (i32.shl (local.get $i) (i32.const 2))))
+        (local.set $x (i64.add (i64.mul (i64.load32_u (local.get $p)) (local.get $k)) (local.get $carry)))
+        (i32.store (local.get $p) (i32.wrap_i64 (i64.rem_u (local.get $x) (i64.const 1000000000))))
+        (local.set $carry (i64.div_u (local.get $x) (i64.const 1000000000)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (block $done
+      (loop $next
+        (br_if $done (i64.eqz (local.get $carry)))
+        (i32.store (i32.add (global.get $ml_limbs) (i32.shl (global.get $ml_limb_count) (i32.const 2)))
+                   (i32.wrap_i64 (i64.rem_u (local.get $carry) (i64.const 1000000000))))
+        (local.set $carry (i64.div_u (local.get $carry) (i64.const 1000000000)))
+        (global.set $ml_limb_count (i32.add (global.get $ml_limb_count) (i32.const 1)))
+        (br $next))))
+
+  ;; Multiply the big number by 2^e
+  (func $ml_big_pow2 (param $e i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.lt_s (local.get $e) (i32.const 29)))
+        (call $ml_big_mul (i64.const 536870912))
+        (local.set $e (i32.sub (local.get $e) (i32.const 29)))
+        (br $next)))
+    (call $ml_big_mul (i64.shl (i64.const 1) (i64.extend_i32_u (local.get $e)))))
+
+  ;; Multiply the big number by 5^e
+  (func $ml_big_pow5 (param $e i32)
+    (local $k i64)
+    (block $done
+      (loop $next
+        (br_if $done (i32.lt_s (local.get $e) (i32.const 13)))
+        (call $ml_big_mul (i64.const 1220703125))
+        (local.set $e (i32.sub (local.get $e) (i32.const 13)))
+        (br $next)))
+    (local.set $k (i64.const 1))
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (local.get $e)))
+        (local.set $k (i64.mul (local.get $k) (i64.const 5)))
+        (local.set $e (i32.sub (local.get $e) (i32.const 1)))
+        (br $next)))
+    (call $ml_big_mul (local.get $k)))
+
+  ;; Write the digits of `m`·2^`e` to `out`, returning how many there are.
+  ;; The last max(0, -e) of them are after the decimal point.
+  (func $ml_exact (param $m i64) (param $e i32) (param $out i32) (result i32)
+    (local $i i32) (local $j i32) (local $limb i32) (local $n i32) (local $zeros i32)
+    (call $ml_big_set (local.get $m))
+    (if (i32.ge_s (local.get $e) (i32.const 0))
+      (then (call $ml_big_pow2 (local.get $e)))
+      (else (call $ml_big_pow5 (i32.sub (i32.const 0) (local.get $e)))))
+    ;; Nine digits a limb, most significant first
+    (local.set $i (global.get $ml_limb_count))
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (local.get $i)))
+        (local.set $i (i32.sub (local.get $i) (i32.const 1)))
+        (local.set $limb (i32.load (i32.add (global.get $ml_limbs) (i32.shl (local.get $i) (i32.const 2)))))
+        (local.set $j (i32.const 9))
+        (block $limb_done
+          (loop $digit
+            (br_if $limb_done (i32.eqz (local.get $j)))
+            (local.set $j (i32.sub (local.get $j) (i32.const 1)))
+            (i32.store8 (i32.add (i32.add (local.get $out) (local.get $n)) (local.get $j))
+                        (i32.rem_u (local.get $limb) (i32.const 10)))
+            (local.set $limb (i32.div_u (local.get $limb) (i32.const 10)))
+            (br $digit)))
+        (local.set $n (i32.add (local.get $n) (i32.const 9)))
+        (br $next)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $zeros) (i32.sub (local.get $n) (i32.const 1))))
+        (br_if $done (i32.load8_u (i32.add (local.get $out) (local.get $zeros))))
+        (local.set $zeros (i32.add (local.get $zeros) (i32.const 1)))
+        (br $next)))
+    (memory.copy (local.get $out) (i32.add (local.get $out) (local.get $zeros)) (i32.sub (local.get $n) (local.get $zeros)))
+    (i32.sub (local.get $n) (local.get $zeros)))
+
+  ;; Compare the digit arrays `a` and `b` as numbers
+  (func $ml_digits_cmp (param $a i32) (param $na i32) (param $b i32) (param $nb i32) (result i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_u (local.get $na) (i32.const 1)))
+        (br_if $done (i32.load8_u (local.get $a)))
+        (local.set $a (i32.add (local.get $a) (i32.const 1)))
+        (local.set $na (i32.sub (local.get $na) (i32.const 1)))
+        (br $next)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_u (local.get $nb) (i32.const 1)))
+        (br_if $done (i32.load8_u (local.get $b)))
+        (local.set $b (i32.add (local.get $b) (i32.const 1)))
+        (local.set $nb (i32.sub (local.get $nb) (i32.const 1)))
+        (br $next)))
+    (if (i32.ne (local.get $na) (local.get $nb))
+      (then (return (select (i32.const -1) (i32.const 1) (i32.lt_u (local.get $na) (local.get $nb))))))
+    (call $ml_memcmp (local.get $a) (local.get $b) (local.get $na)))
+
+  ;; Round the digits at `p` to their first `keep`, half to even, given that
+  ;; there are `n`. The digit before `p` must be 0 to take a carry. Returns
+  ;; whether anything was dropped.
+  (func $ml_round (param $p i32) (param $n i32) (param $keep i32) (result i32)
+    (local $first i32) (local $i i32) (local $rest i32) (local $up i32) (local $q i32)
+    (if (i32.ge_s (local.get $keep) (local.get $n)) (then (return (i32.const 0))))
+    (local.set $first (i32.load8_u (i32.add (local.get $p) (local.get $keep))))
+    (local.set $i (i32.add (local.get $keep) (i32.const 1)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_s (local.get $i) (local.get $n)))
+        (local.set $rest (i32.or (local.get $rest) (i32.load8_u (i32.add (local.get $p) (local.get $i)))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (local.set $up
+      (i32.or (i32.gt_u (local.get $first) (i32.const 5))
+              (i32.and (i32.eq (local.get $first) (i32.const 5))
+                       (i32.or (i32.ne (local.get $rest) (i32.const 0))
+                               (i32.and (i32.load8_u (i32.sub (i32.add (local.get $p) (local.get $keep)) (i32.const 1)))
+                                        (i32.const 1))))))
+    (memory.fill (i32.add (local.get $p) (local.get $keep)) (i32.const 0) (i32.sub (local.get $n) (local.get $keep)))
+    (if (local.get $up)
+      (then
+        (local.set $q (i32.sub (i32.add (local.get $p) (local.get $keep)) (i32.const 1)))
+        (block $done
+          (loop $next
+            (br_if $done (i32.lt_u (i32.load8_u (local.get $q)) (i32.const 9)))
+            (i32.store8 (local.get $q) (i32.const 0))
+            (local.set $q (i32.sub (local.get $q) (i32.const 1)))
+            (br $next)))
+        (i32.store8 (local.get $q) (i32.add (i32.load8_u (local.get $q)) (i32.const 1)))))
+    (i32.or (local.get $first) (local.get $rest)))
+
+  ;; Append `n` digits from `p` as characters
+  (func $ml_put_digits (param $b i32) (param $p i32) (param $n i32)
+    (local $i i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_s (local.get $i) (local.get $n)))
+        (call $ml_buf_byte (local.get $b) (i32.add (i32.const 48) (i32.load8_u (i32.add (local.get $p) (local.get $i)))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next))))
+
+  (func $ml_put_zeros (param $b i32) (param $n i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_s (local.get $n) (i32.const 0)))
+        (call $ml_buf_byte (local.get $b) (i32.const 48))
+        (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+        (br $next))))
+
+  ;; `m`·2^`e` with `precision` digits after the point
+  (func $ml_put_fixed (param $b i32) (param $m i64) (param $e i32) (param $precision i32)
+    (local $n i32) (local $scale i32) (local $pad i32) (local $p i32) (local $len i32) (local $keep i32) (local $int i32)
+    (local.set $scale (select (i32.sub (i32.const 0) (local.get $e)) (i32.const 0) (i32.lt_s (local.get $e) (i32.const 0))))
+    ;; Leading zeros so that there is a digit before the point and one more for a carry
+    (local.set $n (call $ml_exact (local.get $m) (local.get $e) (global.get $ml_digits_value)))
+    (local.set $pad (i32.add (i32.const 1) (select (i32.sub (i32.add (local.get $scale) (i32.const 1)) (local.get $n))
+                                                  (i32.const 0)
+                                                  (i32.gt_s (i32.add (local.get $scale) (i32.const 1)) (local.get $n)))))
+    (local.set $p (global.get $ml_digits_rounded))
+    (memory.fill (local.get $p) (i32.const 0) (local.get $pad))
+    (memory.copy (i32.add (local.get $p) (local.get $pad)) (global.get $ml_digits_value) (local.get $n))
+    (local.set $len (i32.add (local.get $pad) (local.get $n)))
+    (if (i32.gt_s (local.get $scale) (local.get $precision))
+      (then
+        (local.set $keep (i32.sub (local.get $len) (i32.sub (local.get $scale) (local.get $precision))))
+        (drop (call $ml_round (local.get $p) (local.get $len) (local.get $keep)))
+        (local.set $len (local.get $keep))
+        (local.set $scale (local.get $precision))))
+    ;; Integer digits without leading zeros, but at least one
+    (local.set $int (i32.sub (local.get $len) (local.get $scale)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_s (local.get $int) (i32.const 1)))
+        (br_if $done (i32.load8_u (local.get $p)))
+        (local.set $p (i32.add (local.get $p) (i32.const 1)))
+        (local.set $int (i32.sub (local.get $int) (i32.const 1)))
+        (br $next)))
+    (call $ml_put_digits (local.get $b) (local.get $p) (local.get $int))
+    (if (i32.gt_s (local.get $precision) (i32.const 0))
+      (then
+        (call $ml_buf_byte (local.get $b) (i32.const 46))
+        (call $ml_put_digits (local.get $b) (i32.add (local.get $p) (local.get $int)) (local.get $scale))
+        (call $ml_put_zeros (local.get $b) (i32.sub (local.get $precision) (local.get $scale))))))
+
+  ;; The shortest digits of `m`·2^`e` that lie strictly between the midpoints
+  ;; to its neighbours, or on one when `m` is even. `lower` says the
+  ;; neighbour below is half as far, at the bottom of a binade.
+  (func $ml_put_shortest (param $b i32) (param $m i64) (param $e i32) (param $lower i32) (param $debug i32)
+    (local $n i32) (local $nl i32) (local $nh i32) (local $scale i32) (local $even i32) (local $digits i32)
+    (local $len i32) (local $p i32) (local $r i32) (local $last i32) (local $int i32) (local $i i32)
+    (if (i64.eqz (local.get $m))
+      (then
+        (call $ml_buf_byte (local.get $b) (i32.const 48))
+        (if (local.get $debug)
+          (then (call $ml_buf_byte (local.get $b) (i32.const 46)) (call $ml_buf_byte (local.get $b) (i32.const 48))))
+        (return)))
+    (local.set $e (i32.sub (local.get $e) (i32.const 2)))
+    (local.set $scale (select (i32.sub (i32.const 0) (local.get $e)) (i32.const 0) (i32.lt_s (local.get $e) (i32.const 0))))
+    (local.set $n (call $ml_exact (i64.shl (local.get $m) (i64.const 2)) (local.get $e) (global.get $ml_digits_value)))
+    (local.set $nl (call $ml_exact (i64.sub (i64.shl (local.get $m) (i64.const 2))
+                                            (select (i64.const 1) (i64.const 2) (local.get $lower)))
+                                   (local.get $e) (global.get $ml_digits_low)))
+    (local.set $nh (call $ml_exact (i64.add (i64.shl (local.get $m) (i64.const 2)) (i64.const 2))
+                                   (local.get $e) (global.get $ml_digits_high)))
+    (local.set $even (i64.eqz (i64.and (local.get $m) (i64.const 1))))
+    ;; The value rounded to more and more digits, with a 0 in front for a carry
+    (local.set $r (global.get $ml_digits_rounded))
+    (local.set $len (i32.add (local.get $n) (i32.const 1)))
+    (local.set $digits (i32.const 1))
+    (block $found
+      (loop $next
+        (i32.store8 (local.get $r) (i32.const 0))
+        (memory.copy (i32.add (local.get $r) (i32.const 1)) (global.get $ml_digits_value) (local.get $n))
+        (br_if $found (i32.ge_s (local.get $digits) (local.get $n)))
+        (drop (call $ml_round (i32.add (local.get $r) (i32.const 1)) (local.get $n) (local.get $digits)))
+        (if (i32.and
+              (i32.ge_s (call $ml_digits_cmp (local.get $r) (local.get $len) (global.get $ml_digits_low) (local.get $nl))
+                        (i32.sub (i32.const 1) (local.get $even)))
+              (i32.le_s (call $ml_digits_cmp (local.get $r) (local.get $len) (global.get $ml_digits_high) (local.get $nh))
+                        (i32.sub (local.get $even) (i32.const 1))))
+          (then (br $found)))
+        (local.set $digits (i32.add (local.get $digits) (i32.const 1)))
+        (br $next)))
+    ;; Drop the carry digit when unused, then the trailing zeros
+    (local.set $p (local.get $r))
+    (if (i32.eqz (i32.load8_u (local.get $p)))
+      (then
+        (local.set $p (i32.add (local.get $p) (i32.const 1)))
+        (local.set $len (i32.sub (local.get $len) (i32.const 1)))))
+    (local.set $last (local.get $len))
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_s (local.get $last) (i32.const 1)))
+        (br_if $done (i32.load8_u (i32.add (local.get $p) (i32.sub (local.get $last) (i32.const 1)))))
+        (local.set $last (i32.sub (local.get $last) (i32.const 1)))
+        (br $next)))
+    (local.set $int (i32.sub (local.get $len) (local.get $scale)))
+    (if (i32.le_s (local.get $int) (i32.const 0))
+      (then
+        (call $ml_buf_byte (local.get $b) (i32.const 48))
+        (call $ml_buf_byte (local.get $b) (i32.const 46))
+        (call $ml_put_zeros (local.get $b) (i32.sub (i32.const 0) (local.get $int)))
+        (call $ml_put_digits (local.get $b) (local.get $p) (local.get $last))
+        (return)))
+    (call $ml_put_digits (local.get $b) (local.get $p) (local.get $int))
+    (if (i32.gt_s (local.get $last) (local.get $int))
+      (then
+        (call $ml_buf_byte (local.get $b) (i32.const 46))
+        (call $ml_put_digits (local.get $b) (i32.add (local.get $p) (local.get $int))
+                             (i32.sub (local.get $last) (local.get $int))))
+      (else
+        (if (local.get $debug)
+          (then (call $ml_buf_byte (local.get $b) (i32.const 46)) (call $ml_buf_byte (local.get $b) (i32.const 48)))))))
+
+  ;; A finite float `m`·2^`e` when `special` is 0, or else an infinity when it
+  ;; is 1 and NaN when it is 2
+  (func $ml_put_float
+    (param $b i32) (param $negative i32) (param $special i32) (param $m i64) (param $e i32) (param $lower i32)
+    (param $width i32) (param $left i32) (param $precision i32) (param $debug i32)
+    (local $start i32)
+    (local.set $start (call $ml_buf_len (local.get $b)))
+    (block $done
+      (if (i32.eq (local.get $special) (i32.const 2))
+        (then (call $ml_buf_str (local.get $b) (global.get $ml_text_nan)) (br $done)))
+      (if (local.get $negative) (then (call $ml_buf_byte (local.get $b) (i32.const 45))))
+      (if (local.get $special)
+        (then (call $ml_buf_str (local.get $b) (global.get $ml_text_inf)) (br $done)))
+      (if (i32.ge_s (local.get $precision) (i32.const 0))
+        (then (call $ml_put_fixed (local.get $b) (local.get $m) (local.get $e) (local.get $precision)))
+        (else (call $ml_put_shortest (local.get $b) (local.get $m) (local.get $e) (local.get $lower) (local.get $debug)))))
+    (call $ml_pad (local.get $b) (local.get $start) (local.get $width) (local.get $left)))
+
+  (func $ml_fmt_f64
+    (param $b i32) (param $x f64) (param $width i32) (param $left i32) (param $precision i32) (param $debug i32)
+    (local $bits i64) (local $exp i32) (local $frac i64)
+    (local.set $bits (i64.reinterpret_f64 (local.get $x)))
+    (local.set $exp (i32.wrap_i64 (i64.and (i64.shr_u (local.get $bits) (i64.const 52)) (i64.const 0x7FF))))
+    (local.set $frac (i64.and (local.get $bits) (i64.const 0xFFFFFFFFFFFFF)))
+    (call $ml_put_float (local.get $b) (i64.lt_s (local.get $bits) (i64.const 0))
+      (select (i32.add (i32.const 1) (i64.ne (local.get $frac) (i64.const 0))) (i32.const 0)
+              (i32.eq (local.get $exp) (i32.const 0x7FF)))
+      (if (result i64) (local.get $exp)
+        (then (i64.or (local.get $frac) (i64.const 0x10000000000000)))
+        (else (local.get $frac)))
+      (if (result i32) (local.get $exp)
+        (then (i32.sub (local.get $exp) (i32.const 1075)))
+        (else (i32.const -1074)))
+      (i32.and (i64.eqz (local.get $frac)) (i32.gt_u (local.get $exp) (i32.const 1)))
+      (local.get $width) (local.get $left) (local.get $precision) (local.get $debug)))
+
+  (func $ml_fmt_f32
+    (param $b i32) (param $x f32) (param $width i32) (param $left i32) (param $precision i32) (param $debug i32)
+    (local $bits i32) (local $exp i32) (local $frac i32)
+    (local.set $bits (i32.reinterpret_f32 (local.get $x)))
+    (local.set $exp (i32.and (i32.shr_u (local.get $bits) (i32.const 23)) (i32.const 0xFF)))
+    (local.set $frac (i32.and (local.get $bits) (i32.const 0x7FFFFF)))
+    (call $ml_put_float (local.get $b) (i32.lt_s (local.get $bits) (i32.const 0))
+      (select (i32.add (i32.const 1) (i32.ne (local.get $frac) (i32.const 0))) (i32.const 0)
+              (i32.eq (local.get $exp) (i32.const 0xFF)))
+      (i64.extend_i32_u
+        (if (result i32) (local.get $exp)
+          (then (i32.or (local.get $frac) (i32.const 0x800000)))
+          (else (local.get $frac))))
+      (if (result i32) (local.get $exp)
+        (then (i32.sub (local.get $exp) (i32.const 150)))
+        (else (i32.const -149)))
+      (i32.and (i32.eqz (local.get $frac)) (i32.gt_u (local.get $exp) (i32.const 1)))
+      (local.get $width) (local.get $left) (local.get $precision) (local.get $debug)))
+
+  ;; ========== Arithmetic ==========
+
+  ;; `x % y` on floats, which keeps the sign of `x` as Rust's does
+  (func $ml_fmod_f64 (param $x f64) (param $y f64) (result f64)
+    (if (i32.and (f64.eq (f64.abs (local.get $y)) (f64.const inf))
+                 (f64.lt (f64.abs (local.get $x)) (f64.const inf)))
+      (then (return (local.get $x))))
+    (f64.copysign
+      (f64.sub (local.get $x) (f64.mul (local.get $y) (f64.trunc (f64.div (local.get $x) (local.get $y)))))
+      (local.get $x)))
+
+  (func $ml_fmod_f32 (param $x f32) (param $y f32) (result f32)
+    (f32.demote_f64 (call $ml_fmod_f64 (f64.promote_f32 (local.get $x)) (f64.promote_f32 (local.get $y)))))
+
+  ;; `base.pow(exp)`, wrapping on overflow
+  (func $ml_pow_i64 (param $base i64) (param $exp i32) (result i64)
+    (local $r i64)
+    (local.set $r (i64.const 1))
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (local.get $exp)))
+        (if (i32.and (local.get $exp) (i32.const 1))
+          (then (local.set $r (i64.mul (local.get $r) (local.get $base)))))
+        (local.set $base (i64.mul (local.get $base) (local.get $base)))
+        (local.set $exp (i32.shr_u (local.get $exp) (i32.const 1)))
+        (br $next)))
+    (local.get $r))
+
+  (func $ml_pow_i32 (param $base i32) (param $exp i32) (result i32)
+    (i32.wrap_i64 (call $ml_pow_i64 (i64.extend_i32_u (local.get $base)) (local.get $exp))))
+
+  ;; `x.powi(n)`, multiplying as the C library's `__powidf2` does
+  (func $ml_powi_f64 (param $x f64) (param $n i32) (result f64)
+    (local $r f64) (local $b i32)
+    (local.set $r (f64.const 1))
+    (local.set $b (local.get $n))
+    (block $done
+      (loop $next
+        (if (i32.and (local.get $b) (i32.const 1))
+          (then (local.set $r (f64.mul (local.get $r) (local.get $x)))))
+        (local.set $b (i32.div_s (local.get $b) (i32.const 2)))
+        (br_if $done (i32.eqz (local.get $b)))
+        (local.set $x (f64.mul (local.get $x) (local.get $x)))
+        (br $next)))
+    (select (f64.div (f64.const 1) (local.get $r)) (local.get $r) (i32.lt_s (local.get $n) (i32.const 0))))
+
+  (func $ml_powi_f32 (param $x f32) (param $n i32) (result f32)
+    (local $r f32) (local $b i32)
+    (local.set $r (f32.const 1))
+    (local.set $b (local.get $n))
+    (block $done
+      (loop $next
+        (if (i32.and (local.get $b) (i32.const 1))
+          (then (local.set $r (f32.mul (local.get $r) (local.get $x)))))
+        (local.set $b (i32.div_s (local.get $b) (i32.const 2)))
+        (br_if $done (i32.eqz (local.get $b)))
+        (local.set $x (f32.mul (local.get $x) (local.get $x)))
+        (br $next)))
+    (select (f32.div (f32.const 1) (local.get $r)) (local.get $r) (i32.lt_s (local.get $n) (i32.const 0))))
+
+  ;; ========== Vectors ==========
+
+  ;; A vector is the address of its elements, its length and its capacity
+
+  ;; Where a new last element of `size` bytes goes, after making room for it
+  (func $ml_vec_push (param $v i32) (param $size i32) (result i32)
+    (local $len i32) (local $cap i32) (local $data i32)
+    (local.set $data (i32.load (local.get $v)))
+    (local.set $len (i32.load offset=4 (local.get $v)))
+    (local.set $cap (i32.load offset=8 (local.get $v)))
+    (if (i32.eq (local.get $len) (local.get $cap))
+      (then
+        (local.set $cap (select (i32.shl (local.get $cap) (i32.const 1)) (i32.const 4) (local.get $cap)))
+        (local.set $data (call $ml_realloc (local.get $data) (i32.mul (local.get $len) (local.get $size))
+                                           (i32.mul (local.get $cap) (local.get $size))))
+        (i32.store (local.get $v) (local.get $data))
+        (i32.store offset=8 (local.get $v) (local.get $cap))))
+    (i32.store offset=4 (local.get $v) (i32.add (local.get $len) (i32.const 1)))
+    (i32.add (local.get $data) (i32.mul (local.get $len) (local.get $size))))
+
+  ;; The address of element `i`, or 0 when there is none
+  (func $ml_vec_get (param $v i32) (param $i i64) (param $size i32) (result i32)
+    (if (i64.ge_u (local.get $i) (i64.load32_u offset=4 (local.get $v)))
+      (then (return (i32.const 0))))
+    (i32.add (i32.load (local.get $v)) (i32.mul (i32.wrap_i64 (local.get $i)) (local.get $size))))
+
+  ;; A panic about the index `i` and the length of `v`, between the texts given
+  (func $ml_vec_panic (param $v i32) (param $i i64) (param $before i32) (param $between i32) (param $loc i32)
+    (local $b i32)
+    (local.set $b (call $ml_buf_new))
+    (call $ml_buf_str (local.get $b) (local.get $before))
+    (call $ml_fmt_i64 (local.get $b) (local.get $i) (i32.const -1) (i32.const 0) (i32.const -1))
+    (call $ml_buf_str (local.get $b) (local.get $between))
+    (call $ml_fmt_u64 (local.get $b) (i64.load32_u offset=4 (local.get $v)) (i32.const -1) (i32.const 0) (i32.const -1))
+    (call $ml_buf_byte (local.get $b) (i32.const 41))
+    (call $ml_panic_buf (local.get $loc) (local.get $b)))
+
+  ;; Where a new element `i` of `size` bytes goes, after moving the rest up
+  (func $ml_vec_insert (param $v i32) (param $i i64) (param $size i32) (param $loc i32) (result i32)
+    (local $at i32) (local $len i32)
+    (local.set $len (i32.load offset=4 (local.get $v)))
+    (if (i64.gt_u (local.get $i) (i64.extend_i32_u (local.get $len)))
+      (then (call $ml_vec_panic (local.get $v) (local.get $i) (global.get $ml_text_insert_index)
+                                (global.get $ml_text_insert_len) (local.get $loc))))
+    (drop (call $ml_vec_push (local.get $v) (local.get $size)))
+    (local.set $at (i32.add (i32.load (local.get $v)) (i32.mul (i32.wrap_i64 (local.get $i)) (local.get $size))))
+    (memory.copy (i32.add (local.get $at) (local.get $size)) (local.get $at)
+                 (i32.mul (i32.sub (local.get $len) (i32.wrap_i64 (local.get $i))) (local.get $size)))
+    (local.get $at))
+
+  ;; Move element `i` of `size` bytes to `out`, and the rest down
+  (func $ml_vec_remove (param $v i32) (param $i i64) (param $size i32) (param $loc i32) (param $out i32)
+    (local $at i32) (local $len i32)
+    (local.set $len (i32.load offset=4 (local.get $v)))
+    (if (i64.ge_u (local.get $i) (i64.extend_i32_u (local.get $len)))
+      (then (call $ml_vec_panic (local.get $v) (local.get $i) (global.get $ml_text_remove_index)
+                                (global.get $ml_text_remove_len) (local.get $loc))))
+    (local.set $at (i32.add (i32.load (local.get $v)) (i32.mul (i32.wrap_i64 (local.get $i)) (local.get $size))))
+    (memory.copy (local.get $out) (local.get $at) (local.get $size))
+    (memory.copy (local.get $at) (i32.add (local.get $at) (local.get $size))
+                 (i32.mul (i32.sub (i32.sub (local.get $len) (i32.wrap_i64 (local.get $i))) (i32.const 1))
+                          (local.get $size)))
+    (i32.store offset=4 (local.get $v) (i32.sub (local.get $len) (i32.const 1))))
+
+  ;; A copy of `v` at `out`, with elements of its own
+  (func $ml_vec_clone (param $v i32) (param $size i32) (param $out i32)
+    (local $n i32) (local $data i32)
+    (local.set $n (i32.mul (i32.load offset=4 (local.get $v)) (local.get $size)))
+    (local.set $data (call $ml_alloc (local.get $n)))
+    (memory.copy (local.get $data) (i32.load (local.get $v)) (local.get $n))
+    (i32.store (local.get $out) (local.get $data))
+    (i32.store offset=4 (local.get $out) (i32.load offset=4 (local.get $v)))
+    (i32.store offset=8 (local.get $out) (i32.load offset=4 (local.get $v))))
+
+  ;; A vector at `out` holding the `n` elements of `size` bytes at `p`
+  (func $ml_vec_from (param $p i32) (param $n i32) (param $size i32) (param $out i32)
+    (local $data i32)
+    (local.set $data (call $ml_alloc (i32.mul (local.get $n) (local.get $size))))
+    (memory.copy (local.get $data) (local.get $p) (i32.mul (local.get $n) (local.get $size)))
+    (i32.store (local.get $out) (local.get $data))
+    (i32.store offset=4 (local.get $out) (local.get $n))
+    (i32.store offset=8 (local.get $out) (local.get $n)))
+
+  (func $ml_swap (param $a i32) (param $b i32) (param $size i32)
+    (local $i i32) (local $x i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $size)))
+        (local.set $x (i32.load8_u (i32.add (local.get $a) (local.get $i))))
+        (i32.store8 (i32.add (local.get $a) (local.get $i)) (i32.load8_u (i32.add (local.get $b) (local.get $i))))
+        (i32.store8 (i32.add (local.get $b) (local.get $i)) (local.get $x))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next))))
+
+  (func $ml_vec_reverse (param $v i32) (param $size i32)
+    (local $i i32) (local $j i32)
+    (local.set $j (i32.load offset=4 (local.get $v)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_s (i32.add (local.get $i) (i32.const 1)) (local.get $j)))
+        (local.set $j (i32.sub (local.get $j) (i32.const 1)))
+        (call $ml_swap (i32.add (i32.load (local.get $v)) (i32.mul (local.get $i) (local.get $size)))
+                       (i32.add (i32.load (local.get $v)) (i32.mul (local.get $j) (local.get $size)))
+                       (local.get $size))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next))))
+
+  ;; A stable merge sort, ordering elements with the function at `compare` in the table
+  (func $ml_vec_sort (param $v i32) (param $size i32) (param $compare i32)
+    (local $n i32) (local $src i32) (local $dst i32) (local $tmp i32) (local $width i32) (local $lo i32)
+    (local $mid i32) (local $hi i32) (local $i i32) (local $j i32) (local $k i32) (local $bytes i32)
+    (local.set $n (i32.load offset=4 (local.get $v)))
+    (if (i32.lt_u (local.get $n) (i32.const 2)) (then (return)))
+    (local.set $bytes (i32.mul (local.get $n) (local.get $size)))
+    (local.set $src (i32.load (local.get $v)))
+    (local.set $dst (call $ml_alloc (local.get $bytes)))
+    (local.set $width (i32.const 1))
+    (block $sorted
+      (loop $pass
+        (br_if $sorted (i32.ge_u (local.get $width) (local.get $n)))
+        (local.set $lo (i32.const 0))
+        (block $merged
+          (loop $run
+            (br_if $merged (i32.ge_u (local.get $lo) (local.get $n)))
+            (local.set $mid (i32.add (local.get $lo) (local.get $width)))
+            (if (i32.gt_u (local.get $mid) (local.get $n)) (then (local.set $mid (local.get $n))))
+            (local.set $hi (i32.add (local.get $mid) (local.get $width)))
+            (if (i32.gt_u (local.get $hi) (local.get $n)) (then (local.set $hi (local.get $n))))
+            (local.set $i (local.get $lo))
+            (local.set $j (local.get $mid))
+            (local.set $k (local.get $lo))
+            (block $done
+              (loop $next
+                (br_if $done (i32.ge_u (local.get $k) (local.get $hi)))
+                (if (i32.and (i32.lt_u (local.get $i) (local.get $mid))
+                             (i32.or (i32.ge_u (local.get $j) (local.get $hi))
+                                     (i32.le_s (call_indirect $ml_fns (type $ml_compare)
+                                                 (i32.add (local.get $src) (i32.mul (local.get $i) (local.get $size)))
+                                                 (i32.add (local.get $src) (i32.mul (local.get $j) (local.get $size)))
+                                                 (local.get $compare))
+                                               (i32.const 0))))
+                  (then
+                    (memory.copy (i32.add (local.get $dst) (i32.mul (local.get $k) (local.get $size)))
+                                 (i32.add (local.get $src) (i32.mul (local.get $i) (local.get $size))) (local.get $size))
+                    (local.set $i (i32.add (local.get $i) (i32.const 1))))
+                  (else
+                    (memory.copy (i32.add (local.get $dst) (i32.mul (local.get $k) (local.get $size)))
+                                 (i32.add (local.get $src) (i32.mul (local.get $j) (local.get $size))) (local.get $size))
+                    (local.set $j (i32.add (local.get $j) (i32.const 1)))))
+                (local.set $k (i32.add (local.get $k) (i32.const 1)))
+                (br $next)))
+            (local.set $lo (local.get $hi))
+            (br $run)))
+        (local.set $tmp (local.get $src))
+        (local.set $src (local.get $dst))
+        (local.set $dst (local.get $tmp))
+        (local.set $width (i32.shl (local.get $width) (i32.const 1)))
+        (br $pass)))
+    (if (i32.ne (local.get $src) (i32.load (local.get $v)))
+      (then (memory.copy (i32.load (local.get $v)) (local.get $src) (local.get $bytes)))))
+
+  ;; ========== Output and Exit ==========
+
+  (func $ml_write_bytes (param $fd i32) (param $p i32) (param $n i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_s (local.get $n) (i32.const 0)))
+        (i32.store (i32.const 16) (local.get $p))
+        (i32.store (i32.const 20) (local.get $n))
+        (br_if $done (call $wasi_fd_write (local.get $fd) (i32.const 16) (i32.const 1) (i32.const 0)))
+        (local.set $p (i32.add (local.get $p) (i32.load (i32.const 0))))
+        (local.set $n (i32.sub (local.get $n) (i32.load (i32.const 0))))
+        (br $next))))
+
+  (func $ml_write (param $fd i32) (param $s i32)
+    (call $ml_write_bytes (local.get $fd) (i32.add (local.get $s) (i32.const 4)) (i32.load (local.get $s))))
+
+  ;; Write out what `b` holds and give its memory back
+  (func $ml_print (param $fd i32) (param $b i32)
+    (local $data i32)
+    (local.set $data (i32.load (local.get $b)))
+    (if (i32.eqz (local.get $data)) (then (return)))
+    (call $ml_write_bytes (local.get $fd) (i32.add (local.get $data) (i32.const 4)) (i32.load offset=4 (local.get $b)))
+    (call $ml_free (local.get $data) (i32.add (i32.load offset=8 (local.get $b)) (i32.const 4)))
+    (call $ml_free (local.get $b) (i32.const 12)))
+
+  (func $ml_exit (param $code i32)
+    (call $wasi_proc_exit (local.get $code))
+    (unreachable))
+
+  ;; Stop as a Rust program panicking would, with status 101. `loc` is the
+  ;; text `file:line:column`.
+  (func $ml_panic (param $loc i32) (param $message i32)
+    (local $b i32)
+    (local.set $b (call $ml_buf_new))
+    (call $ml_buf_str (local.get $b) (global.get $ml_text_panicked))
+    (call $ml_buf_str (local.get $b) (local.get $loc))
+    (call $ml_buf_str (local.get $b) (global.get $ml_text_colon_newline))
+    (call $ml_buf_str (local.get $b) (local.get $message))
+    (call $ml_buf_byte (local.get $b) (i32.const 10))
+    (call $ml_print (i32.const 2) (local.get $b))
+    (call $ml_exit (i32.const 101)))
+
+  (func $ml_panic_buf (param $loc i32) (param $b i32)
+    (call $ml_panic (local.get $loc) (call $ml_buf_finish (local.get $b))))
+
+  ;; `i` if it is below `len`, or else the panic indexing out of bounds causes
+  (func $ml_index (param $i i64) (param $len i32) (param $loc i32) (result i32)
+    (local $b i32)
+    (if (i64.lt_u (local.get $i) (i64.extend_i32_u (local.get $len)))
+      (then (return (i32.wrap_i64 (local.get $i)))))
+    (local.set $b (call $ml_buf_new))
+    (call $ml_buf_str (local.get $b) (global.get $ml_text_index_len))
+    (call $ml_fmt_u64 (local.get $b) (i64.extend_i32_u (local.get $len)) (i32.const -1) (i32.const 0) (i32.const -1))
+    (call $ml_buf_str (local.get $b) (global.get $ml_text_index_index))
+    (call $ml_fmt_i64 (local.get $b) (local.get $i) (i32.const -1) (i32.const 0) (i32.const -1))
+    (call $ml_panic_buf (local.get $loc) (local.get $b))
+    (unreachable))
+
+  ;; ========== Arithmetic ==========
+  ;; Integer operations that overflow panic with `message`, as the
+  ;; interpreter's do. Integers narrower than 64 bits are computed exactly in
+  ;; 64 and must then fit their type.
+
+  ;; `r` if it is within `lo..=hi`
+  (func $ml_fit (param $r i64) (param $lo i64) (param $hi i64) (param $loc i32) (param $message i32) (result i64)
+    (if (i32.or (i64.lt_s (local.get $r) (local.get $lo)) (i64.gt_s (local.get $r) (local.get $hi)))
+      (then (call $ml_panic (local.get $loc) (local.get $message))))
+    (local.get $r))
+
+  (func $ml_add_s64 (param $a i64) (param $b i64) (param $loc i32) (param $message i32) (result i64)
+    (local $r i64)
+    (local.set $r (i64.add (local.get $a) (local.get $b)))
+    ;; The sum's sign differs from both operands'
+    (if (i64.lt_s (i64.and (i64.xor (local.get $a) (local.get $r)) (i64.xor (local.get $b) (local.get $r)))
+                  (i64.const 0))
+      (then (call $ml_panic (local.get $loc) (local.get $message))))
+    (local.get $r))
+
+  (func $ml_sub_s64 (param $a i64) (param $b i64) (param $loc i32) (param $message i32) (result i64)
+    (local $r i64)
+    (local.set $r (i64.sub (local.get $a) (local.get $b)))
+    ;; The operands' signs differ, and the difference's differs from `a`'s
+    (if (i64.lt_s (i64.and (i64.xor (local.get $a) (local.get $b)) (i64.xor (local.get $a) (local.get $r)))
+                  (i64.const 0))
+      (then (call $ml_panic (local.get $loc) (local.get $message))))
+    (local.get $r))
+
+  (func $ml_mul_s64 (param $a i64) (param $b i64) (param $loc i32) (param $message i32) (result i64)
+    (local $r i64)
+    (local.set $r (i64.mul (local.get $a) (local.get $b)))
+    ;; Dividing back would itself overflow for the least value times -1
+    (if (i64.eq (local.get $a) (i64.const -1))
+      (then
+        (if (i64.eq (local.get $b) (i64.const 0x8000000000000000))
+          (then (call $ml_panic (local.get $loc) (local.get $message))))
+        (return (local.get $r))))
+    (if (i32.and (i64.ne (local.get $a) (i64.const 0))
+                 (i64.ne (i64.div_s (local.get $r) (local.get $a)) (local.get $b)))
+      (then (call $ml_panic (local.get $loc) (local.get $message))))
+    (local.get $r))
+
+  ;; The least value divided by -1 is the only division that overflows
+  (func $ml_div_s64 (param $a i64) (param $b i64) (param $loc i32) (param $message i32) (result i64)
+    (if (i32.and (i64.eq (local.get $a) (i64.const 0x8000000000000000)) (i64.eq (local.get $b) (i64.const -1)))
+      (then (call $ml_panic (local.get $loc) (local.get $message))))
+    (i64.div_s (local.get $a) (local.get $b)))
+
+  (func $ml_rem_s64 (param $a i64) (param $b i64) (param $loc i32) (param $message i32) (result i64)
+    (if (i32.and (i64.eq (local.get $a) (i64.const 0x8000000000000000)) (i64.eq (local.get $b) (i64.const -1)))
+      (then (call $ml_panic (local.get $loc) (local.get $message))))
+    (i64.rem_s (local.get $a) (local.get $b)))
+
+  (func $ml_add_u64 (param $a i64) (param $b i64) (param $loc i32) (param $message i32) (result i64)
+    (local $r i64)
+    (local.set $r (i64.add (local.get $a) (local.get $b)))
+    (if (i64.lt_u (local.get $r) (local.get $a))
+      (then (call $ml_panic (local.get $loc) (local.get $message))))
+    (local.get $r))
+
+  (func $ml_sub_u64 (param $a i64) (param $b i64) (param $loc i32) (param $message i32) (result i64)
+    (if (i64.lt_u (local.get $a) (local.get $b))
+      (then (call $ml_panic (local.get $loc) (local.get $message))))
+    (i64.sub (local.get $a) (local.get $b)))
+
+  (func $ml_mul_u64 (param $a i64) (param $b i64) (param $loc i32) (param $message i32) (result i64)
+    (local $r i64)
+    (local.set $r (i64.mul (local.get $a) (local.get $b)))
+    (if (i32.and (i64.ne (local.get $a) (i64.const 0))
+                 (i64.ne (i64.div_u (local.get $r) (local.get $a)) (local.get $b)))
+      (then (call $ml_panic (local.get $loc) (local.get $message))))
+    (local.get $r))
+
+  ;; ========== Environment ==========
+
+  ;; The program's arguments, as a vector of strings at `out`
+  (func $ml_args (param $out i32)
+    (local $argc i32) (local $argv i32) (local $data i32) (local $i i32) (local $p i32) (local $n i32)
+    (drop (call $wasi_args_sizes_get (i32.const 0) (i32.const 4)))
+    (local.set $argc (i32.load (i32.const 0)))
+    (local.set $argv (call $ml_alloc (i32.shl (local.get $argc) (i32.const 2))))
+    (drop (call $wasi_args_get (local.get $argv) (call $ml_alloc (i32.load (i32.const 4)))))
+    (local.set $data (call $ml_alloc (i32.shl (local.get $argc) (i32.const 2))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $argc)))
+        (local.set $p (i32.load (i32.add (local.get $argv) (i32.shl (local.get $i) (i32.const 2)))))
+        (local.set $n (i32.const 0))
+        (block $end
+          (loop $byte
+            (br_if $end (i32.eqz (i32.load8_u (i32.add (local.get $p) (local.get $n)))))
+            (local.set $n (i32.add (local.get $n) (i32.const 1)))
+            (br $byte)))
+        (i32.store (i32.add (local.get $data) (i32.shl (local.get $i) (i32.const 2)))
+                   (call $ml_str_new (local.get $p) (local.get $n)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.store (local.get $out) (local.get $data))
+    (i32.store offset=4 (local.get $out) (local.get $argc))
+    (i32.store offset=8 (local.get $out) (local.get $argc)))
+
+  ;; The text and number Rust shows for an error number of WASI. The number is
+  ;; the host's, which Linux and macOS agree on, so that a program reports the
+  ;; same error under every backend
+  (func $ml_os_error (param $errno i32) (result i32)
+    (local $b i32) (local $text i32) (local $host i32)
+    (local.set $text (global.get $ml_text_unknown_error))
+    (local.set $host (local.get $errno))
+    (if (i32.eq (local.get $errno) (i32.const 2))
+      (then (local.set $text (global.get $ml_text_e2)) (local.set $host (i32.const 13))))
+    (if (i32.eq (local.get $errno) (i32.const 8))
+      (then (local.set $text (global.get $ml_text_e8)) (local.set $host (i32.const 9))))
+    (if (i32.eq (local.get $errno) (i32.const 20))
+      (then (local.set $text (global.get $ml_text_e20)) (local.set $host (i32.const 17))))
+    (if (i32.eq (local.get $errno) (i32.const 28))
+      (then (local.set $text (global.get $ml_text_e28)) (local.set $host (i32.const 22))))
+    (if (i32.eq (local.get $errno) (i32.const 31))
+      (then (local.set $text (global.get $ml_text_e31)) (local.set $host (i32.const 21))))
+    (if (i32.eq (local.get $errno) (i32.const 44))
+      (then (local.set $text (global.get $ml_text_e44)) (local.set $host (i32.const 2))))
+    (if (i32.eq (local.get $errno) (i32.const 54))
+      (then (local.set $text (global.get $ml_text_e54)) (local.set $host (i32.const 20))))
+    (if (i32.eq (local.get $errno) (i32.const 63))
+      (then (local.set $text (global.get $ml_text_e63)) (local.set $host (i32.const 1))))
+    (if (i32.eq (local.get $errno) (i32.const 76)) (then (local.set $text (global.get $ml_text_e76))))
+    (local.set $b (call $ml_buf_new))
+    (call $ml_buf_str (local.get $b) (local.get $text))
+    (call $ml_buf_str (local.get $b) (global.get $ml_text_os_error))
+    (call $ml_put_u64 (local.get $b) (i64.extend_i32_u (local.get $host)))
+    (call $ml_buf_byte (local.get $b) (i32.const 41))
+    (call $ml_buf_finish (local.get $b)))
+
+  ;; Open `path` through the preopened directory that holds it, returning the
+  ;; new descriptor, or the error number negated. Absolute paths go through
+  ;; the longest directory they start with; relative ones through `.`.
+  (func $ml_open (param $path i32) (param $oflags i32) (param $rights i64) (result i32)
+    (local $fd i32) (local $dir i32) (local $best i32) (local $name i32) (local $len i32) (local $p i32) (local $n i32)
+    (local $rest i32) (local $rest_len i32) (local $absolute i32) (local $errno i32)
+    (local.set $p (i32.add (local.get $path) (i32.const 4)))
+    (local.set $n (i32.load (local.get $path)))
+    (local.set $absolute (i32.and (i32.gt_u (local.get $n) (i32.const 0))
+                                  (i32.eq (i32.load8_u (local.get $p)) (i32.const 47))))
+    (local.set $dir (i32.const -1))
+    (local.set $best (i32.const -1))
+    (local.set $fd (i32.const 3))
+    (block $done
+      (loop $next
+        (br_if $done (call $wasi_fd_prestat_get (local.get $fd) (i32.const 32)))
+        (if (i32.eqz (i32.load8_u (i32.const 32)))
+          (then
+            (local.set $len (i32.load (i32.const 36)))
+            (local.set $name (call $ml_alloc (local.get $len)))
+            (drop (call $wasi_fd_prestat_dir_name (local.get $fd) (local.get $name) (local.get $len)))
+            ;; Names may end in NUL or `/`
+            (block $trimmed
+              (loop $trim
+                (br_if $trimmed (i32.eqz (local.get $len)))
+                (br_if $trimmed
+                  (i32.eqz (i32.or (i32.eqz (i32.load8_u (i32.sub (i32.add (local.get $name) (local.get $len)) (i32.const 1))))
+                                   (i32.and (i32.gt_u (local.get $len) (i32.const 1))
+                                            (i32.eq (i32.load8_u (i32.sub (i32.add (local.get $name) (local.get $len))
+                                                                          (i32.const 1)))
+                                                    (i32.const 47))))))
+                (local.set $len (i32.sub (local.get $len) (i32.const 1)))
+                (br $trim)))
+            (if (local.get $absolute)
+              (then
+                (if (i32.and (i32.and (i32.gt_s (local.get $len) (local.get $best))
+                                      (i32.le_u (local.get $len) (local.get $n)))
+                             (i32.and (i32.eq (i32.load8_u (local.get $name)) (i32.const 47))
+                                      (i32.eqz (call $ml_memcmp (local.get $name) (local.get $p) (local.get $len)))))
+                  (then
+                    (if (i32.or (i32.or (i32.eq (local.get $len) (local.get $n)) (i32.eq (local.get $len) (i32.const 1)))
+                                (i32.eq (i32.load8_u (i32.add (local.get $p) (local.get $len))) (i32.const 47)))
+                      (then
+                        (local.set $dir (local.get $fd))
+                        (local.set $best (local.get $len)))))))
+              (else
+                (if (i32.and (i32.lt_s (local.get $dir) (i32.const 0))
+                             (i32.or (i32.eqz (local.get $len))
+                                     (i32.and (i32.eq (local.get $len) (i32.const 1))
+                                              (i32.eq (i32.load8_u (local.get $name)) (i32.const 46)))))
+                  (then (local.set $dir (local.get $fd))))))
+            (call $ml_free (local.get $name) (i32.load (i32.const 36)))))
+        (local.set $fd (i32.add (local.get $fd) (i32.const 1)))
+        (br $next)))
+    (if (i32.lt_s (local.get $dir) (i32.const 0)) (then (return (i32.const -76))))
+    (local.set $rest (local.get $p))
+    (local.set $rest_len (local.get $n))
+    (if (local.get $absolute)
+      (then
+        (local.set $rest (i32.add (local.get $p) (local.get $best)))
+        (local.set $rest_len (i32.sub (local.get $n) (local.get $best)))
+        (block $done
+          (loop $next
+            (br_if $done (i32.eqz (local.get $rest_len)))
+            (br_if $done (i32.ne (i32.load8_u (local.get $rest)) (i32.const 47)))
+            (local.set $rest (i32.add (local.get $rest) (i32.const 1)))
+            (local.set $rest_len (i32.sub (local.get $rest_len) (i32.const 1)))
+            (br $next)))
+        (if (i32.eqz (local.get $rest_len))
+          (then
+            (i32.store8 (i32.const 24) (i32.const 46))
+            (local.set $rest (i32.const 24))
+            (local.set $rest_len (i32.const 1))))))
+    (local.set $errno (call $wasi_path_open (local.get $dir) (i32.const 1) (local.get $rest) (local.get $rest_len)
+                                            (local.get $oflags) (local.get $rights) (i64.const 0) (i32.const 0)
+                                            (i32.const 0)))
+    (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+    (i32.load (i32.const 0)))
+
+  ;; `fs::read_to_string`: the contents and 1, or the error's text and 0
+  (func $ml_fs_read (param $path i32) (result i32 i32)
+    (local $fd i32) (local $b i32) (local $errno i32)
+    (local.set $fd (call $ml_open (local.get $path) (i32.const 0) (i64.const 2)))
+    (if (i32.lt_s (local.get $fd) (i32.const 0))
+      (then (return (call $ml_os_error (i32.sub (i32.const 0) (local.get $fd))) (i32.const 0))))
+    (local.set $b (call $ml_buf_new))
+    (block $done
+      (loop $next
+        (call $ml_buf_reserve (local.get $b) (i32.const 4096))
+        (i32.store (i32.const 16) (call $ml_buf_end (local.get $b)))
+        (i32.store (i32.const 20) (i32.const 4096))
+        (local.set $errno (call $wasi_fd_read (local.get $fd) (i32.const 16) (i32.const 1) (i32.const 0)))
+        (if (local.get $errno)
+          (then
+            (drop (call $wasi_fd_close (local.get $fd)))
+            (return (call $ml_os_error (local.get $errno)) (i32.const 0))))
+        (br_if $done (i32.eqz (i32.load (i32.const 0))))
+        (i32.store offset=4 (local.get $b) (i32.add (i32.load offset=4 (local.get $b)) (i32.load (i32.const 0))))
+        (br $next)))
+    (drop (call $wasi_fd_close (local.get $fd)))
+    (call $ml_buf_finish (local.get $b))
+    (i32.const 1))
+
+  ;; `fs::write`: 0 and 1, or the error's text and 0
+  (func $ml_fs_write (param $path i32) (param $contents i32) (result i32 i32)
+    (local $fd i32) (local $p i32) (local $n i32) (local $errno i32)
+    ;; Created and truncated, for writing
+    (local.set $fd (call $ml_open (local.get $path) (i32.const 9) (i64.const 64)))
+    (if (i32.lt_s (local.get $fd) (i32.const 0))
+      (then (return (call $ml_os_error (i32.sub (i32.const 0) (local.get $fd))) (i32.const 0))))
+    (local.set $p (i32.add (local.get $contents) (i32.const 4)))
+    (local.set $n (i32.load (local.get $contents)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_s (local.get $n) (i32.const 0)))
+        (i32.store (i32.const 16) (local.get $p))
+        (i32.store (i32.const 20) (local.get $n))
+        (local.set $errno (call $wasi_fd_write (local.get $fd) (i32.const 16) (i32.const 1) (i32.const 0)))
+        (if (local.get $errno)
+          (then
+            (drop (call $wasi_fd_close (local.get $fd)))
+            (return (call $ml_os_error (local.get $errno)) (i32.const 0))))
+        (local.set $p (i32.add (local.get $p) (i32.load (i32.const 0))))
+        (local.set $n (i32.sub (local.get $n) (i32.load (i32.const 0))))
+        (br $next)))
+    (drop (call $wasi_fd_close (local.get $fd)))
+    (i32.const 0)
+    (i32.const 1))
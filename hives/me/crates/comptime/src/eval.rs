@@ -0,0 +1,1016 @@
+// Compile-time evaluator
+// A tree-walking interpreter for constant initializers and `comptime`
+// functions. It has no I/O, and every evaluation runs within a budget of
+// steps, bytes allocated and nested calls.
+
+use crate::value::Value;
+use my_lang_ast::*;
+use my_lang_resolve::{DefId, DefKind, Res, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Budget for evaluating one constant or expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Expressions evaluated, counting every loop iteration
+    pub steps: u64,
+    /// Bytes allocated for strings, arrays, tuples and structs
+    pub memory: usize,
+    /// Nested `comptime` function calls
+    pub depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self { steps: 10_000_000, memory: 64 << 20, depth: 512 }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum EvalErrorKind {
+    #[error("{0}")]
+    Panic(String),
+
+    #[error("exceeded the limit of {0} evaluation steps")]
+    StepLimit(u64),
+
+    #[error("exceeded the limit of {0} bytes of memory")]
+    MemoryLimit(usize),
+
+    #[error("exceeded the limit of {0} nested calls")]
+    DepthLimit(usize),
+
+    /// Something compile-time code cannot do, such as I/O or calling a non-`comptime` function
+    #[error("{0}")]
+    NotComptime(String),
+
+    #[error("cycle detected when evaluating constant `{0}`")]
+    Cycle(String),
+}
+
+/// An evaluation failure at `span`, with the calls that led to it, innermost first
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalError {
+    pub kind: EvalErrorKind,
+    pub span: Span,
+    /// Function called and the call site
+    pub backtrace: Vec<(String, Span)>,
+}
+
+impl EvalError {
+    pub fn new(kind: EvalErrorKind, span: Span) -> Self {
+        Self { kind, span, backtrace: Vec::new() }
+    }
+}
+
+/// Control leaving an expression other than by producing a value
+enum Unwind {
+    Break(Value),
+    Continue,
+    Return(Value),
+    Error(EvalError),
+}
+
+impl From<EvalError> for Unwind {
+    fn from(err: EvalError) -> Self {
+        Unwind::Error(err)
+    }
+}
+
+type Eval<T> = Result<T, Unwind>;
+
+fn panic<T>(message: impl Into<String>, span: Span) -> Eval<T> {
+    Err(EvalError::new(EvalErrorKind::Panic(message.into()), span).into())
+}
+
+fn not_comptime<T>(message: impl Into<String>, span: Span) -> Eval<T> {
+    Err(EvalError::new(EvalErrorKind::NotComptime(message.into()), span).into())
+}
+
+/// Locals of one function call or constant initializer
+struct Frame {
+    locals: HashMap<DefId, Value>,
+}
+
+/// One step from a local towards the place an assignment writes
+enum Proj {
+    Field(String),
+    Index(usize, Span),
+}
+
+pub struct Evaluator<'a> {
+    res: &'a Resolution,
+    types: &'a TypeckResults,
+    fns: HashMap<DefId, &'a Function>,
+    consts: HashMap<DefId, &'a Const>,
+    values: HashMap<DefId, Value>,
+    /// Constants whose evaluation failed, so each failure is met only once
+    errors: HashMap<DefId, EvalError>,
+    /// Constants being evaluated, innermost last
+    evaluating: Vec<DefId>,
+    limits: Limits,
+    steps: u64,
+    memory: usize,
+    frames: Vec<Frame>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(programs: &[&'a Program], res: &'a Resolution, types: &'a TypeckResults, limits: Limits) -> Self {
+        let mut eval = Self {
+            res,
+            types,
+            fns: HashMap::new(),
+            consts: HashMap::new(),
+            values: HashMap::new(),
+            errors: HashMap::new(),
+            evaluating: Vec::new(),
+            limits,
+            steps: 0,
+            memory: 0,
+            frames: Vec::new(),
+        };
+        for program in programs {
+            eval.collect(&program.items);
+        }
+        eval
+    }
+
+    fn collect(&mut self, items: &'a [Item]) {
+        for item in items {
+            match &item.kind {
+                ItemKind::Function(func) => self.add_fn(func),
+                ItemKind::Const(c) => self.add_const(c),
+                ItemKind::Impl(imp) => {
+                    for item in &imp.items {
+                        match item {
+                            ImplItem::Function(func) => self.add_fn(func),
+                            ImplItem::Const(c) => self.add_const(c),
+                            ImplItem::Type(_) => {}
+                        }
+                    }
+                }
+                ItemKind::Trait(t) => {
+                    for item in &t.items {
+                        match item {
+                            TraitItem::Function(func) => self.add_fn(func),
+                            TraitItem::Const(c) => self.add_const(c),
+                            _ => {}
+                        }
+                    }
+                }
+                ItemKind::Module(module) => self.collect(&module.items),
+                _ => {}
+            }
+        }
+    }
+
+    fn add_fn(&mut self, func: &'a Function) {
+        if let Some(def) = self.res.def_of_node(func.id) {
+            self.fns.insert(def, func);
+        }
+    }
+
+    fn add_const(&mut self, c: &'a Const) {
+        if let Some(def) = self.res.def_of_node(c.id) {
+            self.consts.insert(def, c);
+        }
+    }
+
+    /// Every constant of the crate, in source order
+    pub fn constants(&self) -> Vec<(DefId, &'a Const)> {
+        let mut consts: Vec<_> = self.consts.iter().map(|(&def, &c)| (def, c)).collect();
+        consts.sort_by_key(|(_, c)| (c.span.file, c.span.start));
+        consts
+    }
+
+    /// Values of the constants evaluated so far
+    pub fn into_values(self) -> HashMap<DefId, Value> {
+        self.values
+    }
+
+    /// The value of constant `def`, evaluating it on first use
+    pub fn eval_const(&mut self, def: DefId) -> Result<Value, EvalError> {
+        if let Some(value) = self.values.get(&def) {
+            return Ok(value.clone());
+        }
+        if let Some(err) = self.errors.get(&def) {
+            return Err(err.clone());
+        }
+        let Some(&c) = self.consts.get(&def) else {
+            let name = &self.res.def(def).name;
+            let kind = EvalErrorKind::NotComptime(format!("`{}` has no value at compile time", name));
+            return Err(EvalError::new(kind, self.res.def(def).span));
+        };
+        if self.evaluating.contains(&def) {
+            return Err(EvalError::new(EvalErrorKind::Cycle(c.name.clone()), c.value.span));
+        }
+
+        self.evaluating.push(def);
+        let result = self.eval_root(&c.value);
+        self.evaluating.pop();
+        match &result {
+            Ok(value) => self.values.insert(def, value.clone()),
+            Err(err) => self.errors.insert(def, err.clone()).map(|_| Value::unit()),
+        };
+        result
+    }
+
+    /// Evaluate `expr` outside any function, such as an array size. Constants
+    /// it uses are evaluated within the same budget.
+    pub fn eval_root(&mut self, expr: &Expression) -> Result<Value, EvalError> {
+        let outermost = self.frames.is_empty();
+        if outermost {
+            self.steps = 0;
+            self.memory = 0;
+        }
+        self.frames.push(Frame { locals: HashMap::new() });
+        let result = self.expr(expr);
+        self.frames.pop();
+        match result {
+            Ok(value) | Err(Unwind::Return(value)) | Err(Unwind::Break(value)) => Ok(value),
+            Err(Unwind::Continue) => Ok(Value::unit()),
+            Err(Unwind::Error(err)) => Err(err),
+        }
+    }
+
+    // ========== Budget ==========
+
+    fn step(&mut self, span: Span) -> Eval<()> {
+        self.steps += 1;
+        if self.steps > self.limits.steps {
+            return Err(EvalError::new(EvalErrorKind::StepLimit(self.limits.steps), span).into());
+        }
+        Ok(())
+    }
+
+    fn alloc(&mut self, value: Value, span: Span) -> Eval<Value> {
+        self.memory = self.memory.saturating_add(value.footprint());
+        if self.memory > self.limits.memory {
+            return Err(EvalError::new(EvalErrorKind::MemoryLimit(self.limits.memory), span).into());
+        }
+        Ok(value)
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("evaluation runs inside a frame")
+    }
+
+    // ========== Expressions ==========
+
+    fn block(&mut self, block: &Block) -> Eval<Value> {
+        self.step(block.span)?;
+        for stmt in &block.stmts {
+            self.step(stmt.span)?;
+            match &stmt.kind {
+                StatementKind::Let { pattern, init, .. } => {
+                    // A declaration without a value leaves its binding unset until assigned
+                    let Some(init) = init else { continue };
+                    let value = self.expr(init)?;
+                    self.bind_irrefutable(pattern, value)?;
+                }
+                StatementKind::Expression(expr) => {
+                    self.expr(expr)?;
+                }
+                StatementKind::Item(_) | StatementKind::Error => {}
+            }
+        }
+        match &block.expr {
+            Some(expr) => self.expr(expr),
+            None => Ok(Value::unit()),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expression) -> Eval<Value> {
+        self.step(expr.span)?;
+        match &expr.kind {
+            ExpressionKind::Literal(lit) => self.alloc(literal(lit), expr.span),
+            ExpressionKind::Identifier(_) | ExpressionKind::Path(_) => self.name(expr),
+            ExpressionKind::Binary { left, op, right } => self.binary(expr, left, op, right),
+            ExpressionKind::Unary { op, expr: operand } => {
+                let value = self.expr(operand)?;
+                match (op, value) {
+                    (UnaryOp::Neg, Value::Int(n)) => self.int(expr, n.checked_neg(), "negate"),
+                    (UnaryOp::Neg, Value::Float(x)) => Ok(Value::Float(-x)),
+                    (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+                    (UnaryOp::Not, Value::Int(n)) => match self.int_range(expr) {
+                        Some((0, max)) => Ok(Value::Int(max - n)),
+                        _ => Ok(Value::Int(!n)),
+                    },
+                    // References are copies: compile-time code cannot observe the difference
+                    (UnaryOp::Deref | UnaryOp::Ref | UnaryOp::RefMut, value) => Ok(value),
+                    (op, value) => not_comptime(format!("cannot apply `{:?}` to `{}`", op, value), expr.span),
+                }
+            }
+            ExpressionKind::Call { func, args } => self.call(expr, func, args),
+            ExpressionKind::MethodCall { receiver, method, args } => self.method_call(expr, receiver, method, args),
+            ExpressionKind::If { cond, then_block, else_block } => {
+                if self.condition(cond)? {
+                    self.block(then_block)
+                } else if let Some(block) = else_block {
+                    self.block(block)
+                } else {
+                    Ok(Value::unit())
+                }
+            }
+            ExpressionKind::Match { expr: scrutinee, arms } => {
+                let value = self.expr(scrutinee)?;
+                for arm in arms {
+                    let mut binds = Vec::new();
+                    if !self.matches(&arm.pattern, &value, &mut binds)? {
+                        continue;
+                    }
+                    self.frame().locals.extend(binds);
+                    if let Some(guard) = &arm.guard {
+                        if !self.condition(guard)? {
+                            continue;
+                        }
+                    }
+                    return self.expr(&arm.body);
+                }
+                panic(format!("no match arm matched `{}`", value), expr.span)
+            }
+            ExpressionKind::Loop(body) => loop {
+                match self.block(body) {
+                    Ok(_) | Err(Unwind::Continue) => {}
+                    Err(Unwind::Break(value)) => return Ok(value),
+                    Err(other) => return Err(other),
+                }
+            },
+            ExpressionKind::While { cond, body } => {
+                while self.condition(cond)? {
+                    match self.block(body) {
+                        Ok(_) | Err(Unwind::Continue) => {}
+                        Err(Unwind::Break(_)) => break,
+                        Err(other) => return Err(other),
+                    }
+                }
+                Ok(Value::unit())
+            }
+            ExpressionKind::For { pattern, iter, body } => {
+                let elems = match self.expr(iter)? {
+                    Value::Array(elems) => elems,
+                    other => {
+                        return not_comptime(format!("cannot iterate over `{}` at compile time", other), iter.span)
+                    }
+                };
+                for elem in elems {
+                    self.bind_irrefutable(pattern, elem)?;
+                    match self.block(body) {
+                        Ok(_) | Err(Unwind::Continue) => {}
+                        Err(Unwind::Break(_)) => break,
+                        Err(other) => return Err(other),
+                    }
+                }
+                Ok(Value::unit())
+            }
+            ExpressionKind::Return(value) => {
+                let value = match value {
+                    Some(value) => self.expr(value)?,
+                    None => Value::unit(),
+                };
+                Err(Unwind::Return(value))
+            }
+            ExpressionKind::Break(value) => {
+                let value = match value {
+                    Some(value) => self.expr(value)?,
+                    None => Value::unit(),
+                };
+                Err(Unwind::Break(value))
+            }
+            ExpressionKind::Continue => Err(Unwind::Continue),
+            ExpressionKind::Block(block) => self.block(block),
+            ExpressionKind::Comptime(inner) => self.expr(inner),
+            ExpressionKind::Tuple(elems) => {
+                let values = self.exprs(elems)?;
+                self.alloc(Value::Tuple(values), expr.span)
+            }
+            ExpressionKind::Array(elems) => {
+                let values = self.exprs(elems)?;
+                self.alloc(Value::Array(values), expr.span)
+            }
+            ExpressionKind::Index { expr: base, index } => {
+                let base = self.expr(base)?;
+                let i = self.index(index)?;
+                match base {
+                    Value::Array(elems) => match elems.get(i) {
+                        Some(elem) => Ok(elem.clone()),
+                        None => panic(out_of_bounds(elems.len(), i), expr.span),
+                    },
+                    other => not_comptime(format!("cannot index into `{}` at compile time", other), expr.span),
+                }
+            }
+            ExpressionKind::Field { expr: base, field } => {
+                let base = self.expr(base)?;
+                match field_of(&base, field) {
+                    Some(value) => Ok(value.clone()),
+                    None => not_comptime(format!("`{}` has no field `{}`", base, field), expr.span),
+                }
+            }
+            ExpressionKind::Struct { fields, .. } => {
+                let Some(Res::Def(def)) = self.res.res(expr.id).cloned() else {
+                    return not_comptime("cannot build this struct at compile time", expr.span);
+                };
+                let mut values = Vec::new();
+                for (name, value) in fields {
+                    values.push((name.clone(), self.expr(value)?));
+                }
+                // Fields in declaration order, so that equal values compare equal
+                if let Some(declared) = self.res.fields.get(&def) {
+                    values.sort_by_key(|(name, _)| declared.iter().position(|f| f.name == *name));
+                }
+                let value = Value::Adt { def, name: self.adt_name(def), fields: values };
+                self.alloc(value, expr.span)
+            }
+            _ => not_comptime("this expression cannot be evaluated at compile time", expr.span),
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expression]) -> Eval<Vec<Value>> {
+        exprs.iter().map(|e| self.expr(e)).collect()
+    }
+
+    fn condition(&mut self, cond: &Expression) -> Eval<bool> {
+        match self.expr(cond)? {
+            Value::Bool(b) => Ok(b),
+            other => not_comptime(format!("expected a `bool`, found `{}`", other), cond.span),
+        }
+    }
+
+    fn index(&mut self, index: &Expression) -> Eval<usize> {
+        match self.expr(index)? {
+            Value::Int(n) => Ok(usize::try_from(n).unwrap_or(usize::MAX)),
+            other => not_comptime(format!("expected an index, found `{}`", other), index.span),
+        }
+    }
+
+    /// A local, constant or unit variant
+    fn name(&mut self, expr: &Expression) -> Eval<Value> {
+        let Some(Res::Def(def)) = self.res.res(expr.id).cloned() else {
+            return not_comptime("this path cannot be evaluated at compile time", expr.span);
+        };
+        let definition = self.res.def(def);
+        match definition.kind {
+            DefKind::Local | DefKind::Param => match self.frame().locals.get(&def) {
+                Some(value) => Ok(value.clone()),
+                // A run-time local named in a `comptime` expression, or one never assigned
+                None => not_comptime(format!("`{}` has no value at compile time", definition.name), expr.span),
+            },
+            DefKind::Const => self.eval_const(def).map_err(Unwind::Error),
+            DefKind::Variant | DefKind::Struct => Ok(Value::Adt { def, name: self.adt_name(def), fields: Vec::new() }),
+            kind => not_comptime(format!("cannot use {} `{}` as a value", kind.describe(), definition.name), expr.span),
+        }
+    }
+
+    /// `Enum::Variant` for variants of the crate's own enums, the bare name otherwise
+    fn adt_name(&self, def: DefId) -> String {
+        let definition = self.res.def(def);
+        match definition.parent {
+            Some(parent) if definition.kind == DefKind::Variant && !definition.is_prelude() => {
+                format!("{}::{}", self.res.def(parent).name, definition.name)
+            }
+            _ => definition.name.clone(),
+        }
+    }
+
+    // ========== Operators ==========
+
+    fn binary(&mut self, expr: &Expression, left: &Expression, op: &BinaryOp, right: &Expression) -> Eval<Value> {
+        match op {
+            BinaryOp::Assign => {
+                let value = self.expr(right)?;
+                self.assign(left, value)?;
+                return Ok(Value::unit());
+            }
+            BinaryOp::And => return Ok(Value::Bool(self.condition(left)? && self.condition(right)?)),
+            BinaryOp::Or => return Ok(Value::Bool(self.condition(left)? || self.condition(right)?)),
+            _ => {}
+        }
+        let lhs = self.expr(left)?;
+        let rhs = self.expr(right)?;
+        use BinaryOp::*;
+        match (op, lhs, rhs) {
+            (Eq, l, r) => Ok(Value::Bool(l == r)),
+            (Ne, l, r) => Ok(Value::Bool(l != r)),
+            (Add, Value::Str(l), r) => {
+                let joined = Value::Str(l + &r.to_text());
+                self.alloc(joined, expr.span)
+            }
+            (_, Value::Int(a), Value::Int(b)) => self.int_binary(expr, op, a, b),
+            (_, Value::Float(a), Value::Float(b)) => Ok(match op {
+                Add => Value::Float(a + b),
+                Sub => Value::Float(a - b),
+                Mul => Value::Float(a * b),
+                Div => Value::Float(a / b),
+                Mod => Value::Float(a % b),
+                Lt => Value::Bool(a < b),
+                Gt => Value::Bool(a > b),
+                Le => Value::Bool(a <= b),
+                Ge => Value::Bool(a >= b),
+                _ => return not_comptime(format!("cannot apply `{:?}` to floats", op), expr.span),
+            }),
+            (BitAnd, Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a & b)),
+            (BitOr, Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a | b)),
+            (BitXor, Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a ^ b)),
+            (Lt | Gt | Le | Ge, l, r) => {
+                let ordering = match (&l, &r) {
+                    (Value::Str(a), Value::Str(b)) => a.cmp(b),
+                    (Value::Char(a), Value::Char(b)) => a.cmp(b),
+                    (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+                    _ => return not_comptime(format!("cannot compare `{}` with `{}`", l, r), expr.span),
+                };
+                Ok(Value::Bool(match op {
+                    Lt => ordering.is_lt(),
+                    Gt => ordering.is_gt(),
+                    Le => ordering.is_le(),
+                    _ => ordering.is_ge(),
+                }))
+            }
+            (op, l, r) => not_comptime(format!("cannot apply `{:?}` to `{}` and `{}`", op, l, r), expr.span),
+        }
+    }
+
+    fn int_binary(&mut self, expr: &Expression, op: &BinaryOp, a: i128, b: i128) -> Eval<Value> {
+        use BinaryOp::*;
+        match op {
+            Add => self.int(expr, a.checked_add(b), "add"),
+            Sub => self.int(expr, a.checked_sub(b), "subtract"),
+            Mul => self.int(expr, a.checked_mul(b), "multiply"),
+            Div if b == 0 => panic("attempt to divide by zero", expr.span),
+            Div => self.int(expr, a.checked_div(b), "divide"),
+            Mod if b == 0 => panic("attempt to calculate the remainder with a divisor of zero", expr.span),
+            Mod => self.int(expr, a.checked_rem(b), "calculate the remainder"),
+            BitAnd => Ok(Value::Int(a & b)),
+            BitOr => Ok(Value::Int(a | b)),
+            BitXor => Ok(Value::Int(a ^ b)),
+            Shl | Shr => {
+                let bits = self.int_bits(expr);
+                let Some(shift) = u32::try_from(b).ok().filter(|s| *s < bits) else {
+                    let verb = if *op == Shl { "shift left" } else { "shift right" };
+                    return panic(format!("attempt to {} with overflow", verb), expr.span);
+                };
+                if *op == Shr {
+                    return Ok(Value::Int(a >> shift));
+                }
+                // Bits shifted out of the type are lost, as at run time
+                let shifted = a.wrapping_shl(shift);
+                Ok(Value::Int(match self.int_range(expr) {
+                    Some((0, max)) => shifted & max,
+                    Some(_) => (shifted << (128 - bits)) >> (128 - bits),
+                    None => shifted,
+                }))
+            }
+            Lt => Ok(Value::Bool(a < b)),
+            Gt => Ok(Value::Bool(a > b)),
+            Le => Ok(Value::Bool(a <= b)),
+            Ge => Ok(Value::Bool(a >= b)),
+            _ => not_comptime(format!("cannot apply `{:?}` to integers", op), expr.span),
+        }
+    }
+
+    /// An arithmetic result, which must fit the type of `expr`
+    fn int(&self, expr: &Expression, result: Option<i128>, verb: &str) -> Eval<Value> {
+        match (result, self.int_range(expr)) {
+            (Some(n), Some((lo, hi))) if n < lo || n > hi => {
+                panic(format!("attempt to {} with overflow", verb), expr.span)
+            }
+            (Some(n), _) => Ok(Value::Int(n)),
+            (None, _) => panic(format!("attempt to {} with overflow", verb), expr.span),
+        }
+    }
+
+    fn int_range(&self, expr: &Expression) -> Option<(i128, i128)> {
+        let Some(Ty::Prim(prim)) = self.types.type_of(expr.id) else {
+            return None;
+        };
+        Some(match prim {
+            PrimitiveType::I8 => (i8::MIN.into(), i8::MAX.into()),
+            PrimitiveType::I16 => (i16::MIN.into(), i16::MAX.into()),
+            PrimitiveType::I32 => (i32::MIN.into(), i32::MAX.into()),
+            PrimitiveType::I64 | PrimitiveType::Isize => (i64::MIN.into(), i64::MAX.into()),
+            PrimitiveType::I128 => (i128::MIN, i128::MAX),
+            PrimitiveType::U8 => (0, u8::MAX.into()),
+            PrimitiveType::U16 => (0, u16::MAX.into()),
+            PrimitiveType::U32 => (0, u32::MAX.into()),
+            PrimitiveType::U64 | PrimitiveType::Usize => (0, u64::MAX.into()),
+            PrimitiveType::U128 => (0, i128::MAX),
+            _ => return None,
+        })
+    }
+
+    fn int_bits(&self, expr: &Expression) -> u32 {
+        match self.int_range(expr) {
+            Some((lo, hi)) => 128 - if lo < 0 { hi.leading_zeros() - 1 } else { hi.leading_zeros() },
+            None => 128,
+        }
+    }
+
+    // ========== Places ==========
+
+    fn assign(&mut self, place: &Expression, value: Value) -> Eval<()> {
+        let (root, projs) = self.place(place)?;
+        let frame = self.frame();
+        if projs.is_empty() {
+            frame.locals.insert(root, value);
+            return Ok(());
+        }
+        let Some(mut slot) = frame.locals.get_mut(&root) else {
+            return panic("assignment into an uninitialized value", place.span);
+        };
+        for proj in projs {
+            slot = match (proj, slot) {
+                (Proj::Field(name), slot) => match field_of_mut(slot, &name) {
+                    Some(field) => field,
+                    None => return not_comptime(format!("no field `{}` to assign", name), place.span),
+                },
+                (Proj::Index(i, span), Value::Array(elems)) => {
+                    let len = elems.len();
+                    match elems.get_mut(i) {
+                        Some(elem) => elem,
+                        None => return panic(out_of_bounds(len, i), span),
+                    }
+                }
+                (Proj::Index(_, span), _) => return not_comptime("cannot index into this value", span),
+            };
+        }
+        *slot = value;
+        Ok(())
+    }
+
+    /// The local `place` is part of, and the fields and indices leading to it
+    fn place(&mut self, place: &Expression) -> Eval<(DefId, Vec<Proj>)> {
+        match &place.kind {
+            ExpressionKind::Identifier(_) => match self.res.res(place.id) {
+                Some(Res::Def(def)) if matches!(self.res.def(*def).kind, DefKind::Local | DefKind::Param) => {
+                    Ok((*def, Vec::new()))
+                }
+                _ => not_comptime("cannot assign to this at compile time", place.span),
+            },
+            ExpressionKind::Field { expr, field } => {
+                let (root, mut projs) = self.place(expr)?;
+                projs.push(Proj::Field(field.clone()));
+                Ok((root, projs))
+            }
+            ExpressionKind::Index { expr, index } => {
+                let i = self.index(index)?;
+                let (root, mut projs) = self.place(expr)?;
+                projs.push(Proj::Index(i, place.span));
+                Ok((root, projs))
+            }
+            ExpressionKind::Unary { op: UnaryOp::Deref | UnaryOp::RefMut, expr } => self.place(expr),
+            _ => not_comptime("cannot assign to this at compile time", place.span),
+        }
+    }
+
+    fn is_place(expr: &Expression) -> bool {
+        match &expr.kind {
+            ExpressionKind::Identifier(_) => true,
+            ExpressionKind::Field { expr, .. } | ExpressionKind::Index { expr, .. } => Self::is_place(expr),
+            ExpressionKind::Unary { op: UnaryOp::Deref | UnaryOp::RefMut, expr } => Self::is_place(expr),
+            _ => false,
+        }
+    }
+
+    // ========== Patterns ==========
+
+    fn bind_irrefutable(&mut self, pattern: &Pattern, value: Value) -> Eval<()> {
+        let mut binds = Vec::new();
+        if !self.matches(pattern, &value, &mut binds)? {
+            return panic(format!("pattern does not match `{}`", value), pattern.span);
+        }
+        self.frame().locals.extend(binds);
+        Ok(())
+    }
+
+    fn matches(&mut self, pattern: &Pattern, value: &Value, binds: &mut Vec<(DefId, Value)>) -> Eval<bool> {
+        match &pattern.kind {
+            PatternKind::Wildcard => Ok(true),
+            PatternKind::Identifier(_) | PatternKind::Path(_) => match self.res.res(pattern.id).cloned() {
+                Some(Res::Def(def)) if self.res.def(def).kind == DefKind::Const => {
+                    Ok(self.eval_const(def).map_err(Unwind::Error)? == *value)
+                }
+                Some(Res::Def(def)) if matches!(self.res.def(def).kind, DefKind::Variant | DefKind::Struct) => {
+                    Ok(matches!(value, Value::Adt { def: d, .. } if *d == def))
+                }
+                _ => {
+                    if let Some(def) = self.res.def_of_node(pattern.id) {
+                        binds.push((def, value.clone()));
+                    }
+                    Ok(true)
+                }
+            },
+            PatternKind::Literal(lit) => Ok(literal(lit) == *value),
+            PatternKind::Tuple(pats) => match value {
+                Value::Tuple(elems) if elems.len() == pats.len() => self.match_all(pats, elems, binds),
+                _ => Ok(false),
+            },
+            PatternKind::TupleStruct { elems: pats, .. } => {
+                let Some(Res::Def(def)) = self.res.res(pattern.id).cloned() else {
+                    return Ok(false);
+                };
+                match value {
+                    Value::Adt { def: d, fields, .. } if *d == def && fields.len() == pats.len() => {
+                        let elems: Vec<Value> = fields.iter().map(|(_, v)| v.clone()).collect();
+                        self.match_all(pats, &elems, binds)
+                    }
+                    _ => Ok(false),
+                }
+            }
+            PatternKind::Struct { fields: pats, .. } => {
+                let Some(Res::Def(def)) = self.res.res(pattern.id).cloned() else {
+                    return Ok(false);
+                };
+                if !matches!(value, Value::Adt { def: d, .. } if *d == def) {
+                    return Ok(false);
+                }
+                for (name, pat) in pats {
+                    let Some(field) = field_of(value, name) else {
+                        return Ok(false);
+                    };
+                    if !self.matches(pat, &field.clone(), binds)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    fn match_all(&mut self, pats: &[Pattern], values: &[Value], binds: &mut Vec<(DefId, Value)>) -> Eval<bool> {
+        for (pat, value) in pats.iter().zip(values) {
+            if !self.matches(pat, value, binds)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // ========== Calls ==========
+
+    fn call(&mut self, expr: &Expression, callee: &Expression, args: &[Expression]) -> Eval<Value> {
+        let def = match self.res.res(callee.id) {
+            Some(Res::Def(def)) => *def,
+            // `Self::f` and `T::f`, which type checking resolved
+            _ => match self.types.method_calls.get(&callee.id).or_else(|| self.types.method_calls.get(&expr.id)) {
+                Some(def) => *def,
+                None => return not_comptime("this call cannot be evaluated at compile time", expr.span),
+            },
+        };
+        let definition = self.res.def(def);
+        match definition.kind {
+            DefKind::Function if definition.is_prelude() => {
+                let name = definition.name.clone();
+                self.builtin(expr, &name, args)
+            }
+            DefKind::Variant | DefKind::Struct => {
+                let values = self.exprs(args)?;
+                let fields = values.into_iter().enumerate().map(|(i, v)| (i.to_string(), v)).collect();
+                let value = Value::Adt { def, name: self.adt_name(def), fields };
+                self.alloc(value, expr.span)
+            }
+            _ => {
+                let values = self.exprs(args)?;
+                let (result, params) = self.call_fn(def, values, expr.span)?;
+                self.write_back(def, args.iter(), params)?;
+                Ok(result)
+            }
+        }
+    }
+
+    fn method_call(
+        &mut self,
+        expr: &Expression,
+        receiver: &Expression,
+        method: &str,
+        args: &[Expression],
+    ) -> Eval<Value> {
+        let recv = self.expr(receiver)?;
+        let target = self.types.method_calls.get(&expr.id).copied().filter(|def| self.fns.contains_key(def));
+        let mut values = self.exprs(args)?;
+        let Some(def) = target else {
+            return self.builtin_method(expr, recv, method, values);
+        };
+        values.insert(0, recv);
+        let (result, params) = self.call_fn(def, values, expr.span)?;
+        self.write_back(def, std::iter::once(receiver).chain(args), params)?;
+        Ok(result)
+    }
+
+    /// Run function `def`, returning its result and the final values of its parameters
+    fn call_fn(&mut self, def: DefId, args: Vec<Value>, span: Span) -> Eval<(Value, Vec<Value>)> {
+        let name = self.res.def(def).name.clone();
+        let Some(&func) = self.fns.get(&def) else {
+            return not_comptime(format!("cannot call `{}` at compile time: its body is not available", name), span);
+        };
+        if !func.is_comptime {
+            return not_comptime(format!("cannot call non-`comptime` function `{}` at compile time", name), span);
+        }
+        if self.frames.len() > self.limits.depth {
+            return Err(EvalError::new(EvalErrorKind::DepthLimit(self.limits.depth), span).into());
+        }
+
+        let mut locals = HashMap::new();
+        for (param, value) in func.params.iter().zip(args) {
+            if let Some(def) = self.res.def_of_node(param.id) {
+                locals.insert(def, value);
+            }
+        }
+        self.frames.push(Frame { locals });
+        let result = match self.run(func) {
+            Err(Unwind::Error(mut err)) => {
+                err.backtrace.push((name, span));
+                Err(Unwind::Error(err))
+            }
+            result => result,
+        };
+        let frame = self.frames.pop().expect("the call pushed a frame");
+        let params = func
+            .params
+            .iter()
+            .map(|p| self.res.def_of_node(p.id).and_then(|d| frame.locals.get(&d).cloned()).unwrap_or_else(Value::unit))
+            .collect();
+        Ok((result?, params))
+    }
+
+    /// Run the body of `func` in the frame just pushed for it. Array sizes in
+    /// its signature may name its parameters, so lengths are checked per call.
+    fn run(&mut self, func: &Function) -> Eval<Value> {
+        for param in &func.params {
+            if !matches!(param.ty.kind, TypeKind::Array { size: Some(_), .. }) {
+                continue;
+            }
+            let value = self.res.def_of_node(param.id).and_then(|def| self.frame().locals.get(&def).cloned());
+            if let Some(value) = value {
+                self.check_len(&param.ty, &value)?;
+            }
+        }
+        let value = match self.block(&func.body) {
+            Ok(value) | Err(Unwind::Return(value)) => value,
+            Err(Unwind::Break(_) | Unwind::Continue) => Value::unit(),
+            Err(err) => return Err(err),
+        };
+        if let Some(ret) = &func.return_type {
+            self.check_len(ret, &value)?;
+        }
+        Ok(value)
+    }
+
+    /// Fail unless `value` has the length array type `ty` gives it
+    fn check_len(&mut self, ty: &Type, value: &Value) -> Eval<()> {
+        let (TypeKind::Array { size: Some(size), .. }, Value::Array(elems)) = (&ty.kind, value) else {
+            return Ok(());
+        };
+        match self.expr(size)? {
+            Value::Int(len) if len != elems.len() as i128 => {
+                panic(format!("expected an array of length {}, found one of length {}", len, elems.len()), ty.span)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Copy what a call left in its `&mut` parameters back to the places the arguments named
+    fn write_back<'e>(
+        &mut self,
+        def: DefId,
+        args: impl Iterator<Item = &'e Expression>,
+        params: Vec<Value>,
+    ) -> Eval<()> {
+        let func = self.fns[&def];
+        for ((arg, param), value) in args.zip(&func.params).zip(params) {
+            if matches!(param.ty.kind, TypeKind::Reference { is_mut: true, .. }) && Self::is_place(arg) {
+                self.assign(arg, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prelude functions without side effects outside the evaluation
+    fn builtin(&mut self, expr: &Expression, name: &str, args: &[Expression]) -> Eval<Value> {
+        let values = self.exprs(args)?;
+        let message = |values: &[Value], default: &str| match values.split_first() {
+            Some((Value::Str(template), rest)) => format_values(template, rest),
+            _ => default.to_string(),
+        };
+        match name {
+            "panic" => panic(message(&values, "explicit panic"), expr.span),
+            "unreachable" => panic(message(&values, "internal error: entered unreachable code"), expr.span),
+            "todo" => panic(message(&values, "not yet implemented"), expr.span),
+            "assert" => match values.split_first() {
+                Some((Value::Bool(true), _)) => Ok(Value::unit()),
+                Some((_, rest)) => panic(message(rest, "assertion failed"), expr.span),
+                None => panic("assertion failed", expr.span),
+            },
+            "assert_eq" | "assert_ne" => {
+                let [left, right, rest @ ..] = values.as_slice() else {
+                    return panic("assertion failed", expr.span);
+                };
+                let equal = name == "assert_eq";
+                if (left == right) == equal {
+                    return Ok(Value::unit());
+                }
+                let op = if equal { "==" } else { "!=" };
+                let detail = format!("\n  left: {}\n right: {}", left, right);
+                panic(message(rest, &format!("assertion `left {} right` failed", op)) + &detail, expr.span)
+            }
+            "format" => {
+                let text = message(&values, "");
+                self.alloc(Value::Str(text), expr.span)
+            }
+            "dbg" => Ok(values.into_iter().next().unwrap_or_else(Value::unit)),
+            "drop" => Ok(Value::unit()),
+            // Lowered contracts report through this; at compile time a violation is an error
+            "contract_violation" => match values.as_slice() {
+                [Value::Str(kind), Value::Str(function), Value::Str(clause), ..] => {
+                    panic(format!("{} `{}` of `{}` violated", kind, clause, function), expr.span)
+                }
+                _ => panic("contract violated", expr.span),
+            },
+            "print" | "println" | "eprint" | "eprintln" => {
+                not_comptime(format!("`{}` performs I/O, which compile-time code cannot do", name), expr.span)
+            }
+            _ => not_comptime(format!("cannot call `{}` at compile time", name), expr.span),
+        }
+    }
+
+    fn builtin_method(&mut self, expr: &Expression, recv: Value, method: &str, args: Vec<Value>) -> Eval<Value> {
+        let value = match (method, &recv, args.as_slice()) {
+            ("len", Value::Array(elems), []) => Value::Int(elems.len() as i128),
+            ("len", Value::Str(s), []) => Value::Int(s.len() as i128),
+            ("is_empty", Value::Array(elems), []) => Value::Bool(elems.is_empty()),
+            ("is_empty", Value::Str(s), []) => Value::Bool(s.is_empty()),
+            ("contains", Value::Array(elems), [x]) => Value::Bool(elems.contains(x)),
+            ("contains", Value::Str(s), [Value::Str(x)]) => Value::Bool(s.contains(x.as_str())),
+            ("clone", _, []) => recv.clone(),
+            ("to_string", _, []) => return self.alloc(Value::Str(recv.to_text()), expr.span),
+            ("abs", Value::Int(n), []) => return self.int(expr, n.checked_abs(), "take the absolute value"),
+            ("abs", Value::Float(x), []) => Value::Float(x.abs()),
+            ("min", Value::Int(a), [Value::Int(b)]) => Value::Int(*a.min(b)),
+            ("max", Value::Int(a), [Value::Int(b)]) => Value::Int(*a.max(b)),
+            ("pow", Value::Int(a), [Value::Int(b)]) => {
+                let result = u32::try_from(*b).ok().and_then(|b| a.checked_pow(b));
+                return self.int(expr, result, "raise to a power");
+            }
+            _ => return not_comptime(format!("method `{}` is not available at compile time", method), expr.span),
+        };
+        Ok(value)
+    }
+}
+
+fn literal(lit: &Literal) -> Value {
+    match lit {
+        Literal::Int(n) => Value::Int(*n as i128),
+        Literal::Float(x) => Value::Float(*x),
+        Literal::String(s) => Value::Str(s.clone()),
+        Literal::Char(c) => Value::Char(*c),
+        Literal::Bool(b) => Value::Bool(*b),
+        Literal::Unit => Value::unit(),
+    }
+}
+
+fn field_of<'v>(value: &'v Value, field: &str) -> Option<&'v Value> {
+    match value {
+        Value::Adt { fields, .. } => fields.iter().find(|(name, _)| name == field).map(|(_, v)| v),
+        Value::Tuple(elems) => elems.get(field.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+fn field_of_mut<'v>(value: &'v mut Value, field: &str) -> Option<&'v mut Value> {
+    match value {
+        Value::Adt { fields, .. } => fields.iter_mut().find(|(name, _)| name == field).map(|(_, v)| v),
+        Value::Tuple(elems) => elems.get_mut(field.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+fn out_of_bounds(len: usize, index: usize) -> String {
+    format!("index out of bounds: the length is {} but the index is {}", len, index)
+}
+
+/// Substitute `{}` and `{:?}` in `template` with `args`, as `format` does
+pub fn format_values(template: &str, args: &[Value]) -> String {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut rest = template;
+    while let Some(open) = rest.find(['{', '}']) {
+        out.push_str(&rest[..open]);
+        rest = &rest[open..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            out.push_str(&rest[..1]);
+            rest = &rest[2..];
+        } else if let (true, Some(close)) = (rest.starts_with('{'), rest.find('}')) {
+            let debug = rest[1..close].ends_with('?');
+            match args.next() {
+                Some(arg) if debug => out.push_str(&arg.to_string()),
+                Some(arg) => out.push_str(&arg.to_text()),
+                None => out.push_str(&rest[..=close]),
+            }
+            rest = &rest[close + 1..];
+        } else {
+            out.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
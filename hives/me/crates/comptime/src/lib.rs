@@ -0,0 +1,399 @@
+// Compile-time evaluation
+// Runs constant initializers and the `comptime` functions they call during
+// compilation, in a sandbox limited in steps, memory and call depth
+
+mod eval;
+mod sites;
+mod value;
+
+pub use eval::{format_values, EvalError, EvalErrorKind, Evaluator, Limits};
+pub use sites::{sites, Site, SiteKind};
+pub use value::Value;
+
+use my_lang_ast::{NodeId, Program};
+use my_lang_diagnostics::{codes, Diagnostic, Span};
+use my_lang_resolve::{DefId, Resolution};
+use my_lang_typechecker::TypeckResults;
+use std::collections::{HashMap, HashSet};
+
+/// Stack for the evaluation thread; each nested call takes a few frames of the host's
+const STACK_SIZE: usize = 256 << 20;
+
+/// Output of compile-time evaluation, shared by later phases
+#[derive(Debug, Clone, Default)]
+pub struct ComptimeResults {
+    /// Value of every constant that evaluated
+    pub consts: HashMap<DefId, Value>,
+    /// Value of every `comptime` expression in run-time code, keyed by the `comptime` node
+    pub exprs: HashMap<NodeId, Value>,
+    /// Length of every array whose size is not a literal, keyed by the size expression
+    pub array_sizes: HashMap<NodeId, usize>,
+}
+
+/// Evaluate every constant, array size and `comptime` expression of a
+/// type-checked crate, returning the values and an error for each failure
+pub fn eval_crate(
+    programs: &[&Program],
+    res: &Resolution,
+    types: &TypeckResults,
+    limits: Limits,
+) -> (ComptimeResults, Vec<Diagnostic>) {
+    std::thread::scope(|scope| {
+        let worker = std::thread::Builder::new()
+            .name("comptime".into())
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(scope, || {
+                let mut eval = Evaluator::new(programs, res, types, limits);
+                let mut results = ComptimeResults::default();
+                let mut errors = Vec::new();
+                // A failing constant fails those using it too; report the cause once
+                let mut reported = HashSet::new();
+                let mut report = |what: String, at: Span, err: EvalError| {
+                    if reported.insert((err.span, err.kind.to_string())) {
+                        errors.push(diagnostic(&what, at, &err, &limits));
+                    }
+                };
+
+                for (def, c) in eval.constants() {
+                    if let Err(err) = eval.eval_const(def) {
+                        report(format!("constant `{}`", c.name), c.value.span, err);
+                    }
+                }
+                for site in sites(programs) {
+                    match (site.kind, eval.eval_root(site.expr)) {
+                        (SiteKind::ArraySize, Ok(Value::Int(n))) => match usize::try_from(n) {
+                            Ok(len) => {
+                                results.array_sizes.insert(site.expr.id, len);
+                            }
+                            Err(_) => {
+                                let kind = EvalErrorKind::Panic(format!("array size {} does not fit `usize`", n));
+                                report("array size".into(), site.expr.span, EvalError::new(kind, site.expr.span));
+                            }
+                        },
+                        // Type checking makes every size a `usize`
+                        (SiteKind::ArraySize, Ok(_)) => {}
+                        (SiteKind::Comptime, Ok(value)) => {
+                            results.exprs.insert(site.expr.id, value);
+                        }
+                        (SiteKind::ArraySize, Err(err)) => report("array size".into(), site.expr.span, err),
+                        (SiteKind::Comptime, Err(err)) => report("`comptime` expression".into(), site.expr.span, err),
+                    }
+                }
+                results.consts = eval.into_values();
+                (results, errors)
+            })
+            .expect("failed to spawn the compile-time evaluation thread");
+        match worker.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    })
+}
+
+/// Runs of calls labelled in a backtrace before the middle ones are elided
+const SHOWN_FRAMES: usize = 8;
+
+/// The error for evaluating `what`, such as "constant `N`", whose expression is at `root`
+pub fn diagnostic(what: &str, root: Span, err: &EvalError, limits: &Limits) -> Diagnostic {
+    let code = match err.kind {
+        EvalErrorKind::Panic(_) => codes::COMPTIME_PANIC,
+        EvalErrorKind::StepLimit(_) | EvalErrorKind::MemoryLimit(_) | EvalErrorKind::DepthLimit(_) => {
+            codes::COMPTIME_LIMIT
+        }
+        EvalErrorKind::NotComptime(_) => codes::NOT_COMPTIME,
+        EvalErrorKind::Cycle(_) => codes::CONST_CYCLE,
+    };
+    let mut diag = Diagnostic::error(format!("evaluation of {} failed", what))
+        .with_code(code)
+        .with_primary(err.span, err.kind.to_string());
+    // Recursion repeats the same call many times over: show each run of
+    // identical frames once, and only the innermost and outermost runs
+    let mut frames: Vec<(&str, Span, usize)> = Vec::new();
+    for (function, call) in &err.backtrace {
+        match frames.last_mut() {
+            Some((last, span, count)) if last == function && span == call => *count += 1,
+            _ => frames.push((function, *call, 1)),
+        }
+    }
+    let hidden = frames.len().saturating_sub(SHOWN_FRAMES);
+    for (i, (function, call, count)) in frames.iter().enumerate() {
+        if (SHOWN_FRAMES / 2..SHOWN_FRAMES / 2 + hidden).contains(&i) {
+            continue;
+        }
+        let message = match count {
+            1 => format!("inside `{}` called here", function),
+            n => format!("inside `{}` called here {} times", function, n),
+        };
+        diag = diag.with_secondary(*call, message);
+    }
+    if hidden > 0 {
+        diag = diag.with_note(format!("... and {} more frames", hidden));
+    }
+    if root != err.span && !err.backtrace.iter().any(|(_, call)| *call == root) {
+        diag = diag.with_secondary(root, format!("while evaluating {}", what));
+    }
+    match err.kind {
+        EvalErrorKind::StepLimit(_) => diag.with_note("a loop or recursion here may not terminate"),
+        EvalErrorKind::MemoryLimit(_) => diag.with_note(format!(
+            "compile-time code may allocate at most {} MiB for each evaluation",
+            limits.memory >> 20
+        )),
+        EvalErrorKind::DepthLimit(_) => diag.with_note("recursion here may not terminate"),
+        EvalErrorKind::NotComptime(ref message) if message.contains("non-`comptime`") => {
+            diag.with_note("only `comptime fn`s run during compilation")
+        }
+        _ => diag,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use my_lang_parser::Parser;
+    use my_lang_resolve::Resolver;
+
+    fn eval(source: &str) -> (Vec<(String, Value)>, Vec<Diagnostic>) {
+        eval_with(source, Limits::default())
+    }
+
+    fn check_source(source: &str) -> (Program, Resolution, TypeckResults) {
+        let (program, errors) = Parser::new(source).parse_program();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let mut resolver = Resolver::new();
+        resolver.add_module(&[], &program);
+        let (res, errors) = resolver.finish();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let (types, errors) = my_lang_typechecker::check_crate(&[&program], &res);
+        assert!(errors.is_empty(), "{:?}", errors);
+        (program, res, types)
+    }
+
+    fn eval_with(source: &str, limits: Limits) -> (Vec<(String, Value)>, Vec<Diagnostic>) {
+        let (program, res, types) = check_source(source);
+        let (results, errors) = eval_crate(&[&program], &res, &types, limits);
+        let mut values: Vec<_> = results.consts.into_iter().map(|(def, v)| (res.def(def).name.clone(), v)).collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        (values, errors)
+    }
+
+    fn value_of(values: &[(String, Value)], name: &str) -> String {
+        values.iter().find(|(n, _)| n == name).map(|(_, v)| v.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_consts_call_comptime_functions() {
+        let (values, errors) = eval(
+            r#"
+            comptime fn factorial(n: u64) -> u64 {
+                if n == 0 { 1 } else { n * factorial(n - 1) }
+            }
+
+            struct Point { x: i32, y: i32 }
+
+            comptime fn table(n: usize) -> [usize; 4] {
+                let mut t = [0, 0, 0, 0];
+                let mut i = 0;
+                while i < n {
+                    t[i] = i * i;
+                    i = i + 1;
+                }
+                t
+            }
+
+            comptime fn swap(p: &mut Point) {
+                let x = p.x;
+                p.x = p.y;
+                p.y = x;
+            }
+
+            comptime fn origin() -> Point {
+                let mut p = Point { y: 2, x: 1 };
+                swap(&mut p);
+                p
+            }
+
+            const FACT: u64 = factorial(10);
+            const NAME: str = "n = " + FACT;
+            const P: Point = origin();
+            const TABLE: [usize; 4] = table(3);
+            const SUM: i32 = match P { Point { x, y } => x + y };
+            "#,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(value_of(&values, "FACT"), "3628800");
+        assert_eq!(value_of(&values, "NAME"), "\"n = 3628800\"");
+        assert_eq!(value_of(&values, "P"), "Point { x: 2, y: 1 }");
+        assert_eq!(value_of(&values, "SUM"), "3");
+        assert_eq!(value_of(&values, "TABLE"), "[0, 1, 4, 0]");
+    }
+
+    #[test]
+    fn test_overflow_reports_the_calls_leading_to_it() {
+        let source = "comptime fn down(n: u8) -> u8 {\n    if n == 0 { n - 1 } else { down(n - 1) }\n}\n\
+                      const LOW: u8 = down(3);\n";
+        let (_, errors) = eval(source);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        let diag = &errors[0];
+        assert_eq!(diag.code, Some(codes::COMPTIME_PANIC));
+        assert_eq!(diag.message, "evaluation of constant `LOW` failed");
+        let labels: Vec<&str> = diag.labels.iter().map(|l| l.message.as_str()).collect();
+        assert_eq!(labels[0], "attempt to subtract with overflow");
+        assert_eq!(labels[1..], ["inside `down` called here 3 times", "inside `down` called here"]);
+    }
+
+    #[test]
+    fn test_deep_backtraces_are_summarized() {
+        let limits = Limits { depth: 64, ..Limits::default() };
+        let (_, errors) = eval_with("comptime fn rec(n: i32) -> i32 { rec(n + 1) }\nconst X: i32 = rec(0);\n", limits);
+        let labels: Vec<&str> = errors[0].labels.iter().map(|l| l.message.as_str()).collect();
+        assert_eq!(labels[1..], ["inside `rec` called here 63 times", "inside `rec` called here"]);
+
+        let source = "comptime fn ping(n: i32) -> i32 { pong(n) }\ncomptime fn pong(n: i32) -> i32 { ping(n) }\n\
+                      const X: i32 = ping(0);\n";
+        let (_, errors) = eval_with(source, limits);
+        assert_eq!(errors[0].labels.len(), 1 + SHOWN_FRAMES, "{:?}", errors[0].labels);
+        assert_eq!(errors[0].notes, ["... and 56 more frames", "recursion here may not terminate"]);
+    }
+
+    #[test]
+    fn test_limits_stop_runaway_evaluation() {
+        let limits = Limits { steps: 10_000, ..Limits::default() };
+        let (_, errors) = eval_with("comptime fn spin() -> i32 { loop {} }\nconst X: i32 = spin();\n", limits);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].code, Some(codes::COMPTIME_LIMIT));
+
+        let limits = Limits { depth: 64, ..Limits::default() };
+        let (_, errors) = eval_with("comptime fn f(n: i32) -> i32 { f(n + 1) }\nconst X: i32 = f(0);\n", limits);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].labels[0].message, "exceeded the limit of 64 nested calls");
+
+        let limits = Limits { memory: 1 << 16, ..Limits::default() };
+        let source = "comptime fn grow() -> str {\n    let mut s = \"x\";\n    loop { s = s + s; }\n}\n\
+                      const S: str = grow();\n";
+        let (_, errors) = eval_with(source, limits);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].labels[0].message, "exceeded the limit of 65536 bytes of memory");
+    }
+
+    #[test]
+    fn test_counting_loop_hits_the_step_limit() {
+        let limits = Limits { steps: 100_000, memory: 1 << 16, ..Limits::default() };
+        let source = "comptime fn count() -> i32 {\n    let mut i = 0;\n    loop { i = i + 1; }\n}\n\
+                      const N: i32 = count();\n";
+        let (_, errors) = eval_with(source, limits);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].labels[0].message, "exceeded the limit of 100000 evaluation steps");
+    }
+
+    #[test]
+    fn test_runtime_only_code_is_rejected() {
+        let (_, errors) = eval(
+            r#"
+            fn double(n: i32) -> i32 { n * 2 }
+            comptime fn noisy() -> i32 { println("hi"); 1 }
+            const A: i32 = double(2);
+            const B: i32 = noisy();
+            const C: i32 = C + 1;
+            const D: i32 = panic("no {} here", 42);
+            "#,
+        );
+        let summary: Vec<(String, String)> = errors
+            .iter()
+            .map(|d| (d.code.map(|c| c.to_string()).unwrap_or_default(), d.labels[0].message.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("E0402".to_string(), "cannot call non-`comptime` function `double` at compile time".to_string()),
+                ("E0402".to_string(), "`println` performs I/O, which compile-time code cannot do".to_string()),
+                ("E0403".to_string(), "cycle detected when evaluating constant `C`".to_string()),
+                ("E0400".to_string(), "no 42 here".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_array_sizes_and_comptime_expressions() {
+        let (program, res, types) = check_source(
+            r#"
+            const N: usize = 4;
+            comptime fn cube(n: i64) -> i64 { n * n * n }
+            struct Buf { data: [u8; N * 2] }
+            fn f(x: i64) -> i64 {
+                let t: [i64; N] = [1, 2, 3, 4];
+                x + comptime cube(3)
+            }
+            fn g(x: i64) -> i64 { comptime { x } }
+            "#,
+        );
+        let (results, errors) = eval_crate(&[&program], &res, &types, Limits::default());
+        let found: Vec<(SiteKind, Option<String>)> = sites(&[&program])
+            .iter()
+            .map(|site| {
+                let value = match site.kind {
+                    SiteKind::ArraySize => results.array_sizes.get(&site.expr.id).map(|n| n.to_string()),
+                    SiteKind::Comptime => results.exprs.get(&site.expr.id).map(|v| v.to_string()),
+                };
+                (site.kind, value)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (SiteKind::ArraySize, Some("8".to_string())),
+                (SiteKind::ArraySize, Some("4".to_string())),
+                (SiteKind::Comptime, Some("27".to_string())),
+                (SiteKind::Comptime, None),
+            ]
+        );
+        // A run-time parameter has no value to give
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].message, "evaluation of `comptime` expression failed");
+        assert_eq!(errors[0].labels[0].message, "`x` has no value at compile time");
+    }
+
+    #[test]
+    fn test_array_lengths_from_parameters() {
+        let source = r#"
+            comptime fn sum(count: usize, values: [i64; count]) -> i64 {
+                let mut total = 0;
+                let mut i = 0;
+                while i < count {
+                    total = total + values[i];
+                    i = i + 1;
+                }
+                total
+            }
+            comptime fn pair(value: i64, count: usize) -> [i64; count] { [value, value] }
+            const TOTAL: i64 = sum(3, [1, 2, 3]);
+            const TWO: [i64; 2] = pair(7, 2);
+            const SHORT: i64 = sum(4, [1, 2, 3]);
+            const LONG: [i64; 3] = pair(7, 3);
+            "#;
+        let (values, errors) = eval(source);
+        assert_eq!(value_of(&values, "TOTAL"), "6");
+        assert_eq!(value_of(&values, "TWO"), "[7, 7]");
+        let messages: Vec<&str> = errors.iter().map(|d| d.labels[0].message.as_str()).collect();
+        assert_eq!(
+            messages,
+            ["expected an array of length 4, found one of length 3", "expected an array of length 3, found one of length 2"]
+        );
+    }
+
+    #[test]
+    fn test_comptime_blocks_run_once() {
+        let source = r#"
+            const SIZE: usize = 10;
+            comptime {
+                if SIZE < 100 {
+                    panic("SIZE must be at least {}", 100);
+                }
+            }
+            comptime { assert(SIZE > 0); }
+            "#;
+        let (_, errors) = eval(source);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].code, Some(codes::COMPTIME_PANIC));
+        assert_eq!(errors[0].labels[0].message, "SIZE must be at least 100");
+    }
+}
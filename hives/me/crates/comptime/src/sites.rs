@@ -0,0 +1,231 @@
+// Evaluation sites
+// Expressions besides constant initializers that compilation evaluates:
+// array sizes that are not literals, and `comptime` expressions in run-time code
+
+use my_lang_ast::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiteKind {
+    ArraySize,
+    /// A `comptime` expression, recorded as the `comptime` node itself
+    Comptime,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Site<'a> {
+    pub kind: SiteKind,
+    pub expr: &'a Expression,
+}
+
+/// Every site in `programs`, in source order
+pub fn sites<'a>(programs: &[&'a Program]) -> Vec<Site<'a>> {
+    let mut collector = Collector { sites: Vec::new(), in_comptime_fn: false };
+    for program in programs {
+        collector.items(&program.items);
+    }
+    collector.sites
+}
+
+struct Collector<'a> {
+    sites: Vec<Site<'a>>,
+    /// Array sizes in a `comptime fn` may name its parameters, so their
+    /// lengths are only known once a call runs it
+    in_comptime_fn: bool,
+}
+
+impl<'a> Collector<'a> {
+    fn items(&mut self, items: &'a [Item]) {
+        for item in items {
+            self.item(item);
+        }
+    }
+
+    fn item(&mut self, item: &'a Item) {
+        match &item.kind {
+            ItemKind::Function(func) => self.function(func),
+            ItemKind::Struct(s) => s.fields.iter().for_each(|f| self.ty(&f.ty)),
+            ItemKind::Enum(e) => {
+                for variant in &e.variants {
+                    match &variant.data {
+                        VariantData::Unit => {}
+                        VariantData::Tuple(tys) => tys.iter().for_each(|ty| self.ty(ty)),
+                        VariantData::Struct(fields) => fields.iter().for_each(|f| self.ty(&f.ty)),
+                    }
+                }
+            }
+            ItemKind::Type(alias) => self.ty(&alias.ty),
+            ItemKind::Const(c) => self.constant(c),
+            ItemKind::Comptime(expr) => self.expr(expr, true),
+            ItemKind::Impl(imp) => {
+                self.ty(&imp.self_ty);
+                for item in &imp.items {
+                    match item {
+                        ImplItem::Function(func) => self.function(func),
+                        ImplItem::Const(c) => self.constant(c),
+                        ImplItem::Type(alias) => self.ty(&alias.ty),
+                    }
+                }
+            }
+            ItemKind::Trait(t) => {
+                for item in &t.items {
+                    match item {
+                        TraitItem::Function(func) => self.function(func),
+                        TraitItem::Const(c) => self.constant(c),
+                        _ => {}
+                    }
+                }
+            }
+            ItemKind::Module(module) => self.items(&module.items),
+            _ => {}
+        }
+    }
+
+    fn function(&mut self, func: &'a Function) {
+        let outer = std::mem::replace(&mut self.in_comptime_fn, func.is_comptime);
+        for param in &func.params {
+            self.ty(&param.ty);
+        }
+        if let Some(ret) = &func.return_type {
+            self.ty(ret);
+        }
+        // The body of a `comptime fn` only runs inside other evaluations
+        self.block(&func.body, !func.is_comptime);
+        self.in_comptime_fn = outer;
+    }
+
+    /// A constant's initializer is evaluated whole, but may declare arrays
+    fn constant(&mut self, c: &'a Const) {
+        self.ty(&c.ty);
+        self.expr(&c.value, false);
+    }
+
+    fn ty(&mut self, ty: &'a Type) {
+        match &ty.kind {
+            TypeKind::Array { elem, size } => {
+                if let Some(size) = size.as_deref().filter(|_| !self.in_comptime_fn) {
+                    if !matches!(size.kind, ExpressionKind::Literal(_)) {
+                        self.sites.push(Site { kind: SiteKind::ArraySize, expr: size });
+                    }
+                }
+                self.ty(elem);
+            }
+            TypeKind::Generic { args: tys, .. } | TypeKind::Path { args: tys, .. } | TypeKind::Tuple(tys) => {
+                tys.iter().for_each(|ty| self.ty(ty));
+            }
+            TypeKind::Reference { ty, .. } | TypeKind::Affine(ty) | TypeKind::Fuzzy(ty) => self.ty(ty),
+            TypeKind::Function { params, ret } => {
+                params.iter().for_each(|ty| self.ty(ty));
+                self.ty(ret);
+            }
+            TypeKind::Primitive(_) | TypeKind::Named(_) | TypeKind::Inferred | TypeKind::Learned(_) => {}
+        }
+    }
+
+    /// `runtime` is false inside code that is itself evaluated at compile time
+    fn block(&mut self, block: &'a Block, runtime: bool) {
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StatementKind::Let { ty, init, .. } => {
+                    if let Some(ty) = ty {
+                        self.ty(ty);
+                    }
+                    if let Some(init) = init {
+                        self.expr(init, runtime);
+                    }
+                }
+                StatementKind::Expression(expr) => self.expr(expr, runtime),
+                StatementKind::Item(item) => self.item(item),
+                StatementKind::Error => {}
+            }
+        }
+        if let Some(expr) = &block.expr {
+            self.expr(expr, runtime);
+        }
+    }
+
+    fn expr(&mut self, expr: &'a Expression, runtime: bool) {
+        match &expr.kind {
+            ExpressionKind::Comptime(inner) => {
+                if runtime {
+                    self.sites.push(Site { kind: SiteKind::Comptime, expr });
+                }
+                self.expr(inner, false);
+            }
+            ExpressionKind::Literal(_)
+            | ExpressionKind::Identifier(_)
+            | ExpressionKind::Path(_)
+            | ExpressionKind::Continue => {}
+            ExpressionKind::Binary { left, right, .. } => {
+                self.expr(left, runtime);
+                self.expr(right, runtime);
+            }
+            ExpressionKind::Unary { expr: inner, .. } | ExpressionKind::Field { expr: inner, .. } => {
+                self.expr(inner, runtime)
+            }
+            ExpressionKind::Call { func: callee, args } | ExpressionKind::MethodCall { receiver: callee, args, .. } => {
+                self.expr(callee, runtime);
+                args.iter().for_each(|arg| self.expr(arg, runtime));
+            }
+            ExpressionKind::If { cond, then_block, else_block } => {
+                self.expr(cond, runtime);
+                self.block(then_block, runtime);
+                if let Some(block) = else_block {
+                    self.block(block, runtime);
+                }
+            }
+            ExpressionKind::Match { expr: scrutinee, arms } => {
+                self.expr(scrutinee, runtime);
+                for arm in arms {
+                    if let Some(guard) = &arm.guard {
+                        self.expr(guard, runtime);
+                    }
+                    self.expr(&arm.body, runtime);
+                }
+            }
+            ExpressionKind::Loop(body) | ExpressionKind::Block(body) => self.block(body, runtime),
+            ExpressionKind::While { cond: head, body } | ExpressionKind::For { iter: head, body, .. } => {
+                self.expr(head, runtime);
+                self.block(body, runtime);
+            }
+            ExpressionKind::Return(value) | ExpressionKind::Break(value) => {
+                if let Some(value) = value {
+                    self.expr(value, runtime);
+                }
+            }
+            ExpressionKind::Tuple(elems) | ExpressionKind::Array(elems) => {
+                elems.iter().for_each(|elem| self.expr(elem, runtime));
+            }
+            ExpressionKind::Index { expr: base, index } => {
+                self.expr(base, runtime);
+                self.expr(index, runtime);
+            }
+            ExpressionKind::Struct { fields, .. }
+            | ExpressionKind::Intent { options: fields, .. }
+            | ExpressionKind::Spawn { config: fields, .. } => {
+                fields.iter().for_each(|(_, value)| self.expr(value, runtime));
+            }
+            ExpressionKind::Synth { config, expr: inner } | ExpressionKind::Verify { config, expr: inner } => {
+                config.iter().for_each(|(_, value)| self.expr(value, runtime));
+                self.expr(inner, runtime);
+            }
+            ExpressionKind::Hybrid { symbolic: first, neural: second, .. }
+            | ExpressionKind::Send { message: first, recipient: second } => {
+                self.expr(first, runtime);
+                self.expr(second, runtime);
+            }
+            ExpressionKind::Receive { filter, timeout } => {
+                for e in filter.iter().chain(timeout) {
+                    self.expr(e, runtime);
+                }
+            }
+            ExpressionKind::Broadcast { message, .. } => self.expr(message, runtime),
+            ExpressionKind::Closure { params, ret, body, .. } => {
+                params.iter().for_each(|param| self.ty(&param.ty));
+                if let Some(ret) = ret {
+                    self.ty(ret);
+                }
+                self.expr(body, runtime);
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+// Compile-time values
+// What constants evaluate to, and what compile-time code computes with
+
+use my_lang_resolve::DefId;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// Any integer type; the static type decides the range it must stay in
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Tuple(Vec<Value>),
+    Array(Vec<Value>),
+    /// A struct or enum variant. Tuple-like ones have fields named `0`, `1`, ...
+    Adt {
+        def: DefId,
+        name: String,
+        fields: Vec<(String, Value)>,
+    },
+}
+
+impl Value {
+    pub fn unit() -> Value {
+        Value::Tuple(Vec::new())
+    }
+
+    /// Bytes charged against the memory limit when the value is built: what
+    /// it holds on the heap. Scalars live inline, so a loop that only counts
+    /// is stopped by the step limit rather than the memory limit.
+    pub(crate) fn footprint(&self) -> usize {
+        let slot = std::mem::size_of::<Value>();
+        match self {
+            Value::Str(s) => s.len(),
+            Value::Tuple(elems) | Value::Array(elems) => slot * elems.len(),
+            Value::Adt { fields, .. } => slot * fields.len(),
+            _ => 0,
+        }
+    }
+
+    /// The value as `format` and string concatenation show it: strings without quotes
+    pub fn to_text(&self) -> String {
+        match self {
+            Value::Str(s) => s.clone(),
+            Value::Char(c) => c.to_string(),
+            other => other.to_string(),
+        }
+    }
+}
+
+/// Source syntax for the value, as diagnostics quote it
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn list(f: &mut fmt::Formatter, open: &str, elems: &[Value], close: &str) -> fmt::Result {
+            f.write_str(open)?;
+            for (i, elem) in elems.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}", elem)?;
+            }
+            f.write_str(close)
+        }
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Char(c) => write!(f, "{:?}", c),
+            Value::Str(s) => write!(f, "{:?}", s),
+            Value::Tuple(elems) if elems.len() == 1 => write!(f, "({},)", elems[0]),
+            Value::Tuple(elems) => list(f, "(", elems, ")"),
+            Value::Array(elems) => list(f, "[", elems, "]"),
+            Value::Adt { name, fields, .. } if fields.is_empty() => f.write_str(name),
+            Value::Adt { name, fields, .. } if fields[0].0 == "0" => {
+                let elems: Vec<Value> = fields.iter().map(|(_, v)| v.clone()).collect();
+                list(f, &format!("{}(", name), &elems, ")")
+            }
+            Value::Adt { name, fields, .. } => {
+                write!(f, "{} {{ ", name)?;
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", field, value)?;
+                }
+                f.write_str(" }")
+            }
+        }
+    }
+}
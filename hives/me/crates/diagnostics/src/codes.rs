@@ -0,0 +1,157 @@
+// Diagnostic codes
+// Every diagnostic class has a stable code; codes are never reused once published.
+//
+//   E00xx  lexing and parsing
+//   E01xx  module loading and name resolution
+//   E02xx  type checking
+//   E03xx  ownership
+//   E04xx  compile-time evaluation
+//   E05xx  code generation
+
+use std::fmt;
+
+/// Stable identifier for a class of diagnostic, e.g. `E0001`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Code(pub &'static str);
+
+impl Code {
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+// ========== Lexing & Parsing ==========
+
+/// Characters that do not form any token
+pub const INVALID_TOKEN: Code = Code("E0001");
+/// A specific token was required but another was found
+pub const UNEXPECTED_TOKEN: Code = Code("E0002");
+/// The file ended in the middle of a construct
+pub const UNEXPECTED_EOF: Code = Code("E0003");
+/// A literal that cannot be represented, such as an out-of-range integer
+pub const INVALID_LITERAL: Code = Code("E0004");
+pub const INVALID_OPERATOR: Code = Code("E0005");
+pub const EXPECTED_TYPE: Code = Code("E0006");
+pub const EXPECTED_PATTERN: Code = Code("E0007");
+pub const EXPECTED_IDENTIFIER: Code = Code("E0008");
+pub const MISSING_TYPE: Code = Code("E0009");
+pub const EXPECTED_EXPRESSION: Code = Code("E0010");
+pub const EXPECTED_STATEMENT: Code = Code("E0011");
+pub const EXPECTED_ITEM: Code = Code("E0012");
+pub const INVALID_CONTRACT: Code = Code("E0013");
+pub const INVALID_ATTRIBUTE: Code = Code("E0014");
+/// `pub` on something that cannot have a visibility, such as an impl block
+pub const UNEXPECTED_VISIBILITY: Code = Code("E0015");
+
+// ========== Modules & Resolution ==========
+
+/// An imported module has no source file
+pub const MODULE_NOT_FOUND: Code = Code("E0100");
+/// A name with no definition in scope
+pub const UNRESOLVED_NAME: Code = Code("E0101");
+/// Two definitions of one name in the same scope and namespace
+pub const DUPLICATE_DEFINITION: Code = Code("E0102");
+/// An import binds a name already defined or imported in its module
+pub const IMPORT_CONFLICT: Code = Code("E0103");
+/// An import path that names nothing
+pub const UNRESOLVED_IMPORT: Code = Code("E0104");
+/// A bound or `impl ... for` names something other than a trait
+pub const EXPECTED_TRAIT: Code = Code("E0105");
+/// A private item named from outside its module
+pub const PRIVATE_ITEM: Code = Code("E0106");
+/// A `mod name;` with both `name.solo` and `name/mod.solo` present
+pub const AMBIGUOUS_MODULE_FILE: Code = Code("E0107");
+/// A private field named from outside its struct's module
+pub const PRIVATE_FIELD: Code = Code("E0108");
+
+// ========== Types ==========
+
+/// An expression's type differs from the one its context requires
+pub const TYPE_MISMATCH: Code = Code("E0200");
+/// A call or tuple pattern with the wrong number of arguments
+pub const ARITY_MISMATCH: Code = Code("E0201");
+/// A field access or struct literal naming a field the type does not have
+pub const UNKNOWN_FIELD: Code = Code("E0202");
+/// A struct literal that leaves fields uninitialized
+pub const MISSING_FIELDS: Code = Code("E0203");
+/// A call whose callee is not a function
+pub const NOT_CALLABLE: Code = Code("E0204");
+/// A type that inference could not determine
+pub const TYPE_ANNOTATIONS_NEEDED: Code = Code("E0205");
+/// A generic type given the wrong number of type arguments
+pub const WRONG_TYPE_ARGUMENTS: Code = Code("E0206");
+
+// ========== Traits ==========
+
+/// A type used where a bound requires a trait it does not implement
+pub const TRAIT_NOT_IMPLEMENTED: Code = Code("E0210");
+/// A trait impl that leaves required methods or associated types undefined
+pub const MISSING_TRAIT_ITEMS: Code = Code("E0211");
+/// A trait impl defining an item its trait does not declare
+pub const NOT_A_TRAIT_MEMBER: Code = Code("E0212");
+/// Two impls of one trait that apply to the same type
+pub const CONFLICTING_IMPLS: Code = Code("E0213");
+/// An impl of a foreign trait for a foreign type
+pub const ORPHAN_IMPL: Code = Code("E0214");
+/// A trait impl method whose signature differs from the trait's declaration
+pub const INCOMPATIBLE_IMPL_ITEM: Code = Code("E0215");
+/// A method call no impl or bound provides
+pub const NO_METHOD: Code = Code("E0216");
+/// A method call that more than one trait provides
+pub const AMBIGUOUS_METHOD: Code = Code("E0217");
+
+// ========== Patterns ==========
+
+/// A `match` that some value of the scrutinee's type falls through
+pub const NON_EXHAUSTIVE_MATCH: Code = Code("E0220");
+/// A `match` arm that earlier arms already cover
+pub const UNREACHABLE_PATTERN: Code = Code("E0221");
+
+// ========== Contracts ==========
+
+/// `old(expr)` outside a postcondition
+pub const OLD_OUTSIDE_POSTCONDITION: Code = Code("E0230");
+
+// ========== Ownership ==========
+
+/// A value used after it was moved out of
+pub const USE_AFTER_MOVE: Code = Code("E0300");
+/// An affine value dropped without being consumed on some path
+pub const AFFINE_NOT_CONSUMED: Code = Code("E0301");
+/// `Copy` or `Clone` implemented for a type holding an affine value
+pub const AFFINE_COPY: Code = Code("E0302");
+/// A place borrowed, written or moved while a conflicting borrow of it is live
+pub const CONFLICTING_BORROW: Code = Code("E0303");
+/// A write or `&mut` borrow through a `&` reference
+pub const MUTATION_THROUGH_SHARED: Code = Code("E0304");
+/// A borrow still in use after the value it borrows goes out of scope
+pub const BORROW_OUTLIVES_VALUE: Code = Code("E0305");
+/// A returned reference borrowed from a parameter its lifetime does not cover
+pub const LIFETIME_MISMATCH: Code = Code("E0306");
+/// An elided return lifetime that could come from more than one parameter
+pub const MISSING_LIFETIME: Code = Code("E0307");
+
+// ========== Compile-time Evaluation ==========
+
+/// Compile-time code that panicked, overflowed or indexed out of bounds
+pub const COMPTIME_PANIC: Code = Code("E0400");
+/// Compile-time code that ran out of steps, memory or call depth
+pub const COMPTIME_LIMIT: Code = Code("E0401");
+/// Compile-time code doing something only run time can, such as I/O
+pub const NOT_COMPTIME: Code = Code("E0402");
+/// A constant whose value depends on itself
+pub const CONST_CYCLE: Code = Code("E0403");
+
+// ========== Code Generation ==========
+
+/// A construct the selected target cannot express
+pub const UNSUPPORTED_BY_TARGET: Code = Code("E0500");
+
+/// The system linker could not make the executable
+pub const LINK_FAILED: Code = Code("E0501");
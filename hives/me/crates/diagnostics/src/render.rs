@@ -0,0 +1,361 @@
+// Terminal rendering
+// Formats diagnostics as annotated source snippets with carets under the labelled spans
+
+use crate::{Diagnostic, Label, Severity, SourceMap, Span, Suggestion};
+use my_lang_lexer::SourceFile;
+use std::fmt::Write;
+use std::io::IsTerminal;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const GREEN: &str = "\x1b[1;32m";
+const CYAN: &str = "\x1b[1;36m";
+const BLUE: &str = "\x1b[1;34m";
+
+const TAB_WIDTH: usize = 4;
+
+/// Renders diagnostics in the style of
+///
+/// ```text
+/// error[E0002]: expected `;`, found `let`
+///  --> main.solo:2:14
+///   |
+/// 2 |     let x = 1
+///   |              ^ expected `;`
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Renderer {
+    color: bool,
+}
+
+impl Renderer {
+    pub fn new(color: bool) -> Self {
+        Self { color }
+    }
+
+    pub fn plain() -> Self {
+        Self::new(false)
+    }
+
+    /// Colour output when stderr is a terminal and `NO_COLOR` is not set
+    pub fn auto() -> Self {
+        let color = std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+        Self::new(color)
+    }
+
+    pub fn render(&self, diag: &Diagnostic, sources: &SourceMap) -> String {
+        let mut out = String::new();
+        let sev = self.severity_style(diag.severity);
+
+        // Header
+        out.push_str(&self.paint(sev, &diag.severity.to_string()));
+        if let Some(code) = diag.code {
+            out.push_str(&self.paint(sev, &format!("[{}]", code)));
+        }
+        out.push_str(&self.paint(BOLD, &format!(": {}", diag.message)));
+        out.push('\n');
+
+        let labels: Vec<&Label> = diag.labels.iter().filter(|l| !l.span.is_dummy()).collect();
+        let width = self.gutter_width(diag, &labels);
+        let pad = " ".repeat(width);
+
+        // Snippets, one group per file with the primary file first
+        let mut files: Vec<_> = Vec::new();
+        if let Some(primary) = labels.iter().find(|l| l.primary) {
+            files.push(primary.span.file);
+        }
+        for label in &labels {
+            if !files.contains(&label.span.file) {
+                files.push(label.span.file);
+            }
+        }
+
+        for (index, file_id) in files.iter().enumerate() {
+            let in_file: Vec<&Label> = labels
+                .iter()
+                .copied()
+                .filter(|l| l.span.file == *file_id)
+                .collect();
+            let first = in_file
+                .iter()
+                .find(|l| l.primary)
+                .unwrap_or(&in_file[0])
+                .span;
+            let arrow = if index == 0 { "-->" } else { ":::" };
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                pad,
+                self.paint(BLUE, arrow),
+                sources.describe(first)
+            );
+
+            let Some(file) = sources.get(*file_id) else {
+                continue;
+            };
+            let _ = writeln!(out, "{} {}", pad, self.paint(BLUE, "|"));
+            self.render_lines(&mut out, file, &in_file, diag.severity, width);
+        }
+
+        if !files.is_empty() && (!diag.notes.is_empty() || !diag.suggestions.is_empty()) {
+            let _ = writeln!(out, "{} {}", pad, self.paint(BLUE, "|"));
+        }
+
+        for note in &diag.notes {
+            let _ = writeln!(out, "{} {} {}", pad, self.paint(BLUE, "="), note_text(self, note));
+        }
+
+        for suggestion in &diag.suggestions {
+            self.render_suggestion(&mut out, suggestion, sources, width);
+        }
+
+        out
+    }
+
+    /// Render every diagnostic, separated by blank lines
+    pub fn render_all<'a>(
+        &self,
+        diags: impl IntoIterator<Item = &'a Diagnostic>,
+        sources: &SourceMap,
+    ) -> String {
+        diags
+            .into_iter()
+            .map(|d| self.render(d, sources))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn render_lines(
+        &self,
+        out: &mut String,
+        file: &SourceFile,
+        labels: &[&Label],
+        severity: Severity,
+        width: usize,
+    ) {
+        let mut lines: Vec<u32> = labels.iter().map(|l| l.span.line).collect();
+        lines.sort_unstable();
+        lines.dedup();
+
+        let mut previous: Option<u32> = None;
+        for line in lines {
+            if let Some(prev) = previous {
+                if line > prev + 1 {
+                    let _ = writeln!(out, "{}", self.paint(BLUE, "..."));
+                }
+            }
+            previous = Some(line);
+
+            let text = file.line_text(line).unwrap_or("");
+            let _ = writeln!(
+                out,
+                "{:>width$} {} {}",
+                self.paint(BLUE, &line.to_string()),
+                self.paint(BLUE, "|"),
+                expand_tabs(text),
+                width = width + self.paint_overhead(BLUE)
+            );
+
+            // Primary labels first so their message sits closest to the source line
+            let mut on_line: Vec<&Label> =
+                labels.iter().copied().filter(|l| l.span.line == line).collect();
+            on_line.sort_by_key(|l| (!l.primary, l.span.start));
+
+            for label in on_line {
+                let (start, len) = underline_range(file, text, label.span);
+                let (mark, style) = if label.primary {
+                    ('^', self.severity_style(severity))
+                } else {
+                    ('-', BLUE)
+                };
+                let marks: String = std::iter::repeat_n(mark, len).collect();
+                let mut underline = format!("{}{}", " ".repeat(start), marks);
+                if !label.message.is_empty() {
+                    underline.push(' ');
+                    underline.push_str(&label.message);
+                }
+                let _ = writeln!(
+                    out,
+                    "{} {} {}",
+                    " ".repeat(width),
+                    self.paint(BLUE, "|"),
+                    self.paint(style, &underline)
+                );
+            }
+        }
+    }
+
+    fn render_suggestion(
+        &self,
+        out: &mut String,
+        suggestion: &Suggestion,
+        sources: &SourceMap,
+        width: usize,
+    ) {
+        let _ = writeln!(out, "{}: {}", self.paint(CYAN, "help"), suggestion.message);
+
+        let span = suggestion.span;
+        let Some(file) = sources.get(span.file).filter(|_| !span.is_dummy()) else {
+            return;
+        };
+        let Some(line_start) = file.lines().line_start(span.line as usize - 1) else {
+            return;
+        };
+        let text = file.line_text(span.line).unwrap_or("");
+        let start = span.start.saturating_sub(line_start).min(text.len());
+        let end = span.end.saturating_sub(line_start).clamp(start, text.len());
+        if !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+            return;
+        }
+
+        // Only the first line of a multi-line replacement is shown
+        let replacement = suggestion.replacement.lines().next().unwrap_or("");
+        let patched = format!("{}{}{}", &text[..start], replacement, &text[end..]);
+        let pad = " ".repeat(width);
+
+        let _ = writeln!(out, "{} {}", pad, self.paint(BLUE, "|"));
+        let _ = writeln!(
+            out,
+            "{:>width$} {} {}",
+            self.paint(BLUE, &span.line.to_string()),
+            self.paint(BLUE, "|"),
+            expand_tabs(&patched),
+            width = width + self.paint_overhead(BLUE)
+        );
+        let offset = display_width(&text[..start]);
+        let marks: String = std::iter::repeat_n('+', display_width(replacement).max(1)).collect();
+        let _ = writeln!(
+            out,
+            "{} {} {}{}",
+            pad,
+            self.paint(BLUE, "|"),
+            " ".repeat(offset),
+            self.paint(GREEN, &marks)
+        );
+    }
+
+    fn gutter_width(&self, diag: &Diagnostic, labels: &[&Label]) -> usize {
+        labels
+            .iter()
+            .map(|l| l.span.line)
+            .chain(diag.suggestions.iter().map(|s| s.span.line))
+            .max()
+            .unwrap_or(0)
+            .to_string()
+            .len()
+    }
+
+    fn severity_style(&self, severity: Severity) -> &'static str {
+        match severity {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+            Severity::Note => GREEN,
+            Severity::Help => CYAN,
+        }
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// Extra bytes `paint` adds, so `{:>width$}` alignment still works on coloured text
+    fn paint_overhead(&self, style: &str) -> usize {
+        if self.color {
+            style.len() + RESET.len()
+        } else {
+            0
+        }
+    }
+}
+
+fn note_text(renderer: &Renderer, note: &str) -> String {
+    format!("{} {}", renderer.paint(BOLD, "note:"), note)
+}
+
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', &" ".repeat(TAB_WIDTH))
+}
+
+fn display_width(text: &str) -> usize {
+    text.chars().map(|c| if c == '\t' { TAB_WIDTH } else { 1 }).sum()
+}
+
+/// Display column and width of the underline for `span` on its first line
+fn underline_range(file: &SourceFile, text: &str, span: Span) -> (usize, usize) {
+    let line_start = file
+        .lines()
+        .line_start(span.line as usize - 1)
+        .unwrap_or(span.start);
+    let start = span.start.saturating_sub(line_start).min(text.len());
+    let end = span.end.saturating_sub(line_start).clamp(start, text.len());
+
+    match (text.get(..start), text.get(start..end)) {
+        (Some(before), Some(covered)) => (display_width(before), display_width(covered).max(1)),
+        _ => (span.column.saturating_sub(1) as usize, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codes, FileId};
+
+    fn span_of(sources: &SourceMap, file: FileId, needle: &str) -> Span {
+        let f = sources.get(file).unwrap();
+        let start = f.source.find(needle).unwrap();
+        let (line, column) = f.line_col(start);
+        Span::new(file, start, start + needle.len(), line, column)
+    }
+
+    #[test]
+    fn test_render_primary_and_secondary() {
+        let mut sources = SourceMap::new();
+        let file = sources.add_file("main.solo", "fn main() {\n    let x = y;\n    x\n}\n");
+        let diag = Diagnostic::error("cannot find value `y`")
+            .with_code(codes::UNEXPECTED_TOKEN)
+            .with_primary(span_of(&sources, file, "y"), "not found")
+            .with_secondary(span_of(&sources, file, "x\n}"), "used here")
+            .with_note("values must be declared before use");
+
+        let out = Renderer::plain().render(&diag, &sources);
+        let expected = "\
+error[E0002]: cannot find value `y`
+ --> main.solo:2:13
+  |
+2 |     let x = y;
+  |             ^ not found
+3 |     x
+  |     - used here
+  |
+  = note: values must be declared before use
+";
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_render_suggestion() {
+        let mut sources = SourceMap::new();
+        let file = sources.add_file("main.solo", "let x = 1\nlet y = 2;\n");
+        let after = span_of(&sources, file, "1");
+        let insert = Span::new(file, after.end, after.end, after.line, after.column + 1);
+        let diag = Diagnostic::error("expected `;`, found `let`")
+            .with_primary(insert, "expected `;`")
+            .with_suggestion(Suggestion::new("add `;` here", insert, ";"));
+
+        let out = Renderer::plain().render(&diag, &sources);
+        assert!(out.contains("1 | let x = 1\n  |          ^ expected `;`\n"), "{}", out);
+        assert!(out.ends_with("help: add `;` here\n  |\n1 | let x = 1;\n  |          +\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_render_without_source() {
+        let diag = Diagnostic::error("no input files");
+        assert_eq!(Renderer::plain().render(&diag, &SourceMap::new()), "error: no input files\n");
+    }
+}
@@ -1,6 +1,10 @@
 // Solo Language Lexer
 // Tokenizes source code into a stream of tokens
 
+mod span;
+
+pub use span::{FileId, LineTable, SourceFile, SourceMap, Span};
+
 use logos::Logos;
 use std::fmt;
 
@@ -278,40 +282,206 @@ pub enum Token {
     Eof,
 }
 
+impl Token {
+    /// Human-readable name of the token for diagnostics, e.g. "`;`" or "identifier"
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Token::Fn => "`fn`",
+            Token::Let => "`let`",
+            Token::Mut => "`mut`",
+            Token::If => "`if`",
+            Token::Else => "`else`",
+            Token::Match => "`match`",
+            Token::Loop => "`loop`",
+            Token::While => "`while`",
+            Token::For => "`for`",
+            Token::In => "`in`",
+            Token::Return => "`return`",
+            Token::Break => "`break`",
+            Token::Continue => "`continue`",
+            Token::Struct => "`struct`",
+            Token::Enum => "`enum`",
+            Token::Trait => "`trait`",
+            Token::Impl => "`impl`",
+            Token::Mod => "`mod`",
+            Token::Import => "`import`",
+            Token::Use => "`use`",
+            Token::Pub => "`pub`",
+            Token::As => "`as`",
+            Token::Where => "`where`",
+            Token::Type => "`type`",
+            Token::Const => "`const`",
+            Token::Static => "`static`",
+            Token::Async => "`async`",
+            Token::Await => "`await`",
+            Token::Move => "`move`",
+            Token::Ref => "`ref`",
+            Token::Unsafe => "`unsafe`",
+            Token::True => "`true`",
+            Token::False => "`false`",
+            Token::Affine => "`affine`",
+            Token::Comptime => "`comptime`",
+            Token::Pre => "`pre`",
+            Token::Post => "`post`",
+            Token::Invariant => "`invariant`",
+            Token::Requires => "`requires`",
+            Token::Ensures => "`ensures`",
+            Token::Intent => "`intent`",
+            Token::Hybrid => "`hybrid`",
+            Token::Agent => "`agent`",
+            Token::Workflow => "`workflow`",
+            Token::Spawn => "`spawn`",
+            Token::Send => "`send`",
+            Token::Receive => "`receive`",
+            Token::Broadcast => "`broadcast`",
+            Token::State => "`state`",
+            Token::Capabilities => "`capabilities`",
+            Token::Goals => "`goals`",
+            Token::Constraints => "`constraints`",
+            Token::Communication => "`communication`",
+            Token::I8 => "`i8`",
+            Token::I16 => "`i16`",
+            Token::I32 => "`i32`",
+            Token::I64 => "`i64`",
+            Token::I128 => "`i128`",
+            Token::Isize => "`isize`",
+            Token::U8 => "`u8`",
+            Token::U16 => "`u16`",
+            Token::U32 => "`u32`",
+            Token::U64 => "`u64`",
+            Token::U128 => "`u128`",
+            Token::Usize => "`usize`",
+            Token::F32 => "`f32`",
+            Token::F64 => "`f64`",
+            Token::Bool => "`bool`",
+            Token::Char => "`char`",
+            Token::Str => "`str`",
+            Token::Plus => "`+`",
+            Token::Minus => "`-`",
+            Token::Star => "`*`",
+            Token::Slash => "`/`",
+            Token::Percent => "`%`",
+            Token::EqualEqual => "`==`",
+            Token::NotEqual => "`!=`",
+            Token::Less => "`<`",
+            Token::Greater => "`>`",
+            Token::LessEqual => "`<=`",
+            Token::GreaterEqual => "`>=`",
+            Token::And => "`&&`",
+            Token::Or => "`||`",
+            Token::Not => "`!`",
+            Token::Ampersand => "`&`",
+            Token::Pipe => "`|`",
+            Token::Caret => "`^`",
+            Token::LeftShift => "`<<`",
+            Token::RightShift => "`>>`",
+            Token::Equal => "`=`",
+            Token::PlusEqual => "`+=`",
+            Token::MinusEqual => "`-=`",
+            Token::StarEqual => "`*=`",
+            Token::SlashEqual => "`/=`",
+            Token::PercentEqual => "`%=`",
+            Token::LeftParen => "`(`",
+            Token::RightParen => "`)`",
+            Token::LeftBrace => "`{`",
+            Token::RightBrace => "`}`",
+            Token::LeftBracket => "`[`",
+            Token::RightBracket => "`]`",
+            Token::Comma => "`,`",
+            Token::Semicolon => "`;`",
+            Token::Colon => "`:`",
+            Token::ColonColon => "`::`",
+            Token::Dot => "`.`",
+            Token::DotDot => "`..`",
+            Token::DotDotEqual => "`..=`",
+            Token::Arrow => "`->`",
+            Token::FatArrow => "`=>`",
+            Token::Hash => "`#`",
+            Token::At => "`@`",
+            Token::Question => "`?`",
+            Token::Underscore => "`_`",
+            Token::Lifetime => "`'`",
+            Token::Identifier => "identifier",
+            Token::IntLiteral => "integer literal",
+            Token::FloatLiteral => "float literal",
+            Token::StringLiteral => "string literal",
+            Token::CharLiteral => "character literal",
+            Token::Error => "invalid token",
+            Token::Eof => "end of file",
+        }
+    }
+}
+
 impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
+/// A token together with its source text and location
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub text: String,
+    pub span: Span,
+}
+
 pub struct Lexer<'source> {
     lexer: logos::Lexer<'source, Token>,
+    source: &'source str,
+    file: FileId,
+    lines: LineTable,
 }
 
 impl<'source> Lexer<'source> {
     pub fn new(source: &'source str) -> Self {
+        Self::with_file(source, FileId::default())
+    }
+
+    /// Create a lexer whose spans refer to `file`
+    pub fn with_file(source: &'source str, file: FileId) -> Self {
         Self {
             lexer: Token::lexer(source),
+            source,
+            file,
+            lines: LineTable::new(source),
         }
     }
 
-    pub fn next_token(&mut self) -> (Token, &'source str) {
-        match self.lexer.next() {
-            Some(Ok(token)) => (token, self.lexer.slice()),
-            Some(Err(_)) => (Token::Error, self.lexer.slice()),
-            None => (Token::Eof, ""),
+    fn span(&self, range: std::ops::Range<usize>) -> Span {
+        let (line, column) = self.lines.line_col(self.source, range.start);
+        Span::new(self.file, range.start, range.end, line, column)
+    }
+
+    pub fn next_token(&mut self) -> SpannedToken {
+        let token = match self.lexer.next() {
+            Some(Ok(token)) => token,
+            Some(Err(_)) => Token::Error,
+            None => {
+                let end = self.source.len();
+                return SpannedToken {
+                    token: Token::Eof,
+                    text: String::new(),
+                    span: self.span(end..end),
+                };
+            }
+        };
+        SpannedToken {
+            token,
+            text: self.lexer.slice().to_string(),
+            span: self.span(self.lexer.span()),
         }
     }
 
-    pub fn tokenize_all(&mut self) -> Vec<(Token, String)> {
+    pub fn tokenize_all(&mut self) -> Vec<SpannedToken> {
         let mut tokens = Vec::new();
         loop {
-            let (token, slice) = self.next_token();
-            if token == Token::Eof {
-                tokens.push((token, slice.to_string()));
+            let token = self.next_token();
+            let done = token.token == Token::Eof;
+            tokens.push(token);
+            if done {
                 break;
             }
-            tokens.push((token, slice.to_string()));
         }
         tokens
     }
@@ -327,11 +497,11 @@ fn test_keywords() {
         let mut lexer = Lexer::new(source);
         let tokens = lexer.tokenize_all();
 
-        assert_eq!(tokens[0].0, Token::Fn);
-        assert_eq!(tokens[1].0, Token::Let);
-        assert_eq!(tokens[2].0, Token::Mut);
-        assert_eq!(tokens[3].0, Token::If);
-        assert_eq!(tokens[4].0, Token::Else);
+        assert_eq!(tokens[0].token, Token::Fn);
+        assert_eq!(tokens[1].token, Token::Let);
+        assert_eq!(tokens[2].token, Token::Mut);
+        assert_eq!(tokens[3].token, Token::If);
+        assert_eq!(tokens[4].token, Token::Else);
     }
 
     #[test]
@@ -340,10 +510,10 @@ fn test_identifiers() {
         let mut lexer = Lexer::new(source);
         let tokens = lexer.tokenize_all();
 
-        assert_eq!(tokens[0].0, Token::Identifier);
-        assert_eq!(tokens[0].1, "foo");
-        assert_eq!(tokens[1].0, Token::Identifier);
-        assert_eq!(tokens[1].1, "bar");
+        assert_eq!(tokens[0].token, Token::Identifier);
+        assert_eq!(tokens[0].text, "foo");
+        assert_eq!(tokens[1].token, Token::Identifier);
+        assert_eq!(tokens[1].text, "bar");
     }
 
     #[test]
@@ -352,12 +522,12 @@ fn test_literals() {
         let mut lexer = Lexer::new(source);
         let tokens = lexer.tokenize_all();
 
-        assert_eq!(tokens[0].0, Token::IntLiteral);
-        assert_eq!(tokens[1].0, Token::FloatLiteral);
-        assert_eq!(tokens[2].0, Token::StringLiteral);
-        assert_eq!(tokens[3].0, Token::CharLiteral);
-        assert_eq!(tokens[4].0, Token::True);
-        assert_eq!(tokens[5].0, Token::False);
+        assert_eq!(tokens[0].token, Token::IntLiteral);
+        assert_eq!(tokens[1].token, Token::FloatLiteral);
+        assert_eq!(tokens[2].token, Token::StringLiteral);
+        assert_eq!(tokens[3].token, Token::CharLiteral);
+        assert_eq!(tokens[4].token, Token::True);
+        assert_eq!(tokens[5].token, Token::False);
     }
 
     #[test]
@@ -366,12 +536,12 @@ fn test_operators() {
         let mut lexer = Lexer::new(source);
         let tokens = lexer.tokenize_all();
 
-        assert_eq!(tokens[0].0, Token::Plus);
-        assert_eq!(tokens[1].0, Token::Minus);
-        assert_eq!(tokens[2].0, Token::Star);
-        assert_eq!(tokens[3].0, Token::Slash);
-        assert_eq!(tokens[4].0, Token::EqualEqual);
-        assert_eq!(tokens[5].0, Token::NotEqual);
+        assert_eq!(tokens[0].token, Token::Plus);
+        assert_eq!(tokens[1].token, Token::Minus);
+        assert_eq!(tokens[2].token, Token::Star);
+        assert_eq!(tokens[3].token, Token::Slash);
+        assert_eq!(tokens[4].token, Token::EqualEqual);
+        assert_eq!(tokens[5].token, Token::NotEqual);
     }
 
     #[test]
@@ -380,9 +550,9 @@ fn test_function() {
         let mut lexer = Lexer::new(source);
         let tokens = lexer.tokenize_all();
 
-        assert_eq!(tokens[0].0, Token::Fn);
-        assert_eq!(tokens[1].0, Token::Identifier);
-        assert_eq!(tokens[2].0, Token::LeftParen);
+        assert_eq!(tokens[0].token, Token::Fn);
+        assert_eq!(tokens[1].token, Token::Identifier);
+        assert_eq!(tokens[2].token, Token::LeftParen);
     }
 
     #[test]
@@ -392,6 +562,33 @@ fn test_comments() {
         let tokens = lexer.tokenize_all();
 
         // Comment should be skipped
-        assert_eq!(tokens[4].0, Token::IntLiteral);
+        assert_eq!(tokens[5].token, Token::IntLiteral);
+    }
+
+    #[test]
+    fn test_spans() {
+        let source = "fn main() {\n    let x = 42;\n}";
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.tokenize_all();
+
+        let let_token = &tokens[5];
+        assert_eq!(let_token.token, Token::Let);
+        assert_eq!((let_token.span.line, let_token.span.column), (2, 5));
+        assert_eq!(&source[let_token.span.start..let_token.span.end], "let");
+
+        let int_token = &tokens[8];
+        assert_eq!(int_token.text, "42");
+        assert_eq!((int_token.span.line, int_token.span.column), (2, 13));
+
+        let eof = tokens.last().unwrap();
+        assert_eq!(eof.token, Token::Eof);
+        assert_eq!(eof.span.start, source.len());
+        assert_eq!((eof.span.line, eof.span.column), (3, 2));
+    }
+
+    #[test]
+    fn test_file_id() {
+        let mut lexer = Lexer::with_file("x", FileId(3));
+        assert_eq!(lexer.next_token().span.file, FileId(3));
     }
 }
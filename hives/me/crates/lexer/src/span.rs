@@ -0,0 +1,248 @@
+// Source locations
+// Byte spans with line/column information and a map of loaded source files
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Identifies a file registered in a `SourceMap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub struct FileId(pub u32);
+
+/// A byte range in a source file, with the 1-based line and column of its start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Span {
+    pub file: FileId,
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Span {
+    pub fn new(file: FileId, start: usize, end: usize, line: u32, column: u32) -> Self {
+        Self { file, start, end, line, column }
+    }
+
+    /// A span that points nowhere, for synthesized tokens and nodes
+    pub fn dummy() -> Self {
+        Self::default()
+    }
+
+    pub fn is_dummy(&self) -> bool {
+        self.line == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Span covering `self` through the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        if self.is_dummy() {
+            return other;
+        }
+        if other.is_dummy() {
+            return self;
+        }
+        Span {
+            end: other.end.max(self.end),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Byte offsets of the start of every line in a source text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineTable {
+    line_starts: Vec<usize>,
+}
+
+impl LineTable {
+    pub fn new(source: &str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        Self { line_starts }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// 0-based index of the line containing `offset`
+    pub fn line_index(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        }
+    }
+
+    /// Byte offset at which the 0-based line `index` starts
+    pub fn line_start(&self, index: usize) -> Option<usize> {
+        self.line_starts.get(index).copied()
+    }
+
+    /// 1-based line and column (in characters) of `offset` within `source`
+    pub fn line_col(&self, source: &str, offset: usize) -> (u32, u32) {
+        let offset = offset.min(source.len());
+        let line = self.line_index(offset);
+        let start = self.line_starts[line];
+        let column = source
+            .get(start..offset)
+            .map(|s| s.chars().count())
+            .unwrap_or(offset - start);
+        (line as u32 + 1, column as u32 + 1)
+    }
+}
+
+/// A source file loaded into a `SourceMap`
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub id: FileId,
+    pub path: PathBuf,
+    pub source: String,
+    lines: LineTable,
+}
+
+impl SourceFile {
+    pub fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    pub fn lines(&self) -> &LineTable {
+        &self.lines
+    }
+
+    /// 1-based line and column of a byte offset
+    pub fn line_col(&self, offset: usize) -> (u32, u32) {
+        self.lines.line_col(&self.source, offset)
+    }
+
+    /// Text of the 1-based `line`, without its trailing newline
+    pub fn line_text(&self, line: u32) -> Option<&str> {
+        let index = (line as usize).checked_sub(1)?;
+        let start = self.lines.line_start(index)?;
+        let end = self
+            .lines
+            .line_start(index + 1)
+            .unwrap_or(self.source.len());
+        self.source
+            .get(start..end)
+            .map(|s| s.trim_end_matches(['\n', '\r']))
+    }
+
+    /// Source text covered by a span
+    pub fn snippet(&self, span: Span) -> Option<&str> {
+        self.source.get(span.start..span.end)
+    }
+}
+
+/// All source files known to a compilation session, indexed by `FileId`
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a file and return its id
+    pub fn add_file(&mut self, path: impl AsRef<Path>, source: impl Into<String>) -> FileId {
+        let id = FileId(self.files.len() as u32);
+        let source = source.into();
+        let lines = LineTable::new(&source);
+        self.files.push(SourceFile {
+            id,
+            path: path.as_ref().to_path_buf(),
+            source,
+            lines,
+        });
+        id
+    }
+
+    pub fn get(&self, id: FileId) -> Option<&SourceFile> {
+        self.files.get(id.0 as usize)
+    }
+
+    /// Find an already loaded file by path
+    pub fn find(&self, path: &Path) -> Option<FileId> {
+        self.files.iter().find(|f| f.path == path).map(|f| f.id)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &SourceFile> {
+        self.files.iter()
+    }
+
+    /// Source text covered by a span
+    pub fn snippet(&self, span: Span) -> Option<&str> {
+        self.get(span.file)?.snippet(span)
+    }
+
+    /// Render a span as `path:line:column`
+    pub fn describe(&self, span: Span) -> String {
+        match self.get(span.file) {
+            Some(file) => format!("{}:{}:{}", file.name(), span.line, span.column),
+            None => span.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_col() {
+        let source = "ab\ncd\n\nef";
+        let table = LineTable::new(source);
+        assert_eq!(table.line_count(), 4);
+        assert_eq!(table.line_col(source, 0), (1, 1));
+        assert_eq!(table.line_col(source, 1), (1, 2));
+        assert_eq!(table.line_col(source, 3), (2, 1));
+        assert_eq!(table.line_col(source, 6), (3, 1));
+        assert_eq!(table.line_col(source, 8), (4, 2));
+    }
+
+    #[test]
+    fn test_line_col_counts_chars() {
+        let source = "\"é\" x";
+        let table = LineTable::new(source);
+        // 'x' is at byte 5 but character 5 (the é is two bytes)
+        assert_eq!(table.line_col(source, 5), (1, 5));
+    }
+
+    #[test]
+    fn test_source_map() {
+        let mut map = SourceMap::new();
+        let a = map.add_file("a.solo", "fn a() {}\n");
+        let b = map.add_file("b.solo", "fn b() {}\nfn c() {}\n");
+        assert_ne!(a, b);
+
+        let file = map.get(b).unwrap();
+        assert_eq!(file.line_text(2), Some("fn c() {}"));
+        assert_eq!(map.snippet(Span::new(b, 13, 14, 2, 4)), Some("c"));
+        assert_eq!(map.describe(Span::new(b, 13, 14, 2, 4)), "b.solo:2:4");
+        assert_eq!(map.find(Path::new("a.solo")), Some(a));
+    }
+
+    #[test]
+    fn test_span_to() {
+        let a = Span::new(FileId(0), 2, 5, 1, 3);
+        let b = Span::new(FileId(0), 8, 10, 1, 9);
+        let joined = a.to(b);
+        assert_eq!((joined.start, joined.end, joined.column), (2, 10, 3));
+        assert_eq!(Span::dummy().to(b), b);
+    }
+}
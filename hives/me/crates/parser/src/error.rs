@@ -1,10 +1,15 @@
 // Parser error types
 
-use my_lang_lexer::Token;
+use my_lang_diagnostics::{codes, Diagnostic, Suggestion};
+use my_lang_lexer::{Span, Token};
+use std::fmt;
 use thiserror::Error;
 
 #[derive(Error, Debug, Clone, PartialEq)]
-pub enum ParseError {
+pub enum ParseErrorKind {
+    #[error("Invalid token: {0}")]
+    InvalidToken(String),
+
     #[error("Unexpected token: expected {expected}, found {found}")]
     UnexpectedToken {
         expected: String,
@@ -32,29 +37,186 @@ pub enum ParseError {
     #[error("Missing type annotation")]
     MissingType,
 
-    #[error("Invalid expression")]
-    InvalidExpression,
+    #[error("Invalid expression: {0}")]
+    InvalidExpression(String),
 
     #[error("Invalid statement")]
     InvalidStatement,
 
-    #[error("Invalid item")]
-    InvalidItem,
+    #[error("Invalid item: {0}")]
+    InvalidItem(String),
 
     #[error("Invalid contract clause: {0}")]
     InvalidContract(String),
 
     #[error("Invalid attribute: {0}")]
     InvalidAttribute(String),
+
+    #[error("Visibility qualifier not permitted on {0}")]
+    UnexpectedVisibility(String),
 }
 
-pub type ParseResult<T> = Result<T, ParseError>;
+/// A parse error and the location of the token that caused it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+    /// Empty span just after the token before the error, where a missing token would be inserted
+    pub insertion: Option<Span>,
+}
 
 impl ParseError {
-    pub fn unexpected(expected: impl Into<String>, found: &Token) -> Self {
-        ParseError::UnexpectedToken {
-            expected: expected.into(),
-            found: format!("{:?}", found),
+    pub fn new(kind: ParseErrorKind, span: Span) -> Self {
+        Self { kind, span, insertion: None }
+    }
+
+    pub fn unexpected(expected: impl Into<String>, found: &Token, span: Span) -> Self {
+        Self::new(
+            ParseErrorKind::UnexpectedToken {
+                expected: expected.into(),
+                found: found.describe().to_string(),
+            },
+            span,
+        )
+    }
+
+    pub fn with_insertion(mut self, insertion: Option<Span>) -> Self {
+        self.insertion = insertion.filter(|at| !at.is_dummy());
+        self
+    }
+
+    /// Convert to a diagnostic with a code, labels and, where possible, a fix-it
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let span = self.span;
+        match &self.kind {
+            ParseErrorKind::InvalidToken(text) => {
+                Diagnostic::error(format!("unknown start of token `{}`", text))
+                    .with_code(codes::INVALID_TOKEN)
+                    .with_primary(span, "not a valid token")
+            }
+            ParseErrorKind::UnexpectedToken { expected, found } if found == Token::Eof.describe() => {
+                Diagnostic::error("unexpected end of file")
+                    .with_code(codes::UNEXPECTED_EOF)
+                    .with_primary(span, format!("expected {}", expected))
+            }
+            ParseErrorKind::UnexpectedToken { expected, found } => {
+                let mut diag = Diagnostic::error(format!("expected {}, found {}", expected, found))
+                    .with_code(codes::UNEXPECTED_TOKEN);
+                match self.insertion {
+                    Some(at) if is_closing(expected) => {
+                        diag = diag.with_primary(at, format!("expected {}", expected));
+                        // With no space between the tokens both labels would
+                        // point at the same column
+                        if at.start != span.start {
+                            diag = diag.with_secondary(span, "unexpected token");
+                        }
+                        diag = diag.with_suggestion(Suggestion::new(
+                                format!("add {} here", expected),
+                                at,
+                                expected.trim_matches('`'),
+                            ));
+                    }
+                    _ => diag = diag.with_primary(span, format!("expected {}", expected)),
+                }
+                diag
+            }
+            ParseErrorKind::UnexpectedEof => Diagnostic::error("unexpected end of file")
+                .with_code(codes::UNEXPECTED_EOF)
+                .with_primary(span, "expected more input"),
+            ParseErrorKind::InvalidLiteral(text) => {
+                let diag = Diagnostic::error(format!("invalid literal `{}`", text))
+                    .with_code(codes::INVALID_LITERAL)
+                    .with_primary(span, "literal out of range or malformed");
+                if is_integer(text) {
+                    diag.with_note("integer literals must fit in 64 bits")
+                } else {
+                    diag
+                }
+            }
+            ParseErrorKind::InvalidOperator(op) => {
+                Diagnostic::error(format!("{} is not a binary operator", op))
+                    .with_code(codes::INVALID_OPERATOR)
+                    .with_primary(span, "not an operator")
+            }
+            ParseErrorKind::InvalidType(found) => {
+                Diagnostic::error(format!("expected type, found {}", found))
+                    .with_code(codes::EXPECTED_TYPE)
+                    .with_primary(span, "expected type")
+            }
+            ParseErrorKind::InvalidPattern(found) => {
+                Diagnostic::error(format!("expected pattern, found {}", found))
+                    .with_code(codes::EXPECTED_PATTERN)
+                    .with_primary(span, "expected pattern")
+            }
+            ParseErrorKind::MissingIdentifier => Diagnostic::error("expected identifier")
+                .with_code(codes::EXPECTED_IDENTIFIER)
+                .with_primary(span, "expected identifier"),
+            ParseErrorKind::MissingType => Diagnostic::error("missing type annotation")
+                .with_code(codes::MISSING_TYPE)
+                .with_primary(span, "type required here"),
+            ParseErrorKind::InvalidExpression(found) => {
+                Diagnostic::error(format!("expected expression, found {}", found))
+                    .with_code(codes::EXPECTED_EXPRESSION)
+                    .with_primary(span, "expected expression")
+            }
+            ParseErrorKind::InvalidStatement => Diagnostic::error("expected statement")
+                .with_code(codes::EXPECTED_STATEMENT)
+                .with_primary(span, "expected statement"),
+            ParseErrorKind::InvalidItem(found) => {
+                Diagnostic::error(format!("expected item, found {}", found))
+                    .with_code(codes::EXPECTED_ITEM)
+                    .with_primary(span, "expected item")
+                    .with_note(
+                        "items start with `fn`, `struct`, `enum`, `trait`, `impl`, `mod`, \
+                         `import`, `const`, `type`, `agent` or `workflow`",
+                    )
+            }
+            ParseErrorKind::InvalidContract(msg) => {
+                Diagnostic::error(format!("invalid contract clause: {}", msg))
+                    .with_code(codes::INVALID_CONTRACT)
+                    .with_primary(span, "in this clause")
+            }
+            ParseErrorKind::InvalidAttribute(msg) => {
+                Diagnostic::error(format!("invalid attribute: {}", msg))
+                    .with_code(codes::INVALID_ATTRIBUTE)
+                    .with_primary(span, "in this attribute")
+            }
+            ParseErrorKind::UnexpectedVisibility(what) => {
+                Diagnostic::error(format!("visibility qualifiers are not permitted on {}", what))
+                    .with_code(codes::UNEXPECTED_VISIBILITY)
+                    .with_primary(span, "qualifier not permitted here")
+                    .with_note("place qualifiers on the individual items instead")
+            }
         }
     }
 }
+
+impl From<&ParseError> for Diagnostic {
+    fn from(err: &ParseError) -> Self {
+        err.to_diagnostic()
+    }
+}
+
+/// Tokens that are commonly forgotten at the end of a construct
+fn is_closing(expected: &str) -> bool {
+    matches!(expected, "`;`" | "`)`" | "`]`" | "`}`" | "`,`")
+}
+
+/// Whether literal `text` is an integer rather than a float
+fn is_integer(text: &str) -> bool {
+    let text = text.trim_start_matches('-');
+    if text.starts_with("0x") || text.starts_with("0b") || text.starts_with("0o") {
+        return true;
+    }
+    !text.contains(['.', 'e', 'E'])
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.kind, self.span)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub type ParseResult<T> = Result<T, ParseError>;
@@ -4,52 +4,149 @@
 mod error;
 mod precedence;
 
-pub use error::{ParseError, ParseResult};
+pub use error::{ParseError, ParseErrorKind, ParseResult};
 pub use precedence::Precedence;
 
-use my_lang_lexer::{Lexer, Token};
+use my_lang_lexer::{FileId, Lexer, LineTable, Span, SpannedToken, Token};
 use my_lang_ast::*;
 use precedence::{token_to_binary_op, is_right_associative};
 
 /// The parser structure
 pub struct Parser {
-    tokens: Vec<(Token, String)>,
+    tokens: Vec<SpannedToken>,
     pos: usize,
+    next_id: u32,
+    errors: Vec<ParseError>,
+    /// Set while parsing the head of `if`, `while`, `match` and `for`, where
+    /// `name {` opens the body rather than a struct literal
+    no_struct: bool,
+    /// The text parsed and its lines, when known, to place columns of points between tokens
+    source: Option<(String, LineTable)>,
+}
+
+/// Where panic-mode recovery is resynchronizing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Recovery {
+    /// Items at the top level of a file
+    TopLevel,
+    /// Items inside `mod`, `impl` or `trait` braces
+    Nested,
+    /// Statements inside a block
+    Statement,
 }
 
 impl Parser {
     /// Create a new parser from source code
     pub fn new(source: &str) -> Self {
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.tokenize_all();
-        Self { tokens, pos: 0 }
+        Self::with_file(source, FileId::default())
+    }
+
+    /// Create a parser whose spans refer to `file` in the session's `SourceMap`
+    pub fn with_file(source: &str, file: FileId) -> Self {
+        let mut lexer = Lexer::with_file(source, file);
+        let mut parser = Self::from_tokens(lexer.tokenize_all());
+        parser.source = Some((source.to_string(), LineTable::new(source)));
+        parser
+    }
+
+    /// Create a parser over an already lexed token stream
+    ///
+    /// Tokens the lexer could not recognize are reported as errors and
+    /// dropped, so parsing continues past them.
+    pub fn from_tokens(tokens: Vec<SpannedToken>) -> Self {
+        let (invalid, tokens): (Vec<_>, Vec<_>) =
+            tokens.into_iter().partition(|t| t.token == Token::Error);
+        let errors = invalid
+            .into_iter()
+            .map(|t| ParseError::new(ParseErrorKind::InvalidToken(t.text), t.span))
+            .collect();
+        Self { tokens, pos: 0, next_id: 0, errors, no_struct: false, source: None }
+    }
+
+    /// Start assigning node ids at `first`, so several files parsed into one
+    /// session get distinct ids
+    pub fn with_first_node_id(mut self, first: NodeId) -> Self {
+        self.next_id = first.0;
+        self
+    }
+
+    /// The id the next allocated node will receive
+    pub fn next_node_id(&self) -> NodeId {
+        NodeId(self.next_id)
     }
 
     // ========== Token Navigation ==========
 
     /// Get the current token without consuming it
     fn peek(&self) -> &Token {
-        self.tokens.get(self.pos).map(|(t, _)| t).unwrap_or(&Token::Eof)
+        self.tokens.get(self.pos).map(|t| &t.token).unwrap_or(&Token::Eof)
+    }
+
+    /// Get the token after the current one without consuming anything
+    fn peek_next(&self) -> &Token {
+        self.tokens.get(self.pos + 1).map(|t| &t.token).unwrap_or(&Token::Eof)
     }
 
     /// Get the current token's text
     fn peek_text(&self) -> &str {
-        self.tokens.get(self.pos).map(|(_, s)| s.as_str()).unwrap_or("")
+        self.tokens.get(self.pos).map(|t| t.text.as_str()).unwrap_or("")
     }
 
-    /// Look ahead n tokens
-    fn peek_ahead(&self, n: usize) -> &Token {
-        self.tokens.get(self.pos + n).map(|(t, _)| t).unwrap_or(&Token::Eof)
+    /// Get the current token's span
+    fn peek_span(&self) -> Span {
+        self.tokens.get(self.pos)
+            .or_else(|| self.tokens.last())
+            .map(|t| t.span)
+            .unwrap_or_default()
+    }
+
+    /// Get the span of the most recently consumed token
+    fn prev_span(&self) -> Span {
+        self.pos.checked_sub(1)
+            .and_then(|i| self.tokens.get(i))
+            .map(|t| t.span)
+            .unwrap_or_default()
+    }
+
+    /// Empty span just after the most recently consumed token, where a
+    /// missing token would be inserted
+    fn insertion_point(&self) -> Option<Span> {
+        let prev = self.pos.checked_sub(1).and_then(|i| self.tokens.get(i))?;
+        let span = prev.span;
+        let (line, column) = match &self.source {
+            Some((source, lines)) => lines.line_col(source, span.end),
+            None => (span.line, span.column + prev.text.chars().count() as u32),
+        };
+        Some(Span::new(span.file, span.end, span.end, line, column))
+    }
+
+    /// Span from `start` through the most recently consumed token
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.prev_span())
     }
 
     /// Consume the current token and advance
-    fn advance(&mut self) -> (Token, String) {
+    fn advance(&mut self) -> SpannedToken {
         let result = self.tokens.get(self.pos).cloned()
-            .unwrap_or((Token::Eof, String::new()));
+            .unwrap_or_else(|| SpannedToken {
+                token: Token::Eof,
+                text: String::new(),
+                span: self.peek_span(),
+            });
         self.pos += 1;
         result
     }
 
+    /// Build an error located at the current token
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError::new(kind, self.peek_span())
+    }
+
+    /// Describe the current token for error messages
+    fn found(&self) -> String {
+        self.peek().describe().to_string()
+    }
+
     /// Check if we're at end of input
     fn is_at_end(&self) -> bool {
         matches!(self.peek(), Token::Eof)
@@ -58,9 +155,10 @@ fn is_at_end(&self) -> bool {
     /// Consume a specific token or return error
     fn expect(&mut self, expected: Token) -> ParseResult<String> {
         if self.peek() == &expected {
-            Ok(self.advance().1)
+            Ok(self.advance().text)
         } else {
-            Err(ParseError::unexpected(format!("{:?}", expected), self.peek()))
+            Err(ParseError::unexpected(expected.describe(), self.peek(), self.peek_span())
+                .with_insertion(self.insertion_point()))
         }
     }
 
@@ -77,27 +175,207 @@ fn match_token(&mut self, expected: &Token) -> bool {
     /// Consume an identifier or return error
     fn expect_identifier(&mut self) -> ParseResult<String> {
         if matches!(self.peek(), Token::Identifier) {
-            Ok(self.advance().1)
+            Ok(self.advance().text)
         } else {
-            Err(ParseError::MissingIdentifier)
+            Err(self.error(ParseErrorKind::MissingIdentifier))
+        }
+    }
+
+    /// Consume the name of a function, method or field. Keywords that only
+    /// have meaning inside agents, workflows and contracts are accepted here,
+    /// so `fn send(self)` and `conn.send(data)` parse.
+    fn expect_member_name(&mut self) -> ParseResult<String> {
+        match self.peek() {
+            Token::Identifier
+            | Token::Intent
+            | Token::Hybrid
+            | Token::Agent
+            | Token::Workflow
+            | Token::Spawn
+            | Token::Send
+            | Token::Receive
+            | Token::Broadcast
+            | Token::State
+            | Token::Capabilities
+            | Token::Goals
+            | Token::Constraints
+            | Token::Communication
+            | Token::Pre
+            | Token::Post
+            | Token::Invariant
+            | Token::Requires
+            | Token::Ensures => Ok(self.advance().text),
+            _ => Err(self.error(ParseErrorKind::MissingIdentifier)),
+        }
+    }
+
+    /// Consume `::name` segments following `first`. A `::` that is not
+    /// followed by an identifier is left for the caller.
+    fn parse_path_rest(&mut self, first: String) -> Vec<String> {
+        let mut path = vec![first];
+        while matches!(self.peek(), Token::ColonColon) && matches!(self.peek_next(), Token::Identifier) {
+            self.advance();
+            path.push(self.advance().text);
         }
+        path
+    }
+
+    /// Run `f` with struct literals allowed or forbidden, restoring the previous setting
+    fn with_struct_literals<T>(
+        &mut self,
+        allowed: bool,
+        f: impl FnOnce(&mut Self) -> ParseResult<T>,
+    ) -> ParseResult<T> {
+        let saved = std::mem::replace(&mut self.no_struct, !allowed);
+        let result = f(self);
+        self.no_struct = saved;
+        result
+    }
+
+    /// Parse the head expression of `if`, `while`, `match` or `for`
+    fn parse_condition(&mut self) -> ParseResult<Expression> {
+        self.with_struct_literals(false, Self::parse_expression)
+    }
+
+    // ========== Node Construction ==========
+
+    fn next_id(&mut self) -> NodeId {
+        let id = NodeId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn mk_expr(&mut self, kind: ExpressionKind, span: Span) -> Expression {
+        let id = self.next_id();
+        Expression::new(id, kind, span)
+    }
+
+    fn mk_type(&mut self, kind: TypeKind, span: Span) -> Type {
+        let id = self.next_id();
+        Type::new(id, kind, span)
+    }
+
+    fn mk_pattern(&mut self, kind: PatternKind, span: Span) -> Pattern {
+        let id = self.next_id();
+        Pattern::new(id, kind, span)
+    }
+
+    fn mk_stmt(&mut self, kind: StatementKind, span: Span) -> Statement {
+        let id = self.next_id();
+        Statement::new(id, kind, span)
     }
 
     // ========== Top-Level Parsing ==========
 
-    /// Parse a complete program
-    pub fn parse_program(&mut self) -> ParseResult<Program> {
+    /// Parse a complete program, recovering from syntax errors.
+    ///
+    /// Items and statements that fail to parse are replaced by `Error`
+    /// placeholders, so the returned program is usable even when the
+    /// error list is non-empty.
+    pub fn parse_program(&mut self) -> (Program, Vec<ParseError>) {
+        let start = self.peek_span();
         let mut items = Vec::new();
         while !self.is_at_end() {
-            items.push(self.parse_item()?);
+            items.push(self.parse_item_recovering(Recovery::TopLevel));
+        }
+        let span = self.span_from(start);
+        let program = Program { id: self.next_id(), span, items };
+        // Lexer errors were recorded up front; report everything in source order
+        let mut errors = std::mem::take(&mut self.errors);
+        errors.sort_by_key(|e| (e.span.file, e.span.start));
+        (program, errors)
+    }
+
+    /// Parse an item, or record the error and skip to the next item boundary
+    fn parse_item_recovering(&mut self, mode: Recovery) -> Item {
+        let start_pos = self.pos;
+        let start = self.peek_span();
+        match self.parse_item() {
+            Ok(item) => item,
+            Err(err) => {
+                self.errors.push(err);
+                self.synchronize(start_pos, mode);
+                let span = self.span_from(start);
+                Item::new(self.next_id(), ItemKind::Error, span)
+            }
+        }
+    }
+
+    /// Parse a statement, or record the error and skip to the next statement boundary
+    fn parse_statement_recovering(&mut self) -> Statement {
+        let start_pos = self.pos;
+        let start = self.peek_span();
+        match self.parse_statement() {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                self.errors.push(err);
+                self.synchronize(start_pos, Recovery::Statement);
+                let span = self.span_from(start);
+                self.mk_stmt(StatementKind::Error, span)
+            }
+        }
+    }
+
+    // ========== Error Recovery ==========
+
+    /// Whether a token can start an item
+    fn is_item_start(token: &Token) -> bool {
+        matches!(
+            token,
+            Token::Fn | Token::Struct | Token::Enum | Token::Trait | Token::Impl
+            | Token::Mod | Token::Import | Token::Use | Token::Const | Token::Type
+            | Token::Pub | Token::Async | Token::Comptime | Token::Hash
+            | Token::Agent | Token::Workflow
+        )
+    }
+
+    /// Skip tokens until the next `;`, `}` or item keyword at the nesting
+    /// level where the failed construct started. Always consumes at least
+    /// one token past `start_pos` so recovery cannot loop.
+    fn synchronize(&mut self, start_pos: usize, mode: Recovery) {
+        let mut depth = 0usize;
+        loop {
+            let at_start = self.pos <= start_pos;
+            match self.peek() {
+                Token::Eof => return,
+                Token::LeftBrace | Token::LeftParen | Token::LeftBracket => depth += 1,
+                Token::RightParen | Token::RightBracket => depth = depth.saturating_sub(1),
+                Token::RightBrace if depth == 0 => {
+                    // A stray `}` at the top level closes nothing; skip it
+                    if mode == Recovery::TopLevel {
+                        self.advance();
+                    }
+                    return;
+                }
+                Token::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 && mode != Recovery::Statement {
+                        self.advance();
+                        return;
+                    }
+                }
+                Token::Semicolon if depth == 0 => {
+                    self.advance();
+                    return;
+                }
+                token if depth == 0
+                    && !at_start
+                    && (Self::is_item_start(token)
+                        || (mode == Recovery::Statement && *token == Token::Let)) =>
+                {
+                    return;
+                }
+                _ => {}
+            }
+            self.advance();
         }
-        Ok(Program { items })
     }
 
     /// Parse a single top-level item
     fn parse_item(&mut self) -> ParseResult<Item> {
-        // Check for visibility modifier
-        let _is_pub = self.match_token(&Token::Pub);
+        let start = self.peek_span();
+
+        let visibility = self.parse_visibility();
 
         // Check for attributes
         let attributes = self.parse_attributes()?;
@@ -106,26 +384,63 @@ fn parse_item(&mut self) -> ParseResult<Item> {
         let is_async = self.match_token(&Token::Async);
         let is_comptime = self.match_token(&Token::Comptime);
 
-        match self.peek() {
-            Token::Fn => self.parse_function(attributes, is_async, is_comptime).map(Item::Function),
-            Token::Struct => self.parse_struct().map(Item::Struct),
-            Token::Enum => self.parse_enum().map(Item::Enum),
-            Token::Trait => self.parse_trait().map(Item::Trait),
-            Token::Impl => self.parse_impl().map(Item::Impl),
-            Token::Mod => self.parse_module().map(Item::Module),
-            Token::Import | Token::Use => self.parse_import().map(Item::Import),
-            Token::Const => self.parse_const().map(Item::Const),
-            Token::Type => self.parse_type_alias().map(Item::Type),
-            Token::Agent => self.parse_agent().map(Item::Agent),
-            Token::Workflow => self.parse_workflow().map(Item::Workflow),
-            _ => Err(ParseError::InvalidItem),
+        let kind = match self.peek() {
+            Token::Fn => ItemKind::Function(self.parse_function(visibility, attributes, is_async, is_comptime, false)?),
+            Token::Struct => ItemKind::Struct(self.parse_struct(visibility)?),
+            Token::Enum => ItemKind::Enum(self.parse_enum(visibility)?),
+            Token::Trait => ItemKind::Trait(self.parse_trait(visibility)?),
+            Token::Impl => {
+                if visibility != Visibility::Private {
+                    return Err(ParseError::new(ParseErrorKind::UnexpectedVisibility("impl blocks".into()), start));
+                }
+                ItemKind::Impl(self.parse_impl()?)
+            }
+            Token::Mod => ItemKind::Module(self.parse_module(visibility)?),
+            Token::Import | Token::Use => ItemKind::Import(self.parse_import(visibility)?),
+            Token::Const => ItemKind::Const(self.parse_const(visibility)?),
+            Token::Type => ItemKind::Type(self.parse_type_alias(visibility)?),
+            Token::Agent => ItemKind::Agent(self.parse_agent(visibility)?),
+            Token::Workflow => ItemKind::Workflow(self.parse_workflow(visibility)?),
+            Token::LeftBrace if is_comptime => {
+                if visibility != Visibility::Private {
+                    let what = "`comptime` blocks".into();
+                    return Err(ParseError::new(ParseErrorKind::UnexpectedVisibility(what), start));
+                }
+                let body_start = self.peek_span();
+                let block = self.parse_block()?;
+                let body = self.mk_expr(ExpressionKind::Block(block), self.span_from(body_start));
+                let span = self.span_from(start);
+                ItemKind::Comptime(self.mk_expr(ExpressionKind::Comptime(Box::new(body)), span))
+            }
+            _ => return Err(self.error(ParseErrorKind::InvalidItem(self.found()))),
+        };
+
+        let span = self.span_from(start);
+        Ok(Item::new(self.next_id(), kind, span))
+    }
+
+    /// Parse an optional `pub` or `pub(crate)`
+    fn parse_visibility(&mut self) -> Visibility {
+        if !self.match_token(&Token::Pub) {
+            return Visibility::Private;
+        }
+        let restricted = matches!(self.peek(), Token::LeftParen)
+            && self.tokens.get(self.pos + 1).is_some_and(|t| t.text == "crate")
+            && matches!(self.tokens.get(self.pos + 2).map(|t| &t.token), Some(Token::RightParen));
+        if restricted {
+            self.pos += 3;
+            Visibility::Crate
+        } else {
+            Visibility::Public
         }
     }
 
     /// Parse attributes (#[...])
     fn parse_attributes(&mut self) -> ParseResult<Vec<Attribute>> {
         let mut attrs = Vec::new();
-        while self.match_token(&Token::Hash) {
+        while matches!(self.peek(), Token::Hash) {
+            let start = self.peek_span();
+            self.advance();
             self.expect(Token::LeftBracket)?;
             let name = self.expect_identifier()?;
             let mut args = Vec::new();
@@ -144,16 +459,25 @@ fn parse_attributes(&mut self) -> ParseResult<Vec<Attribute>> {
             }
 
             self.expect(Token::RightBracket)?;
-            attrs.push(Attribute { name, args });
+            let span = self.span_from(start);
+            attrs.push(Attribute { id: self.next_id(), span, name, args });
         }
         Ok(attrs)
     }
 
     // ========== Function Parsing ==========
 
-    fn parse_function(&mut self, attributes: Vec<Attribute>, is_async: bool, is_comptime: bool) -> ParseResult<Function> {
+    fn parse_function(
+        &mut self,
+        visibility: Visibility,
+        attributes: Vec<Attribute>,
+        is_async: bool,
+        is_comptime: bool,
+        optional_body: bool,
+    ) -> ParseResult<Function> {
+        let start = self.peek_span();
         self.expect(Token::Fn)?;
-        let name = self.expect_identifier()?;
+        let name = self.expect_member_name()?;
 
         // Parse generics
         let generics = self.parse_generics()?;
@@ -176,11 +500,20 @@ fn parse_function(&mut self, attributes: Vec<Attribute>, is_async: bool, is_comp
         // Parse contract (pre/post conditions)
         let contract = self.parse_contract()?;
 
-        // Parse body
-        let body = self.parse_block()?;
+        // Parse body; a trait method may end in `;` and leave it to each impl
+        let body = if optional_body && self.match_token(&Token::Semicolon) {
+            let span = self.prev_span();
+            Block { id: self.next_id(), span, stmts: Vec::new(), expr: None }
+        } else {
+            self.parse_block()?
+        };
 
+        let span = self.span_from(start);
         Ok(Function {
+            id: self.next_id(),
+            span,
             name,
+            visibility,
             generics,
             params,
             return_type,
@@ -204,6 +537,27 @@ fn parse_generics(&mut self) -> ParseResult<Vec<Generic>> {
                 break;
             }
 
+            // Lifetime parameters, such as `'a: 'b`, are only read by the borrow
+            // checker, off the reference types that name them
+            if self.match_token(&Token::Lifetime) {
+                self.parse_lifetime_name()?;
+                if self.match_token(&Token::Colon) {
+                    loop {
+                        self.expect(Token::Lifetime)?;
+                        self.parse_lifetime_name()?;
+                        if !self.match_token(&Token::Plus) {
+                            break;
+                        }
+                    }
+                }
+                if !self.match_token(&Token::Comma) {
+                    self.expect(Token::Greater)?;
+                    break;
+                }
+                continue;
+            }
+
+            let start = self.peek_span();
             let name = self.expect_identifier()?;
             let mut bounds = Vec::new();
 
@@ -217,7 +571,8 @@ fn parse_generics(&mut self) -> ParseResult<Vec<Generic>> {
                 }
             }
 
-            generics.push(Generic { name, bounds });
+            let span = self.span_from(start);
+            generics.push(Generic { id: self.next_id(), span, name, bounds });
 
             if !self.match_token(&Token::Comma) {
                 self.expect(Token::Greater)?;
@@ -227,16 +582,54 @@ fn parse_generics(&mut self) -> ParseResult<Vec<Generic>> {
         Ok(generics)
     }
 
+    /// The name after a `'`; `'static` is spelled with a keyword
+    fn parse_lifetime_name(&mut self) -> ParseResult<String> {
+        if self.match_token(&Token::Static) {
+            return Ok("static".to_string());
+        }
+        self.expect_identifier()
+    }
+
     fn parse_params(&mut self) -> ParseResult<Vec<Param>> {
         let mut params = Vec::new();
 
         while !matches!(self.peek(), Token::RightParen) {
+            let start = self.peek_span();
+
+            // `&self` and `&mut self`
+            if matches!(self.peek(), Token::Ampersand) {
+                self.advance();
+                let is_mut_ref = self.match_token(&Token::Mut);
+                if self.peek_text() != "self" {
+                    return Err(self.error(ParseErrorKind::MissingIdentifier));
+                }
+                let self_span = self.advance().span;
+                let self_ty = self.mk_type(TypeKind::Named("Self".to_string()), self_span);
+                let span = self.span_from(start);
+                let ty = self.mk_type(
+                    TypeKind::Reference { ty: Box::new(self_ty), is_mut: is_mut_ref, lifetime: None },
+                    span,
+                );
+                params.push(Param { id: self.next_id(), span, name: "self".to_string(), ty, is_mut: false });
+                if !self.match_token(&Token::Comma) {
+                    break;
+                }
+                continue;
+            }
+
             let is_mut = self.match_token(&Token::Mut);
             let name = self.expect_identifier()?;
-            self.expect(Token::Colon)?;
-            let ty = self.parse_type()?;
+            // `self` and `mut self` take `Self` by value unless a type is given
+            let ty = if name == "self" && !matches!(self.peek(), Token::Colon) {
+                let span = self.prev_span();
+                self.mk_type(TypeKind::Named("Self".to_string()), span)
+            } else {
+                self.expect(Token::Colon)?;
+                self.parse_type()?
+            };
 
-            params.push(Param { name, ty, is_mut });
+            let span = self.span_from(start);
+            params.push(Param { id: self.next_id(), span, name, ty, is_mut });
 
             if !self.match_token(&Token::Comma) {
                 break;
@@ -247,12 +640,14 @@ fn parse_params(&mut self) -> ParseResult<Vec<Param>> {
     }
 
     fn parse_where_clause(&mut self) -> ParseResult<Option<WhereClause>> {
+        let start = self.peek_span();
         if !self.match_token(&Token::Where) {
             return Ok(None);
         }
 
         let mut predicates = Vec::new();
         loop {
+            let pred_start = self.peek_span();
             let ty = self.parse_type()?;
             self.expect(Token::Colon)?;
 
@@ -264,17 +659,20 @@ fn parse_where_clause(&mut self) -> ParseResult<Option<WhereClause>> {
                 }
             }
 
-            predicates.push(WherePredicate { ty, bounds });
+            let span = self.span_from(pred_start);
+            predicates.push(WherePredicate { id: self.next_id(), span, ty, bounds });
 
             if !self.match_token(&Token::Comma) {
                 break;
             }
         }
 
-        Ok(Some(WhereClause { predicates }))
+        let span = self.span_from(start);
+        Ok(Some(WhereClause { id: self.next_id(), span, predicates }))
     }
 
     fn parse_contract(&mut self) -> ParseResult<Option<Contract>> {
+        let start = self.peek_span();
         let mut preconditions = Vec::new();
         let mut postconditions = Vec::new();
         let mut invariants = Vec::new();
@@ -283,15 +681,15 @@ fn parse_contract(&mut self) -> ParseResult<Option<Contract>> {
             match self.peek() {
                 Token::Pre | Token::Requires => {
                     self.advance();
-                    preconditions.push(self.parse_expression()?);
+                    preconditions.push(self.parse_condition()?);
                 }
                 Token::Post | Token::Ensures => {
                     self.advance();
-                    postconditions.push(self.parse_expression()?);
+                    postconditions.push(self.parse_condition()?);
                 }
                 Token::Invariant => {
                     self.advance();
-                    invariants.push(self.parse_expression()?);
+                    invariants.push(self.parse_condition()?);
                 }
                 _ => break,
             }
@@ -300,33 +698,30 @@ fn parse_contract(&mut self) -> ParseResult<Option<Contract>> {
         if preconditions.is_empty() && postconditions.is_empty() && invariants.is_empty() {
             Ok(None)
         } else {
-            Ok(Some(Contract { preconditions, postconditions, invariants }))
+            let span = self.span_from(start);
+            Ok(Some(Contract { id: self.next_id(), span, preconditions, postconditions, invariants }))
         }
     }
 
     // ========== Struct Parsing ==========
 
-    fn parse_struct(&mut self) -> ParseResult<Struct> {
-        self.expect(Token::Struct)?;
-        let name = self.expect_identifier()?;
-        let generics = self.parse_generics()?;
-        let where_clause = self.parse_where_clause()?;
-
-        self.expect(Token::LeftBrace)?;
+    /// Parse a `{ name: Type, ... }` field list after the opening brace
+    fn parse_fields(&mut self, default_visibility: Visibility) -> ParseResult<Vec<Field>> {
         let mut fields = Vec::new();
 
         while !self.match_token(&Token::RightBrace) {
-            let visibility = if self.match_token(&Token::Pub) {
-                Visibility::Public
-            } else {
-                Visibility::Private
+            let start = self.peek_span();
+            let visibility = match self.parse_visibility() {
+                Visibility::Private => default_visibility,
+                visibility => visibility,
             };
 
             let field_name = self.expect_identifier()?;
             self.expect(Token::Colon)?;
             let ty = self.parse_type()?;
 
-            fields.push(Field { name: field_name, ty, visibility });
+            let span = self.span_from(start);
+            fields.push(Field { id: self.next_id(), span, name: field_name, ty, visibility });
 
             if !self.match_token(&Token::Comma) {
                 self.expect(Token::RightBrace)?;
@@ -334,12 +729,27 @@ fn parse_struct(&mut self) -> ParseResult<Struct> {
             }
         }
 
-        Ok(Struct { name, generics, fields, where_clause })
+        Ok(fields)
+    }
+
+    fn parse_struct(&mut self, visibility: Visibility) -> ParseResult<Struct> {
+        let start = self.peek_span();
+        self.expect(Token::Struct)?;
+        let name = self.expect_identifier()?;
+        let generics = self.parse_generics()?;
+        let where_clause = self.parse_where_clause()?;
+
+        self.expect(Token::LeftBrace)?;
+        let fields = self.parse_fields(Visibility::Private)?;
+
+        let span = self.span_from(start);
+        Ok(Struct { id: self.next_id(), span, name, visibility, generics, fields, where_clause })
     }
 
     // ========== Enum Parsing ==========
 
-    fn parse_enum(&mut self) -> ParseResult<Enum> {
+    fn parse_enum(&mut self, visibility: Visibility) -> ParseResult<Enum> {
+        let start = self.peek_span();
         self.expect(Token::Enum)?;
         let name = self.expect_identifier()?;
         let generics = self.parse_generics()?;
@@ -348,6 +758,7 @@ fn parse_enum(&mut self) -> ParseResult<Enum> {
         let mut variants = Vec::new();
 
         while !self.match_token(&Token::RightBrace) {
+            let variant_start = self.peek_span();
             let variant_name = self.expect_identifier()?;
 
             let data = if self.match_token(&Token::LeftParen) {
@@ -363,23 +774,13 @@ fn parse_enum(&mut self) -> ParseResult<Enum> {
                 VariantData::Tuple(types)
             } else if self.match_token(&Token::LeftBrace) {
                 // Struct variant
-                let mut fields = Vec::new();
-                while !self.match_token(&Token::RightBrace) {
-                    let field_name = self.expect_identifier()?;
-                    self.expect(Token::Colon)?;
-                    let ty = self.parse_type()?;
-                    fields.push(Field { name: field_name, ty, visibility: Visibility::Public });
-                    if !self.match_token(&Token::Comma) {
-                        self.expect(Token::RightBrace)?;
-                        break;
-                    }
-                }
-                VariantData::Struct(fields)
+                VariantData::Struct(self.parse_fields(Visibility::Public)?)
             } else {
                 VariantData::Unit
             };
 
-            variants.push(Variant { name: variant_name, data });
+            let span = self.span_from(variant_start);
+            variants.push(Variant { id: self.next_id(), span, name: variant_name, data });
 
             if !self.match_token(&Token::Comma) {
                 self.expect(Token::RightBrace)?;
@@ -387,12 +788,14 @@ fn parse_enum(&mut self) -> ParseResult<Enum> {
             }
         }
 
-        Ok(Enum { name, generics, variants })
+        let span = self.span_from(start);
+        Ok(Enum { id: self.next_id(), span, name, visibility, generics, variants })
     }
 
     // ========== Trait Parsing ==========
 
-    fn parse_trait(&mut self) -> ParseResult<Trait> {
+    fn parse_trait(&mut self, visibility: Visibility) -> ParseResult<Trait> {
+        let start = self.peek_span();
         self.expect(Token::Trait)?;
         let name = self.expect_identifier()?;
         let generics = self.parse_generics()?;
@@ -401,31 +804,48 @@ fn parse_trait(&mut self) -> ParseResult<Trait> {
         let mut items = Vec::new();
 
         while !self.match_token(&Token::RightBrace) {
-            match self.peek() {
-                Token::Fn => {
-                    items.push(TraitItem::Function(
-                        self.parse_function(Vec::new(), false, false)?
-                    ));
-                }
-                Token::Type => {
-                    self.advance();
-                    let type_name = self.expect_identifier()?;
-                    self.expect(Token::Semicolon)?;
-                    items.push(TraitItem::Type(type_name));
-                }
-                Token::Const => {
-                    items.push(TraitItem::Const(self.parse_const()?));
+            if self.is_at_end() {
+                self.expect(Token::RightBrace)?;
+            }
+            let item_pos = self.pos;
+            match self.parse_trait_item() {
+                Ok(item) => items.push(item),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize(item_pos, Recovery::Nested);
                 }
-                _ => return Err(ParseError::InvalidItem),
             }
         }
 
-        Ok(Trait { name, generics, items })
+        let span = self.span_from(start);
+        Ok(Trait { id: self.next_id(), span, name, visibility, generics, items })
+    }
+
+    fn parse_trait_item(&mut self) -> ParseResult<TraitItem> {
+        match self.peek() {
+            Token::Fn => {
+                let func = self.parse_function(Visibility::Public, Vec::new(), false, false, true)?;
+                if matches!(self.tokens.get(self.pos - 1).map(|t| &t.token), Some(Token::Semicolon)) {
+                    Ok(TraitItem::Signature(func))
+                } else {
+                    Ok(TraitItem::Function(func))
+                }
+            }
+            Token::Type => {
+                self.advance();
+                let type_name = self.expect_identifier()?;
+                self.expect(Token::Semicolon)?;
+                Ok(TraitItem::Type(type_name))
+            }
+            Token::Const => Ok(TraitItem::Const(self.parse_const(Visibility::Public)?)),
+            _ => Err(self.error(ParseErrorKind::InvalidItem(self.found()))),
+        }
     }
 
     // ========== Impl Parsing ==========
 
     fn parse_impl(&mut self) -> ParseResult<Impl> {
+        let start = self.peek_span();
         self.expect(Token::Impl)?;
         let generics = self.parse_generics()?;
 
@@ -433,79 +853,147 @@ fn parse_impl(&mut self) -> ParseResult<Impl> {
         let first_type = self.parse_type()?;
 
         let (trait_name, self_ty) = if self.match_token(&Token::For) {
-            let ty_name = match &first_type {
-                Type::Named(n) => Some(n.clone()),
+            let ty_name = match &first_type.kind {
+                TypeKind::Named(n) => Some(n.clone()),
                 _ => None,
             };
             (ty_name, self.parse_type()?)
         } else {
             (None, first_type)
         };
+        let contract = self.parse_impl_invariants()?;
 
         self.expect(Token::LeftBrace)?;
         let mut items = Vec::new();
 
         while !self.match_token(&Token::RightBrace) {
-            let is_pub = self.match_token(&Token::Pub);
-            match self.peek() {
-                Token::Fn | Token::Async => {
-                    let is_async = self.match_token(&Token::Async);
-                    items.push(ImplItem::Function(
-                        self.parse_function(Vec::new(), is_async, false)?
-                    ));
-                }
-                Token::Type => {
-                    items.push(ImplItem::Type(self.parse_type_alias()?));
-                }
-                Token::Const => {
-                    items.push(ImplItem::Const(self.parse_const()?));
+            if self.is_at_end() {
+                self.expect(Token::RightBrace)?;
+            }
+            let item_pos = self.pos;
+            match self.parse_impl_item() {
+                Ok(item) => items.push(item),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize(item_pos, Recovery::Nested);
                 }
-                _ => return Err(ParseError::InvalidItem),
             }
         }
 
-        Ok(Impl { generics, trait_name, self_ty, items })
+        let span = self.span_from(start);
+        Ok(Impl { id: self.next_id(), span, generics, trait_name, self_ty, contract, items })
+    }
+
+    /// `where invariant cond invariant cond ...` after an impl's self type
+    fn parse_impl_invariants(&mut self) -> ParseResult<Option<Contract>> {
+        let start = self.peek_span();
+        if !self.match_token(&Token::Where) {
+            return Ok(None);
+        }
+        if !matches!(self.peek(), Token::Invariant) {
+            return Err(self.error(ParseErrorKind::InvalidContract("expected `invariant` after `where`".to_string())));
+        }
+        let mut invariants = Vec::new();
+        while self.match_token(&Token::Invariant) {
+            invariants.push(self.parse_condition()?);
+            self.match_token(&Token::Comma);
+        }
+        if matches!(self.peek(), Token::Pre | Token::Requires | Token::Post | Token::Ensures) {
+            let msg = "impl blocks only take `invariant` clauses; put pre- and postconditions on methods";
+            return Err(self.error(ParseErrorKind::InvalidContract(msg.to_string())));
+        }
+        let span = self.span_from(start);
+        let (preconditions, postconditions) = (Vec::new(), Vec::new());
+        Ok(Some(Contract { id: self.next_id(), span, preconditions, postconditions, invariants }))
+    }
+
+    fn parse_impl_item(&mut self) -> ParseResult<ImplItem> {
+        let visibility = self.parse_visibility();
+        match self.peek() {
+            Token::Fn | Token::Async => {
+                let is_async = self.match_token(&Token::Async);
+                Ok(ImplItem::Function(self.parse_function(visibility, Vec::new(), is_async, false, false)?))
+            }
+            Token::Type => Ok(ImplItem::Type(self.parse_type_alias(visibility)?)),
+            Token::Const => Ok(ImplItem::Const(self.parse_const(visibility)?)),
+            _ => Err(self.error(ParseErrorKind::InvalidItem(self.found()))),
+        }
     }
 
     // ========== Module/Import Parsing ==========
 
-    fn parse_module(&mut self) -> ParseResult<Module> {
+    fn parse_module(&mut self, visibility: Visibility) -> ParseResult<Module> {
+        let start = self.peek_span();
         self.expect(Token::Mod)?;
         let name = self.expect_identifier()?;
 
+        // `mod name;` declares a module whose items live in another file
+        if self.match_token(&Token::Semicolon) {
+            let span = self.span_from(start);
+            return Ok(Module { id: self.next_id(), span, name, visibility, inline: false, items: Vec::new() });
+        }
+
         self.expect(Token::LeftBrace)?;
         let mut items = Vec::new();
 
         while !self.match_token(&Token::RightBrace) {
-            items.push(self.parse_item()?);
+            if self.is_at_end() {
+                self.expect(Token::RightBrace)?;
+            }
+            items.push(self.parse_item_recovering(Recovery::Nested));
         }
 
-        Ok(Module { name, items })
+        let span = self.span_from(start);
+        Ok(Module { id: self.next_id(), span, name, visibility, inline: true, items })
     }
 
-    fn parse_import(&mut self) -> ParseResult<Import> {
+    fn parse_import(&mut self, visibility: Visibility) -> ParseResult<Import> {
+        let start = self.peek_span();
         self.advance(); // consume 'import' or 'use'
 
-        let mut path = Vec::new();
-        path.push(self.expect_identifier()?);
+        let tree = self.parse_import_tree()?;
 
-        while self.match_token(&Token::ColonColon) {
-            path.push(self.expect_identifier()?);
-        }
+        self.expect(Token::Semicolon)?;
+        let span = self.span_from(start);
+        Ok(Import { id: self.next_id(), span, visibility, tree })
+    }
 
-        let alias = if self.match_token(&Token::As) {
-            Some(self.expect_identifier()?)
-        } else {
-            None
+    /// Parse `a::b`, `a::b as c` or `a::{...}`, where groups may nest
+    fn parse_import_tree(&mut self) -> ParseResult<ImportTree> {
+        let start = self.peek_span();
+        let mut prefix = vec![self.expect_identifier()?];
+
+        let kind = loop {
+            if !self.match_token(&Token::ColonColon) {
+                let alias = if self.match_token(&Token::As) {
+                    Some(self.expect_identifier()?)
+                } else {
+                    None
+                };
+                break ImportTreeKind::Simple { alias };
+            }
+            if self.match_token(&Token::LeftBrace) {
+                let mut trees = Vec::new();
+                while !self.match_token(&Token::RightBrace) {
+                    trees.push(self.parse_import_tree()?);
+                    if !self.match_token(&Token::Comma) {
+                        self.expect(Token::RightBrace)?;
+                        break;
+                    }
+                }
+                break ImportTreeKind::Group(trees);
+            }
+            prefix.push(self.expect_identifier()?);
         };
 
-        self.expect(Token::Semicolon)?;
-        Ok(Import { path, alias })
+        let span = self.span_from(start);
+        Ok(ImportTree { id: self.next_id(), span, prefix, kind })
     }
 
     // ========== Const/Type Alias Parsing ==========
 
-    fn parse_const(&mut self) -> ParseResult<Const> {
+    fn parse_const(&mut self, visibility: Visibility) -> ParseResult<Const> {
+        let start = self.peek_span();
         self.expect(Token::Const)?;
         let name = self.expect_identifier()?;
         self.expect(Token::Colon)?;
@@ -514,10 +1002,12 @@ fn parse_const(&mut self) -> ParseResult<Const> {
         let value = self.parse_expression()?;
         self.expect(Token::Semicolon)?;
 
-        Ok(Const { name, ty, value })
+        let span = self.span_from(start);
+        Ok(Const { id: self.next_id(), span, name, visibility, ty, value })
     }
 
-    fn parse_type_alias(&mut self) -> ParseResult<TypeAlias> {
+    fn parse_type_alias(&mut self, visibility: Visibility) -> ParseResult<TypeAlias> {
+        let start = self.peek_span();
         self.expect(Token::Type)?;
         let name = self.expect_identifier()?;
         let generics = self.parse_generics()?;
@@ -525,12 +1015,14 @@ fn parse_type_alias(&mut self) -> ParseResult<TypeAlias> {
         let ty = self.parse_type()?;
         self.expect(Token::Semicolon)?;
 
-        Ok(TypeAlias { name, generics, ty })
+        let span = self.span_from(start);
+        Ok(TypeAlias { id: self.next_id(), span, name, visibility, generics, ty })
     }
 
     // ========== Agent/Workflow Parsing (Ensemble) ==========
 
-    fn parse_agent(&mut self) -> ParseResult<Agent> {
+    fn parse_agent(&mut self, visibility: Visibility) -> ParseResult<Agent> {
+        let start = self.peek_span();
         self.expect(Token::Agent)?;
         let name = self.expect_identifier()?;
         self.expect(Token::LeftBrace)?;
@@ -546,10 +1038,14 @@ fn parse_agent(&mut self) -> ParseResult<Agent> {
                     self.advance();
                     self.expect(Token::LeftBrace)?;
                     while !self.match_token(&Token::RightBrace) {
+                        let field_start = self.peek_span();
                         let field_name = self.expect_identifier()?;
                         self.expect(Token::Colon)?;
                         let ty = self.parse_type()?;
+                        let span = self.span_from(field_start);
                         state.push(StateField {
+                            id: self.next_id(),
+                            span,
                             name: field_name,
                             ty,
                             persistence: Persistence::Persistent,
@@ -561,13 +1057,17 @@ fn parse_agent(&mut self) -> ParseResult<Agent> {
                     self.advance();
                     self.expect(Token::LeftBrace)?;
                     while !self.match_token(&Token::RightBrace) {
+                        let cap_start = self.peek_span();
                         let cap_name = self.expect_identifier()?;
                         self.expect(Token::LeftParen)?;
                         let params = self.parse_params()?;
                         self.expect(Token::RightParen)?;
                         self.expect(Token::Arrow)?;
                         let return_type = self.parse_type()?;
+                        let span = self.span_from(cap_start);
                         capabilities.push(Capability {
+                            id: self.next_id(),
+                            span,
                             name: cap_name,
                             params,
                             return_type,
@@ -580,10 +1080,14 @@ fn parse_agent(&mut self) -> ParseResult<Agent> {
                     self.advance();
                     self.expect(Token::LeftBrace)?;
                     while !self.match_token(&Token::RightBrace) {
+                        let goal_start = self.peek_span();
                         let goal_name = self.expect_identifier()?;
                         self.expect(Token::Colon)?;
                         let expr = self.parse_expression()?;
+                        let span = self.span_from(goal_start);
                         goals.push(Goal {
+                            id: self.next_id(),
+                            span,
                             name: goal_name,
                             expr,
                             priority: Priority::Medium,
@@ -595,21 +1099,24 @@ fn parse_agent(&mut self) -> ParseResult<Agent> {
                     self.advance();
                     self.expect(Token::LeftBrace)?;
                     while !self.match_token(&Token::RightBrace) {
+                        // A handler without a pattern takes any message; the pattern is where the handler starts
+                        let at = self.peek_span();
+                        let pattern_span = Span { end: at.start, ..at };
                         let handler = self.parse_block()?;
-                        communication.push(CommunicationHandler {
-                            pattern: MessagePattern::Any,
-                            handler,
-                        });
+                        let pattern = MessagePattern::new(self.next_id(), MessagePatternKind::Any, pattern_span);
+                        communication.push(CommunicationHandler { id: self.next_id(), span: handler.span, pattern, handler });
                     }
                 }
-                _ => return Err(ParseError::InvalidItem),
+                _ => return Err(self.error(ParseErrorKind::InvalidItem(self.found()))),
             }
         }
 
-        Ok(Agent { name, state, capabilities, goals, communication })
+        let span = self.span_from(start);
+        Ok(Agent { id: self.next_id(), span, name, visibility, state, capabilities, goals, communication })
     }
 
-    fn parse_workflow(&mut self) -> ParseResult<Workflow> {
+    fn parse_workflow(&mut self, visibility: Visibility) -> ParseResult<Workflow> {
+        let start = self.peek_span();
         self.expect(Token::Workflow)?;
         let name = self.expect_identifier()?;
         self.expect(Token::LeftBrace)?;
@@ -619,29 +1126,39 @@ fn parse_workflow(&mut self) -> ParseResult<Workflow> {
 
         while !self.match_token(&Token::RightBrace) {
             // Simple: just parse agent names for now
+            let stage_start = self.peek_span();
             let agent_name = self.expect_identifier()?;
-            stages.push(Stage::Agent(agent_name));
+            let span = self.span_from(stage_start);
+            stages.push(Stage::new(self.next_id(), StageKind::Agent(agent_name), span));
             self.match_token(&Token::Arrow);
         }
 
-        Ok(Workflow { name, stages, coordination })
+        let span = self.span_from(start);
+        Ok(Workflow { id: self.next_id(), span, name, visibility, stages, coordination })
     }
 
     // ========== Block & Statement Parsing ==========
 
     fn parse_block(&mut self) -> ParseResult<Block> {
+        self.with_struct_literals(true, Self::parse_block_inner)
+    }
+
+    fn parse_block_inner(&mut self) -> ParseResult<Block> {
+        let start = self.peek_span();
         self.expect(Token::LeftBrace)?;
 
         let mut stmts = Vec::new();
         let mut expr = None;
 
-        while !matches!(self.peek(), Token::RightBrace) {
+        while !matches!(self.peek(), Token::RightBrace | Token::Eof) {
             // Check if this might be a trailing expression
-            let stmt_or_expr = self.parse_statement()?;
+            let stmt_or_expr = self.parse_statement_recovering();
 
-            if matches!(self.peek(), Token::RightBrace) {
-                // Last item - could be trailing expression
-                if let Statement::Expression(e) = stmt_or_expr {
+            // A final expression is the block's value unless a `;` discards it
+            let terminated = self.pos > 0
+                && matches!(self.tokens.get(self.pos - 1).map(|t| &t.token), Some(Token::Semicolon));
+            if matches!(self.peek(), Token::RightBrace) && !terminated {
+                if let StatementKind::Expression(e) = stmt_or_expr.kind {
                     expr = Some(Box::new(e));
                 } else {
                     stmts.push(stmt_or_expr);
@@ -652,28 +1169,37 @@ fn parse_block(&mut self) -> ParseResult<Block> {
         }
 
         self.expect(Token::RightBrace)?;
-        Ok(Block { stmts, expr })
+        let span = self.span_from(start);
+        Ok(Block { id: self.next_id(), span, stmts, expr })
     }
 
     fn parse_statement(&mut self) -> ParseResult<Statement> {
+        let start = self.peek_span();
         match self.peek() {
             Token::Let => self.parse_let_statement(),
             Token::Fn | Token::Struct | Token::Enum | Token::Trait | Token::Impl
-            | Token::Mod | Token::Const | Token::Type => {
-                Ok(Statement::Item(Box::new(self.parse_item()?)))
-            }
+            | Token::Mod | Token::Const | Token::Type => self.parse_item_statement(),
+            Token::Comptime if matches!(self.peek_next(), Token::Fn) => self.parse_item_statement(),
             _ => {
                 let expr = self.parse_expression()?;
                 // Expression statements end with semicolon, trailing expressions don't
                 if self.match_token(&Token::Semicolon) {
                     // It's a statement
                 }
-                Ok(Statement::Expression(expr))
+                let span = self.span_from(start);
+                Ok(self.mk_stmt(StatementKind::Expression(expr), span))
             }
         }
     }
 
+    fn parse_item_statement(&mut self) -> ParseResult<Statement> {
+        let item = self.parse_item()?;
+        let span = item.span;
+        Ok(self.mk_stmt(StatementKind::Item(Box::new(item)), span))
+    }
+
     fn parse_let_statement(&mut self) -> ParseResult<Statement> {
+        let start = self.peek_span();
         self.expect(Token::Let)?;
         let is_mut = self.match_token(&Token::Mut);
         let pattern = self.parse_pattern()?;
@@ -692,7 +1218,8 @@ fn parse_let_statement(&mut self) -> ParseResult<Statement> {
 
         self.expect(Token::Semicolon)?;
 
-        Ok(Statement::Let { pattern, ty, init, is_mut })
+        let span = self.span_from(start);
+        Ok(self.mk_stmt(StatementKind::Let { pattern, ty, init, is_mut }, span))
     }
 
     // ========== Expression Parsing (Pratt Parser) ==========
@@ -716,110 +1243,109 @@ fn parse_expression_with_precedence(&mut self, min_prec: Precedence) -> ParseRes
         Ok(left)
     }
 
+    /// Parse the operand of a prefix operator and wrap it in a unary expression
+    fn parse_unary(&mut self, op: UnaryOp, start: Span) -> ParseResult<Expression> {
+        let expr = self.parse_expression_with_precedence(Precedence::Unary)?;
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::Unary { op, expr: Box::new(expr) }, span))
+    }
+
     fn parse_prefix(&mut self) -> ParseResult<Expression> {
-        match self.peek().clone() {
+        let start = self.peek_span();
+        let kind = match self.peek().clone() {
             // Literals
             Token::IntLiteral => {
-                let text = self.advance().1;
-                let value: i64 = text.parse().map_err(|_| ParseError::InvalidLiteral(text))?;
-                Ok(Expression::Literal(Literal::Int(value)))
+                let SpannedToken { text, span, .. } = self.advance();
+                let value: i64 = text.parse()
+                    .map_err(|_| ParseError::new(ParseErrorKind::InvalidLiteral(text), span))?;
+                ExpressionKind::Literal(Literal::Int(value))
             }
             Token::FloatLiteral => {
-                let text = self.advance().1;
-                let value: f64 = text.parse().map_err(|_| ParseError::InvalidLiteral(text))?;
-                Ok(Expression::Literal(Literal::Float(value)))
+                let SpannedToken { text, span, .. } = self.advance();
+                let value: f64 = text.parse()
+                    .map_err(|_| ParseError::new(ParseErrorKind::InvalidLiteral(text), span))?;
+                ExpressionKind::Literal(Literal::Float(value))
             }
             Token::StringLiteral => {
-                let text = self.advance().1;
+                let text = self.advance().text;
                 // Remove quotes
                 let content = text[1..text.len()-1].to_string();
-                Ok(Expression::Literal(Literal::String(content)))
+                ExpressionKind::Literal(Literal::String(content))
             }
             Token::CharLiteral => {
-                let text = self.advance().1;
+                let text = self.advance().text;
                 let ch = text.chars().nth(1).unwrap_or('\0');
-                Ok(Expression::Literal(Literal::Char(ch)))
+                ExpressionKind::Literal(Literal::Char(ch))
             }
             Token::True => {
                 self.advance();
-                Ok(Expression::Literal(Literal::Bool(true)))
+                ExpressionKind::Literal(Literal::Bool(true))
             }
             Token::False => {
                 self.advance();
-                Ok(Expression::Literal(Literal::Bool(false)))
+                ExpressionKind::Literal(Literal::Bool(false))
             }
 
             // Identifiers
             Token::Identifier => {
-                let name = self.advance().1;
+                let first = self.advance().text;
+                let mut path = self.parse_path_rest(first);
 
                 // Check for struct literal
-                if self.match_token(&Token::LeftBrace) {
-                    let fields = self.parse_struct_fields()?;
-                    Ok(Expression::Struct { name, fields })
+                if !self.no_struct && matches!(self.peek(), Token::LeftBrace) {
+                    self.advance();
+                    let fields = self.with_struct_literals(true, Self::parse_struct_fields)?;
+                    ExpressionKind::Struct { path, fields }
+                } else if path.len() == 1 {
+                    ExpressionKind::Identifier(path.remove(0))
                 } else {
-                    Ok(Expression::Identifier(name))
+                    ExpressionKind::Path(path)
                 }
             }
 
             // Unary operators
             Token::Minus => {
                 self.advance();
-                let expr = self.parse_expression_with_precedence(Precedence::Unary)?;
-                Ok(Expression::Unary {
-                    op: UnaryOp::Neg,
-                    expr: Box::new(expr),
-                })
+                return self.parse_unary(UnaryOp::Neg, start);
             }
             Token::Not => {
                 self.advance();
-                let expr = self.parse_expression_with_precedence(Precedence::Unary)?;
-                Ok(Expression::Unary {
-                    op: UnaryOp::Not,
-                    expr: Box::new(expr),
-                })
+                return self.parse_unary(UnaryOp::Not, start);
             }
             Token::Ampersand => {
                 self.advance();
                 let is_mut = self.match_token(&Token::Mut);
-                let expr = self.parse_expression_with_precedence(Precedence::Unary)?;
-                Ok(Expression::Unary {
-                    op: if is_mut { UnaryOp::RefMut } else { UnaryOp::Ref },
-                    expr: Box::new(expr),
-                })
+                return self.parse_unary(if is_mut { UnaryOp::RefMut } else { UnaryOp::Ref }, start);
             }
             Token::Star => {
                 self.advance();
-                let expr = self.parse_expression_with_precedence(Precedence::Unary)?;
-                Ok(Expression::Unary {
-                    op: UnaryOp::Deref,
-                    expr: Box::new(expr),
-                })
+                return self.parse_unary(UnaryOp::Deref, start);
             }
 
             // Grouping
             Token::LeftParen => {
                 self.advance();
                 if self.match_token(&Token::RightParen) {
-                    return Ok(Expression::Literal(Literal::Unit));
+                    let span = self.span_from(start);
+                    return Ok(self.mk_expr(ExpressionKind::Literal(Literal::Unit), span));
                 }
 
-                let expr = self.parse_expression()?;
+                let expr = self.with_struct_literals(true, Self::parse_expression)?;
 
                 // Check for tuple
                 if self.match_token(&Token::Comma) {
                     let mut elements = vec![expr];
                     while !self.match_token(&Token::RightParen) {
-                        elements.push(self.parse_expression()?);
+                        elements.push(self.with_struct_literals(true, Self::parse_expression)?);
                         if !self.match_token(&Token::Comma) {
                             self.expect(Token::RightParen)?;
                             break;
                         }
                     }
-                    Ok(Expression::Tuple(elements))
+                    ExpressionKind::Tuple(elements)
                 } else {
                     self.expect(Token::RightParen)?;
-                    Ok(expr)
+                    return Ok(expr);
                 }
             }
 
@@ -828,116 +1354,135 @@ fn parse_prefix(&mut self) -> ParseResult<Expression> {
                 self.advance();
                 let mut elements = Vec::new();
                 while !self.match_token(&Token::RightBracket) {
-                    elements.push(self.parse_expression()?);
+                    elements.push(self.with_struct_literals(true, Self::parse_expression)?);
                     if !self.match_token(&Token::Comma) {
                         self.expect(Token::RightBracket)?;
                         break;
                     }
                 }
-                Ok(Expression::Array(elements))
+                ExpressionKind::Array(elements)
             }
 
             // Block expression
             Token::LeftBrace => {
-                Ok(Expression::Block(self.parse_block()?))
+                ExpressionKind::Block(self.parse_block()?)
             }
 
             // Control flow
-            Token::If => self.parse_if_expression(),
-            Token::Match => self.parse_match_expression(),
-            Token::Loop => self.parse_loop_expression(),
-            Token::While => self.parse_while_expression(),
-            Token::For => self.parse_for_expression(),
-            Token::Return => self.parse_return_expression(),
-            Token::Break => self.parse_break_expression(),
+            Token::If => return self.parse_if_expression(),
+            Token::Match => return self.parse_match_expression(),
+            Token::Loop => return self.parse_loop_expression(),
+            Token::While => return self.parse_while_expression(),
+            Token::For => return self.parse_for_expression(),
+            Token::Return => return self.parse_return_expression(),
+            Token::Break => return self.parse_break_expression(),
             Token::Continue => {
                 self.advance();
-                Ok(Expression::Continue)
+                ExpressionKind::Continue
+            }
+
+            Token::Pipe | Token::Or | Token::Move => return self.parse_closure_expression(),
+
+            // `comptime` binds like a prefix operator: `comptime f(x) + 1` adds at run time
+            Token::Comptime => {
+                self.advance();
+                let expr = self.parse_expression_with_precedence(Precedence::Unary)?;
+                ExpressionKind::Comptime(Box::new(expr))
             }
 
             // Ensemble-specific
-            Token::Spawn => self.parse_spawn_expression(),
-            Token::Send => self.parse_send_expression(),
-            Token::Receive => self.parse_receive_expression(),
+            Token::Spawn => return self.parse_spawn_expression(),
+            Token::Send => return self.parse_send_expression(),
+            Token::Receive => return self.parse_receive_expression(),
 
-            _ => Err(ParseError::InvalidExpression),
-        }
+            _ => return Err(self.error(ParseErrorKind::InvalidExpression(self.found()))),
+        };
+
+        let span = self.span_from(start);
+        Ok(self.mk_expr(kind, span))
     }
 
     fn parse_infix(&mut self, left: Expression, prec: Precedence) -> ParseResult<Expression> {
-        match self.peek().clone() {
+        let start = left.span;
+        let kind = match self.peek().clone() {
             // Binary operators
             Token::Plus | Token::Minus | Token::Star | Token::Slash | Token::Percent
             | Token::EqualEqual | Token::NotEqual | Token::Less | Token::Greater
             | Token::LessEqual | Token::GreaterEqual | Token::And | Token::Or
             | Token::Ampersand | Token::Pipe | Token::Caret | Token::LeftShift | Token::RightShift
             | Token::Equal => {
-                let (op_token, _) = self.advance();
-                let op = token_to_binary_op(&op_token)
-                    .ok_or_else(|| ParseError::InvalidOperator(format!("{:?}", op_token)))?;
+                let SpannedToken { token: op_token, span: op_span, .. } = self.advance();
+                let op = token_to_binary_op(&op_token).ok_or_else(|| {
+                    ParseError::new(ParseErrorKind::InvalidOperator(format!("{:?}", op_token)), op_span)
+                })?;
 
+                // The right operand takes only tighter operators, or for
+                // assignment another assignment as well
                 let next_prec = if is_right_associative(&op_token) {
-                    prec
+                    Precedence::None
                 } else {
-                    prec.next()
+                    prec
                 };
 
                 let right = self.parse_expression_with_precedence(next_prec)?;
 
-                Ok(Expression::Binary {
+                ExpressionKind::Binary {
                     left: Box::new(left),
                     op,
                     right: Box::new(right),
-                })
+                }
             }
 
             // Function call
             Token::LeftParen => {
                 self.advance();
-                let args = self.parse_call_args()?;
+                let args = self.with_struct_literals(true, Self::parse_call_args)?;
                 self.expect(Token::RightParen)?;
-                Ok(Expression::Call {
+                ExpressionKind::Call {
                     func: Box::new(left),
                     args,
-                })
+                }
             }
 
             // Index
             Token::LeftBracket => {
                 self.advance();
-                let index = self.parse_expression()?;
+                let index = self.with_struct_literals(true, Self::parse_expression)?;
                 self.expect(Token::RightBracket)?;
-                Ok(Expression::Index {
+                ExpressionKind::Index {
                     expr: Box::new(left),
                     index: Box::new(index),
-                })
+                }
             }
 
             // Field access / method call
             Token::Dot => {
                 self.advance();
-                let field = self.expect_identifier()?;
+                let field = self.expect_member_name()?;
 
                 if self.match_token(&Token::LeftParen) {
                     // Method call
-                    let args = self.parse_call_args()?;
+                    let args = self.with_struct_literals(true, Self::parse_call_args)?;
                     self.expect(Token::RightParen)?;
-                    Ok(Expression::MethodCall {
+                    ExpressionKind::MethodCall {
                         receiver: Box::new(left),
                         method: field,
                         args,
-                    })
+                    }
                 } else {
                     // Field access
-                    Ok(Expression::Field {
+                    ExpressionKind::Field {
                         expr: Box::new(left),
                         field,
-                    })
+                    }
                 }
             }
 
-            _ => Err(ParseError::InvalidExpression),
-        }
+            _ => return Err(self.error(ParseErrorKind::InvalidExpression(self.found()))),
+        };
+
+        let span = self.span_from(start);
+        Ok(self.mk_expr(kind, span))
     }
 
     fn parse_call_args(&mut self) -> ParseResult<Vec<Expression>> {
@@ -954,9 +1499,15 @@ fn parse_call_args(&mut self) -> ParseResult<Vec<Expression>> {
     fn parse_struct_fields(&mut self) -> ParseResult<Vec<(String, Expression)>> {
         let mut fields = Vec::new();
         while !self.match_token(&Token::RightBrace) {
+            let start = self.peek_span();
             let name = self.expect_identifier()?;
-            self.expect(Token::Colon)?;
-            let value = self.parse_expression()?;
+            // `Point { x, y }` is shorthand for `Point { x: x, y: y }`
+            let value = if self.match_token(&Token::Colon) {
+                self.parse_expression()?
+            } else {
+                let span = self.span_from(start);
+                self.mk_expr(ExpressionKind::Identifier(name.clone()), span)
+            };
             fields.push((name, value));
             if !self.match_token(&Token::Comma) {
                 self.expect(Token::RightBrace)?;
@@ -969,8 +1520,9 @@ fn parse_struct_fields(&mut self) -> ParseResult<Vec<(String, Expression)>> {
     // ========== Control Flow Expressions ==========
 
     fn parse_if_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
         self.expect(Token::If)?;
-        let cond = self.parse_expression()?;
+        let cond = self.parse_condition()?;
         let then_block = self.parse_block()?;
 
         let else_block = if self.match_token(&Token::Else) {
@@ -978,6 +1530,8 @@ fn parse_if_expression(&mut self) -> ParseResult<Expression> {
                 // else if
                 let else_if = self.parse_if_expression()?;
                 Some(Block {
+                    id: self.next_id(),
+                    span: else_if.span,
                     stmts: vec![],
                     expr: Some(Box::new(else_if)),
                 })
@@ -988,20 +1542,23 @@ fn parse_if_expression(&mut self) -> ParseResult<Expression> {
             None
         };
 
-        Ok(Expression::If {
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::If {
             cond: Box::new(cond),
             then_block,
             else_block,
-        })
+        }, span))
     }
 
     fn parse_match_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
         self.expect(Token::Match)?;
-        let expr = self.parse_expression()?;
+        let expr = self.parse_condition()?;
         self.expect(Token::LeftBrace)?;
 
         let mut arms = Vec::new();
         while !self.match_token(&Token::RightBrace) {
+            let arm_start = self.peek_span();
             let pattern = self.parse_pattern()?;
 
             let guard = if self.match_token(&Token::If) {
@@ -1013,7 +1570,8 @@ fn parse_match_expression(&mut self) -> ParseResult<Expression> {
             self.expect(Token::FatArrow)?;
             let body = self.parse_expression()?;
 
-            arms.push(MatchArm { pattern, guard, body });
+            let span = self.span_from(arm_start);
+            arms.push(MatchArm { id: self.next_id(), span, pattern, guard, body });
 
             if !self.match_token(&Token::Comma) {
                 self.expect(Token::RightBrace)?;
@@ -1021,64 +1579,115 @@ fn parse_match_expression(&mut self) -> ParseResult<Expression> {
             }
         }
 
-        Ok(Expression::Match {
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::Match {
             expr: Box::new(expr),
             arms,
-        })
+        }, span))
     }
 
     fn parse_loop_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
         self.expect(Token::Loop)?;
         let body = self.parse_block()?;
-        Ok(Expression::Loop(body))
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::Loop(body), span))
     }
 
     fn parse_while_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
         self.expect(Token::While)?;
-        let cond = self.parse_expression()?;
+        let cond = self.parse_condition()?;
         let body = self.parse_block()?;
-        Ok(Expression::While {
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::While {
             cond: Box::new(cond),
             body,
-        })
+        }, span))
     }
 
     fn parse_for_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
         self.expect(Token::For)?;
         let pattern = self.parse_pattern()?;
         self.expect(Token::In)?;
-        let iter = self.parse_expression()?;
+        let iter = self.parse_condition()?;
         let body = self.parse_block()?;
-        Ok(Expression::For {
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::For {
             pattern,
             iter: Box::new(iter),
             body,
-        })
+        }, span))
     }
 
     fn parse_return_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
         self.expect(Token::Return)?;
         let value = if matches!(self.peek(), Token::Semicolon | Token::RightBrace) {
             None
         } else {
             Some(Box::new(self.parse_expression()?))
         };
-        Ok(Expression::Return(value))
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::Return(value), span))
+    }
+
+    fn parse_closure_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
+        let is_move = self.match_token(&Token::Move);
+        let mut params = Vec::new();
+        // `||` lexes as a single token
+        if !self.match_token(&Token::Or) {
+            self.expect(Token::Pipe)?;
+            while !self.match_token(&Token::Pipe) {
+                let param_start = self.peek_span();
+                let is_mut = self.match_token(&Token::Mut);
+                let name = self.expect_identifier()?;
+                let ty = if self.match_token(&Token::Colon) {
+                    self.parse_type()?
+                } else {
+                    let span = self.prev_span();
+                    self.mk_type(TypeKind::Inferred, span)
+                };
+                let span = self.span_from(param_start);
+                params.push(Param { id: self.next_id(), span, name, ty, is_mut });
+                if !self.match_token(&Token::Comma) {
+                    self.expect(Token::Pipe)?;
+                    break;
+                }
+            }
+        }
+        // With a return type the body must be a block, as `|x| -> i32 x` would be ambiguous
+        let (ret, body) = if self.match_token(&Token::Arrow) {
+            let ret = self.parse_type()?;
+            let body_start = self.peek_span();
+            let block = self.parse_block()?;
+            let span = self.span_from(body_start);
+            (Some(ret), self.mk_expr(ExpressionKind::Block(block), span))
+        } else {
+            (None, self.parse_expression()?)
+        };
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::Closure { params, ret, body: Box::new(body), is_move }, span))
     }
 
     fn parse_break_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
         self.expect(Token::Break)?;
         let value = if matches!(self.peek(), Token::Semicolon | Token::RightBrace) {
             None
         } else {
             Some(Box::new(self.parse_expression()?))
         };
-        Ok(Expression::Break(value))
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::Break(value), span))
     }
 
     // ========== Ensemble Expressions ==========
 
     fn parse_spawn_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
         self.expect(Token::Spawn)?;
         let agent = self.expect_identifier()?;
         let config = if self.match_token(&Token::LeftBrace) {
@@ -1086,10 +1695,12 @@ fn parse_spawn_expression(&mut self) -> ParseResult<Expression> {
         } else {
             Vec::new()
         };
-        Ok(Expression::Spawn { agent, config })
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::Spawn { agent, config }, span))
     }
 
     fn parse_send_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
         self.expect(Token::Send)?;
         let message = self.parse_expression()?;
         // Expect 'to' keyword (using identifier check)
@@ -1097,48 +1708,77 @@ fn parse_send_expression(&mut self) -> ParseResult<Expression> {
             self.advance();
         }
         let recipient = self.parse_expression()?;
-        Ok(Expression::Send {
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::Send {
             message: Box::new(message),
             recipient: Box::new(recipient),
-        })
+        }, span))
     }
 
     fn parse_receive_expression(&mut self) -> ParseResult<Expression> {
+        let start = self.peek_span();
         self.expect(Token::Receive)?;
         let filter = None; // Simplified
         let timeout = None;
-        Ok(Expression::Receive { filter, timeout })
+        let span = self.span_from(start);
+        Ok(self.mk_expr(ExpressionKind::Receive { filter, timeout }, span))
     }
 
     // ========== Type Parsing ==========
 
     fn parse_type(&mut self) -> ParseResult<Type> {
+        let start = self.peek_span();
+
         // Check for reference types
         if self.match_token(&Token::Ampersand) {
             let is_mut = self.match_token(&Token::Mut);
             let lifetime = if self.match_token(&Token::Lifetime) {
-                Some(self.expect_identifier()?)
+                Some(self.parse_lifetime_name()?)
             } else {
                 None
             };
             let ty = self.parse_type()?;
-            return Ok(Type::Reference {
+            let span = self.span_from(start);
+            return Ok(self.mk_type(TypeKind::Reference {
                 ty: Box::new(ty),
                 is_mut,
                 lifetime,
-            });
+            }, span));
+        }
+
+        // `fn(A, B) -> R`, the type of functions and closures
+        if self.match_token(&Token::Fn) {
+            self.expect(Token::LeftParen)?;
+            let mut params = Vec::new();
+            while !self.match_token(&Token::RightParen) {
+                params.push(self.parse_type()?);
+                if !self.match_token(&Token::Comma) {
+                    self.expect(Token::RightParen)?;
+                    break;
+                }
+            }
+            let ret = if self.match_token(&Token::Arrow) {
+                self.parse_type()?
+            } else {
+                let span = self.prev_span();
+                self.mk_type(TypeKind::Primitive(PrimitiveType::Unit), span)
+            };
+            let span = self.span_from(start);
+            return Ok(self.mk_type(TypeKind::Function { params, ret: Box::new(ret) }, span));
         }
 
         // Check for affine type
         if self.match_token(&Token::Affine) {
             let ty = self.parse_type()?;
-            return Ok(Type::Affine(Box::new(ty)));
+            let span = self.span_from(start);
+            return Ok(self.mk_type(TypeKind::Affine(Box::new(ty)), span));
         }
 
         // Check for tuple type
         if self.match_token(&Token::LeftParen) {
             if self.match_token(&Token::RightParen) {
-                return Ok(Type::Primitive(PrimitiveType::Unit));
+                let span = self.span_from(start);
+                return Ok(self.mk_type(TypeKind::Primitive(PrimitiveType::Unit), span));
             }
 
             let first = self.parse_type()?;
@@ -1151,7 +1791,8 @@ fn parse_type(&mut self) -> ParseResult<Type> {
                         break;
                     }
                 }
-                return Ok(Type::Tuple(types));
+                let span = self.span_from(start);
+                return Ok(self.mk_type(TypeKind::Tuple(types), span));
             }
             self.expect(Token::RightParen)?;
             return Ok(first);
@@ -1161,70 +1802,79 @@ fn parse_type(&mut self) -> ParseResult<Type> {
         if self.match_token(&Token::LeftBracket) {
             let elem = self.parse_type()?;
             let size = if self.match_token(&Token::Semicolon) {
-                if let Token::IntLiteral = self.peek() {
-                    let text = self.advance().1;
-                    Some(text.parse().unwrap_or(0))
-                } else {
-                    None
-                }
+                Some(Box::new(self.with_struct_literals(true, Self::parse_expression)?))
             } else {
                 None
             };
             self.expect(Token::RightBracket)?;
-            return Ok(Type::Array {
+            let span = self.span_from(start);
+            return Ok(self.mk_type(TypeKind::Array {
                 elem: Box::new(elem),
                 size,
-            });
+            }, span));
         }
 
         // Primitive types
-        let ty = match self.peek() {
-            Token::I8 => { self.advance(); Type::Primitive(PrimitiveType::I8) }
-            Token::I16 => { self.advance(); Type::Primitive(PrimitiveType::I16) }
-            Token::I32 => { self.advance(); Type::Primitive(PrimitiveType::I32) }
-            Token::I64 => { self.advance(); Type::Primitive(PrimitiveType::I64) }
-            Token::I128 => { self.advance(); Type::Primitive(PrimitiveType::I128) }
-            Token::Isize => { self.advance(); Type::Primitive(PrimitiveType::Isize) }
-            Token::U8 => { self.advance(); Type::Primitive(PrimitiveType::U8) }
-            Token::U16 => { self.advance(); Type::Primitive(PrimitiveType::U16) }
-            Token::U32 => { self.advance(); Type::Primitive(PrimitiveType::U32) }
-            Token::U64 => { self.advance(); Type::Primitive(PrimitiveType::U64) }
-            Token::U128 => { self.advance(); Type::Primitive(PrimitiveType::U128) }
-            Token::Usize => { self.advance(); Type::Primitive(PrimitiveType::Usize) }
-            Token::F32 => { self.advance(); Type::Primitive(PrimitiveType::F32) }
-            Token::F64 => { self.advance(); Type::Primitive(PrimitiveType::F64) }
-            Token::Bool => { self.advance(); Type::Primitive(PrimitiveType::Bool) }
-            Token::Char => { self.advance(); Type::Primitive(PrimitiveType::Char) }
-            Token::Str => { self.advance(); Type::Primitive(PrimitiveType::Str) }
+        let kind = match self.peek() {
+            Token::I8 => { self.advance(); TypeKind::Primitive(PrimitiveType::I8) }
+            Token::I16 => { self.advance(); TypeKind::Primitive(PrimitiveType::I16) }
+            Token::I32 => { self.advance(); TypeKind::Primitive(PrimitiveType::I32) }
+            Token::I64 => { self.advance(); TypeKind::Primitive(PrimitiveType::I64) }
+            Token::I128 => { self.advance(); TypeKind::Primitive(PrimitiveType::I128) }
+            Token::Isize => { self.advance(); TypeKind::Primitive(PrimitiveType::Isize) }
+            Token::U8 => { self.advance(); TypeKind::Primitive(PrimitiveType::U8) }
+            Token::U16 => { self.advance(); TypeKind::Primitive(PrimitiveType::U16) }
+            Token::U32 => { self.advance(); TypeKind::Primitive(PrimitiveType::U32) }
+            Token::U64 => { self.advance(); TypeKind::Primitive(PrimitiveType::U64) }
+            Token::U128 => { self.advance(); TypeKind::Primitive(PrimitiveType::U128) }
+            Token::Usize => { self.advance(); TypeKind::Primitive(PrimitiveType::Usize) }
+            Token::F32 => { self.advance(); TypeKind::Primitive(PrimitiveType::F32) }
+            Token::F64 => { self.advance(); TypeKind::Primitive(PrimitiveType::F64) }
+            Token::Bool => { self.advance(); TypeKind::Primitive(PrimitiveType::Bool) }
+            Token::Char => { self.advance(); TypeKind::Primitive(PrimitiveType::Char) }
+            Token::Str => { self.advance(); TypeKind::Primitive(PrimitiveType::Str) }
             Token::Identifier => {
-                let name = self.advance().1;
+                let first = self.advance().text;
+                let mut path = self.parse_path_rest(first);
 
                 // Check for generic args
-                if self.match_token(&Token::Less) {
-                    let mut args = Vec::new();
+                let mut args = Vec::new();
+                let has_args = self.match_token(&Token::Less);
+                if has_args {
                     while !self.match_token(&Token::Greater) {
-                        args.push(self.parse_type()?);
+                        // Lifetime arguments are implied by the references a value holds
+                        if self.match_token(&Token::Lifetime) {
+                            self.parse_lifetime_name()?;
+                        } else {
+                            args.push(self.parse_type()?);
+                        }
                         if !self.match_token(&Token::Comma) {
                             self.expect(Token::Greater)?;
                             break;
                         }
                     }
-                    Type::Generic { name, args }
-                } else {
-                    Type::Named(name)
+                }
+
+                match (path.len(), has_args) {
+                    (1, false) => TypeKind::Named(path.remove(0)),
+                    (1, true) => TypeKind::Generic { name: path.remove(0), args },
+                    _ => TypeKind::Path { path, args },
                 }
             }
-            _ => return Err(ParseError::InvalidType(format!("{:?}", self.peek()))),
+            _ => return Err(self.error(ParseErrorKind::InvalidType(self.found()))),
         };
+        let span = self.span_from(start);
+        let ty = self.mk_type(kind, span);
 
         // Check for function type
         if self.match_token(&Token::Arrow) {
             let ret = self.parse_type()?;
-            if let Type::Tuple(params) = ty {
-                return Ok(Type::Function {
+            if let TypeKind::Tuple(params) = ty.kind {
+                let span = self.span_from(start);
+                return Ok(self.mk_type(TypeKind::Function {
                     params,
                     ret: Box::new(ret),
-                });
+                }, span));
             }
         }
 
@@ -1234,41 +1884,46 @@ fn parse_type(&mut self) -> ParseResult<Type> {
     // ========== Pattern Parsing ==========
 
     fn parse_pattern(&mut self) -> ParseResult<Pattern> {
-        match self.peek().clone() {
+        let start = self.peek_span();
+        let kind = match self.peek().clone() {
             Token::Underscore => {
                 self.advance();
-                Ok(Pattern::Wildcard)
+                PatternKind::Wildcard
             }
             Token::IntLiteral => {
-                let text = self.advance().1;
-                let value: i64 = text.parse().map_err(|_| ParseError::InvalidLiteral(text))?;
-                Ok(Pattern::Literal(Literal::Int(value)))
+                let SpannedToken { text, span, .. } = self.advance();
+                let value: i64 = text.parse()
+                    .map_err(|_| ParseError::new(ParseErrorKind::InvalidLiteral(text), span))?;
+                PatternKind::Literal(Literal::Int(value))
             }
             Token::StringLiteral => {
-                let text = self.advance().1;
+                let text = self.advance().text;
                 let content = text[1..text.len()-1].to_string();
-                Ok(Pattern::Literal(Literal::String(content)))
+                PatternKind::Literal(Literal::String(content))
             }
             Token::True => {
                 self.advance();
-                Ok(Pattern::Literal(Literal::Bool(true)))
+                PatternKind::Literal(Literal::Bool(true))
             }
             Token::False => {
                 self.advance();
-                Ok(Pattern::Literal(Literal::Bool(false)))
+                PatternKind::Literal(Literal::Bool(false))
             }
             Token::Identifier => {
-                let name = self.advance().1;
+                let first = self.advance().text;
+                let mut path = self.parse_path_rest(first);
 
                 // Check for struct pattern
                 if self.match_token(&Token::LeftBrace) {
                     let mut fields = Vec::new();
                     while !self.match_token(&Token::RightBrace) {
+                        let field_start = self.peek_span();
                         let field_name = self.expect_identifier()?;
                         let pattern = if self.match_token(&Token::Colon) {
                             self.parse_pattern()?
                         } else {
-                            Pattern::Identifier(field_name.clone())
+                            let span = self.span_from(field_start);
+                            self.mk_pattern(PatternKind::Identifier(field_name.clone()), span)
                         };
                         fields.push((field_name, pattern));
                         if !self.match_token(&Token::Comma) {
@@ -1276,9 +1931,21 @@ fn parse_pattern(&mut self) -> ParseResult<Pattern> {
                             break;
                         }
                     }
-                    Ok(Pattern::Struct { name, fields })
+                    PatternKind::Struct { path, fields }
+                } else if self.match_token(&Token::LeftParen) {
+                    let mut elems = Vec::new();
+                    while !self.match_token(&Token::RightParen) {
+                        elems.push(self.parse_pattern()?);
+                        if !self.match_token(&Token::Comma) {
+                            self.expect(Token::RightParen)?;
+                            break;
+                        }
+                    }
+                    PatternKind::TupleStruct { path, elems }
+                } else if path.len() == 1 {
+                    PatternKind::Identifier(path.remove(0))
                 } else {
-                    Ok(Pattern::Identifier(name))
+                    PatternKind::Path(path)
                 }
             }
             Token::LeftParen => {
@@ -1291,17 +1958,28 @@ fn parse_pattern(&mut self) -> ParseResult<Pattern> {
                         break;
                     }
                 }
-                Ok(Pattern::Tuple(patterns))
+                PatternKind::Tuple(patterns)
             }
-            _ => Err(ParseError::InvalidPattern(format!("{:?}", self.peek()))),
-        }
+            _ => return Err(self.error(ParseErrorKind::InvalidPattern(self.found()))),
+        };
+
+        let span = self.span_from(start);
+        Ok(self.mk_pattern(kind, span))
     }
 }
 
-/// Convenience function to parse source code
+/// Convenience function to parse source code, failing with the first error
 pub fn parse(source: &str) -> ParseResult<Program> {
-    let mut parser = Parser::new(source);
-    parser.parse_program()
+    let (program, errors) = parse_recovering(source);
+    match errors.into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(program),
+    }
+}
+
+/// Parse source code, returning the partial program and every error found
+pub fn parse_recovering(source: &str) -> (Program, Vec<ParseError>) {
+    Parser::new(source).parse_program()
 }
 
 #[cfg(test)]
@@ -1359,6 +2037,31 @@ fn test_parse_binary_expression() {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_binary_precedence_and_associativity() {
+        let tail = |source: &str| {
+            let program = parse(source).unwrap();
+            let ItemKind::Function(f) = &program.items[0].kind else { panic!("expected function") };
+            f.body.expr.clone().expect("expected a tail expression")
+        };
+        // `1 + 2 * 3` is `1 + (2 * 3)`
+        let ExpressionKind::Binary { op: BinaryOp::Add, right, .. } = tail("fn main() { 1 + 2 * 3 }").kind else {
+            panic!("expected addition")
+        };
+        assert!(matches!(right.kind, ExpressionKind::Binary { op: BinaryOp::Mul, .. }));
+        // `8 - 4 - 2` is `(8 - 4) - 2`
+        let ExpressionKind::Binary { left, right, .. } = tail("fn main() { 8 - 4 - 2 }").kind else {
+            panic!("expected subtraction")
+        };
+        assert!(matches!(left.kind, ExpressionKind::Binary { op: BinaryOp::Sub, .. }));
+        assert!(matches!(right.kind, ExpressionKind::Literal(Literal::Int(2))));
+        // `a = b = 1` is `a = (b = 1)`
+        let ExpressionKind::Binary { op: BinaryOp::Assign, right, .. } = tail("fn main() { a = b = 1 }").kind else {
+            panic!("expected assignment")
+        };
+        assert!(matches!(right.kind, ExpressionKind::Binary { op: BinaryOp::Assign, .. }));
+    }
+
     #[test]
     fn test_parse_function_call() {
         let source = "fn main() { foo(1, 2, 3) }";
@@ -1384,4 +2087,441 @@ fn test_parse_contract() {
         let result2 = parse(source2);
         assert!(result2.is_ok(), "Parse with post failed: {:?}", result2);
     }
+
+    #[test]
+    fn test_error_span() {
+        let source = "fn main() {\n    let = 1;\n}";
+        let err = parse(source).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::InvalidPattern("`=`".to_string()));
+        assert_eq!((err.span.line, err.span.column), (2, 9));
+        assert_eq!(&source[err.span.start..err.span.end], "=");
+    }
+
+    #[test]
+    fn test_invalid_token_reported() {
+        let (program, errors) = parse_recovering("fn main() { let x = $ 1; }\nfn f() {}");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::InvalidToken("$".to_string()));
+        assert_eq!(program.items.len(), 2);
+
+        let diag = errors[0].to_diagnostic();
+        assert_eq!(diag.code, Some(my_lang_diagnostics::codes::INVALID_TOKEN));
+    }
+
+    #[test]
+    fn test_missing_semicolon_fix_it() {
+        let source = "fn main() {\n    let x = 1\n    let y = 2;\n}";
+        let (_, errors) = parse_recovering(source);
+        let diag = errors[0].to_diagnostic();
+        assert_eq!(diag.message, "expected `;`, found `let`");
+        assert_eq!(diag.code, Some(my_lang_diagnostics::codes::UNEXPECTED_TOKEN));
+
+        let fix = &diag.suggestions[0];
+        assert_eq!(fix.replacement, ";");
+        assert_eq!(&source[..fix.span.start], "fn main() {\n    let x = 1");
+        assert_eq!(diag.primary_span(), Some(fix.span));
+    }
+
+    #[test]
+    fn test_fix_it_column_counts_characters() {
+        let source = "fn main() {\n    let s = \"héllo\"\n    let y = 2;\n}";
+        let (_, errors) = parse_recovering(source);
+        let fix = &errors[0].to_diagnostic().suggestions[0];
+        assert_eq!(&source[..fix.span.start], "fn main() {\n    let s = \"héllo\"");
+        assert_eq!((fix.span.line, fix.span.column), (2, 20));
+    }
+
+    #[test]
+    fn test_fix_it_without_whitespace() {
+        let (_, errors) = parse_recovering("fn main() { f(1}; }");
+        let diag = errors[0].to_diagnostic();
+        assert_eq!(diag.message, "expected `)`, found `}`");
+        assert_eq!(diag.labels.len(), 1);
+        let fix = &diag.suggestions[0];
+        assert_eq!((fix.span.start, fix.span.column), (15, 16));
+        assert_eq!(diag.primary_span(), Some(fix.span));
+    }
+
+    #[test]
+    fn test_literal_note_only_for_integers() {
+        let int = ParseError::new(ParseErrorKind::InvalidLiteral("99999999999999999999".into()), Span::dummy());
+        assert_eq!(int.to_diagnostic().notes, ["integer literals must fit in 64 bits"]);
+        let float = ParseError::new(ParseErrorKind::InvalidLiteral("1.5e".into()), Span::dummy());
+        assert!(float.to_diagnostic().notes.is_empty());
+    }
+
+    #[test]
+    fn test_keywords_as_method_names() {
+        let source = "impl Conn { fn send(self, data: str) -> Conn { self } }\nfn main() { conn.send(1).state; }";
+        let (_, errors) = parse_recovering(source);
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn test_error_span_file() {
+        let mut parser = Parser::with_file("struct {}", FileId(2));
+        let (_, errors) = parser.parse_program();
+        let err = &errors[0];
+        assert_eq!(err.kind, ParseErrorKind::MissingIdentifier);
+        assert_eq!(err.span.file, FileId(2));
+        assert_eq!((err.span.line, err.span.column), (1, 8));
+    }
+
+    #[test]
+    fn test_node_spans() {
+        let source = "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}";
+        let program = parse(source).unwrap();
+        let item = &program.items[0];
+        assert_eq!(&source[item.span.start..item.span.end], source);
+
+        let ItemKind::Function(func) = &item.kind else { panic!("expected function") };
+        assert_eq!(&source[func.params[1].span.start..func.params[1].span.end], "b: i32");
+
+        let body = func.body.expr.as_ref().unwrap();
+        assert_eq!(&source[body.span.start..body.span.end], "a + b");
+        assert_eq!((body.span.line, body.span.column), (2, 5));
+    }
+
+    #[test]
+    fn test_workflow_and_agent_node_spans() {
+        let source = "agent Bot { communication { { ping() } } }\nworkflow W { Bot -> Checker }";
+        let program = parse(source).unwrap();
+        let ItemKind::Agent(agent) = &program.items[0].kind else { panic!("expected agent") };
+        let pattern = &agent.communication[0].pattern;
+        assert_eq!(pattern.kind, MessagePatternKind::Any);
+        assert_eq!((pattern.span.start, pattern.span.end), (28, 28));
+
+        let ItemKind::Workflow(workflow) = &program.items[1].kind else { panic!("expected workflow") };
+        let spans: Vec<&str> = workflow.stages.iter().map(|s| &source[s.span.start..s.span.end]).collect();
+        assert_eq!(spans, ["Bot", "Checker"]);
+        assert_ne!(workflow.stages[0].id, workflow.stages[1].id);
+        assert_ne!(pattern.id, agent.communication[0].id);
+    }
+
+    #[test]
+    fn test_node_ids_unique() {
+        let program = parse("fn main() { let x = foo(1, 2); x }").unwrap();
+        let json = serde_json::to_value(&program).unwrap();
+
+        fn collect(value: &serde_json::Value, ids: &mut Vec<u64>) {
+            match value {
+                serde_json::Value::Object(map) => {
+                    if let Some(id) = map.get("id").and_then(|v| v.as_u64()) {
+                        ids.push(id);
+                    }
+                    map.values().for_each(|v| collect(v, ids));
+                }
+                serde_json::Value::Array(items) => items.iter().for_each(|v| collect(v, ids)),
+                _ => {}
+            }
+        }
+
+        let mut ids = Vec::new();
+        collect(&json, &mut ids);
+        let count = ids.len();
+        ids.sort();
+        ids.dedup();
+        assert!(count > 10);
+        assert_eq!(ids.len(), count);
+    }
+
+    #[test]
+    fn test_ast_json_round_trip() {
+        let source = "struct P { x: i32 }\nfn f(p: P) -> i32 pre p.x > 0 { match p.x { 1 => 2, _ => 3.5 } }";
+        let program = parse(source).unwrap();
+        let json = serde_json::to_string(&program).unwrap();
+        let back: Program = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, program);
+    }
+
+    #[test]
+    fn test_recover_multiple_statement_errors() {
+        let source = "fn main() {\n    let = 1;\n    let y = 2;\n    foo(;\n    bar()\n}\nfn other() {}";
+        let (program, errors) = parse_recovering(source);
+        assert_eq!(errors.len(), 2, "errors: {:?}", errors);
+        assert_eq!(errors[0].span.line, 2);
+        assert_eq!(errors[1].span.line, 4);
+
+        assert_eq!(program.items.len(), 2);
+        let ItemKind::Function(main) = &program.items[0].kind else { panic!("expected function") };
+        assert!(matches!(main.body.stmts[0].kind, StatementKind::Error));
+        assert!(matches!(main.body.stmts[1].kind, StatementKind::Let { .. }));
+        assert!(matches!(main.body.stmts[2].kind, StatementKind::Error));
+        assert!(main.body.expr.is_some());
+    }
+
+    #[test]
+    fn test_recover_at_item_keywords() {
+        let source = "struct { x: i32 }\nfn ok() {}\nagent { }\nworkflow W { A }\nenum E { A, }";
+        let (program, errors) = parse_recovering(source);
+        assert_eq!(errors.len(), 2, "errors: {:?}", errors);
+        let kinds: Vec<_> = program.items.iter().map(|i| &i.kind).collect();
+        assert!(matches!(kinds[0], ItemKind::Error));
+        assert!(matches!(kinds[1], ItemKind::Function(_)));
+        assert!(matches!(kinds[2], ItemKind::Error));
+        assert!(matches!(kinds[3], ItemKind::Workflow(_)));
+        assert!(matches!(kinds[4], ItemKind::Enum(_)));
+    }
+
+    #[test]
+    fn test_recover_inside_impl_and_module() {
+        let source = "impl P { fn a(x) {} fn b() {} }\nmod m { const = 1; fn c() {} }\n}\nfn d() {}";
+        let (program, errors) = parse_recovering(source);
+        assert_eq!(errors.len(), 3, "errors: {:?}", errors);
+
+        let ItemKind::Impl(imp) = &program.items[0].kind else { panic!("expected impl") };
+        assert_eq!(imp.items.len(), 1);
+        let ItemKind::Module(m) = &program.items[1].kind else { panic!("expected module") };
+        assert!(matches!(m.items[0].kind, ItemKind::Error));
+        assert!(matches!(m.items[1].kind, ItemKind::Function(_)));
+        assert!(matches!(program.items.last().unwrap().kind, ItemKind::Function(_)));
+    }
+
+    #[test]
+    fn test_recover_unclosed_block() {
+        let (program, errors) = parse_recovering("fn main() { let x = 1;");
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(program.items[0].kind, ItemKind::Error));
+    }
+
+    fn body_expr(source: &str) -> ExpressionKind {
+        let program = parse(source).unwrap();
+        let ItemKind::Function(f) = &program.items[0].kind else { panic!("expected function") };
+        f.body.expr.as_ref().expect("tail expression").kind.clone()
+    }
+
+    #[test]
+    fn test_parse_paths() {
+        let ExpressionKind::Call { func, .. } = body_expr("fn main() { io::println(1) }") else {
+            panic!("expected call");
+        };
+        assert_eq!(func.kind, ExpressionKind::Path(vec!["io".into(), "println".into()]));
+
+        let ExpressionKind::Struct { path, fields } = body_expr("fn main() { Shape::Rect { w, h: 2 } }") else {
+            panic!("expected struct literal");
+        };
+        assert_eq!(path, ["Shape", "Rect"]);
+        assert_eq!(fields[0].1.kind, ExpressionKind::Identifier("w".into()));
+
+        let ExpressionKind::Match { arms, .. } =
+            body_expr("fn main() { match s { Shape::Circle(r) => r, Shape::Empty => 0, P { x } => x } }")
+        else {
+            panic!("expected match");
+        };
+        assert!(matches!(&arms[0].pattern.kind, PatternKind::TupleStruct { path, .. } if path.len() == 2));
+        assert!(matches!(&arms[1].pattern.kind, PatternKind::Path(path) if path.len() == 2));
+        assert!(matches!(&arms[2].pattern.kind, PatternKind::Struct { path, .. } if path == &["P"]));
+    }
+
+    #[test]
+    fn test_no_struct_literal_in_condition() {
+        // `x { ... }` here is a condition followed by a block, not a struct literal
+        let ExpressionKind::If { cond, .. } = body_expr("fn main() { if x { 1 } else { 2 } }") else {
+            panic!("expected if");
+        };
+        assert_eq!(cond.kind, ExpressionKind::Identifier("x".into()));
+        assert!(parse("fn main() { while ok(P { x: 1 }) { } for i in v { } }").is_ok());
+    }
+
+    #[test]
+    fn test_parse_self_params() {
+        let program = parse("impl P { fn a(&self) {} fn b(&mut self, x: i32) {} fn c(self) {} }").unwrap();
+        let ItemKind::Impl(imp) = &program.items[0].kind else { panic!("expected impl") };
+        let params: Vec<_> = imp
+            .items
+            .iter()
+            .map(|item| match item {
+                ImplItem::Function(f) => (&f.params[0].name, &f.params[0].ty.kind),
+                _ => panic!("expected function"),
+            })
+            .collect();
+        assert!(params.iter().all(|(name, _)| name.as_str() == "self"));
+        assert!(matches!(params[1].1, TypeKind::Reference { is_mut: true, .. }));
+        assert_eq!(params[2].1, &TypeKind::Named("Self".into()));
+    }
+
+    #[test]
+    fn test_parse_trait_signatures() {
+        let program = parse("trait T { type Item; fn get(&self) -> i32; fn twice(&self) -> i32 { 2 } }").unwrap();
+        let ItemKind::Trait(t) = &program.items[0].kind else { panic!("expected trait") };
+        assert!(matches!(&t.items[0], TraitItem::Type(name) if name == "Item"));
+        assert!(matches!(&t.items[1], TraitItem::Signature(f) if f.name == "get" && f.return_type.is_some()));
+        assert!(matches!(&t.items[2], TraitItem::Function(f) if f.body.expr.is_some()));
+        assert!(parse("fn f();").is_err());
+    }
+
+    #[test]
+    fn test_parse_lifetimes() {
+        let program = parse(
+            "struct View<'a> { text: &'a str }\n\
+             fn longest<'a, 'b: 'a>(x: &'a str, y: &'b View<'b>) -> &'a str { x }\n\
+             const NAME: &'static str = \"solo\";",
+        )
+        .unwrap();
+        let ItemKind::Function(f) = &program.items[1].kind else { panic!("expected function") };
+        assert!(f.generics.is_empty());
+        let TypeKind::Reference { lifetime, ty, .. } = &f.params[1].ty.kind else { panic!("expected reference") };
+        assert_eq!(lifetime.as_deref(), Some("b"));
+        assert!(matches!(&ty.kind, TypeKind::Generic { args, .. } if args.is_empty()));
+        let ItemKind::Const(c) = &program.items[2].kind else { panic!("expected const") };
+        assert!(matches!(&c.ty.kind, TypeKind::Reference { lifetime: Some(l), .. } if l == "static"));
+    }
+
+    #[test]
+    fn test_parse_impl_invariants() {
+        let program = parse(
+            "impl Account where invariant self.balance >= 0 invariant self.limit > 0 {\n\
+                 pub fn deposit(&mut self, n: i64) { self.balance = self.balance + n; }\n\
+             }",
+        )
+        .unwrap();
+        let ItemKind::Impl(imp) = &program.items[0].kind else { panic!("expected impl") };
+        let contract = imp.contract.as_ref().expect("invariants are kept");
+        assert_eq!(contract.invariants.len(), 2);
+        assert!(contract.preconditions.is_empty() && contract.postconditions.is_empty());
+        assert_eq!(imp.items.len(), 1);
+
+        let err = parse("impl Account where pre true {}").unwrap_err();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidContract(_)));
+        let err = parse("impl Account where invariant true post true {}").unwrap_err();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidContract(_)));
+    }
+
+    #[test]
+    fn test_parse_comptime_expressions() {
+        let program = parse(
+            "const FACT: u64 = comptime factorial(10) + 1;\n\
+             const SIZE: usize = comptime { let x = 10; x * 2 };\n\
+             fn f(a: [u8; SIZE * 2]) {\n\
+                 comptime fn helper() -> usize { 3 }\n\
+                 let b: [[i32; helper()]; 2] = comptime { [[0, 0, 0], [1, 1, 1]] };\n\
+             }",
+        )
+        .unwrap();
+        let ItemKind::Const(fact) = &program.items[0].kind else { panic!("expected const") };
+        // `comptime` covers the call only
+        let ExpressionKind::Binary { left, .. } = &fact.value.kind else { panic!("expected addition") };
+        let ExpressionKind::Comptime(call) = &left.kind else { panic!("expected comptime") };
+        assert!(matches!(call.kind, ExpressionKind::Call { .. }));
+        let ItemKind::Const(size) = &program.items[1].kind else { panic!("expected const") };
+        let ExpressionKind::Comptime(block) = &size.value.kind else { panic!("expected comptime") };
+        assert!(matches!(block.kind, ExpressionKind::Block(_)));
+
+        let ItemKind::Function(f) = &program.items[2].kind else { panic!("expected function") };
+        let TypeKind::Array { size: Some(size), .. } = &f.params[0].ty.kind else { panic!("expected sized array") };
+        assert!(matches!(size.kind, ExpressionKind::Binary { op: BinaryOp::Mul, .. }));
+        let StatementKind::Item(item) = &f.body.stmts[0].kind else { panic!("expected item") };
+        assert!(matches!(&item.kind, ItemKind::Function(h) if h.is_comptime));
+        let StatementKind::Let { ty: Some(ty), .. } = &f.body.stmts[1].kind else { panic!("expected let") };
+        let TypeKind::Array { elem, size: Some(outer) } = &ty.kind else { panic!("expected array") };
+        assert!(matches!(outer.kind, ExpressionKind::Literal(Literal::Int(2))));
+        let TypeKind::Array { size: Some(inner), .. } = &elem.kind else { panic!("expected array") };
+        assert!(matches!(inner.kind, ExpressionKind::Call { .. }));
+    }
+
+    #[test]
+    fn test_parse_comptime_blocks() {
+        let program = parse("comptime {\n    assert(SIZE > 0);\n}\nfn main() {}").unwrap();
+        let ItemKind::Comptime(expr) = &program.items[0].kind else { panic!("expected comptime block") };
+        let ExpressionKind::Comptime(block) = &expr.kind else { panic!("expected comptime") };
+        assert!(matches!(block.kind, ExpressionKind::Block(_)));
+        assert_eq!((expr.span.start, expr.span.end), (0, 34));
+
+        let (_, errors) = parse_recovering("pub comptime { }");
+        assert!(matches!(errors[0].kind, ParseErrorKind::UnexpectedVisibility(_)), "{:?}", errors);
+    }
+
+    #[test]
+    fn test_parse_closures() {
+        let program = parse(
+            "fn apply(f: fn(i32, i32) -> i32) -> fn() {\n\
+                 let add = |a: i32, b: i32| a + b;\n\
+                 let twice = |x| -> i32 { x * 2 };\n\
+                 move || f(1, 2)\n\
+             }",
+        )
+        .unwrap();
+        let ItemKind::Function(f) = &program.items[0].kind else { panic!("expected function") };
+        let TypeKind::Function { params, .. } = &f.params[0].ty.kind else { panic!("expected function type") };
+        assert_eq!(params.len(), 2);
+        let Some(TypeKind::Function { params, ret }) = f.return_type.as_ref().map(|t| &t.kind) else {
+            panic!("expected function type")
+        };
+        assert!(params.is_empty());
+        assert!(matches!(ret.kind, TypeKind::Primitive(PrimitiveType::Unit)));
+
+        let StatementKind::Let { init: Some(add), .. } = &f.body.stmts[0].kind else { panic!("expected let") };
+        let ExpressionKind::Closure { params, ret: None, body, is_move: false } = &add.kind else {
+            panic!("expected closure")
+        };
+        let names: Vec<&str> = params.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+        // The body takes in the whole addition
+        assert!(matches!(body.kind, ExpressionKind::Binary { op: BinaryOp::Add, .. }));
+
+        let StatementKind::Let { init: Some(twice), .. } = &f.body.stmts[1].kind else { panic!("expected let") };
+        let ExpressionKind::Closure { params, ret: Some(_), body, .. } = &twice.kind else {
+            panic!("expected closure")
+        };
+        assert!(matches!(params[0].ty.kind, TypeKind::Inferred));
+        assert!(matches!(body.kind, ExpressionKind::Block(_)));
+
+        let tail = f.body.expr.as_ref().unwrap();
+        assert!(matches!(&tail.kind, ExpressionKind::Closure { params, is_move: true, .. } if params.is_empty()));
+    }
+
+    #[test]
+    fn test_parse_import_trees() {
+        let program = parse("pub import std::net::{self, http::{Client, Server as S}, Tcp};").unwrap();
+        let ItemKind::Import(import) = &program.items[0].kind else { panic!("expected import") };
+        assert_eq!(import.visibility, Visibility::Public);
+        let leaves: Vec<_> = import
+            .leaves()
+            .into_iter()
+            .map(|(path, tree)| (path.join("::"), tree.alias().map(str::to_string)))
+            .collect();
+        assert_eq!(
+            leaves,
+            [
+                ("std::net".to_string(), None),
+                ("std::net::http::Client".to_string(), None),
+                ("std::net::http::Server".to_string(), Some("S".to_string())),
+                ("std::net::Tcp".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_module_declarations() {
+        let program = parse("mod util;\npub mod math { fn f() {} }").unwrap();
+        let modules: Vec<_> = program
+            .items
+            .iter()
+            .map(|item| match &item.kind {
+                ItemKind::Module(m) => (m.inline, m.visibility, m.items.len()),
+                _ => panic!("expected module"),
+            })
+            .collect();
+        assert_eq!(modules, [(false, Visibility::Private, 0), (true, Visibility::Public, 1)]);
+    }
+
+    #[test]
+    fn test_parse_visibility() {
+        let program = parse(
+            "pub(crate) fn a() {}\npub struct S { pub(crate) x: i32, y: i32 }\nagent Bot {}\npub workflow W {}",
+        )
+        .unwrap();
+        let visibilities: Vec<_> = program.items.iter().map(|i| i.kind.visibility().unwrap()).collect();
+        assert_eq!(
+            visibilities,
+            [Visibility::Crate, Visibility::Public, Visibility::Private, Visibility::Public]
+        );
+        let ItemKind::Struct(s) = &program.items[1].kind else { panic!("expected struct") };
+        assert_eq!(s.fields[0].visibility, Visibility::Crate);
+        assert_eq!(s.fields[1].visibility, Visibility::Private);
+
+        let (_, errors) = parse_recovering("pub impl S {}\nfn f() {}");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::UnexpectedVisibility("impl blocks".into()));
+    }
 }
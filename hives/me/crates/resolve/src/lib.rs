@@ -0,0 +1,248 @@
+// Name Resolution
+// Builds nested scopes over a crate's modules and binds every name use to its definition
+
+pub mod prelude;
+pub mod privacy;
+mod resolver;
+mod scope;
+
+pub use resolver::Resolver;
+pub use scope::{Scope, ScopeId, ScopeKind};
+
+use my_lang_ast::{NodeId, Program, Span, Visibility};
+use my_lang_diagnostics::Diagnostic;
+use std::collections::HashMap;
+
+/// Index of a definition in `Resolution::defs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DefId(pub u32);
+
+/// Types and values are looked up separately, so a struct and a function may share a name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Namespace {
+    Type,
+    Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DefKind {
+    Module,
+    Struct,
+    Enum,
+    Variant,
+    Trait,
+    TypeAlias,
+    Const,
+    Function,
+    Agent,
+    Workflow,
+    /// Generic type parameter
+    TypeParam,
+    /// `Self` in a trait, or in an impl whose self type is not a local definition
+    SelfType,
+    /// `type Item` in a trait or impl
+    AssocType,
+    /// `let`, `for` or match arm binding
+    Local,
+    Param,
+    /// State field of an agent
+    StateField,
+    /// Item imported from a library whose source is not loaded, such as `std`
+    External,
+}
+
+impl DefKind {
+    pub fn namespaces(self) -> &'static [Namespace] {
+        use Namespace::*;
+        match self {
+            DefKind::Module | DefKind::Struct | DefKind::Enum | DefKind::Trait
+            | DefKind::TypeAlias | DefKind::Agent | DefKind::Workflow | DefKind::TypeParam
+            | DefKind::SelfType | DefKind::AssocType => &[Type],
+            DefKind::Const | DefKind::Function | DefKind::Local | DefKind::Param
+            | DefKind::StateField => &[Value],
+            DefKind::Variant | DefKind::External => &[Type, Value],
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            DefKind::Module => "module",
+            DefKind::Struct => "struct",
+            DefKind::Enum => "enum",
+            DefKind::Variant => "variant",
+            DefKind::Trait => "trait",
+            DefKind::TypeAlias => "type alias",
+            DefKind::Const => "constant",
+            DefKind::Function => "function",
+            DefKind::Agent => "agent",
+            DefKind::Workflow => "workflow",
+            DefKind::TypeParam => "type parameter",
+            DefKind::SelfType => "self type",
+            DefKind::AssocType => "associated type",
+            DefKind::Local => "local variable",
+            DefKind::Param => "parameter",
+            DefKind::StateField => "state field",
+            DefKind::External => "external item",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Definition {
+    pub id: DefId,
+    pub name: String,
+    pub kind: DefKind,
+    /// Declared visibility of items; public for everything else
+    pub visibility: Visibility,
+    /// Dummy for prelude definitions
+    pub span: Span,
+    /// The defining node; `NodeId::DUMMY` for prelude definitions and trait associated types
+    pub node: NodeId,
+    /// Module, enum, trait or type that owns this definition
+    pub parent: Option<DefId>,
+}
+
+impl Definition {
+    /// Whether this comes from the prelude rather than source code
+    pub fn is_prelude(&self) -> bool {
+        self.span.is_dummy()
+    }
+}
+
+/// A named field of a struct or struct-like variant
+#[derive(Debug, Clone)]
+pub struct FieldDef {
+    pub name: String,
+    pub visibility: Visibility,
+    pub span: Span,
+}
+
+/// What a name use refers to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Res {
+    Def(DefId),
+    /// A path that continues past a definition whose members are only known
+    /// after type checking, such as `T::default` or `io::println`
+    Partial { base: DefId, rest: Vec<String> },
+}
+
+impl Res {
+    /// The definition named, or the last one reached along a partial path
+    pub fn base(&self) -> DefId {
+        match self {
+            Res::Def(id) | Res::Partial { base: id, .. } => *id,
+        }
+    }
+}
+
+/// Output of name resolution, shared by later phases and the language server
+#[derive(Debug, Clone, Default)]
+pub struct Resolution {
+    pub defs: Vec<Definition>,
+    pub scopes: Vec<Scope>,
+    /// Uses keyed by the node naming them: identifier, path and struct
+    /// expressions, named types, patterns, imports, and impls (for their trait)
+    pub uses: HashMap<NodeId, Res>,
+    /// Definition introduced by each defining node
+    pub node_defs: HashMap<NodeId, DefId>,
+    /// Scope opened by each scoping node
+    pub node_scopes: HashMap<NodeId, ScopeId>,
+    /// Associated-item scope of each module, struct, enum and trait
+    pub members: HashMap<DefId, ScopeId>,
+    /// Traits named as bounds, keyed by the `Generic` or `WherePredicate` node
+    pub bounds: HashMap<NodeId, Vec<DefId>>,
+    /// Self type definition of each impl, keyed by the impl node
+    pub impl_self: HashMap<NodeId, DefId>,
+    /// Named fields of each struct and struct-like variant
+    pub fields: HashMap<DefId, Vec<FieldDef>>,
+    /// Module owning each module scope
+    pub scope_modules: HashMap<ScopeId, DefId>,
+    /// Locals each closure uses from outside it, in order of first use, keyed
+    /// by the closure expression
+    pub captures: HashMap<NodeId, Vec<DefId>>,
+}
+
+impl Resolution {
+    pub fn def(&self, id: DefId) -> &Definition {
+        &self.defs[id.0 as usize]
+    }
+
+    pub fn scope(&self, id: ScopeId) -> &Scope {
+        &self.scopes[id.0 as usize]
+    }
+
+    /// What the name at `node` refers to
+    pub fn res(&self, node: NodeId) -> Option<&Res> {
+        self.uses.get(&node)
+    }
+
+    /// Definition the name at `node` refers to, if fully resolved
+    pub fn definition_of(&self, node: NodeId) -> Option<&Definition> {
+        match self.uses.get(&node)? {
+            Res::Def(id) => Some(self.def(*id)),
+            Res::Partial { .. } => None,
+        }
+    }
+
+    /// Definition introduced by a defining node
+    pub fn def_of_node(&self, node: NodeId) -> Option<DefId> {
+        self.node_defs.get(&node).copied()
+    }
+
+    /// Nodes that refer to `def`, in no particular order
+    pub fn references(&self, def: DefId) -> impl Iterator<Item = NodeId> + '_ {
+        self.uses
+            .iter()
+            .filter(move |(_, res)| **res == Res::Def(def))
+            .map(|(node, _)| *node)
+    }
+
+    /// Look `name` up from `scope` outwards
+    pub fn lookup(&self, scope: ScopeId, ns: Namespace, name: &str) -> Option<DefId> {
+        let mut current = Some(scope);
+        while let Some(id) = current {
+            let scope = self.scope(id);
+            if let Some(def) = scope.get(ns, name) {
+                return Some(def);
+            }
+            current = scope.parent;
+        }
+        None
+    }
+
+    /// Module whose code `scope` is part of
+    pub fn module_of(&self, scope: ScopeId) -> Option<DefId> {
+        let mut current = Some(scope);
+        while let Some(id) = current {
+            if let Some(&module) = self.scope_modules.get(&id) {
+                return Some(module);
+            }
+            current = self.scope(id).parent;
+        }
+        None
+    }
+
+    /// Whether field `name` of struct or variant `owner` may be used from `scope`.
+    /// Unknown fields count as visible; reporting them is the type checker's job.
+    pub fn field_visible(&self, owner: DefId, name: &str, scope: ScopeId) -> bool {
+        let Some(field) = self.fields.get(&owner).and_then(|fs| fs.iter().find(|f| f.name == name)) else {
+            return true;
+        };
+        match self.module_of(scope) {
+            Some(from) => privacy::is_visible(self, field.visibility, privacy::home_module(self, owner), from),
+            None => true,
+        }
+    }
+
+    /// Associated item `name` of a module, struct, enum or trait
+    pub fn member(&self, owner: DefId, ns: Namespace, name: &str) -> Option<DefId> {
+        self.scope(*self.members.get(&owner)?).get(ns, name)
+    }
+}
+
+/// Resolve a single-file program
+pub fn resolve(program: &Program) -> (Resolution, Vec<Diagnostic>) {
+    let mut resolver = Resolver::new();
+    resolver.add_module(&[], program);
+    resolver.finish()
+}
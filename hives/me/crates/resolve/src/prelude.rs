@@ -0,0 +1,52 @@
+// Prelude
+// Names every module can use without importing them
+
+/// Library types with their type parameters, opaque to the resolver
+pub const TYPES: &[(&str, &[&str])] = &[
+    ("String", &[]),
+    ("Vec", &["T"]),
+    ("VecDeque", &["T"]),
+    ("Box", &["T"]),
+    ("HashMap", &["K", "V"]),
+    ("HashSet", &["T"]),
+    ("BTreeMap", &["K", "V"]),
+    ("BTreeSet", &["T"]),
+    ("Rc", &["T"]),
+    ("Arc", &["T"]),
+    ("Weak", &["T"]),
+    ("Cell", &["T"]),
+    ("RefCell", &["T"]),
+    ("Mutex", &["T"]),
+    ("RwLock", &["T"]),
+];
+
+/// Library traits usable as bounds and in `impl ... for`
+pub const TRAITS: &[&str] = &[
+    "Clone", "Copy", "Debug", "Default", "Display", "Drop", "Eq", "PartialEq", "Ord",
+    "PartialOrd", "Hash", "Iterator", "IntoIterator", "From", "Into", "ToString",
+    "Send", "Sync", "Sized", "Fn", "FnMut", "FnOnce",
+    "Add", "Sub", "Mul", "Div", "Rem", "Neg", "Not", "Index",
+];
+
+/// A prelude enum: its type parameters and its variants, each with the
+/// parameters its fields hold. Variants are also in scope unqualified.
+pub struct PreludeEnum {
+    pub name: &'static str,
+    pub params: &'static [&'static str],
+    pub variants: &'static [(&'static str, &'static [&'static str])],
+}
+
+pub const ENUMS: &[PreludeEnum] = &[
+    PreludeEnum { name: "Option", params: &["T"], variants: &[("Some", &["T"]), ("None", &[])] },
+    PreludeEnum { name: "Result", params: &["T", "E"], variants: &[("Ok", &["T"]), ("Err", &["E"])] },
+];
+
+/// Free functions. `old` snapshots a value in postconditions, and
+/// `contract_violation` is called by the checks contracts lower to.
+pub const FUNCTIONS: &[&str] = &[
+    "print", "println", "eprint", "eprintln", "format", "panic", "assert", "assert_eq",
+    "assert_ne", "unreachable", "todo", "dbg", "drop", "old", "contract_violation",
+];
+
+/// Roots of libraries whose source is not loaded; anything imported from them is accepted
+pub const EXTERN_CRATES: &[&str] = &["std"];
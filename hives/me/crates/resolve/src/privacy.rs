@@ -0,0 +1,41 @@
+// Privacy
+// Visibility rules shared by the resolver's checks and later phases
+
+use crate::{DefId, DefKind, Resolution};
+use my_lang_ast::Visibility;
+
+/// Module that owns `def`, looking through enums, traits and types.
+/// `None` for prelude and other definitions outside any module.
+pub fn home_module(res: &Resolution, def: DefId) -> Option<DefId> {
+    let mut current = res.def(def).parent;
+    while let Some(id) = current {
+        if res.def(id).kind == DefKind::Module {
+            return Some(id);
+        }
+        current = res.def(id).parent;
+    }
+    None
+}
+
+/// Whether `module` is `ancestor` or nested inside it
+pub fn is_within(res: &Resolution, module: DefId, ancestor: DefId) -> bool {
+    let mut current = Some(module);
+    while let Some(id) = current {
+        if id == ancestor {
+            return true;
+        }
+        current = res.def(id).parent;
+    }
+    false
+}
+
+/// Whether something declared with `visibility` in module `home` can be
+/// named from module `from`. Private items are visible in their own module
+/// and its descendants; `pub` and `pub(crate)` items everywhere, since a
+/// program is a single crate.
+pub fn is_visible(res: &Resolution, visibility: Visibility, home: Option<DefId>, from: DefId) -> bool {
+    match (visibility, home) {
+        (Visibility::Public | Visibility::Crate, _) | (_, None) => true,
+        (Visibility::Private, Some(home)) => is_within(res, from, home),
+    }
+}
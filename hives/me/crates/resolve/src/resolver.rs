@@ -0,0 +1,1783 @@
+// Resolver
+// Collects item definitions, resolves imports to a fixed point, then walks bodies binding uses
+
+use crate::{prelude, privacy};
+use crate::{DefId, DefKind, Definition, FieldDef, Namespace, Res, Resolution, Scope, ScopeId, ScopeKind};
+use my_lang_ast::*;
+use my_lang_diagnostics::{codes, Diagnostic, Suggestion};
+use std::collections::HashMap;
+
+/// Builds a `Resolution` for every module of a crate.
+///
+/// Modules are registered with `add_module` under their path from the crate
+/// root (empty for the entry file); `finish` then resolves them together so
+/// imports can cross file boundaries.
+pub struct Resolver<'a> {
+    modules: Vec<(Vec<String>, &'a Program)>,
+    res: Resolution,
+    diagnostics: Vec<Diagnostic>,
+    prelude: ScopeId,
+    root: DefId,
+    /// Names bound by imports, with the import's span and visibility
+    imported: HashMap<(ScopeId, Namespace, String), (Span, Visibility)>,
+    /// Imports not yet resolved
+    pending: Vec<PendingImport<'a>>,
+    /// Module being walked, for `self::` and `super::` paths
+    module: DefId,
+    /// Closure expression owning each closure scope
+    closures: HashMap<ScopeId, NodeId>,
+}
+
+/// One name bound by an `import`, possibly from inside a brace group
+struct PendingImport<'a> {
+    /// Module the import appears in
+    module: DefId,
+    /// Scope the name is bound into
+    scope: ScopeId,
+    path: Vec<String>,
+    tree: &'a ImportTree,
+    visibility: Visibility,
+}
+
+/// A resolved import path
+#[derive(Default)]
+struct ResolvedImport {
+    /// The named definition in each namespace it exists in
+    targets: Vec<(Namespace, DefId)>,
+    /// Each member lookup made: owner, namespace, segment index and definition found
+    steps: Vec<(DefId, Namespace, usize, DefId)>,
+}
+
+impl Default for Resolver<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new() -> Self {
+        let mut resolver = Self {
+            modules: Vec::new(),
+            res: Resolution::default(),
+            diagnostics: Vec::new(),
+            prelude: ScopeId(0),
+            root: DefId(0),
+            imported: HashMap::new(),
+            pending: Vec::new(),
+            module: DefId(0),
+            closures: HashMap::new(),
+        };
+        resolver.prelude = resolver.new_scope(ScopeKind::Prelude, None);
+        resolver.install_prelude();
+
+        let root_scope = resolver.new_scope(ScopeKind::Module, Some(resolver.prelude));
+        resolver.root = resolver.add_def("crate", DefKind::Module, Span::dummy(), NodeId::DUMMY, None);
+        resolver.res.members.insert(resolver.root, root_scope);
+        resolver.res.scope_modules.insert(root_scope, resolver.root);
+        resolver.module = resolver.root;
+        resolver
+    }
+
+    /// Register a parsed file as the module at `path` from the crate root
+    pub fn add_module(&mut self, path: &[String], program: &'a Program) {
+        self.modules.push((path.to_vec(), program));
+    }
+
+    /// Resolve every registered module
+    pub fn finish(mut self) -> (Resolution, Vec<Diagnostic>) {
+        let modules = std::mem::take(&mut self.modules);
+        let mut module_scopes = Vec::new();
+
+        for (path, program) in &modules {
+            let (def, scope) = self.module_for_path(path, program);
+            self.module = def;
+            self.collect_items(scope, def, &program.items);
+            module_scopes.push((def, scope));
+        }
+
+        self.resolve_imports();
+
+        for ((_, program), &(_, scope)) in modules.iter().zip(&module_scopes) {
+            self.attach_impls(scope, &program.items);
+        }
+
+        for ((_, program), &(def, scope)) in modules.iter().zip(&module_scopes) {
+            self.module = def;
+            self.walk_items(scope, &program.items);
+        }
+
+        self.diagnostics
+            .sort_by_key(|d| d.primary_span().map(|s| (s.file, s.start)));
+        (self.res, self.diagnostics)
+    }
+
+    // ========== Definitions & Scopes ==========
+
+    fn new_scope(&mut self, kind: ScopeKind, parent: Option<ScopeId>) -> ScopeId {
+        let id = ScopeId(self.res.scopes.len() as u32);
+        self.res.scopes.push(Scope::new(kind, parent));
+        id
+    }
+
+    fn add_def(&mut self, name: &str, kind: DefKind, span: Span, node: NodeId, parent: Option<DefId>) -> DefId {
+        let id = DefId(self.res.defs.len() as u32);
+        let visibility = Visibility::Public;
+        self.res.defs.push(Definition { id, name: name.to_string(), kind, visibility, span, node, parent });
+        if node != NodeId::DUMMY {
+            self.res.node_defs.insert(node, id);
+        }
+        id
+    }
+
+    /// Record the declared visibility of the definition made for `node`
+    fn set_visibility(&mut self, node: NodeId, visibility: Visibility) {
+        if let Some(def) = self.res.def_of_node(node) {
+            self.res.defs[def.0 as usize].visibility = visibility;
+        }
+    }
+
+    fn bind(&mut self, scope: ScopeId, ns: Namespace, name: &str, def: DefId) {
+        self.res.scopes[scope.0 as usize]
+            .table_mut(ns)
+            .insert(name.to_string(), def);
+    }
+
+    fn kind(&self, def: DefId) -> DefKind {
+        self.res.def(def).kind
+    }
+
+    /// Define an item, reporting a clash with an earlier definition in the same scope
+    fn define(
+        &mut self,
+        scope: ScopeId,
+        name: &str,
+        kind: DefKind,
+        span: Span,
+        node: NodeId,
+        parent: Option<DefId>,
+    ) -> DefId {
+        let def = self.add_def(name, kind, span, node, parent);
+        let mut reported = false;
+        for &ns in kind.namespaces() {
+            match self.res.scope(scope).get(ns, name) {
+                Some(prev) if !reported => {
+                    self.report_redefinition(scope, ns, prev, def);
+                    reported = true;
+                }
+                Some(_) => {}
+                None => self.bind(scope, ns, name, def),
+            }
+        }
+        def
+    }
+
+    fn report_redefinition(&mut self, scope: ScopeId, ns: Namespace, prev: DefId, def: DefId) {
+        let def = self.res.def(def);
+        let prev = self.res.def(prev);
+        let container = match self.res.scope(scope).kind {
+            ScopeKind::Members => "type",
+            ScopeKind::Module | ScopeKind::Prelude => "module",
+            _ => "block",
+        };
+        let ns = match ns {
+            Namespace::Type => "type",
+            Namespace::Value => "value",
+        };
+        let diag = Diagnostic::error(format!("the name `{}` is defined multiple times", def.name))
+            .with_code(codes::DUPLICATE_DEFINITION)
+            .with_primary(def.span, format!("`{}` redefined here", def.name))
+            .with_secondary(
+                prev.span,
+                format!("previous definition of the {} `{}` here", prev.kind.describe(), prev.name),
+            )
+            .with_note(format!(
+                "`{}` must be defined only once in the {} namespace of this {}",
+                def.name, ns, container
+            ));
+        self.diagnostics.push(diag);
+    }
+
+    fn install_prelude(&mut self) {
+        let prelude = self.prelude;
+        let add = |this: &mut Self, name: &str, kind: DefKind| {
+            let def = this.add_def(name, kind, Span::dummy(), NodeId::DUMMY, None);
+            for &ns in kind.namespaces() {
+                this.bind(prelude, ns, name, def);
+            }
+            def
+        };
+        // Type parameters belong to their type but are bound in no scope
+        let add_params = |this: &mut Self, owner: DefId, params: &[&str]| {
+            for param in params {
+                this.add_def(param, DefKind::TypeParam, Span::dummy(), NodeId::DUMMY, Some(owner));
+            }
+        };
+        for (name, params) in prelude::TYPES {
+            let def = add(self, name, DefKind::Struct);
+            add_params(self, def, params);
+        }
+        for name in prelude::TRAITS {
+            add(self, name, DefKind::Trait);
+        }
+        for name in prelude::FUNCTIONS {
+            add(self, name, DefKind::Function);
+        }
+        for prelude_enum in prelude::ENUMS {
+            let def = add(self, prelude_enum.name, DefKind::Enum);
+            add_params(self, def, prelude_enum.params);
+            let members = self.new_scope(ScopeKind::Members, None);
+            self.res.members.insert(def, members);
+            for (variant, _) in prelude_enum.variants {
+                let id = self.add_def(variant, DefKind::Variant, Span::dummy(), NodeId::DUMMY, Some(def));
+                for ns in [Namespace::Type, Namespace::Value] {
+                    self.bind(members, ns, variant, id);
+                    self.bind(prelude, ns, variant, id);
+                }
+            }
+        }
+    }
+
+    /// Module definition and scope for a file at `path`, creating parent modules as needed
+    fn module_for_path(&mut self, path: &[String], program: &Program) -> (DefId, ScopeId) {
+        let mut current = self.root;
+        for (i, segment) in path.iter().enumerate() {
+            let parent_scope = self.res.members[&current];
+            current = match self.res.member(current, Namespace::Type, segment) {
+                Some(def) if self.kind(def) == DefKind::Module => def,
+                _ => {
+                    let (span, node) = if i == path.len() - 1 {
+                        (program.span, program.id)
+                    } else {
+                        (Span::dummy(), NodeId::DUMMY)
+                    };
+                    let def = self.define(parent_scope, segment, DefKind::Module, span, node, Some(current));
+                    let scope = self.new_scope(ScopeKind::Module, Some(self.prelude));
+                    self.res.members.insert(def, scope);
+                    self.res.scope_modules.insert(scope, def);
+                    def
+                }
+            };
+        }
+        if path.is_empty() {
+            let root = &mut self.res.defs[self.root.0 as usize];
+            root.span = program.span;
+            root.node = program.id;
+            self.res.node_defs.insert(program.id, self.root);
+        }
+        let scope = self.res.members[&current];
+        self.res.node_scopes.insert(program.id, scope);
+        (current, scope)
+    }
+
+    // ========== Collection ==========
+
+    fn collect_items(&mut self, scope: ScopeId, module: DefId, items: &'a [Item]) {
+        for item in items {
+            self.collect_item(scope, module, item);
+        }
+    }
+
+    /// Define the names an item introduces into `scope`
+    fn collect_item(&mut self, scope: ScopeId, module: DefId, item: &'a Item) {
+        let parent = Some(module);
+        match &item.kind {
+            ItemKind::Function(f) => {
+                self.define(scope, &f.name, DefKind::Function, f.span, f.id, parent);
+            }
+            ItemKind::Struct(s) => {
+                let def = self.define(scope, &s.name, DefKind::Struct, s.span, s.id, parent);
+                let members = self.new_scope(ScopeKind::Members, None);
+                self.res.members.insert(def, members);
+                self.res.fields.insert(def, field_defs(&s.fields));
+            }
+            ItemKind::Enum(e) => {
+                let def = self.define(scope, &e.name, DefKind::Enum, e.span, e.id, parent);
+                let members = self.new_scope(ScopeKind::Members, None);
+                self.res.members.insert(def, members);
+                for variant in &e.variants {
+                    let id = self.define(members, &variant.name, DefKind::Variant, variant.span, variant.id, Some(def));
+                    if let VariantData::Struct(fields) = &variant.data {
+                        self.res.fields.insert(id, field_defs(fields));
+                    }
+                }
+            }
+            ItemKind::Trait(t) => {
+                let def = self.define(scope, &t.name, DefKind::Trait, t.span, t.id, parent);
+                let members = self.new_scope(ScopeKind::Members, None);
+                self.res.members.insert(def, members);
+                for trait_item in &t.items {
+                    match trait_item {
+                        TraitItem::Function(f) | TraitItem::Signature(f) => {
+                            self.define(members, &f.name, DefKind::Function, f.span, f.id, Some(def));
+                        }
+                        TraitItem::Type(name) => {
+                            self.define(members, name, DefKind::AssocType, t.span, NodeId::DUMMY, Some(def));
+                        }
+                        TraitItem::Const(c) => {
+                            self.define(members, &c.name, DefKind::Const, c.span, c.id, Some(def));
+                        }
+                    }
+                }
+            }
+            ItemKind::Impl(_) | ItemKind::Comptime(_) => {}
+            ItemKind::Module(m) => {
+                let def = self.define(scope, &m.name, DefKind::Module, m.span, m.id, parent);
+                let inner = self.new_scope(ScopeKind::Module, Some(self.prelude));
+                self.res.members.insert(def, inner);
+                self.res.scope_modules.insert(inner, def);
+                self.res.node_scopes.insert(m.id, inner);
+                self.collect_items(inner, def, &m.items);
+            }
+            ItemKind::Import(import) => {
+                for (path, tree) in import.leaves() {
+                    let visibility = import.visibility;
+                    self.pending.push(PendingImport { module, scope, path, tree, visibility });
+                }
+            }
+            ItemKind::Const(c) => {
+                self.define(scope, &c.name, DefKind::Const, c.span, c.id, parent);
+            }
+            ItemKind::Type(alias) => {
+                self.define(scope, &alias.name, DefKind::TypeAlias, alias.span, alias.id, parent);
+            }
+            ItemKind::SynthFunction(sf) => {
+                self.define(scope, &sf.func.name, DefKind::Function, sf.func.span, sf.func.id, parent);
+            }
+            ItemKind::VerifyFunction(vf) => {
+                self.define(scope, &vf.func.name, DefKind::Function, vf.func.span, vf.func.id, parent);
+            }
+            ItemKind::Agent(a) => {
+                self.define(scope, &a.name, DefKind::Agent, a.span, a.id, parent);
+            }
+            ItemKind::Workflow(w) => {
+                self.define(scope, &w.name, DefKind::Workflow, w.span, w.id, parent);
+            }
+            ItemKind::Error => {}
+        }
+        if let (Some(node), Some(visibility)) = (item_node(&item.kind), item.kind.visibility()) {
+            self.set_visibility(node, visibility);
+        }
+    }
+
+    // ========== Imports ==========
+
+    /// Resolve pending imports until no more make progress, then report the rest
+    fn resolve_imports(&mut self) {
+        let mut pending = std::mem::take(&mut self.pending);
+        loop {
+            let before = pending.len();
+            pending.retain(|import| !self.try_import(import));
+            if pending.is_empty() || pending.len() == before {
+                break;
+            }
+        }
+        for import in pending {
+            self.report_unresolved_import(&import);
+        }
+    }
+
+    /// Bind an import if its path resolves; returns false to retry later
+    fn try_import(&mut self, import: &PendingImport<'a>) -> bool {
+        let Some(last) = import.path.last() else {
+            return true;
+        };
+        let name = import.tree.alias().unwrap_or(last).to_string();
+
+        if prelude::EXTERN_CRATES.contains(&import.path[0].as_str()) {
+            let def = self.add_def(&name, DefKind::External, import.tree.span, import.tree.id, None);
+            self.bind_import(import, &name, &[(Namespace::Type, def), (Namespace::Value, def)]);
+            return true;
+        }
+
+        match self.resolve_import_path(import.module, import.scope, &import.path) {
+            Ok(resolved) => {
+                for &(owner, ns, segment, def) in &resolved.steps {
+                    if !self.check_access(import.module, owner, ns, &import.path[segment], def, import.tree.span) {
+                        break;
+                    }
+                }
+                self.bind_import(import, &name, &resolved.targets);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Definitions an import path names; on failure, the index of the first
+    /// segment that could not be found
+    fn resolve_import_path(&self, module: DefId, scope: ScopeId, path: &[String]) -> Result<ResolvedImport, usize> {
+        let mut current = match path[0].as_str() {
+            "crate" => self.root,
+            "self" => module,
+            "super" => self.res.def(module).parent.ok_or(0usize)?,
+            first => {
+                // Names visible where the import appears, then modules at the crate root
+                self.res
+                    .lookup(scope, Namespace::Type, first)
+                    .or_else(|| self.res.member(self.root, Namespace::Type, first))
+                    .or_else(|| self.res.lookup(scope, Namespace::Value, first))
+                    .ok_or(0usize)?
+            }
+        };
+
+        let mut resolved = ResolvedImport::default();
+        if path.len() == 1 {
+            resolved.targets.push((Namespace::Type, current));
+            return Ok(resolved);
+        }
+        for (i, segment) in path.iter().enumerate().take(path.len() - 1).skip(1) {
+            let def = self.res.member(current, Namespace::Type, segment).ok_or(i)?;
+            resolved.steps.push((current, Namespace::Type, i, def));
+            current = def;
+        }
+
+        let last = path.len() - 1;
+        for ns in [Namespace::Type, Namespace::Value] {
+            if let Some(def) = self.res.member(current, ns, &path[last]) {
+                resolved.targets.push((ns, def));
+                resolved.steps.push((current, ns, last, def));
+            }
+        }
+        if resolved.targets.is_empty() {
+            Err(last)
+        } else {
+            Ok(resolved)
+        }
+    }
+
+    fn bind_import(&mut self, import: &PendingImport<'a>, name: &str, targets: &[(Namespace, DefId)]) {
+        if let Some(&(_, first)) = targets.first() {
+            self.res.uses.insert(import.tree.id, Res::Def(first));
+        }
+
+        let scope = import.scope;
+        let mut reported = false;
+        for &(ns, def) in targets {
+            let key = (scope, ns, name.to_string());
+            match self.res.scope(scope).get(ns, name) {
+                // Importing the same item twice is harmless
+                Some(prev) if prev == def => {}
+                Some(prev) if !reported => {
+                    reported = true;
+                    let prev_import = self.imported.get(&key).map(|&(span, _)| span);
+                    self.report_import_conflict(import, name, prev, prev_import);
+                }
+                Some(_) => {}
+                None => {
+                    self.bind(scope, ns, name, def);
+                    self.imported.insert(key, (import.tree.span, import.visibility));
+                }
+            }
+        }
+    }
+
+    fn report_import_conflict(&mut self, import: &PendingImport<'a>, name: &str, prev: DefId, prev_import: Option<Span>) {
+        let span = import.tree.span;
+        let mut diag = Diagnostic::error(format!("the name `{}` is defined multiple times", name))
+            .with_code(codes::IMPORT_CONFLICT);
+        diag = match prev_import {
+            Some(prev_span) => diag
+                .with_primary(span, format!("`{}` reimported here", name))
+                .with_secondary(prev_span, format!("previous import of `{}` here", name)),
+            None => {
+                let prev = self.res.def(prev);
+                diag.with_primary(span, format!("import of `{}` shadows a local {}", name, prev.kind.describe()))
+                    .with_secondary(prev.span, format!("`{}` is defined here", name))
+            }
+        };
+
+        // Offer `as` right after a single-line import path
+        if import.tree.alias().is_none() {
+            let column = span.column + span.len() as u32;
+            let at = Span::new(span.file, span.end, span.end, span.line, column);
+            diag = diag.with_suggestion(Suggestion::new(
+                "use `as` to bind the import under a different name",
+                at,
+                format!(" as other_{}", name),
+            ));
+        }
+        self.diagnostics.push(diag);
+    }
+
+    fn report_unresolved_import(&mut self, import: &PendingImport<'a>) {
+        let path = &import.path;
+        let failed = self
+            .resolve_import_path(import.module, import.scope, path)
+            .err()
+            .unwrap_or(0);
+        let segment = &path[failed];
+        let label = if failed == 0 {
+            if segment == "super" {
+                "there is no parent module".to_string()
+            } else {
+                format!("no module or item `{}` in the crate", segment)
+            }
+        } else {
+            format!("no `{}` in `{}`", segment, path[..failed].join("::"))
+        };
+        self.diagnostics.push(
+            Diagnostic::error(format!("unresolved import `{}`", path.join("::")))
+                .with_code(codes::UNRESOLVED_IMPORT)
+                .with_primary(import.tree.span, label),
+        );
+    }
+
+    // ========== Privacy ==========
+
+    /// Check that `def`, found as member `name` of `owner`, may be named from
+    /// module `from`. An imported name is as visible as its `import`.
+    fn check_access(&mut self, from: DefId, owner: DefId, ns: Namespace, name: &str, def: DefId, span: Span) -> bool {
+        let Some(&members) = self.res.members.get(&owner) else {
+            return true;
+        };
+        let import = self.imported.get(&(members, ns, name.to_string())).copied();
+        let (visibility, home) = match import {
+            Some((_, visibility)) => (visibility, Some(owner)),
+            None => (self.res.def(def).visibility, privacy::home_module(&self.res, def)),
+        };
+        if privacy::is_visible(&self.res, visibility, home, from) {
+            return true;
+        }
+
+        let def = self.res.def(def);
+        let kind = def.kind.describe();
+        let mut diag = match import {
+            Some((import_span, _)) => Diagnostic::error(format!("{} import `{}` is private", kind, name))
+                .with_code(codes::PRIVATE_ITEM)
+                .with_primary(span, format!("private {} import", kind))
+                .with_secondary(import_span, format!("the {} import `{}` is defined here", kind, name)),
+            None => Diagnostic::error(format!("{} `{}` is private", kind, name))
+                .with_code(codes::PRIVATE_ITEM)
+                .with_primary(span, format!("private {}", kind))
+                .with_secondary(def.span, format!("the {} `{}` is defined here", kind, name)),
+        };
+        if import.is_none() && !def.span.is_dummy() {
+            diag = diag.with_note(format!("mark `{}` as `pub` to use it outside its module", name));
+        }
+        self.diagnostics.push(diag);
+        false
+    }
+
+    /// Check the fields named by a struct literal or pattern against their
+    /// visibility. A literal must also be able to see every field it initializes,
+    /// so a struct with any hidden field cannot be built outside its module.
+    fn check_fields<'f>(
+        &mut self,
+        owner: DefId,
+        named: impl Iterator<Item = (&'f str, Span)>,
+        span: Span,
+        is_literal: bool,
+    ) {
+        let Some(fields) = self.res.fields.get(&owner) else {
+            return;
+        };
+        let home = privacy::home_module(&self.res, owner);
+        let hidden: Vec<(String, Span)> = fields
+            .iter()
+            .filter(|f| !privacy::is_visible(&self.res, f.visibility, home, self.module))
+            .map(|f| (f.name.clone(), f.span))
+            .collect();
+        if hidden.is_empty() {
+            return;
+        }
+
+        let owner = self.res.def(owner);
+        let (kind, owner_name) = (owner.kind.describe(), owner.name.clone());
+        let mut reported = false;
+        for (name, at) in named {
+            let Some((_, declared)) = hidden.iter().find(|(field, _)| field == name) else {
+                continue;
+            };
+            reported = true;
+            self.diagnostics.push(
+                Diagnostic::error(format!("field `{}` of {} `{}` is private", name, kind, owner_name))
+                    .with_code(codes::PRIVATE_FIELD)
+                    .with_primary(at, "private field")
+                    .with_secondary(*declared, format!("`{}` declared here", name)),
+            );
+        }
+        if is_literal && !reported {
+            let names: Vec<_> = hidden.iter().map(|(name, _)| format!("`{}`", name)).collect();
+            let note = match names.as_slice() {
+                [one] => format!("field {} is private", one),
+                many => format!("fields {} are private", many.join(", ")),
+            };
+            self.diagnostics.push(
+                Diagnostic::error(format!(
+                    "cannot construct `{}` with struct literal syntax due to private fields",
+                    owner_name
+                ))
+                .with_code(codes::PRIVATE_FIELD)
+                .with_primary(span, "private fields cannot be initialized here")
+                .with_note(note),
+            );
+        }
+    }
+
+    // ========== Impls ==========
+
+    fn attach_impls(&mut self, scope: ScopeId, items: &'a [Item]) {
+        for item in items {
+            match &item.kind {
+                ItemKind::Impl(imp) => self.attach_impl(scope, imp),
+                ItemKind::Module(m) => {
+                    if let Some(&inner) = self.res.node_scopes.get(&m.id) {
+                        self.attach_impls(inner, &m.items);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Add the items of an impl to its self type's associated items, so
+    /// paths like `Point::new` resolve. Items of impls for types without
+    /// members, such as `impl Trait for i32`, are defined but bound nowhere.
+    fn attach_impl(&mut self, scope: ScopeId, imp: &'a Impl) {
+        let self_def = match &imp.self_ty.kind {
+            TypeKind::Named(name) | TypeKind::Generic { name, .. } => self.res.lookup(scope, Namespace::Type, name),
+            _ => None,
+        };
+        if let Some(self_def) = self_def {
+            self.res.impl_self.insert(imp.id, self_def);
+        }
+        let members = self_def.and_then(|def| self.res.members.get(&def).copied());
+
+        for impl_item in &imp.items {
+            let (name, kind, span, node, visibility) = match impl_item {
+                ImplItem::Function(f) => (&f.name, DefKind::Function, f.span, f.id, f.visibility),
+                ImplItem::Type(alias) => (&alias.name, DefKind::AssocType, alias.span, alias.id, alias.visibility),
+                ImplItem::Const(c) => (&c.name, DefKind::Const, c.span, c.id, c.visibility),
+            };
+            let Some(members) = members else {
+                self.add_def(name, kind, span, node, None);
+                self.set_visibility(node, visibility);
+                continue;
+            };
+            if imp.trait_name.is_none() {
+                self.define(members, name, kind, span, node, self_def);
+                self.set_visibility(node, visibility);
+            } else {
+                // Trait items never clash with inherent ones; the inherent item wins
+                let def = self.add_def(name, kind, span, node, self_def);
+                for &ns in kind.namespaces() {
+                    if self.res.scope(members).get(ns, name).is_none() {
+                        self.bind(members, ns, name, def);
+                    }
+                }
+            }
+        }
+    }
+
+    // ========== Items ==========
+
+    fn walk_items(&mut self, scope: ScopeId, items: &'a [Item]) {
+        for item in items {
+            self.walk_item(scope, item);
+        }
+    }
+
+    fn walk_item(&mut self, scope: ScopeId, item: &'a Item) {
+        match &item.kind {
+            ItemKind::Function(f) => {
+                self.walk_function(scope, f);
+            }
+            ItemKind::Struct(s) => {
+                let inner = self.generics_scope(scope, s.id, &s.generics);
+                self.walk_where_clause(inner, s.where_clause.as_ref());
+                self.walk_fields(inner, &s.fields);
+            }
+            ItemKind::Enum(e) => {
+                let inner = self.generics_scope(scope, e.id, &e.generics);
+                for variant in &e.variants {
+                    match &variant.data {
+                        VariantData::Unit => {}
+                        VariantData::Tuple(types) => {
+                            for ty in types {
+                                self.resolve_type(inner, ty);
+                            }
+                        }
+                        VariantData::Struct(fields) => self.walk_fields(inner, fields),
+                    }
+                }
+            }
+            ItemKind::Trait(t) => self.walk_trait(scope, t),
+            ItemKind::Impl(imp) => self.walk_impl(scope, imp),
+            ItemKind::Module(m) => {
+                let (Some(&inner), Some(def)) = (self.res.node_scopes.get(&m.id), self.res.def_of_node(m.id)) else {
+                    return;
+                };
+                let saved = std::mem::replace(&mut self.module, def);
+                self.walk_items(inner, &m.items);
+                self.module = saved;
+            }
+            ItemKind::Import(_) => {}
+            ItemKind::Const(c) => {
+                self.resolve_type(scope, &c.ty);
+                self.resolve_expr(scope, &c.value);
+            }
+            ItemKind::Comptime(expr) => self.resolve_expr(scope, expr),
+            ItemKind::Type(alias) => {
+                let inner = self.generics_scope(scope, alias.id, &alias.generics);
+                self.resolve_type(inner, &alias.ty);
+            }
+            ItemKind::SynthFunction(sf) => {
+                let inner = self.walk_function(scope, &sf.func);
+                if let Some(spec) = &sf.spec {
+                    self.resolve_expr(inner, spec);
+                }
+                for (input, output) in &sf.examples {
+                    self.resolve_expr(inner, input);
+                    self.resolve_expr(inner, output);
+                }
+            }
+            ItemKind::VerifyFunction(vf) => {
+                let inner = self.walk_function(scope, &vf.func);
+                self.resolve_expr(inner, &vf.property);
+            }
+            ItemKind::Agent(a) => self.walk_agent(scope, a),
+            ItemKind::Workflow(w) => self.walk_workflow(scope, w),
+            ItemKind::Error => {}
+        }
+    }
+
+    fn walk_fields(&mut self, scope: ScopeId, fields: &'a [Field]) {
+        let mut seen: HashMap<&str, Span> = HashMap::new();
+        for field in fields {
+            self.resolve_type(scope, &field.ty);
+            if let Some(prev) = seen.insert(&field.name, field.span) {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("field `{}` is already declared", field.name))
+                        .with_code(codes::DUPLICATE_DEFINITION)
+                        .with_primary(field.span, "field already declared")
+                        .with_secondary(prev, format!("`{}` first declared here", field.name)),
+                );
+            }
+        }
+    }
+
+    /// Scope binding the generic parameters of a struct, enum or type alias
+    fn generics_scope(&mut self, parent: ScopeId, node: NodeId, generics: &'a [Generic]) -> ScopeId {
+        let scope = self.new_scope(ScopeKind::Generics, Some(parent));
+        self.res.node_scopes.insert(node, scope);
+        self.define_generics(scope, generics);
+        scope
+    }
+
+    fn define_generics(&mut self, scope: ScopeId, generics: &'a [Generic]) {
+        for generic in generics {
+            self.define(scope, &generic.name, DefKind::TypeParam, generic.span, generic.id, None);
+        }
+        for generic in generics {
+            self.resolve_bounds(scope, generic.id, &generic.bounds, generic.span);
+        }
+    }
+
+    fn walk_where_clause(&mut self, scope: ScopeId, clause: Option<&'a WhereClause>) {
+        for predicate in clause.iter().flat_map(|c| &c.predicates) {
+            self.resolve_type(scope, &predicate.ty);
+            self.resolve_bounds(scope, predicate.id, &predicate.bounds, predicate.span);
+        }
+    }
+
+    fn resolve_bounds(&mut self, scope: ScopeId, node: NodeId, bounds: &[String], span: Span) {
+        let mut traits = Vec::new();
+        for bound in bounds {
+            match self.res.lookup(scope, Namespace::Type, bound) {
+                Some(def) if matches!(self.kind(def), DefKind::Trait | DefKind::External) => traits.push(def),
+                Some(def) => self.expected_trait(def, span),
+                None => self.unresolved(scope, Namespace::Type, "trait", bound, span),
+            }
+        }
+        if !traits.is_empty() {
+            self.res.bounds.insert(node, traits);
+        }
+    }
+
+    fn walk_function(&mut self, parent: ScopeId, f: &'a Function) -> ScopeId {
+        let scope = self.new_scope(ScopeKind::Function, Some(parent));
+        self.res.node_scopes.insert(f.id, scope);
+        self.define_generics(scope, &f.generics);
+        self.walk_where_clause(scope, f.where_clause.as_ref());
+        self.define_params(scope, &f.params);
+        if let Some(ty) = &f.return_type {
+            self.resolve_type(scope, ty);
+        }
+        if let Some(contract) = &f.contract {
+            self.walk_contract(scope, contract);
+        }
+        self.walk_block(scope, &f.body);
+        scope
+    }
+
+    fn define_params(&mut self, scope: ScopeId, params: &'a [Param]) {
+        for param in params {
+            self.resolve_type(scope, &param.ty);
+            if let Some(prev) = self.res.scope(scope).get(Namespace::Value, &param.name) {
+                let prev = self.res.def(prev).span;
+                self.diagnostics.push(
+                    Diagnostic::error(format!(
+                        "identifier `{}` is bound more than once in this parameter list",
+                        param.name
+                    ))
+                    .with_code(codes::DUPLICATE_DEFINITION)
+                    .with_primary(param.span, "used as parameter more than once")
+                    .with_secondary(prev, "first bound here"),
+                );
+                continue;
+            }
+            let def = self.add_def(&param.name, DefKind::Param, param.span, param.id, None);
+            self.bind(scope, Namespace::Value, &param.name, def);
+        }
+    }
+
+    fn walk_contract(&mut self, scope: ScopeId, contract: &'a Contract) {
+        for expr in contract.preconditions.iter().chain(&contract.invariants) {
+            self.resolve_expr(scope, expr);
+        }
+        if contract.postconditions.is_empty() {
+            return;
+        }
+        // Postconditions see the return value as `result`
+        let post = self.new_scope(ScopeKind::Contract, Some(scope));
+        self.res.node_scopes.insert(contract.id, post);
+        let result = self.add_def("result", DefKind::Local, contract.span, contract.id, None);
+        self.bind(post, Namespace::Value, "result", result);
+        for expr in &contract.postconditions {
+            self.resolve_expr(post, expr);
+        }
+    }
+
+    fn walk_trait(&mut self, parent: ScopeId, t: &'a Trait) {
+        let scope = self.new_scope(ScopeKind::Trait, Some(parent));
+        self.res.node_scopes.insert(t.id, scope);
+        let trait_def = self.res.def_of_node(t.id);
+        let self_def = self.add_def("Self", DefKind::SelfType, t.span, NodeId::DUMMY, trait_def);
+        self.bind(scope, Namespace::Type, "Self", self_def);
+        self.define_generics(scope, &t.generics);
+
+        for trait_item in &t.items {
+            match trait_item {
+                TraitItem::Function(f) | TraitItem::Signature(f) => {
+                    self.walk_function(scope, f);
+                }
+                TraitItem::Const(c) => {
+                    self.resolve_type(scope, &c.ty);
+                    self.resolve_expr(scope, &c.value);
+                }
+                TraitItem::Type(_) => {}
+            }
+        }
+    }
+
+    fn walk_impl(&mut self, parent: ScopeId, imp: &'a Impl) {
+        let scope = self.new_scope(ScopeKind::Impl, Some(parent));
+        self.res.node_scopes.insert(imp.id, scope);
+        self.define_generics(scope, &imp.generics);
+        self.resolve_type(scope, &imp.self_ty);
+
+        // `Self` names the implementing struct or enum directly when it is one
+        let self_def = match self.res.impl_self.get(&imp.id) {
+            Some(&def) if matches!(self.kind(def), DefKind::Struct | DefKind::Enum) => def,
+            _ => {
+                let def = self.add_def("Self", DefKind::SelfType, imp.self_ty.span, NodeId::DUMMY, None);
+                self.res.impl_self.insert(imp.id, def);
+                def
+            }
+        };
+        self.bind(scope, Namespace::Type, "Self", self_def);
+
+        if let Some(name) = &imp.trait_name {
+            match self.res.lookup(scope, Namespace::Type, name) {
+                Some(def) if matches!(self.kind(def), DefKind::Trait | DefKind::External) => {
+                    self.res.uses.insert(imp.id, Res::Def(def));
+                }
+                Some(def) => self.expected_trait(def, imp.span),
+                None => self.unresolved(scope, Namespace::Type, "trait", name, imp.span),
+            }
+        }
+
+        // Invariants describe any value of the type, named `self`
+        if let Some(contract) = &imp.contract {
+            let inner = self.new_scope(ScopeKind::Contract, Some(scope));
+            self.res.node_scopes.insert(contract.id, inner);
+            let self_value = self.add_def("self", DefKind::Param, contract.span, contract.id, None);
+            self.bind(inner, Namespace::Value, "self", self_value);
+            for expr in &contract.invariants {
+                self.resolve_expr(inner, expr);
+            }
+        }
+
+        for impl_item in &imp.items {
+            match impl_item {
+                ImplItem::Function(f) => {
+                    self.walk_function(scope, f);
+                }
+                ImplItem::Type(alias) => {
+                    let inner = self.generics_scope(scope, alias.id, &alias.generics);
+                    self.resolve_type(inner, &alias.ty);
+                }
+                ImplItem::Const(c) => {
+                    self.resolve_type(scope, &c.ty);
+                    self.resolve_expr(scope, &c.value);
+                }
+            }
+        }
+    }
+
+    fn walk_agent(&mut self, parent: ScopeId, agent: &'a Agent) {
+        let scope = self.new_scope(ScopeKind::Agent, Some(parent));
+        self.res.node_scopes.insert(agent.id, scope);
+        for field in &agent.state {
+            self.resolve_type(scope, &field.ty);
+            self.define(scope, &field.name, DefKind::StateField, field.span, field.id, None);
+        }
+        for capability in &agent.capabilities {
+            let inner = self.new_scope(ScopeKind::Function, Some(scope));
+            self.res.node_scopes.insert(capability.id, inner);
+            self.define_params(inner, &capability.params);
+            self.resolve_type(inner, &capability.return_type);
+        }
+        for goal in &agent.goals {
+            self.resolve_expr(scope, &goal.expr);
+        }
+        for handler in &agent.communication {
+            self.walk_block(scope, &handler.handler);
+        }
+    }
+
+    fn walk_workflow(&mut self, scope: ScopeId, workflow: &'a Workflow) {
+        for stage in &workflow.stages {
+            self.walk_stage(scope, stage);
+        }
+        for rule in &workflow.coordination {
+            match &rule.kind {
+                CoordinationRuleKind::Consensus { on, .. } | CoordinationRuleKind::Voting { on, .. } => {
+                    self.resolve_expr(scope, on);
+                }
+            }
+        }
+    }
+
+    fn walk_stage(&mut self, scope: ScopeId, stage: &'a Stage) {
+        match &stage.kind {
+            StageKind::Agent(name) => {
+                if self.res.lookup(scope, Namespace::Type, name).is_none() {
+                    self.unresolved(scope, Namespace::Type, "agent", name, stage.span);
+                }
+            }
+            StageKind::Parallel(stages) => {
+                for stage in stages {
+                    self.walk_stage(scope, stage);
+                }
+            }
+            StageKind::Conditional { cond, then_stage, else_stage } => {
+                self.resolve_expr(scope, cond);
+                self.walk_stage(scope, then_stage);
+                if let Some(stage) = else_stage {
+                    self.walk_stage(scope, stage);
+                }
+            }
+        }
+    }
+
+    // ========== Blocks & Expressions ==========
+
+    fn walk_block(&mut self, parent: ScopeId, block: &'a Block) {
+        let scope = self.new_scope(ScopeKind::Block, Some(parent));
+        self.res.node_scopes.insert(block.id, scope);
+
+        // Items are visible throughout their block, before their definition too
+        for stmt in &block.stmts {
+            if let StatementKind::Item(item) = &stmt.kind {
+                self.collect_item(scope, self.module, item);
+            }
+        }
+        self.resolve_imports();
+        for stmt in &block.stmts {
+            if let StatementKind::Item(item) = &stmt.kind {
+                if let ItemKind::Impl(imp) = &item.kind {
+                    self.attach_impl(scope, imp);
+                }
+            }
+        }
+
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StatementKind::Let { pattern, ty, init, .. } => {
+                    if let Some(ty) = ty {
+                        self.resolve_type(scope, ty);
+                    }
+                    if let Some(init) = init {
+                        self.resolve_expr(scope, init);
+                    }
+                    self.bind_pattern(scope, pattern, &mut HashMap::new());
+                }
+                StatementKind::Expression(expr) => self.resolve_expr(scope, expr),
+                StatementKind::Item(item) => self.walk_item(scope, item),
+                StatementKind::Error => {}
+            }
+        }
+        if let Some(expr) = &block.expr {
+            self.resolve_expr(scope, expr);
+        }
+    }
+
+    fn resolve_expr(&mut self, scope: ScopeId, expr: &'a Expression) {
+        match &expr.kind {
+            ExpressionKind::Literal(_) | ExpressionKind::Continue => {}
+            ExpressionKind::Identifier(name) => {
+                self.resolve_name(scope, expr.id, expr.span, name, Namespace::Value, "value");
+            }
+            ExpressionKind::Path(path) => self.resolve_path(scope, expr.id, expr.span, path, Namespace::Value),
+            ExpressionKind::Binary { left, right, .. } => {
+                self.resolve_expr(scope, left);
+                self.resolve_expr(scope, right);
+            }
+            ExpressionKind::Unary { expr, .. } | ExpressionKind::Comptime(expr) => self.resolve_expr(scope, expr),
+            ExpressionKind::Call { func, args } => {
+                match &func.kind {
+                    ExpressionKind::Identifier(name) => {
+                        self.resolve_name(scope, func.id, func.span, name, Namespace::Value, "function");
+                    }
+                    _ => self.resolve_expr(scope, func),
+                }
+                self.resolve_exprs(scope, args);
+            }
+            ExpressionKind::MethodCall { receiver, args, .. } => {
+                self.resolve_expr(scope, receiver);
+                self.resolve_exprs(scope, args);
+            }
+            ExpressionKind::If { cond, then_block, else_block } => {
+                self.resolve_expr(scope, cond);
+                self.walk_block(scope, then_block);
+                if let Some(block) = else_block {
+                    self.walk_block(scope, block);
+                }
+            }
+            ExpressionKind::Match { expr, arms } => {
+                self.resolve_expr(scope, expr);
+                for arm in arms {
+                    let arm_scope = self.new_scope(ScopeKind::MatchArm, Some(scope));
+                    self.res.node_scopes.insert(arm.id, arm_scope);
+                    self.bind_pattern(arm_scope, &arm.pattern, &mut HashMap::new());
+                    if let Some(guard) = &arm.guard {
+                        self.resolve_expr(arm_scope, guard);
+                    }
+                    self.resolve_expr(arm_scope, &arm.body);
+                }
+            }
+            ExpressionKind::Loop(body) => self.walk_block(scope, body),
+            ExpressionKind::While { cond, body } => {
+                self.resolve_expr(scope, cond);
+                self.walk_block(scope, body);
+            }
+            ExpressionKind::For { pattern, iter, body } => {
+                self.resolve_expr(scope, iter);
+                let loop_scope = self.new_scope(ScopeKind::Loop, Some(scope));
+                self.res.node_scopes.insert(expr.id, loop_scope);
+                self.bind_pattern(loop_scope, pattern, &mut HashMap::new());
+                self.walk_block(loop_scope, body);
+            }
+            ExpressionKind::Return(value) | ExpressionKind::Break(value) => {
+                if let Some(value) = value {
+                    self.resolve_expr(scope, value);
+                }
+            }
+            ExpressionKind::Block(block) => self.walk_block(scope, block),
+            ExpressionKind::Tuple(elems) | ExpressionKind::Array(elems) => self.resolve_exprs(scope, elems),
+            ExpressionKind::Index { expr, index } => {
+                self.resolve_expr(scope, expr);
+                self.resolve_expr(scope, index);
+            }
+            ExpressionKind::Field { expr, .. } => self.resolve_expr(scope, expr),
+            ExpressionKind::Struct { path, fields } => {
+                self.resolve_path(scope, expr.id, expr.span, path, Namespace::Type);
+                for (_, value) in fields {
+                    self.resolve_expr(scope, value);
+                }
+                if let Some(Res::Def(def)) = self.res.uses.get(&expr.id) {
+                    let named = fields.iter().map(|(name, value)| (name.as_str(), value.span));
+                    self.check_fields(*def, named, expr.span, true);
+                }
+            }
+            ExpressionKind::Intent { options, .. } => self.resolve_config(scope, options),
+            ExpressionKind::Synth { config, expr } | ExpressionKind::Verify { config, expr } => {
+                self.resolve_config(scope, config);
+                self.resolve_expr(scope, expr);
+            }
+            ExpressionKind::Hybrid { symbolic, neural, .. } => {
+                self.resolve_expr(scope, symbolic);
+                self.resolve_expr(scope, neural);
+            }
+            ExpressionKind::Spawn { agent, config } => {
+                match self.res.lookup(scope, Namespace::Type, agent) {
+                    Some(def) => {
+                        self.res.uses.insert(expr.id, Res::Def(def));
+                    }
+                    None => self.unresolved(scope, Namespace::Type, "agent", agent, expr.span),
+                }
+                self.resolve_config(scope, config);
+            }
+            ExpressionKind::Send { message, recipient } => {
+                self.resolve_expr(scope, message);
+                self.resolve_expr(scope, recipient);
+            }
+            ExpressionKind::Receive { filter, timeout } => {
+                for expr in [filter, timeout].into_iter().flatten() {
+                    self.resolve_expr(scope, expr);
+                }
+            }
+            ExpressionKind::Broadcast { message, .. } => self.resolve_expr(scope, message),
+            ExpressionKind::Closure { params, ret, body, .. } => {
+                let closure_scope = self.new_scope(ScopeKind::Closure, Some(scope));
+                self.res.node_scopes.insert(expr.id, closure_scope);
+                self.closures.insert(closure_scope, expr.id);
+                self.res.captures.insert(expr.id, Vec::new());
+                self.define_params(closure_scope, params);
+                if let Some(ty) = ret {
+                    self.resolve_type(closure_scope, ty);
+                }
+                self.resolve_expr(closure_scope, body);
+            }
+        }
+    }
+
+    fn resolve_exprs(&mut self, scope: ScopeId, exprs: &'a [Expression]) {
+        for expr in exprs {
+            self.resolve_expr(scope, expr);
+        }
+    }
+
+    fn resolve_config(&mut self, scope: ScopeId, config: &'a [(String, Expression)]) {
+        for (_, value) in config {
+            self.resolve_expr(scope, value);
+        }
+    }
+
+    fn resolve_type(&mut self, scope: ScopeId, ty: &'a Type) {
+        match &ty.kind {
+            TypeKind::Primitive(_) | TypeKind::Inferred => {}
+            TypeKind::Named(name) => self.resolve_name(scope, ty.id, ty.span, name, Namespace::Type, "type"),
+            TypeKind::Generic { name, args } => {
+                self.resolve_name(scope, ty.id, ty.span, name, Namespace::Type, "type");
+                for arg in args {
+                    self.resolve_type(scope, arg);
+                }
+            }
+            TypeKind::Path { path, args } => {
+                self.resolve_path(scope, ty.id, ty.span, path, Namespace::Type);
+                for arg in args {
+                    self.resolve_type(scope, arg);
+                }
+            }
+            TypeKind::Tuple(elems) => {
+                for elem in elems {
+                    self.resolve_type(scope, elem);
+                }
+            }
+            TypeKind::Array { elem, size } => {
+                self.resolve_type(scope, elem);
+                if let Some(size) = size {
+                    self.resolve_expr(scope, size);
+                }
+            }
+            TypeKind::Reference { ty, .. } | TypeKind::Affine(ty) | TypeKind::Fuzzy(ty) => {
+                self.resolve_type(scope, ty);
+            }
+            TypeKind::Function { params, ret } => {
+                for param in params {
+                    self.resolve_type(scope, param);
+                }
+                self.resolve_type(scope, ret);
+            }
+            TypeKind::Learned(exprs) => self.resolve_exprs(scope, exprs),
+        }
+    }
+
+    /// Bind the variables a pattern introduces. An identifier naming a unit
+    /// variant or constant in scope matches against it instead of binding.
+    fn bind_pattern(&mut self, scope: ScopeId, pattern: &'a Pattern, seen: &mut HashMap<String, Span>) {
+        match &pattern.kind {
+            PatternKind::Wildcard | PatternKind::Literal(_) => {}
+            PatternKind::Identifier(name) => {
+                if let Some(def) = self.res.lookup(scope, Namespace::Value, name) {
+                    if matches!(self.kind(def), DefKind::Variant | DefKind::Const) {
+                        self.res.uses.insert(pattern.id, Res::Def(def));
+                        return;
+                    }
+                }
+                if let Some(prev) = seen.insert(name.clone(), pattern.span) {
+                    self.diagnostics.push(
+                        Diagnostic::error(format!(
+                            "identifier `{}` is bound more than once in the same pattern",
+                            name
+                        ))
+                        .with_code(codes::DUPLICATE_DEFINITION)
+                        .with_primary(pattern.span, "used in a pattern more than once")
+                        .with_secondary(prev, "first bound here"),
+                    );
+                    return;
+                }
+                let def = self.add_def(name, DefKind::Local, pattern.span, pattern.id, None);
+                self.bind(scope, Namespace::Value, name, def);
+            }
+            PatternKind::Tuple(elems) => {
+                for elem in elems {
+                    self.bind_pattern(scope, elem, seen);
+                }
+            }
+            PatternKind::Struct { path, fields } => {
+                self.resolve_path(scope, pattern.id, pattern.span, path, Namespace::Type);
+                for (_, field) in fields {
+                    self.bind_pattern(scope, field, seen);
+                }
+                if let Some(Res::Def(def)) = self.res.uses.get(&pattern.id) {
+                    let named = fields.iter().map(|(name, field)| (name.as_str(), field.span));
+                    self.check_fields(*def, named, pattern.span, false);
+                }
+            }
+            PatternKind::TupleStruct { path, elems } => {
+                self.resolve_path(scope, pattern.id, pattern.span, path, Namespace::Value);
+                for elem in elems {
+                    self.bind_pattern(scope, elem, seen);
+                }
+            }
+            PatternKind::Path(path) => self.resolve_path(scope, pattern.id, pattern.span, path, Namespace::Value),
+        }
+    }
+
+    // ========== Lookup ==========
+
+    fn resolve_name(&mut self, scope: ScopeId, node: NodeId, span: Span, name: &str, ns: Namespace, what: &str) {
+        match self.res.lookup(scope, ns, name) {
+            Some(def) => {
+                self.res.uses.insert(node, Res::Def(def));
+                if matches!(self.res.def(def).kind, DefKind::Local | DefKind::Param) {
+                    self.capture(scope, ns, name, def);
+                }
+            }
+            None => self.unresolved(scope, ns, what, name, span),
+        }
+    }
+
+    /// Record local `def`, found by looking `name` up from `scope`, as captured
+    /// by every closure between the two
+    fn capture(&mut self, scope: ScopeId, ns: Namespace, name: &str, def: DefId) {
+        let mut current = scope;
+        while self.res.scope(current).get(ns, name) != Some(def) {
+            if let Some(&closure) = self.closures.get(&current) {
+                let captures = self.res.captures.entry(closure).or_default();
+                if !captures.contains(&def) {
+                    captures.push(def);
+                }
+            }
+            match self.res.scope(current).parent {
+                Some(parent) => current = parent,
+                None => return,
+            }
+        }
+    }
+
+    /// Resolve a path segment by segment. Paths into modules and local traits
+    /// must resolve fully; paths into types may name associated items that
+    /// only type checking can find, and are recorded as partial.
+    fn resolve_path(&mut self, scope: ScopeId, node: NodeId, span: Span, path: &[String], ns: Namespace) {
+        if let [name] = path {
+            let what = match ns {
+                Namespace::Type => "type",
+                Namespace::Value => "value",
+            };
+            self.resolve_name(scope, node, span, name, ns, what);
+            return;
+        }
+
+        let mut current = match path[0].as_str() {
+            "crate" => self.root,
+            "self" => self.module,
+            "super" => match self.res.def(self.module).parent {
+                Some(parent) => parent,
+                None => {
+                    self.diagnostics.push(
+                        Diagnostic::error("there are too many leading `super` keywords")
+                            .with_code(codes::UNRESOLVED_NAME)
+                            .with_primary(span, "the crate root has no parent module"),
+                    );
+                    return;
+                }
+            },
+            first => match self.res.lookup(scope, Namespace::Type, first) {
+                Some(def) => def,
+                None => {
+                    self.diagnostics.push(
+                        Diagnostic::error(format!("failed to resolve: use of undeclared type or module `{}`", first))
+                            .with_code(codes::UNRESOLVED_NAME)
+                            .with_primary(span, format!("use of undeclared type or module `{}`", first)),
+                    );
+                    return;
+                }
+            },
+        };
+
+        let mut denied = false;
+        for (i, segment) in path.iter().enumerate().skip(1) {
+            let last = i == path.len() - 1;
+            let segment_ns = if last { ns } else { Namespace::Type };
+            let other = match segment_ns {
+                Namespace::Type => Namespace::Value,
+                Namespace::Value => Namespace::Type,
+            };
+            let found = self
+                .res
+                .member(current, segment_ns, segment)
+                .map(|def| (segment_ns, def))
+                .or_else(|| last.then(|| Some((other, self.res.member(current, other, segment)?))).flatten());
+
+            match found {
+                Some((found_ns, def)) => {
+                    // Still bind a private item, so later phases see what was meant
+                    if !denied {
+                        denied = !self.check_access(self.module, current, found_ns, segment, def, span);
+                    }
+                    current = def;
+                }
+                None if self.is_closed(current) => {
+                    let owner = self.res.def(current);
+                    self.diagnostics.push(
+                        Diagnostic::error(format!(
+                            "cannot find `{}` in {} `{}`",
+                            segment,
+                            owner.kind.describe(),
+                            owner.name
+                        ))
+                        .with_code(codes::UNRESOLVED_NAME)
+                        .with_primary(span, format!("not found in `{}`", path[..i].join("::"))),
+                    );
+                    return;
+                }
+                None => {
+                    let rest = path[i..].to_vec();
+                    self.res.uses.insert(node, Res::Partial { base: current, rest });
+                    return;
+                }
+            }
+        }
+        self.res.uses.insert(node, Res::Def(current));
+    }
+
+    /// Whether every member of `def` is known, so a missing one is an error
+    fn is_closed(&self, def: DefId) -> bool {
+        let def = self.res.def(def);
+        match def.kind {
+            DefKind::Module => true,
+            DefKind::Trait => !def.is_prelude(),
+            _ => false,
+        }
+    }
+
+    fn unresolved(&mut self, scope: ScopeId, ns: Namespace, what: &str, name: &str, span: Span) {
+        let mut diag = Diagnostic::error(format!("cannot find {} `{}` in this scope", what, name))
+            .with_code(codes::UNRESOLVED_NAME)
+            .with_primary(span, "not found in this scope");
+        if let Some(similar) = self.similar_name(scope, ns, name) {
+            let kind = self.res.def(similar).kind.describe();
+            let candidate = self.res.def(similar).name.clone();
+            diag = diag.with_suggestion(Suggestion::new(
+                format!("a {} with a similar name exists: `{}`", kind, candidate),
+                span,
+                candidate,
+            ));
+        }
+        self.diagnostics.push(diag);
+    }
+
+    fn expected_trait(&mut self, def: DefId, span: Span) {
+        let def = self.res.def(def);
+        self.diagnostics.push(
+            Diagnostic::error(format!("expected trait, found {} `{}`", def.kind.describe(), def.name))
+                .with_code(codes::EXPECTED_TRAIT)
+                .with_primary(span, "not a trait")
+                .with_secondary(def.span, format!("`{}` defined here", def.name)),
+        );
+    }
+
+    /// The visible name closest to `name` by edit distance, if any is close enough
+    fn similar_name(&self, scope: ScopeId, ns: Namespace, name: &str) -> Option<DefId> {
+        let limit = name.chars().count().div_ceil(3);
+        let mut best: Option<(usize, DefId)> = None;
+        let mut current = Some(scope);
+        while let Some(id) = current {
+            let scope = self.res.scope(id);
+            for (candidate, &def) in scope.table(ns) {
+                let distance = edit_distance(name, candidate);
+                if distance <= limit && best.is_none_or(|(d, _)| distance < d) {
+                    best = Some((distance, def));
+                }
+            }
+            current = scope.parent;
+        }
+        best.map(|(_, def)| def)
+    }
+}
+
+/// Node of the definition an item introduces
+fn item_node(kind: &ItemKind) -> Option<NodeId> {
+    match kind {
+        ItemKind::Function(f) => Some(f.id),
+        ItemKind::Struct(s) => Some(s.id),
+        ItemKind::Enum(e) => Some(e.id),
+        ItemKind::Trait(t) => Some(t.id),
+        ItemKind::Module(m) => Some(m.id),
+        ItemKind::Const(c) => Some(c.id),
+        ItemKind::Type(alias) => Some(alias.id),
+        ItemKind::SynthFunction(sf) => Some(sf.func.id),
+        ItemKind::VerifyFunction(vf) => Some(vf.func.id),
+        ItemKind::Agent(a) => Some(a.id),
+        ItemKind::Workflow(w) => Some(w.id),
+        ItemKind::Impl(_) | ItemKind::Import(_) | ItemKind::Comptime(_) | ItemKind::Error => None,
+    }
+}
+
+fn field_defs(fields: &[Field]) -> Vec<FieldDef> {
+    fields
+        .iter()
+        .map(|f| FieldDef { name: f.name.clone(), visibility: f.visibility, span: f.span })
+        .collect()
+}
+
+/// Levenshtein distance between two strings, by characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != cb);
+            let next = (row[j + 1] + 1).min(row[j] + 1).min(prev + cost);
+            prev = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolve;
+
+    fn resolve_source(source: &str) -> (Resolution, Vec<Diagnostic>) {
+        let (program, errors) = my_lang_parser::parse_recovering(source);
+        assert!(errors.is_empty(), "parse errors: {:?}", errors);
+        resolve(&program)
+    }
+
+    fn codes_of(diags: &[Diagnostic]) -> Vec<&str> {
+        diags.iter().filter_map(|d| d.code).map(|c| c.as_str()).collect()
+    }
+
+    /// Definition kind and name for each use of `name` in the resolution
+    fn uses_of<'r>(res: &'r Resolution, name: &str) -> Vec<&'r Definition> {
+        let mut defs: Vec<_> = res
+            .uses
+            .values()
+            .filter_map(|r| match r {
+                Res::Def(id) => Some(res.def(*id)),
+                Res::Partial { .. } => None,
+            })
+            .filter(|d| d.name == name)
+            .collect();
+        defs.sort_by_key(|d| d.id);
+        defs
+    }
+
+    #[test]
+    fn test_binds_locals_params_and_items() {
+        let (res, diags) = resolve_source(
+            "struct Point { x: i32 }\n\
+             fn make(x: i32) -> Point { let p = Point { x: x }; p }\n\
+             fn main() { let q = make(1); }",
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert_eq!(uses_of(&res, "make")[0].kind, DefKind::Function);
+        assert_eq!(uses_of(&res, "x")[0].kind, DefKind::Param);
+        assert_eq!(uses_of(&res, "p")[0].kind, DefKind::Local);
+        assert!(uses_of(&res, "Point").iter().all(|d| d.kind == DefKind::Struct));
+    }
+
+    #[test]
+    fn test_closure_params_and_captures() {
+        let (res, diags) = resolve_source(
+            "fn main(n: i32) { let a = 1; let f = |x| { let b = x; let g = || a + b + n; g() + a }; let y = x; }",
+        );
+        // Closure parameters are not visible after the closure
+        assert_eq!(codes_of(&diags), ["E0101"]);
+        assert_eq!(uses_of(&res, "x")[0].kind, DefKind::Param);
+        // Each closure captures what it uses from outside, including for closures inside it
+        let mut captures: Vec<Vec<&str>> = res
+            .captures
+            .values()
+            .map(|defs| defs.iter().map(|d| res.def(*d).name.as_str()).collect())
+            .collect();
+        captures.sort_by_key(Vec::len);
+        assert_eq!(captures, [vec!["a", "n"], vec!["a", "b", "n"]]);
+    }
+
+    #[test]
+    fn test_shadowing_and_block_scopes() {
+        let (res, diags) = resolve_source(
+            "fn main() { let x = 1; let x = x + 1; { let y = x; } y }",
+        );
+        // The inner `y` is out of scope at the tail expression
+        assert_eq!(codes_of(&diags), ["E0101"]);
+        // `x + 1` refers to the first `x`, the block's `y = x` to the second
+        let xs = uses_of(&res, "x");
+        assert_eq!(xs.len(), 2);
+        assert!(xs[0].span.start < xs[1].span.start);
+    }
+
+    #[test]
+    fn test_unresolved_with_suggestion() {
+        let (_, diags) = resolve_source("fn main() { let count = 1; coutn + 1 }");
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "cannot find value `coutn` in this scope");
+        assert_eq!(diags[0].suggestions[0].replacement, "count");
+    }
+
+    #[test]
+    fn test_unresolved_type_and_function() {
+        let (_, diags) = resolve_source("fn f(a: Missing) { helper(a) }");
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            ["cannot find type `Missing` in this scope", "cannot find function `helper` in this scope"]
+        );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let (_, diags) = resolve_source(
+            "fn a() {}\nfn a() {}\nstruct S { f: i32, f: i32 }\nfn g(x: i32, x: i32) {}\n\
+             fn h() { let (y, y) = (1, 2); }",
+        );
+        assert_eq!(codes_of(&diags), ["E0102", "E0102", "E0102", "E0102"]);
+        assert_eq!(diags[0].message, "the name `a` is defined multiple times");
+        assert_eq!(diags[0].labels.len(), 2);
+    }
+
+    #[test]
+    fn test_types_and_values_do_not_clash() {
+        let (_, diags) = resolve_source("struct Point { x: i32 }\nfn Point() {}");
+        assert!(diags.is_empty(), "{:?}", diags);
+    }
+
+    #[test]
+    fn test_enum_variants_and_match_arms() {
+        let (res, diags) = resolve_source(
+            "enum Shape { Circle(f64), Square { side: f64 }, Empty }\n\
+             fn area(s: Shape) -> f64 {\n\
+                 match s { Shape::Circle(r) => r, Shape::Square { side } => side, Shape::Empty => 0.0 }\n\
+             }\n\
+             fn unwrap(o: Option<i32>) -> i32 { match o { Some(v) => v, None => 0 } }",
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert!(uses_of(&res, "Circle").iter().all(|d| d.kind == DefKind::Variant));
+        assert_eq!(uses_of(&res, "r")[0].kind, DefKind::Local);
+        // `None` matches the prelude variant rather than binding a variable
+        assert_eq!(uses_of(&res, "None")[0].kind, DefKind::Variant);
+    }
+
+    #[test]
+    fn test_impl_items_and_self() {
+        let (res, diags) = resolve_source(
+            "struct Counter { n: i32 }\n\
+             impl Counter {\n\
+                 fn new() -> Self { Self { n: 0 } }\n\
+                 fn get(&self) -> i32 { self.n }\n\
+             }\n\
+             fn main() { let c = Counter::new(); c.get(); Counter::missing(); }",
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert_eq!(uses_of(&res, "new")[0].kind, DefKind::Function);
+        assert_eq!(uses_of(&res, "self")[0].kind, DefKind::Param);
+        // Unknown associated items are left to the type checker
+        assert!(res.uses.values().any(|r| matches!(r, Res::Partial { rest, .. } if rest == &["missing"])));
+    }
+
+    #[test]
+    fn test_generics_and_bounds() {
+        let (res, diags) = resolve_source(
+            "trait Shape { fn area(&self) -> f64 { 0.0 } }\n\
+             fn total<T: Shape>(items: Vec<T>) -> f64 { 0.0 }\n\
+             fn bad<U: Nope>(x: U) {}",
+        );
+        assert_eq!(codes_of(&diags), ["E0101"]);
+        assert_eq!(diags[0].message, "cannot find trait `Nope` in this scope");
+        assert_eq!(res.bounds.len(), 1);
+        assert_eq!(uses_of(&res, "T")[0].kind, DefKind::TypeParam);
+    }
+
+    #[test]
+    fn test_inline_modules_and_imports() {
+        let (res, diags) = resolve_source(
+            "mod geometry { pub fn area() -> i32 { 1 } pub mod inner { pub fn deep() {} } }\n\
+             import geometry::area;\n\
+             import geometry::inner::deep as d;\n\
+             import std::io;\n\
+             fn main() { area(); d(); geometry::area(); io::println(\"hi\"); }",
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert_eq!(uses_of(&res, "area").len(), 3);
+        assert!(res.uses.values().any(|r| matches!(r, Res::Partial { rest, .. } if rest == &["println"])));
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let (_, diags) = resolve_source(
+            "mod m { pub fn f() {} pub fn g() {} }\n\
+             import m::nothing;\n\
+             import m::g as f;\n\
+             import m::f;\n\
+             fn main() { m::h(); }",
+        );
+        assert_eq!(codes_of(&diags), ["E0104", "E0103", "E0101"]);
+        assert_eq!(diags[0].labels[0].message, "no `nothing` in `m`");
+        assert_eq!(diags[1].suggestions[0].replacement, " as other_f");
+        assert_eq!(diags[2].message, "cannot find `h` in module `m`");
+    }
+
+    #[test]
+    fn test_import_shadowing_local_item() {
+        let (_, diags) = resolve_source("mod m { pub fn f() {} }\nimport m::f;\nfn f() {}");
+        assert_eq!(codes_of(&diags), ["E0103"]);
+        assert_eq!(diags[0].labels[0].message, "import of `f` shadows a local function");
+    }
+
+    #[test]
+    fn test_imports_resolve_in_any_order() {
+        let (_, diags) = resolve_source(
+            "import b::item;\nmod a { pub fn item() {} }\nmod b { pub import a::item; }",
+        );
+        // `b::item` only exists once b's own import has been resolved
+        assert!(diags.is_empty(), "{:?}", diags);
+    }
+
+    #[test]
+    fn test_brace_imports() {
+        let (res, diags) = resolve_source(
+            "pub mod net { pub mod http { pub fn get() {} pub fn put() {} } pub fn connect() {} }\n\
+             import net::{self as n, connect, http::{get, put as upload}};\n\
+             fn main() { n::connect(); connect(); get(); upload(); }",
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert_eq!(uses_of(&res, "connect").len(), 3);
+        assert_eq!(uses_of(&res, "put").len(), 2);
+    }
+
+    #[test]
+    fn test_privacy() {
+        let (_, diags) = resolve_source(
+            "mod outer {\n\
+                 fn hidden() {}\n\
+                 pub struct Point { x: i32 }\n\
+                 impl Point { pub fn new() -> Point { Point { x: 0 } } fn secret() {} }\n\
+                 pub mod inner { pub fn call() { super::hidden(); } }\n\
+             }\n\
+             fn main() { outer::inner::call(); outer::Point::new(); outer::Point::secret(); outer::hidden(); }",
+        );
+        // Descendants may use private items; everyone else may not
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["function `secret` is private", "function `hidden` is private"]);
+        assert!(diags.iter().all(|d| d.code == Some(codes::PRIVATE_ITEM)));
+    }
+
+    #[test]
+    fn test_crate_visibility() {
+        let (res, diags) = resolve_source(
+            "mod a { pub(crate) fn shared() {} pub(crate) struct S { pub(crate) v: i32 } }\n\
+             fn main() { a::shared(); let s = a::S { v: 1 }; }",
+        );
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert_eq!(uses_of(&res, "shared")[0].visibility, Visibility::Crate);
+    }
+
+    #[test]
+    fn test_private_fields() {
+        let (res, diags) = resolve_source(
+            "mod geo {\n\
+                 pub struct Point { pub x: i32, y: i32 }\n\
+                 pub struct Meters { value: f64 }\n\
+                 pub fn origin() -> Point { Point { x: 0, y: 0 } }\n\
+             }\n\
+             fn main() {\n\
+                 let p = geo::Point { x: 1, y: 2 };\n\
+                 let m = geo::Meters {};\n\
+                 match geo::origin() { geo::Point { x, y } => x }\n\
+             }",
+        );
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "field `y` of struct `Point` is private",
+                "cannot construct `Meters` with struct literal syntax due to private fields",
+                "field `y` of struct `Point` is private",
+            ]
+        );
+        assert!(diags.iter().all(|d| d.code == Some(codes::PRIVATE_FIELD)));
+
+        // Later phases ask the same question for field access expressions
+        let point = res.defs.iter().find(|d| d.name == "Point").unwrap().id;
+        let geo = res.defs.iter().find(|d| d.name == "geo").unwrap().id;
+        let inside = res.members[&geo];
+        let outside = res.members[&DefId(res.defs.iter().position(|d| d.name == "crate").unwrap() as u32)];
+        assert!(res.field_visible(point, "y", inside));
+        assert!(!res.field_visible(point, "y", outside));
+        assert!(res.field_visible(point, "x", outside));
+    }
+
+    #[test]
+    fn test_reexports() {
+        let (_, diags) = resolve_source(
+            "mod geometry { pub struct Point { pub x: f64 } pub struct Circle { r: f64 } }\n\
+             mod shapes { pub use super::geometry::{Circle, Point}; use super::geometry::Circle as Round; }\n\
+             fn main() { let p: shapes::Point = shapes::Point { x: 1.0 }; let c: shapes::Round = 1; }",
+        );
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["struct import `Round` is private"]);
+    }
+
+    #[test]
+    fn test_multiple_files() {
+        let (main, _) = my_lang_parser::parse_recovering("import util::helper;\nfn main() { helper(); util::other(); }");
+        let mut parser = my_lang_parser::Parser::with_file("pub fn helper() {}\npub fn other() {}", FileId(1))
+            .with_first_node_id(NodeId(1000));
+        let (util, _) = parser.parse_program();
+
+        let mut resolver = Resolver::new();
+        resolver.add_module(&[], &main);
+        resolver.add_module(&["util".to_string()], &util);
+        let (res, diags) = resolver.finish();
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert_eq!(uses_of(&res, "helper")[0].span.file, FileId(1));
+    }
+
+    #[test]
+    fn test_postcondition_sees_result() {
+        let (res, diags) = resolve_source(
+            "fn inc(x: i32) -> i32 requires x > 0 ensures result > x { x + 1 }\n\
+             fn bad(x: i32) -> i32 requires result > 0 { x }",
+        );
+        // `result` is only bound in postconditions
+        assert_eq!(codes_of(&diags), ["E0101"]);
+        assert_eq!(uses_of(&res, "result")[0].kind, DefKind::Local);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("same", "same"), 0);
+    }
+}
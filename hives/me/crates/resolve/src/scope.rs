@@ -0,0 +1,67 @@
+// Scopes
+// A tree of symbol tables, one per module, function, block, match arm, impl, trait and agent
+
+use crate::{DefId, Namespace};
+use std::collections::HashMap;
+
+/// Index of a scope in `Resolution::scopes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ScopeId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeKind {
+    /// Names visible everywhere without an import
+    Prelude,
+    Module,
+    /// Parameters and generics of a function
+    Function,
+    Block,
+    MatchArm,
+    /// Bindings of a `for` pattern
+    Loop,
+    /// Generic parameters of a struct, enum or type alias
+    Generics,
+    Impl,
+    Trait,
+    Agent,
+    /// Postconditions, where `result` is bound
+    Contract,
+    /// Parameters of a closure
+    Closure,
+    /// Associated items of a struct, enum or trait, reached through a path
+    Members,
+}
+
+/// A symbol table with separate type and value namespaces
+#[derive(Debug, Clone)]
+pub struct Scope {
+    pub kind: ScopeKind,
+    /// Enclosing scope for lexical lookup; `None` only for the prelude and member scopes
+    pub parent: Option<ScopeId>,
+    pub types: HashMap<String, DefId>,
+    pub values: HashMap<String, DefId>,
+}
+
+impl Scope {
+    pub fn new(kind: ScopeKind, parent: Option<ScopeId>) -> Self {
+        Self { kind, parent, types: HashMap::new(), values: HashMap::new() }
+    }
+
+    pub fn get(&self, ns: Namespace, name: &str) -> Option<DefId> {
+        self.table(ns).get(name).copied()
+    }
+
+    pub fn table(&self, ns: Namespace) -> &HashMap<String, DefId> {
+        match ns {
+            Namespace::Type => &self.types,
+            Namespace::Value => &self.values,
+        }
+    }
+
+    pub(crate) fn table_mut(&mut self, ns: Namespace) -> &mut HashMap<String, DefId> {
+        match ns {
+            Namespace::Type => &mut self.types,
+            Namespace::Value => &mut self.values,
+        }
+    }
+}
@@ -0,0 +1,1189 @@
+// Prelude and library
+// The prelude's functions, the library paths programs call such as
+// `String::new` and `env::args`, and methods of strings, numbers,
+// collections, `Option` and `Result`
+
+use crate::interp::{at, int, panic, unsupported, Eval, Unwind};
+use crate::value::{map_insert, out_of_bounds, set_insert, slot, Callable, Place, Step, Value};
+use crate::{RuntimeError, RuntimeErrorKind};
+use my_lang_ast::{PrimitiveType, Span};
+use my_lang_codegen::bytecode::{int_range, Type};
+use my_lang_resolve::{DefId, DefKind, Resolution};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// What the library needs from the machine running the program, which is
+/// the interpreter or the bytecode VM
+pub(crate) trait Host {
+    fn variants(&self) -> Variants;
+    /// What runs the program, as messages about what it cannot do name it
+    fn engine(&self) -> &'static str;
+    /// Command line, program first, as `env::args` returns it
+    fn args(&self) -> &[String];
+    fn out(&mut self) -> &mut dyn Write;
+    fn err(&mut self) -> &mut dyn Write;
+    /// Whether `def` is one of the library's types, such as `HashMap`
+    fn is_library(&self, def: DefId) -> bool;
+    /// Call a function value, as adapters such as `map` do
+    fn call(&mut self, callable: &Callable, args: Vec<Value>, site: &Site) -> Eval<Value>;
+    /// The crate's `to_string` for the type of `value`, if it declares one
+    fn to_string_fn(&self, value: &Value, span: Span) -> Eval<Option<DefId>>;
+}
+
+/// Where a library call happens, and the static type of its result
+pub(crate) struct Site<'t> {
+    pub span: Span,
+    pub ty: &'t Type,
+}
+
+/// Variants of the prelude's `Option` and `Result`, which library methods return
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Variants {
+    pub some: DefId,
+    pub none: DefId,
+    pub ok: DefId,
+    pub err: DefId,
+}
+
+impl Variants {
+    pub fn new(res: &Resolution) -> Self {
+        let find = |name: &str| {
+            let def = res.defs.iter().find(|d| d.is_prelude() && d.kind == DefKind::Variant && d.name == name);
+            def.expect("the prelude defines `Option` and `Result`").id
+        };
+        Self { some: find("Some"), none: find("None"), ok: find("Ok"), err: find("Err") }
+    }
+
+    fn wrap(def: DefId, name: &str, value: Option<Value>) -> Value {
+        let fields = value.into_iter().map(|v| ("0".to_string(), v)).collect();
+        Value::Adt { def, name: name.to_string(), fields }
+    }
+
+    pub fn option(&self, value: Option<Value>) -> Value {
+        match value {
+            Some(value) => Self::wrap(self.some, "Some", Some(value)),
+            None => Self::wrap(self.none, "None", None),
+        }
+    }
+
+    pub fn ok(&self, value: Value) -> Value {
+        Self::wrap(self.ok, "Ok", Some(value))
+    }
+
+    pub fn err(&self, value: Value) -> Value {
+        Self::wrap(self.err, "Err", Some(value))
+    }
+}
+
+/// Library types by name, for dispatching on strings and collections
+pub(crate) struct LibraryTypes<D>(HashMap<String, D>);
+
+impl<D: Copy> LibraryTypes<D> {
+    pub fn new(types: impl IntoIterator<Item = (String, D)>) -> Self {
+        Self(types.into_iter().collect())
+    }
+
+    /// The library types whose methods apply to `value`, most specific first
+    pub fn of(&self, value: &Value) -> Vec<D> {
+        let names: &[&str] = match value {
+            Value::Str(_) => &["String"],
+            Value::List(_) => &["Vec", "VecDeque"],
+            Value::Map(_) => &["HashMap", "BTreeMap"],
+            Value::Set(_) => &["HashSet", "BTreeSet"],
+            _ => &[],
+        };
+        names.iter().filter_map(|name| self.0.get(*name).copied()).collect()
+    }
+}
+
+/// Methods of `Rc`, `RefCell`, `Cell` and `Mutex` themselves, rather than of their contents
+pub(crate) fn is_cell_method(method: &str) -> bool {
+    matches!(
+        method,
+        "clone"
+            | "borrow"
+            | "borrow_mut"
+            | "get_mut"
+            | "get"
+            | "set"
+            | "replace"
+            | "lock"
+            | "read"
+            | "write"
+            | "into_inner"
+    )
+}
+
+fn int_arg(args: &[Value], i: usize) -> Option<i128> {
+    match args.get(i) {
+        Some(Value::Int(n)) => Some(*n),
+        _ => None,
+    }
+}
+
+fn index_arg(args: &[Value], i: usize) -> Option<usize> {
+    int_arg(args, i).map(|n| usize::try_from(n).unwrap_or(usize::MAX))
+}
+
+fn text_arg(args: &[Value], i: usize) -> Option<String> {
+    match args.get(i) {
+        Some(Value::Str(s)) => Some(s.clone()),
+        Some(Value::Char(c)) => Some(c.to_string()),
+        _ => None,
+    }
+}
+
+impl dyn Host + '_ {
+    /// Text of `value` as `{}` shows it, using the crate's `to_string` for its own types
+    pub(crate) fn display(&mut self, value: &Value, span: Span) -> Eval<String> {
+        let loaded = at(value.load(), span)?;
+        if let Value::Adt { .. } = loaded {
+            if let Some(def) = self.to_string_fn(&loaded, span)? {
+                let site = Site { span, ty: &Type::Prim(PrimitiveType::Str) };
+                let text = self.call(&Callable::Def(def), vec![Value::Ref(Place::temp(loaded))], &site)?;
+                return Ok(text.to_text());
+            }
+        }
+        Ok(loaded.to_text())
+    }
+
+    // ========== Prelude Functions ==========
+
+    pub(crate) fn builtin(&mut self, name: &str, args: Vec<Value>, site: &Site) -> Eval<Value> {
+        let span = site.span;
+        match name {
+            "print" | "println" | "eprint" | "eprintln" => {
+                let mut text = self.message(&args, "", span)?;
+                if name.ends_with("ln") {
+                    text.push('\n');
+                }
+                let written = if name.starts_with('e') {
+                    // Keep what the program printed before in order with the error output
+                    let _ = self.out().flush();
+                    self.err().write_all(text.as_bytes())
+                } else {
+                    self.out().write_all(text.as_bytes())
+                };
+                match written {
+                    Ok(()) => Ok(Value::unit()),
+                    Err(err) => panic(
+                        format!(
+                            "failed printing to {}: {}",
+                            if name.starts_with('e') { "stderr" } else { "stdout" },
+                            err
+                        ),
+                        span,
+                    ),
+                }
+            }
+            "format" => Ok(Value::Str(self.message(&args, "", span)?)),
+            "panic" => {
+                let message = self.message(&args, "explicit panic", span)?;
+                panic(message, span)
+            }
+            "unreachable" => {
+                let message = self.message(&args, "internal error: entered unreachable code", span)?;
+                panic(message, span)
+            }
+            "todo" => {
+                let message = self.message(&args, "not yet implemented", span)?;
+                panic(message, span)
+            }
+            "assert" => match args.split_first() {
+                Some((cond, rest)) if at(cond.load(), span)? != Value::Bool(true) => {
+                    let message = self.message(rest, "assertion failed", span)?;
+                    panic(message, span)
+                }
+                Some(_) => Ok(Value::unit()),
+                None => panic("assertion failed", span),
+            },
+            "assert_eq" | "assert_ne" => {
+                let [left, right, rest @ ..] = args.as_slice() else {
+                    return panic("assertion failed", span);
+                };
+                let equal = name == "assert_eq";
+                if (left == right) == equal {
+                    return Ok(Value::unit());
+                }
+                let op = if equal { "==" } else { "!=" };
+                let message = self.message(rest, &format!("assertion `left {} right` failed", op), span)?;
+                panic(format!("{}\n  left: {}\n right: {}", message, left, right), span)
+            }
+            "dbg" => {
+                let value = args.into_iter().next().unwrap_or_else(Value::unit);
+                let _ = self.out().flush();
+                let _ = writeln!(self.err(), "[{}:{}] {}", span.line, span.column, value);
+                Ok(value)
+            }
+            "drop" => Ok(Value::unit()),
+            // Postconditions are lowered with their snapshots already taken
+            "old" => Ok(args.into_iter().next().unwrap_or_else(Value::unit)),
+            // Lowered contracts call this with the clause and the values it mentions
+            "contract_violation" => {
+                let texts: Vec<Value> = args.iter().map(|a| a.unref()).collect::<Result<_, _>>().unwrap_or_default();
+                let message = match texts.as_slice() {
+                    [Value::Str(kind), Value::Str(function), Value::Str(clause), ..] => {
+                        format!("{} `{}` of `{}` violated", kind, clause, function)
+                    }
+                    _ => "contract violated".to_string(),
+                };
+                let mut err = RuntimeError::new(RuntimeErrorKind::Panic(message), span);
+                for pair in texts.get(3..).unwrap_or_default().chunks(2) {
+                    if let [Value::Str(name), value] = pair {
+                        err.notes.push(format!("{} = {}", name, value));
+                    }
+                }
+                Err(err.into())
+            }
+            _ => unsupported(format!("`{}` is not available in {}", name, self.engine()), span),
+        }
+    }
+
+    /// A template and its arguments, or a single value to show as text
+    fn message(&mut self, args: &[Value], default: &str, span: Span) -> Eval<String> {
+        match args.split_first() {
+            None => Ok(default.to_string()),
+            Some((first, [])) => self.display(first, span),
+            Some((first, rest)) => match at(first.load(), span)? {
+                Value::Str(template) => self.format(&template, rest, span),
+                other => unsupported(format!("expected a format string, found `{}`", other), span),
+            },
+        }
+    }
+
+    /// Substitute `{}`, `{:?}` and `{:.N}` in `template` with `args`, as `format` does
+    pub(crate) fn format(&mut self, template: &str, args: &[Value], span: Span) -> Eval<String> {
+        let mut out = String::new();
+        let mut args = args.iter();
+        let mut rest = template;
+        while let Some(open) = rest.find(['{', '}']) {
+            out.push_str(&rest[..open]);
+            rest = &rest[open..];
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                out.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue;
+            }
+            let (true, Some(close)) = (rest.starts_with('{'), rest.find('}')) else {
+                out.push_str(&rest[..1]);
+                rest = &rest[1..];
+                continue;
+            };
+            let spec = rest[1..close].split_once(':').map_or("", |(_, spec)| spec);
+            match args.next() {
+                Some(arg) => {
+                    let (spec, debug) = match spec.strip_suffix('?') {
+                        Some(spec) => (spec, true),
+                        None => (spec, false),
+                    };
+                    let (width, precision) = match spec.split_once('.') {
+                        Some((width, precision)) => (width, precision.parse::<usize>().ok()),
+                        None => (spec, None),
+                    };
+                    let text = match (at(arg.load(), span)?, precision) {
+                        (Value::Float(x), Some(precision)) => format!("{:.*}", precision, x),
+                        (value, _) if debug => value.to_string(),
+                        _ => self.display(arg, span)?,
+                    };
+                    out.push_str(&pad(&text, width));
+                }
+                None => out.push_str(&rest[..=close]),
+            }
+            rest = &rest[close + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    // ========== Library Paths ==========
+
+    /// A library function named by path, such as `io::println` or `Vec::new`
+    pub(crate) fn library(&mut self, path: &str, mut args: Vec<Value>, site: &Site) -> Eval<Value> {
+        let span = site.span;
+        let segments: Vec<&str> = path.split("::").collect();
+        let (owner, name) = match segments.as_slice() {
+            [.., owner, name] => (*owner, *name),
+            [name] => ("", *name),
+            [] => ("", ""),
+        };
+        let first = args.first().map(|a| a.unref()).transpose();
+        let first = at(first, span)?;
+        let value = match (owner, name) {
+            ("env", "args") => Value::List(self.args().iter().cloned().map(Value::Str).collect()),
+            ("process", "exit") => {
+                let code = match first {
+                    Some(Value::Int(code)) => code as i32,
+                    _ => 0,
+                };
+                let _ = self.out().flush();
+                return Err(Unwind::Exit(code));
+            }
+            ("String", "new") => Value::Str(String::new()),
+            ("String", "from") => Value::Str(first.map(|v| v.to_text()).unwrap_or_default()),
+            ("Vec" | "VecDeque", "new" | "with_capacity") => Value::List(Vec::new()),
+            ("Vec" | "VecDeque", "from") => match first {
+                Some(Value::List(elems)) => Value::List(elems),
+                _ => Value::List(Vec::new()),
+            },
+            ("HashMap" | "BTreeMap", "from") => {
+                let mut entries = Vec::new();
+                if let Some(Value::List(pairs)) = first {
+                    for pair in pairs {
+                        if let Value::Tuple(mut pair) = pair {
+                            if pair.len() == 2 {
+                                let value = pair.pop().unwrap_or_else(Value::unit);
+                                map_insert(&mut entries, pair.pop().unwrap_or_else(Value::unit), value);
+                            }
+                        }
+                    }
+                }
+                Value::Map(entries)
+            }
+            ("HashSet" | "BTreeSet", "from") => {
+                let mut set = Vec::new();
+                if let Some(Value::List(elems)) = first {
+                    for elem in elems {
+                        set_insert(&mut set, elem);
+                    }
+                }
+                Value::Set(set)
+            }
+            ("HashMap" | "BTreeMap", "new" | "with_capacity") => Value::Map(Vec::new()),
+            ("HashSet" | "BTreeSet", "new" | "with_capacity") => Value::Set(Vec::new()),
+            // A box is its contents: nothing can tell them apart
+            ("Box", "new") => first.unwrap_or_else(Value::unit),
+            ("Rc" | "Arc" | "RefCell" | "Cell" | "Mutex" | "RwLock", "new") => {
+                Value::Shared(slot(first.unwrap_or_else(Value::unit)))
+            }
+            ("Rc" | "Arc", "clone") => first.unwrap_or_else(Value::unit),
+            ("Rc" | "Arc", "ptr_eq") => match (first, args.get(1).map(|a| a.unref()).transpose()) {
+                (Some(Value::Shared(a)), Ok(Some(Value::Shared(b)))) => Value::Bool(std::rc::Rc::ptr_eq(&a, &b)),
+                _ => Value::Bool(false),
+            },
+            // Errors are the text of the host's, as compiled programs give them
+            ("fs", "read_to_string") => {
+                let path = first.map(|v| v.to_text()).unwrap_or_default();
+                match std::fs::read_to_string(path) {
+                    Ok(text) => self.variants().ok(Value::Str(text)),
+                    Err(err) => self.variants().err(Value::Str(err.to_string())),
+                }
+            }
+            ("fs", "write") => {
+                let path = first.map(|v| v.to_text()).unwrap_or_default();
+                let contents = at(args.get(1).map(|a| a.unref()).transpose(), span)?;
+                match std::fs::write(path, contents.map(|v| v.to_text()).unwrap_or_default()) {
+                    Ok(()) => self.variants().ok(Value::unit()),
+                    Err(err) => self.variants().err(Value::Str(err.to_string())),
+                }
+            }
+            ("mem", "swap") => match args.as_slice() {
+                [Value::Ref(a), Value::Ref(b)] => {
+                    let (x, y) = (at(a.get(), span)?, at(b.get(), span)?);
+                    at(a.set(y), span)?;
+                    at(b.set(x), span)?;
+                    Value::unit()
+                }
+                _ => return unsupported("`mem::swap` takes two mutable references", span),
+            },
+            ("mem", "replace") => match (args.pop(), args.as_slice()) {
+                (Some(value), [Value::Ref(dest)]) => {
+                    let old = at(dest.get(), span)?;
+                    at(dest.set(value), span)?;
+                    old
+                }
+                _ => return unsupported("`mem::replace` takes a mutable reference and a value", span),
+            },
+            (_, name) if my_lang_resolve::prelude::FUNCTIONS.contains(&name) => return self.builtin(name, args, site),
+            _ => return unsupported(format!("`{}` is not available in {}", path, self.engine()), span),
+        };
+        Ok(value)
+    }
+
+    /// Call a function value with `args`, as adapters such as `map` do
+    fn apply(&mut self, f: &Value, args: Vec<Value>, site: &Site) -> Eval<Value> {
+        match at(f.load(), site.span)? {
+            Value::Fn(callable) => self.call(&callable, args, site),
+            other => unsupported(format!("`{}` is not a function", other), site.span),
+        }
+    }
+
+    fn predicate(&mut self, f: &Value, arg: Value, site: &Site) -> Eval<bool> {
+        match at(self.apply(f, vec![arg], site)?.load(), site.span)? {
+            Value::Bool(b) => Ok(b),
+            other => unsupported(format!("expected a `bool`, found `{}`", other), site.span),
+        }
+    }
+
+    // ========== Methods ==========
+
+    /// A method of a library type on the receiver at `place`, which references do not lead away from
+    pub(crate) fn builtin_method(&mut self, site: &Site, place: Place, method: &str, args: Vec<Value>) -> Eval<Value> {
+        let span = site.span;
+        let mut args: Vec<Value> = at(args.iter().map(Value::unref).collect(), span)?;
+
+        let cell = at(place.with(|v| if let Value::Shared(cell) = v { Some(cell.clone()) } else { None }), span)?;
+        if let Some(cell) = cell {
+            let inner = Place::new(cell.clone());
+            return match (method, args.len()) {
+                ("clone", 0) => Ok(Value::Shared(cell)),
+                ("borrow" | "borrow_mut" | "get_mut", 0) => Ok(Value::Ref(inner)),
+                ("lock" | "read" | "write", 0) => Ok(self.variants().ok(Value::Ref(inner))),
+                ("get" | "into_inner", 0) => at(inner.get(), span),
+                ("set", 1) => {
+                    at(inner.set(args.remove(0)), span)?;
+                    Ok(Value::unit())
+                }
+                ("replace", 1) => at(inner.with_mut(|v| std::mem::replace(v, args.remove(0))), span),
+                _ => {
+                    let inner = at(inner.follow(), span)?;
+                    self.builtin_method(site, inner, method, args)
+                }
+            };
+        }
+
+        let variants = self.variants();
+        if let Some(result) = at(place.with_mut(|value| mutate(value, method, &mut args, &variants)), span)? {
+            return at(result, span);
+        }
+        if let Some(value) = self.borrowing_method(site, &place, method, &args)? {
+            return Ok(value);
+        }
+        let receiver = at(place.get(), span)?;
+        self.value_method(site, receiver, method, args)
+    }
+
+    /// Methods that look into the receiver without copying it, or borrow part of it
+    fn borrowing_method(&mut self, site: &Site, place: &Place, method: &str, args: &[Value]) -> Eval<Option<Value>> {
+        let span = site.span;
+        let variants = self.variants();
+        let some_ref = |step: Step| variants.option(Some(Value::Ref(place.child(step))));
+        let value = at(
+            place.with(|receiver| match (receiver, method, args) {
+                (Value::List(e) | Value::Set(e), "len" | "count", []) => Some(Value::Int(e.len() as i128)),
+                (Value::Map(e), "len", []) => Some(Value::Int(e.len() as i128)),
+                (Value::Str(s), "len", []) => Some(Value::Int(s.len() as i128)),
+                (Value::List(e) | Value::Set(e), "is_empty", []) => Some(Value::Bool(e.is_empty())),
+                (Value::Map(e), "is_empty", []) => Some(Value::Bool(e.is_empty())),
+                (Value::Str(s), "is_empty", []) => Some(Value::Bool(s.is_empty())),
+                (Value::List(e) | Value::Set(e), "contains", [x]) => Some(Value::Bool(e.contains(x))),
+                (Value::Map(e), "contains_key", [k]) => Some(Value::Bool(e.iter().any(|(key, _)| key == k))),
+                (Value::List(e), "get" | "get_mut", [Value::Int(i)]) => Some(match usize::try_from(*i) {
+                    Ok(i) if i < e.len() => some_ref(Step::Index(i)),
+                    _ => variants.option(None),
+                }),
+                (Value::List(e), "first" | "first_mut", []) if !e.is_empty() => Some(some_ref(Step::Index(0))),
+                (Value::List(e), "last" | "last_mut", []) if !e.is_empty() => Some(some_ref(Step::Index(e.len() - 1))),
+                (Value::List(_), "first" | "first_mut" | "last" | "last_mut", []) => Some(variants.option(None)),
+                (Value::List(e), "iter" | "iter_mut", []) => {
+                    Some(Value::List((0..e.len()).map(|i| Value::Ref(place.child(Step::Index(i)))).collect()))
+                }
+                (Value::Map(e), "get" | "get_mut", [k]) => Some(match e.iter().any(|(key, _)| key == k) {
+                    true => some_ref(Step::Key(k.clone())),
+                    false => variants.option(None),
+                }),
+                (Value::Map(e), "keys", []) => Some(Value::List(e.iter().map(|(k, _)| k.clone()).collect())),
+                (Value::Map(e), "values" | "values_mut", []) => {
+                    Some(Value::List(e.iter().map(|(k, _)| Value::Ref(place.child(Step::Key(k.clone())))).collect()))
+                }
+                (Value::Map(e), "iter" | "iter_mut", []) => Some(Value::List(
+                    e.iter()
+                        .map(|(k, _)| Value::Tuple(vec![k.clone(), Value::Ref(place.child(Step::Key(k.clone())))]))
+                        .collect(),
+                )),
+                (Value::Set(e), "iter", []) => Some(Value::List(e.clone())),
+                (Value::Adt { def, .. }, "as_ref" | "as_mut", []) if *def == variants.some => {
+                    Some(some_ref(Step::Field("0".into())))
+                }
+                (Value::Adt { def, .. }, "as_ref" | "as_mut", []) if *def == variants.none => {
+                    Some(variants.option(None))
+                }
+                _ => None,
+            }),
+            span,
+        )?;
+        if value.is_some() {
+            return Ok(value);
+        }
+
+        // Reorder or filter in place, calling back into the program for keys and predicates
+        match (method, args) {
+            ("retain" | "sort_by_key", [f]) => {
+                let Value::List(elems) = at(place.get(), span)? else {
+                    return Ok(None);
+                };
+                let mut kept = Vec::new();
+                let mut keyed = Vec::new();
+                for elem in elems {
+                    if method == "retain" {
+                        if self.predicate(f, Value::Ref(Place::temp(elem.clone())), site)? {
+                            kept.push(elem);
+                        }
+                    } else {
+                        let key = self.apply(f, vec![Value::Ref(Place::temp(elem.clone()))], site)?;
+                        keyed.push((at(key.load(), span)?, elem));
+                    }
+                }
+                if method == "sort_by_key" {
+                    keyed.sort_by(|a, b| a.0.compare(&b.0).unwrap_or(Ordering::Equal));
+                    kept = keyed.into_iter().map(|(_, elem)| elem).collect();
+                }
+                at(place.set(Value::List(kept)), span)?;
+                Ok(Some(Value::unit()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Methods that only read the receiver, given a copy of it
+    fn value_method(&mut self, site: &Site, receiver: Value, method: &str, args: Vec<Value>) -> Eval<Value> {
+        let span = site.span;
+        let variants = self.variants();
+        if let Value::Adt { def, fields, .. } = &receiver {
+            if [variants.some, variants.none, variants.ok, variants.err].contains(def) {
+                let payload = fields.first().map(|(_, v)| v.clone());
+                if let Some(value) = self.variant_method(site, *def, payload, method, &args)? {
+                    return Ok(value);
+                }
+            }
+        }
+        let value = match (receiver, method, args.as_slice()) {
+            (Value::List(elems), _, _) => return self.list_method(site, elems, method, args),
+            (Value::Str(s), _, _) if self.is_str_method(method) => return self.str_method(site, s, method, args),
+            (Value::Char(c), _, _) => return char_method(c, method, &args, &variants, span),
+            (Value::Int(n), _, _) if method != "to_string" && method != "clone" => {
+                return self.int_method(site, n, method, &args)
+            }
+            (Value::Float(x), _, _) if method != "to_string" && method != "clone" => {
+                return float_method(x, method, &args, span)
+            }
+            (Value::Map(entries), "into_iter", []) => {
+                Value::List(entries.into_iter().map(|(k, v)| Value::Tuple(vec![k, v])).collect())
+            }
+            (Value::Set(elems), "into_iter", []) => Value::List(elems),
+            (Value::Set(elems), "union" | "intersection" | "difference", [other]) => {
+                let Value::Set(other) = at(other.load(), span)? else {
+                    return unsupported(format!("`{}` takes a set", method), span);
+                };
+                let mut result = match method {
+                    "union" => elems,
+                    "intersection" => elems.into_iter().filter(|e| other.contains(e)).collect(),
+                    _ => elems.into_iter().filter(|e| !other.contains(e)).collect(),
+                };
+                if method == "union" {
+                    for elem in other {
+                        set_insert(&mut result, elem);
+                    }
+                }
+                Value::List(result)
+            }
+            (receiver, "to_string", []) => Value::Str(self.display(&receiver, span)?),
+            (receiver, "clone" | "to_owned" | "into" | "as_str" | "as_ref" | "as_mut" | "borrow" | "deref", []) => {
+                receiver
+            }
+            (receiver, "eq", [other]) => Value::Bool(receiver == *other),
+            (receiver, "ne", [other]) => Value::Bool(receiver != *other),
+            (receiver, _, _) => {
+                return unsupported(format!("no method `{}` on `{}` in the interpreter", method, receiver), span);
+            }
+        };
+        Ok(value)
+    }
+
+    /// Methods of `Option` and `Result`, given the variant and its payload
+    fn variant_method(
+        &mut self,
+        site: &Site,
+        def: DefId,
+        payload: Option<Value>,
+        method: &str,
+        args: &[Value],
+    ) -> Eval<Option<Value>> {
+        let span = site.span;
+        let v = self.variants();
+        let present = def == v.some || def == v.ok;
+        let is_option = def == v.some || def == v.none;
+        let receiver = |payload: Option<Value>| match def {
+            d if d == v.some || d == v.none => v.option(payload),
+            d if d == v.ok => v.ok(payload.unwrap_or_else(Value::unit)),
+            _ => v.err(payload.unwrap_or_else(Value::unit)),
+        };
+        let inner = payload.clone().unwrap_or_else(Value::unit);
+        let value = match (method, args) {
+            ("is_some", []) => Value::Bool(def == v.some),
+            ("is_none", []) => Value::Bool(def == v.none),
+            ("is_ok", []) => Value::Bool(def == v.ok),
+            ("is_err", []) => Value::Bool(def == v.err),
+            ("unwrap", []) if present => inner,
+            ("unwrap", []) if is_option => return panic("called `Option::unwrap()` on a `None` value", span),
+            ("unwrap", []) => return panic(format!("called `Result::unwrap()` on an `Err` value: {}", inner), span),
+            ("expect", [message]) if !present => {
+                let message = message.to_text();
+                return match is_option {
+                    true => panic(message, span),
+                    false => panic(format!("{}: {}", message, inner), span),
+                };
+            }
+            ("expect", [_]) => inner,
+            ("unwrap_err", []) if def == v.err => inner,
+            ("unwrap_err", []) => {
+                return panic(format!("called `Result::unwrap_err()` on an `Ok` value: {}", inner), span)
+            }
+            ("unwrap_or", [default]) => {
+                if present {
+                    inner
+                } else {
+                    default.clone()
+                }
+            }
+            ("unwrap_or_else", [f]) if !present => {
+                let args = if is_option { Vec::new() } else { vec![inner] };
+                self.apply(f, args, site)?
+            }
+            ("unwrap_or_else", [_]) => inner,
+            ("map", [f]) if present => receiver(Some(self.apply(f, vec![inner], site)?)),
+            ("map_err", [f]) if def == v.err => v.err(self.apply(f, vec![inner], site)?),
+            ("map" | "map_err", [_]) => receiver(payload),
+            ("and_then", [f]) if present => self.apply(f, vec![inner], site)?,
+            ("and_then", [_]) => receiver(payload),
+            ("or", [other]) => {
+                if present {
+                    receiver(payload)
+                } else {
+                    other.clone()
+                }
+            }
+            ("or_else", [f]) if !present => {
+                let args = if is_option { Vec::new() } else { vec![inner] };
+                self.apply(f, args, site)?
+            }
+            ("or_else", [_]) => receiver(payload),
+            ("filter", [f]) if present => {
+                let keep = self.predicate(f, Value::Ref(Place::temp(inner.clone())), site)?;
+                v.option(keep.then_some(inner))
+            }
+            ("filter", [_]) => v.option(None),
+            ("ok", []) => v.option(payload.filter(|_| def == v.ok)),
+            ("err", []) => v.option(payload.filter(|_| def == v.err)),
+            ("ok_or", [err]) => {
+                if present {
+                    v.ok(inner)
+                } else {
+                    v.err(err.clone())
+                }
+            }
+            ("cloned" | "copied", []) => receiver(payload.map(|p| p.unref()).transpose().unwrap_or(None)),
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    /// Iterator adapters and consumers, and slice methods. Iterators are
+    /// lists, so each adapter runs to completion before the next.
+    fn list_method(&mut self, site: &Site, elems: Vec<Value>, method: &str, args: Vec<Value>) -> Eval<Value> {
+        let span = site.span;
+        let v = self.variants();
+        let value = match (method, args.as_slice()) {
+            ("into_iter" | "to_vec" | "drain" | "clone" | "as_slice" | "into", _) => Value::List(elems),
+            ("cloned" | "copied", []) => Value::List(at(elems.iter().map(Value::unref).collect(), span)?),
+            ("map", [f]) => {
+                let mut out = Vec::with_capacity(elems.len());
+                for elem in elems {
+                    out.push(self.apply(f, vec![elem], site)?);
+                }
+                Value::List(out)
+            }
+            ("filter", [f]) => {
+                let mut out = Vec::new();
+                for elem in elems {
+                    if self.predicate(f, Value::Ref(Place::temp(elem.clone())), site)? {
+                        out.push(elem);
+                    }
+                }
+                Value::List(out)
+            }
+            ("filter_map", [f]) => {
+                let mut out = Vec::new();
+                for elem in elems {
+                    if let Value::Adt { def, fields, .. } = at(self.apply(f, vec![elem], site)?.unref(), span)? {
+                        if def == v.some {
+                            out.extend(fields.into_iter().map(|(_, v)| v));
+                        }
+                    }
+                }
+                Value::List(out)
+            }
+            ("for_each", [f]) => {
+                for elem in elems {
+                    self.apply(f, vec![elem], site)?;
+                }
+                Value::unit()
+            }
+            ("fold", [init, f]) => {
+                let mut acc = init.clone();
+                for elem in elems {
+                    acc = self.apply(f, vec![acc, elem], site)?;
+                }
+                acc
+            }
+            ("any", [f]) => {
+                for elem in elems {
+                    if self.predicate(f, elem, site)? {
+                        return Ok(Value::Bool(true));
+                    }
+                }
+                Value::Bool(false)
+            }
+            ("all", [f]) => {
+                for elem in elems {
+                    if !self.predicate(f, elem, site)? {
+                        return Ok(Value::Bool(false));
+                    }
+                }
+                Value::Bool(true)
+            }
+            ("find", [f]) => {
+                for elem in elems {
+                    if self.predicate(f, Value::Ref(Place::temp(elem.clone())), site)? {
+                        return Ok(v.option(Some(elem)));
+                    }
+                }
+                v.option(None)
+            }
+            ("position", [f]) => {
+                for (i, elem) in elems.into_iter().enumerate() {
+                    if self.predicate(f, elem, site)? {
+                        return Ok(v.option(Some(Value::Int(i as i128))));
+                    }
+                }
+                v.option(None)
+            }
+            ("min_by_key" | "max_by_key", [f]) => {
+                let mut best: Option<(Value, Value)> = None;
+                for elem in elems {
+                    let key = at(self.apply(f, vec![Value::Ref(Place::temp(elem.clone()))], site)?.load(), span)?;
+                    let better = match &best {
+                        None => true,
+                        Some((best, _)) if method == "min_by_key" => key.compare(best) == Some(Ordering::Less),
+                        Some((best, _)) => key.compare(best) != Some(Ordering::Less),
+                    };
+                    if better {
+                        best = Some((key, elem));
+                    }
+                }
+                v.option(best.map(|(_, elem)| elem))
+            }
+            ("enumerate", []) => Value::List(
+                elems.into_iter().enumerate().map(|(i, e)| Value::Tuple(vec![Value::Int(i as i128), e])).collect(),
+            ),
+            ("zip", [Value::List(other)]) => Value::List(
+                elems.into_iter().zip(other.iter().cloned()).map(|(a, b)| Value::Tuple(vec![a, b])).collect(),
+            ),
+            ("chain", [Value::List(other)]) => Value::List(elems.into_iter().chain(other.iter().cloned()).collect()),
+            ("rev", []) => Value::List(elems.into_iter().rev().collect()),
+            ("skip", [_]) => Value::List(elems.into_iter().skip(index_arg(&args, 0).unwrap_or(0)).collect()),
+            ("take", [_]) => Value::List(elems.into_iter().take(index_arg(&args, 0).unwrap_or(0)).collect()),
+            ("step_by", [_]) => {
+                Value::List(elems.into_iter().step_by(index_arg(&args, 0).unwrap_or(1).max(1)).collect())
+            }
+            ("windows" | "chunks", [_]) => {
+                let size = index_arg(&args, 0).unwrap_or(1).max(1);
+                let groups: Vec<Value> = match method {
+                    "windows" => elems.windows(size).map(|w| Value::List(w.to_vec())).collect(),
+                    _ => elems.chunks(size).map(|c| Value::List(c.to_vec())).collect(),
+                };
+                Value::List(groups)
+            }
+            ("count", []) => Value::Int(elems.len() as i128),
+            ("last", []) => v.option(elems.into_iter().last()),
+            ("nth", [_]) => v.option(elems.into_iter().nth(index_arg(&args, 0).unwrap_or(usize::MAX))),
+            ("min" | "max", []) => {
+                let mut best: Option<Value> = None;
+                for elem in elems {
+                    let better = match &best {
+                        None => true,
+                        Some(best) if method == "min" => elem.compare(best) == Some(Ordering::Less),
+                        Some(best) => elem.compare(best) != Some(Ordering::Less),
+                    };
+                    if better {
+                        best = Some(elem);
+                    }
+                }
+                v.option(best)
+            }
+            ("sum" | "product", []) => {
+                let product = method == "product";
+                let loaded: Vec<Value> = at(elems.iter().map(Value::load).collect(), span)?;
+                let float = loaded.iter().any(|e| matches!(e, Value::Float(_)))
+                    || matches!(site.ty, Type::Prim(PrimitiveType::F32 | PrimitiveType::F64));
+                if float {
+                    let xs = loaded.iter().map(|e| match e {
+                        Value::Float(x) => *x,
+                        Value::Int(n) => *n as f64,
+                        _ => 0.0,
+                    });
+                    Value::Float(if product { xs.product() } else { xs.sum() })
+                } else {
+                    let mut acc = Some(if product { 1 } else { 0 });
+                    for elem in &loaded {
+                        let Value::Int(n) = elem else {
+                            return unsupported(format!("cannot add up `{}`", elem), span);
+                        };
+                        acc = acc.and_then(|acc: i128| if product { acc.checked_mul(*n) } else { acc.checked_add(*n) });
+                    }
+                    return int(acc, site.ty.prim(), if product { "multiply" } else { "add" }, span);
+                }
+            }
+            ("collect", []) => self.collect_into(site, elems)?,
+            ("join" | "concat", _) => {
+                let separator = text_arg(&args, 0).unwrap_or_default();
+                let mut parts = Vec::with_capacity(elems.len());
+                for elem in &elems {
+                    parts.push(self.display(elem, span)?);
+                }
+                Value::Str(parts.join(&separator))
+            }
+            _ => {
+                return unsupported(
+                    format!("no method `{}` on `{}` in the interpreter", method, Value::List(elems)),
+                    span,
+                )
+            }
+        };
+        Ok(value)
+    }
+
+    /// Build the collection the static type of `collect()` names
+    fn collect_into(&mut self, site: &Site, elems: Vec<Value>) -> Eval<Value> {
+        let span = site.span;
+        let target = match site.ty {
+            Type::Adt { def, name, .. } if self.is_library(DefId(*def)) => name.as_str(),
+            _ => "Vec",
+        };
+        Ok(match target {
+            "String" => {
+                let mut text = String::new();
+                for elem in &elems {
+                    text.push_str(&self.display(elem, span)?);
+                }
+                Value::Str(text)
+            }
+            "HashMap" | "BTreeMap" => {
+                let mut entries = Vec::new();
+                for elem in elems {
+                    match at(elem.load(), span)? {
+                        Value::Tuple(pair) if pair.len() == 2 => {
+                            let mut pair = pair.into_iter();
+                            let (key, value) =
+                                (pair.next().unwrap_or_else(Value::unit), pair.next().unwrap_or_else(Value::unit));
+                            map_insert(&mut entries, key, value);
+                        }
+                        other => return unsupported(format!("cannot collect `{}` into a map", other), span),
+                    }
+                }
+                Value::Map(entries)
+            }
+            "HashSet" | "BTreeSet" => {
+                let mut set = Vec::new();
+                for elem in elems {
+                    set_insert(&mut set, at(elem.load(), span)?);
+                }
+                Value::Set(set)
+            }
+            _ => Value::List(elems),
+        })
+    }
+
+    fn is_str_method(&self, method: &str) -> bool {
+        !matches!(
+            method,
+            "to_string" | "clone" | "to_owned" | "into" | "as_str" | "as_ref" | "borrow" | "deref" | "eq" | "ne"
+        )
+    }
+
+    fn str_method(&mut self, site: &Site, s: String, method: &str, args: Vec<Value>) -> Eval<Value> {
+        let span = site.span;
+        let v = self.variants();
+        let strs = |parts: Vec<&str>| Value::List(parts.into_iter().map(|p| Value::Str(p.to_string())).collect());
+        let pattern = text_arg(&args, 0);
+        let value = match (method, pattern.as_deref()) {
+            ("chars", _) => Value::List(s.chars().map(Value::Char).collect()),
+            ("bytes" | "as_bytes", _) => Value::List(s.bytes().map(|b| Value::Int(b.into())).collect()),
+            ("char_indices", _) => Value::List(
+                s.char_indices().map(|(i, c)| Value::Tuple(vec![Value::Int(i as i128), Value::Char(c)])).collect(),
+            ),
+            ("lines", _) => strs(s.lines().collect()),
+            ("split_whitespace", _) => strs(s.split_whitespace().collect()),
+            ("split", Some(sep)) => strs(s.split(sep).collect()),
+            ("trim", _) => Value::Str(s.trim().to_string()),
+            ("trim_start", _) => Value::Str(s.trim_start().to_string()),
+            ("trim_end", _) => Value::Str(s.trim_end().to_string()),
+            ("to_uppercase", _) => Value::Str(s.to_uppercase()),
+            ("to_lowercase", _) => Value::Str(s.to_lowercase()),
+            ("contains", Some(p)) => Value::Bool(s.contains(p)),
+            ("starts_with", Some(p)) => Value::Bool(s.starts_with(p)),
+            ("ends_with", Some(p)) => Value::Bool(s.ends_with(p)),
+            ("find", Some(p)) => v.option(s.find(p).map(|i| Value::Int(i as i128))),
+            ("replace", Some(from)) => Value::Str(s.replace(from, &text_arg(&args, 1).unwrap_or_default())),
+            ("repeat", _) => Value::Str(s.repeat(index_arg(&args, 0).unwrap_or(0))),
+            ("eq_ignore_ascii_case", Some(other)) => Value::Bool(s.eq_ignore_ascii_case(other)),
+            ("parse", _) => self.parse(site, &s),
+            _ => return unsupported(format!("no method `{}` on strings in the interpreter", method), span),
+        };
+        Ok(value)
+    }
+
+    /// `str::parse` into the type the result is expected to hold
+    fn parse(&self, site: &Site, s: &str) -> Value {
+        let v = self.variants();
+        let target = match site.ty {
+            Type::Adt { args, .. } => args.first(),
+            _ => None,
+        };
+        let error = |message: &str| v.err(Value::Str(message.to_string()));
+        match target {
+            Some(Type::Prim(PrimitiveType::F32 | PrimitiveType::F64)) => match s.parse::<f64>() {
+                Ok(x) => v.ok(Value::Float(x)),
+                Err(_) => error("invalid float literal"),
+            },
+            Some(Type::Prim(PrimitiveType::Bool)) => match s.parse::<bool>() {
+                Ok(b) => v.ok(Value::Bool(b)),
+                Err(_) => error("provided string was not `true` or `false`"),
+            },
+            Some(Type::Prim(PrimitiveType::Char)) => match s.parse::<char>() {
+                Ok(c) => v.ok(Value::Char(c)),
+                Err(_) => error("too many characters in string"),
+            },
+            _ if s.is_empty() => error("cannot parse integer from empty string"),
+            _ => match s.parse::<i128>() {
+                Ok(n) => v.ok(Value::Int(n)),
+                Err(_) => match (target, s.parse::<f64>()) {
+                    (None, Ok(x)) => v.ok(Value::Float(x)),
+                    _ => error("invalid digit found in string"),
+                },
+            },
+        }
+    }
+
+    fn int_method(&mut self, site: &Site, n: i128, method: &str, args: &[Value]) -> Eval<Value> {
+        let span = site.span;
+        let v = self.variants();
+        let m = int_arg(args, 0);
+        // `checked_*` results are options of the receiver's type
+        let ty = match site.ty {
+            Type::Adt { args, .. } => args.first().and_then(Type::prim),
+            ty => ty.prim(),
+        };
+        let (lo, hi) = ty.and_then(int_range).unwrap_or((i64::MIN.into(), i64::MAX.into()));
+        let checked = |result: Option<i128>| v.option(result.filter(|r| (lo..=hi).contains(r)).map(Value::Int));
+        let value = match (method, m) {
+            ("abs", None) => return int(n.checked_abs(), ty, "take the absolute value", span),
+            ("pow", Some(m)) => return int(u32::try_from(m).ok().and_then(|m| n.checked_pow(m)), ty, "multiply", span),
+            ("min", Some(m)) => Value::Int(n.min(m)),
+            ("max", Some(m)) => Value::Int(n.max(m)),
+            ("signum", None) => Value::Int(n.signum()),
+            ("is_positive", None) => Value::Bool(n > 0),
+            ("is_negative", None) => Value::Bool(n < 0),
+            ("rem_euclid", Some(0)) => return panic("attempt to calculate the remainder with a divisor of zero", span),
+            ("rem_euclid", Some(m)) => Value::Int(n.rem_euclid(m)),
+            ("checked_add", Some(m)) => checked(n.checked_add(m)),
+            ("checked_sub", Some(m)) => checked(n.checked_sub(m)),
+            ("checked_mul", Some(m)) => checked(n.checked_mul(m)),
+            ("checked_div", Some(m)) => checked(n.checked_div(m)),
+            ("saturating_add", Some(m)) => Value::Int(n.saturating_add(m).clamp(lo, hi)),
+            ("saturating_sub", Some(m)) => Value::Int(n.saturating_sub(m).clamp(lo, hi)),
+            ("saturating_mul", Some(m)) => Value::Int(n.saturating_mul(m).clamp(lo, hi)),
+            _ => return unsupported(format!("no method `{}` on integers in the interpreter", method), span),
+        };
+        Ok(value)
+    }
+}
+
+/// Methods that change the receiver in place, or `None` when `method` is not one of them for `value`
+fn mutate(value: &mut Value, method: &str, args: &mut [Value], v: &Variants) -> Option<Result<Value, String>> {
+    let len = args.len();
+    let mut arg = |i: usize| std::mem::replace(&mut args[i], Value::unit());
+    let unit = Ok(Value::unit());
+    let result = match (value, method, len) {
+        (Value::List(elems), "push" | "push_back", 1) => {
+            elems.push(arg(0));
+            unit
+        }
+        (Value::List(elems), "push_front", 1) => {
+            elems.insert(0, arg(0));
+            unit
+        }
+        (Value::List(elems), "pop" | "pop_back", 0) => Ok(v.option(elems.pop())),
+        // Iterators are lists, consumed from the front
+        (Value::List(elems), "pop_front" | "next", 0) => {
+            Ok(v.option(if elems.is_empty() { None } else { Some(elems.remove(0)) }))
+        }
+        (Value::List(elems), "insert", 2) => match (arg(0), arg(1)) {
+            (Value::Int(i), x) if (0..=elems.len() as i128).contains(&i) => {
+                elems.insert(i as usize, x);
+                unit
+            }
+            (i, _) => Err(format!("insertion index (is {}) should be <= len (is {})", i, elems.len())),
+        },
+        (Value::List(elems), "remove", 1) => match arg(0) {
+            Value::Int(i) if (0..elems.len() as i128).contains(&i) => Ok(elems.remove(i as usize)),
+            i => Err(format!("removal index (is {}) should be < len (is {})", i, elems.len())),
+        },
+        (Value::List(elems), "swap", 2) => match (arg(0), arg(1)) {
+            (Value::Int(i), Value::Int(j)) if i.max(j) < elems.len() as i128 && i.min(j) >= 0 => {
+                elems.swap(i as usize, j as usize);
+                unit
+            }
+            (Value::Int(i), Value::Int(j)) => Err(out_of_bounds(elems.len(), i.max(j) as usize)),
+            _ => return None,
+        },
+        (Value::List(elems), "truncate", 1) => match arg(0) {
+            Value::Int(n) => {
+                elems.truncate(usize::try_from(n).unwrap_or(0));
+                unit
+            }
+            _ => return None,
+        },
+        (Value::List(elems), "extend" | "append", 1) => match arg(0) {
+            Value::List(more) => {
+                elems.extend(more);
+                unit
+            }
+            _ => return None,
+        },
+        (Value::List(elems), "clear", 0) => {
+            elems.clear();
+            unit
+        }
+        (Value::List(elems), "reverse", 0) => {
+            elems.reverse();
+            unit
+        }
+        (Value::List(elems), "sort" | "sort_unstable", 0) => {
+            elems.sort_by(|a, b| a.compare(b).unwrap_or(Ordering::Equal));
+            unit
+        }
+        (Value::List(elems), "dedup", 0) => {
+            elems.dedup();
+            unit
+        }
+        (Value::Str(s), "push_str" | "push", 1) => {
+            s.push_str(&arg(0).to_text());
+            unit
+        }
+        (Value::Str(s), "pop", 0) => Ok(v.option(s.pop().map(Value::Char))),
+        (Value::Str(s), "clear", 0) => {
+            s.clear();
+            unit
+        }
+        (Value::Map(entries), "insert", 2) => {
+            let (key, value) = (arg(0), arg(1));
+            Ok(v.option(map_insert(entries, key, value)))
+        }
+        (Value::Map(entries), "remove", 1) => {
+            let key = arg(0);
+            let removed = entries.iter().position(|(k, _)| *k == key).map(|i| entries.remove(i).1);
+            Ok(v.option(removed))
+        }
+        (Value::Map(entries), "clear", 0) => {
+            entries.clear();
+            unit
+        }
+        (Value::Set(elems), "insert", 1) => Ok(Value::Bool(set_insert(elems, arg(0)))),
+        (Value::Set(elems), "remove", 1) => {
+            let elem = arg(0);
+            let before = elems.len();
+            elems.retain(|e| *e != elem);
+            Ok(Value::Bool(elems.len() != before))
+        }
+        (Value::Set(elems), "clear", 0) => {
+            elems.clear();
+            unit
+        }
+        (option @ Value::Adt { .. }, "take", 0) if is_option(option, v) => {
+            Ok(std::mem::replace(option, v.option(None)))
+        }
+        (option @ Value::Adt { .. }, "replace", 1) if is_option(option, v) => {
+            Ok(std::mem::replace(option, v.option(Some(arg(0)))))
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn is_option(value: &Value, v: &Variants) -> bool {
+    matches!(value, Value::Adt { def, .. } if *def == v.some || *def == v.none)
+}
+
+fn char_method(c: char, method: &str, args: &[Value], v: &Variants, span: Span) -> Eval<Value> {
+    let radix = index_arg(args, 0).and_then(|r| u32::try_from(r).ok()).unwrap_or(10);
+    let value = match method {
+        "is_digit" => Value::Bool(c.is_digit(radix)),
+        "is_ascii_digit" => Value::Bool(c.is_ascii_digit()),
+        "is_numeric" => Value::Bool(c.is_numeric()),
+        "is_alphabetic" => Value::Bool(c.is_alphabetic()),
+        "is_alphanumeric" => Value::Bool(c.is_alphanumeric()),
+        "is_whitespace" => Value::Bool(c.is_whitespace()),
+        "is_uppercase" => Value::Bool(c.is_uppercase()),
+        "is_lowercase" => Value::Bool(c.is_lowercase()),
+        "is_ascii_punctuation" => Value::Bool(c.is_ascii_punctuation()),
+        "to_digit" => v.option(c.to_digit(radix).map(|d| Value::Int(d.into()))),
+        "to_uppercase" => Value::Str(c.to_uppercase().collect()),
+        "to_lowercase" => Value::Str(c.to_lowercase().collect()),
+        "to_ascii_uppercase" => Value::Char(c.to_ascii_uppercase()),
+        "to_ascii_lowercase" => Value::Char(c.to_ascii_lowercase()),
+        "len_utf8" => Value::Int(c.len_utf8() as i128),
+        "to_string" => Value::Str(c.to_string()),
+        "clone" => Value::Char(c),
+        "eq" => Value::Bool(args.first() == Some(&Value::Char(c))),
+        _ => return unsupported(format!("no method `{}` on characters in the interpreter", method), span),
+    };
+    Ok(value)
+}
+
+fn float_method(x: f64, method: &str, args: &[Value], span: Span) -> Eval<Value> {
+    let y = match args.first() {
+        Some(Value::Float(y)) => Some(*y),
+        Some(Value::Int(n)) => Some(*n as f64),
+        _ => None,
+    };
+    let value = match (method, y) {
+        ("sqrt", None) => x.sqrt(),
+        ("abs", None) => x.abs(),
+        ("floor", None) => x.floor(),
+        ("ceil", None) => x.ceil(),
+        ("round", None) => x.round(),
+        ("trunc", None) => x.trunc(),
+        ("fract", None) => x.fract(),
+        ("signum", None) => x.signum(),
+        ("sin", None) => x.sin(),
+        ("cos", None) => x.cos(),
+        ("tan", None) => x.tan(),
+        ("ln", None) => x.ln(),
+        ("log10", None) => x.log10(),
+        ("log2", None) => x.log2(),
+        ("exp", None) => x.exp(),
+        ("powi" | "powf", Some(y)) => x.powf(y),
+        ("min", Some(y)) => x.min(y),
+        ("max", Some(y)) => x.max(y),
+        ("is_nan", None) => return Ok(Value::Bool(x.is_nan())),
+        ("eq", Some(y)) => return Ok(Value::Bool(x == y)),
+        _ => return unsupported(format!("no method `{}` on floats in the interpreter", method), span),
+    };
+    Ok(Value::Float(value))
+}
+
+/// `text` right-aligned to `width`, or left-aligned with a `<` before the width
+fn pad(text: &str, width: &str) -> String {
+    let (left, digits) = match width.strip_prefix('<') {
+        Some(digits) => (true, digits),
+        None => (false, width.strip_prefix('>').unwrap_or(width)),
+    };
+    let Ok(width) = digits.parse::<usize>() else {
+        return text.to_string();
+    };
+    match left {
+        true => format!("{:<width$}", text, width = width),
+        false => format!("{:>width$}", text, width = width),
+    }
+}
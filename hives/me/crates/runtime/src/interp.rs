@@ -0,0 +1,1056 @@
+// Interpreter
+// Runs a checked crate by walking its syntax tree from `main`. Method calls
+// use the impls type checking selected, dispatching trait methods on the
+// value the receiver holds at run time.
+
+use crate::builtins::{Host, LibraryTypes, Site, Variants};
+use crate::value::{slot, Callable, Place, Slot, Step, Value};
+use crate::{RuntimeError, RuntimeErrorKind};
+use my_lang_ast::*;
+use my_lang_codegen::bytecode::{int_range, Type};
+use my_lang_codegen::lower_ty;
+use my_lang_codegen::mono::{self, Key, SelfTypes};
+use my_lang_resolve::{DefId, DefKind, Res, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults};
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Nested calls allowed before the program stops with a stack overflow
+pub const MAX_DEPTH: usize = 10_000;
+
+/// Control leaving an expression other than by producing a value
+pub(crate) enum Unwind {
+    Break(Value),
+    Continue,
+    Return(Value),
+    /// `process::exit` with a status code
+    Exit(i32),
+    Error(RuntimeError),
+}
+
+impl From<RuntimeError> for Unwind {
+    fn from(err: RuntimeError) -> Self {
+        Unwind::Error(err)
+    }
+}
+
+pub(crate) type Eval<T> = Result<T, Unwind>;
+
+pub(crate) fn panic<T>(message: impl Into<String>, span: Span) -> Eval<T> {
+    Err(RuntimeError::new(RuntimeErrorKind::Panic(message.into()), span).into())
+}
+
+pub(crate) fn unsupported<T>(message: impl Into<String>, span: Span) -> Eval<T> {
+    Err(RuntimeError::new(RuntimeErrorKind::Unsupported(message.into()), span).into())
+}
+
+/// A failed read or write of a place, which is a panic such as an index out of bounds
+pub(crate) fn at<T>(result: Result<T, String>, span: Span) -> Eval<T> {
+    result.or_else(|message| panic(message, span))
+}
+
+pub(crate) const INTS: [PrimitiveType; 12] = [
+    PrimitiveType::I32,
+    PrimitiveType::I64,
+    PrimitiveType::Usize,
+    PrimitiveType::U32,
+    PrimitiveType::U64,
+    PrimitiveType::U8,
+    PrimitiveType::I8,
+    PrimitiveType::I16,
+    PrimitiveType::U16,
+    PrimitiveType::I128,
+    PrimitiveType::U128,
+    PrimitiveType::Isize,
+];
+
+/// A function defined in an impl
+struct Method {
+    trait_def: Option<DefId>,
+    name: String,
+    def: DefId,
+}
+
+/// Locals of one function call
+#[derive(Default)]
+struct Frame {
+    locals: HashMap<DefId, Slot>,
+}
+
+pub struct Interpreter<'a> {
+    pub(crate) res: &'a Resolution,
+    pub(crate) types: &'a TypeckResults,
+    fns: HashMap<DefId, &'a Function>,
+    /// Closure expressions evaluated so far, by node
+    closures: HashMap<NodeId, &'a Expression>,
+    consts: HashMap<DefId, &'a Const>,
+    const_values: HashMap<DefId, Value>,
+    methods: HashMap<Key, Vec<Method>>,
+    self_types: SelfTypes,
+    lib_types: LibraryTypes<DefId>,
+    pub(crate) variants: Variants,
+    frames: Vec<Frame>,
+    /// Command line, program first, as `env::args` returns it
+    pub(crate) args: Vec<String>,
+    pub(crate) out: &'a mut dyn Write,
+    pub(crate) err: &'a mut dyn Write,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(
+        programs: &[&'a Program],
+        res: &'a Resolution,
+        types: &'a TypeckResults,
+        args: Vec<String>,
+        out: &'a mut dyn Write,
+        err: &'a mut dyn Write,
+    ) -> Self {
+        let lib_types = LibraryTypes::new(mono::library_types(res).map(|(name, def)| (name.to_string(), def)));
+        let mut interp = Self {
+            res,
+            types,
+            fns: HashMap::new(),
+            closures: HashMap::new(),
+            consts: HashMap::new(),
+            const_values: HashMap::new(),
+            methods: HashMap::new(),
+            self_types: SelfTypes::default(),
+            lib_types,
+            variants: Variants::new(res),
+            frames: Vec::new(),
+            args,
+            out,
+            err,
+        };
+        for program in programs {
+            interp.collect(&program.items);
+        }
+        interp
+    }
+
+    fn collect(&mut self, items: &'a [Item]) {
+        for item in items {
+            match &item.kind {
+                ItemKind::Function(func) => self.add_fn(func),
+                ItemKind::Const(c) => self.add_const(c),
+                ItemKind::Impl(imp) => self.add_impl(imp),
+                ItemKind::Trait(t) => {
+                    for item in &t.items {
+                        match item {
+                            TraitItem::Function(func) => self.add_fn(func),
+                            TraitItem::Const(c) => self.add_const(c),
+                            _ => {}
+                        }
+                    }
+                }
+                ItemKind::Module(module) => self.collect(&module.items),
+                _ => {}
+            }
+        }
+    }
+
+    fn add_fn(&mut self, func: &'a Function) {
+        if let Some(def) = self.res.def_of_node(func.id) {
+            self.fns.insert(def, func);
+        }
+        // Items declared inside the body
+        for stmt in &func.body.stmts {
+            if let StatementKind::Item(item) = &stmt.kind {
+                self.collect(std::slice::from_ref(item));
+            }
+        }
+    }
+
+    fn add_const(&mut self, c: &'a Const) {
+        if let Some(def) = self.res.def_of_node(c.id) {
+            self.consts.insert(def, c);
+        }
+    }
+
+    fn add_impl(&mut self, imp: &'a Impl) {
+        let trait_def = match self.res.res(imp.id) {
+            Some(Res::Def(def)) => Some(*def),
+            _ => None,
+        };
+        let key = self.self_types.add_impl(self.res, imp);
+        for item in &imp.items {
+            match item {
+                ImplItem::Function(func) => {
+                    self.add_fn(func);
+                    if let (Some(key), Some(def)) = (key, self.res.def_of_node(func.id)) {
+                        let method = Method { trait_def, name: func.name.clone(), def };
+                        self.methods.entry(key).or_default().push(method);
+                    }
+                }
+                ImplItem::Const(c) => self.add_const(c),
+                ImplItem::Type(_) => {}
+            }
+        }
+    }
+
+    /// Run `main`, returning the process exit status
+    pub fn run_main(&mut self, program: &'a Program) -> Result<i32, RuntimeError> {
+        let main = program.items.iter().find_map(|item| match &item.kind {
+            ItemKind::Function(func) if func.name == "main" => self.res.def_of_node(func.id).map(|def| (def, func)),
+            _ => None,
+        });
+        let Some((def, func)) = main else {
+            let kind = RuntimeErrorKind::Unsupported("`main` function not found".to_string());
+            return Err(RuntimeError::new(kind, Span::dummy()));
+        };
+        // `main` may take the command line instead of calling `env::args`
+        let args = match func.params.len() {
+            0 => Vec::new(),
+            _ => vec![Value::List(self.args.iter().cloned().map(Value::Str).collect())],
+        };
+        let result = self.call_fn(def, args, func.span);
+        let _ = self.out.flush();
+        match result {
+            Ok(value) => match value.load() {
+                Ok(Value::Adt { def, fields, .. }) if def == self.variants.err => {
+                    let error = fields.first().map(|(_, e)| e.to_string()).unwrap_or_default();
+                    let _ = writeln!(self.err, "Error: {}", error);
+                    Ok(1)
+                }
+                _ => Ok(0),
+            },
+            Err(Unwind::Exit(code)) => Ok(code),
+            Err(Unwind::Error(err)) => Err(err),
+            Err(_) => Ok(0),
+        }
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("evaluation runs inside a frame")
+    }
+
+    fn local(&mut self, def: DefId) -> Slot {
+        self.frame().locals.entry(def).or_insert_with(|| slot(Value::unit())).clone()
+    }
+
+    // ========== Expressions ==========
+
+    fn block(&mut self, block: &'a Block) -> Eval<Value> {
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StatementKind::Let { pattern, init, .. } => match init {
+                    Some(init) => {
+                        let value = self.expr(init)?;
+                        self.bind(pattern, value)?;
+                    }
+                    // A declaration without a value gets a slot for a later assignment to fill
+                    None => {
+                        if let Some(def) = self.res.def_of_node(pattern.id) {
+                            self.frame().locals.insert(def, slot(Value::unit()));
+                        }
+                    }
+                },
+                StatementKind::Expression(expr) => {
+                    self.expr(expr)?;
+                }
+                StatementKind::Item(_) | StatementKind::Error => {}
+            }
+        }
+        match &block.expr {
+            Some(expr) => self.expr(expr),
+            None => Ok(Value::unit()),
+        }
+    }
+
+    pub(crate) fn expr(&mut self, expr: &'a Expression) -> Eval<Value> {
+        match &expr.kind {
+            ExpressionKind::Literal(lit) => Ok(literal(lit)),
+            ExpressionKind::Identifier(_) | ExpressionKind::Path(_) => self.name(expr),
+            ExpressionKind::Binary { left, op, right } => self.binary(expr, left, op, right),
+            ExpressionKind::Unary { op, expr: operand } => self.unary(expr, op, operand),
+            ExpressionKind::Call { func, args } => {
+                let callee = self.expr(func)?;
+                let values = self.exprs(args)?;
+                match at(callee.load(), func.span)? {
+                    Value::Fn(callable) => {
+                        let ty = self.ty_of(expr);
+                        self.call_value(&callable, values, &Site { span: expr.span, ty: &ty })
+                    }
+                    other => unsupported(format!("`{}` is not a function", other), func.span),
+                }
+            }
+            ExpressionKind::MethodCall { receiver, method, args } => self.method_call(expr, receiver, method, args),
+            ExpressionKind::If { cond, then_block, else_block } => {
+                if self.condition(cond)? {
+                    self.block(then_block)
+                } else if let Some(block) = else_block {
+                    self.block(block)
+                } else {
+                    Ok(Value::unit())
+                }
+            }
+            ExpressionKind::Match { expr: scrutinee, arms } => {
+                let place = self.place(scrutinee)?;
+                for arm in arms {
+                    let mut binds = Vec::new();
+                    if !self.matches(&arm.pattern, &place, false, &mut binds)? {
+                        continue;
+                    }
+                    self.frame().locals.extend(binds.into_iter().map(|(def, value)| (def, slot(value))));
+                    if let Some(guard) = &arm.guard {
+                        if !self.condition(guard)? {
+                            continue;
+                        }
+                    }
+                    return self.expr(&arm.body);
+                }
+                let value = at(place.get(), expr.span)?;
+                panic(format!("no match arm matched `{}`", value), expr.span)
+            }
+            ExpressionKind::Loop(body) => loop {
+                match self.block(body) {
+                    Ok(_) | Err(Unwind::Continue) => {}
+                    Err(Unwind::Break(value)) => return Ok(value),
+                    Err(other) => return Err(other),
+                }
+            },
+            ExpressionKind::While { cond, body } => {
+                while self.condition(cond)? {
+                    match self.block(body) {
+                        Ok(_) | Err(Unwind::Continue) => {}
+                        Err(Unwind::Break(_)) => break,
+                        Err(other) => return Err(other),
+                    }
+                }
+                Ok(Value::unit())
+            }
+            ExpressionKind::For { pattern, iter, body } => {
+                for item in self.iterate(iter)? {
+                    self.bind(pattern, item)?;
+                    match self.block(body) {
+                        Ok(_) | Err(Unwind::Continue) => {}
+                        Err(Unwind::Break(_)) => break,
+                        Err(other) => return Err(other),
+                    }
+                }
+                Ok(Value::unit())
+            }
+            ExpressionKind::Return(value) => {
+                let value = match value {
+                    Some(value) => self.expr(value)?,
+                    None => Value::unit(),
+                };
+                Err(Unwind::Return(value))
+            }
+            ExpressionKind::Break(value) => {
+                let value = match value {
+                    Some(value) => self.expr(value)?,
+                    None => Value::unit(),
+                };
+                Err(Unwind::Break(value))
+            }
+            ExpressionKind::Continue => Err(Unwind::Continue),
+            ExpressionKind::Block(block) => self.block(block),
+            // Pure, so evaluating it again gives the value compilation computed
+            ExpressionKind::Comptime(inner) => self.expr(inner),
+            ExpressionKind::Tuple(elems) => Ok(Value::Tuple(self.exprs(elems)?)),
+            ExpressionKind::Array(elems) => Ok(Value::List(self.exprs(elems)?)),
+            ExpressionKind::Index { .. } | ExpressionKind::Field { .. } => {
+                let place = self.place(expr)?;
+                at(place.get(), expr.span)
+            }
+            ExpressionKind::Struct { fields, .. } => {
+                let Some(Res::Def(def)) = self.res.res(expr.id).cloned() else {
+                    return unsupported("cannot build this struct", expr.span);
+                };
+                let mut values = Vec::new();
+                for (name, value) in fields {
+                    values.push((name.clone(), self.expr(value)?));
+                }
+                // Fields in declaration order, so that equal values compare equal
+                if let Some(declared) = self.res.fields.get(&def) {
+                    values.sort_by_key(|(name, _)| declared.iter().position(|f| f.name == *name));
+                }
+                Ok(Value::Adt { def, name: self.res.def(def).name.clone(), fields: values })
+            }
+            // Without a model to consult, the symbolic side is the answer
+            ExpressionKind::Synth { expr: inner, .. } | ExpressionKind::Verify { expr: inner, .. } => self.expr(inner),
+            ExpressionKind::Hybrid { symbolic, .. } => self.expr(symbolic),
+            ExpressionKind::Intent { .. } => {
+                unsupported("`intent` needs a model to synthesize from, which the interpreter does not have", expr.span)
+            }
+            ExpressionKind::Spawn { .. }
+            | ExpressionKind::Send { .. }
+            | ExpressionKind::Receive { .. }
+            | ExpressionKind::Broadcast { .. } => unsupported("agents are not supported by the interpreter", expr.span),
+            ExpressionKind::Closure { is_move, .. } => {
+                self.closures.insert(expr.id, expr);
+                let res = self.res;
+                let mut captures = Vec::new();
+                for &def in res.captures.get(&expr.id).into_iter().flatten() {
+                    let local = self.local(def);
+                    // `move` closures take the values; others share the locals' slots
+                    captures.push(if *is_move { slot(local.borrow().clone()) } else { local });
+                }
+                Ok(Value::Fn(Callable::Closure { code: expr.id.0, captures }))
+            }
+        }
+    }
+
+    fn exprs(&mut self, exprs: &'a [Expression]) -> Eval<Vec<Value>> {
+        exprs.iter().map(|e| self.expr(e)).collect()
+    }
+
+    fn condition(&mut self, cond: &'a Expression) -> Eval<bool> {
+        match at(self.expr(cond)?.load(), cond.span)? {
+            Value::Bool(b) => Ok(b),
+            other => unsupported(format!("expected a `bool`, found `{}`", other), cond.span),
+        }
+    }
+
+    /// A local, constant, function or unit variant
+    fn name(&mut self, expr: &Expression) -> Eval<Value> {
+        match self.res.res(expr.id).cloned() {
+            Some(Res::Def(def)) => {
+                let definition = self.res.def(def);
+                match definition.kind {
+                    DefKind::Local | DefKind::Param => match self.frame().locals.get(&def) {
+                        Some(slot) => Ok(slot.borrow().clone()),
+                        None => panic(format!("use of uninitialized `{}`", definition.name), expr.span),
+                    },
+                    DefKind::Const => self.constant(def, expr.span),
+                    DefKind::Variant | DefKind::Struct if !matches!(self.types.type_of(expr.id), Some(Ty::Fn(..))) => {
+                        Ok(Value::Adt { def, name: definition.name.clone(), fields: Vec::new() })
+                    }
+                    DefKind::Variant | DefKind::Struct | DefKind::Function => Ok(Value::Fn(Callable::Def(def))),
+                    DefKind::External => Ok(Value::Fn(Callable::Library(definition.name.clone()))),
+                    kind => unsupported(
+                        format!("cannot use {} `{}` as a value", kind.describe(), definition.name),
+                        expr.span,
+                    ),
+                }
+            }
+            Some(Res::Partial { .. }) => {
+                let def = mono::resolve_partial(self.res, self.types, &self.self_types, expr, |key, name| {
+                    self.find_method(&[key], None, name)
+                });
+                Ok(Value::Fn(def.map_or_else(Callable::Library, Callable::Def)))
+            }
+            None => unsupported("this name did not resolve", expr.span),
+        }
+    }
+
+    fn constant(&mut self, def: DefId, span: Span) -> Eval<Value> {
+        if let Some(value) = self.const_values.get(&def) {
+            return Ok(value.clone());
+        }
+        let Some(&c) = self.consts.get(&def) else {
+            return unsupported(format!("constant `{}` has no value", self.res.def(def).name), span);
+        };
+        self.frames.push(Frame::default());
+        let value = self.expr(&c.value);
+        self.frames.pop();
+        let value = value?;
+        self.const_values.insert(def, value.clone());
+        Ok(value)
+    }
+
+    fn iterate(&mut self, iter: &'a Expression) -> Eval<Vec<Value>> {
+        let value = self.expr(iter)?;
+        items(value, iter.span)
+    }
+
+    /// The type of `expr` as library calls consult it
+    fn ty_of(&self, expr: &Expression) -> Type {
+        self.types.type_of(expr.id).map_or(Type::Unknown, |ty| lower_ty(ty, self.res))
+    }
+
+    fn prim_of(&self, expr: &Expression) -> Option<PrimitiveType> {
+        match self.types.type_of(expr.id) {
+            Some(Ty::Prim(prim)) => Some(*prim),
+            _ => None,
+        }
+    }
+
+    fn host(&mut self) -> &mut (dyn Host + 'a) {
+        self
+    }
+
+    // ========== Operators ==========
+
+    fn unary(&mut self, expr: &'a Expression, op: &UnaryOp, operand: &'a Expression) -> Eval<Value> {
+        match op {
+            UnaryOp::Deref => {
+                let place = self.place(expr)?;
+                return at(place.get(), expr.span);
+            }
+            UnaryOp::Ref | UnaryOp::RefMut => return Ok(Value::Ref(self.place(operand)?)),
+            _ => {}
+        }
+        let value = at(self.expr(operand)?.load(), operand.span)?;
+        unary_op(op, value, self.prim_of(expr), expr.span)
+    }
+
+    fn binary(&mut self, expr: &'a Expression, left: &'a Expression, op: &BinaryOp, right: &'a Expression) -> Eval<Value> {
+        match op {
+            BinaryOp::Assign => {
+                let value = self.expr(right)?;
+                let place = self.place(left)?;
+                at(place.set(value), expr.span)?;
+                return Ok(Value::unit());
+            }
+            BinaryOp::And => return Ok(Value::Bool(self.condition(left)? && self.condition(right)?)),
+            BinaryOp::Or => return Ok(Value::Bool(self.condition(left)? || self.condition(right)?)),
+            _ => {}
+        }
+        let lhs = at(self.expr(left)?.load(), left.span)?;
+        let rhs = at(self.expr(right)?.load(), right.span)?;
+        let ty = self.prim_of(expr);
+        self.host().binary_op(op, lhs, rhs, ty, expr.span)
+    }
+
+    // ========== Places ==========
+
+    /// Where `expr` lives: a local or a part of one, what a reference points
+    /// to, or a temporary holding the value of any other expression
+    pub(crate) fn place(&mut self, expr: &'a Expression) -> Eval<Place> {
+        match &expr.kind {
+            ExpressionKind::Identifier(_) => match self.res.res(expr.id) {
+                Some(Res::Def(def)) if matches!(self.res.def(*def).kind, DefKind::Local | DefKind::Param) => {
+                    Ok(Place::new(self.local(*def)))
+                }
+                _ => Ok(Place::temp(self.expr(expr)?)),
+            },
+            ExpressionKind::Field { expr: base, field } => {
+                let base = self.place(base)?;
+                let base = at(base.follow(), expr.span)?;
+                Ok(base.child(Step::Field(field.clone())))
+            }
+            ExpressionKind::Index { expr: base, index } => {
+                let base = self.place(base)?;
+                let base = at(base.follow(), expr.span)?;
+                let index = at(self.expr(index)?.load(), index.span)?;
+                let is_map = at(base.with(|value| matches!(value, Value::Map(_))), expr.span)?;
+                let step = match index {
+                    key if is_map => Step::Key(key),
+                    Value::Int(i) => Step::Index(usize::try_from(i).unwrap_or(usize::MAX)),
+                    other => return unsupported(format!("cannot index with `{}`", other), expr.span),
+                };
+                Ok(base.child(step))
+            }
+            // A `Box` is its contents, so dereferencing anything else stays in place
+            ExpressionKind::Unary { op: UnaryOp::Deref, expr: inner } => {
+                let place = self.place(inner)?;
+                at(place.follow(), expr.span)
+            }
+            _ => Ok(Place::temp(self.expr(expr)?)),
+        }
+    }
+
+    // ========== Patterns ==========
+
+    fn bind(&mut self, pattern: &Pattern, value: Value) -> Eval<()> {
+        if let (PatternKind::Identifier(_), Some(def)) = (&pattern.kind, self.res.def_of_node(pattern.id)) {
+            self.frame().locals.insert(def, slot(value));
+            return Ok(());
+        }
+        let place = Place::temp(value);
+        let mut binds = Vec::new();
+        if !self.matches(pattern, &place, false, &mut binds)? {
+            let value = at(place.get(), pattern.span)?;
+            return panic(format!("pattern does not match `{}`", value), pattern.span);
+        }
+        self.frame().locals.extend(binds.into_iter().map(|(def, value)| (def, slot(value))));
+        Ok(())
+    }
+
+    /// Whether the value at `place` matches `pattern`. Bindings reached
+    /// through a reference borrow from it rather than copying.
+    fn matches(
+        &mut self,
+        pattern: &Pattern,
+        place: &Place,
+        by_ref: bool,
+        binds: &mut Vec<(DefId, Value)>,
+    ) -> Eval<bool> {
+        let span = pattern.span;
+        match &pattern.kind {
+            PatternKind::Wildcard => Ok(true),
+            PatternKind::Identifier(_) | PatternKind::Path(_) => match self.res.res(pattern.id).cloned() {
+                Some(Res::Def(def)) if self.res.def(def).kind == DefKind::Const => {
+                    let expected = self.constant(def, span)?;
+                    Ok(at(place.get(), span)? == expected)
+                }
+                Some(Res::Def(def)) if matches!(self.res.def(def).kind, DefKind::Variant | DefKind::Struct) => {
+                    let (place, _) = deref_pattern(place, by_ref, span)?;
+                    at(place.with(|value| matches!(value, Value::Adt { def: d, .. } if *d == def)), span)
+                }
+                _ => {
+                    if let Some(def) = self.res.def_of_node(pattern.id) {
+                        let value = if by_ref { Value::Ref(place.clone()) } else { at(place.get(), span)? };
+                        binds.push((def, value));
+                    }
+                    Ok(true)
+                }
+            },
+            PatternKind::Literal(lit) => Ok(at(place.get(), span)? == literal(lit)),
+            PatternKind::Tuple(pats) => {
+                let (place, by_ref) = deref_pattern(place, by_ref, span)?;
+                let len =
+                    at(place.with(|value| matches!(value, Value::Tuple(elems) if elems.len() == pats.len())), span)?;
+                Ok(len
+                    && self.match_fields(
+                        pats.iter().enumerate().map(|(i, p)| (i.to_string(), p)),
+                        &place,
+                        by_ref,
+                        binds,
+                    )?)
+            }
+            PatternKind::TupleStruct { elems: pats, .. } => {
+                let Some(Res::Def(def)) = self.res.res(pattern.id).cloned() else {
+                    return Ok(false);
+                };
+                let (place, by_ref) = deref_pattern(place, by_ref, span)?;
+                let matched = place.with(|value| {
+                    matches!(value, Value::Adt { def: d, fields, .. } if *d == def && fields.len() == pats.len())
+                });
+                Ok(at(matched, span)?
+                    && self.match_fields(
+                        pats.iter().enumerate().map(|(i, p)| (i.to_string(), p)),
+                        &place,
+                        by_ref,
+                        binds,
+                    )?)
+            }
+            PatternKind::Struct { fields: pats, .. } => {
+                let Some(Res::Def(def)) = self.res.res(pattern.id).cloned() else {
+                    return Ok(false);
+                };
+                let (place, by_ref) = deref_pattern(place, by_ref, span)?;
+                let matched = place.with(|value| matches!(value, Value::Adt { def: d, .. } if *d == def));
+                Ok(at(matched, span)?
+                    && self.match_fields(pats.iter().map(|(n, p)| (n.clone(), p)), &place, by_ref, binds)?)
+            }
+        }
+    }
+
+    fn match_fields<'p>(
+        &mut self,
+        fields: impl Iterator<Item = (String, &'p Pattern)>,
+        place: &Place,
+        by_ref: bool,
+        binds: &mut Vec<(DefId, Value)>,
+    ) -> Eval<bool> {
+        for (name, pat) in fields {
+            if !self.matches(pat, &place.child(Step::Field(name)), by_ref, binds)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // ========== Calls ==========
+
+    pub(crate) fn call_value(&mut self, callable: &Callable, args: Vec<Value>, site: &Site) -> Eval<Value> {
+        let def = match callable {
+            Callable::Def(def) => *def,
+            Callable::Library(path) => return self.host().library(path, args, site),
+            Callable::Closure { code, captures } => return self.call_closure(NodeId(*code), captures, args, site.span),
+        };
+        let definition = self.res.def(def);
+        match definition.kind {
+            DefKind::Variant | DefKind::Struct => {
+                let fields = args.into_iter().enumerate().map(|(i, v)| (i.to_string(), v)).collect();
+                Ok(Value::Adt { def, name: definition.name.clone(), fields })
+            }
+            DefKind::Function if definition.is_prelude() => {
+                let name = definition.name.clone();
+                self.host().builtin(&name, args, site)
+            }
+            _ => {
+                let keys = match args.first() {
+                    Some(receiver) => self.keys_of(receiver, None, site.span)?,
+                    None => Vec::new(),
+                };
+                let target = self.dispatch(def, &keys, site.span)?;
+                self.call_fn(target, args, site.span)
+            }
+        }
+    }
+
+    fn method_call(
+        &mut self,
+        expr: &'a Expression,
+        receiver: &'a Expression,
+        method: &str,
+        args: &'a [Expression],
+    ) -> Eval<Value> {
+        let mut place = self.place(receiver)?;
+        let mut values = self.exprs(args)?;
+        // Auto-dereference: methods apply to what references point to
+        while let Some(target) = at(place.referent(), expr.span)? {
+            place = target;
+        }
+        let static_ty = self.types.type_of(receiver.id);
+        let resolved = self.types.method_calls.get(&expr.id).copied().filter(|def| self.is_user_fn(*def));
+        let shared = at(place.with(|v| matches!(v, Value::Shared(_))), expr.span)?;
+        if shared && resolved.is_none() && crate::builtins::is_cell_method(method) {
+            let ty = self.ty_of(expr);
+            return self.host().builtin_method(&Site { span: expr.span, ty: &ty }, place, method, values);
+        }
+        if shared {
+            place = at(place.follow(), expr.span)?;
+        }
+
+        let receiver_value = at(place.with(Value::clone), expr.span)?;
+        let keys = self.keys_of(&receiver_value, static_ty, expr.span)?;
+        let target = match resolved {
+            Some(def) => Some(self.dispatch(def, &keys, expr.span)?),
+            // Receivers whose type only run time knows, such as elements of library collections
+            None => self.find_method(&keys, None, method),
+        };
+        let Some(def) = target else {
+            let ty = self.ty_of(expr);
+            return self.host().builtin_method(&Site { span: expr.span, ty: &ty }, place, method, values);
+        };
+        let by_ref = matches!(
+            self.fns.get(&def).and_then(|f| f.params.first()).map(|p| &p.ty.kind),
+            Some(TypeKind::Reference { .. })
+        );
+        values.insert(0, if by_ref { Value::Ref(place) } else { receiver_value });
+        self.call_fn(def, values, expr.span)
+    }
+
+    fn is_user_fn(&self, def: DefId) -> bool {
+        self.fns.contains_key(&def) || self.trait_of(def).is_some()
+    }
+
+    fn trait_of(&self, def: DefId) -> Option<DefId> {
+        self.res.def(def).parent.filter(|parent| self.res.def(*parent).kind == DefKind::Trait)
+    }
+
+    /// The function a call to `def` runs: for a trait's function, the one the
+    /// receiver's impl defines, or else the trait's default
+    fn dispatch(&self, def: DefId, keys: &[Key], span: Span) -> Eval<DefId> {
+        let Some(trait_def) = self.trait_of(def) else {
+            return Ok(def);
+        };
+        let name = &self.res.def(def).name;
+        if let Some(found) = self.find_method(keys, Some(trait_def), name) {
+            return Ok(found);
+        }
+        if self.fns.contains_key(&def) {
+            return Ok(def);
+        }
+        // A function without `self` on a type parameter: only one impl can be meant
+        let mut impls = self.methods.values().flatten().filter(|m| m.trait_def == Some(trait_def) && m.name == *name);
+        match (impls.next(), impls.next()) {
+            (Some(method), None) => Ok(method.def),
+            _ => unsupported(format!("cannot tell which implementation of `{}` to call", name), span),
+        }
+    }
+
+    pub(crate) fn find_method(&self, keys: &[Key], trait_def: Option<DefId>, name: &str) -> Option<DefId> {
+        keys.iter().find_map(|key| {
+            let methods = self.methods.get(key)?;
+            methods.iter().find(|m| m.name == name && (trait_def.is_none() || m.trait_def == trait_def)).map(|m| m.def)
+        })
+    }
+
+    /// Impl keys that could apply to `value`, most specific first. Integers
+    /// carry no width at run time, so the static type is preferred when known.
+    pub(crate) fn keys_of(&self, value: &Value, static_ty: Option<&Ty>, span: Span) -> Eval<Vec<Key>> {
+        let mut ty = static_ty;
+        while let Some(Ty::Ref { ty: inner, .. }) = ty {
+            ty = Some(inner);
+        }
+        match ty {
+            Some(Ty::Prim(prim)) => return Ok(vec![Key::Prim(*prim)]),
+            Some(Ty::Adt(def, _)) => return Ok(vec![Key::Def(*def)]),
+            _ => {}
+        }
+        let loaded = at(value.load(), span)?;
+        let mut keys = match &loaded {
+            Value::Adt { def, .. } => {
+                let definition = self.res.def(*def);
+                match (definition.kind, definition.parent) {
+                    (DefKind::Variant, Some(parent)) => vec![Key::Def(parent)],
+                    _ => vec![Key::Def(*def)],
+                }
+            }
+            Value::Int(_) => INTS.iter().map(|p| Key::Prim(*p)).collect(),
+            Value::Float(_) => vec![Key::Prim(PrimitiveType::F64), Key::Prim(PrimitiveType::F32)],
+            Value::Bool(_) => vec![Key::Prim(PrimitiveType::Bool)],
+            Value::Char(_) => vec![Key::Prim(PrimitiveType::Char)],
+            Value::Str(_) => vec![Key::Prim(PrimitiveType::Str)],
+            _ => Vec::new(),
+        };
+        keys.extend(self.lib_types.of(&loaded).into_iter().map(Key::Def));
+        Ok(keys)
+    }
+
+    /// Run function `def` with `args` bound to its parameters
+    pub(crate) fn call_fn(&mut self, def: DefId, args: Vec<Value>, span: Span) -> Eval<Value> {
+        let Some(&func) = self.fns.get(&def) else {
+            return unsupported(format!("`{}` has no body to run", self.res.def(def).name), span);
+        };
+        if self.frames.len() >= MAX_DEPTH {
+            return Err(RuntimeError::new(RuntimeErrorKind::StackOverflow, span).into());
+        }
+        let mut locals = HashMap::new();
+        self.bind_params(&mut locals, &func.params, args);
+        self.run_frame(locals, |interp| interp.block(&func.body), |interp| interp.fn_name(def), span)
+    }
+
+    fn call_closure(&mut self, node: NodeId, captures: &[Slot], args: Vec<Value>, span: Span) -> Eval<Value> {
+        let Some(&closure) = self.closures.get(&node) else {
+            return unsupported("closure has no body to run", span);
+        };
+        let ExpressionKind::Closure { params, body, .. } = &closure.kind else {
+            return unsupported("closure has no body to run", span);
+        };
+        if self.frames.len() >= MAX_DEPTH {
+            return Err(RuntimeError::new(RuntimeErrorKind::StackOverflow, span).into());
+        }
+        let res = self.res;
+        let captured = res.captures.get(&node).into_iter().flatten();
+        let mut locals: HashMap<DefId, Slot> = captured.copied().zip(captures.iter().cloned()).collect();
+        self.bind_params(&mut locals, params, args);
+        self.run_frame(locals, |interp| interp.expr(body), |_| "<closure>".to_string(), span)
+    }
+
+    fn bind_params(&self, locals: &mut HashMap<DefId, Slot>, params: &[Param], args: Vec<Value>) {
+        for (param, value) in params.iter().zip(args) {
+            if let Some(def) = self.res.def_of_node(param.id) {
+                locals.insert(def, slot(value));
+            }
+        }
+    }
+
+    /// Run `body` in a frame of its own; `name` labels the frame in backtraces
+    fn run_frame(
+        &mut self,
+        locals: HashMap<DefId, Slot>,
+        body: impl FnOnce(&mut Self) -> Eval<Value>,
+        name: impl FnOnce(&Self) -> String,
+        span: Span,
+    ) -> Eval<Value> {
+        self.frames.push(Frame { locals });
+        let result = match body(self) {
+            Ok(value) | Err(Unwind::Return(value)) => Ok(value),
+            Err(Unwind::Break(_) | Unwind::Continue) => Ok(Value::unit()),
+            Err(Unwind::Error(mut err)) => {
+                err.backtrace.push((name(self), span));
+                Err(Unwind::Error(err))
+            }
+            Err(exit) => Err(exit),
+        };
+        self.frames.pop();
+        result
+    }
+
+    /// `Type::method` for functions of impls and traits, the bare name otherwise
+    fn fn_name(&self, def: DefId) -> String {
+        let definition = self.res.def(def);
+        match definition.parent.map(|p| self.res.def(p)) {
+            Some(parent) if matches!(parent.kind, DefKind::Struct | DefKind::Enum | DefKind::Trait) => {
+                format!("{}::{}", parent.name, definition.name)
+            }
+            _ => definition.name.clone(),
+        }
+    }
+
+}
+
+impl<'a> Host for Interpreter<'a> {
+    fn variants(&self) -> Variants {
+        self.variants
+    }
+
+    fn engine(&self) -> &'static str {
+        "the interpreter"
+    }
+
+    fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    fn out(&mut self) -> &mut dyn Write {
+        self.out
+    }
+
+    fn err(&mut self) -> &mut dyn Write {
+        self.err
+    }
+
+    fn is_library(&self, def: DefId) -> bool {
+        self.res.def(def).is_prelude()
+    }
+
+    fn call(&mut self, callable: &Callable, args: Vec<Value>, site: &Site) -> Eval<Value> {
+        self.call_value(callable, args, site)
+    }
+
+    fn to_string_fn(&self, value: &Value, span: Span) -> Eval<Option<DefId>> {
+        let keys = self.keys_of(value, None, span)?;
+        Ok(self.find_method(&keys, None, "to_string"))
+    }
+}
+
+pub(crate) fn literal(lit: &Literal) -> Value {
+    match lit {
+        Literal::Int(n) => Value::Int(*n as i128),
+        Literal::Float(x) => Value::Float(*x),
+        Literal::String(s) => Value::Str(s.clone()),
+        Literal::Char(c) => Value::Char(*c),
+        Literal::Bool(b) => Value::Bool(*b),
+        Literal::Unit => Value::unit(),
+    }
+}
+
+/// Values a `for` loop runs over. Borrowed collections give references to their elements.
+pub(crate) fn items(value: Value, span: Span) -> Eval<Vec<Value>> {
+    let place = match value {
+        Value::Ref(_) | Value::Shared(_) => at(Place::temp(value).follow(), span)?,
+        Value::List(elems) | Value::Set(elems) => return Ok(elems),
+        Value::Map(entries) => return Ok(entries.into_iter().map(|(k, v)| Value::Tuple(vec![k, v])).collect()),
+        other => return unsupported(format!("cannot iterate over `{}`", other), span),
+    };
+    let items = place.with(|value| match value {
+        Value::List(elems) => Some((0..elems.len()).map(|i| Value::Ref(place.child(Step::Index(i)))).collect()),
+        Value::Set(elems) => Some(elems.clone()),
+        Value::Map(entries) => Some(
+            entries
+                .iter()
+                .map(|(k, _)| Value::Tuple(vec![k.clone(), Value::Ref(place.child(Step::Key(k.clone())))]))
+                .collect(),
+        ),
+        _ => None,
+    });
+    match at(items, span)? {
+        Some(items) => Ok(items),
+        None => unsupported(format!("cannot iterate over `{}`", at(place.get(), span)?), span),
+    }
+}
+
+/// The place a destructuring pattern looks into, past any references
+pub(crate) fn deref_pattern(place: &Place, by_ref: bool, span: Span) -> Eval<(Place, bool)> {
+    let mut place = place.clone();
+    let mut by_ref = by_ref;
+    while let Some(target) = at(place.referent(), span)? {
+        place = target;
+        by_ref = true;
+    }
+    Ok((place, by_ref))
+}
+
+// ========== Operators ==========
+
+/// `-value` or `!value`, with `ty` the type of the result
+pub(crate) fn unary_op(op: &UnaryOp, value: Value, ty: Option<PrimitiveType>, span: Span) -> Eval<Value> {
+    match (op, value) {
+        (UnaryOp::Neg, Value::Int(n)) => int(n.checked_neg(), ty, "negate", span),
+        (UnaryOp::Neg, Value::Float(x)) => Ok(Value::Float(-x)),
+        (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+        (UnaryOp::Not, Value::Int(n)) => match ty.and_then(int_range) {
+            Some((0, max)) => Ok(Value::Int(max - n)),
+            _ => Ok(Value::Int(!n)),
+        },
+        (op, value) => unsupported(format!("cannot apply `{:?}` to `{}`", op, value), span),
+    }
+}
+
+impl dyn Host + '_ {
+    /// An arithmetic, bitwise or comparison operator on loaded values, with `ty` the type of the result
+    pub(crate) fn binary_op(
+        &mut self,
+        op: &BinaryOp,
+        lhs: Value,
+        rhs: Value,
+        ty: Option<PrimitiveType>,
+        span: Span,
+    ) -> Eval<Value> {
+        use BinaryOp::*;
+        match (op, lhs, rhs) {
+            (Eq, l, r) => Ok(Value::Bool(l == r)),
+            (Ne, l, r) => Ok(Value::Bool(l != r)),
+            (Add, Value::Str(l), r) => {
+                let text = self.display(&r, span)?;
+                Ok(Value::Str(l + &text))
+            }
+            (_, Value::Int(a), Value::Int(b)) => int_binary(op, a, b, ty, span),
+            (_, Value::Float(a), Value::Float(b)) => Ok(match op {
+                Add => Value::Float(a + b),
+                Sub => Value::Float(a - b),
+                Mul => Value::Float(a * b),
+                Div => Value::Float(a / b),
+                Mod => Value::Float(a % b),
+                Lt => Value::Bool(a < b),
+                Gt => Value::Bool(a > b),
+                Le => Value::Bool(a <= b),
+                Ge => Value::Bool(a >= b),
+                _ => return unsupported(format!("cannot apply `{:?}` to floats", op), span),
+            }),
+            (BitAnd, Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a & b)),
+            (BitOr, Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a | b)),
+            (BitXor, Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a ^ b)),
+            (Lt | Gt | Le | Ge, l, r) => {
+                let Some(ordering) = l.compare(&r) else {
+                    return unsupported(format!("cannot compare `{}` with `{}`", l, r), span);
+                };
+                Ok(Value::Bool(match op {
+                    Lt => ordering.is_lt(),
+                    Gt => ordering.is_gt(),
+                    Le => ordering.is_le(),
+                    _ => ordering.is_ge(),
+                }))
+            }
+            (op, l, r) => unsupported(format!("cannot apply `{:?}` to `{}` and `{}`", op, l, r), span),
+        }
+    }
+}
+
+fn int_binary(op: &BinaryOp, a: i128, b: i128, ty: Option<PrimitiveType>, span: Span) -> Eval<Value> {
+    use BinaryOp::*;
+    match op {
+        Add => int(a.checked_add(b), ty, "add", span),
+        Sub => int(a.checked_sub(b), ty, "subtract", span),
+        Mul => int(a.checked_mul(b), ty, "multiply", span),
+        Div if b == 0 => panic("attempt to divide by zero", span),
+        Div => int(a.checked_div(b), ty, "divide", span),
+        Mod if b == 0 => panic("attempt to calculate the remainder with a divisor of zero", span),
+        Mod => int(a.checked_rem(b), ty, "calculate the remainder", span),
+        BitAnd => Ok(Value::Int(a & b)),
+        BitOr => Ok(Value::Int(a | b)),
+        BitXor => Ok(Value::Int(a ^ b)),
+        Shl | Shr => {
+            let range = ty.and_then(int_range);
+            let bits = match range {
+                Some((lo, hi)) => 128 - if lo < 0 { hi.leading_zeros() - 1 } else { hi.leading_zeros() },
+                None => 128,
+            };
+            let Some(shift) = u32::try_from(b).ok().filter(|s| *s < bits) else {
+                let verb = if *op == Shl { "shift left" } else { "shift right" };
+                return panic(format!("attempt to {} with overflow", verb), span);
+            };
+            if *op == Shr {
+                return Ok(Value::Int(a >> shift));
+            }
+            let shifted = a.wrapping_shl(shift);
+            Ok(Value::Int(match range {
+                Some((0, max)) => shifted & max,
+                Some(_) => (shifted << (128 - bits)) >> (128 - bits),
+                None => shifted,
+            }))
+        }
+        Lt => Ok(Value::Bool(a < b)),
+        Gt => Ok(Value::Bool(a > b)),
+        Le => Ok(Value::Bool(a <= b)),
+        Ge => Ok(Value::Bool(a >= b)),
+        _ => unsupported(format!("cannot apply `{:?}` to integers", op), span),
+    }
+}
+
+/// An arithmetic result, which must fit the integer type `ty`
+pub(crate) fn int(result: Option<i128>, ty: Option<PrimitiveType>, verb: &str, span: Span) -> Eval<Value> {
+    match (result, ty.and_then(int_range)) {
+        (Some(n), Some((lo, hi))) if n < lo || n > hi => panic(format!("attempt to {} with overflow", verb), span),
+        (Some(n), _) => Ok(Value::Int(n)),
+        (None, _) => panic(format!("attempt to {} with overflow", verb), span),
+    }
+}
@@ -1 +1,558 @@
-// runtime stub - implementation pending
+// Runtime
+// A reference interpreter that runs checked Solo programs from `main`, and a
+// virtual machine for their bytecode, with the prelude and the parts of the
+// library programs use most
+
+mod builtins;
+mod interp;
+mod value;
+mod vm;
+
+pub use interp::{Interpreter, MAX_DEPTH};
+pub use value::{Callable, Place, Step, Value};
+pub use vm::Vm;
+
+use my_lang_ast::{Program, Span};
+use my_lang_codegen::bytecode::Module;
+use my_lang_resolve::Resolution;
+use my_lang_typechecker::TypeckResults;
+use std::io::Write;
+use thiserror::Error;
+
+/// Stack for the interpreter's and the machine's thread, deep enough for `MAX_DEPTH` nested calls
+const STACK_SIZE: usize = 1 << 30;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum RuntimeErrorKind {
+    #[error("{0}")]
+    Panic(String),
+
+    #[error("stack overflow")]
+    StackOverflow,
+
+    /// Something the interpreter cannot run, such as a library function it does not provide
+    #[error("{0}")]
+    Unsupported(String),
+}
+
+/// A run stopped at `span`, with the calls that led to it, innermost first
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub span: Span,
+    /// Values involved, such as the arguments of a violated contract
+    pub notes: Vec<String>,
+    /// Function called and the call site
+    pub backtrace: Vec<(String, Span)>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: RuntimeErrorKind, span: Span) -> Self {
+        Self { kind, span, notes: Vec::new(), backtrace: Vec::new() }
+    }
+
+    /// Status the process exits with, as a Rust program stopping the same way would
+    pub fn exit_code(&self) -> i32 {
+        match self.kind {
+            RuntimeErrorKind::Panic(_) => 101,
+            RuntimeErrorKind::StackOverflow => 134,
+            RuntimeErrorKind::Unsupported(_) => 1,
+        }
+    }
+}
+
+/// Run the crate's `main`, which is in the first of `programs`, returning its exit status.
+/// `args` is the command line, program first.
+pub fn run(
+    programs: &[&Program],
+    res: &Resolution,
+    types: &TypeckResults,
+    args: &[String],
+    out: &mut (dyn Write + Send),
+    err: &mut (dyn Write + Send),
+) -> Result<i32, RuntimeError> {
+    let Some(entry) = programs.first() else {
+        let kind = RuntimeErrorKind::Unsupported("`main` function not found".to_string());
+        return Err(RuntimeError::new(kind, Span::dummy()));
+    };
+    on_big_stack(|| Interpreter::new(programs, res, types, args.to_vec(), out, err).run_main(entry))
+}
+
+/// Run the `main` of a compiled module on the virtual machine, returning its exit status
+pub fn run_bytecode(
+    module: &Module,
+    args: &[String],
+    out: &mut (dyn Write + Send),
+    err: &mut (dyn Write + Send),
+) -> Result<i32, RuntimeError> {
+    on_big_stack(|| Vm::new(module, args.to_vec(), out, err).run_main())
+}
+
+/// Run `f` on a thread with room for `MAX_DEPTH` nested calls
+fn on_big_stack<F>(f: F) -> Result<i32, RuntimeError>
+where
+    F: FnOnce() -> Result<i32, RuntimeError> + Send,
+{
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new().stack_size(STACK_SIZE).spawn_scoped(scope, f);
+        match thread.map(|thread| thread.join()) {
+            Ok(Ok(result)) => result,
+            Ok(Err(payload)) => std::panic::resume_unwind(payload),
+            Err(spawn) => {
+                let kind = RuntimeErrorKind::Unsupported(format!("failed to start the interpreter: {}", spawn));
+                Err(RuntimeError::new(kind, Span::dummy()))
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use my_lang_codegen::bytecode::{decode, encode};
+    use my_lang_codegen::{compile, ContractLowering, ContractMode};
+    use my_lang_parser::Parser;
+    use my_lang_resolve::Resolver;
+
+    /// Exit status, stdout and stderr of running `source`, or the error it stopped with
+    fn run_source(source: &str, args: &[&str]) -> (Result<i32, RuntimeError>, String, String) {
+        run_with_contracts(source, args, ContractMode::Off)
+    }
+
+    fn run_with_contracts(
+        source: &str,
+        args: &[&str],
+        contracts: ContractMode,
+    ) -> (Result<i32, RuntimeError>, String, String) {
+        let (program, res, types) = check_source(source, contracts);
+        let args: Vec<String> = std::iter::once("prog").chain(args.iter().copied()).map(String::from).collect();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let result = run(&[&program], &res, &types, &args, &mut out, &mut err);
+        let interpreted = (result, String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap());
+
+        // The machine runs the program from its file and must behave the same
+        let module = decode(&encode(&compile(&[&program], &res, &types))).unwrap();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let result = run_bytecode(&module, &args, &mut out, &mut err);
+        let compiled = (result, String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap());
+        assert_eq!(outcome(&compiled), outcome(&interpreted), "{}", module);
+        interpreted
+    }
+
+    fn check_source(source: &str, contracts: ContractMode) -> (Program, Resolution, TypeckResults) {
+        let mut parser = Parser::new(source);
+        let (mut program, errors) = parser.parse_program();
+        assert!(errors.is_empty(), "{:?}", errors);
+        ContractLowering::new(source, contracts).with_first_node_id(parser.next_node_id()).lower(&mut program);
+        let mut resolver = Resolver::new();
+        resolver.add_module(&[], &program);
+        let (res, errors) = resolver.finish();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let (types, errors) = my_lang_typechecker::check_crate(&[&program], &res);
+        assert!(errors.is_empty(), "{:?}", errors);
+        (program, res, types)
+    }
+
+    /// What a run did, without the spans, which the bytecode keeps only lines and columns of
+    fn outcome(run: &(Result<i32, RuntimeError>, String, String)) -> impl PartialEq + std::fmt::Debug + '_ {
+        let (result, out, err) = run;
+        let result = result.as_ref().map_err(|e| {
+            let names: Vec<&str> = e.backtrace.iter().map(|(name, _)| name.as_str()).collect();
+            (&e.kind, &e.notes, names, e.span.line)
+        });
+        (result, out, err)
+    }
+
+    fn output(source: &str) -> String {
+        let (result, out, err) = run_source(source, &[]);
+        assert_eq!(result, Ok(0), "stderr: {}", err);
+        out
+    }
+
+    #[test]
+    fn test_prints_and_formats() {
+        let out = output(
+            r#"
+            fn main() {
+                let name = "Solo";
+                println("Hello, {}!", name);
+                print("{:>5}|{:<4}|", 42, "ab");
+                println("{:.2} {:?} {{}}", 3.14159, "quoted");
+                println(format("{} + {} = {}", 1, 2, 1 + 2));
+            }
+            "#,
+        );
+        assert_eq!(out, "Hello, Solo!\n   42|ab  |3.14 \"quoted\" {}\n1 + 2 = 3\n");
+    }
+
+    #[test]
+    fn test_structs_enums_and_match() {
+        let out = output(
+            r#"
+            struct Point { x: i32, y: i32 }
+
+            enum Shape {
+                Circle(f64),
+                Rect { w: f64, h: f64 },
+                Empty,
+            }
+
+            fn area(shape: &Shape) -> f64 {
+                match shape {
+                    Shape::Circle(r) => 3.0 * *r * *r,
+                    Shape::Rect { w, h } => *w * *h,
+                    Shape::Empty => 0.0,
+                }
+            }
+
+            fn main() {
+                let p = Point { x: 3, y: 4 };
+                let Point { x, y } = p;
+                println("{}", x * x + y * y);
+                let shapes: Vec<Shape> = Vec::from([Shape::Circle(1.0), Shape::Rect { w: 2.0, h: 5.0 }, Shape::Empty]);
+                for shape in shapes.iter() {
+                    println("{}", area(shape));
+                }
+                let pair = (1, "one");
+                match pair {
+                    (0, _) => println("zero"),
+                    (n, word) => println("{} is {}", n, word),
+                }
+            }
+            "#,
+        );
+        assert_eq!(out, "25\n3\n10\n0\n1 is one\n");
+    }
+
+    #[test]
+    fn test_trait_methods_dispatch_on_the_value() {
+        let out = output(
+            r#"
+            trait Animal {
+                fn name(&self) -> String;
+                fn greet(&self) -> String {
+                    format("I am {}", self.name())
+                }
+            }
+
+            struct Dog {}
+            struct Cat {}
+
+            impl Animal for Dog {
+                fn name(&self) -> String { "Dog".to_string() }
+            }
+
+            impl Animal for Cat {
+                fn name(&self) -> String { "Cat".to_string() }
+                fn greet(&self) -> String { "Meow".to_string() }
+            }
+
+            fn introduce<T: Animal>(animal: &T) {
+                println(animal.greet());
+            }
+
+            fn main() {
+                introduce(&Dog {});
+                introduce(&Cat {});
+            }
+            "#,
+        );
+        assert_eq!(out, "I am Dog\nMeow\n");
+    }
+
+    #[test]
+    fn test_mutable_references_write_through() {
+        let out = output(
+            r#"
+            struct Counter { count: i32 }
+
+            impl Counter {
+                fn bump(&mut self) {
+                    self.count = self.count + 1;
+                }
+            }
+
+            fn double(x: &mut i32) {
+                *x = *x * 2;
+            }
+
+            fn main() {
+                let mut c = Counter { count: 0 };
+                c.bump();
+                c.bump();
+                let mut n = 21;
+                double(&mut n);
+                let mut v: Vec<i32> = Vec::from([1, 2, 3]);
+                v[1] = 20;
+                for x in v.iter_mut() {
+                    *x = *x + 1;
+                }
+                println("{} {} {:?}", c.count, n, v);
+            }
+            "#,
+        );
+        assert_eq!(out, "2 42 [2, 21, 4]\n");
+    }
+
+    #[test]
+    fn test_collections_and_options() {
+        let out = output(
+            r#"
+            fn square(x: i32) -> i32 { x * x }
+
+            fn is_even(x: &i32) -> bool { *x % 2 == 0 }
+
+            fn main() {
+                let mut counts: HashMap<String, i32> = HashMap::new();
+                let text = "a b a c a b";
+                let words: Vec<String> = text.split_whitespace();
+                for word in words {
+                    let n = match counts.get(&word) {
+                        Some(n) => *n,
+                        None => 0,
+                    };
+                    counts.insert(word, n + 1);
+                }
+                println("{:?}", counts.get("a"));
+                println("{}", counts.len());
+                let mut v: Vec<i32> = Vec::from([4, 1, 3, 2]);
+                v.sort();
+                v.retain(is_even);
+                v.push(square(3));
+                let mut total = 0;
+                for x in &v {
+                    total = total + *x;
+                }
+                println("{:?} {}", v, total);
+                let mut stack: Vec<i32> = Vec::new();
+                stack.push(1);
+                let last: Option<i32> = stack.pop();
+                let empty: Option<i32> = stack.pop();
+                println("{:?} {:?}", last, empty);
+                let parsed: Result<i32, String> = "12x".parse();
+                println("{}", parsed.is_err());
+            }
+            "#,
+        );
+        assert_eq!(out, "Some(3)\n3\n[2, 4, 9] 15\nSome(1) None\ntrue\n");
+    }
+
+    #[test]
+    fn test_function_values_constants_and_loops() {
+        let out = output(
+            r#"
+            const LIMIT: i32 = 4;
+
+            fn inc(x: i32) -> i32 { x + 1 }
+
+            fn main() {
+                let add = inc;
+                println("{}", add(add(5)));
+                let mut i = 0;
+                let found = loop {
+                    i = i + 1;
+                    if i * i > LIMIT { break i; }
+                };
+                let mut odd = 0;
+                while odd < 10 {
+                    odd = odd + 1;
+                    if odd % 2 == 0 { continue; }
+                    print("{} ", odd);
+                }
+                let shared: RefCell<i32> = RefCell::new(1);
+                *shared.borrow_mut() = 7;
+                println("| {} {}", found, shared.borrow());
+                let word: Option<String> = if found == 3 { Some("three".to_string()) } else { None };
+                match word {
+                    Some(w) => println(w),
+                    None => {}
+                }
+            }
+            "#,
+        );
+        assert_eq!(out, "7\n1 3 5 7 9 | 3 7\nthree\n");
+    }
+
+    #[test]
+    fn test_closures_capture_locals() {
+        let source = r#"
+            fn apply(f: fn(i32) -> i32, value: i32) -> i32 { f(value) }
+
+            fn make_adder(n: i32) -> fn(i32) -> i32 {
+                move |x| x + n
+            }
+
+            fn main() {
+                let multiply = |x: i32, y: i32| -> i32 { x * y };
+                let factor = 10;
+                let scale = |x| x * factor;
+                let mut count = 0;
+                let mut increment = || {
+                    count = count + 1;
+                    count
+                };
+                increment();
+                increment();
+                let mut snapshot = 1;
+                let frozen = move || snapshot;
+                snapshot = 2;
+                println("{} {} {} {}", multiply(3, 4), scale(5), count, frozen());
+                let add_5 = make_adder(5);
+                println("{} {}", apply(|x| x * 2, 21), apply(add_5, 10));
+            }
+            "#;
+        // Shared captures see later writes, `move` ones keep the value they took
+        assert_eq!(output(source), "12 50 2 1\n42 15\n");
+    }
+
+    #[test]
+    fn test_closures_appear_in_backtraces() {
+        let (result, _, _) = run_source(
+            r#"
+            fn main() {
+                let items = [1, 2];
+                let at = |i: i32| items[i];
+                at(5);
+            }
+            "#,
+            &[],
+        );
+        let err = result.unwrap_err();
+        let names: Vec<&str> = err.backtrace.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["<closure>", "main"]);
+    }
+
+    #[test]
+    fn test_args_are_passed_to_main() {
+        let (result, out, _) = run_source(
+            r#"
+            fn main(args: Vec<String>) {
+                println("{}", args.len());
+                println("{}", args[1]);
+            }
+            "#,
+            &["first", "second"],
+        );
+        assert_eq!(result, Ok(0));
+        assert_eq!(out, "3\nfirst\n");
+    }
+
+    #[test]
+    fn test_files_are_written_and_read() {
+        let dir = std::env::temp_dir().join(format!("my-lang-fs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (saved, missing) = (dir.join("out.txt"), dir.join("missing.txt"));
+        let (result, out, _) = run_source(
+            r#"
+            import std::fs;
+
+            fn main(args: Vec<String>) {
+                match fs::write(args[1], "saved") {
+                    Ok(_) => println("wrote"),
+                    Err(e) => println("{}", e),
+                }
+                match fs::read_to_string(args[1]) {
+                    Ok(text) => println("read {}", text),
+                    Err(e) => println("{}", e),
+                }
+                match fs::read_to_string(args[2]) {
+                    Ok(text) => println("read {}", text),
+                    Err(e) => println("{}", e),
+                }
+            }
+            "#,
+            &[saved.to_str().unwrap(), missing.to_str().unwrap()],
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, Ok(0));
+        assert_eq!(out, "wrote\nread saved\nNo such file or directory (os error 2)\n");
+    }
+
+    #[test]
+    fn test_panics_carry_a_backtrace() {
+        let (result, out, _) = run_source(
+            r#"
+            fn get(v: &Vec<i32>, i: usize) -> i32 {
+                v[i]
+            }
+
+            fn main() {
+                println("before");
+                let v: Vec<i32> = Vec::from([1, 2, 3]);
+                get(&v, 7);
+            }
+            "#,
+            &[],
+        );
+        assert_eq!(out, "before\n");
+        let err = result.unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Panic("index out of bounds: the len is 3 but the index is 7".into()));
+        assert_eq!(err.exit_code(), 101);
+        let names: Vec<&str> = err.backtrace.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["get", "main"]);
+    }
+
+    #[test]
+    fn test_overflow_and_recursion_limits() {
+        let (result, _, _) = run_source(
+            r#"
+            fn main() {
+                let x: u8 = 200;
+                let y = x + 100;
+            }
+            "#,
+            &[],
+        );
+        assert_eq!(result.unwrap_err().kind, RuntimeErrorKind::Panic("attempt to add with overflow".into()));
+
+        let (result, _, _) = run_source(
+            r#"
+            fn forever(n: i64) -> i64 { forever(n + 1) }
+            fn main() { forever(0); }
+            "#,
+            &[],
+        );
+        assert_eq!(result.unwrap_err().kind, RuntimeErrorKind::StackOverflow);
+    }
+
+    #[test]
+    fn test_exit_status_from_process_exit_and_err() {
+        let (result, _, _) = run_source("import std::process; fn main() { process::exit(3); }", &[]);
+        assert_eq!(result, Ok(3));
+
+        let (result, _, err) = run_source(
+            r#"
+            fn main() -> Result<(), String> {
+                Err("bad input".to_string())
+            }
+            "#,
+            &[],
+        );
+        assert_eq!(result, Ok(1));
+        assert_eq!(err, "Error: \"bad input\"\n");
+    }
+
+    #[test]
+    fn test_contract_violations_report_the_clause() {
+        let source = r#"
+            fn withdraw(balance: i64, amount: i64) -> i64
+                pre amount <= balance
+            {
+                balance - amount
+            }
+
+            fn main() {
+                println("{}", withdraw(10, 3));
+                withdraw(5, 8);
+            }
+        "#;
+        let (result, out, _) = run_with_contracts(source, &[], ContractMode::All);
+        assert_eq!(out, "7\n");
+        let err = result.unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Panic("precondition `amount <= balance` of `withdraw` violated".into()));
+        assert_eq!(err.notes, ["balance = 5", "amount = 8"]);
+
+        let (result, _, _) = run_with_contracts(source, &[], ContractMode::Off);
+        assert_eq!(result, Ok(0));
+    }
+}
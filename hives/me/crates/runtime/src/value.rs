@@ -0,0 +1,356 @@
+// Run-time values
+// What the interpreter computes with. Locals live in shared slots, so a
+// reference is a place inside one rather than a copy.
+
+use my_lang_resolve::DefId;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+/// Storage for a local, a temporary, or the contents of an `Rc` or `RefCell`
+pub type Slot = Rc<RefCell<Value>>;
+
+pub fn slot(value: Value) -> Slot {
+    Rc::new(RefCell::new(value))
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    /// Any integer type; the static type decides the range it must stay in
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Tuple(Vec<Value>),
+    /// Arrays, `Vec`s and `VecDeque`s, and iterators, which are evaluated eagerly
+    List(Vec<Value>),
+    /// `HashMap`s and `BTreeMap`s, sorted by key so that iteration order is deterministic
+    Map(Vec<(Value, Value)>),
+    /// `HashSet`s and `BTreeSet`s, sorted like maps
+    Set(Vec<Value>),
+    /// A struct or enum variant. Tuple-like ones have fields named `0`, `1`, ...
+    Adt {
+        def: DefId,
+        name: String,
+        fields: Vec<(String, Value)>,
+    },
+    /// `&place` or `&mut place`
+    Ref(Place),
+    /// An `Rc`, `Arc`, `Cell`, `RefCell`, `Mutex` or `RwLock`; clones share the contents
+    Shared(Slot),
+    /// A function, constructor or library function used as a value
+    Fn(Callable),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Callable {
+    /// A function of the crate or prelude, or a tuple struct or variant constructor
+    Def(DefId),
+    /// A library function named by its path, such as `String::from` or `println`
+    Library(String),
+    /// A closure: the code run when it is called, which the interpreter finds by
+    /// the closure expression's node and the VM by function index, and the slots
+    /// of the locals it captures, in capture order
+    Closure { code: u32, captures: Vec<Slot> },
+}
+
+/// A location values can be read from and written to: a slot and the
+/// fields, indices and keys leading into it
+#[derive(Debug, Clone)]
+pub struct Place {
+    pub slot: Slot,
+    pub path: Vec<Step>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// A named field, or a tuple element by position
+    Field(String),
+    Index(usize),
+    Key(Value),
+}
+
+impl Place {
+    pub fn new(slot: Slot) -> Self {
+        Self { slot, path: Vec::new() }
+    }
+
+    /// A place holding a value no local owns, such as a call result being borrowed
+    pub fn temp(value: Value) -> Self {
+        Self::new(slot(value))
+    }
+
+    pub fn child(&self, step: Step) -> Place {
+        let mut path = self.path.clone();
+        path.push(step);
+        Place { slot: self.slot.clone(), path }
+    }
+
+    pub fn with<R>(&self, f: impl FnOnce(&Value) -> R) -> Result<R, String> {
+        let root = self.slot.try_borrow().map_err(|_| "value is already mutably borrowed".to_string())?;
+        let mut value = &*root;
+        for step in &self.path {
+            value = project(value, step)?;
+        }
+        Ok(f(value))
+    }
+
+    pub fn with_mut<R>(&self, f: impl FnOnce(&mut Value) -> R) -> Result<R, String> {
+        let mut root = self.slot.try_borrow_mut().map_err(|_| "value is already borrowed".to_string())?;
+        let mut value = &mut *root;
+        for step in &self.path {
+            value = project_mut(value, step)?;
+        }
+        Ok(f(value))
+    }
+
+    pub fn get(&self) -> Result<Value, String> {
+        self.with(Value::clone)
+    }
+
+    pub fn set(&self, value: Value) -> Result<(), String> {
+        self.with_mut(|place| *place = value)
+    }
+
+    /// What the reference stored here points to, if a reference is stored here
+    pub fn referent(&self) -> Result<Option<Place>, String> {
+        self.with(|value| match value {
+            Value::Ref(target) => Some(target.clone()),
+            _ => None,
+        })
+    }
+
+    /// Where this place leads once references and shared cells stored in it are followed
+    pub fn follow(&self) -> Result<Place, String> {
+        let mut place = self.clone();
+        loop {
+            let next = place.with(|value| match value {
+                Value::Ref(target) => Some(target.clone()),
+                Value::Shared(slot) => Some(Place::new(slot.clone())),
+                _ => None,
+            })?;
+            match next {
+                Some(next) => place = next,
+                None => return Ok(place),
+            }
+        }
+    }
+}
+
+fn project<'v>(value: &'v Value, step: &Step) -> Result<&'v Value, String> {
+    match (value, step) {
+        (Value::Adt { fields, .. }, Step::Field(name)) => {
+            fields.iter().find(|(field, _)| field == name).map(|(_, v)| v).ok_or_else(|| no_field(name))
+        }
+        (Value::Tuple(elems), Step::Field(name)) => {
+            name.parse::<usize>().ok().and_then(|i| elems.get(i)).ok_or_else(|| no_field(name))
+        }
+        (Value::List(elems), Step::Index(i)) => elems.get(*i).ok_or_else(|| out_of_bounds(elems.len(), *i)),
+        (Value::Map(entries), Step::Key(key)) => {
+            entries.iter().find(|(k, _)| k == key).map(|(_, v)| v).ok_or_else(|| format!("key {} not found", key))
+        }
+        (value, _) => Err(format!("cannot index into `{}`", value)),
+    }
+}
+
+fn project_mut<'v>(value: &'v mut Value, step: &Step) -> Result<&'v mut Value, String> {
+    match (value, step) {
+        (Value::Adt { fields, .. }, Step::Field(name)) => {
+            fields.iter_mut().find(|(field, _)| field == name).map(|(_, v)| v).ok_or_else(|| no_field(name))
+        }
+        (Value::Tuple(elems), Step::Field(name)) => {
+            name.parse::<usize>().ok().and_then(|i| elems.get_mut(i)).ok_or_else(|| no_field(name))
+        }
+        (Value::List(elems), Step::Index(i)) => {
+            let len = elems.len();
+            elems.get_mut(*i).ok_or_else(|| out_of_bounds(len, *i))
+        }
+        (Value::Map(entries), Step::Key(key)) => {
+            entries.iter_mut().find(|(k, _)| k == key).map(|(_, v)| v).ok_or_else(|| format!("key {} not found", key))
+        }
+        (value, _) => Err(format!("cannot index into `{}`", value)),
+    }
+}
+
+fn no_field(name: &str) -> String {
+    format!("no field `{}`", name)
+}
+
+pub fn out_of_bounds(len: usize, index: usize) -> String {
+    format!("index out of bounds: the len is {} but the index is {}", len, index)
+}
+
+impl Value {
+    pub fn unit() -> Value {
+        Value::Tuple(Vec::new())
+    }
+
+    /// The value, or what it refers to through references and shared cells
+    pub fn load(&self) -> Result<Value, String> {
+        match self {
+            Value::Ref(place) => place.get()?.load(),
+            Value::Shared(slot) => {
+                let inner = slot.try_borrow().map_err(|_| "value is already mutably borrowed".to_string())?;
+                inner.load()
+            }
+            other => Ok(other.clone()),
+        }
+    }
+
+    /// The value, or what it refers to through references; shared cells stay shared
+    pub fn unref(&self) -> Result<Value, String> {
+        match self {
+            Value::Ref(place) => place.get()?.unref(),
+            other => Ok(other.clone()),
+        }
+    }
+
+    /// The value as `{}` shows it: strings and characters without quotes
+    pub fn to_text(&self) -> String {
+        match self {
+            Value::Str(s) => s.clone(),
+            Value::Char(c) => c.to_string(),
+            Value::Float(x) => x.to_string(),
+            Value::Ref(_) | Value::Shared(_) => match self.load() {
+                Ok(value) => value.to_text(),
+                Err(_) => "<borrowed>".to_string(),
+            },
+            other => other.to_string(),
+        }
+    }
+
+    /// Order of comparable values: numbers, text, and sequences of them
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Ref(_) | Value::Shared(_), _) | (_, Value::Ref(_) | Value::Shared(_)) => {
+                self.load().ok()?.compare(&other.load().ok()?)
+            }
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+            (Value::Char(a), Value::Char(b)) => Some(a.cmp(b)),
+            (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+            (Value::Tuple(a), Value::Tuple(b)) | (Value::List(a), Value::List(b)) => {
+                for (x, y) in a.iter().zip(b) {
+                    match x.compare(y)? {
+                        Ordering::Equal => {}
+                        ordering => return Some(ordering),
+                    }
+                }
+                Some(a.len().cmp(&b.len()))
+            }
+            (Value::Adt { def: a, fields: fa, .. }, Value::Adt { def: b, fields: fb, .. }) if a == b => {
+                let (fa, fb): (Vec<Value>, Vec<Value>) =
+                    (fa.iter().map(|f| f.1.clone()).collect(), fb.iter().map(|f| f.1.clone()).collect());
+                Value::Tuple(fa).compare(&Value::Tuple(fb))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Insert into sorted map entries, returning the value replaced
+pub fn map_insert(entries: &mut Vec<(Value, Value)>, key: Value, value: Value) -> Option<Value> {
+    if let Some(entry) = entries.iter_mut().find(|(k, _)| *k == key) {
+        return Some(std::mem::replace(&mut entry.1, value));
+    }
+    let at = entries.iter().position(|(k, _)| k.compare(&key) == Some(Ordering::Greater)).unwrap_or(entries.len());
+    entries.insert(at, (key, value));
+    None
+}
+
+/// Insert into a sorted set, returning whether the value was new
+pub fn set_insert(elems: &mut Vec<Value>, value: Value) -> bool {
+    if elems.contains(&value) {
+        return false;
+    }
+    let at = elems.iter().position(|e| e.compare(&value) == Some(Ordering::Greater)).unwrap_or(elems.len());
+    elems.insert(at, value);
+    true
+}
+
+/// Values are equal when what they refer to is: references compare their targets
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Ref(_) | Value::Shared(_), _) | (_, Value::Ref(_) | Value::Shared(_)) => {
+                matches!((self.load(), other.load()), (Ok(a), Ok(b)) if a == b)
+            }
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Char(a), Value::Char(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Tuple(a), Value::Tuple(b)) | (Value::List(a), Value::List(b)) | (Value::Set(a), Value::Set(b)) => {
+                a == b
+            }
+            (Value::Map(a), Value::Map(b)) => a == b,
+            (Value::Adt { def: a, fields: fa, .. }, Value::Adt { def: b, fields: fb, .. }) => a == b && fa == fb,
+            (Value::Fn(a), Value::Fn(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// The value as `{:?}` shows it
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn list<'v>(
+            f: &mut fmt::Formatter,
+            open: &str,
+            elems: impl Iterator<Item = &'v Value>,
+            close: &str,
+        ) -> fmt::Result {
+            f.write_str(open)?;
+            for (i, elem) in elems.enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}", elem)?;
+            }
+            f.write_str(close)
+        }
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Char(c) => write!(f, "{:?}", c),
+            Value::Str(s) => write!(f, "{:?}", s),
+            Value::Tuple(elems) if elems.len() == 1 => write!(f, "({},)", elems[0]),
+            Value::Tuple(elems) => list(f, "(", elems.iter(), ")"),
+            Value::List(elems) => list(f, "[", elems.iter(), "]"),
+            Value::Set(elems) => list(f, "{", elems.iter(), "}"),
+            Value::Map(entries) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                f.write_str("}")
+            }
+            Value::Adt { name, fields, .. } if fields.is_empty() => f.write_str(name),
+            Value::Adt { name, fields, .. } if fields[0].0 == "0" => {
+                list(f, &format!("{}(", name), fields.iter().map(|(_, v)| v), ")")
+            }
+            Value::Adt { name, fields, .. } => {
+                write!(f, "{} {{ ", name)?;
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", field, value)?;
+                }
+                f.write_str(" }")
+            }
+            Value::Ref(_) | Value::Shared(_) => match self.load() {
+                Ok(value) => write!(f, "{}", value),
+                Err(_) => f.write_str("<borrowed>"),
+            },
+            Value::Fn(_) => f.write_str("<function>"),
+        }
+    }
+}
@@ -0,0 +1,514 @@
+// Virtual machine
+// Runs compiled bytecode with a dispatch loop over register frames. Values,
+// the prelude and the library are the interpreter's, so both run programs alike.
+
+use crate::builtins::{is_cell_method, Host, LibraryTypes, Site, Variants};
+use crate::interp::{at, deref_pattern, items, unary_op, unsupported, Eval, Unwind, INTS, MAX_DEPTH};
+use crate::value::{slot, Callable, Place, Slot, Step, Value};
+use crate::{RuntimeError, RuntimeErrorKind};
+use my_lang_ast::{FileId, PrimitiveType, Span};
+use my_lang_codegen::bytecode::{Const, Function, ImplMethod, Instr, Loc, Module, Pattern, Reg, Type, TypeKey};
+use my_lang_resolve::DefId;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+pub struct Vm<'m> {
+    module: &'m Module,
+    /// Functions by definition, as indices into `module.functions`
+    fns: HashMap<u32, u32>,
+    /// Structs and variants by definition, with the enum of a variant
+    ctors: HashMap<u32, (&'m str, Option<u32>)>,
+    /// Functions declared by traits, with their trait and name
+    trait_fns: HashMap<u32, (u32, &'m str)>,
+    methods: HashMap<TypeKey, Vec<&'m ImplMethod>>,
+    lib_types: LibraryTypes<u32>,
+    lib_defs: HashSet<u32>,
+    /// Values of constant items computed so far
+    globals: Vec<Option<Value>>,
+    variants: Variants,
+    depth: usize,
+    args: Vec<String>,
+    out: &'m mut dyn Write,
+    err: &'m mut dyn Write,
+}
+
+impl<'m> Vm<'m> {
+    pub fn new(module: &'m Module, args: Vec<String>, out: &'m mut dyn Write, err: &'m mut dyn Write) -> Self {
+        let mut methods: HashMap<TypeKey, Vec<&ImplMethod>> = HashMap::new();
+        for method in &module.methods {
+            methods.entry(method.key).or_default().push(method);
+        }
+        let [some, none, ok, err_def] = module.variants.map(DefId);
+        Self {
+            module,
+            fns: (module.functions.iter().enumerate())
+                .filter(|(_, f)| f.captures.is_none())
+                .map(|(i, f)| (f.def, i as u32))
+                .collect(),
+            ctors: module.ctors.iter().map(|c| (c.def, (c.name.as_str(), c.parent))).collect(),
+            trait_fns: module.trait_fns.iter().map(|f| (f.def, (f.trait_def, f.name.as_str()))).collect(),
+            methods,
+            lib_types: LibraryTypes::new(module.library.iter().cloned()),
+            lib_defs: module.library.iter().map(|(_, def)| *def).collect(),
+            globals: vec![None; module.globals.len()],
+            variants: Variants { some, none, ok, err: err_def },
+            depth: 0,
+            args,
+            out,
+            err,
+        }
+    }
+
+    /// Run `main`, returning the process exit status
+    pub fn run_main(&mut self) -> Result<i32, RuntimeError> {
+        let Some(entry) = self.module.entry else {
+            let kind = RuntimeErrorKind::Unsupported("`main` function not found".to_string());
+            return Err(RuntimeError::new(kind, Span::dummy()));
+        };
+        let main = &self.module.functions[entry as usize];
+        // `main` may take the command line instead of calling `env::args`
+        let args = match main.params {
+            0 => Vec::new(),
+            _ => vec![Value::List(self.args.iter().cloned().map(Value::Str).collect())],
+        };
+        let result = self.call_fn(entry, args, span(main.loc));
+        let _ = self.out.flush();
+        match result {
+            Ok(value) => match value.load() {
+                Ok(Value::Adt { def, fields, .. }) if def == self.variants.err => {
+                    let error = fields.first().map(|(_, e)| e.to_string()).unwrap_or_default();
+                    let _ = writeln!(self.err, "Error: {}", error);
+                    Ok(1)
+                }
+                _ => Ok(0),
+            },
+            Err(Unwind::Exit(code)) => Ok(code),
+            Err(Unwind::Error(err)) => Err(err),
+            Err(_) => Ok(0),
+        }
+    }
+
+    fn host(&mut self) -> &mut (dyn Host + 'm) {
+        self
+    }
+
+    // ========== Calls ==========
+
+    /// Run `functions[index]`, adding it to the backtrace of an error
+    fn call_fn(&mut self, index: u32, args: Vec<Value>, span: Span) -> Eval<Value> {
+        self.call_closure(index, args, &[], span)
+    }
+
+    /// Run `functions[index]` with `captures` in the registers after its parameters
+    fn call_closure(&mut self, index: u32, args: Vec<Value>, captures: &[Slot], span: Span) -> Eval<Value> {
+        if self.depth >= MAX_DEPTH {
+            return Err(RuntimeError::new(RuntimeErrorKind::StackOverflow, span).into());
+        }
+        match self.invoke(index, args, captures) {
+            Err(Unwind::Error(mut err)) => {
+                err.backtrace.push((self.module.functions[index as usize].name.clone(), span));
+                Err(Unwind::Error(err))
+            }
+            result => result,
+        }
+    }
+
+    fn invoke(&mut self, index: u32, args: Vec<Value>, captures: &[Slot]) -> Eval<Value> {
+        let func = &self.module.functions[index as usize];
+        let mut regs: Vec<Slot> = (0..func.registers).map(|_| slot(Value::unit())).collect();
+        for (reg, value) in regs.iter_mut().zip(args).take(func.params as usize) {
+            *reg = slot(value);
+        }
+        let params = func.params as usize;
+        regs[params..params + captures.len()].clone_from_slice(captures);
+        self.depth += 1;
+        let result = self.execute(func, &mut regs);
+        self.depth -= 1;
+        result
+    }
+
+    /// Call function `def` of the crate
+    fn call_def(&mut self, def: u32, args: Vec<Value>, span: Span) -> Eval<Value> {
+        match self.fns.get(&def) {
+            Some(&index) => self.call_fn(index, args, span),
+            None => {
+                let name = self.trait_fns.get(&def).map_or("function", |(_, name)| name);
+                unsupported(format!("`{}` has no body to run", name), span)
+            }
+        }
+    }
+
+    fn call_value(&mut self, callable: &Callable, args: Vec<Value>, site: &Site) -> Eval<Value> {
+        let def = match callable {
+            Callable::Def(def) => def.0,
+            Callable::Library(path) => return self.host().library(path, args, site),
+            Callable::Closure { code, captures } => return self.call_closure(*code, args, captures, site.span),
+        };
+        if let Some((name, _)) = self.ctors.get(&def) {
+            let fields = args.into_iter().enumerate().map(|(i, v)| (i.to_string(), v)).collect();
+            return Ok(Value::Adt { def: DefId(def), name: name.to_string(), fields });
+        }
+        let keys = match args.first() {
+            Some(receiver) => self.keys_of(receiver, site.span)?,
+            None => Vec::new(),
+        };
+        let target = self.dispatch(def, &keys, site.span)?;
+        self.call_def(target, args, site.span)
+    }
+
+    fn method_call(&mut self, index: u32, recv: Value, mut args: Vec<Value>, span: Span) -> Eval<Value> {
+        let module = self.module;
+        let site = &module.sites[index as usize];
+        let site_ty = Site { span, ty: &module.types[site.ty as usize] };
+        let mut place = place_in(recv);
+        // Auto-dereference: methods apply to what references point to
+        while let Some(target) = at(place.referent(), span)? {
+            place = target;
+        }
+        let method = site.name.as_str();
+        let shared = at(place.with(|v| matches!(v, Value::Shared(_))), span)?;
+        if shared && site.resolved.is_none() && is_cell_method(method) {
+            return self.host().builtin_method(&site_ty, place, method, args);
+        }
+        if shared {
+            place = at(place.follow(), span)?;
+        }
+
+        let receiver = at(place.with(Value::clone), span)?;
+        let keys = match site.keys.is_empty() {
+            true => self.keys_of(&receiver, span)?,
+            false => site.keys.clone(),
+        };
+        let target = match site.resolved {
+            Some(def) => Some(self.dispatch(def, &keys, span)?),
+            // Receivers whose type only run time knows, such as elements of library collections
+            None => self.find_method(&keys, None, method),
+        };
+        let Some(def) = target else {
+            return self.host().builtin_method(&site_ty, place, method, args);
+        };
+        let by_ref = self.fns.get(&def).is_some_and(|&i| module.functions[i as usize].self_ref);
+        args.insert(0, if by_ref { Value::Ref(place) } else { receiver });
+        self.call_def(def, args, span)
+    }
+
+    /// The function a call to `def` runs: for a trait's function, the one the
+    /// receiver's impl defines, or else the trait's default
+    fn dispatch(&self, def: u32, keys: &[TypeKey], span: Span) -> Eval<u32> {
+        let Some(&(trait_def, name)) = self.trait_fns.get(&def) else {
+            return Ok(def);
+        };
+        if let Some(found) = self.find_method(keys, Some(trait_def), name) {
+            return Ok(found);
+        }
+        if self.fns.contains_key(&def) {
+            return Ok(def);
+        }
+        // A function without `self` on a type parameter: only one impl can be meant
+        let mut impls = self.module.methods.iter().filter(|m| m.trait_def == Some(trait_def) && m.name == name);
+        match (impls.next(), impls.next()) {
+            (Some(method), None) => Ok(method.def),
+            _ => unsupported(format!("cannot tell which implementation of `{}` to call", name), span),
+        }
+    }
+
+    fn find_method(&self, keys: &[TypeKey], trait_def: Option<u32>, name: &str) -> Option<u32> {
+        keys.iter().find_map(|key| {
+            let methods = self.methods.get(key)?;
+            methods.iter().find(|m| m.name == name && (trait_def.is_none() || m.trait_def == trait_def)).map(|m| m.def)
+        })
+    }
+
+    /// Impl keys that could apply to `value`, most specific first
+    fn keys_of(&self, value: &Value, span: Span) -> Eval<Vec<TypeKey>> {
+        let loaded = at(value.load(), span)?;
+        let mut keys = match &loaded {
+            Value::Adt { def, .. } => match self.ctors.get(&def.0) {
+                Some((_, Some(parent))) => vec![TypeKey::Def(*parent)],
+                _ => vec![TypeKey::Def(def.0)],
+            },
+            Value::Int(_) => INTS.iter().map(|p| TypeKey::Prim(*p)).collect(),
+            Value::Float(_) => vec![TypeKey::Prim(PrimitiveType::F64), TypeKey::Prim(PrimitiveType::F32)],
+            Value::Bool(_) => vec![TypeKey::Prim(PrimitiveType::Bool)],
+            Value::Char(_) => vec![TypeKey::Prim(PrimitiveType::Char)],
+            Value::Str(_) => vec![TypeKey::Prim(PrimitiveType::Str)],
+            _ => Vec::new(),
+        };
+        keys.extend(self.lib_types.of(&loaded).into_iter().map(TypeKey::Def));
+        Ok(keys)
+    }
+
+    /// Value of constant item `globals[index]`, computed on first use
+    fn global(&mut self, index: u32) -> Eval<Value> {
+        if let Some(value) = &self.globals[index as usize] {
+            return Ok(value.clone());
+        }
+        let value = self.invoke(self.module.globals[index as usize].init, Vec::new(), &[])?;
+        self.globals[index as usize] = Some(value.clone());
+        Ok(value)
+    }
+
+    // ========== Dispatch Loop ==========
+
+    fn execute(&mut self, func: &'m Function, regs: &mut [Slot]) -> Eval<Value> {
+        let module = self.module;
+        let get = |regs: &[Slot], r: Reg| regs[r as usize].borrow().clone();
+        let list = |regs: &[Slot], start: Reg, count: u32| -> Vec<Value> {
+            regs[start as usize..(start + count) as usize].iter().map(|r| r.borrow().clone()).collect()
+        };
+        let mut pc = 0;
+        while let Some(instr) = func.code.get(pc) {
+            let span = span(func.locs[pc]);
+            pc += 1;
+            let (dst, value) = match instr {
+                Instr::Const { dst, index } => (dst, constant(&module.consts[*index as usize])),
+                Instr::Move { dst, src } => (dst, get(regs, *src)),
+                Instr::Def { dst, def } => (dst, Value::Fn(Callable::Def(DefId(*def)))),
+                Instr::Library { dst, path } => (dst, Value::Fn(Callable::Library(text(module, *path)))),
+                Instr::Global { dst, index } => (dst, self.global(*index)?),
+                Instr::Tuple { dst, start, count } => (dst, Value::Tuple(list(regs, *start, *count))),
+                Instr::List { dst, start, count } => (dst, Value::List(list(regs, *start, *count))),
+                Instr::Adt { dst, ctor, start, count } => {
+                    let ctor = &module.ctors[*ctor as usize];
+                    let fields = ctor.fields.iter().cloned().zip(list(regs, *start, *count)).collect();
+                    (dst, Value::Adt { def: DefId(ctor.def), name: ctor.name.clone(), fields })
+                }
+                Instr::Unary { op, ty, dst, src } => {
+                    let value = at(get(regs, *src).load(), span)?;
+                    (dst, unary_op(op, value, *ty, span)?)
+                }
+                Instr::Binary { op, ty, dst, lhs, rhs } => {
+                    let lhs = at(get(regs, *lhs).load(), span)?;
+                    let rhs = at(get(regs, *rhs).load(), span)?;
+                    (dst, self.host().binary_op(op, lhs, rhs, *ty, span)?)
+                }
+                Instr::Jump { target } => {
+                    pc = *target as usize;
+                    continue;
+                }
+                Instr::JumpIf { cond, target } | Instr::JumpUnless { cond, target } => {
+                    let expected = matches!(instr, Instr::JumpIf { .. });
+                    match at(get(regs, *cond).load(), span)? {
+                        Value::Bool(b) if b == expected => pc = *target as usize,
+                        Value::Bool(_) => {}
+                        other => return unsupported(format!("expected a `bool`, found `{}`", other), span),
+                    }
+                    continue;
+                }
+                Instr::Call { dst, func, start, count } => {
+                    (dst, self.call_fn(*func, list(regs, *start, *count), span)?)
+                }
+                Instr::CallValue { dst, callee, start, count } => {
+                    let args = list(regs, *start, *count);
+                    let value = match at(get(regs, *callee).load(), span)? {
+                        Value::Fn(callable) => self.call_value(&callable, args, &Site { span, ty: &Type::Unknown })?,
+                        other => return unsupported(format!("`{}` is not a function", other), span),
+                    };
+                    (dst, value)
+                }
+                Instr::CallLibrary { dst, path, ty, start, count } => {
+                    let site = Site { span, ty: &module.types[*ty as usize] };
+                    let path = text(module, *path);
+                    (dst, self.host().library(&path, list(regs, *start, *count), &site)?)
+                }
+                Instr::Method { dst, site, recv, start, count } => {
+                    let args = list(regs, *start, *count);
+                    (dst, self.method_call(*site, get(regs, *recv), args, span)?)
+                }
+                Instr::Return { src } => return Ok(get(regs, *src)),
+                Instr::Addr { dst, src } => (dst, Value::Ref(Place::new(regs[*src as usize].clone()))),
+                Instr::Temp { dst, src } => (dst, Value::Ref(Place::temp(get(regs, *src)))),
+                Instr::FieldAddr { dst, base, name } => {
+                    let base = at(place_in(get(regs, *base)).follow(), span)?;
+                    (dst, Value::Ref(base.child(Step::Field(text(module, *name)))))
+                }
+                Instr::IndexAddr { dst, base, index } => {
+                    let base = at(place_in(get(regs, *base)).follow(), span)?;
+                    let index = at(get(regs, *index).load(), span)?;
+                    let is_map = at(base.with(|value| matches!(value, Value::Map(_))), span)?;
+                    let step = match index {
+                        key if is_map => Step::Key(key),
+                        Value::Int(i) => Step::Index(usize::try_from(i).unwrap_or(usize::MAX)),
+                        other => return unsupported(format!("cannot index with `{}`", other), span),
+                    };
+                    (dst, Value::Ref(base.child(step)))
+                }
+                Instr::Deref { dst, src } => (dst, Value::Ref(at(place_in(get(regs, *src)).follow(), span)?)),
+                Instr::Load { dst, src } => (dst, at(place_in(get(regs, *src)).get(), span)?),
+                Instr::Store { dst, src } => {
+                    at(place_in(get(regs, *dst)).set(get(regs, *src)), span)?;
+                    continue;
+                }
+                Instr::Match { dst, place, pattern } => {
+                    let mut binds = Vec::new();
+                    let place = place_in(get(regs, *place));
+                    let matched = self.matches(&module.patterns[*pattern as usize], &place, false, &mut binds, span)?;
+                    if matched {
+                        for (reg, value) in binds {
+                            regs[reg as usize] = slot(value);
+                        }
+                    }
+                    (dst, Value::Bool(matched))
+                }
+                // Items are popped from the end, so they are kept in reverse
+                Instr::Iter { dst, src } => {
+                    let mut items = items(get(regs, *src), span)?;
+                    items.reverse();
+                    (dst, Value::List(items))
+                }
+                Instr::Next { dst, iter, target } => {
+                    let item = match &mut *regs[*iter as usize].borrow_mut() {
+                        Value::List(items) => items.pop(),
+                        _ => None,
+                    };
+                    match item {
+                        Some(item) => (dst, item),
+                        None => {
+                            pc = *target as usize;
+                            continue;
+                        }
+                    }
+                }
+                Instr::Unsupported { message } => return unsupported(text(module, *message), span),
+                Instr::Closure { dst, func, captures } => {
+                    let captures = captures.iter().map(|r| regs[*r as usize].clone()).collect();
+                    (dst, Value::Fn(Callable::Closure { code: *func, captures }))
+                }
+            };
+            regs[*dst as usize] = slot(value);
+        }
+        Ok(Value::unit())
+    }
+
+    // ========== Patterns ==========
+
+    /// Whether the value at `place` matches `pattern`. Bindings reached
+    /// through a reference borrow from it rather than copying.
+    fn matches(
+        &mut self,
+        pattern: &Pattern,
+        place: &Place,
+        by_ref: bool,
+        binds: &mut Vec<(Reg, Value)>,
+        span: Span,
+    ) -> Eval<bool> {
+        match pattern {
+            Pattern::Wildcard => Ok(true),
+            Pattern::Bind(reg) => {
+                let value = if by_ref { Value::Ref(place.clone()) } else { at(place.get(), span)? };
+                binds.push((*reg, value));
+                Ok(true)
+            }
+            Pattern::Literal(index) => Ok(at(place.get(), span)? == constant(&self.module.consts[*index as usize])),
+            Pattern::Global(index) => {
+                let expected = self.global(*index)?;
+                Ok(at(place.get(), span)? == expected)
+            }
+            Pattern::Tuple(pats) => {
+                let (place, by_ref) = deref_pattern(place, by_ref, span)?;
+                let len =
+                    at(place.with(|value| matches!(value, Value::Tuple(elems) if elems.len() == pats.len())), span)?;
+                Ok(len
+                    && self.match_fields(
+                        pats.iter().enumerate().map(|(i, p)| (i.to_string(), p)),
+                        &place,
+                        by_ref,
+                        binds,
+                        span,
+                    )?)
+            }
+            Pattern::Ctor { def, fields, arity } => {
+                let (place, by_ref) = deref_pattern(place, by_ref, span)?;
+                let matched = place.with(|value| {
+                    matches!(value, Value::Adt { def: d, fields: values, .. }
+                        if d.0 == *def && arity.is_none_or(|n| values.len() == n as usize))
+                });
+                Ok(at(matched, span)?
+                    && self.match_fields(fields.iter().map(|(n, p)| (n.clone(), p)), &place, by_ref, binds, span)?)
+            }
+        }
+    }
+
+    fn match_fields<'p>(
+        &mut self,
+        fields: impl Iterator<Item = (String, &'p Pattern)>,
+        place: &Place,
+        by_ref: bool,
+        binds: &mut Vec<(Reg, Value)>,
+        span: Span,
+    ) -> Eval<bool> {
+        for (name, pat) in fields {
+            if !self.matches(pat, &place.child(Step::Field(name)), by_ref, binds, span)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<'m> Host for Vm<'m> {
+    fn variants(&self) -> Variants {
+        self.variants
+    }
+
+    fn engine(&self) -> &'static str {
+        "the bytecode VM"
+    }
+
+    fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    fn out(&mut self) -> &mut dyn Write {
+        self.out
+    }
+
+    fn err(&mut self) -> &mut dyn Write {
+        self.err
+    }
+
+    fn is_library(&self, def: DefId) -> bool {
+        self.lib_defs.contains(&def.0)
+    }
+
+    fn call(&mut self, callable: &Callable, args: Vec<Value>, site: &Site) -> Eval<Value> {
+        self.call_value(callable, args, site)
+    }
+
+    fn to_string_fn(&self, value: &Value, span: Span) -> Eval<Option<DefId>> {
+        let keys = self.keys_of(value, span)?;
+        Ok(self.find_method(&keys, None, "to_string").map(DefId))
+    }
+}
+
+/// The place a register refers to; any other value is a temporary
+fn place_in(value: Value) -> Place {
+    match value {
+        Value::Ref(place) => place,
+        other => Place::temp(other),
+    }
+}
+
+fn constant(c: &Const) -> Value {
+    match c {
+        Const::Unit => Value::unit(),
+        Const::Bool(b) => Value::Bool(*b),
+        Const::Int(n) => Value::Int(*n),
+        Const::Float(x) => Value::Float(*x),
+        Const::Char(c) => Value::Char(*c),
+        Const::Str(s) => Value::Str(s.clone()),
+    }
+}
+
+/// A string from the constant pool, such as a field name or library path
+fn text(module: &Module, index: u32) -> String {
+    match &module.consts[index as usize] {
+        Const::Str(s) => s.clone(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// The span a location stands for, where errors point
+pub(crate) fn span(loc: Loc) -> Span {
+    Span::new(FileId(loc.file), 0, 0, loc.line, loc.column)
+}
@@ -0,0 +1,1564 @@
+// Function checking
+// Generates type constraints over one body at a time and solves them by unification
+
+use crate::collect::{self, FnSig, Tables, VariantDef, VariantKind};
+use crate::exhaustive::{self, MatchCheck};
+use crate::infer::{InferCtxt, Scheme};
+use crate::traits::{self, Selection};
+use crate::ty::{InferTy, Ty};
+use my_lang_ast::*;
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_resolve::{DefId, DefKind, Res, Resolution, ScopeId};
+use std::collections::{HashMap, HashSet};
+
+/// Why two types were required to be equal, for the error message
+#[derive(Debug, Clone, Copy)]
+enum Cause {
+    Plain,
+    /// The declared return type, at this span
+    Return(Span),
+    /// A type annotation on a `let` or constant
+    Annotation(Span),
+    /// An argument to the function defined at this span
+    Argument(Span),
+    /// The first arm of an `if` or `match`
+    FirstBranch(Span),
+}
+
+/// A constraint that waits until a type is known well enough to look into
+#[derive(Debug)]
+enum Obligation {
+    /// `base.name` has type `ty`
+    Field { base: Ty, name: String, ty: Ty, span: Span },
+    /// `receiver.name(args)` returns `ret`
+    Method { expr: NodeId, receiver: Ty, name: String, args: Vec<(Ty, Span)>, ret: Ty, span: Span },
+    /// `ty` implements `trait_def`, as the bound written at `bound` requires
+    Trait { ty: Ty, trait_def: DefId, span: Span, bound: Span },
+    /// Associated type `item` of `self_ty` is `ty`
+    Projection { self_ty: Ty, item: DefId, ty: Ty, span: Span },
+}
+
+impl Obligation {
+    fn subject(&self) -> &Ty {
+        match self {
+            Obligation::Field { base, .. } => base,
+            Obligation::Method { receiver, .. } => receiver,
+            Obligation::Trait { ty, .. } => ty,
+            Obligation::Projection { self_ty, .. } => self_ty,
+        }
+    }
+
+    fn span(&self) -> Span {
+        match self {
+            Obligation::Field { span, .. }
+            | Obligation::Method { span, .. }
+            | Obligation::Trait { span, .. }
+            | Obligation::Projection { span, .. } => *span,
+        }
+    }
+}
+
+/// Outcome of looking a method up on a receiver type
+enum Probe {
+    /// The function called, with its signature as seen from the receiver
+    Found(DefId, FnSig),
+    /// Candidates from several traits
+    Ambiguous(Vec<DefId>),
+    NotFound,
+    /// Depends on inference variables not yet resolved
+    Pending,
+}
+
+/// Inference state for one function body, constant or other expression root
+pub struct FnCtxt<'t, 'a> {
+    tables: &'t Tables<'a>,
+    res: &'a Resolution,
+    infcx: InferCtxt,
+    self_ty: Option<Ty>,
+    /// Scope the body is checked from, for field privacy
+    scope: Option<ScopeId>,
+    locals: HashMap<DefId, Scheme>,
+    /// Declared return type, with the span of its annotation if written
+    ret: Option<(Ty, Option<Span>)>,
+    /// Result type of each enclosing loop; `None` for `while` and `for`
+    loops: Vec<Option<Ty>>,
+    pending: Vec<Obligation>,
+    /// Matches whose patterns checked cleanly, for exhaustiveness once types are known
+    matches: Vec<MatchCheck>,
+    /// Inside a postcondition, where `old(expr)` may be used
+    in_postcondition: bool,
+    pub node_types: HashMap<NodeId, Ty>,
+    pub method_calls: HashMap<NodeId, DefId>,
+    pub diags: Vec<Diagnostic>,
+}
+
+impl<'t, 'a> FnCtxt<'t, 'a> {
+    pub fn new(tables: &'t Tables<'a>, self_ty: Option<Ty>, scope: Option<ScopeId>) -> Self {
+        Self {
+            tables,
+            res: tables.res,
+            infcx: InferCtxt::new(),
+            self_ty,
+            scope,
+            locals: HashMap::new(),
+            ret: None,
+            loops: Vec::new(),
+            pending: Vec::new(),
+            matches: Vec::new(),
+            in_postcondition: false,
+            node_types: HashMap::new(),
+            method_calls: HashMap::new(),
+            diags: Vec::new(),
+        }
+    }
+
+    // ========== Roots ==========
+
+    pub fn check_fn(&mut self, func: &Function, sig: &FnSig) {
+        for (param, ty) in func.params.iter().zip(&sig.inputs) {
+            let ty = self.normalize(ty, param.span);
+            if let Some(def) = self.res.def_of_node(param.id) {
+                self.locals.insert(def, Scheme::mono(ty.clone()));
+            }
+            self.node_types.insert(param.id, ty);
+        }
+        let output = self.normalize(&sig.output, func.span);
+        self.ret = Some((output.clone(), func.return_type.as_ref().map(|t| t.span)));
+
+        if let Some(contract) = &func.contract {
+            for cond in contract.preconditions.iter().chain(&contract.invariants) {
+                self.check_condition(cond);
+            }
+        }
+
+        let body_ty = self.check_block(&func.body, Some(&output));
+        self.check_body_value(func, &body_ty, &output);
+
+        if let Some(contract) = &func.contract {
+            if let Some(result) = self.res.def_of_node(contract.id) {
+                self.locals.insert(result, Scheme::mono(output.clone()));
+            }
+            self.in_postcondition = true;
+            for cond in &contract.postconditions {
+                self.check_condition(cond);
+            }
+            self.in_postcondition = false;
+        }
+    }
+
+    /// The value a body produces must match the declared return type
+    fn check_body_value(&mut self, func: &Function, body_ty: &Ty, output: &Ty) {
+        let cause = match &func.return_type {
+            Some(ty) => Cause::Return(ty.span),
+            None => Cause::Plain,
+        };
+        if let Some(tail) = &func.body.expr {
+            self.coerce(body_ty, output, tail.span, cause);
+            return;
+        }
+        if self.infcx.unify(output, body_ty).is_ok() {
+            return;
+        }
+        let expected = self.infcx.resolve(output);
+        let mut diag = self.mismatch(&expected, &Ty::unit(), func.body.span, cause);
+        // `fn f() -> i32 { x + 1; }` only needs the semicolon gone
+        if let Some(Statement { kind: StatementKind::Expression(last), .. }) = func.body.stmts.last() {
+            let last_ty = self.node_types.get(&last.id).map(|t| self.infcx.resolve(t));
+            if last_ty.is_some_and(|t| t == self.infcx.resolve(output)) {
+                diag = diag
+                    .with_secondary(last.span, "this expression has the return type")
+                    .with_note("remove the semicolon after it to return its value");
+            }
+        }
+        self.diags.push(diag);
+    }
+
+    /// Check a free-standing expression, such as a constant's value, against `expected`
+    pub fn check_root_expr(&mut self, expr: &Expression, expected: Option<(&Ty, Span)>) {
+        match expected {
+            Some((ty, annotation)) => {
+                let ty = ty.clone();
+                let found = self.infer_expr(expr, Some(&ty));
+                self.coerce(&found, &ty, expr.span, Cause::Annotation(annotation));
+            }
+            None => {
+                self.infer_expr(expr, None);
+            }
+        }
+    }
+
+    /// Check an impl's invariants, with `self` a value of the impl's self type
+    pub fn check_invariants(&mut self, contract: &Contract) {
+        if let (Some(def), Some(ty)) = (self.res.def_of_node(contract.id), self.self_ty.clone()) {
+            self.locals.insert(def, Scheme::mono(ty));
+        }
+        for cond in &contract.invariants {
+            self.check_condition(cond);
+        }
+    }
+
+    /// Check the size of an array type, which compile-time evaluation computes
+    pub fn check_array_size(&mut self, size: &Expression) {
+        let usize = Ty::from_primitive(PrimitiveType::Usize);
+        let found = self.infer_expr(size, Some(&usize));
+        self.coerce(&found, &usize, size.span, Cause::Plain);
+    }
+
+    pub fn check_root_condition(&mut self, expr: &Expression) {
+        self.check_condition(expr);
+    }
+
+    pub fn check_root_block(&mut self, block: &Block) {
+        self.check_block(block, None);
+    }
+
+    /// Solve what remains, default numeric literals and resolve every recorded type
+    pub fn finish(mut self) -> (HashMap<NodeId, Ty>, HashMap<NodeId, DefId>, Vec<Diagnostic>) {
+        self.select_obligations();
+        self.infcx.default_numeric_vars();
+        self.select_obligations();
+        for obligation in std::mem::take(&mut self.pending) {
+            self.diags.push(
+                Diagnostic::error("type annotations needed")
+                    .with_code(codes::TYPE_ANNOTATIONS_NEEDED)
+                    .with_primary(obligation.span(), "type must be known at this point")
+                    .with_note("consider giving the value a type annotation"),
+            );
+        }
+        for mut check in std::mem::take(&mut self.matches) {
+            check.scrutinee = self.infcx.resolve(&check.scrutinee);
+            let mut unknown = false;
+            check.scrutinee.walk(&mut |t| unknown |= matches!(t, Ty::Infer(_) | Ty::Error));
+            if !unknown {
+                exhaustive::check_match(self.tables, &check, &mut self.diags);
+            }
+        }
+        let node_types = self.node_types.iter().map(|(&node, ty)| (node, self.infcx.resolve(ty))).collect();
+        (node_types, self.method_calls, self.diags)
+    }
+
+    // ========== Blocks & Statements ==========
+
+    fn check_block(&mut self, block: &Block, expected: Option<&Ty>) -> Ty {
+        let mut diverges = false;
+        for stmt in &block.stmts {
+            diverges |= self.check_stmt(stmt);
+        }
+        match &block.expr {
+            Some(expr) => self.infer_expr(expr, expected),
+            None if diverges => Ty::Never,
+            None => Ty::unit(),
+        }
+    }
+
+    /// Check a statement, returning whether it never completes
+    fn check_stmt(&mut self, stmt: &Statement) -> bool {
+        let ty = match &stmt.kind {
+            StatementKind::Let { pattern, ty, init, .. } => {
+                let annotated = ty.as_ref().map(|t| (self.lower(t), t.span));
+                let declared = match &annotated {
+                    Some((ty, _)) => ty.clone(),
+                    None => self.infcx.new_var(),
+                };
+                let init_ty = init.as_ref().map(|init| {
+                    let found = self.infer_expr(init, Some(&declared));
+                    let cause = annotated.as_ref().map_or(Cause::Plain, |(_, span)| Cause::Annotation(*span));
+                    self.coerce(&found, &declared, init.span, cause);
+                    found
+                });
+                self.check_pat(pattern, &declared, None);
+                if let (PatternKind::Identifier(_), Some(init)) = (&pattern.kind, init) {
+                    if is_syntactic_value(init) {
+                        self.generalize_local(pattern.id, &declared);
+                    }
+                }
+                init_ty
+            }
+            StatementKind::Expression(expr) => Some(self.infer_expr(expr, None)),
+            StatementKind::Item(_) | StatementKind::Error => None,
+        };
+        self.select_obligations();
+        ty.is_some_and(|t| matches!(self.infcx.shallow_resolve(&t), Ty::Never))
+    }
+
+    /// Let-polymorphism: quantify a value binding over the variables no
+    /// enclosing binding mentions
+    fn generalize_local(&mut self, pattern: NodeId, ty: &Ty) {
+        let Some(def) = self.res.def_of_node(pattern) else {
+            return;
+        };
+        self.locals.remove(&def);
+        let mut env = HashSet::new();
+        for scheme in self.locals.values() {
+            let mut vars = HashSet::new();
+            self.infcx.free_vars(&scheme.ty, &mut vars);
+            env.extend(vars.into_iter().filter(|v| !scheme.vars.contains(v)));
+        }
+        if let Some((ret, _)) = &self.ret {
+            self.infcx.free_vars(ret, &mut env);
+        }
+        for obligation in &self.pending {
+            self.infcx.free_vars(obligation.subject(), &mut env);
+        }
+        let scheme = self.infcx.generalize(ty, &env);
+        self.locals.insert(def, scheme);
+    }
+
+    // ========== Expressions ==========
+
+    fn check_condition(&mut self, cond: &Expression) {
+        let ty = self.infer_expr(cond, None);
+        self.coerce(&ty, &Ty::bool(), cond.span, Cause::Plain);
+    }
+
+    /// Infer the type of `expr`. `expected` only guides literals whose type
+    /// depends on context; the caller still unifies the result.
+    fn infer_expr(&mut self, expr: &Expression, expected: Option<&Ty>) -> Ty {
+        let ty = self.infer_expr_kind(expr, expected);
+        self.node_types.insert(expr.id, ty.clone());
+        ty
+    }
+
+    fn infer_expr_kind(&mut self, expr: &Expression, expected: Option<&Ty>) -> Ty {
+        match &expr.kind {
+            ExpressionKind::Literal(lit) => self.literal_ty(lit, expected),
+            ExpressionKind::Identifier(_) | ExpressionKind::Path(_) => self.path_ty(expr.id, expr.span),
+            ExpressionKind::Binary { left, op, right } => self.binary_ty(left, op, right),
+            ExpressionKind::Comptime(inner) => self.infer_expr(inner, expected),
+            ExpressionKind::Unary { op, expr: operand } => {
+                let inner_expected = match (op, expected.map(|t| self.infcx.shallow_resolve(t))) {
+                    (UnaryOp::Ref | UnaryOp::RefMut, Some(Ty::Ref { ty, .. })) => Some(*ty),
+                    _ => None,
+                };
+                let ty = self.infer_expr(operand, inner_expected.as_ref());
+                match op {
+                    UnaryOp::Neg | UnaryOp::Not => ty,
+                    UnaryOp::Ref => Ty::Ref { ty: Box::new(ty), mutable: false },
+                    UnaryOp::RefMut => Ty::Ref { ty: Box::new(ty), mutable: true },
+                    UnaryOp::Deref => match self.infcx.shallow_resolve(&ty) {
+                        Ty::Ref { ty, .. } => *ty,
+                        other => self.smart_pointee(&other).unwrap_or_else(|| self.infcx.new_var()),
+                    },
+                }
+            }
+            ExpressionKind::Call { func, args } => self.call_ty(expr, func, args),
+            ExpressionKind::Closure { params, ret, body, .. } => self.closure_ty(params, ret.as_ref(), body, expected),
+            ExpressionKind::MethodCall { receiver, method, args } => {
+                let receiver_ty = self.infer_expr(receiver, None);
+                let args = args.iter().map(|a| (self.infer_expr(a, None), a.span)).collect();
+                let ret = self.infcx.new_var();
+                self.pending.push(Obligation::Method {
+                    expr: expr.id,
+                    receiver: receiver_ty,
+                    name: method.clone(),
+                    args,
+                    ret: ret.clone(),
+                    span: expr.span,
+                });
+                self.select_obligations();
+                ret
+            }
+            ExpressionKind::If { cond, then_block, else_block } => {
+                self.check_condition(cond);
+                let then_ty = self.check_block(then_block, expected);
+                match else_block {
+                    Some(block) => {
+                        let else_ty = self.check_block(block, expected.or(Some(&then_ty)));
+                        let then_span = block_value_span(then_block);
+                        self.coerce(&else_ty, &then_ty, block_value_span(block), Cause::FirstBranch(then_span));
+                        self.join(then_ty, else_ty)
+                    }
+                    None => {
+                        self.coerce(&then_ty, &Ty::unit(), block_value_span(then_block), Cause::Plain);
+                        Ty::unit()
+                    }
+                }
+            }
+            ExpressionKind::Match { expr: scrutinee, arms } => {
+                let scrutinee_ty = self.infer_expr(scrutinee, None);
+                let mut result: Option<(Ty, Span)> = None;
+                let mut pattern_errors = false;
+                for arm in arms {
+                    let errors = self.diags.len();
+                    self.check_pat(&arm.pattern, &scrutinee_ty, None);
+                    pattern_errors |= self.diags.len() > errors;
+                    if let Some(guard) = &arm.guard {
+                        self.check_condition(guard);
+                    }
+                    let hint = result.as_ref().map(|(t, _)| t.clone()).or_else(|| expected.cloned());
+                    let arm_ty = self.infer_expr(&arm.body, hint.as_ref());
+                    result = Some(match result {
+                        None => (arm_ty, arm.body.span),
+                        Some((first, first_span)) => {
+                            self.coerce(&arm_ty, &first, arm.body.span, Cause::FirstBranch(first_span));
+                            (self.join(first, arm_ty), first_span)
+                        }
+                    });
+                }
+                if !pattern_errors {
+                    self.matches.push(MatchCheck {
+                        scrutinee: scrutinee_ty,
+                        scrutinee_span: scrutinee.span,
+                        arms: arms.iter().map(|arm| (arm.pattern.clone(), arm.guard.is_some())).collect(),
+                    });
+                }
+                result.map_or(Ty::Never, |(ty, _)| ty)
+            }
+            ExpressionKind::Loop(body) => {
+                let ty = self.infcx.new_var();
+                self.loops.push(Some(ty.clone()));
+                let body_ty = self.check_block(body, None);
+                self.coerce(&body_ty, &Ty::unit(), block_value_span(body), Cause::Plain);
+                self.loops.pop();
+                // A loop without `break` never completes
+                match self.infcx.shallow_resolve(&ty) {
+                    Ty::Infer(InferTy::Var(_)) if !contains_break(body) => Ty::Never,
+                    _ => ty,
+                }
+            }
+            ExpressionKind::While { cond, body } => {
+                self.check_condition(cond);
+                self.check_loop_body(body);
+                Ty::unit()
+            }
+            ExpressionKind::For { pattern, iter, body } => {
+                let iter_ty = self.infer_expr(iter, None);
+                let elem = self.element_ty(&iter_ty);
+                self.check_pat(pattern, &elem, None);
+                self.check_loop_body(body);
+                Ty::unit()
+            }
+            ExpressionKind::Return(value) => {
+                let (ret, annotation) = match &self.ret {
+                    Some((ty, span)) => (ty.clone(), *span),
+                    None => (Ty::Error, None),
+                };
+                let cause = annotation.map_or(Cause::Plain, Cause::Return);
+                match value {
+                    Some(value) => {
+                        let ty = self.infer_expr(value, Some(&ret));
+                        self.coerce(&ty, &ret, value.span, cause);
+                    }
+                    None => self.coerce(&Ty::unit(), &ret, expr.span, cause),
+                }
+                Ty::Never
+            }
+            ExpressionKind::Break(value) => {
+                let target = self.loops.last().cloned().flatten();
+                match (value, target) {
+                    (Some(value), Some(ty)) => {
+                        let found = self.infer_expr(value, Some(&ty));
+                        self.coerce(&found, &ty, value.span, Cause::Plain);
+                    }
+                    (Some(value), None) => {
+                        let found = self.infer_expr(value, None);
+                        self.coerce(&found, &Ty::unit(), value.span, Cause::Plain);
+                    }
+                    (None, Some(ty)) => self.coerce(&Ty::unit(), &ty, expr.span, Cause::Plain),
+                    (None, None) => {}
+                }
+                Ty::Never
+            }
+            ExpressionKind::Continue => Ty::Never,
+            ExpressionKind::Block(block) => self.check_block(block, expected),
+            ExpressionKind::Tuple(elems) => {
+                let hints = match expected.map(|t| self.infcx.shallow_resolve(t)) {
+                    Some(Ty::Tuple(tys)) if tys.len() == elems.len() => tys,
+                    _ => Vec::new(),
+                };
+                Ty::Tuple(elems.iter().enumerate().map(|(i, e)| self.infer_expr(e, hints.get(i))).collect())
+            }
+            ExpressionKind::Array(elems) => {
+                let elem = match expected.map(|t| self.infcx.shallow_resolve(t)) {
+                    Some(Ty::Array(elem, _)) => *elem,
+                    _ => self.infcx.new_var(),
+                };
+                for e in elems {
+                    let ty = self.infer_expr(e, Some(&elem));
+                    self.coerce(&ty, &elem, e.span, Cause::Plain);
+                }
+                Ty::Array(Box::new(elem), Some(elems.len()))
+            }
+            ExpressionKind::Index { expr: base, index } => {
+                let base_ty = self.infer_expr(base, None);
+                self.infer_expr(index, None);
+                self.index_ty(&base_ty)
+            }
+            ExpressionKind::Field { expr: base, field } => {
+                let base_ty = self.infer_expr(base, None);
+                let ty = self.infcx.new_var();
+                self.pending.push(Obligation::Field {
+                    base: base_ty,
+                    name: field.clone(),
+                    ty: ty.clone(),
+                    span: expr.span,
+                });
+                self.select_obligations();
+                ty
+            }
+            ExpressionKind::Struct { fields, .. } => self.struct_literal_ty(expr, fields),
+            ExpressionKind::Intent { options: config, .. } | ExpressionKind::Spawn { config, .. } => {
+                for (_, value) in config {
+                    self.infer_expr(value, None);
+                }
+                self.infcx.new_var()
+            }
+            ExpressionKind::Synth { config, expr: inner } | ExpressionKind::Verify { config, expr: inner } => {
+                for (_, value) in config {
+                    self.infer_expr(value, None);
+                }
+                self.infer_expr(inner, expected)
+            }
+            ExpressionKind::Hybrid { symbolic, neural, .. } => {
+                let ty = self.infer_expr(symbolic, expected);
+                let neural_ty = self.infer_expr(neural, Some(&ty));
+                self.coerce(&neural_ty, &ty, neural.span, Cause::FirstBranch(symbolic.span));
+                ty
+            }
+            ExpressionKind::Send { message, recipient } => {
+                self.infer_expr(message, None);
+                self.infer_expr(recipient, None);
+                Ty::unit()
+            }
+            ExpressionKind::Receive { filter, timeout } => {
+                for e in [filter, timeout].into_iter().flatten() {
+                    self.infer_expr(e, None);
+                }
+                self.infcx.new_var()
+            }
+            ExpressionKind::Broadcast { message, .. } => {
+                self.infer_expr(message, None);
+                Ty::unit()
+            }
+        }
+    }
+
+    fn literal_ty(&mut self, lit: &Literal, expected: Option<&Ty>) -> Ty {
+        match lit {
+            Literal::Int(_) => self.infcx.new_int_var(),
+            Literal::Float(_) => self.infcx.new_float_var(),
+            // A string literal is a `str`, or borrows one where `&str` is wanted
+            Literal::String(_) => match expected.map(|t| self.infcx.resolve(t)) {
+                Some(Ty::Ref { ty, mutable: false }) if *ty == Ty::Prim(PrimitiveType::Str) => {
+                    Ty::Ref { ty, mutable: false }
+                }
+                _ => Ty::Prim(PrimitiveType::Str),
+            },
+            Literal::Char(_) => Ty::Prim(PrimitiveType::Char),
+            Literal::Bool(_) => Ty::bool(),
+            Literal::Unit => Ty::unit(),
+        }
+    }
+
+    /// Type of a name used as a value
+    fn path_ty(&mut self, node: NodeId, span: Span) -> Ty {
+        match self.res.res(node) {
+            Some(Res::Def(def)) => self.def_value_ty(*def, span),
+            Some(Res::Partial { base, rest }) => match rest.as_slice() {
+                [name] => self.assoc_fn_ty(node, *base, name, span),
+                _ => self.infcx.new_var(),
+            },
+            None => Ty::Error,
+        }
+    }
+
+    /// `T::f` or `Self::f`, through a trait the parameter is bounded by. Other
+    /// associated items of types are left to inference.
+    fn assoc_fn_ty(&mut self, node: NodeId, base: DefId, name: &str, span: Span) -> Ty {
+        let self_ty = match self.res.def(base).kind {
+            DefKind::TypeParam => Ty::Param(base),
+            DefKind::SelfType => self.self_ty.clone().unwrap_or(Ty::Param(base)),
+            _ => return self.infcx.new_var(),
+        };
+        let Ty::Param(param) = self_ty else {
+            return self.infcx.new_var();
+        };
+        let found = self.bound_fns(param, name);
+        let [(trait_def, def)] = found.as_slice() else {
+            return self.infcx.new_var();
+        };
+        let sig = traits::method_sig_for(&self.tables.sigs[def], &self.tables.trait_defs[trait_def], &self_ty);
+        self.method_calls.insert(node, *def);
+        let (inputs, output) = self.instantiate_sig(&sig, span);
+        Ty::Fn(inputs, Box::new(output))
+    }
+
+    /// Functions named `name` in the traits bounding `param`
+    fn bound_fns(&self, param: DefId, name: &str) -> Vec<(DefId, DefId)> {
+        let bounds = self.tables.param_bounds.get(&param).into_iter().flatten();
+        bounds
+            .filter_map(|t| Some((*t, self.tables.trait_defs.get(t)?.func(name)?.def)))
+            .collect()
+    }
+
+    fn def_value_ty(&mut self, def: DefId, span: Span) -> Ty {
+        let definition = self.res.def(def);
+        match definition.kind {
+            DefKind::Local | DefKind::Param => match self.locals.get(&def).cloned() {
+                Some(scheme) => self.infcx.instantiate(&scheme),
+                None => Ty::Error,
+            },
+            DefKind::Function => match self.tables.sigs.get(&def) {
+                Some(sig) => {
+                    let (inputs, output) = self.instantiate_sig(sig, span);
+                    Ty::Fn(inputs, Box::new(output))
+                }
+                None => self.infcx.new_var(),
+            },
+            DefKind::Const => self.tables.const_tys.get(&def).cloned().unwrap_or(Ty::Error),
+            DefKind::StateField => self.tables.state_field_tys.get(&def).cloned().unwrap_or(Ty::Error),
+            DefKind::Variant | DefKind::Struct => {
+                let Some((adt, variant)) = self.tables.variant(def) else {
+                    return self.infcx.new_var();
+                };
+                let (kind, fields) = (variant.kind, variant.fields.clone());
+                let args = self.fresh_args(adt);
+                let ty = self.adt_ty(adt, &args).unwrap_or(Ty::Error);
+                match kind {
+                    VariantKind::Unit => ty,
+                    VariantKind::Tuple => Ty::Fn(fields.iter().map(|f| f.ty.subst(&args)).collect(), Box::new(ty)),
+                    VariantKind::Struct if fields.is_empty() => ty,
+                    VariantKind::Struct => {
+                        let name = &self.res.def(def).name;
+                        self.diags.push(
+                            Diagnostic::error(format!("expected value, found {} `{}`", definition.kind.describe(), name))
+                                .with_code(codes::TYPE_MISMATCH)
+                                .with_primary(span, format!("use struct literal syntax: `{} {{ .. }}`", name)),
+                        );
+                        Ty::Error
+                    }
+                }
+            }
+            DefKind::External => self.infcx.new_var(),
+            _ => Ty::Error,
+        }
+    }
+
+    fn binary_ty(&mut self, left: &Expression, op: &BinaryOp, right: &Expression) -> Ty {
+        let left_ty = self.infer_expr(left, None);
+        match op {
+            BinaryOp::And | BinaryOp::Or => {
+                self.coerce(&left_ty, &Ty::bool(), left.span, Cause::Plain);
+                let right_ty = self.infer_expr(right, Some(&Ty::bool()));
+                self.coerce(&right_ty, &Ty::bool(), right.span, Cause::Plain);
+                Ty::bool()
+            }
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => {
+                let right_ty = self.infer_expr(right, Some(&left_ty));
+                self.coerce(&right_ty, &left_ty, right.span, Cause::Plain);
+                Ty::bool()
+            }
+            BinaryOp::Assign => {
+                let right_ty = self.infer_expr(right, Some(&left_ty));
+                self.coerce(&right_ty, &left_ty, right.span, Cause::Plain);
+                Ty::unit()
+            }
+            BinaryOp::Shl | BinaryOp::Shr => {
+                self.infer_expr(right, None);
+                left_ty
+            }
+            BinaryOp::Add
+            | BinaryOp::Sub
+            | BinaryOp::Mul
+            | BinaryOp::Div
+            | BinaryOp::Mod
+            | BinaryOp::BitAnd
+            | BinaryOp::BitOr
+            | BinaryOp::BitXor => {
+                let right_ty = self.infer_expr(right, Some(&left_ty));
+                // `+` on a string appends the other operand's text
+                let str_ty = Ty::Prim(PrimitiveType::Str);
+                if *op == BinaryOp::Add && [&left_ty, &right_ty].iter().any(|t| self.is_str(t)) {
+                    return str_ty;
+                }
+                self.coerce(&right_ty, &left_ty, right.span, Cause::Plain);
+                left_ty
+            }
+        }
+    }
+
+    fn is_str(&self, ty: &Ty) -> bool {
+        match self.infcx.shallow_resolve(ty) {
+            Ty::Prim(PrimitiveType::Str) => true,
+            Ty::Ref { ty, .. } => self.is_str(&ty),
+            _ => false,
+        }
+    }
+
+    fn call_ty(&mut self, expr: &Expression, func: &Expression, args: &[Expression]) -> Ty {
+        let callee_def = match self.res.res(func.id) {
+            Some(Res::Def(def)) => Some(*def),
+            _ => None,
+        };
+        if let Some(def) = callee_def.filter(|d| self.res.def(*d).is_prelude() && self.res.def(*d).kind == DefKind::Function) {
+            self.node_types.insert(func.id, Ty::Error);
+            return self.prelude_call_ty(expr, &self.res.def(def).name.clone(), args);
+        }
+
+        let callee = self.infer_expr(func, None);
+        match self.infcx.shallow_resolve(&callee) {
+            Ty::Fn(params, ret) => {
+                let defined = callee_def.map(|d| self.res.def(d).span).filter(|s| !s.is_dummy());
+                if params.len() != args.len() {
+                    let what = callee_def.map_or("function", |d| describe_callable(self.res.def(d).kind));
+                    self.report_arity(what, params.len(), args.len(), expr.span, defined);
+                }
+                for (i, arg) in args.iter().enumerate() {
+                    match params.get(i) {
+                        Some(param) => {
+                            let ty = self.infer_expr(arg, Some(param));
+                            let cause = defined.map_or(Cause::Plain, Cause::Argument);
+                            self.coerce(&ty, param, arg.span, cause);
+                        }
+                        None => {
+                            self.infer_expr(arg, None);
+                        }
+                    }
+                }
+                *ret
+            }
+            Ty::Infer(InferTy::Var(_)) => {
+                let params = args.iter().map(|a| self.infer_expr(a, None)).collect();
+                let ret = self.infcx.new_var();
+                let _ = self.infcx.unify(&callee, &Ty::Fn(params, Box::new(ret.clone())));
+                ret
+            }
+            Ty::Error => {
+                for arg in args {
+                    self.infer_expr(arg, None);
+                }
+                Ty::Error
+            }
+            other => {
+                let found = self.infcx.resolve(&other);
+                self.diags.push(
+                    Diagnostic::error(format!("expected function, found `{}`", found.display(self.res)))
+                        .with_code(codes::NOT_CALLABLE)
+                        .with_primary(func.span, "call expression requires function"),
+                );
+                for arg in args {
+                    self.infer_expr(arg, None);
+                }
+                Ty::Error
+            }
+        }
+    }
+
+    /// A closure's parameters take their types from annotations, or else from
+    /// the function type expected of it. Captured locals keep the types the
+    /// enclosing body gives them, so using one inside constrains it outside too.
+    fn closure_ty(&mut self, params: &[Param], ret: Option<&Type>, body: &Expression, expected: Option<&Ty>) -> Ty {
+        let hints = match expected.map(|t| self.infcx.shallow_resolve(t)) {
+            Some(Ty::Fn(inputs, output)) if inputs.len() == params.len() => Some((inputs, *output)),
+            _ => None,
+        };
+        let mut inputs = Vec::new();
+        for (i, param) in params.iter().enumerate() {
+            let ty = match (&param.ty.kind, &hints) {
+                (TypeKind::Inferred, Some((hints, _))) => hints[i].clone(),
+                _ => self.lower(&param.ty),
+            };
+            if let Some(def) = self.res.def_of_node(param.id) {
+                self.locals.insert(def, Scheme::mono(ty.clone()));
+            }
+            self.node_types.insert(param.id, ty.clone());
+            inputs.push(ty);
+        }
+        let output = match (ret, hints) {
+            (Some(ty), _) => self.lower(ty),
+            (None, Some((_, output))) => output,
+            (None, None) => self.infcx.new_var(),
+        };
+
+        // `return` leaves the closure, and `break` cannot reach loops outside it
+        let outer_ret = self.ret.replace((output.clone(), ret.map(|t| t.span)));
+        let outer_loops = std::mem::take(&mut self.loops);
+        let body_ty = self.infer_expr(body, Some(&output));
+        let cause = ret.map_or(Cause::Plain, |t| Cause::Return(t.span));
+        self.coerce(&body_ty, &output, body.span, cause);
+        self.ret = outer_ret;
+        self.loops = outer_loops;
+        Ty::Fn(inputs, Box::new(output))
+    }
+
+    /// Prelude functions take format arguments of any type
+    fn prelude_call_ty(&mut self, call: &Expression, name: &str, args: &[Expression]) -> Ty {
+        let tys: Vec<Ty> = args.iter().map(|a| self.infer_expr(a, None)).collect();
+        match name {
+            "assert" => {
+                if let (Some(cond), Some(ty)) = (args.first(), tys.first()) {
+                    self.coerce(ty, &Ty::bool(), cond.span, Cause::Plain);
+                }
+                Ty::unit()
+            }
+            "assert_eq" | "assert_ne" => {
+                if let ([first, second, ..], [left, right, ..]) = (args, tys.as_slice()) {
+                    self.coerce(right, left, second.span, Cause::FirstBranch(first.span));
+                }
+                Ty::unit()
+            }
+            "format" => Ty::Prim(PrimitiveType::Str),
+            "panic" | "unreachable" | "todo" => Ty::Never,
+            "dbg" => tys.into_iter().next().unwrap_or_else(Ty::unit),
+            "old" => self.old_ty(call, tys),
+            "contract_violation" => Ty::Never,
+            _ => Ty::unit(),
+        }
+    }
+
+    /// `old(expr)` is the value `expr` had on entry, so only postconditions can ask for it
+    fn old_ty(&mut self, call: &Expression, tys: Vec<Ty>) -> Ty {
+        if !self.in_postcondition {
+            self.diags.push(
+                Diagnostic::error("`old` can only be used in a postcondition")
+                    .with_code(codes::OLD_OUTSIDE_POSTCONDITION)
+                    .with_primary(call.span, "not inside a `post` or `ensures` clause")
+                    .with_note("`old(expr)` is the value `expr` had when the function was entered"),
+            );
+        }
+        if tys.len() != 1 {
+            self.report_arity("function", 1, tys.len(), call.span, None);
+            return Ty::Error;
+        }
+        tys.into_iter().next().unwrap_or(Ty::Error)
+    }
+
+    fn report_arity(&mut self, what: &str, expected: usize, found: usize, span: Span, defined: Option<Span>) {
+        let plural = |n: usize| if n == 1 { "argument" } else { "arguments" };
+        let mut diag = Diagnostic::error(format!(
+            "this {} takes {} {} but {} {} supplied",
+            what,
+            expected,
+            plural(expected),
+            found,
+            if found == 1 { "argument was" } else { "arguments were" }
+        ))
+        .with_code(codes::ARITY_MISMATCH)
+        .with_primary(span, format!("expected {} {}", expected, plural(expected)));
+        if let Some(defined) = defined {
+            diag = diag.with_secondary(defined, format!("{} defined here", what));
+        }
+        self.diags.push(diag);
+    }
+
+    fn struct_literal_ty(&mut self, expr: &Expression, fields: &[(String, Expression)]) -> Ty {
+        let target = match self.res.res(expr.id) {
+            Some(Res::Def(def)) => self.tables.variant(*def).map(|(adt, v)| (adt, v.clone())),
+            _ => None,
+        };
+        let Some((adt, variant)) = target else {
+            for (_, value) in fields {
+                self.infer_expr(value, None);
+            }
+            return match self.res.res(expr.id) {
+                Some(_) => self.infcx.new_var(),
+                None => Ty::Error,
+            };
+        };
+
+        let args = self.fresh_args(adt);
+        let ty = self.adt_ty(adt, &args).unwrap_or(Ty::Error);
+        let name = self.res.def(variant.def).name.clone();
+        let mut seen = HashSet::new();
+        for (field, value) in fields {
+            match variant.field(field) {
+                Some(declared) => {
+                    let expected = declared.ty.subst(&args);
+                    let found = self.infer_expr(value, Some(&expected));
+                    self.coerce(&found, &expected, value.span, Cause::Plain);
+                    seen.insert(field.as_str());
+                }
+                None => {
+                    self.infer_expr(value, None);
+                    let mut diag = Diagnostic::error(format!("struct `{}` has no field named `{}`", name, field))
+                        .with_code(codes::UNKNOWN_FIELD)
+                        .with_primary(value.span, "unknown field");
+                    if let Some(note) = available_fields(variant.fields.iter().map(|f| f.name.as_str())) {
+                        diag = diag.with_note(note);
+                    }
+                    self.diags.push(diag);
+                }
+            }
+        }
+        let missing: Vec<String> = variant
+            .fields
+            .iter()
+            .filter(|f| !seen.contains(f.name.as_str()))
+            .map(|f| format!("`{}`", f.name))
+            .collect();
+        if !missing.is_empty() && variant.kind == VariantKind::Struct {
+            let (noun, list) = match missing.as_slice() {
+                [one] => ("field", one.clone()),
+                [init @ .., last] => ("fields", format!("{} and {}", init.join(", "), last)),
+                [] => unreachable!(),
+            };
+            self.diags.push(
+                Diagnostic::error(format!("missing {} {} in initializer of `{}`", noun, list, name))
+                    .with_code(codes::MISSING_FIELDS)
+                    .with_primary(expr.span, format!("missing {}", list)),
+            );
+        }
+        ty
+    }
+
+    fn check_loop_body(&mut self, body: &Block) {
+        self.loops.push(None);
+        let ty = self.check_block(body, None);
+        self.coerce(&ty, &Ty::unit(), block_value_span(body), Cause::Plain);
+        self.loops.pop();
+    }
+
+    /// Element type of what a `for` loop iterates over
+    fn element_ty(&mut self, iter: &Ty) -> Ty {
+        match self.infcx.shallow_resolve(iter) {
+            Ty::Array(elem, _) => *elem,
+            Ty::Ref { ty, mutable } => match self.infcx.shallow_resolve(&ty) {
+                Ty::Array(elem, _) => Ty::Ref { ty: elem, mutable },
+                Ty::Adt(def, args) if self.is_prelude_type(def, &["Vec", "VecDeque", "HashSet", "BTreeSet"]) => {
+                    Ty::Ref { ty: Box::new(args[0].clone()), mutable }
+                }
+                _ => self.infcx.new_var(),
+            },
+            Ty::Adt(def, args) if self.is_prelude_type(def, &["Vec", "VecDeque", "HashSet", "BTreeSet"]) => {
+                args[0].clone()
+            }
+            _ => self.infcx.new_var(),
+        }
+    }
+
+    fn index_ty(&mut self, base: &Ty) -> Ty {
+        match self.infcx.shallow_resolve(base) {
+            Ty::Array(elem, _) => *elem,
+            Ty::Ref { ty, .. } => self.index_ty(&ty),
+            Ty::Adt(def, args) if self.is_prelude_type(def, &["Vec", "VecDeque"]) => args[0].clone(),
+            Ty::Adt(def, args) if self.is_prelude_type(def, &["HashMap", "BTreeMap"]) => args[1].clone(),
+            Ty::Prim(PrimitiveType::Str) => Ty::Prim(PrimitiveType::Str),
+            _ => self.infcx.new_var(),
+        }
+    }
+
+    // ========== Fields & Methods ==========
+
+    /// Retry deferred constraints until none makes progress
+    fn select_obligations(&mut self) {
+        loop {
+            let mut progress = false;
+            for obligation in std::mem::take(&mut self.pending) {
+                if self.select(&obligation) {
+                    progress = true;
+                } else {
+                    self.pending.push(obligation);
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+    }
+
+    /// Discharge an obligation, or return false if it waits on inference
+    fn select(&mut self, obligation: &Obligation) -> bool {
+        match obligation {
+            Obligation::Trait { ty, trait_def, span, bound } => {
+                return self.select_trait(ty, *trait_def, *span, *bound);
+            }
+            Obligation::Projection { self_ty, item, ty, .. } => {
+                if matches!(self.infcx.shallow_resolve(self_ty), Ty::Infer(_)) {
+                    return false;
+                }
+                let assoc = Ty::Assoc { self_ty: Box::new(self_ty.clone()), item: *item };
+                let projected = traits::normalize(self.tables, &assoc, &self.infcx);
+                let _ = self.infcx.unify(ty, &projected);
+                return true;
+            }
+            _ => {}
+        }
+        let subject = self.autoderef(obligation.subject());
+        if matches!(subject, Ty::Infer(InferTy::Var(_))) {
+            return false;
+        }
+        match obligation {
+            Obligation::Field { name, ty, span, .. } => {
+                self.select_field(&subject, name, ty, *span);
+                true
+            }
+            Obligation::Method { expr, name, args, ret, span, .. } => {
+                self.select_method(*expr, &subject, name, args, ret, *span)
+            }
+            Obligation::Trait { .. } | Obligation::Projection { .. } => unreachable!("handled above"),
+        }
+    }
+
+    /// Strip references and smart pointers
+    fn autoderef(&self, ty: &Ty) -> Ty {
+        let mut ty = self.infcx.shallow_resolve(ty);
+        loop {
+            match ty {
+                Ty::Ref { ty: inner, .. } => ty = self.infcx.shallow_resolve(&inner),
+                Ty::Adt(..) => match self.smart_pointee(&ty) {
+                    Some(inner) => ty = self.infcx.shallow_resolve(&inner),
+                    None => return ty,
+                },
+                _ => return ty,
+            }
+        }
+    }
+
+    fn smart_pointee(&self, ty: &Ty) -> Option<Ty> {
+        match ty {
+            Ty::Adt(def, args) if self.is_prelude_type(*def, &["Box", "Rc", "Arc"]) => args.first().cloned(),
+            _ => None,
+        }
+    }
+
+    fn is_prelude_type(&self, def: DefId, names: &[&str]) -> bool {
+        let definition = self.res.def(def);
+        definition.is_prelude() && names.contains(&definition.name.as_str())
+    }
+
+    fn select_field(&mut self, base: &Ty, name: &str, ty: &Ty, span: Span) {
+        let found = match base {
+            Ty::Tuple(elems) => name.parse::<usize>().ok().and_then(|i| elems.get(i).cloned()),
+            Ty::Adt(def, args) => {
+                let definition = self.res.def(*def);
+                if definition.is_prelude() || definition.kind != DefKind::Struct {
+                    if definition.kind != DefKind::Enum {
+                        return;
+                    }
+                    None
+                } else {
+                    let Some((_, variant)) = self.tables.variant(*def) else {
+                        return;
+                    };
+                    let generics = self.tables.generics_of(*def);
+                    let map: HashMap<DefId, Ty> = generics.into_iter().zip(args.iter().cloned()).collect();
+                    let field_ty = variant.field(name).map(|f| f.ty.subst(&map));
+                    if field_ty.is_some() {
+                        self.check_field_privacy(*def, name, span);
+                    }
+                    field_ty
+                }
+            }
+            Ty::Error | Ty::Never => return,
+            _ => None,
+        };
+        match found {
+            Some(field_ty) => {
+                let _ = self.infcx.unify(ty, &field_ty);
+            }
+            None => {
+                let base = self.infcx.resolve(base);
+                let mut diag = Diagnostic::error(format!("no field `{}` on type `{}`", name, base.display(self.res)))
+                    .with_code(codes::UNKNOWN_FIELD)
+                    .with_primary(span, "unknown field");
+                if let Ty::Adt(def, _) = &base {
+                    if let Some((_, variant)) = self.tables.variant(*def) {
+                        if let Some(note) = available_fields(variant.fields.iter().map(|f| f.name.as_str())) {
+                            diag = diag.with_note(note);
+                        }
+                    }
+                }
+                self.diags.push(diag);
+                let _ = self.infcx.unify(ty, &Ty::Error);
+            }
+        }
+    }
+
+    fn check_field_privacy(&mut self, owner: DefId, name: &str, span: Span) {
+        let Some(scope) = self.scope else {
+            return;
+        };
+        if self.res.field_visible(owner, name, scope) {
+            return;
+        }
+        let definition = self.res.def(owner);
+        let mut diag = Diagnostic::error(format!(
+            "field `{}` of {} `{}` is private",
+            name,
+            definition.kind.describe(),
+            definition.name
+        ))
+        .with_code(codes::PRIVATE_FIELD)
+        .with_primary(span, "private field");
+        if let Some(field) = self.res.fields.get(&owner).and_then(|fs| fs.iter().find(|f| f.name == name)) {
+            diag = diag.with_secondary(field.span, format!("`{}` declared here", name));
+        }
+        self.diags.push(diag);
+    }
+
+    fn select_method(
+        &mut self,
+        expr: NodeId,
+        receiver: &Ty,
+        name: &str,
+        args: &[(Ty, Span)],
+        ret: &Ty,
+        span: Span,
+    ) -> bool {
+        let (method, sig) = match self.probe_method(receiver, name) {
+            Probe::Found(method, sig) => (method, sig),
+            Probe::Pending => return false,
+            Probe::NotFound => {
+                self.report_no_method(receiver, name, span);
+                return true;
+            }
+            Probe::Ambiguous(candidates) => {
+                let mut diag = Diagnostic::error("multiple applicable items in scope")
+                    .with_code(codes::AMBIGUOUS_METHOD)
+                    .with_primary(span, format!("multiple `{}` found", name));
+                for (i, candidate) in candidates.iter().enumerate() {
+                    let definition = self.res.def(*candidate);
+                    diag = diag.with_secondary(definition.span, format!("candidate #{} is defined here", i + 1));
+                }
+                self.diags.push(diag);
+                return true;
+            }
+        };
+        self.method_calls.insert(expr, method);
+        let (inputs, output) = self.instantiate_sig(&sig, span);
+        let self_param = self.autoderef(&inputs[0]);
+        let _ = self.infcx.unify(&self_param, receiver);
+
+        let defined = Some(self.res.def(method).span).filter(|s| !s.is_dummy());
+        if inputs.len() - 1 != args.len() {
+            self.report_arity("method", inputs.len() - 1, args.len(), span, defined);
+        }
+        for ((arg, arg_span), param) in args.iter().zip(&inputs[1..]) {
+            self.coerce(arg, param, *arg_span, defined.map_or(Cause::Plain, Cause::Argument));
+        }
+        let _ = self.infcx.unify(ret, &output);
+        true
+    }
+
+    /// Find the method `name` takes on `receiver`: through the bounds of a
+    /// type parameter, or else in an inherent impl, then in trait impls
+    fn probe_method(&self, receiver: &Ty, name: &str) -> Probe {
+        let takes_self = |def: &DefId| self.tables.sigs.get(def).is_some_and(|s| s.has_self);
+        if let Ty::Param(param) = receiver {
+            let found: Vec<(DefId, DefId)> =
+                self.bound_fns(*param, name).into_iter().filter(|(_, def)| takes_self(def)).collect();
+            return match found.as_slice() {
+                [] => Probe::NotFound,
+                [(trait_def, def)] => {
+                    let sig = traits::method_sig_for(&self.tables.sigs[def], &self.tables.trait_defs[trait_def], receiver);
+                    Probe::Found(*def, sig)
+                }
+                _ => Probe::Ambiguous(found.into_iter().map(|(_, def)| def).collect()),
+            };
+        }
+
+        let mut pending = false;
+        for imp in self.tables.impls.iter().filter(|imp| imp.trait_def.is_none()) {
+            let Some(def) = imp.fns.get(name).filter(|d| takes_self(d)) else { continue };
+            match traits::impl_applies(imp, receiver, &self.infcx) {
+                Some(true) => return Probe::Found(*def, self.tables.sigs[def].clone()),
+                Some(false) => {}
+                None => pending = true,
+            }
+        }
+        if pending {
+            return Probe::Pending;
+        }
+
+        let mut found: Vec<(DefId, FnSig)> = Vec::new();
+        for imp in &self.tables.impls {
+            let Some(trait_def) = imp.trait_def else { continue };
+            let trait_fn = self.tables.trait_defs.get(&trait_def).and_then(|t| t.func(name));
+            // A method the impl leaves out comes from the trait's default
+            let candidate = match (imp.fns.get(name), trait_fn) {
+                (Some(def), _) => Some((*def, self.tables.sigs[def].clone())),
+                (None, Some(trait_fn)) => self.tables.sigs.get(&trait_fn.def).map(|sig| {
+                    (trait_fn.def, traits::method_sig_for(sig, &self.tables.trait_defs[&trait_def], receiver))
+                }),
+                (None, None) => None,
+            };
+            let Some((def, sig)) = candidate.filter(|(_, sig)| sig.has_self) else { continue };
+            match traits::impl_applies(imp, receiver, &self.infcx) {
+                Some(true) if !found.iter().any(|(d, _)| *d == def) => found.push((def, sig)),
+                Some(_) => {}
+                None => pending = true,
+            }
+        }
+        match found.len() {
+            0 if pending => Probe::Pending,
+            0 => Probe::NotFound,
+            1 => {
+                let (def, sig) = found.pop().expect("one candidate");
+                Probe::Found(def, sig)
+            }
+            _ => Probe::Ambiguous(found.into_iter().map(|(def, _)| def).collect()),
+        }
+    }
+
+    /// Library methods are not modelled, so a missing method is only an error
+    /// on a type parameter bounded by the crate's own traits, or on the crate's
+    /// own type when one of its traits declares the method
+    fn report_no_method(&mut self, receiver: &Ty, name: &str, span: Span) {
+        let declaring: Vec<DefId> = self
+            .tables
+            .trait_defs
+            .iter()
+            .filter(|(_, t)| t.func(name).is_some())
+            .map(|(def, _)| *def)
+            .collect();
+        let mut declaring: Vec<&str> = declaring.iter().map(|d| self.res.def(*d).name.as_str()).collect();
+        declaring.sort_unstable();
+        let (what, note) = match receiver {
+            Ty::Param(param) => {
+                let bounds = self.tables.param_bounds.get(param).into_iter().flatten();
+                if bounds.clone().any(|t| !self.tables.trait_defs.contains_key(t)) {
+                    return;
+                }
+                let param_name = &self.res.def(*param).name;
+                let note = declaring.first().map(|t| {
+                    format!(
+                        "trait `{}` defines an item `{}`; perhaps you need to restrict type parameter `{}` with it: `{}: {}`",
+                        t, name, param_name, param_name, t
+                    )
+                });
+                ("type parameter", note)
+            }
+            Ty::Adt(def, _) if !declaring.is_empty() => {
+                let definition = self.res.def(*def);
+                if definition.is_prelude() || definition.kind == DefKind::External {
+                    return;
+                }
+                let ty = receiver.display(self.res).to_string();
+                let note = format!(
+                    "trait `{}` defines an item `{}`, but it is not implemented for `{}`",
+                    declaring[0], name, ty
+                );
+                (definition.kind.describe(), Some(note))
+            }
+            _ => return,
+        };
+        let ty = self.infcx.resolve(receiver);
+        let mut diag = Diagnostic::error(format!(
+            "no method named `{}` found for {} `{}` in the current scope",
+            name,
+            what,
+            ty.display(self.res)
+        ))
+        .with_code(codes::NO_METHOD)
+        .with_primary(span, format!("method not found in `{}`", ty.display(self.res)));
+        if let Some(note) = note {
+            diag = diag.with_note(note);
+        }
+        self.diags.push(diag);
+    }
+
+    /// Whether `ty` implements `trait_def`. Traits from libraries are taken
+    /// to hold, since their impls are not visible.
+    fn select_trait(&mut self, ty: &Ty, trait_def: DefId, span: Span, bound: Span) -> bool {
+        let ty = self.infcx.shallow_resolve(ty);
+        match &ty {
+            Ty::Infer(InferTy::Var(_)) => return false,
+            Ty::Error | Ty::Never | Ty::Assoc { .. } => return true,
+            _ => {}
+        }
+        if !self.tables.trait_defs.contains_key(&trait_def) {
+            return true;
+        }
+        if let Ty::Param(param) = &ty {
+            if self.tables.param_bounds.get(param).is_some_and(|b| b.contains(&trait_def)) {
+                return true;
+            }
+        } else {
+            match traits::select_impl(self.tables, trait_def, &ty, &self.infcx) {
+                Selection::Impl { index, args } => {
+                    // The impl's own bounds must hold for the types it was matched with
+                    for (ty, trait_def, bound) in traits::impl_predicates(&self.tables.impls[index], &args) {
+                        self.pending.push(Obligation::Trait { ty, trait_def, span, bound });
+                    }
+                    return true;
+                }
+                Selection::Ambiguous => return false,
+                Selection::Unimplemented => {}
+            }
+        }
+
+        let (ty, trait_name) = (self.infcx.resolve(&ty), &self.res.def(trait_def).name);
+        let mut diag = Diagnostic::error(format!(
+            "the trait bound `{}: {}` is not satisfied",
+            ty.display(self.res),
+            trait_name
+        ))
+        .with_code(codes::TRAIT_NOT_IMPLEMENTED)
+        .with_primary(span, format!("the trait `{}` is not implemented for `{}`", trait_name, ty.display(self.res)));
+        if !bound.is_dummy() {
+            diag = diag.with_secondary(bound, "required by this bound");
+        }
+        let mut implementors: Vec<String> = Vec::new();
+        for imp in self.tables.impls.iter().filter(|imp| imp.trait_def == Some(trait_def)) {
+            let ty = format!("`{}`", imp.self_ty.display(self.res));
+            if !implementors.contains(&ty) {
+                implementors.push(ty);
+            }
+        }
+        if !implementors.is_empty() {
+            diag = diag.with_note(format!("the trait `{}` is implemented for {}", trait_name, implementors.join(", ")));
+        }
+        self.diags.push(diag);
+        true
+    }
+
+    // ========== Patterns ==========
+
+    /// Check `pat` against the type of the value it matches. Matching a
+    /// reference with a constructor pattern binds its fields by reference.
+    fn check_pat(&mut self, pat: &Pattern, expected: &Ty, by_ref: Option<bool>) {
+        self.node_types.insert(pat.id, expected.clone());
+        let (expected, by_ref) = match &pat.kind {
+            PatternKind::Identifier(_) | PatternKind::Wildcard => (expected.clone(), by_ref),
+            _ => self.peel_refs(expected, by_ref),
+        };
+        match &pat.kind {
+            PatternKind::Wildcard => {}
+            PatternKind::Identifier(_) => {
+                if let Some(Res::Def(def)) = self.res.res(pat.id) {
+                    let ty = self.def_value_ty(*def, pat.span);
+                    self.coerce(&expected, &ty, pat.span, Cause::Plain);
+                    return;
+                }
+                let ty = match by_ref {
+                    Some(mutable) => Ty::Ref { ty: Box::new(expected.clone()), mutable },
+                    None => expected.clone(),
+                };
+                self.node_types.insert(pat.id, ty.clone());
+                if let Some(def) = self.res.def_of_node(pat.id) {
+                    self.locals.insert(def, Scheme::mono(ty));
+                }
+            }
+            PatternKind::Literal(lit) => {
+                let ty = self.literal_ty(lit, Some(&expected));
+                self.coerce(&ty, &expected, pat.span, Cause::Plain);
+            }
+            PatternKind::Tuple(elems) => {
+                let tys: Vec<Ty> = elems.iter().map(|_| self.infcx.new_var()).collect();
+                self.coerce(&Ty::Tuple(tys.clone()), &expected, pat.span, Cause::Plain);
+                for (elem, ty) in elems.iter().zip(&tys) {
+                    self.check_pat(elem, ty, by_ref);
+                }
+            }
+            PatternKind::Struct { fields, .. } => {
+                let Some((variant, args)) = self.pat_variant(pat, &expected) else {
+                    for (_, field) in fields {
+                        self.check_pat(field, &Ty::Error, by_ref);
+                    }
+                    return;
+                };
+                let name = self.res.def(variant.def).name.clone();
+                for (field, sub) in fields {
+                    match variant.field(field) {
+                        Some(declared) => {
+                            let ty = declared.ty.subst(&args);
+                            self.check_pat(sub, &ty, by_ref);
+                        }
+                        None => {
+                            self.diags.push(
+                                Diagnostic::error(format!("`{}` does not have a field named `{}`", name, field))
+                                    .with_code(codes::UNKNOWN_FIELD)
+                                    .with_primary(sub.span, "unknown field"),
+                            );
+                            self.check_pat(sub, &Ty::Error, by_ref);
+                        }
+                    }
+                }
+            }
+            PatternKind::TupleStruct { elems, .. } => {
+                let Some((variant, args)) = self.pat_variant(pat, &expected) else {
+                    for elem in elems {
+                        self.check_pat(elem, &Ty::Error, by_ref);
+                    }
+                    return;
+                };
+                if variant.fields.len() != elems.len() {
+                    let plural = |n: usize| if n == 1 { "field" } else { "fields" };
+                    self.diags.push(
+                        Diagnostic::error(format!(
+                            "this pattern has {} {}, but the corresponding variant has {} {}",
+                            elems.len(),
+                            plural(elems.len()),
+                            variant.fields.len(),
+                            plural(variant.fields.len())
+                        ))
+                        .with_code(codes::ARITY_MISMATCH)
+                        .with_primary(pat.span, format!("expected {} {}", variant.fields.len(), plural(variant.fields.len()))),
+                    );
+                }
+                for (i, elem) in elems.iter().enumerate() {
+                    let ty = variant.fields.get(i).map_or(Ty::Error, |f| f.ty.subst(&args));
+                    self.check_pat(elem, &ty, by_ref);
+                }
+            }
+            PatternKind::Path(_) => {
+                if let Some(Res::Def(def)) = self.res.res(pat.id) {
+                    let ty = self.def_value_ty(*def, pat.span);
+                    self.coerce(&expected, &ty, pat.span, Cause::Plain);
+                }
+            }
+        }
+    }
+
+    fn peel_refs(&self, ty: &Ty, mut by_ref: Option<bool>) -> (Ty, Option<bool>) {
+        let mut ty = self.infcx.shallow_resolve(ty);
+        while let Ty::Ref { ty: inner, mutable } = ty {
+            by_ref = Some(by_ref.map_or(mutable, |outer| outer && mutable));
+            ty = self.infcx.shallow_resolve(&inner);
+        }
+        (ty, by_ref)
+    }
+
+    /// The struct or variant a pattern names, unified with the matched type
+    fn pat_variant(&mut self, pat: &Pattern, expected: &Ty) -> Option<(VariantDef, HashMap<DefId, Ty>)> {
+        let Some(Res::Def(def)) = self.res.res(pat.id) else {
+            return None;
+        };
+        let (adt, variant) = self.tables.variant(*def)?;
+        let variant = variant.clone();
+        let args = self.fresh_args(adt);
+        let ty = self.adt_ty(adt, &args)?;
+        self.coerce(expected, &ty, pat.span, Cause::Plain);
+        Some((variant, args))
+    }
+
+    // ========== Helpers ==========
+
+    fn lower(&mut self, ty: &Type) -> Ty {
+        let mut sizes = Vec::new();
+        collect::array_sizes(ty, &mut sizes);
+        for size in sizes {
+            self.check_array_size(size);
+        }
+        let mut diags = Vec::new();
+        let lowered = self.tables.lower(ty, self.self_ty.as_ref(), Some(&mut self.infcx), &mut diags);
+        self.diags.extend(diags);
+        self.normalize(&lowered, ty.span)
+    }
+
+    /// Project associated types through impls. One whose self type is not
+    /// known yet becomes a variable, bound once it is.
+    fn normalize(&mut self, ty: &Ty, span: Span) -> Ty {
+        let ty = traits::normalize(self.tables, ty, &self.infcx);
+        let (infcx, pending) = (&mut self.infcx, &mut self.pending);
+        ty.fold(&mut |t| match t {
+            Ty::Assoc { self_ty, item } if matches!(infcx.shallow_resolve(self_ty), Ty::Infer(_)) => {
+                let ty = infcx.new_var();
+                pending.push(Obligation::Projection { self_ty: (**self_ty).clone(), item: *item, ty: ty.clone(), span });
+                Some(ty)
+            }
+            _ => None,
+        })
+    }
+
+    /// Fresh variables for a signature's parameters, requiring its bounds of
+    /// them at `span`, with associated types projected
+    fn instantiate_sig(&mut self, sig: &FnSig, span: Span) -> (Vec<Ty>, Ty) {
+        let map: HashMap<DefId, Ty> = sig.generics.iter().map(|&g| (g, self.infcx.new_var())).collect();
+        for predicate in &sig.predicates {
+            self.pending.push(Obligation::Trait {
+                ty: predicate.ty.subst(&map),
+                trait_def: predicate.trait_def,
+                span,
+                bound: predicate.span,
+            });
+        }
+        let inputs = sig.inputs.iter().map(|t| self.normalize(&t.subst(&map), span)).collect();
+        (inputs, self.normalize(&sig.output.subst(&map), span))
+    }
+
+    fn fresh_args(&mut self, adt: DefId) -> HashMap<DefId, Ty> {
+        self.tables.generics_of(adt).into_iter().map(|g| (g, self.infcx.new_var())).collect()
+    }
+
+    fn adt_ty(&self, adt: DefId, args: &HashMap<DefId, Ty>) -> Option<Ty> {
+        let generics = self.tables.generics_of(adt);
+        Some(Ty::Adt(adt, generics.iter().map(|g| args.get(g).cloned()).collect::<Option<Vec<_>>>()?))
+    }
+
+    /// The type two branches produce together: the one that does not diverge
+    fn join(&self, first: Ty, second: Ty) -> Ty {
+        match self.infcx.shallow_resolve(&first) {
+            Ty::Never => second,
+            _ => first,
+        }
+    }
+
+    /// Require `found` to be usable where `expected` is wanted, reporting a
+    /// mismatch at `span`. A `&mut T` may be used where `&T` is wanted.
+    fn coerce(&mut self, found: &Ty, expected: &Ty, span: Span, cause: Cause) {
+        let result = match (self.infcx.shallow_resolve(found), self.infcx.shallow_resolve(expected)) {
+            (Ty::Ref { ty: inner, mutable: true }, Ty::Ref { ty: target, mutable: false }) => {
+                self.infcx.unify(&target, &inner)
+            }
+            _ => self.infcx.unify(expected, found),
+        };
+        if result.is_err() {
+            let (expected, found) = (self.infcx.resolve(expected), self.infcx.resolve(found));
+            let diag = self.mismatch(&expected, &found, span, cause);
+            self.diags.push(diag);
+        }
+    }
+
+    fn mismatch(&self, expected: &Ty, found: &Ty, span: Span, cause: Cause) -> Diagnostic {
+        let (e, f) = (expected.display(self.res).to_string(), found.display(self.res).to_string());
+        let diag = Diagnostic::error("mismatched types")
+            .with_code(codes::TYPE_MISMATCH)
+            .with_primary(span, format!("expected `{}`, found `{}`", e, f));
+        match cause {
+            Cause::Plain => diag,
+            Cause::Return(at) => diag.with_secondary(at, format!("expected `{}` because of return type", e)),
+            Cause::Annotation(at) => diag.with_secondary(at, "expected due to this"),
+            Cause::Argument(at) => diag.with_secondary(at, "function defined here"),
+            Cause::FirstBranch(at) => diag.with_secondary(at, format!("this is found to be of type `{}`", e)),
+        }
+    }
+}
+
+fn describe_callable(kind: DefKind) -> &'static str {
+    match kind {
+        DefKind::Variant => "enum variant",
+        _ => "function",
+    }
+}
+
+fn available_fields<'n>(names: impl Iterator<Item = &'n str>) -> Option<String> {
+    let names: Vec<String> = names.map(|n| format!("`{}`", n)).collect();
+    (!names.is_empty()).then(|| format!("available fields are: {}", names.join(", ")))
+}
+
+/// Where a block's value comes from, for pointing at it in errors
+fn block_value_span(block: &Block) -> Span {
+    block.expr.as_ref().map_or(block.span, |e| e.span)
+}
+
+/// Expressions that may be generalized when bound by `let`: evaluating
+/// them has no effects, so each use may see a different instance
+fn is_syntactic_value(expr: &Expression) -> bool {
+    match &expr.kind {
+        ExpressionKind::Literal(_) | ExpressionKind::Identifier(_) | ExpressionKind::Path(_) => true,
+        ExpressionKind::Tuple(elems) | ExpressionKind::Array(elems) => elems.iter().all(is_syntactic_value),
+        _ => false,
+    }
+}
+
+/// Whether a `break` leaves this loop body, ignoring nested loops
+fn contains_break(block: &Block) -> bool {
+    fn in_expr(expr: &Expression) -> bool {
+        match &expr.kind {
+            ExpressionKind::Break(_) => true,
+            ExpressionKind::Loop(_) | ExpressionKind::While { .. } | ExpressionKind::For { .. } => false,
+            ExpressionKind::Block(block) => contains_break(block),
+            ExpressionKind::If { cond, then_block, else_block } => {
+                in_expr(cond) || contains_break(then_block) || else_block.as_ref().is_some_and(contains_break)
+            }
+            ExpressionKind::Match { expr, arms } => in_expr(expr) || arms.iter().any(|a| in_expr(&a.body)),
+            _ => false,
+        }
+    }
+    block.stmts.iter().any(|stmt| match &stmt.kind {
+        StatementKind::Expression(expr) => in_expr(expr),
+        StatementKind::Let { init: Some(init), .. } => in_expr(init),
+        _ => false,
+    }) || block.expr.as_deref().is_some_and(in_expr)
+}
@@ -0,0 +1,801 @@
+// Item collection
+// Indexes the crate's items and lowers their declared types into signatures
+
+use crate::infer::InferCtxt;
+use crate::ty::Ty;
+use my_lang_ast::*;
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_resolve::{prelude, DefId, DefKind, Namespace, Res, Resolution};
+use std::collections::HashMap;
+
+/// Alias expansions nested deeper than this are taken to be cyclic
+const MAX_ALIAS_DEPTH: u32 = 32;
+
+/// The item an associated function or constant belongs to
+#[derive(Clone, Copy)]
+pub enum Owner<'a> {
+    Free,
+    Impl(&'a Impl),
+    Trait(&'a Trait),
+}
+
+#[derive(Clone, Copy)]
+pub struct FnItem<'a> {
+    pub func: &'a Function,
+    pub owner: Owner<'a>,
+    /// False for trait methods declared without a default body
+    pub has_body: bool,
+}
+
+/// A requirement that `ty` implements `trait_def`, from the bound written at `span`
+#[derive(Debug, Clone)]
+pub struct Predicate {
+    pub ty: Ty,
+    pub trait_def: DefId,
+    pub span: Span,
+}
+
+/// Type of a function item, generic over `generics`
+#[derive(Debug, Clone)]
+pub struct FnSig {
+    /// Parameters of the owning impl or trait, then the function's own
+    pub generics: Vec<DefId>,
+    pub inputs: Vec<Ty>,
+    pub output: Ty,
+    /// Whether the first input is a `self` parameter
+    pub has_self: bool,
+    /// Bounds every use must satisfy; a trait's functions require `Self` to implement it
+    pub predicates: Vec<Predicate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VariantKind {
+    Unit,
+    Tuple,
+    Struct,
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldTy {
+    pub name: String,
+    pub ty: Ty,
+}
+
+/// A struct, or one variant of an enum
+#[derive(Debug, Clone)]
+pub struct VariantDef {
+    pub def: DefId,
+    pub kind: VariantKind,
+    /// Tuple fields are named by position
+    pub fields: Vec<FieldTy>,
+}
+
+impl VariantDef {
+    pub fn field(&self, name: &str) -> Option<&FieldTy> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdtDef {
+    pub generics: Vec<DefId>,
+    /// A single entry for structs
+    pub variants: Vec<VariantDef>,
+}
+
+/// A function declared by a trait
+#[derive(Debug, Clone)]
+pub struct TraitFn {
+    pub name: String,
+    pub def: DefId,
+    pub has_default: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraitDef {
+    /// The trait's `Self` parameter
+    pub self_param: DefId,
+    pub fns: Vec<TraitFn>,
+    /// Associated types every impl must define
+    pub assoc_types: Vec<(String, DefId)>,
+}
+
+impl TraitDef {
+    pub fn func(&self, name: &str) -> Option<&TraitFn> {
+        self.fns.iter().find(|f| f.name == name)
+    }
+
+    pub fn assoc_type(&self, name: &str) -> Option<DefId> {
+        self.assoc_types.iter().find(|(n, _)| n == name).map(|(_, def)| *def)
+    }
+}
+
+/// An impl block with its header lowered
+pub struct ImplDef<'a> {
+    pub imp: &'a Impl,
+    /// The implemented trait; `None` for inherent impls
+    pub trait_def: Option<DefId>,
+    pub generics: Vec<DefId>,
+    pub self_ty: Ty,
+    /// Bounds on the impl's parameters, which hold wherever it applies
+    pub predicates: Vec<Predicate>,
+    /// Functions defined in the block, by name
+    pub fns: HashMap<String, DefId>,
+    /// Associated types defined in the block, by name
+    pub assoc_tys: HashMap<String, Ty>,
+}
+
+/// Every item of the crate by defining node, plus the signatures lowered from them
+pub struct Tables<'a> {
+    pub res: &'a Resolution,
+    pub functions: HashMap<NodeId, FnItem<'a>>,
+    structs: HashMap<NodeId, &'a Struct>,
+    enums: HashMap<NodeId, &'a Enum>,
+    aliases: HashMap<NodeId, &'a TypeAlias>,
+    consts: HashMap<NodeId, (&'a Const, Owner<'a>)>,
+    state_fields: HashMap<NodeId, &'a StateField>,
+    traits: HashMap<NodeId, &'a Trait>,
+    impl_nodes: Vec<&'a Impl>,
+    /// Type parameters of prelude types, which have no AST
+    prelude_generics: HashMap<DefId, Vec<DefId>>,
+
+    pub adts: HashMap<DefId, AdtDef>,
+    pub trait_defs: HashMap<DefId, TraitDef>,
+    /// Trait and inherent impls, in source order
+    pub impls: Vec<ImplDef<'a>>,
+    /// Traits each type parameter is bounded by, from its declaration and where clauses
+    pub param_bounds: HashMap<DefId, Vec<DefId>>,
+    pub sigs: HashMap<DefId, FnSig>,
+    pub const_tys: HashMap<DefId, Ty>,
+    pub state_field_tys: HashMap<DefId, Ty>,
+    /// Self type of each impl, keyed by the impl node
+    pub impl_self_tys: HashMap<NodeId, Ty>,
+    /// Lengths of arrays whose size is not a literal, keyed by the size expression
+    array_sizes: HashMap<NodeId, usize>,
+}
+
+impl<'a> Tables<'a> {
+    /// Index `programs` and lower every item signature, reporting malformed types
+    pub fn collect(
+        programs: &[&'a Program],
+        res: &'a Resolution,
+        array_sizes: HashMap<NodeId, usize>,
+        diags: &mut Vec<Diagnostic>,
+    ) -> Self {
+        let mut tables = Tables {
+            res,
+            functions: HashMap::new(),
+            structs: HashMap::new(),
+            enums: HashMap::new(),
+            aliases: HashMap::new(),
+            consts: HashMap::new(),
+            state_fields: HashMap::new(),
+            traits: HashMap::new(),
+            impl_nodes: Vec::new(),
+            prelude_generics: HashMap::new(),
+            adts: HashMap::new(),
+            trait_defs: HashMap::new(),
+            impls: Vec::new(),
+            param_bounds: HashMap::new(),
+            sigs: HashMap::new(),
+            const_tys: HashMap::new(),
+            state_field_tys: HashMap::new(),
+            impl_self_tys: HashMap::new(),
+            array_sizes,
+        };
+        for def in &res.defs {
+            if let (DefKind::TypeParam, Some(owner)) = (def.kind, def.parent) {
+                if res.def(owner).is_prelude() {
+                    tables.prelude_generics.entry(owner).or_default().push(def.id);
+                }
+            }
+        }
+        for program in programs {
+            tables.index_items(&program.items);
+        }
+        tables.lower_items(diags);
+        tables
+    }
+
+    // ========== Indexing ==========
+
+    fn index_items(&mut self, items: &'a [Item]) {
+        for item in items {
+            self.index_item(item);
+        }
+    }
+
+    fn index_item(&mut self, item: &'a Item) {
+        match &item.kind {
+            ItemKind::Function(f) => self.index_function(f, Owner::Free),
+            ItemKind::SynthFunction(sf) => self.index_function(&sf.func, Owner::Free),
+            ItemKind::VerifyFunction(vf) => self.index_function(&vf.func, Owner::Free),
+            ItemKind::Struct(s) => {
+                self.structs.insert(s.id, s);
+                self.index_generics(&s.generics, s.where_clause.as_ref());
+            }
+            ItemKind::Enum(e) => {
+                self.enums.insert(e.id, e);
+                self.index_generics(&e.generics, None);
+            }
+            ItemKind::Type(alias) => {
+                self.aliases.insert(alias.id, alias);
+            }
+            ItemKind::Const(c) => {
+                self.consts.insert(c.id, (c, Owner::Free));
+            }
+            ItemKind::Trait(t) => {
+                self.traits.insert(t.id, t);
+                self.index_generics(&t.generics, None);
+                for trait_item in &t.items {
+                    match trait_item {
+                        TraitItem::Function(f) => self.index_function(f, Owner::Trait(t)),
+                        TraitItem::Signature(f) => {
+                            self.functions.insert(f.id, FnItem { func: f, owner: Owner::Trait(t), has_body: false });
+                            self.index_generics(&f.generics, f.where_clause.as_ref());
+                        }
+                        TraitItem::Const(c) => {
+                            self.consts.insert(c.id, (c, Owner::Trait(t)));
+                        }
+                        TraitItem::Type(_) => {}
+                    }
+                }
+            }
+            ItemKind::Impl(imp) => {
+                self.impl_nodes.push(imp);
+                self.index_generics(&imp.generics, None);
+                for impl_item in &imp.items {
+                    match impl_item {
+                        ImplItem::Function(f) => self.index_function(f, Owner::Impl(imp)),
+                        ImplItem::Const(c) => {
+                            self.consts.insert(c.id, (c, Owner::Impl(imp)));
+                        }
+                        ImplItem::Type(alias) => {
+                            self.aliases.insert(alias.id, alias);
+                        }
+                    }
+                }
+            }
+            ItemKind::Module(m) => self.index_items(&m.items),
+            ItemKind::Agent(a) => {
+                for field in &a.state {
+                    self.state_fields.insert(field.id, field);
+                }
+            }
+            ItemKind::Import(_) | ItemKind::Workflow(_) | ItemKind::Comptime(_) | ItemKind::Error => {}
+        }
+    }
+
+    fn index_function(&mut self, func: &'a Function, owner: Owner<'a>) {
+        self.functions.insert(func.id, FnItem { func, owner, has_body: true });
+        self.index_generics(&func.generics, func.where_clause.as_ref());
+        self.index_block(&func.body);
+    }
+
+    /// Record the bounds of type parameters, including `T: Trait` where predicates
+    fn index_generics(&mut self, generics: &[Generic], clause: Option<&WhereClause>) {
+        let res = self.res;
+        for generic in generics {
+            if let (Some(param), Some(bounds)) = (res.def_of_node(generic.id), res.bounds.get(&generic.id)) {
+                self.param_bounds.entry(param).or_default().extend(bounds);
+            }
+        }
+        for predicate in clause.iter().flat_map(|c| &c.predicates) {
+            let Some(bounds) = res.bounds.get(&predicate.id) else { continue };
+            if let Some(Res::Def(param)) = res.res(predicate.ty.id) {
+                if res.def(*param).kind == DefKind::TypeParam {
+                    self.param_bounds.entry(*param).or_default().extend(bounds);
+                }
+            }
+        }
+    }
+
+    /// Items declared inside function bodies
+    fn index_block(&mut self, block: &'a Block) {
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StatementKind::Item(item) => self.index_item(item),
+                StatementKind::Expression(expr) => self.index_expr(expr),
+                StatementKind::Let { init: Some(init), .. } => self.index_expr(init),
+                StatementKind::Let { .. } | StatementKind::Error => {}
+            }
+        }
+        if let Some(expr) = &block.expr {
+            self.index_expr(expr);
+        }
+    }
+
+    fn index_expr(&mut self, expr: &'a Expression) {
+        match &expr.kind {
+            ExpressionKind::Block(block) | ExpressionKind::Loop(block) => self.index_block(block),
+            ExpressionKind::While { body, .. } | ExpressionKind::For { body, .. } => self.index_block(body),
+            ExpressionKind::If { then_block, else_block, .. } => {
+                self.index_block(then_block);
+                if let Some(block) = else_block {
+                    self.index_block(block);
+                }
+            }
+            ExpressionKind::Match { arms, .. } => {
+                for arm in arms {
+                    self.index_expr(&arm.body);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // ========== Signatures ==========
+
+    fn lower_items(&mut self, diags: &mut Vec<Diagnostic>) {
+        let res = self.res;
+        let def_of = |node: NodeId| res.def_of_node(node);
+
+        // Alias bodies are checked once here; uses expand them quietly
+        for alias in self.aliases.values() {
+            self.lower(&alias.ty, None, None, diags);
+        }
+
+        for (&node, s) in &self.structs {
+            let Some(def) = def_of(node) else { continue };
+            let fields = s
+                .fields
+                .iter()
+                .map(|f| FieldTy { name: f.name.clone(), ty: self.lower(&f.ty, None, None, diags) })
+                .collect();
+            let variant = VariantDef { def, kind: VariantKind::Struct, fields };
+            let adt = AdtDef { generics: self.generic_defs(&s.generics), variants: vec![variant] };
+            self.adts.insert(def, adt);
+        }
+        for (&node, e) in &self.enums {
+            let Some(def) = def_of(node) else { continue };
+            let mut variants = Vec::new();
+            for variant in &e.variants {
+                let Some(variant_def) = def_of(variant.id) else { continue };
+                let (kind, fields) = match &variant.data {
+                    VariantData::Unit => (VariantKind::Unit, Vec::new()),
+                    VariantData::Tuple(tys) => {
+                        let fields = tys
+                            .iter()
+                            .enumerate()
+                            .map(|(i, ty)| FieldTy { name: i.to_string(), ty: self.lower(ty, None, None, diags) })
+                            .collect();
+                        (VariantKind::Tuple, fields)
+                    }
+                    VariantData::Struct(fs) => {
+                        let fields = fs
+                            .iter()
+                            .map(|f| FieldTy { name: f.name.clone(), ty: self.lower(&f.ty, None, None, diags) })
+                            .collect();
+                        (VariantKind::Struct, fields)
+                    }
+                };
+                variants.push(VariantDef { def: variant_def, kind, fields });
+            }
+            self.adts.insert(def, AdtDef { generics: self.generic_defs(&e.generics), variants });
+        }
+        self.add_prelude_enums();
+
+        let traits: Vec<&'a Trait> = self.traits.values().copied().collect();
+        for t in traits {
+            let (Some(def), Some(self_param)) = (def_of(t.id), self.trait_self(t)) else { continue };
+            self.param_bounds.entry(self_param).or_default().push(def);
+            let mut trait_def = TraitDef { self_param, fns: Vec::new(), assoc_types: Vec::new() };
+            for trait_item in &t.items {
+                match trait_item {
+                    TraitItem::Function(f) | TraitItem::Signature(f) => {
+                        let Some(fn_def) = def_of(f.id) else { continue };
+                        let has_default = matches!(trait_item, TraitItem::Function(_));
+                        trait_def.fns.push(TraitFn { name: f.name.clone(), def: fn_def, has_default });
+                    }
+                    TraitItem::Type(name) => {
+                        if let Some(item) = res.member(def, Namespace::Type, name) {
+                            trait_def.assoc_types.push((name.clone(), item));
+                        }
+                    }
+                    TraitItem::Const(_) => {}
+                }
+            }
+            self.trait_defs.insert(def, trait_def);
+        }
+
+        for imp in &self.impl_nodes {
+            let self_ty = self.lower(&imp.self_ty, None, None, diags);
+            self.impl_self_tys.insert(imp.id, self_ty);
+        }
+
+        for item in self.functions.values() {
+            let Some(def) = def_of(item.func.id) else { continue };
+            let sig = self.lower_sig(item, diags);
+            self.sigs.insert(def, sig);
+        }
+
+        let impl_nodes = self.impl_nodes.clone();
+        for imp in impl_nodes {
+            let impl_def = self.lower_impl(imp);
+            self.impls.push(impl_def);
+        }
+        for (&node, (c, owner)) in &self.consts {
+            let Some(def) = def_of(node) else { continue };
+            let self_ty = self.owner_self_ty(*owner);
+            let ty = self.lower(&c.ty, self_ty.as_ref(), None, diags);
+            self.const_tys.insert(def, ty);
+        }
+        for (&node, field) in &self.state_fields {
+            let Some(def) = def_of(node) else { continue };
+            let ty = self.lower(&field.ty, None, None, diags);
+            self.state_field_tys.insert(def, ty);
+        }
+    }
+
+    fn add_prelude_enums(&mut self) {
+        let res = self.res;
+        for prelude_enum in prelude::ENUMS {
+            let found = res.defs.iter().find(|d| d.is_prelude() && d.kind == DefKind::Enum && d.name == prelude_enum.name);
+            let Some(def) = found.map(|d| d.id) else {
+                continue;
+            };
+            let generics = self.prelude_generics.get(&def).cloned().unwrap_or_default();
+            let param = |name: &str| {
+                let index = prelude_enum.params.iter().position(|p| *p == name).expect("declared parameter");
+                Ty::Param(generics[index])
+            };
+            let variants = prelude_enum
+                .variants
+                .iter()
+                .filter_map(|(name, holds)| {
+                    let variant = res.member(def, Namespace::Value, name)?;
+                    let kind = if holds.is_empty() { VariantKind::Unit } else { VariantKind::Tuple };
+                    let fields =
+                        holds.iter().enumerate().map(|(i, p)| FieldTy { name: i.to_string(), ty: param(p) }).collect();
+                    Some(VariantDef { def: variant, kind, fields })
+                })
+                .collect();
+            self.adts.insert(def, AdtDef { generics, variants });
+        }
+    }
+
+    fn lower_sig(&self, item: &FnItem<'a>, diags: &mut Vec<Diagnostic>) -> FnSig {
+        let func = item.func;
+        let self_ty = self.owner_self_ty(item.owner);
+        let (mut generics, mut predicates) = match item.owner {
+            Owner::Free => (Vec::new(), Vec::new()),
+            Owner::Impl(imp) => (self.generic_defs(&imp.generics), self.bound_predicates(&imp.generics)),
+            Owner::Trait(t) => {
+                let mut generics: Vec<DefId> = self.trait_self(t).into_iter().collect();
+                let mut predicates = Vec::new();
+                if let (Some(&self_param), Some(trait_def)) = (generics.first(), self.res.def_of_node(t.id)) {
+                    predicates.push(Predicate { ty: Ty::Param(self_param), trait_def, span: t.span });
+                }
+                generics.extend(self.generic_defs(&t.generics));
+                predicates.extend(self.bound_predicates(&t.generics));
+                (generics, predicates)
+            }
+        };
+        generics.extend(self.generic_defs(&func.generics));
+        predicates.extend(self.bound_predicates(&func.generics));
+        for predicate in func.where_clause.iter().flat_map(|c| &c.predicates) {
+            let ty = self.lower(&predicate.ty, self_ty.as_ref(), None, diags);
+            for &trait_def in self.res.bounds.get(&predicate.id).into_iter().flatten() {
+                predicates.push(Predicate { ty: ty.clone(), trait_def, span: predicate.span });
+            }
+        }
+        let inputs = func.params.iter().map(|p| self.lower(&p.ty, self_ty.as_ref(), None, diags)).collect();
+        let output = match &func.return_type {
+            Some(ty) => self.lower(ty, self_ty.as_ref(), None, diags),
+            None => Ty::unit(),
+        };
+        let has_self = func.params.first().is_some_and(|p| p.name == "self");
+        FnSig { generics, inputs, output, has_self, predicates }
+    }
+
+    fn bound_predicates(&self, generics: &[Generic]) -> Vec<Predicate> {
+        let mut predicates = Vec::new();
+        for generic in generics {
+            let Some(param) = self.res.def_of_node(generic.id) else { continue };
+            for &trait_def in self.res.bounds.get(&generic.id).into_iter().flatten() {
+                predicates.push(Predicate { ty: Ty::Param(param), trait_def, span: generic.span });
+            }
+        }
+        predicates
+    }
+
+    fn lower_impl(&self, imp: &'a Impl) -> ImplDef<'a> {
+        let self_ty = self.impl_self_tys.get(&imp.id).cloned().unwrap_or(Ty::Error);
+        let trait_def = match self.res.res(imp.id) {
+            Some(Res::Def(def)) => Some(*def),
+            _ => None,
+        };
+        let mut fns = HashMap::new();
+        let mut assoc_tys = HashMap::new();
+        for impl_item in &imp.items {
+            match impl_item {
+                ImplItem::Function(f) => {
+                    if let Some(def) = self.res.def_of_node(f.id) {
+                        fns.insert(f.name.clone(), def);
+                    }
+                }
+                // Already reported when the alias bodies were lowered
+                ImplItem::Type(alias) => {
+                    let ty = self.lower(&alias.ty, Some(&self_ty), None, &mut Vec::new());
+                    assoc_tys.insert(alias.name.clone(), ty);
+                }
+                ImplItem::Const(_) => {}
+            }
+        }
+        ImplDef {
+            imp,
+            trait_def,
+            generics: self.generic_defs(&imp.generics),
+            self_ty,
+            predicates: self.bound_predicates(&imp.generics),
+            fns,
+            assoc_tys,
+        }
+    }
+
+    /// Every constant, with the item it belongs to
+    pub fn consts(&self) -> impl Iterator<Item = (&'a Const, Owner<'a>)> + '_ {
+        self.consts.values().copied()
+    }
+
+    /// Size expressions of arrays in item signatures, in source order
+    pub fn signature_array_sizes(&self) -> Vec<&'a Expression> {
+        let mut types: Vec<&'a Type> = Vec::new();
+        for s in self.structs.values() {
+            types.extend(s.fields.iter().map(|f| &f.ty));
+        }
+        for variant in self.enums.values().flat_map(|e| &e.variants) {
+            match &variant.data {
+                VariantData::Unit => {}
+                VariantData::Tuple(tys) => types.extend(tys),
+                VariantData::Struct(fields) => types.extend(fields.iter().map(|f| &f.ty)),
+            }
+        }
+        for item in self.functions.values() {
+            types.extend(item.func.params.iter().map(|p| &p.ty));
+            types.extend(&item.func.return_type);
+        }
+        types.extend(self.aliases.values().map(|alias| &alias.ty));
+        types.extend(self.consts.values().map(|(c, _)| &c.ty));
+        types.extend(self.state_fields.values().map(|field| &field.ty));
+        types.extend(self.impl_nodes.iter().map(|imp| &imp.self_ty));
+
+        let mut sizes = Vec::new();
+        for ty in types {
+            array_sizes(ty, &mut sizes);
+        }
+        sizes.sort_by_key(|size| size.id);
+        sizes
+    }
+
+    /// What `Self` means inside the items of `owner`
+    pub fn owner_self_ty(&self, owner: Owner<'a>) -> Option<Ty> {
+        match owner {
+            Owner::Free => None,
+            Owner::Impl(imp) => self.impl_self_tys.get(&imp.id).cloned(),
+            Owner::Trait(t) => self.trait_self(t).map(Ty::Param),
+        }
+    }
+
+    fn trait_self(&self, t: &Trait) -> Option<DefId> {
+        let scope = self.res.node_scopes.get(&t.id)?;
+        self.res.scope(*scope).get(Namespace::Type, "Self")
+    }
+
+    fn generic_defs(&self, generics: &[Generic]) -> Vec<DefId> {
+        generics.iter().filter_map(|g| self.res.def_of_node(g.id)).collect()
+    }
+
+    /// Type parameters of a struct, enum or alias definition
+    pub fn generics_of(&self, def: DefId) -> Vec<DefId> {
+        if let Some(adt) = self.adts.get(&def) {
+            return adt.generics.clone();
+        }
+        if let Some(generics) = self.prelude_generics.get(&def) {
+            return generics.clone();
+        }
+        let node = self.res.def(def).node;
+        if let Some(s) = self.structs.get(&node) {
+            return self.generic_defs(&s.generics);
+        }
+        if let Some(e) = self.enums.get(&node) {
+            return self.generic_defs(&e.generics);
+        }
+        if let Some(alias) = self.aliases.get(&node) {
+            return self.generic_defs(&alias.generics);
+        }
+        Vec::new()
+    }
+
+    /// The struct or variant `def`, with the definition of its type
+    pub fn variant(&self, def: DefId) -> Option<(DefId, &VariantDef)> {
+        let owner = match self.res.def(def).kind {
+            DefKind::Struct => def,
+            DefKind::Variant => self.res.def(def).parent?,
+            _ => return None,
+        };
+        let variant = self.adts.get(&owner)?.variants.iter().find(|v| v.def == def)?;
+        Some((owner, variant))
+    }
+
+    // ========== Lowering ==========
+
+    /// Lower a written type. `self_ty` replaces `Self`; with an inference
+    /// context, `_` and omitted type arguments become fresh variables.
+    pub fn lower(
+        &self,
+        ty: &Type,
+        self_ty: Option<&Ty>,
+        infcx: Option<&mut InferCtxt>,
+        diags: &mut Vec<Diagnostic>,
+    ) -> Ty {
+        let mut cx = LowerCx { self_ty, infcx, depth: 0 };
+        self.lower_in(ty, &mut cx, diags)
+    }
+
+    fn lower_in(&self, ty: &Type, cx: &mut LowerCx<'_, '_>, diags: &mut Vec<Diagnostic>) -> Ty {
+        match &ty.kind {
+            TypeKind::Primitive(prim) => Ty::from_primitive(*prim),
+            TypeKind::Named(name) if name == "Self" && cx.self_ty.is_some() => {
+                cx.self_ty.cloned().expect("checked above")
+            }
+            TypeKind::Named(_) => self.lower_path(ty, &[], cx, diags),
+            TypeKind::Generic { args, .. } | TypeKind::Path { args, .. } => self.lower_path(ty, args, cx, diags),
+            TypeKind::Tuple(elems) => Ty::Tuple(elems.iter().map(|t| self.lower_in(t, cx, diags)).collect()),
+            TypeKind::Array { elem, size } => {
+                let len = size.as_deref().and_then(|size| match size.kind {
+                    ExpressionKind::Literal(Literal::Int(n)) => usize::try_from(n).ok(),
+                    _ => self.array_sizes.get(&size.id).copied(),
+                });
+                Ty::Array(Box::new(self.lower_in(elem, cx, diags)), len)
+            }
+            TypeKind::Reference { ty, is_mut, .. } => {
+                Ty::Ref { ty: Box::new(self.lower_in(ty, cx, diags)), mutable: *is_mut }
+            }
+            TypeKind::Function { params, ret } => Ty::Fn(
+                params.iter().map(|t| self.lower_in(t, cx, diags)).collect(),
+                Box::new(self.lower_in(ret, cx, diags)),
+            ),
+            // Affinity and fuzziness are tracked by their own passes
+            TypeKind::Affine(inner) | TypeKind::Fuzzy(inner) => self.lower_in(inner, cx, diags),
+            TypeKind::Inferred | TypeKind::Learned(_) => cx.fresh(),
+        }
+    }
+
+    fn lower_path(&self, ty: &Type, args: &[Type], cx: &mut LowerCx<'_, '_>, diags: &mut Vec<Diagnostic>) -> Ty {
+        let def = match self.res.res(ty.id) {
+            Some(Res::Def(def)) => *def,
+            Some(Res::Partial { base, rest }) => return self.lower_projection(*base, rest, cx),
+            None => return Ty::Error,
+        };
+        let definition = self.res.def(def);
+        match definition.kind {
+            DefKind::Struct | DefKind::Enum | DefKind::External | DefKind::Agent => {
+                if definition.is_prelude() && definition.name == "String" {
+                    return Ty::Prim(PrimitiveType::Str);
+                }
+                let args: Vec<Ty> = args.iter().map(|t| self.lower_in(t, cx, diags)).collect();
+                if definition.kind == DefKind::External {
+                    return Ty::Adt(def, args);
+                }
+                let expected = self.generics_of(def).len();
+                match self.type_args(def, args, expected, ty, cx, diags) {
+                    Some(args) => Ty::Adt(def, args),
+                    None => Ty::Error,
+                }
+            }
+            DefKind::TypeParam => Ty::Param(def),
+            DefKind::SelfType => cx.self_ty.cloned().unwrap_or(Ty::Param(def)),
+            // An impl's associated type expands like an alias; a trait's has no body
+            DefKind::TypeAlias | DefKind::AssocType => {
+                let Some(alias) = self.aliases.get(&definition.node) else {
+                    return cx.fresh();
+                };
+                if cx.depth >= MAX_ALIAS_DEPTH {
+                    return Ty::Error;
+                }
+                let args: Vec<Ty> = args.iter().map(|t| self.lower_in(t, cx, diags)).collect();
+                let generics = self.generic_defs(&alias.generics);
+                let Some(args) = self.type_args(def, args, generics.len(), ty, cx, diags) else {
+                    return Ty::Error;
+                };
+                cx.depth += 1;
+                let expanded = self.lower_in(&alias.ty, cx, &mut Vec::new());
+                cx.depth -= 1;
+                expanded.subst(&generics.into_iter().zip(args).collect())
+            }
+            _ => Ty::Error,
+        }
+    }
+
+    /// `T::Item` or `Self::Item`: an associated type of a trait the base is
+    /// bounded by, or of the one trait declaring it when the base is concrete
+    fn lower_projection(&self, base: DefId, rest: &[String], cx: &mut LowerCx<'_, '_>) -> Ty {
+        let [name] = rest else {
+            return cx.fresh();
+        };
+        let self_ty = match self.res.def(base).kind {
+            DefKind::TypeParam => Ty::Param(base),
+            DefKind::SelfType => cx.self_ty.cloned().unwrap_or(Ty::Param(base)),
+            _ => return cx.fresh(),
+        };
+        let declaring: Vec<DefId> = match &self_ty {
+            Ty::Param(param) => self.param_bounds.get(param).into_iter().flatten().copied().collect(),
+            _ => self.trait_defs.keys().copied().collect(),
+        };
+        let mut items = declaring.iter().filter_map(|t| self.trait_defs.get(t)?.assoc_type(name));
+        match (items.next(), items.next()) {
+            (Some(item), None) => Ty::Assoc { self_ty: Box::new(self_ty), item },
+            _ => cx.fresh(),
+        }
+    }
+
+    /// Check the number of type arguments given to `def`
+    fn type_args(
+        &self,
+        def: DefId,
+        args: Vec<Ty>,
+        expected: usize,
+        ty: &Type,
+        cx: &mut LowerCx<'_, '_>,
+        diags: &mut Vec<Diagnostic>,
+    ) -> Option<Vec<Ty>> {
+        if args.len() == expected {
+            return Some(args);
+        }
+        if args.is_empty() && cx.infcx.is_some() {
+            return Some((0..expected).map(|_| cx.fresh()).collect());
+        }
+        let definition = self.res.def(def);
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        diags.push(
+            Diagnostic::error(format!(
+                "{} `{}` takes {} type argument{} but {} {} supplied",
+                definition.kind.describe(),
+                definition.name,
+                expected,
+                plural(expected),
+                args.len(),
+                if args.len() == 1 { "was" } else { "were" }
+            ))
+            .with_code(codes::WRONG_TYPE_ARGUMENTS)
+            .with_primary(ty.span, format!("expected {} type argument{}", expected, plural(expected))),
+        );
+        None
+    }
+}
+
+/// Collect the size expressions of arrays anywhere in `ty`
+pub fn array_sizes<'t>(ty: &'t Type, sizes: &mut Vec<&'t Expression>) {
+    match &ty.kind {
+        TypeKind::Array { elem, size } => {
+            sizes.extend(size.as_deref());
+            array_sizes(elem, sizes);
+        }
+        TypeKind::Generic { args: tys, .. } | TypeKind::Path { args: tys, .. } | TypeKind::Tuple(tys) => {
+            tys.iter().for_each(|t| array_sizes(t, sizes));
+        }
+        TypeKind::Reference { ty, .. } | TypeKind::Affine(ty) | TypeKind::Fuzzy(ty) => array_sizes(ty, sizes),
+        TypeKind::Function { params, ret } => {
+            params.iter().for_each(|t| array_sizes(t, sizes));
+            array_sizes(ret, sizes);
+        }
+        TypeKind::Primitive(_) | TypeKind::Named(_) | TypeKind::Inferred | TypeKind::Learned(_) => {}
+    }
+}
+
+struct LowerCx<'t, 'i> {
+    self_ty: Option<&'t Ty>,
+    infcx: Option<&'i mut InferCtxt>,
+    depth: u32,
+}
+
+impl LowerCx<'_, '_> {
+    /// A fresh variable where inference is possible, an error type elsewhere
+    fn fresh(&mut self) -> Ty {
+        match self.infcx.as_deref_mut() {
+            Some(infcx) => infcx.new_var(),
+            None => Ty::Error,
+        }
+    }
+}
@@ -0,0 +1,379 @@
+// Match checking
+// Exhaustiveness and reachability of `match` arms, decided over a pattern matrix
+
+use crate::collect::{Tables, VariantKind};
+use crate::ty::Ty;
+use my_lang_ast::{Literal, Pattern, PatternKind, PrimitiveType, Span};
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_resolve::{DefId, DefKind, Res};
+use std::collections::HashMap;
+
+/// Missing patterns named in a non-exhaustive match error before the rest are counted
+const SHOWN_WITNESSES: usize = 3;
+
+/// The head of a pattern, compared by equality
+#[derive(Debug, Clone, PartialEq)]
+enum Ctor {
+    /// A struct or enum variant
+    Variant(DefId),
+    /// A tuple, including `()`
+    Tuple,
+    Bool(bool),
+    Int(i64),
+    /// A float literal, by its bits
+    Float(u64),
+    Char(char),
+    Str(String),
+    /// A constant: matches some value, but no other pattern can be proven to cover it
+    Opaque,
+}
+
+/// A pattern with its bindings erased
+#[derive(Debug, Clone)]
+enum Pat {
+    Wild,
+    Ctor(Ctor, Vec<Pat>),
+}
+
+impl Pat {
+    fn head(&self) -> Option<&Ctor> {
+        match self {
+            Pat::Ctor(ctor, _) if *ctor != Ctor::Opaque => Some(ctor),
+            _ => None,
+        }
+    }
+}
+
+/// A `match` to check once its types are resolved
+#[derive(Debug)]
+pub struct MatchCheck {
+    pub scrutinee: Ty,
+    pub scrutinee_span: Span,
+    /// Each arm's pattern, and whether it has a guard
+    pub arms: Vec<(Pattern, bool)>,
+}
+
+/// Report arms no value reaches, then the values no arm matches
+pub fn check_match(tables: &Tables, check: &MatchCheck, diags: &mut Vec<Diagnostic>) {
+    let cx = MatchCx { tables };
+    let ty = [check.scrutinee.clone()];
+    let mut rows: Vec<Vec<Pat>> = Vec::new();
+    let mut catch_all: Option<Span> = None;
+    for (pattern, guarded) in &check.arms {
+        let row = vec![cx.lower(pattern, &check.scrutinee)];
+        if !cx.is_useful(&rows, &row, &ty) {
+            let mut diag = Diagnostic::warning("unreachable pattern")
+                .with_code(codes::UNREACHABLE_PATTERN)
+                .with_primary(pattern.span, "no value reaches this arm");
+            if let Some(span) = catch_all {
+                diag = diag.with_secondary(span, "matches any value");
+            }
+            diags.push(diag);
+        }
+        // A guarded arm may decline any value it matches
+        if !guarded {
+            if catch_all.is_none() && matches!(row[0], Pat::Wild) {
+                catch_all = Some(pattern.span);
+            }
+            rows.push(row);
+        }
+    }
+
+    let missing: Vec<String> = cx.missing(&rows, &ty).iter().map(|w| cx.display(&w[0])).collect();
+    if missing.is_empty() {
+        return;
+    }
+    let listed = list_witnesses(&missing);
+    let noun = if missing.len() == 1 { "pattern" } else { "patterns" };
+    diags.push(
+        Diagnostic::error(format!("non-exhaustive patterns: {} not covered", listed))
+            .with_code(codes::NON_EXHAUSTIVE_MATCH)
+            .with_primary(check.scrutinee_span, format!("{} {} not covered", noun, listed))
+            .with_note(format!("the matched value is of type `{}`", check.scrutinee.display(tables.res)))
+            .with_note("ensure every case is handled, by adding arms or a wildcard `_` arm"),
+    );
+}
+
+/// `` `A`, `B` and `C` ``, or `` `A`, `B`, `C` and 2 more ``
+fn list_witnesses(missing: &[String]) -> String {
+    let quoted: Vec<String> = missing.iter().take(SHOWN_WITNESSES).map(|w| format!("`{}`", w)).collect();
+    if missing.len() > SHOWN_WITNESSES {
+        return format!("{} and {} more", quoted.join(", "), missing.len() - SHOWN_WITNESSES);
+    }
+    match quoted.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+        _ => quoted.join(""),
+    }
+}
+
+struct MatchCx<'t, 'a> {
+    tables: &'t Tables<'a>,
+}
+
+impl MatchCx<'_, '_> {
+    // ========== Lowering ==========
+
+    /// Erase bindings from `pat`, a pattern matching values of type `ty`
+    fn lower(&self, pat: &Pattern, ty: &Ty) -> Pat {
+        let ty = peel_refs(ty);
+        match &pat.kind {
+            PatternKind::Wildcard => Pat::Wild,
+            PatternKind::Identifier(_) | PatternKind::Path(_) => match self.tables.res.res(pat.id) {
+                Some(Res::Def(def)) => match self.tables.res.def(*def).kind {
+                    DefKind::Struct | DefKind::Variant => Pat::Ctor(Ctor::Variant(*def), Vec::new()),
+                    _ => Pat::Ctor(Ctor::Opaque, Vec::new()),
+                },
+                // A fresh binding, or a path that failed to resolve
+                _ => Pat::Wild,
+            },
+            PatternKind::Literal(lit) => Pat::Ctor(
+                match lit {
+                    Literal::Bool(b) => Ctor::Bool(*b),
+                    Literal::Int(i) => Ctor::Int(*i),
+                    Literal::Float(f) => Ctor::Float(f.to_bits()),
+                    Literal::Char(c) => Ctor::Char(*c),
+                    Literal::String(s) => Ctor::Str(s.clone()),
+                    Literal::Unit => Ctor::Tuple,
+                },
+                Vec::new(),
+            ),
+            PatternKind::Tuple(elems) => {
+                let tys = self.field_tys(&Ctor::Tuple, ty, Some(elems.len()));
+                Pat::Ctor(Ctor::Tuple, elems.iter().zip(&tys).map(|(p, t)| self.lower(p, t)).collect())
+            }
+            PatternKind::TupleStruct { elems, .. } => {
+                let Some(def) = self.variant_of(pat) else {
+                    return Pat::Wild;
+                };
+                let ctor = Ctor::Variant(def);
+                let tys = self.field_tys(&ctor, ty, None);
+                let mut fields: Vec<Pat> = elems.iter().zip(&tys).map(|(p, t)| self.lower(p, t)).collect();
+                fields.resize(tys.len(), Pat::Wild);
+                Pat::Ctor(ctor, fields)
+            }
+            PatternKind::Struct { fields: written, .. } => {
+                let Some(def) = self.variant_of(pat) else {
+                    return Pat::Wild;
+                };
+                let Some((_, variant)) = self.tables.variant(def) else {
+                    return Pat::Wild;
+                };
+                let ctor = Ctor::Variant(def);
+                let tys = self.field_tys(&ctor, ty, None);
+                let fields = variant
+                    .fields
+                    .iter()
+                    .zip(&tys)
+                    .map(|(field, t)| match written.iter().find(|(name, _)| *name == field.name) {
+                        Some((_, sub)) => self.lower(sub, t),
+                        None => Pat::Wild,
+                    })
+                    .collect();
+                Pat::Ctor(ctor, fields)
+            }
+        }
+    }
+
+    fn variant_of(&self, pat: &Pattern) -> Option<DefId> {
+        match self.tables.res.res(pat.id) {
+            Some(Res::Def(def)) if self.tables.variant(*def).is_some() => Some(*def),
+            _ => None,
+        }
+    }
+
+    // ========== Constructors ==========
+
+    /// Every constructor of `ty`, or `None` when it has too many to list
+    fn ctors(&self, ty: &Ty) -> Option<Vec<Ctor>> {
+        match peel_refs(ty) {
+            Ty::Prim(PrimitiveType::Bool) => Some(vec![Ctor::Bool(true), Ctor::Bool(false)]),
+            Ty::Tuple(_) => Some(vec![Ctor::Tuple]),
+            Ty::Adt(def, _) => {
+                let adt = self.tables.adts.get(def)?;
+                Some(adt.variants.iter().map(|v| Ctor::Variant(v.def)).collect())
+            }
+            Ty::Never => Some(Vec::new()),
+            _ => None,
+        }
+    }
+
+    /// Types of the fields `ctor` has in `ty`, padded with errors to `arity` where given
+    fn field_tys(&self, ctor: &Ctor, ty: &Ty, arity: Option<usize>) -> Vec<Ty> {
+        let mut tys = match (ctor, peel_refs(ty)) {
+            (Ctor::Tuple, Ty::Tuple(elems)) => elems.clone(),
+            (Ctor::Variant(def), ty) => match self.tables.variant(*def) {
+                Some((adt, variant)) => {
+                    let args: HashMap<DefId, Ty> = match ty {
+                        Ty::Adt(owner, args) if *owner == adt => {
+                            let generics = &self.tables.adts[&adt].generics;
+                            generics.iter().copied().zip(args.iter().cloned()).collect()
+                        }
+                        _ => HashMap::new(),
+                    };
+                    variant.fields.iter().map(|f| f.ty.subst(&args)).collect()
+                }
+                None => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        if let Some(arity) = arity {
+            tys.resize(arity, Ty::Error);
+        }
+        tys
+    }
+
+    /// The rest of `row` if its head may match `ctor`, with the head's fields spliced in
+    fn specialize(&self, row: &[Pat], ctor: &Ctor, arity: usize) -> Option<Vec<Pat>> {
+        let (head, rest) = row.split_first()?;
+        let mut fields = match head {
+            Pat::Wild => vec![Pat::Wild; arity],
+            Pat::Ctor(c, fields) if c == ctor && *c != Ctor::Opaque => fields.clone(),
+            Pat::Ctor(..) => return None,
+        };
+        fields.resize(arity, Pat::Wild);
+        fields.extend_from_slice(rest);
+        Some(fields)
+    }
+
+    /// Rows whose head matches anything, without it
+    fn default_rows(&self, rows: &[Vec<Pat>]) -> Vec<Vec<Pat>> {
+        rows.iter().filter(|row| matches!(row[0], Pat::Wild)).map(|row| row[1..].to_vec()).collect()
+    }
+
+    /// Every constructor of `ty`, if the heads of `rows` mention them all
+    fn covers_all(&self, rows: &[Vec<Pat>], ty: &Ty) -> Option<Vec<Ctor>> {
+        let all = self.ctors(ty)?;
+        let used: Vec<&Ctor> = rows.iter().filter_map(|row| row[0].head()).collect();
+        all.iter().all(|c| used.contains(&c)).then_some(all)
+    }
+
+    // ========== Usefulness ==========
+
+    /// Whether some value matched by `q` is matched by no row of `rows`
+    fn is_useful(&self, rows: &[Vec<Pat>], q: &[Pat], tys: &[Ty]) -> bool {
+        let Some((head, rest)) = q.split_first() else {
+            return rows.is_empty();
+        };
+        let useful_for = |ctor: &Ctor, fields: Vec<Pat>| {
+            let mut field_tys = self.field_tys(ctor, &tys[0], Some(fields.len()));
+            field_tys.extend_from_slice(&tys[1..]);
+            let rows: Vec<Vec<Pat>> = rows.iter().filter_map(|row| self.specialize(row, ctor, fields.len())).collect();
+            let q: Vec<Pat> = fields.into_iter().chain(rest.iter().cloned()).collect();
+            self.is_useful(&rows, &q, &field_tys)
+        };
+        match head.head() {
+            Some(ctor) => {
+                let Pat::Ctor(_, fields) = head else { unreachable!() };
+                useful_for(ctor, fields.clone())
+            }
+            None => match self.covers_all(rows, &tys[0]) {
+                Some(all) => all.iter().any(|ctor| {
+                    let arity = self.field_tys(ctor, &tys[0], None).len();
+                    useful_for(ctor, vec![Pat::Wild; arity])
+                }),
+                None => self.is_useful(&self.default_rows(rows), rest, &tys[1..]),
+            },
+        }
+    }
+
+    /// Example values, one pattern per column, that no row of `rows` matches
+    fn missing(&self, rows: &[Vec<Pat>], tys: &[Ty]) -> Vec<Vec<Pat>> {
+        let Some((ty, rest_tys)) = tys.split_first() else {
+            return if rows.is_empty() { vec![Vec::new()] } else { Vec::new() };
+        };
+        if let Some(all) = self.covers_all(rows, ty) {
+            let mut witnesses = Vec::new();
+            for ctor in all {
+                let mut field_tys = self.field_tys(&ctor, ty, None);
+                let arity = field_tys.len();
+                field_tys.extend_from_slice(rest_tys);
+                let rows: Vec<Vec<Pat>> = rows.iter().filter_map(|row| self.specialize(row, &ctor, arity)).collect();
+                for mut witness in self.missing(&rows, &field_tys) {
+                    let rest = witness.split_off(arity);
+                    witnesses.push(std::iter::once(Pat::Ctor(ctor.clone(), witness)).chain(rest).collect());
+                }
+            }
+            return witnesses;
+        }
+
+        let rest = self.missing(&self.default_rows(rows), rest_tys);
+        if rest.is_empty() {
+            return rest;
+        }
+        // Name the constructors no row mentions, unless the type has only one
+        let used: Vec<&Ctor> = rows.iter().filter_map(|row| row[0].head()).collect();
+        let heads = match self.ctors(ty) {
+            Some(all) if !used.is_empty() || all.len() > 1 => all
+                .into_iter()
+                .filter(|c| !used.contains(&c))
+                .map(|c| {
+                    let arity = self.field_tys(&c, ty, None).len();
+                    Pat::Ctor(c, vec![Pat::Wild; arity])
+                })
+                .collect(),
+            _ => vec![Pat::Wild],
+        };
+        heads
+            .iter()
+            .flat_map(|head| rest.iter().map(move |r| std::iter::once(head.clone()).chain(r.iter().cloned()).collect()))
+            .collect()
+    }
+
+    // ========== Display ==========
+
+    fn display(&self, pat: &Pat) -> String {
+        let (ctor, fields) = match pat {
+            Pat::Wild => return "_".to_string(),
+            Pat::Ctor(ctor, fields) => (ctor, fields),
+        };
+        let list = |fields: &[Pat]| fields.iter().map(|f| self.display(f)).collect::<Vec<_>>().join(", ");
+        match ctor {
+            Ctor::Tuple if fields.len() == 1 => format!("({},)", self.display(&fields[0])),
+            Ctor::Tuple => format!("({})", list(fields)),
+            Ctor::Bool(b) => b.to_string(),
+            Ctor::Int(i) => i.to_string(),
+            Ctor::Float(bits) => f64::from_bits(*bits).to_string(),
+            Ctor::Char(c) => format!("{:?}", c),
+            Ctor::Str(s) => format!("{:?}", s),
+            Ctor::Opaque => "_".to_string(),
+            Ctor::Variant(def) => {
+                let path = self.variant_path(*def);
+                let Some((_, variant)) = self.tables.variant(*def) else {
+                    return path;
+                };
+                match variant.kind {
+                    VariantKind::Unit => path,
+                    VariantKind::Tuple => format!("{}({})", path, list(fields)),
+                    VariantKind::Struct => {
+                        let fields: Vec<String> = variant
+                            .fields
+                            .iter()
+                            .zip(fields)
+                            .map(|(field, pat)| format!("{}: {}", field.name, self.display(pat)))
+                            .collect();
+                        format!("{} {{ {} }}", path, fields.join(", "))
+                    }
+                }
+            }
+        }
+    }
+
+    /// `Enum::Variant`, or just the variant for prelude enums such as `Option`
+    fn variant_path(&self, def: DefId) -> String {
+        let res = self.tables.res;
+        let variant = res.def(def);
+        match variant.parent {
+            Some(parent) if variant.kind == DefKind::Variant && !res.def(parent).is_prelude() => {
+                format!("{}::{}", res.def(parent).name, variant.name)
+            }
+            _ => variant.name.clone(),
+        }
+    }
+}
+
+fn peel_refs(mut ty: &Ty) -> &Ty {
+    while let Ty::Ref { ty: inner, .. } = ty {
+        ty = inner;
+    }
+    ty
+}
@@ -0,0 +1,178 @@
+// Unification
+// Inference variables, their bindings, and the schemes that make `let` polymorphic
+
+use crate::ty::{InferTy, Ty};
+use my_lang_ast::PrimitiveType;
+use std::collections::{HashMap, HashSet};
+
+/// A type generalized over some of its variables; each use instantiates
+/// them afresh
+#[derive(Debug, Clone)]
+pub struct Scheme {
+    pub vars: Vec<u32>,
+    pub ty: Ty,
+}
+
+impl Scheme {
+    pub fn mono(ty: Ty) -> Self {
+        Self { vars: Vec::new(), ty }
+    }
+}
+
+/// Two types that could not be made equal
+#[derive(Debug)]
+pub struct Mismatch;
+
+#[derive(Debug, Default)]
+pub struct InferCtxt {
+    /// Each variable as created, with its binding once known
+    vars: Vec<(InferTy, Option<Ty>)>,
+}
+
+impl InferCtxt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn fresh(&mut self, kind: fn(u32) -> InferTy) -> Ty {
+        let var = kind(self.vars.len() as u32);
+        self.vars.push((var, None));
+        Ty::Infer(var)
+    }
+
+    pub fn new_var(&mut self) -> Ty {
+        self.fresh(InferTy::Var)
+    }
+
+    pub fn new_int_var(&mut self) -> Ty {
+        self.fresh(InferTy::Int)
+    }
+
+    pub fn new_float_var(&mut self) -> Ty {
+        self.fresh(InferTy::Float)
+    }
+
+    /// Follow variable bindings at the top of `ty` only
+    pub fn shallow_resolve(&self, ty: &Ty) -> Ty {
+        let mut ty = ty.clone();
+        while let Ty::Infer(var) = ty {
+            match &self.vars[var.index() as usize].1 {
+                Some(bound) => ty = bound.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// Substitute every bound variable in `ty`
+    pub fn resolve(&self, ty: &Ty) -> Ty {
+        ty.fold(&mut |t| match t {
+            Ty::Infer(var) => self.vars[var.index() as usize].1.as_ref().map(|bound| self.resolve(bound)),
+            _ => None,
+        })
+    }
+
+    /// Make `a` and `b` equal, binding variables as needed. `!` and error
+    /// types unify with anything without binding.
+    pub fn unify(&mut self, a: &Ty, b: &Ty) -> Result<(), Mismatch> {
+        let a = self.shallow_resolve(a);
+        let b = self.shallow_resolve(b);
+        match (&a, &b) {
+            (Ty::Error, _) | (_, Ty::Error) | (Ty::Never, _) | (_, Ty::Never) => Ok(()),
+            (Ty::Infer(x), Ty::Infer(y)) if x == y => Ok(()),
+            (Ty::Infer(x), Ty::Infer(y)) => {
+                // Keep the more specific kind of the two
+                match (x, y) {
+                    (InferTy::Var(_), _) => self.bind(*x, b.clone()),
+                    (_, InferTy::Var(_)) => self.bind(*y, a.clone()),
+                    (InferTy::Int(_), InferTy::Int(_)) | (InferTy::Float(_), InferTy::Float(_)) => {
+                        self.bind(*x, b.clone())
+                    }
+                    _ => Err(Mismatch),
+                }
+            }
+            (Ty::Infer(var), other) | (other, Ty::Infer(var)) => self.bind(*var, other.clone()),
+            (Ty::Prim(x), Ty::Prim(y)) if x == y => Ok(()),
+            (Ty::Param(x), Ty::Param(y)) if x == y => Ok(()),
+            (Ty::Assoc { self_ty: x, item: i }, Ty::Assoc { self_ty: y, item: j }) if i == j => self.unify(x, y),
+            (Ty::Tuple(xs), Ty::Tuple(ys)) if xs.len() == ys.len() => self.unify_all(xs, ys),
+            (Ty::Adt(x, xs), Ty::Adt(y, ys)) if x == y && xs.len() == ys.len() => self.unify_all(xs, ys),
+            (Ty::Array(x, m), Ty::Array(y, n)) if m == n || m.is_none() || n.is_none() => self.unify(x, y),
+            (Ty::Ref { ty: x, mutable: m }, Ty::Ref { ty: y, mutable: n }) if m == n => self.unify(x, y),
+            (Ty::Fn(xs, x), Ty::Fn(ys, y)) if xs.len() == ys.len() => {
+                self.unify_all(xs, ys)?;
+                self.unify(x, y)
+            }
+            _ => Err(Mismatch),
+        }
+    }
+
+    fn unify_all(&mut self, xs: &[Ty], ys: &[Ty]) -> Result<(), Mismatch> {
+        for (x, y) in xs.iter().zip(ys) {
+            self.unify(x, y)?;
+        }
+        Ok(())
+    }
+
+    fn bind(&mut self, var: InferTy, ty: Ty) -> Result<(), Mismatch> {
+        let ok = match var {
+            InferTy::Var(_) => true,
+            InferTy::Int(_) => ty.is_integral(),
+            InferTy::Float(_) => ty.is_float(),
+        };
+        if !ok || self.occurs(var.index(), &ty) {
+            return Err(Mismatch);
+        }
+        self.vars[var.index() as usize].1 = Some(ty);
+        Ok(())
+    }
+
+    fn occurs(&self, index: u32, ty: &Ty) -> bool {
+        let mut found = false;
+        self.resolve(ty).walk(&mut |t| found |= matches!(t, Ty::Infer(v) if v.index() == index));
+        found
+    }
+
+    /// Unbound general variables of `ty`
+    pub fn free_vars(&self, ty: &Ty, out: &mut HashSet<u32>) {
+        self.resolve(ty).walk(&mut |t| {
+            if let Ty::Infer(InferTy::Var(v)) = t {
+                out.insert(*v);
+            }
+        });
+    }
+
+    /// Quantify over the variables of `ty` not free in the environment.
+    /// Integer and float variables are never generalized; they default instead.
+    pub fn generalize(&self, ty: &Ty, env: &HashSet<u32>) -> Scheme {
+        let ty = self.resolve(ty);
+        let mut vars = HashSet::new();
+        self.free_vars(&ty, &mut vars);
+        let mut vars: Vec<u32> = vars.into_iter().filter(|v| !env.contains(v)).collect();
+        vars.sort_unstable();
+        Scheme { vars, ty }
+    }
+
+    pub fn instantiate(&mut self, scheme: &Scheme) -> Ty {
+        if scheme.vars.is_empty() {
+            return scheme.ty.clone();
+        }
+        let fresh: HashMap<u32, Ty> = scheme.vars.iter().map(|&v| (v, self.new_var())).collect();
+        scheme.ty.fold(&mut |t| match t {
+            Ty::Infer(InferTy::Var(v)) => fresh.get(v).cloned(),
+            _ => None,
+        })
+    }
+
+    /// Bind every unbound integer and float variable to `i32` and `f64`
+    pub fn default_numeric_vars(&mut self) {
+        for index in 0..self.vars.len() {
+            let default = match self.vars[index] {
+                (InferTy::Int(_), None) => PrimitiveType::I32,
+                (InferTy::Float(_), None) => PrimitiveType::F64,
+                _ => continue,
+            };
+            self.vars[index].1 = Some(Ty::Prim(default));
+        }
+    }
+}
@@ -1 +1,555 @@
-// Typechecker stub - implementation pending
+// Type Checking
+// Hindley-Milner inference over resolved programs: item signatures are declared, bodies are inferred,
+// and trait bounds are discharged against the crate's impls
+
+mod check;
+mod collect;
+mod exhaustive;
+mod infer;
+mod traits;
+mod ty;
+
+pub use collect::{AdtDef, FieldTy, FnSig, VariantDef, VariantKind};
+pub use ty::{InferTy, Ty};
+
+use check::FnCtxt;
+use collect::{Owner, Tables};
+use my_lang_ast::{Item, ItemKind, NodeId, Program, Stage, StageKind};
+use my_lang_diagnostics::Diagnostic;
+use my_lang_resolve::{DefId, Resolution};
+use std::collections::HashMap;
+
+/// Output of type checking, shared by later phases
+#[derive(Debug, Clone, Default)]
+pub struct TypeckResults {
+    /// Type of every expression, pattern and parameter, with literals defaulted
+    pub node_types: HashMap<NodeId, Ty>,
+    /// Function each method call resolved to, keyed by the call expression.
+    /// `T::f` paths through a bound are keyed by the path expression.
+    pub method_calls: HashMap<NodeId, DefId>,
+    /// Signature of every function item
+    pub sigs: HashMap<DefId, FnSig>,
+    /// Fields of every struct and variants of every enum
+    pub adts: HashMap<DefId, AdtDef>,
+}
+
+impl TypeckResults {
+    pub fn type_of(&self, node: NodeId) -> Option<&Ty> {
+        self.node_types.get(&node)
+    }
+
+    fn absorb(&mut self, fcx: FnCtxt) -> Vec<Diagnostic> {
+        let (node_types, method_calls, diags) = fcx.finish();
+        self.node_types.extend(node_types);
+        self.method_calls.extend(method_calls);
+        diags
+    }
+}
+
+/// Type check every module of a resolved crate
+pub fn check_crate(programs: &[&Program], res: &Resolution) -> (TypeckResults, Vec<Diagnostic>) {
+    check_crate_with_sizes(programs, res, HashMap::new())
+}
+
+/// Type check with the lengths compile-time evaluation found for array sizes
+/// that are not literals, keyed by the size expression. Arrays whose size is
+/// missing match any length.
+pub fn check_crate_with_sizes(
+    programs: &[&Program],
+    res: &Resolution,
+    array_sizes: HashMap<NodeId, usize>,
+) -> (TypeckResults, Vec<Diagnostic>) {
+    let mut diags = Vec::new();
+    let tables = Tables::collect(programs, res, array_sizes, &mut diags);
+    traits::check_impls(&tables, &mut diags);
+    let mut results = TypeckResults::default();
+
+    let mut functions: Vec<_> = tables.functions.values().filter(|item| item.has_body).collect();
+    functions.sort_by_key(|item| item.func.id);
+    for item in functions {
+        let Some(sig) = res.def_of_node(item.func.id).and_then(|def| tables.sigs.get(&def)) else {
+            continue;
+        };
+        let scope = res.node_scopes.get(&item.func.id).copied();
+        let mut fcx = FnCtxt::new(&tables, tables.owner_self_ty(item.owner), scope);
+        fcx.check_fn(item.func, sig);
+        diags.extend(results.absorb(fcx));
+    }
+
+    let mut consts: Vec<_> = tables.consts().collect();
+    consts.sort_by_key(|(c, _)| c.id);
+    for (c, owner) in consts {
+        let Some(ty) = res.def_of_node(c.id).and_then(|def| tables.const_tys.get(&def)) else {
+            continue;
+        };
+        let mut fcx = FnCtxt::new(&tables, tables.owner_self_ty(owner), None);
+        fcx.check_root_expr(&c.value, Some((ty, c.ty.span)));
+        diags.extend(results.absorb(fcx));
+    }
+
+    let mut fcx = FnCtxt::new(&tables, None, None);
+    for size in tables.signature_array_sizes() {
+        fcx.check_array_size(size);
+    }
+    diags.extend(results.absorb(fcx));
+
+    for program in programs {
+        check_items(&tables, &program.items, &mut results, &mut diags);
+    }
+
+    results.sigs = tables.sigs.clone();
+    results.adts = tables.adts.clone();
+    (results, diags)
+}
+
+/// Expressions outside function bodies: synthesis specs, verified
+/// properties, agent goals and handlers, and workflow conditions
+fn check_items(tables: &Tables, items: &[Item], results: &mut TypeckResults, diags: &mut Vec<Diagnostic>) {
+    for item in items {
+        let mut fcx = FnCtxt::new(tables, None, None);
+        match &item.kind {
+            ItemKind::SynthFunction(sf) => {
+                if let Some(spec) = &sf.spec {
+                    fcx.check_root_expr(spec, None);
+                }
+                for (input, output) in &sf.examples {
+                    fcx.check_root_expr(input, None);
+                    fcx.check_root_expr(output, None);
+                }
+            }
+            ItemKind::VerifyFunction(vf) => fcx.check_root_condition(&vf.property),
+            ItemKind::Comptime(expr) => fcx.check_root_expr(expr, None),
+            ItemKind::Impl(imp) => {
+                let Some(contract) = &imp.contract else { continue };
+                let self_ty = tables.owner_self_ty(Owner::Impl(imp));
+                fcx = FnCtxt::new(tables, self_ty, tables.res.node_scopes.get(&contract.id).copied());
+                fcx.check_invariants(contract);
+            }
+            ItemKind::Agent(agent) => {
+                for goal in &agent.goals {
+                    fcx.check_root_expr(&goal.expr, None);
+                }
+                for handler in &agent.communication {
+                    fcx.check_root_block(&handler.handler);
+                }
+            }
+            ItemKind::Workflow(workflow) => {
+                for stage in &workflow.stages {
+                    check_stage(&mut fcx, stage);
+                }
+                for rule in &workflow.coordination {
+                    match &rule.kind {
+                        my_lang_ast::CoordinationRuleKind::Consensus { on, .. }
+                        | my_lang_ast::CoordinationRuleKind::Voting { on, .. } => fcx.check_root_expr(on, None),
+                    }
+                }
+            }
+            ItemKind::Module(m) => check_items(tables, &m.items, results, diags),
+            _ => continue,
+        }
+        diags.extend(results.absorb(fcx));
+    }
+}
+
+fn check_stage(fcx: &mut FnCtxt, stage: &Stage) {
+    match &stage.kind {
+        StageKind::Agent(_) => {}
+        StageKind::Parallel(stages) => stages.iter().for_each(|s| check_stage(fcx, s)),
+        StageKind::Conditional { cond, then_stage, else_stage } => {
+            fcx.check_root_condition(cond);
+            check_stage(fcx, then_stage);
+            if let Some(stage) = else_stage {
+                check_stage(fcx, stage);
+            }
+        }
+    }
+}
+
+/// Resolve and type check a single-file program
+pub fn check_program(program: &Program) -> Vec<Diagnostic> {
+    let (res, mut diags) = my_lang_resolve::resolve(program);
+    if diags.iter().any(Diagnostic::is_error) {
+        return diags;
+    }
+    diags.extend(check_crate(&[program], &res).1);
+    diags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_source(source: &str) -> Vec<Diagnostic> {
+        let program = my_lang_parser::parse(source).expect("test source parses");
+        check_program(&program)
+    }
+
+    fn typeck(source: &str) -> (Program, Resolution, TypeckResults) {
+        let program = my_lang_parser::parse(source).expect("test source parses");
+        let (res, diags) = my_lang_resolve::resolve(&program);
+        assert!(diags.is_empty(), "{:?}", diags);
+        let (results, diags) = check_crate(&[&program], &res);
+        assert!(diags.is_empty(), "{:?}", diags.iter().map(|d| &d.message).collect::<Vec<_>>());
+        (program, res, results)
+    }
+
+    fn codes_of(diags: &[Diagnostic]) -> Vec<&str> {
+        diags.iter().filter_map(|d| d.code).map(|c| c.as_str()).collect()
+    }
+
+    /// Type inferred for the last `let` binding in function `name`
+    fn binding_type(program: &Program, res: &Resolution, results: &TypeckResults, name: &str) -> String {
+        let func = program
+            .items
+            .iter()
+            .find_map(|item| match &item.kind {
+                ItemKind::Function(f) if f.name == name => Some(f),
+                _ => None,
+            })
+            .expect("function exists");
+        let pattern = func
+            .body
+            .stmts
+            .iter()
+            .rev()
+            .find_map(|stmt| match &stmt.kind {
+                my_lang_ast::StatementKind::Let { pattern, .. } => Some(pattern),
+                _ => None,
+            })
+            .expect("let binding");
+        results.type_of(pattern.id).expect("binding was typed").display(res).to_string()
+    }
+
+    #[test]
+    fn test_literal_defaults() {
+        let (program, res, results) = typeck(
+            "fn int() { let x = 42; }\n\
+             fn float() { let y = 3.14; }\n\
+             fn wide() { let z = 7; let w: i64 = z; let v = z; }",
+        );
+        assert_eq!(binding_type(&program, &res, &results, "int"), "i32");
+        assert_eq!(binding_type(&program, &res, &results, "float"), "f64");
+        assert_eq!(binding_type(&program, &res, &results, "wide"), "i64");
+    }
+
+    #[test]
+    fn test_return_type_checked_against_body() {
+        let diags = check_source("fn f() -> i32 { true }");
+        assert_eq!(codes_of(&diags), ["E0200"]);
+        assert_eq!(diags[0].labels[0].message, "expected `i32`, found `bool`");
+        assert_eq!(diags[0].labels[1].message, "expected `i32` because of return type");
+
+        let diags = check_source("fn g(x: i32) -> i32 { x + 1; }");
+        assert_eq!(codes_of(&diags), ["E0200"]);
+        assert!(diags[0].notes[0].contains("remove the semicolon"));
+
+        assert!(check_source("fn h(x: i32) -> i32 { if x > 0 { return 1; } x }").is_empty());
+        assert!(check_source("fn k() -> i32 { loop { } }").is_empty());
+    }
+
+    #[test]
+    fn test_mismatch_spans() {
+        let source = "fn main() { let s: str = 5; }";
+        let diags = check_source(source);
+        assert_eq!(codes_of(&diags), ["E0200"]);
+        let primary = diags[0].primary_span().unwrap();
+        assert_eq!(&source[primary.start..primary.end], "5");
+        assert_eq!(diags[0].labels[0].message, "expected `str`, found `{integer}`");
+    }
+
+    #[test]
+    fn test_calls_and_arity() {
+        let diags = check_source(
+            "fn add(a: i32, b: i32) -> i32 { a + b }\n\
+             fn main() { add(1); add(1, true); let n: i32 = 5; n(); }",
+        );
+        assert_eq!(codes_of(&diags), ["E0201", "E0200", "E0204"]);
+        assert_eq!(diags[0].message, "this function takes 2 arguments but 1 argument was supplied");
+    }
+
+    #[test]
+    fn test_structs_and_fields() {
+        let diags = check_source(
+            "struct Point { x: i32, y: i32 }\n\
+             fn main() {\n\
+                 let p = Point { x: 1, y: 2 };\n\
+                 let a: i32 = p.x;\n\
+                 let b = p.z;\n\
+                 let q = Point { x: 1, z: 3 };\n\
+             }",
+        );
+        assert_eq!(codes_of(&diags), ["E0202", "E0202", "E0203"]);
+        assert_eq!(diags[0].message, "no field `z` on type `Point`");
+        assert_eq!(diags[2].message, "missing field `y` in initializer of `Point`");
+    }
+
+    #[test]
+    fn test_generic_functions_and_enums() {
+        let (program, res, results) = typeck(
+            "fn identity<T>(x: T) -> T { x }\n\
+             enum Shape { Circle(f64), Square { side: f64 } }\n\
+             fn area(s: &Shape) -> f64 { match s { Shape::Circle(r) => *r * *r, Shape::Square { side } => *side } }\n\
+             fn pick() { let a = identity(1); let b = identity(true); let pair = (a, b); }\n\
+             fn maybe() { let o = Some(2.5); let v = match o { Some(v) => v, None => 0.0 }; }",
+        );
+        assert_eq!(binding_type(&program, &res, &results, "pick"), "(i32, bool)");
+        assert_eq!(binding_type(&program, &res, &results, "maybe"), "f64");
+    }
+
+    #[test]
+    fn test_let_polymorphism() {
+        let (program, res, results) = typeck(
+            "fn identity<T>(x: T) -> T { x }\n\
+             fn main() { let id = identity; let a = id(1); let b = id(\"text\"); let pair = (a, b); }",
+        );
+        assert_eq!(binding_type(&program, &res, &results, "main"), "(i32, str)");
+
+        // Only values generalize; a call result stays one type
+        let diags = check_source(
+            "fn make<T>() -> Option<T> { None }\n\
+             fn main() { let o = make(); let a: Option<i32> = o; let b: Option<bool> = o; }",
+        );
+        assert_eq!(codes_of(&diags), ["E0200"]);
+    }
+
+    #[test]
+    fn test_closures() {
+        let (program, res, results) = typeck(
+            "fn apply(f: fn(i32) -> i32, v: i32) -> i32 { f(v) }\n\
+             fn typed() { let add = |a: i32, b: i32| a + b; let f = add; }\n\
+             fn hinted() { let n = apply(|x| x * 2, 21); let twice = |x| x * 2; let m = apply(twice, 1); }\n\
+             fn captures() { let factor = 2.5; let scale = |x| x * factor; let s = scale; }",
+        );
+        assert_eq!(binding_type(&program, &res, &results, "typed"), "fn(i32, i32) -> i32");
+        assert_eq!(binding_type(&program, &res, &results, "hinted"), "i32");
+        // `x * factor` makes the parameter the captured local's type
+        assert_eq!(binding_type(&program, &res, &results, "captures"), "fn(f64) -> f64");
+
+        let diags = check_source(
+            "fn main() { let f = |x: i32| -> bool { x }; let g = |x: i32| x; g(true); let h = || { return 1; }; h(); }",
+        );
+        assert_eq!(codes_of(&diags), ["E0200", "E0200"]);
+        assert_eq!(diags[0].labels[1].message, "expected `bool` because of return type");
+    }
+
+    #[test]
+    fn test_methods() {
+        let (program, res, results) = typeck(
+            "struct Counter { n: i32 }\n\
+             impl Counter {\n\
+                 fn new() -> Self { Counter { n: 0 } }\n\
+                 fn get(&self) -> i32 { self.n }\n\
+             }\n\
+             fn main() { let c = Counter::new(); let n = c.get(); }",
+        );
+        assert_eq!(binding_type(&program, &res, &results, "main"), "i32");
+        assert_eq!(results.method_calls.len(), 1);
+
+        let diags = check_source(
+            "struct Counter { n: i32 }\n\
+             impl Counter { fn add(&mut self, by: i32) { self.n = self.n + by; } }\n\
+             fn main() { let c = Counter { n: 0 }; c.add(1, 2); c.add(false); }",
+        );
+        assert_eq!(codes_of(&diags), ["E0201", "E0200"]);
+    }
+
+    #[test]
+    fn test_if_branches_and_conditions() {
+        let diags = check_source("fn main() { let x = if 1 { 2 } else { true }; }");
+        assert_eq!(codes_of(&diags), ["E0200", "E0200"]);
+        assert_eq!(diags[0].labels[0].message, "expected `bool`, found `{integer}`");
+    }
+
+    #[test]
+    fn test_wrong_type_arguments() {
+        let diags = check_source("struct Wrapper<T> { value: T }\nfn f(w: Wrapper<i32, bool>) {}");
+        assert_eq!(codes_of(&diags), ["E0206"]);
+    }
+
+    #[test]
+    fn test_string_concatenation_and_refs() {
+        assert!(check_source(
+            "fn greet(name: &str) -> str { \"hello \" + name }\n\
+             fn main() { let n = 3; let s = \"n = \" + n; greet(\"you\"); greet(&s); }"
+        )
+        .is_empty());
+    }
+
+    const SHAPES: &str = "trait Shape {\n\
+                              type Unit;\n\
+                              fn area(&self) -> f64;\n\
+                              fn describe(&self) -> str { \"a shape\" }\n\
+                          }\n\
+                          struct Square { side: f64 }\n\
+                          struct Circle { r: f64 }\n";
+
+    #[test]
+    fn test_impls_checked_against_traits() {
+        let diags = check_source(&format!(
+            "{}impl Shape for Square {{ type Unit = f64; fn area(&self) -> i32 {{ 1 }} fn edges(&self) -> i32 {{ 4 }} }}\n\
+             impl Shape for Circle {{ fn area(&self, scale: f64) -> f64 {{ self.r * scale }} }}",
+            SHAPES
+        ));
+        assert_eq!(codes_of(&diags), ["E0215", "E0212", "E0211", "E0201"]);
+        assert_eq!(diags[0].labels[0].message, "expected `f64`, found `i32`");
+        assert_eq!(diags[2].message, "not all trait items implemented, missing: `Unit`");
+
+        // A default method need not be repeated
+        assert!(check_source(&format!(
+            "{}impl Shape for Square {{ type Unit = f64; fn area(&self) -> f64 {{ self.side * self.side }} }}",
+            SHAPES
+        ))
+        .is_empty());
+    }
+
+    #[test]
+    fn test_coherence() {
+        let diags = check_source(&format!(
+            "{}struct Wrapper<T> {{ value: T }}\n\
+             impl<T> Shape for Wrapper<T> {{ type Unit = T; fn area(&self) -> f64 {{ 0.0 }} }}\n\
+             impl Shape for Wrapper<i32> {{ type Unit = i32; fn area(&self) -> f64 {{ 1.0 }} }}\n\
+             impl Shape for Wrapper<bool> {{ type Unit = bool; fn area(&self) -> f64 {{ 2.0 }} }}",
+            SHAPES
+        ));
+        assert_eq!(codes_of(&diags), ["E0213", "E0213"]);
+        assert_eq!(diags[0].message, "conflicting implementations of trait `Shape` for type `Wrapper<i32>`");
+
+        let program = my_lang_parser::parse(
+            "use std::fmt::Display;\nstruct P { x: i32 }\n\
+             impl Display for P { fn fmt(&self) -> str { \"p\" } }\n\
+             impl Display for i32 { fn fmt(&self) -> str { \"i\" } }",
+        )
+        .unwrap();
+        let (res, _) = my_lang_resolve::resolve(&program);
+        let (_, diags) = check_crate(&[&program], &res);
+        assert_eq!(codes_of(&diags), ["E0214"]);
+    }
+
+    #[test]
+    fn test_bounds_checked_at_call_sites() {
+        let source = format!(
+            "{}impl Shape for Square {{ type Unit = f64; fn area(&self) -> f64 {{ self.side }} }}\n\
+             fn total<T: Shape>(a: &T, b: &T) -> f64 {{ a.area() + b.area() }}\n\
+             fn largest<T>(items: [T; 2]) -> f64 where T: Shape {{ items[0].area() }}\n\
+             fn main() {{\n\
+                 let s = Square {{ side: 2.0 }};\n\
+                 total(&s, &s);\n\
+                 let c = Circle {{ r: 1.0 }};\n\
+                 total(&c, &c);\n\
+                 largest([c, c]);\n\
+             }}",
+            SHAPES
+        );
+        let diags = check_source(&source);
+        assert_eq!(codes_of(&diags), ["E0210", "E0210"]);
+        assert_eq!(diags[0].message, "the trait bound `Circle: Shape` is not satisfied");
+        assert_eq!(diags[0].labels[1].message, "required by this bound");
+        assert_eq!(diags[0].notes[0], "the trait `Shape` is implemented for `Square`");
+
+        // Literals satisfy a bound once defaulted
+        assert!(check_source(
+            "trait Double { fn double(&self) -> Self; }\n\
+             impl Double for i32 { fn double(&self) -> i32 { *self * 2 } }\n\
+             fn twice<T: Double>(x: T) -> T { x.double() }\n\
+             fn main() { let n = twice(4); }"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_method_dispatch_through_traits() {
+        let (program, res, results) = typeck(&format!(
+            "{}impl Shape for Square {{ type Unit = f64; fn area(&self) -> f64 {{ self.side }} }}\n\
+             trait Container {{ type Item; fn get(&self) -> Self::Item; }}\n\
+             struct Cell<T> {{ value: T }}\n\
+             impl<T> Container for Cell<T> {{ type Item = T; fn get(&self) -> T {{ self.value }} }}\n\
+             fn first<C: Container>(c: &C) -> C::Item {{ c.get() }}\n\
+             fn text() {{ let s = Square {{ side: 1.0 }}; let d = s.describe(); }}\n\
+             fn item() {{ let c = Cell {{ value: true }}; let v = first(&c); }}",
+            SHAPES
+        ));
+        assert_eq!(binding_type(&program, &res, &results, "text"), "str");
+        assert_eq!(binding_type(&program, &res, &results, "item"), "bool");
+
+        // `describe` runs the trait's default body
+        let describe = results.method_calls.values().find(|def| res.def(**def).name == "describe");
+        assert_eq!(res.def(*describe.unwrap()).parent.map(|t| res.def(t).name.as_str()), Some("Shape"));
+
+        let diags = check_source(&format!(
+            "{}trait Named {{ fn describe(&self) -> str; }}\n\
+             impl Shape for Square {{ type Unit = f64; fn area(&self) -> f64 {{ self.side }} }}\n\
+             impl Named for Square {{ fn describe(&self) -> str {{ \"square\" }} }}\n\
+             fn show<T>(x: T) -> f64 {{ x.area() }}\n\
+             fn main() {{ let s = Square {{ side: 1.0 }}; s.describe(); let c = Circle {{ r: 1.0 }}; c.area(); }}",
+            SHAPES
+        ));
+        assert_eq!(codes_of(&diags), ["E0216", "E0217", "E0216"]);
+        assert!(diags[0].notes[0].contains("restrict type parameter `T`"));
+        assert_eq!(diags[2].notes[0], "trait `Shape` defines an item `area`, but it is not implemented for `Circle`");
+    }
+
+    #[test]
+    fn test_non_exhaustive_matches_list_missing_patterns() {
+        let diags = check_source(
+            "enum Color { Red, Green, Blue }\n\
+             struct P { a: bool, b: bool }\n\
+             fn color(c: Color) -> i32 { match c { Color::Red => 1, Color::Green => 2 } }\n\
+             fn nested(o: Option<Color>) -> i32 { match o { Some(Color::Red) => 1, None => 0 } }\n\
+             fn pair(x: bool, y: bool) -> i32 { match (x, y) { (true, _) => 1, (false, true) => 2 } }\n\
+             fn fields(p: P) -> i32 { match p { P { a: true, b: false } => 1, P { a: false } => 2 } }\n\
+             fn guarded(o: Option<i32>) -> i32 { match o { Some(x) if x > 0 => x, None => 0 } }\n\
+             fn number(n: i32) -> i32 { match n { 0 => 1, 1 => 2 } }\n\
+             fn total(o: Option<bool>) -> i32 { match o { Some(true) => 1, Some(false) => 2, None => 3 } }",
+        );
+        let missing: Vec<&str> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            missing,
+            [
+                "non-exhaustive patterns: `Color::Blue` not covered",
+                "non-exhaustive patterns: `Some(Color::Green)` and `Some(Color::Blue)` not covered",
+                "non-exhaustive patterns: `(false, false)` not covered",
+                "non-exhaustive patterns: `P { a: true, b: true }` not covered",
+                "non-exhaustive patterns: `Some(_)` not covered",
+                "non-exhaustive patterns: `_` not covered",
+            ]
+        );
+        assert!(codes_of(&diags).iter().all(|c| *c == "E0220"));
+        assert_eq!(diags[0].labels[0].message, "pattern `Color::Blue` not covered");
+        assert_eq!(diags[0].notes[0], "the matched value is of type `Color`");
+    }
+
+    #[test]
+    fn test_unreachable_arms() {
+        let diags = check_source(
+            "enum Color { Red, Green, Blue }\n\
+             fn after_wildcard(c: Color) -> i32 { match c { _ => 1, Color::Red => 2 } }\n\
+             fn repeated(n: i32) -> i32 { match n { 1 => 1, 1 => 2, _ => 3 } }\n\
+             fn covered(o: Option<bool>) -> i32 { match o { Some(true) => 1, Some(false) => 2, Some(_) => 3, None => 4 } }\n\
+             fn guarded(n: i32) -> i32 { match n { x if x > 0 => 1, _ => 2 } }",
+        );
+        assert_eq!(codes_of(&diags), ["E0221", "E0221", "E0221"]);
+        assert!(diags.iter().all(|d| d.severity == my_lang_diagnostics::Severity::Warning));
+        assert_eq!(diags[0].labels[1].message, "matches any value");
+    }
+
+    #[test]
+    fn test_old_only_in_postconditions() {
+        let diags = check_source(
+            "fn inc(x: i32) -> i32 post result == old(x) + 1 { x + 1 }\n\
+             fn bad(x: i32) -> i32 pre old(x) > 0 { old(x) }",
+        );
+        assert_eq!(codes_of(&diags), ["E0230", "E0230"]);
+        assert_eq!(diags[0].message, "`old` can only be used in a postcondition");
+    }
+
+    #[test]
+    fn test_impl_invariants_see_self() {
+        let diags = check_source(
+            "struct Account { balance: i64 }\n\
+             impl Account where invariant self.balance >= 0 invariant self.balance { }\n\
+             impl Account where invariant self.overdraft { }",
+        );
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["mismatched types", "no field `overdraft` on type `Account`"]);
+    }
+}
@@ -0,0 +1,398 @@
+// Trait solving
+// Selects the impl a type uses for a trait, and checks impls against their traits and each other
+
+use crate::collect::{FnSig, ImplDef, Tables, TraitDef, TraitFn};
+use crate::infer::InferCtxt;
+use crate::ty::{is_int, InferTy, Ty};
+use my_lang_ast::{Function, ImplItem, Span};
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_resolve::{DefId, DefKind};
+use std::collections::HashMap;
+
+/// How well an impl's self type fits a type that may still hold inference variables
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Fit {
+    No,
+    /// Fits for some values of the variables, not yet known
+    Maybe,
+    Yes,
+}
+
+/// Outcome of looking for the impl of a trait for a type
+pub enum Selection {
+    /// The impl at this index of `Tables::impls`, with its parameters as matched
+    Impl { index: usize, args: HashMap<DefId, Ty> },
+    /// Depends on inference variables not yet resolved
+    Ambiguous,
+    Unimplemented,
+}
+
+/// Find the impl of `trait_def` for `ty`
+pub fn select_impl(tables: &Tables, trait_def: DefId, ty: &Ty, infcx: &InferCtxt) -> Selection {
+    let mut ambiguous = false;
+    for (index, imp) in tables.impls.iter().enumerate() {
+        if imp.trait_def != Some(trait_def) {
+            continue;
+        }
+        match match_impl(imp, ty, infcx) {
+            (Fit::Yes, args) => return Selection::Impl { index, args },
+            (Fit::Maybe, _) => ambiguous = true,
+            (Fit::No, _) => {}
+        }
+    }
+    if ambiguous {
+        Selection::Ambiguous
+    } else {
+        Selection::Unimplemented
+    }
+}
+
+/// Match an impl's self type against `ty`, binding the impl's parameters
+fn match_impl(imp: &ImplDef, ty: &Ty, infcx: &InferCtxt) -> (Fit, HashMap<DefId, Ty>) {
+    let mut args = HashMap::new();
+    let fit = fit(&imp.self_ty, ty, &imp.generics, infcx, &mut args);
+    (fit, args)
+}
+
+/// Whether the inherent or trait impl applies to `ty` for certain
+pub fn impl_applies(imp: &ImplDef, ty: &Ty, infcx: &InferCtxt) -> Option<bool> {
+    match match_impl(imp, ty, infcx).0 {
+        Fit::Yes => Some(true),
+        Fit::Maybe => None,
+        Fit::No => Some(false),
+    }
+}
+
+fn fit(pattern: &Ty, target: &Ty, generics: &[DefId], infcx: &InferCtxt, args: &mut HashMap<DefId, Ty>) -> Fit {
+    let target = infcx.shallow_resolve(target);
+    let all = |xs: &[Ty], ys: &[Ty], args: &mut HashMap<DefId, Ty>| {
+        xs.iter().zip(ys).map(|(x, y)| fit(x, y, generics, infcx, args)).min().unwrap_or(Fit::Yes)
+    };
+    match (pattern, &target) {
+        (Ty::Param(param), _) if generics.contains(param) => match args.get(param) {
+            Some(bound) => {
+                let (bound, target) = (infcx.resolve(bound), infcx.resolve(&target));
+                if bound == target {
+                    Fit::Yes
+                } else if has_infer(&bound) || has_infer(&target) {
+                    Fit::Maybe
+                } else {
+                    Fit::No
+                }
+            }
+            None => {
+                args.insert(*param, target);
+                Fit::Yes
+            }
+        },
+        (_, Ty::Infer(InferTy::Var(_)) | Ty::Error | Ty::Never) => Fit::Maybe,
+        (Ty::Prim(prim), Ty::Infer(InferTy::Int(_))) if is_int(*prim) => Fit::Maybe,
+        (pattern, Ty::Infer(InferTy::Float(_))) if pattern.is_float() => Fit::Maybe,
+        (Ty::Prim(x), Ty::Prim(y)) if x == y => Fit::Yes,
+        (Ty::Param(x), Ty::Param(y)) if x == y => Fit::Yes,
+        (Ty::Tuple(xs), Ty::Tuple(ys)) if xs.len() == ys.len() => all(xs, ys, args),
+        (Ty::Adt(x, xs), Ty::Adt(y, ys)) if x == y && xs.len() == ys.len() => all(xs, ys, args),
+        (Ty::Array(x, m), Ty::Array(y, n)) if m == n || m.is_none() || n.is_none() => {
+            fit(x, y, generics, infcx, args)
+        }
+        (Ty::Ref { ty: x, mutable: m }, Ty::Ref { ty: y, mutable: n }) if m == n => fit(x, y, generics, infcx, args),
+        (Ty::Fn(xs, x), Ty::Fn(ys, y)) if xs.len() == ys.len() => {
+            all(xs, ys, args).min(fit(x, y, generics, infcx, args))
+        }
+        (Ty::Assoc { self_ty: x, item: i }, Ty::Assoc { self_ty: y, item: j }) if i == j => {
+            fit(x, y, generics, infcx, args)
+        }
+        _ => Fit::No,
+    }
+}
+
+fn has_infer(ty: &Ty) -> bool {
+    let mut found = false;
+    ty.walk(&mut |t| found |= matches!(t, Ty::Infer(_)));
+    found
+}
+
+/// Replace associated types of concrete types by what their impls define.
+/// Projections from parameters, or from types not yet known, are left alone.
+pub fn normalize(tables: &Tables, ty: &Ty, infcx: &InferCtxt) -> Ty {
+    ty.fold(&mut |t| {
+        let Ty::Assoc { self_ty, item } = t else {
+            return None;
+        };
+        let self_ty = normalize(tables, self_ty, infcx);
+        if matches!(infcx.shallow_resolve(&self_ty), Ty::Param(_) | Ty::Assoc { .. } | Ty::Infer(_)) {
+            return Some(Ty::Assoc { self_ty: Box::new(self_ty), item: *item });
+        }
+        Some(project(tables, &self_ty, *item, infcx).unwrap_or(Ty::Error))
+    })
+}
+
+/// The type an impl gives associated type `item` for `self_ty`
+fn project(tables: &Tables, self_ty: &Ty, item: DefId, infcx: &InferCtxt) -> Option<Ty> {
+    let definition = tables.res.def(item);
+    let Selection::Impl { index, args } = select_impl(tables, definition.parent?, self_ty, infcx) else {
+        return None;
+    };
+    let ty = tables.impls[index].assoc_tys.get(&definition.name)?.subst(&args);
+    Some(normalize(tables, &ty, infcx))
+}
+
+// ========== Impl Checks ==========
+
+/// Check every impl: trait impls must define what their trait requires, in
+/// the shape it declares, and no two impls of a trait may overlap
+pub fn check_impls(tables: &Tables, diags: &mut Vec<Diagnostic>) {
+    for (index, imp) in tables.impls.iter().enumerate() {
+        let Some(trait_def) = imp.trait_def else { continue };
+        if let Some(def) = tables.trait_defs.get(&trait_def) {
+            check_impl_items(tables, imp, trait_def, def, diags);
+        }
+        check_orphan(tables, imp, trait_def, diags);
+        let earlier = tables.impls[..index].iter().find(|other| other.trait_def == Some(trait_def) && overlaps(other, imp));
+        if let Some(other) = earlier {
+            let trait_name = &tables.res.def(trait_def).name;
+            let self_ty = imp.self_ty.display(tables.res);
+            diags.push(
+                Diagnostic::error(format!("conflicting implementations of trait `{}` for type `{}`", trait_name, self_ty))
+                    .with_code(codes::CONFLICTING_IMPLS)
+                    .with_primary(header_span(imp), format!("conflicting implementation for `{}`", self_ty))
+                    .with_secondary(header_span(other), "first implementation here"),
+            );
+        }
+    }
+}
+
+/// `impl Trait for Type`, without the body
+fn header_span(imp: &ImplDef) -> Span {
+    Span { end: imp.imp.self_ty.span.end, ..imp.imp.span }
+}
+
+fn check_impl_items(tables: &Tables, imp: &ImplDef, trait_def: DefId, def: &TraitDef, diags: &mut Vec<Diagnostic>) {
+    let res = tables.res;
+    let trait_name = &res.def(trait_def).name;
+
+    let missing_fns = def.fns.iter().filter(|f| !f.has_default && !imp.fns.contains_key(&f.name));
+    let missing_types = def.assoc_types.iter().filter(|(name, _)| !imp.assoc_tys.contains_key(name));
+    let missing: Vec<(&str, DefId)> = missing_fns
+        .map(|f| (f.name.as_str(), f.def))
+        .chain(missing_types.map(|(name, item)| (name.as_str(), *item)))
+        .collect();
+    if !missing.is_empty() {
+        let list = missing.iter().map(|(name, _)| format!("`{}`", name)).collect::<Vec<_>>().join(", ");
+        let mut diag = Diagnostic::error(format!("not all trait items implemented, missing: {}", list))
+            .with_code(codes::MISSING_TRAIT_ITEMS)
+            .with_primary(header_span(imp), format!("missing {} in implementation", list));
+        for (name, item) in &missing {
+            diag = diag.with_secondary(res.def(*item).span, format!("`{}` from trait", name));
+        }
+        diags.push(diag);
+    }
+
+    for impl_item in &imp.imp.items {
+        let (what, name, span) = match impl_item {
+            ImplItem::Function(f) => match def.func(&f.name) {
+                Some(trait_fn) => {
+                    compare_method(tables, imp, f, trait_fn, trait_name, def, diags);
+                    continue;
+                }
+                None => ("method", &f.name, f.span),
+            },
+            ImplItem::Type(alias) if def.assoc_type(&alias.name).is_none() => ("type", &alias.name, alias.span),
+            ImplItem::Type(_) | ImplItem::Const(_) => continue,
+        };
+        diags.push(
+            Diagnostic::error(format!("{} `{}` is not a member of trait `{}`", what, name, trait_name))
+                .with_code(codes::NOT_A_TRAIT_MEMBER)
+                .with_primary(span, format!("not a member of trait `{}`", trait_name)),
+        );
+    }
+}
+
+/// An impl's method must take the parameters its trait declares
+fn compare_method(
+    tables: &Tables,
+    imp: &ImplDef,
+    func: &Function,
+    trait_fn: &TraitFn,
+    trait_name: &str,
+    def: &TraitDef,
+    diags: &mut Vec<Diagnostic>,
+) {
+    let res = tables.res;
+    let (Some(impl_sig), Some(trait_sig)) = (
+        res.def_of_node(func.id).and_then(|d| tables.sigs.get(&d)),
+        tables.sigs.get(&trait_fn.def),
+    ) else {
+        return;
+    };
+    let Some(declared) = tables.functions.get(&res.def(trait_fn.def).node).map(|item| item.func) else {
+        return;
+    };
+    let error = |message: String, code, label: String| {
+        Diagnostic::error(message).with_code(code).with_primary(params_span(func), label)
+    };
+
+    if impl_sig.has_self != trait_sig.has_self {
+        let (message, label, note) = if trait_sig.has_self {
+            ("in the trait, but not in the impl", "expected `self` in impl", "`self` used in trait")
+        } else {
+            ("in the impl, but not in the trait", "`self` used in impl", "trait method declared without `self`")
+        };
+        diags.push(
+            error(
+                format!("method `{}` has a `self` declaration {}", func.name, message),
+                codes::INCOMPATIBLE_IMPL_ITEM,
+                label.to_string(),
+            )
+            .with_secondary(params_span(declared), note),
+        );
+        return;
+    }
+    if impl_sig.inputs.len() != trait_sig.inputs.len() {
+        let plural = |n: usize| if n == 1 { "parameter" } else { "parameters" };
+        let (found, expected) = (impl_sig.inputs.len(), trait_sig.inputs.len());
+        diags.push(
+            error(
+                format!(
+                    "method `{}` has {} {} but the declaration in trait `{}` has {}",
+                    func.name,
+                    found,
+                    plural(found),
+                    trait_name,
+                    expected
+                ),
+                codes::ARITY_MISMATCH,
+                format!("expected {} {}, found {}", expected, plural(expected), found),
+            )
+            .with_secondary(params_span(declared), format!("trait requires {} {}", expected, plural(expected))),
+        );
+        return;
+    }
+    if func.generics.len() != declared.generics.len() {
+        diags.push(
+            error(
+                format!(
+                    "method `{}` has {} type parameter(s) but its trait declaration has {}",
+                    func.name,
+                    func.generics.len(),
+                    declared.generics.len()
+                ),
+                codes::INCOMPATIBLE_IMPL_ITEM,
+                format!("expected {}", declared.generics.len()),
+            )
+            .with_secondary(declared.span, "declared here"),
+        );
+        return;
+    }
+
+    // Read the trait's signature as this impl: `Self` is the self type and
+    // the method's own parameters are the impl method's
+    let mut infcx = InferCtxt::new();
+    let own = declared.generics.len();
+    let mut map: HashMap<DefId, Ty> = HashMap::new();
+    let split = trait_sig.generics.len() - own;
+    for &param in &trait_sig.generics[..split] {
+        map.insert(param, infcx.new_var());
+    }
+    map.insert(def.self_param, imp.self_ty.clone());
+    let impl_own = &impl_sig.generics[impl_sig.generics.len() - own..];
+    for (&param, &ours) in trait_sig.generics[split..].iter().zip(impl_own) {
+        map.insert(param, Ty::Param(ours));
+    }
+
+    let spans = |f: &Function| -> Vec<Span> {
+        f.params
+            .iter()
+            .map(|p| p.ty.span)
+            .chain([f.return_type.as_ref().map_or(f.span, |t| t.span)])
+            .collect()
+    };
+    let (found_spans, expected_spans) = (spans(func), spans(declared));
+    let pairs = trait_sig.inputs.iter().chain([&trait_sig.output]).zip(impl_sig.inputs.iter().chain([&impl_sig.output]));
+    for (i, (expected, found)) in pairs.enumerate() {
+        let expected = normalize(tables, &expected.subst(&map), &infcx);
+        let found = normalize(tables, found, &infcx);
+        if infcx.unify(&expected, &found).is_ok() {
+            continue;
+        }
+        let (expected, found) = (infcx.resolve(&expected), infcx.resolve(&found));
+        diags.push(
+            Diagnostic::error(format!("method `{}` has an incompatible type for trait", func.name))
+                .with_code(codes::INCOMPATIBLE_IMPL_ITEM)
+                .with_primary(
+                    found_spans[i],
+                    format!("expected `{}`, found `{}`", expected.display(res), found.display(res)),
+                )
+                .with_secondary(expected_spans[i], "type in trait"),
+        );
+        return;
+    }
+}
+
+/// The parameter list of a function, or its whole span when it has none
+fn params_span(func: &Function) -> Span {
+    match (func.params.first(), func.params.last()) {
+        (Some(first), Some(last)) => first.span.to(last.span),
+        _ => func.span,
+    }
+}
+
+/// Whether some type could use both impls
+fn overlaps(a: &ImplDef, b: &ImplDef) -> bool {
+    if a.self_ty.references_error() || b.self_ty.references_error() {
+        return false;
+    }
+    let mut infcx = InferCtxt::new();
+    let mut fresh = |generics: &[DefId]| -> HashMap<DefId, Ty> { generics.iter().map(|&g| (g, infcx.new_var())).collect() };
+    let (ma, mb) = (fresh(&a.generics), fresh(&b.generics));
+    infcx.unify(&a.self_ty.subst(&ma), &b.self_ty.subst(&mb)).is_ok()
+}
+
+/// A trait from outside the crate may only be implemented for the crate's own types
+fn check_orphan(tables: &Tables, imp: &ImplDef, trait_def: DefId, diags: &mut Vec<Diagnostic>) {
+    let res = tables.res;
+    let definition = res.def(trait_def);
+    if !(definition.kind == DefKind::External || definition.is_prelude()) || imp.self_ty.references_error() {
+        return;
+    }
+    fn is_local(tables: &Tables, ty: &Ty) -> bool {
+        match ty {
+            Ty::Adt(def, _) => {
+                let definition = tables.res.def(*def);
+                !definition.is_prelude() && definition.kind != DefKind::External
+            }
+            Ty::Ref { ty, .. } => is_local(tables, ty),
+            _ => false,
+        }
+    }
+    if is_local(tables, &imp.self_ty) {
+        return;
+    }
+    diags.push(
+        Diagnostic::error("only traits defined in the current crate can be implemented for types defined outside of the crate")
+            .with_code(codes::ORPHAN_IMPL)
+            .with_primary(header_span(imp), "impl doesn't use only types from inside the current crate")
+            .with_note("define and implement a trait or new type instead"),
+    );
+}
+
+/// Bounds an impl places on its parameters, as matched against a use
+pub fn impl_predicates(imp: &ImplDef, args: &HashMap<DefId, Ty>) -> Vec<(Ty, DefId, Span)> {
+    imp.predicates.iter().map(|p| (p.ty.subst(args), p.trait_def, p.span)).collect()
+}
+
+/// Signature of a trait method as seen through `self_ty`
+pub fn method_sig_for(sig: &FnSig, def: &TraitDef, self_ty: &Ty) -> FnSig {
+    let map = HashMap::from([(def.self_param, self_ty.clone())]);
+    FnSig {
+        generics: sig.generics.iter().copied().filter(|g| *g != def.self_param).collect(),
+        inputs: sig.inputs.iter().map(|t| t.subst(&map)).collect(),
+        output: sig.output.subst(&map),
+        has_self: sig.has_self,
+        predicates: sig
+            .predicates
+            .iter()
+            .map(|p| crate::collect::Predicate { ty: p.ty.subst(&map), trait_def: p.trait_def, span: p.span })
+            .collect(),
+    }
+}
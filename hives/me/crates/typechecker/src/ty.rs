@@ -0,0 +1,223 @@
+// Types
+// The semantic type representation shared by inference and later phases
+
+use my_lang_ast::PrimitiveType;
+use my_lang_resolve::{DefId, Resolution};
+use std::collections::HashMap;
+use std::fmt;
+
+/// An inference variable. Integer and float variables only unify with
+/// numeric types of their kind and default to `i32` and `f64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InferTy {
+    Var(u32),
+    Int(u32),
+    Float(u32),
+}
+
+impl InferTy {
+    pub fn index(self) -> u32 {
+        match self {
+            InferTy::Var(i) | InferTy::Int(i) | InferTy::Float(i) => i,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Ty {
+    /// Every primitive except `()` and `!`, which are `Tuple([])` and `Never`
+    Prim(PrimitiveType),
+    Tuple(Vec<Ty>),
+    Array(Box<Ty>, Option<usize>),
+    Ref { ty: Box<Ty>, mutable: bool },
+    Fn(Vec<Ty>, Box<Ty>),
+    /// A struct, enum or library type with its type arguments
+    Adt(DefId, Vec<Ty>),
+    /// A generic parameter, or `Self` inside a trait
+    Param(DefId),
+    /// Associated type `item` of a type known only by its bounds, such as `T::Item`
+    Assoc { self_ty: Box<Ty>, item: DefId },
+    Infer(InferTy),
+    Never,
+    /// Stands in for a type that already produced an error; unifies with anything
+    Error,
+}
+
+impl Ty {
+    pub fn unit() -> Ty {
+        Ty::Tuple(Vec::new())
+    }
+
+    pub fn bool() -> Ty {
+        Ty::Prim(PrimitiveType::Bool)
+    }
+
+    pub fn from_primitive(prim: PrimitiveType) -> Ty {
+        match prim {
+            PrimitiveType::Unit => Ty::unit(),
+            PrimitiveType::Never => Ty::Never,
+            prim => Ty::Prim(prim),
+        }
+    }
+
+    pub fn is_unit(&self) -> bool {
+        matches!(self, Ty::Tuple(elems) if elems.is_empty())
+    }
+
+    pub fn is_integral(&self) -> bool {
+        matches!(self, Ty::Prim(p) if is_int(*p)) || matches!(self, Ty::Infer(InferTy::Int(_)))
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, Ty::Prim(PrimitiveType::F32 | PrimitiveType::F64))
+            || matches!(self, Ty::Infer(InferTy::Float(_)))
+    }
+
+    pub fn references_error(&self) -> bool {
+        let mut found = false;
+        self.walk(&mut |ty| found |= matches!(ty, Ty::Error));
+        found
+    }
+
+    /// Visit this type and every type nested in it
+    pub fn walk(&self, f: &mut impl FnMut(&Ty)) {
+        f(self);
+        match self {
+            Ty::Tuple(elems) | Ty::Adt(_, elems) => elems.iter().for_each(|t| t.walk(f)),
+            Ty::Array(elem, _) | Ty::Ref { ty: elem, .. } | Ty::Assoc { self_ty: elem, .. } => elem.walk(f),
+            Ty::Fn(params, ret) => {
+                params.iter().for_each(|t| t.walk(f));
+                ret.walk(f);
+            }
+            Ty::Prim(_) | Ty::Param(_) | Ty::Infer(_) | Ty::Never | Ty::Error => {}
+        }
+    }
+
+    /// Rebuild the type bottom-up, replacing each node `f` maps to `Some`
+    pub fn fold(&self, f: &mut impl FnMut(&Ty) -> Option<Ty>) -> Ty {
+        if let Some(ty) = f(self) {
+            return ty;
+        }
+        match self {
+            Ty::Tuple(elems) => Ty::Tuple(elems.iter().map(|t| t.fold(f)).collect()),
+            Ty::Adt(def, args) => Ty::Adt(*def, args.iter().map(|t| t.fold(f)).collect()),
+            Ty::Array(elem, len) => Ty::Array(Box::new(elem.fold(f)), *len),
+            Ty::Ref { ty, mutable } => Ty::Ref { ty: Box::new(ty.fold(f)), mutable: *mutable },
+            Ty::Assoc { self_ty, item } => Ty::Assoc { self_ty: Box::new(self_ty.fold(f)), item: *item },
+            Ty::Fn(params, ret) => Ty::Fn(params.iter().map(|t| t.fold(f)).collect(), Box::new(ret.fold(f))),
+            Ty::Prim(_) | Ty::Param(_) | Ty::Infer(_) | Ty::Never | Ty::Error => self.clone(),
+        }
+    }
+
+    /// Replace generic parameters by the types `map` gives them
+    pub fn subst(&self, map: &HashMap<DefId, Ty>) -> Ty {
+        if map.is_empty() {
+            return self.clone();
+        }
+        self.fold(&mut |ty| match ty {
+            Ty::Param(def) => map.get(def).cloned(),
+            _ => None,
+        })
+    }
+
+    /// Printable form, naming definitions through `res`
+    pub fn display<'a>(&'a self, res: &'a Resolution) -> DisplayTy<'a> {
+        DisplayTy { ty: self, res }
+    }
+}
+
+pub(crate) fn is_int(prim: PrimitiveType) -> bool {
+    use PrimitiveType::*;
+    matches!(prim, I8 | I16 | I32 | I64 | I128 | Isize | U8 | U16 | U32 | U64 | U128 | Usize)
+}
+
+pub(crate) fn primitive_name(prim: PrimitiveType) -> &'static str {
+    use PrimitiveType::*;
+    match prim {
+        I8 => "i8",
+        I16 => "i16",
+        I32 => "i32",
+        I64 => "i64",
+        I128 => "i128",
+        Isize => "isize",
+        U8 => "u8",
+        U16 => "u16",
+        U32 => "u32",
+        U64 => "u64",
+        U128 => "u128",
+        Usize => "usize",
+        F32 => "f32",
+        F64 => "f64",
+        Bool => "bool",
+        Char => "char",
+        Str => "str",
+        Unit => "()",
+        Never => "!",
+    }
+}
+
+pub struct DisplayTy<'a> {
+    ty: &'a Ty,
+    res: &'a Resolution,
+}
+
+impl fmt::Display for DisplayTy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter, tys: &[Ty]| -> fmt::Result {
+            for (i, ty) in tys.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}", ty.display(self.res))?;
+            }
+            Ok(())
+        };
+        match self.ty {
+            Ty::Prim(prim) => f.write_str(primitive_name(*prim)),
+            Ty::Tuple(elems) => {
+                f.write_str("(")?;
+                list(f, elems)?;
+                if elems.len() == 1 {
+                    f.write_str(",")?;
+                }
+                f.write_str(")")
+            }
+            Ty::Array(elem, Some(len)) => write!(f, "[{}; {}]", elem.display(self.res), len),
+            Ty::Array(elem, None) => write!(f, "[{}]", elem.display(self.res)),
+            Ty::Ref { ty, mutable } => {
+                write!(f, "&{}{}", if *mutable { "mut " } else { "" }, ty.display(self.res))
+            }
+            Ty::Fn(params, ret) => {
+                f.write_str("fn(")?;
+                list(f, params)?;
+                f.write_str(")")?;
+                if !ret.is_unit() {
+                    write!(f, " -> {}", ret.display(self.res))?;
+                }
+                Ok(())
+            }
+            Ty::Adt(def, args) => {
+                f.write_str(&self.res.def(*def).name)?;
+                if !args.is_empty() {
+                    f.write_str("<")?;
+                    list(f, args)?;
+                    f.write_str(">")?;
+                }
+                Ok(())
+            }
+            Ty::Param(def) => f.write_str(&self.res.def(*def).name),
+            Ty::Assoc { self_ty, item } => {
+                let item = self.res.def(*item);
+                match item.parent {
+                    Some(t) => write!(f, "<{} as {}>::{}", self_ty.display(self.res), self.res.def(t).name, item.name),
+                    None => write!(f, "{}::{}", self_ty.display(self.res), item.name),
+                }
+            }
+            Ty::Infer(InferTy::Var(_)) => f.write_str("_"),
+            Ty::Infer(InferTy::Int(_)) => f.write_str("{integer}"),
+            Ty::Infer(InferTy::Float(_)) => f.write_str("{float}"),
+            Ty::Never => f.write_str("!"),
+            Ty::Error => f.write_str("{error}"),
+        }
+    }
+}
@@ -0,0 +1,10 @@
+// Static contract verification
+// Proves `pre` and `post` clauses of integer and boolean functions by handing
+// verification conditions to an external SMT solver such as z3 or cvc5
+
+pub mod smt;
+mod solver;
+mod vc;
+
+pub use solver::{Outcome, Solver, SolverError};
+pub use vc::{obligations, ClauseKind, Obligation, Query};
@@ -0,0 +1,223 @@
+// SMT-LIB terms
+// Typed s-expressions over integers and booleans, the fragment verification conditions use
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sort {
+    Int,
+    Bool,
+    /// `()`, which never reaches the solver
+    Unit,
+}
+
+impl fmt::Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Sort::Int => "Int",
+            Sort::Bool => "Bool",
+            Sort::Unit => "Unit",
+        })
+    }
+}
+
+/// An SMT-LIB expression and its sort
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Term {
+    text: String,
+    pub sort: Sort,
+}
+
+impl Term {
+    pub fn int(value: impl Into<i128>) -> Term {
+        let value = value.into();
+        let text = if value < 0 { format!("(- {})", value.unsigned_abs()) } else { value.to_string() };
+        Term { text, sort: Sort::Int }
+    }
+
+    pub fn bool(value: bool) -> Term {
+        Term { text: value.to_string(), sort: Sort::Bool }
+    }
+
+    pub fn unit() -> Term {
+        Term { text: "unit".to_string(), sort: Sort::Unit }
+    }
+
+    /// A declared constant or an applied function symbol
+    pub fn var(symbol: &str, sort: Sort) -> Term {
+        Term { text: symbol.to_string(), sort }
+    }
+
+    /// `(op args...)`, or the symbol alone without arguments
+    pub fn app(op: &str, args: &[Term], sort: Sort) -> Term {
+        if args.is_empty() {
+            return Term::var(op, sort);
+        }
+        let mut text = format!("({}", op);
+        for arg in args {
+            text.push(' ');
+            text.push_str(&arg.text);
+        }
+        text.push(')');
+        Term { text, sort }
+    }
+
+    pub fn not(&self) -> Term {
+        Term::app("not", std::slice::from_ref(self), Sort::Bool)
+    }
+
+    /// Conjunction of `terms`, `true` when there are none
+    pub fn and(terms: &[Term]) -> Term {
+        match terms {
+            [] => Term::bool(true),
+            [term] => term.clone(),
+            _ => Term::app("and", terms, Sort::Bool),
+        }
+    }
+
+    pub fn implies(&self, then: &Term) -> Term {
+        Term::app("=>", &[self.clone(), then.clone()], Sort::Bool)
+    }
+
+    pub fn eq(&self, other: &Term) -> Term {
+        Term::app("=", &[self.clone(), other.clone()], Sort::Bool)
+    }
+
+    pub fn ite(cond: &Term, then: &Term, otherwise: &Term) -> Term {
+        Term::app("ite", &[cond.clone(), then.clone(), otherwise.clone()], then.sort)
+    }
+
+    /// `lo <= self <= hi`
+    pub fn within(&self, lo: i128, hi: i128) -> Term {
+        Term::app("<=", &[Term::int(lo), self.clone(), Term::int(hi)], Sort::Bool)
+    }
+
+    /// Division rounding toward zero. SMT-LIB `div` is Euclidean, which
+    /// only agrees with it for non-negative dividends.
+    pub fn trunc_div(a: &Term, b: &Term) -> Term {
+        let non_negative = Term::app(">=", &[a.clone(), Term::int(0)], Sort::Bool);
+        let neg_a = Term::app("-", std::slice::from_ref(a), Sort::Int);
+        let flipped = Term::app("-", &[Term::app("div", &[neg_a, b.clone()], Sort::Int)], Sort::Int);
+        Term::ite(&non_negative, &Term::app("div", &[a.clone(), b.clone()], Sort::Int), &flipped)
+    }
+
+    /// Remainder with the sign of the dividend, matching `trunc_div`
+    pub fn trunc_rem(a: &Term, b: &Term) -> Term {
+        let product = Term::app("*", &[b.clone(), Term::trunc_div(a, b)], Sort::Int);
+        Term::app("-", &[a.clone(), product], Sort::Int)
+    }
+
+    pub fn is_atom(&self) -> bool {
+        !self.text.starts_with('(')
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Symbols SMT-LIB reserves or predefines in the integer logic
+const RESERVED: &[&str] = &[
+    "abs", "and", "as", "assert", "distinct", "div", "exists", "false", "forall", "ite", "let", "mod", "not", "or",
+    "par", "rem", "true", "unit", "xor",
+];
+
+/// `name` as an SMT-LIB symbol, quoted when it would clash with a
+/// predefined one or contains characters simple symbols cannot
+pub fn symbol(name: &str) -> String {
+    let simple = name.chars().all(|c| c.is_ascii_alphanumeric() || "~!@$%^&*_-+=<>.?/".contains(c))
+        && !name.starts_with(|c: char| c.is_ascii_digit());
+    if simple && !RESERVED.contains(&name) {
+        name.to_string()
+    } else {
+        format!("|{}|", name)
+    }
+}
+
+/// Parsed solver output, for reading `get-value` responses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SExpr {
+    Atom(String),
+    List(Vec<SExpr>),
+}
+
+impl SExpr {
+    /// Parse the first s-expression in `text`
+    pub fn parse(text: &str) -> Option<SExpr> {
+        let mut chars = text.chars().peekable();
+        parse_sexpr(&mut chars)
+    }
+
+    /// The value as source syntax: `(- 3)` becomes `-3`
+    pub fn display_value(&self) -> String {
+        match self {
+            SExpr::Atom(atom) => atom.trim_matches('|').to_string(),
+            SExpr::List(items) => match items.as_slice() {
+                [SExpr::Atom(minus), value] if minus == "-" => format!("-{}", value.display_value()),
+                _ => format!("({})", items.iter().map(SExpr::display_value).collect::<Vec<_>>().join(" ")),
+            },
+        }
+    }
+}
+
+fn parse_sexpr(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<SExpr> {
+    while chars.peek()?.is_whitespace() {
+        chars.next();
+    }
+    match chars.next()? {
+        '(' => {
+            let mut items = Vec::new();
+            loop {
+                while chars.peek()?.is_whitespace() {
+                    chars.next();
+                }
+                if chars.peek() == Some(&')') {
+                    chars.next();
+                    return Some(SExpr::List(items));
+                }
+                items.push(parse_sexpr(chars)?);
+            }
+        }
+        ')' => None,
+        '|' => {
+            let mut atom = String::from("|");
+            for c in chars.by_ref() {
+                atom.push(c);
+                if c == '|' {
+                    return Some(SExpr::Atom(atom));
+                }
+            }
+            None
+        }
+        first => {
+            let mut atom = first.to_string();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '(' || c == ')' {
+                    break;
+                }
+                atom.push(c);
+                chars.next();
+            }
+            Some(SExpr::Atom(atom))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_terms() {
+        let x = Term::var("x", Sort::Int);
+        assert_eq!(Term::int(-3).to_string(), "(- 3)");
+        assert_eq!(x.within(0, 255).to_string(), "(<= 0 x 255)");
+        assert_eq!(Term::and(&[]).to_string(), "true");
+        assert_eq!(symbol("div"), "|div|");
+        assert_eq!(symbol("Point::new"), "|Point::new|");
+        assert_eq!(symbol("n!2"), "n!2");
+        assert_eq!(SExpr::parse("((x (- 4)))").map(|e| e.display_value()), Some("((x -4))".to_string()));
+    }
+}
@@ -0,0 +1,142 @@
+// Solver processes
+// Runs an SMT-LIB2 script through an external solver and reads back its verdict
+
+use crate::smt::SExpr;
+use crate::vc::Query;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use thiserror::Error;
+
+/// Solvers looked for on `PATH`, in order of preference
+const KNOWN_SOLVERS: &[&str] = &["z3", "cvc5"];
+
+#[derive(Error, Debug)]
+pub enum SolverError {
+    #[error("couldn't run {}: {source}", .solver.display())]
+    Spawn { solver: PathBuf, source: std::io::Error },
+
+    #[error("{} gave no verdict: {output}", .solver.display())]
+    NoVerdict { solver: PathBuf, output: String },
+}
+
+/// What a solver concluded about one clause
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Proven,
+    /// Argument values under which the clause fails
+    Refuted {
+        counterexample: Vec<(String, String)>,
+    },
+    Unknown {
+        reason: String,
+    },
+}
+
+/// A z3 or cvc5 compatible binary
+#[derive(Debug, Clone)]
+pub struct Solver {
+    path: PathBuf,
+    /// Seconds per query
+    timeout: u64,
+}
+
+impl Solver {
+    pub fn new(path: impl Into<PathBuf>, timeout: u64) -> Self {
+        Self { path: path.into(), timeout }
+    }
+
+    /// The first known solver on `PATH`
+    pub fn find(timeout: u64) -> Option<Solver> {
+        let dirs: Vec<PathBuf> = std::env::split_paths(&std::env::var_os("PATH")?).collect();
+        KNOWN_SOLVERS
+            .iter()
+            .flat_map(|name| dirs.iter().map(move |dir| dir.join(name)))
+            .find(|path| path.is_file())
+            .map(|path| Solver::new(path, timeout))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Arguments reading a script from stdin with the time limit applied
+    fn args(&self) -> Vec<String> {
+        let name = self.path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+        if name.starts_with("cvc") {
+            vec!["--lang=smt2".to_string(), format!("--tlimit-per={}", self.timeout * 1000)]
+        } else {
+            vec!["-in".to_string(), "-smt2".to_string(), format!("-T:{}", self.timeout)]
+        }
+    }
+
+    pub fn check(&self, query: &Query) -> Result<Outcome, SolverError> {
+        let spawn = |source| SolverError::Spawn { solver: self.path.clone(), source };
+        let mut child = Command::new(&self.path)
+            .args(self.args())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(spawn)?;
+        child.stdin.take().expect("stdin is piped").write_all(query.script.as_bytes()).map_err(spawn)?;
+        let output = child.wait_with_output().map_err(spawn)?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        parse_output(&stdout, query).ok_or_else(|| SolverError::NoVerdict {
+            solver: self.path.clone(),
+            output: format!("{}{}", stdout, String::from_utf8_lossy(&output.stderr)).trim().to_string(),
+        })
+    }
+}
+
+/// Read the `check-sat` verdict and, after `sat`, the `get-value` model
+fn parse_output(stdout: &str, query: &Query) -> Option<Outcome> {
+    let mut lines = stdout.lines().map(str::trim).filter(|line| !line.is_empty());
+    match lines.next()? {
+        "unsat" => Some(Outcome::Proven),
+        "sat" => {
+            let rest: String = lines.collect::<Vec<_>>().join(" ");
+            let mut values = Vec::new();
+            if let Some(SExpr::List(pairs)) = SExpr::parse(&rest) {
+                for pair in pairs {
+                    if let SExpr::List(pair) = pair {
+                        if let [SExpr::Atom(symbol), value] = pair.as_slice() {
+                            values.push((symbol.trim_matches('|').to_string(), value.display_value()));
+                        }
+                    }
+                }
+            }
+            // Report under the source names, in parameter order
+            let counterexample = query
+                .model
+                .iter()
+                .filter_map(|(name, symbol)| {
+                    let value = values.iter().find(|(s, _)| s == symbol.trim_matches('|'))?;
+                    Some((name.clone(), value.1.clone()))
+                })
+                .collect();
+            Some(Outcome::Refuted { counterexample })
+        }
+        "unknown" | "timeout" => Some(Outcome::Unknown { reason: "the solver gave up".to_string() }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        let query = Query {
+            script: String::new(),
+            model: vec![("a".to_string(), "a".to_string()), ("div".to_string(), "|div|".to_string())],
+        };
+        assert_eq!(parse_output("unsat\n(error \"model is not available\")\n", &query), Some(Outcome::Proven));
+        let refuted = parse_output("sat\n((a (- 3))\n (|div| 0))\n", &query);
+        let counterexample = vec![("a".to_string(), "-3".to_string()), ("div".to_string(), "0".to_string())];
+        assert_eq!(refuted, Some(Outcome::Refuted { counterexample }));
+        assert!(matches!(parse_output("unknown\n", &query), Some(Outcome::Unknown { .. })));
+        assert_eq!(parse_output("(error \"unsupported\")", &query), None);
+    }
+}
@@ -0,0 +1,746 @@
+// Verification conditions
+// Encodes integer and boolean function bodies as SMT-LIB facts, with one
+// query per postcondition, per precondition at each call site and per
+// arithmetic operation
+
+use crate::smt::{symbol, Sort, Term};
+use my_lang_ast::*;
+use my_lang_resolve::{DefId, Resolution};
+use my_lang_typechecker::{Ty, TypeckResults};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClauseKind {
+    Precondition,
+    Postcondition,
+    /// An arithmetic result fits its type and no divisor is zero
+    Arithmetic,
+}
+
+impl fmt::Display for ClauseKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ClauseKind::Precondition => "precondition",
+            ClauseKind::Postcondition => "postcondition",
+            ClauseKind::Arithmetic => "arithmetic check",
+        })
+    }
+}
+
+/// One clause to prove: a postcondition of `function`, a precondition of
+/// `function` at a call in `caller`, or an arithmetic check in `function`
+#[derive(Debug, Clone)]
+pub struct Obligation {
+    pub kind: ClauseKind,
+    pub function: String,
+    pub caller: String,
+    /// Source text of the clause, or of the operation for arithmetic checks
+    pub clause: String,
+    /// The clause for postconditions, the call for preconditions, the operation for arithmetic checks
+    pub span: Span,
+    /// The query, or why the clause is out of reach of the encoding
+    pub query: Result<Query, String>,
+}
+
+/// An SMT-LIB2 script that is unsatisfiable exactly when a clause holds
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub script: String,
+    /// Source name and SMT symbol of each parameter of the caller, read back as the counterexample
+    pub model: Vec<(String, String)>,
+}
+
+/// A function the encoding can refer to
+struct FnInfo<'p> {
+    func: &'p Function,
+    source: &'p str,
+    /// Display name, `Type::name` for associated functions
+    name: String,
+    /// Uninterpreted function symbol standing for its result
+    symbol: String,
+}
+
+struct Context<'p> {
+    res: &'p Resolution,
+    types: &'p TypeckResults,
+    fns: HashMap<DefId, FnInfo<'p>>,
+}
+
+/// Obligations for every contract in a checked crate, given each module's
+/// program and source text. Every arithmetic operation in a body owes that
+/// its result fits its type, as the operation panics otherwise; within
+/// contract clauses integers are unbounded.
+pub fn obligations(modules: &[(&Program, &str)], res: &Resolution, types: &TypeckResults) -> Vec<Obligation> {
+    let mut cx = Context { res, types, fns: HashMap::new() };
+    for (program, source) in modules {
+        cx.collect(&program.items, source, None);
+    }
+    let mut infos: Vec<&FnInfo> = cx.fns.values().collect();
+    infos.sort_by_key(|info| (info.func.span.file, info.func.span.start));
+    let mut obligations = Vec::new();
+    for info in infos {
+        obligations.extend(encode_fn(&cx, info));
+    }
+    obligations
+}
+
+impl<'p> Context<'p> {
+    fn collect(&mut self, items: &'p [Item], source: &'p str, owner: Option<&str>) {
+        for item in items {
+            match &item.kind {
+                ItemKind::Function(func) => self.add(func, source, owner),
+                ItemKind::Impl(imp) if imp.trait_name.is_none() => {
+                    let owner = source.get(imp.self_ty.span.start..imp.self_ty.span.end).unwrap_or("Self");
+                    for item in &imp.items {
+                        if let ImplItem::Function(func) = item {
+                            self.add(func, source, Some(owner));
+                        }
+                    }
+                }
+                ItemKind::Module(module) => self.collect(&module.items, source, None),
+                _ => {}
+            }
+        }
+    }
+
+    fn add(&mut self, func: &'p Function, source: &'p str, owner: Option<&str>) {
+        let Some(def) = self.res.def_of_node(func.id) else {
+            return;
+        };
+        let name = match owner {
+            Some(owner) => format!("{}::{}", owner, func.name),
+            None => func.name.clone(),
+        };
+        // Same-named functions in different modules get distinct symbols
+        let mut symbol_name = name.clone();
+        if self.fns.values().any(|info| info.name == name) {
+            symbol_name = format!("{}!{}", name, def.0);
+        }
+        self.fns.insert(def, FnInfo { func, source, name, symbol: symbol(&symbol_name) });
+    }
+
+    /// The function a call expression's callee names
+    fn callee(&self, call: &Expression, callee: &Expression) -> Option<&FnInfo<'p>> {
+        let def = match self.res.definition_of(callee.id) {
+            Some(def) => def.id,
+            None => *self.types.method_calls.get(&callee.id).or_else(|| self.types.method_calls.get(&call.id))?,
+        };
+        self.fns.get(&def)
+    }
+}
+
+/// Obligations for `info`'s postconditions, and for the preconditions of the
+/// calls and the arithmetic checks in its body
+fn encode_fn(cx: &Context, info: &FnInfo) -> Vec<Obligation> {
+    let func = info.func;
+    let (pre, post) = match &func.contract {
+        Some(contract) => (contract.preconditions.as_slice(), contract.postconditions.as_slice()),
+        None => (&[][..], &[][..]),
+    };
+    let text = |cond: &Expression| info.source.get(cond.span.start..cond.span.end).unwrap_or_default().to_string();
+    let mut enc = Encoder::new(cx, info);
+
+    let body = enc.signature(func).and_then(|model| {
+        for cond in pre {
+            let fact = enc.clause(cond)?;
+            enc.facts.push(fact);
+        }
+        let value = enc.block(&func.body)?;
+        Ok((model, value))
+    });
+    let (model, value) = match body {
+        Ok(encoded) => encoded,
+        // Nothing is known about the body, so none of its obligations can be checked
+        Err(Unsupported(reason)) => {
+            return post
+                .iter()
+                .map(|cond| Obligation {
+                    kind: ClauseKind::Postcondition,
+                    function: info.name.clone(),
+                    caller: info.name.clone(),
+                    clause: text(cond),
+                    span: cond.span,
+                    query: Err(reason.clone()),
+                })
+                .collect();
+        }
+    };
+
+    let mut obligations = Vec::new();
+    for owed in std::mem::take(&mut enc.owed) {
+        let script =
+            enc.script(&format!("{} `{}` of `{}`", owed.kind, owed.clause, owed.function), &owed.path, &owed.goal);
+        obligations.push(Obligation {
+            kind: owed.kind,
+            function: owed.function,
+            caller: info.name.clone(),
+            clause: owed.clause,
+            span: owed.span,
+            query: Ok(Query { script, model: model.clone() }),
+        });
+    }
+
+    if !post.is_empty() {
+        let result = enc.result(func, value);
+        enc.bind("result", result);
+    }
+    for cond in post {
+        let query = enc.clause(cond).map_err(|Unsupported(reason)| reason).map(|goal| {
+            let script = enc.script(&format!("postcondition `{}` of `{}`", text(cond), info.name), &[], &goal);
+            Query { script, model: model.clone() }
+        });
+        obligations.push(Obligation {
+            kind: ClauseKind::Postcondition,
+            function: info.name.clone(),
+            caller: info.name.clone(),
+            clause: text(cond),
+            span: cond.span,
+            query,
+        });
+    }
+    obligations
+}
+
+/// Why a function or clause could not be encoded
+struct Unsupported(String);
+
+type Encoded<T> = Result<T, Unsupported>;
+
+fn unsupported<T>(what: impl Into<String>) -> Encoded<T> {
+    Err(Unsupported(format!("{} not supported", what.into())))
+}
+
+/// Something owed at one point of a body: a callee precondition where it is
+/// called, or an arithmetic check where the operation runs
+struct Owed {
+    kind: ClauseKind,
+    function: String,
+    clause: String,
+    span: Span,
+    /// Branch conditions under which the call or operation runs
+    path: Vec<Term>,
+    goal: Term,
+}
+
+/// Symbolic execution of one function body. Locals and call results become
+/// constants pinned down by facts, and `return`s are collected with the
+/// branch conditions they run under.
+struct Encoder<'c, 'p> {
+    cx: &'c Context<'p>,
+    info: &'c FnInfo<'p>,
+    consts: Vec<(String, Sort)>,
+    funs: Vec<(String, Vec<Sort>, Sort)>,
+    facts: Vec<Term>,
+    env: Vec<(String, Term)>,
+    /// Symbols of the parameters, whose values make up a counterexample
+    params: Vec<String>,
+    path: Vec<Term>,
+    returns: Vec<(Term, Term)>,
+    owed: Vec<Owed>,
+    /// Inside a contract clause, where calls are uninterpreted, integers are
+    /// unbounded and nothing is owed
+    in_clause: bool,
+    next_fresh: u32,
+}
+
+impl<'c, 'p> Encoder<'c, 'p> {
+    fn new(cx: &'c Context<'p>, info: &'c FnInfo<'p>) -> Self {
+        Self {
+            cx,
+            info,
+            consts: Vec::new(),
+            funs: Vec::new(),
+            facts: Vec::new(),
+            env: Vec::new(),
+            params: Vec::new(),
+            path: Vec::new(),
+            returns: Vec::new(),
+            owed: Vec::new(),
+            in_clause: false,
+            next_fresh: 0,
+        }
+    }
+
+    /// Declare the parameters, returning them as the model to read back
+    fn signature(&mut self, func: &Function) -> Encoded<Vec<(String, String)>> {
+        let mut model = Vec::new();
+        for param in &func.params {
+            let (sort, range) = sort_of(&param.ty)
+                .filter(|(sort, _)| *sort != Sort::Unit)
+                .ok_or_else(|| Unsupported(format!("parameter `{}` is not an integer or `bool`", param.name)))?;
+            let name = symbol(&param.name);
+            self.params.push(name.clone());
+            self.consts.push((name.clone(), sort));
+            let term = Term::var(&name, sort);
+            if let Some((lo, hi)) = range {
+                self.facts.push(term.within(lo, hi));
+            }
+            self.bind(&param.name, term);
+            model.push((param.name.clone(), name));
+        }
+        if let Some(ty) = &func.return_type {
+            sort_of(ty).ok_or_else(|| Unsupported("the result is not an integer or `bool`".to_string()))?;
+        }
+        Ok(model)
+    }
+
+    /// The value of the body along every exit
+    fn result(&mut self, func: &Function, tail: Option<Term>) -> Term {
+        let mut exits = std::mem::take(&mut self.returns);
+        exits.extend(tail.map(|value| (Term::and(&self.path), value)));
+        let sort = func.return_type.as_ref().and_then(sort_of).map_or(Sort::Unit, |(sort, _)| sort);
+        if sort == Sort::Unit {
+            return Term::unit();
+        }
+        let Some((_, last)) = exits.pop() else {
+            // The body never returns, so any result satisfies its postconditions
+            return self.fresh("result", sort);
+        };
+        let value = exits.iter().rev().fold(last, |acc, (guard, value)| Term::ite(guard, value, &acc));
+        self.define("result", value)
+    }
+
+    fn clause(&mut self, cond: &Expression) -> Encoded<Term> {
+        let in_clause = std::mem::replace(&mut self.in_clause, true);
+        let term = self.expr(cond);
+        self.in_clause = in_clause;
+        let term = term?.ok_or_else(|| Unsupported("`return` in a contract is".to_string()))?;
+        if term.sort != Sort::Bool {
+            return unsupported("a clause that is not a `bool` is");
+        }
+        Ok(term)
+    }
+
+    fn script(&self, comment: &str, path: &[Term], goal: &Term) -> String {
+        let mut script = format!("; {}\n(set-logic ALL)\n(set-option :produce-models true)\n", comment);
+        for (name, sort) in &self.consts {
+            script.push_str(&format!("(declare-const {} {})\n", name, sort));
+        }
+        for (name, args, sort) in &self.funs {
+            let args: Vec<String> = args.iter().map(Sort::to_string).collect();
+            script.push_str(&format!("(declare-fun {} ({}) {})\n", name, args.join(" "), sort));
+        }
+        for fact in self.facts.iter().chain(path) {
+            script.push_str(&format!("(assert {})\n", fact));
+        }
+        script.push_str(&format!("(assert {})\n(check-sat)\n", goal.not()));
+        if !self.params.is_empty() {
+            script.push_str(&format!("(get-value ({}))\n", self.params.join(" ")));
+        }
+        script
+    }
+
+    // ========== Bindings ==========
+
+    fn bind(&mut self, name: &str, term: Term) {
+        self.env.push((name.to_string(), term));
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Term> {
+        self.env.iter().rev().find(|(n, _)| n == name).map(|(_, term)| term)
+    }
+
+    fn fresh(&mut self, hint: &str, sort: Sort) -> Term {
+        self.next_fresh += 1;
+        let name = symbol(&format!("{}!{}", hint, self.next_fresh));
+        if sort != Sort::Unit {
+            self.consts.push((name.clone(), sort));
+        }
+        Term::var(&name, sort)
+    }
+
+    /// A constant equal to `value`, so that later terms mention it by name
+    fn define(&mut self, hint: &str, value: Term) -> Term {
+        if value.is_atom() {
+            return value;
+        }
+        let constant = self.fresh(hint, value.sort);
+        self.facts.push(constant.eq(&value));
+        constant
+    }
+
+    // ========== Expressions ==========
+
+    fn block(&mut self, block: &Block) -> Encoded<Option<Term>> {
+        let scope = self.env.len();
+        for stmt in &block.stmts {
+            let reached = match &stmt.kind {
+                StatementKind::Let { pattern, init: Some(init), .. } => {
+                    let PatternKind::Identifier(name) = &pattern.kind else {
+                        return unsupported("destructuring is");
+                    };
+                    self.expr(init)?.map(|value| {
+                        let value = self.define(name, value);
+                        self.bind(name, value);
+                    })
+                }
+                StatementKind::Let { .. } => return unsupported("`let` without a value is"),
+                StatementKind::Expression(expr) => self.expr(expr)?.map(drop),
+                StatementKind::Item(_) | StatementKind::Error => return unsupported("a nested item is"),
+            };
+            if reached.is_none() {
+                self.env.truncate(scope);
+                return Ok(None);
+            }
+        }
+        let value = match &block.expr {
+            Some(expr) => self.expr(expr)?,
+            None => Some(Term::unit()),
+        };
+        self.env.truncate(scope);
+        Ok(value)
+    }
+
+    /// The value of `expr`, or `None` when it always returns early
+    fn expr(&mut self, expr: &Expression) -> Encoded<Option<Term>> {
+        let term = match &expr.kind {
+            ExpressionKind::Literal(Literal::Int(n)) => Term::int(*n),
+            ExpressionKind::Literal(Literal::Bool(b)) => Term::bool(*b),
+            ExpressionKind::Literal(Literal::Unit) => Term::unit(),
+            ExpressionKind::Identifier(name) => match self.lookup(name) {
+                Some(term) => term.clone(),
+                None => return Err(Unsupported(format!("`{}` is not a parameter or local", name))),
+            },
+            ExpressionKind::Unary { op, expr: operand } => {
+                let Some(value) = self.expr(operand)? else {
+                    return Ok(None);
+                };
+                match (op, value.sort) {
+                    (UnaryOp::Neg, Sort::Int) => self.checked(expr, Term::app("-", &[value], Sort::Int), None),
+                    (UnaryOp::Not, Sort::Bool) => value.not(),
+                    _ => return unsupported(format!("unary `{:?}` is", op)),
+                }
+            }
+            ExpressionKind::Binary { left, op, right } => return self.binary(expr, left, op, right),
+            ExpressionKind::If { cond, then_block, else_block } => {
+                let Some(cond) = self.expr(cond)? else {
+                    return Ok(None);
+                };
+                let then = self.branch(cond.clone(), |enc| enc.block(then_block))?;
+                let otherwise = match else_block {
+                    Some(block) => self.branch(cond.not(), |enc| enc.block(block))?,
+                    None => Some(Term::unit()),
+                };
+                match (then, otherwise) {
+                    (Some(then), Some(_)) if then.sort == Sort::Unit => Term::unit(),
+                    (Some(then), Some(otherwise)) => Term::ite(&cond, &then, &otherwise),
+                    // Code after the `if` only runs when the branch that returned was not taken
+                    (Some(then), None) => {
+                        self.path.push(cond);
+                        then
+                    }
+                    (None, Some(otherwise)) => {
+                        self.path.push(cond.not());
+                        otherwise
+                    }
+                    (None, None) => return Ok(None),
+                }
+            }
+            ExpressionKind::Block(block) => return self.block(block),
+            ExpressionKind::Comptime(inner) => return self.expr(inner),
+            ExpressionKind::Return(value) => {
+                if self.in_clause {
+                    return unsupported("`return` in a contract is");
+                }
+                let value = match value {
+                    Some(value) => match self.expr(value)? {
+                        Some(value) => value,
+                        None => return Ok(None),
+                    },
+                    None => Term::unit(),
+                };
+                self.returns.push((Term::and(&self.path), value));
+                return Ok(None);
+            }
+            ExpressionKind::Call { func, args } => return self.call(expr, func, args),
+            ExpressionKind::While { .. } | ExpressionKind::Loop(_) | ExpressionKind::For { .. } => {
+                return unsupported("a loop is")
+            }
+            ExpressionKind::MethodCall { method, .. } => return unsupported(format!("method call `{}` is", method)),
+            _ => return unsupported("this kind of expression is"),
+        };
+        Ok(Some(term))
+    }
+
+    /// Encode `f` under the extra branch condition `cond`
+    fn branch<T>(&mut self, cond: Term, f: impl FnOnce(&mut Self) -> Encoded<T>) -> Encoded<T> {
+        let depth = self.path.len();
+        self.path.push(cond);
+        let result = f(self);
+        self.path.truncate(depth);
+        result
+    }
+
+    fn binary(
+        &mut self,
+        expr: &Expression,
+        left: &Expression,
+        op: &BinaryOp,
+        right: &Expression,
+    ) -> Encoded<Option<Term>> {
+        let Some(lhs) = self.expr(left)? else {
+            return Ok(None);
+        };
+        // The right operand of `&&` and `||` only runs when the left does not decide the result
+        let rhs = match op {
+            BinaryOp::And => self.branch(lhs.clone(), |enc| enc.expr(right))?,
+            BinaryOp::Or => self.branch(lhs.not(), |enc| enc.expr(right))?,
+            BinaryOp::Assign => return unsupported("assignment is"),
+            _ => self.expr(right)?,
+        };
+        let Some(rhs) = rhs else {
+            return Ok(None);
+        };
+        let args = [lhs.clone(), rhs.clone()];
+        let term = match (op, lhs.sort) {
+            (BinaryOp::Add, Sort::Int) => self.checked(expr, Term::app("+", &args, Sort::Int), None),
+            (BinaryOp::Sub, Sort::Int) => self.checked(expr, Term::app("-", &args, Sort::Int), None),
+            (BinaryOp::Mul, Sort::Int) => self.checked(expr, Term::app("*", &args, Sort::Int), None),
+            (BinaryOp::Div, Sort::Int) => self.checked(expr, Term::trunc_div(&lhs, &rhs), Some(&rhs)),
+            (BinaryOp::Mod, Sort::Int) => self.checked(expr, Term::trunc_rem(&lhs, &rhs), Some(&rhs)),
+            (BinaryOp::Lt, Sort::Int) => Term::app("<", &args, Sort::Bool),
+            (BinaryOp::Le, Sort::Int) => Term::app("<=", &args, Sort::Bool),
+            (BinaryOp::Gt, Sort::Int) => Term::app(">", &args, Sort::Bool),
+            (BinaryOp::Ge, Sort::Int) => Term::app(">=", &args, Sort::Bool),
+            (BinaryOp::Eq, Sort::Int | Sort::Bool) => lhs.eq(&rhs),
+            (BinaryOp::Ne, Sort::Int | Sort::Bool) => Term::app("distinct", &args, Sort::Bool),
+            (BinaryOp::And, Sort::Bool) => Term::app("and", &args, Sort::Bool),
+            (BinaryOp::Or, Sort::Bool) => Term::app("or", &args, Sort::Bool),
+            _ => return unsupported(format!("`{:?}` on {} is", op, lhs.sort)),
+        };
+        Ok(Some(term))
+    }
+
+    /// Owe that `value`, the result of the operation `expr`, fits the type
+    /// of `expr` and that `divisor` is not zero. Past the operation both
+    /// hold, since it panics otherwise.
+    fn checked(&mut self, expr: &Expression, value: Term, divisor: Option<&Term>) -> Term {
+        if self.in_clause {
+            return value;
+        }
+        let Some(Ty::Prim(prim)) = self.cx.types.type_of(expr.id) else {
+            return value;
+        };
+        let Some((lo, hi)) = range_of(*prim) else {
+            return value;
+        };
+        let mut goals = Vec::new();
+        if let Some(divisor) = divisor {
+            goals.push(Term::app("distinct", &[divisor.clone(), Term::int(0)], Sort::Bool));
+        }
+        goals.push(value.within(lo, hi));
+        let goal = Term::and(&goals);
+        self.owed.push(Owed {
+            kind: ClauseKind::Arithmetic,
+            function: self.info.name.clone(),
+            clause: self.info.source.get(expr.span.start..expr.span.end).unwrap_or_default().to_string(),
+            span: expr.span,
+            path: self.path.clone(),
+            goal: goal.clone(),
+        });
+        let fact = if self.path.is_empty() { goal } else { Term::and(&self.path).implies(&goal) };
+        self.facts.push(fact);
+        value
+    }
+
+    /// A call's result is the callee's uninterpreted function applied to
+    /// the arguments. Outside contracts the callee's preconditions are owed
+    /// and its postconditions may be assumed.
+    fn call(&mut self, call: &Expression, callee: &Expression, args: &[Expression]) -> Encoded<Option<Term>> {
+        // `old(x)` is `x`, since parameters cannot be assigned
+        if matches!(&callee.kind, ExpressionKind::Identifier(name) if name == "old") && args.len() == 1 {
+            return self.expr(&args[0]);
+        }
+        let cx = self.cx;
+        let Some(info) = cx.callee(call, callee) else {
+            return unsupported("calling anything but a function of this crate is");
+        };
+        let mut values = Vec::new();
+        for arg in args {
+            match self.expr(arg)? {
+                Some(value) => values.push(value),
+                None => return Ok(None),
+            }
+        }
+        let func = info.func;
+        if values.len() != func.params.len() || values.iter().any(|v| v.sort == Sort::Unit) {
+            return unsupported(format!("calling `{}` with these arguments is", info.name));
+        }
+
+        let mut sorts = Vec::new();
+        for param in &func.params {
+            let Some((sort, _)) = sort_of(&param.ty) else {
+                return Err(Unsupported(format!("`{}` takes a parameter that is not an integer or `bool`", info.name)));
+            };
+            sorts.push(sort);
+        }
+        let (result, range) = match &func.return_type {
+            None => (Term::unit(), None),
+            Some(ty) => {
+                let Some((sort, range)) = sort_of(ty) else {
+                    return Err(Unsupported(format!(
+                        "`{}` returns something other than an integer or `bool`",
+                        info.name
+                    )));
+                };
+                if !self.funs.iter().any(|(name, ..)| *name == info.symbol) {
+                    self.funs.push((info.symbol.clone(), sorts, sort));
+                }
+                (Term::app(&info.symbol, &values, sort), range)
+            }
+        };
+        if let Some((lo, hi)) = range {
+            self.facts.push(result.within(lo, hi));
+        }
+        if self.in_clause {
+            return Ok(Some(result));
+        }
+
+        // The callee's clauses over the arguments, each in a scope binding its parameters
+        let Some(contract) = &func.contract else {
+            return Ok(Some(result));
+        };
+        let saved_env = std::mem::take(&mut self.env);
+        for (param, value) in func.params.iter().zip(&values) {
+            self.bind(&param.name, value.clone());
+        }
+        let pre = contract.preconditions.iter().map(|cond| self.clause(cond)).collect::<Encoded<Vec<_>>>();
+        self.bind("result", result.clone());
+        // Postconditions beyond the encoding are simply not assumed
+        let post: Vec<Term> = contract.postconditions.iter().filter_map(|cond| self.clause(cond).ok()).collect();
+        self.env = saved_env;
+        let pre = pre?;
+
+        let text = |cond: &Expression| info.source.get(cond.span.start..cond.span.end).unwrap_or_default().to_string();
+        for (cond, goal) in contract.preconditions.iter().zip(&pre) {
+            self.owed.push(Owed {
+                kind: ClauseKind::Precondition,
+                function: info.name.clone(),
+                clause: text(cond),
+                span: call.span,
+                path: self.path.clone(),
+                goal: goal.clone(),
+            });
+        }
+        if !post.is_empty() {
+            self.facts.push(Term::and(&pre).implies(&Term::and(&post)));
+        }
+        Ok(Some(result))
+    }
+}
+
+/// SMT sort of a source type, with its value range for fixed-width integers
+fn sort_of(ty: &Type) -> Option<(Sort, Option<(i128, i128)>)> {
+    let TypeKind::Primitive(prim) = &ty.kind else {
+        return None;
+    };
+    match prim {
+        PrimitiveType::Bool => Some((Sort::Bool, None)),
+        PrimitiveType::Unit => Some((Sort::Unit, None)),
+        prim => Some((Sort::Int, Some(range_of(*prim)?))),
+    }
+}
+
+/// Value range of a fixed-width integer type
+fn range_of(prim: PrimitiveType) -> Option<(i128, i128)> {
+    let range = match prim {
+        PrimitiveType::I8 => (i8::MIN as i128, i8::MAX as i128),
+        PrimitiveType::I16 => (i16::MIN as i128, i16::MAX as i128),
+        PrimitiveType::I32 => (i32::MIN as i128, i32::MAX as i128),
+        PrimitiveType::I64 | PrimitiveType::Isize => (i64::MIN as i128, i64::MAX as i128),
+        PrimitiveType::I128 => (i128::MIN, i128::MAX),
+        PrimitiveType::U8 => (0, u8::MAX as i128),
+        PrimitiveType::U16 => (0, u16::MAX as i128),
+        PrimitiveType::U32 => (0, u32::MAX as i128),
+        PrimitiveType::U64 | PrimitiveType::Usize => (0, u64::MAX as i128),
+        PrimitiveType::U128 => (0, i128::MAX),
+        _ => return None,
+    };
+    Some(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obligations_of(source: &str) -> Vec<Obligation> {
+        let program = my_lang_parser::parse(source).expect("test source parses");
+        let (res, diags) = my_lang_resolve::resolve(&program);
+        assert!(diags.is_empty(), "{:?}", diags);
+        let (types, diags) = my_lang_typechecker::check_crate(&[&program], &res);
+        assert!(diags.is_empty(), "{:?}", diags);
+        obligations(&[(&program, source)], &res, &types)
+    }
+
+    fn script(obligation: &Obligation) -> &str {
+        &obligation.query.as_ref().expect("clause is encoded").script
+    }
+
+    #[test]
+    fn test_postconditions_see_every_return() {
+        let obligations =
+            obligations_of("fn abs(x: i32) -> i32 pre x > -100 post result >= 0 { if x < 0 { return -x; } x }");
+        assert_eq!(obligations.len(), 2);
+        assert_eq!((obligations[1].kind, obligations[1].clause.as_str()), (ClauseKind::Postcondition, "result >= 0"));
+        let script = script(&obligations[1]);
+        assert!(script.contains("(assert (<= (- 2147483648) x 2147483647))"));
+        assert!(script.contains("(assert (> x (- 100)))"));
+        assert!(script.contains("(assert (= result!1 (ite (< x 0) (- x) x)))"));
+        assert!(script.contains("(assert (not (>= result!1 0)))\n(check-sat)\n(get-value (x))"));
+        assert_eq!(obligations[1].query.as_ref().unwrap().model, [("x".to_string(), "x".to_string())]);
+    }
+
+    #[test]
+    fn test_arithmetic_owes_its_range() {
+        let obligations = obligations_of(
+            "fn abs(x: i32) -> i32 post result >= 0 { if x < 0 { return -x; } x }\n\
+             fn ratio(a: u8, b: u8) -> u8 post result <= a + 1 { a / b }",
+        );
+        let kinds: Vec<_> = obligations.iter().map(|o| (o.kind, o.clause.as_str())).collect();
+        assert_eq!(
+            kinds,
+            [
+                (ClauseKind::Arithmetic, "-x"),
+                (ClauseKind::Postcondition, "result >= 0"),
+                (ClauseKind::Arithmetic, "a / b"),
+                (ClauseKind::Postcondition, "result <= a + 1"),
+            ]
+        );
+        // `-x` overflows for the least `i32`, and only runs when `x < 0`
+        let negate = script(&obligations[0]);
+        assert!(negate.contains("(assert (< x 0))\n(assert (not (<= (- 2147483648) (- x) 2147483647)))"));
+        // Past the negation its result is in range
+        assert!(script(&obligations[1]).contains("(assert (=> (< x 0) (<= (- 2147483648) (- x) 2147483647)))"));
+        assert!(script(&obligations[2]).contains("(assert (not (and (distinct b 0) (<= 0 (ite"));
+        // Clause arithmetic is unbounded and owes nothing
+        assert!(script(&obligations[3]).contains("(assert (not (<= result!1 (+ a 1))))"));
+    }
+
+    #[test]
+    fn test_calls_owe_preconditions_and_assume_postconditions() {
+        let obligations = obligations_of(
+            "fn half(n: u8) -> u8 pre n % 2 == 0 post result * 2 == n { n / 2 }\n\
+             fn quarter(n: u8) -> u8 { if n > 3 { half(half(n)) } else { 0 } }",
+        );
+        let pre: Vec<_> = obligations.iter().filter(|o| o.kind == ClauseKind::Precondition).collect();
+        assert_eq!(pre.len(), 2);
+        assert_eq!((pre[0].function.as_str(), pre[0].caller.as_str()), ("half", "quarter"));
+        let inner = script(pre[0]);
+        assert!(inner.contains("(declare-fun half (Int) Int)"));
+        assert!(inner.contains("(assert (=> (= (- n (* 2 (ite (>= n 0) (div n 2) (- (div (- n) 2))))) 0)"));
+        // Calls run under the branch condition
+        assert!(inner.contains("(assert (> n 3))\n(assert (not (= (- n"));
+        assert!(script(pre[1]).contains("(assert (<= 0 (half n) 255))"));
+    }
+
+    #[test]
+    fn test_unsupported_bodies_are_unknown() {
+        let obligations = obligations_of(
+            "fn count(n: i32) -> i32 post result == n { let mut i = 0; while i < n { i = i + 1; } i }\n\
+             fn length(s: String) -> i32 post result >= 0 { 0 }",
+        );
+        let reasons: Vec<_> = obligations.iter().map(|o| o.query.as_ref().unwrap_err().as_str()).collect();
+        assert_eq!(reasons, ["a loop is not supported", "parameter `s` is not an integer or `bool`"]);
+    }
+}
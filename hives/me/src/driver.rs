@@ -0,0 +1,448 @@
+// Compiler driver
+// Loads a file and the modules it imports, then runs the front-end phases over them
+
+use my_lang_ast::{Item, ItemKind, NodeId, Program};
+use my_lang_codegen::{ContractLowering, ContractMode};
+use my_lang_comptime::ComptimeResults;
+use my_lang_diagnostics::{codes, Diagnostic, Diagnostics, FileId, SourceMap, Span};
+use my_lang_resolve::{Resolution, Resolver};
+use my_lang_typechecker::TypeckResults;
+use my_lang_verify::Obligation;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+/// Extension of Solo source files
+const SOURCE_EXT: &str = "solo";
+
+/// A parsed source file
+pub struct SourceModule {
+    /// Module path relative to the entry file; empty for the entry file itself
+    pub path: Vec<String>,
+    pub file: FileId,
+    pub program: Program,
+}
+
+/// State shared by every phase of one compiler invocation
+#[derive(Default)]
+pub struct Session {
+    pub sources: SourceMap,
+    pub diagnostics: Diagnostics,
+    pub modules: Vec<SourceModule>,
+    /// Name bindings across all modules, filled in by `check`
+    pub resolution: Resolution,
+    /// Inferred types, filled in by `check` once names resolve
+    pub types: TypeckResults,
+    /// Values computed during compilation, filled in by `check` once the crate is well typed
+    pub comptime: ComptimeResults,
+    next_node_id: u32,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse `entry` and every module file it declares with `mod name;`
+    pub fn load(&mut self, entry: &Path) -> std::io::Result<()> {
+        let source = std::fs::read_to_string(entry)?;
+        let file = self.sources.add_file(entry, source);
+        self.parse_module(Vec::new(), file);
+
+        // Modules declared in a file live in the directory of that file, or
+        // in a directory named after the module for non-root files
+        let dir = entry.parent().map(Path::to_path_buf).unwrap_or_default();
+        let mut queue: VecDeque<(usize, PathBuf)> = VecDeque::from([(0, dir)]);
+        while let Some((index, dir)) = queue.pop_front() {
+            let module = &self.modules[index];
+            for decl in declared_modules(&module.program.items, &module.path, &dir) {
+                let Some(path) = self.find_module_file(&decl) else {
+                    continue;
+                };
+                // Already loaded, for instance through a symlink
+                if self.sources.find(&path).is_some() {
+                    continue;
+                }
+                match std::fs::read_to_string(&path) {
+                    Ok(source) => {
+                        let file = self.sources.add_file(&path, source);
+                        self.parse_module(decl.path, file);
+                        queue.push_back((self.modules.len() - 1, decl.dir.join(&decl.name)));
+                    }
+                    Err(err) => self.diagnostics.push(
+                        Diagnostic::error(format!("couldn't read {}: {}", path.display(), err))
+                            .with_code(codes::MODULE_NOT_FOUND)
+                            .with_primary(decl.span, "module declared here"),
+                    ),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `name.solo` or `name/mod.solo` next to the declaring file, reporting
+    /// when neither or both exist
+    fn find_module_file(&mut self, decl: &ModuleDecl) -> Option<PathBuf> {
+        let name = &decl.name;
+        let flat = decl.dir.join(format!("{name}.{SOURCE_EXT}"));
+        let nested = decl.dir.join(name).join(format!("mod.{SOURCE_EXT}"));
+        match (flat.is_file(), nested.is_file()) {
+            (true, false) => Some(flat),
+            (false, true) => Some(nested),
+            (true, true) => {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("file for module `{}` found at both {} and {}", name, flat.display(), nested.display()))
+                        .with_code(codes::AMBIGUOUS_MODULE_FILE)
+                        .with_primary(decl.span, "module declared here")
+                        .with_note("delete or rename one of them"),
+                );
+                None
+            }
+            (false, false) => {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("file not found for module `{}`", name))
+                        .with_code(codes::MODULE_NOT_FOUND)
+                        .with_primary(decl.span, "module declared here")
+                        .with_note(format!(
+                            "to create the module `{}`, create file {} or {}",
+                            name,
+                            flat.display(),
+                            nested.display()
+                        )),
+                );
+                None
+            }
+        }
+    }
+
+    fn parse_module(&mut self, path: Vec<String>, file: FileId) {
+        let source = &self.sources.get(file).expect("file was just added").source;
+        let mut parser = my_lang_parser::Parser::with_file(source, file)
+            .with_first_node_id(NodeId(self.next_node_id));
+        let (program, errors) = parser.parse_program();
+        self.next_node_id = parser.next_node_id().0;
+        self.diagnostics.extend(errors.iter().map(Diagnostic::from));
+        self.modules.push(SourceModule { path, file, program });
+    }
+
+    /// Run the semantic phases over every loaded module. Skipped when
+    /// loading or parsing failed, since later phases would only report
+    /// knock-on errors from the placeholder nodes.
+    pub fn check(&mut self) {
+        if self.diagnostics.has_errors() {
+            return;
+        }
+
+        let mut resolver = Resolver::new();
+        for module in &self.modules {
+            resolver.add_module(&module.path, &module.program);
+        }
+        let (resolution, errors) = resolver.finish();
+        self.resolution = resolution;
+        self.diagnostics.extend(errors);
+        if self.diagnostics.has_errors() {
+            return;
+        }
+
+        tracing::debug!(files = ?self.modules.iter().map(|m| m.file).collect::<Vec<_>>(), "type checking");
+        let programs: Vec<&Program> = self.modules.iter().map(|m| &m.program).collect();
+        let (types, errors) = my_lang_typechecker::check_crate(&programs, &self.resolution);
+        self.types = types;
+        self.diagnostics.extend(errors);
+        if self.diagnostics.has_errors() {
+            return;
+        }
+        self.diagnostics.extend(my_lang_affine::check_crate(&programs, &self.resolution, &self.types));
+        self.evaluate();
+    }
+
+    /// Evaluate constants, array sizes and `comptime` expressions, then type
+    /// check again so that array lengths must agree
+    fn evaluate(&mut self) {
+        let programs: Vec<&Program> = self.modules.iter().map(|m| &m.program).collect();
+        let limits = my_lang_comptime::Limits::default();
+        let (comptime, errors) = my_lang_comptime::eval_crate(&programs, &self.resolution, &self.types, limits);
+        self.diagnostics.extend(errors);
+        if !comptime.array_sizes.is_empty() && !self.diagnostics.has_errors() {
+            let sizes = comptime.array_sizes.clone();
+            let (types, errors) = my_lang_typechecker::check_crate_with_sizes(&programs, &self.resolution, sizes);
+            self.types = types;
+            self.diagnostics.extend(errors);
+        }
+        self.comptime = comptime;
+    }
+
+    /// Rewrite contracts into run-time checks as `mode` selects, then resolve
+    /// and type check the result again for the backends. Call after a clean `check`.
+    pub fn lower_contracts(&mut self, mode: ContractMode) {
+        for module in &mut self.modules {
+            let source = &self.sources.get(module.file).expect("module files are in the source map").source;
+            let mut lowering = ContractLowering::new(source, mode).with_first_node_id(NodeId(self.next_node_id));
+            lowering.lower(&mut module.program);
+            self.next_node_id = lowering.next_node_id().0;
+        }
+
+        let mut resolver = Resolver::new();
+        for module in &self.modules {
+            resolver.add_module(&module.path, &module.program);
+        }
+        let (resolution, errors) = resolver.finish();
+        self.resolution = resolution;
+        self.diagnostics.extend(errors);
+        if self.diagnostics.has_errors() {
+            return;
+        }
+        let programs: Vec<&Program> = self.modules.iter().map(|m| &m.program).collect();
+        let (types, errors) = my_lang_typechecker::check_crate(&programs, &self.resolution);
+        self.types = types;
+        self.diagnostics.extend(errors);
+        // Definitions were renumbered, and contracts of `comptime fn`s now run at compile time
+        if !self.diagnostics.has_errors() {
+            self.evaluate();
+        }
+    }
+
+    /// Verification conditions for the contracts of every module. Call after a clean `check`.
+    pub fn obligations(&self) -> Vec<Obligation> {
+        let modules: Vec<(&Program, &str)> = self
+            .modules
+            .iter()
+            .map(|m| (&m.program, self.sources.get(m.file).map_or("", |file| file.source.as_str())))
+            .collect();
+        my_lang_verify::obligations(&modules, &self.resolution, &self.types)
+    }
+}
+
+/// A `mod name;` declaration
+struct ModuleDecl {
+    /// Module path from the crate root, ending in `name`
+    path: Vec<String>,
+    name: String,
+    /// Directory the module file is looked up in
+    dir: PathBuf,
+    span: Span,
+}
+
+/// Every `mod name;` in `items`, including inside inline modules
+fn declared_modules(items: &[Item], path: &[String], dir: &Path) -> Vec<ModuleDecl> {
+    let mut decls = Vec::new();
+    for item in items {
+        let ItemKind::Module(module) = &item.kind else {
+            continue;
+        };
+        let mut inner = path.to_vec();
+        inner.push(module.name.clone());
+        if module.inline {
+            decls.extend(declared_modules(&module.items, &inner, &dir.join(&module.name)));
+        } else {
+            decls.push(ModuleDecl { path: inner, name: module.name.clone(), dir: dir.to_path_buf(), span: module.span });
+        }
+    }
+    decls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn check(files: &[(&str, &str)]) -> Session {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, source) in files {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        let mut session = Session::new();
+        session.load(&dir.path().join(files[0].0)).unwrap();
+        session.check();
+        session
+    }
+
+    #[test]
+    fn test_loads_declared_modules() {
+        let session = check(&[
+            ("main.solo", "mod math;\nmod util;\nimport math::add;\nimport std::io;\nfn main() {}"),
+            ("math/mod.solo", "mod trig;\npub fn add(a: i32, b: i32) -> i32 { a + b }"),
+            ("math/trig.solo", "pub fn sin(x: f64) -> f64 { x }"),
+            ("util.solo", "mod inner { mod deep; }\nfn id(x: i32) -> i32 { x }"),
+            ("util/inner/deep.solo", "fn f() {}"),
+        ]);
+        assert!(!session.diagnostics.has_errors(), "{:?}", session.diagnostics.as_slice());
+        let paths: Vec<_> = session.modules.iter().map(|m| m.path.join("::")).collect();
+        assert_eq!(paths, ["", "math", "util", "math::trig", "util::inner::deep"]);
+
+        // Node ids stay unique across files
+        let ids: Vec<_> = session.modules.iter().map(|m| m.program.id).collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_reports_errors_in_module_files() {
+        let session = check(&[
+            ("main.solo", "mod broken;\nfn main() {}"),
+            ("broken.solo", "fn f( {}"),
+        ]);
+        let diag = session.diagnostics.iter().next().unwrap();
+        let file = diag.primary_span().unwrap().file;
+        assert!(session.sources.get(file).unwrap().path.ends_with("broken.solo"));
+    }
+
+    #[test]
+    fn test_missing_module_file() {
+        let session = check(&[("main.solo", "mod nowhere;\nfn main() {}")]);
+        assert_eq!(session.diagnostics.error_count(), 1);
+        let diag = session.diagnostics.iter().next().unwrap();
+        assert_eq!(diag.code, Some(codes::MODULE_NOT_FOUND));
+        assert_eq!(diag.message, "file not found for module `nowhere`");
+    }
+
+    #[test]
+    fn test_ambiguous_module_file() {
+        let session = check(&[
+            ("main.solo", "mod both;\nfn main() {}"),
+            ("both.solo", ""),
+            ("both/mod.solo", ""),
+        ]);
+        let codes: Vec<_> = session.diagnostics.iter().filter_map(|d| d.code).collect();
+        assert_eq!(codes, [codes::AMBIGUOUS_MODULE_FILE]);
+    }
+
+    #[test]
+    fn test_resolves_names_across_files() {
+        let session = check(&[
+            ("main.solo", "mod math;\nimport math::{add, sub as minus};\nfn main() { add(1, minus(3, 1)); math::mul(2, 2); }"),
+            ("math.solo", "pub fn add(a: i32, b: i32) -> i32 { a + b }\npub fn sub(a: i32, b: i32) -> i32 { a - b }"),
+        ]);
+        let errors: Vec<_> = session.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(errors, ["cannot find `mul` in module `math`"]);
+
+        let ItemKind::Function(add) = &session.modules[1].program.items[0].kind else {
+            panic!("expected a function");
+        };
+        let add = session.resolution.def_of_node(add.id).unwrap();
+        // The import and the call
+        assert_eq!(session.resolution.references(add).count(), 2);
+    }
+
+    #[test]
+    fn test_private_items_across_files() {
+        let session = check(&[
+            ("main.solo", "mod shapes;\nimport shapes::{Circle, area};\nfn main() { shapes::helper(); }"),
+            ("shapes.solo", "pub struct Circle { r: f64 }\nfn area() -> f64 { helper() }\nfn helper() -> f64 { 0.0 }"),
+        ]);
+        let errors: Vec<_> = session.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(errors, ["function `area` is private", "function `helper` is private"]);
+    }
+
+    #[test]
+    fn test_evaluates_constants_across_files() {
+        let session = check(&[
+            ("main.solo", "mod math;\nimport math::square;\nconst AREA: u32 = square(math::SIDE);\nfn main() {}"),
+            ("math.solo", "pub const SIDE: u32 = 12;\npub comptime fn square(n: u32) -> u32 { n * n }"),
+        ]);
+        assert!(!session.diagnostics.has_errors(), "{:?}", session.diagnostics.as_slice());
+        let ItemKind::Const(area) = &session.modules[0].program.items[2].kind else {
+            panic!("expected a constant");
+        };
+        let area = session.resolution.def_of_node(area.id).unwrap();
+        assert_eq!(session.comptime.consts[&area], my_lang_comptime::Value::Int(144));
+    }
+
+    #[test]
+    fn test_array_lengths_follow_evaluated_sizes() {
+        let session = check(&[(
+            "main.solo",
+            "const N: usize = 1 + 2;\nfn main() { let ok: [i32; N] = [1, 2, 3]; let bad: [i32; N] = [1, 2]; }",
+        )]);
+        let errors: Vec<_> = session.diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+        assert_eq!(errors, [(Some(codes::TYPE_MISMATCH), "mismatched types")]);
+    }
+
+    #[test]
+    fn test_comptime_contracts_are_checked_once_lowered() {
+        let source = "comptime fn half(n: i32) -> i32 pre n % 2 == 0 { n / 2 }\nconst H: i32 = half(3);\nfn main() {}";
+        let mut session = check(&[("main.solo", source)]);
+        assert!(!session.diagnostics.has_errors(), "{:?}", session.diagnostics.as_slice());
+        session.lower_contracts(ContractMode::Pre);
+        let errors: Vec<_> = session.diagnostics.iter().map(|d| d.labels[0].message.as_str()).collect();
+        assert_eq!(errors, ["precondition `n % 2 == 0` of `half` violated"]);
+    }
+
+    #[test]
+    fn test_lowered_contracts_check_clean() {
+        let source = "struct Counter { n: i32 }
+impl Counter where invariant self.n >= 0 {
+    pub fn bump(&mut self, by: i32) -> i32
+        pre by > 0
+        post self.n == old(self.n) + by
+        invariant self.n >= 0
+    { self.n = self.n + by; self.n }
+    pub fn reset(self) -> Self { Counter { n: 0 } }
+}
+fn div(a: i32, b: i32) -> i32 pre b != 0 post result * b <= a { if b < 0 { return 0; } a / b }
+fn main() { let mut c = Counter { n: 0 }; c.bump(div(4, 2)); }";
+        for mode in [ContractMode::Off, ContractMode::Pre, ContractMode::All] {
+            let mut session = check(&[("main.solo", source)]);
+            assert!(!session.diagnostics.has_errors(), "{:?}", session.diagnostics.as_slice());
+            session.lower_contracts(mode);
+            assert!(!session.diagnostics.has_errors(), "{:?}: {:?}", mode, session.diagnostics.as_slice());
+            // `div` and `bump` move their bodies into helpers only when postconditions are checked
+            let helpers = if mode == ContractMode::All { 2 } else { 0 };
+            assert_eq!(session.modules[0].program.items.len(), 4 + helpers);
+        }
+    }
+
+    #[test]
+    fn test_examples_check() {
+        // These use syntax the parser does not take, such as `?`, ranges,
+        // macros, attributes and `async`, so they stop at parse errors
+        const UNPARSED: [&str; 21] = [
+            "02_variables_and_types",
+            "03_functions",
+            "04_control_flow",
+            "05_structs",
+            "07_traits",
+            "08_generics",
+            "09_contracts",
+            "11_async_await",
+            "12_comptime",
+            "14_error_handling",
+            "15_collections",
+            "16_iterators",
+            "17_closures",
+            "18_smart_pointers",
+            "19_threading",
+            "20_macros",
+            "21_file_io",
+            "22_networking",
+            "23_testing",
+            "24_regex",
+            "25_serialization",
+        ];
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/solo");
+        let mut paths: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        paths.sort();
+        for path in paths {
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            if UNPARSED.contains(&name) {
+                continue;
+            }
+            let mut session = Session::new();
+            session.load(&path).unwrap();
+            session.check();
+            let errors: Vec<(&str, u32)> = session
+                .diagnostics
+                .as_slice()
+                .iter()
+                .filter(|d| d.is_error())
+                .map(|d| (d.code.map_or("", |c| c.as_str()), d.primary_span().map_or(0, |s| s.line)))
+                .collect();
+            let expected: &[(&str, u32)] = match name {
+                // `Database` is used without being imported
+                "10_affine_types" => &[("E0101", 53), ("E0101", 58), ("E0101", 106), ("E0101", 148)],
+                _ => &[],
+            };
+            assert_eq!(errors, expected, "{}: {:?}", name, session.diagnostics.as_slice());
+        }
+    }
+}
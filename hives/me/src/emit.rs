@@ -0,0 +1,340 @@
+// Diagnostic output
+// Human-readable, JSON-lines and SARIF renderings of a session's diagnostics
+
+use clap::ValueEnum;
+use my_lang_diagnostics::{Diagnostic, Renderer, Severity, SourceMap, Span};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+const SARIF_VERSION: &str = "2.1.0";
+
+/// How diagnostics are written out, selected with `--message-format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum MessageFormat {
+    /// Annotated source snippets on stderr
+    #[default]
+    Human,
+    /// One JSON object per diagnostic on stdout
+    Json,
+    /// A single SARIF 2.1.0 log on stdout
+    Sarif,
+}
+
+impl MessageFormat {
+    pub fn is_human(self) -> bool {
+        self == MessageFormat::Human
+    }
+}
+
+/// Write `diagnostics` in the requested format
+pub fn emit(format: MessageFormat, diagnostics: &[Diagnostic], sources: &SourceMap) {
+    match format {
+        MessageFormat::Human => {
+            if !diagnostics.is_empty() {
+                eprintln!("{}", Renderer::auto().render_all(diagnostics, sources));
+            }
+        }
+        MessageFormat::Json => {
+            for diag in diagnostics {
+                println!("{}", to_json(diag, sources));
+            }
+        }
+        MessageFormat::Sarif => {
+            let log = to_sarif(diagnostics, sources);
+            println!("{}", serde_json::to_string_pretty(&log).expect("SARIF log is valid JSON"));
+        }
+    }
+}
+
+// ========== JSON ==========
+
+#[derive(Debug, Serialize)]
+struct JsonDiagnostic {
+    code: Option<&'static str>,
+    severity: String,
+    message: String,
+    /// File of the primary span
+    file: Option<String>,
+    spans: Vec<JsonSpan>,
+    notes: Vec<String>,
+    suggestions: Vec<JsonSuggestion>,
+    /// The same diagnostic as `--message-format=human` would print it
+    rendered: String,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonSpan {
+    file: String,
+    byte_start: usize,
+    byte_end: usize,
+    line_start: u32,
+    column_start: u32,
+    line_end: u32,
+    column_end: u32,
+    primary: bool,
+    label: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonSuggestion {
+    message: String,
+    span: JsonSpan,
+    replacement: String,
+}
+
+fn json_span(span: Span, primary: bool, label: &str, sources: &SourceMap) -> JsonSpan {
+    let (line_end, column_end) = end_position(span, sources);
+    JsonSpan {
+        file: file_name(span, sources),
+        byte_start: span.start,
+        byte_end: span.end,
+        line_start: span.line,
+        column_start: span.column,
+        line_end,
+        column_end,
+        primary,
+        label: (!label.is_empty()).then(|| label.to_string()),
+    }
+}
+
+/// A diagnostic as a single-line JSON object
+pub fn to_json(diag: &Diagnostic, sources: &SourceMap) -> String {
+    let record = JsonDiagnostic {
+        code: diag.code.map(|c| c.as_str()),
+        severity: diag.severity.to_string(),
+        message: diag.message.clone(),
+        file: diag.primary_span().map(|s| file_name(s, sources)),
+        spans: diag
+            .labels
+            .iter()
+            .filter(|l| !l.span.is_dummy())
+            .map(|l| json_span(l.span, l.primary, &l.message, sources))
+            .collect(),
+        notes: diag.notes.clone(),
+        suggestions: diag
+            .suggestions
+            .iter()
+            .map(|s| JsonSuggestion {
+                message: s.message.clone(),
+                span: json_span(s.span, true, "", sources),
+                replacement: s.replacement.clone(),
+            })
+            .collect(),
+        rendered: Renderer::plain().render(diag, sources),
+    };
+    serde_json::to_string(&record).expect("diagnostic is valid JSON")
+}
+
+// ========== SARIF ==========
+
+/// All diagnostics as one SARIF 2.1.0 log with a single run
+pub fn to_sarif(diagnostics: &[Diagnostic], sources: &SourceMap) -> Value {
+    let rules: BTreeSet<&str> = diagnostics.iter().filter_map(|d| d.code).map(|c| c.as_str()).collect();
+    let rules: Vec<Value> = rules.into_iter().map(|id| json!({ "id": id })).collect();
+
+    let results: Vec<Value> = diagnostics.iter().map(|d| sarif_result(d, sources)).collect();
+
+    json!({
+        "$schema": SARIF_SCHEMA,
+        "version": SARIF_VERSION,
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                    "rules": rules,
+                }
+            },
+            "columnKind": "unicodeCodePoints",
+            "results": results,
+        }]
+    })
+}
+
+fn sarif_result(diag: &Diagnostic, sources: &SourceMap) -> Value {
+    let level = match diag.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Note | Severity::Help => "note",
+    };
+
+    let mut text = diag.message.clone();
+    for note in &diag.notes {
+        text.push_str("\nnote: ");
+        text.push_str(note);
+    }
+
+    let labels = diag.labels.iter().filter(|l| !l.span.is_dummy());
+    let locations: Vec<Value> = labels
+        .clone()
+        .filter(|l| l.primary)
+        .map(|l| sarif_location(l.span, &l.message, sources))
+        .collect();
+    let related: Vec<Value> = labels
+        .filter(|l| !l.primary)
+        .enumerate()
+        .map(|(id, l)| {
+            let mut location = sarif_location(l.span, &l.message, sources);
+            location["id"] = json!(id);
+            location
+        })
+        .collect();
+
+    let fixes: Vec<Value> = diag
+        .suggestions
+        .iter()
+        .filter(|s| !s.span.is_dummy())
+        .map(|s| {
+            json!({
+                "description": { "text": s.message },
+                "artifactChanges": [{
+                    "artifactLocation": { "uri": file_name(s.span, sources) },
+                    "replacements": [{
+                        "deletedRegion": sarif_region(s.span, sources),
+                        "insertedContent": { "text": s.replacement },
+                    }]
+                }]
+            })
+        })
+        .collect();
+
+    let mut result = json!({
+        "level": level,
+        "message": { "text": text },
+        "locations": locations,
+    });
+    if let Some(code) = diag.code {
+        result["ruleId"] = json!(code.as_str());
+    }
+    if !related.is_empty() {
+        result["relatedLocations"] = json!(related);
+    }
+    if !fixes.is_empty() {
+        result["fixes"] = json!(fixes);
+    }
+    result
+}
+
+fn sarif_location(span: Span, label: &str, sources: &SourceMap) -> Value {
+    let mut location = json!({
+        "physicalLocation": {
+            "artifactLocation": { "uri": file_name(span, sources) },
+            "region": sarif_region(span, sources),
+        }
+    });
+    if !label.is_empty() {
+        location["message"] = json!({ "text": label });
+    }
+    location
+}
+
+/// Columns and character offsets count code points, as the run's
+/// `columnKind` says; without the source text, offsets are given in bytes
+fn sarif_region(span: Span, sources: &SourceMap) -> Value {
+    let (end_line, end_column) = end_position(span, sources);
+    let mut region = json!({
+        "startLine": span.line,
+        "startColumn": span.column,
+        "endLine": end_line,
+        "endColumn": end_column,
+    });
+    let text = sources.get(span.file).and_then(|f| Some((f.source.get(..span.start)?, f.snippet(span)?)));
+    match text {
+        Some((before, text)) => {
+            region["charOffset"] = json!(before.chars().count());
+            region["charLength"] = json!(text.chars().count());
+        }
+        None => {
+            region["byteOffset"] = json!(span.start);
+            region["byteLength"] = json!(span.len());
+        }
+    }
+    region
+}
+
+// ========== Helpers ==========
+
+fn file_name(span: Span, sources: &SourceMap) -> String {
+    sources
+        .get(span.file)
+        .map(|f| f.path.to_string_lossy().replace('\\', "/"))
+        .unwrap_or_default()
+}
+
+/// 1-based line and column just past the end of `span`
+fn end_position(span: Span, sources: &SourceMap) -> (u32, u32) {
+    match sources.get(span.file) {
+        Some(file) => file.line_col(span.end),
+        None => (span.line, span.column + span.len() as u32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use my_lang_diagnostics::{codes, Suggestion};
+
+    fn sample() -> (Vec<Diagnostic>, SourceMap) {
+        let mut sources = SourceMap::new();
+        let file = sources.add_file("src/main.solo", "let x = 1\nlet y = 2;\n");
+        let insert = Span::new(file, 9, 9, 1, 10);
+        let found = Span::new(file, 10, 13, 2, 1);
+        let diag = Diagnostic::error("expected `;`, found `let`")
+            .with_code(codes::UNEXPECTED_TOKEN)
+            .with_primary(insert, "expected `;`")
+            .with_secondary(found, "unexpected token")
+            .with_suggestion(Suggestion::new("add `;` here", insert, ";"));
+        (vec![diag], sources)
+    }
+
+    #[test]
+    fn test_json_record() {
+        let (diags, sources) = sample();
+        let value: Value = serde_json::from_str(&to_json(&diags[0], &sources)).unwrap();
+
+        assert_eq!(value["code"], "E0002");
+        assert_eq!(value["severity"], "error");
+        assert_eq!(value["file"], "src/main.solo");
+        assert_eq!(value["spans"][1]["line_end"], 2);
+        assert_eq!(value["spans"][1]["column_end"], 4);
+        assert_eq!(value["suggestions"][0]["replacement"], ";");
+        assert_eq!(value["suggestions"][0]["span"]["byte_start"], 9);
+    }
+
+    #[test]
+    fn test_sarif_log() {
+        let (diags, sources) = sample();
+        let log = to_sarif(&diags, &sources);
+
+        assert_eq!(log["version"], "2.1.0");
+        let run = &log["runs"][0];
+        assert_eq!(run["tool"]["driver"]["rules"][0]["id"], "E0002");
+
+        let result = &run["results"][0];
+        assert_eq!(result["ruleId"], "E0002");
+        assert_eq!(result["level"], "error");
+        let region = &result["locations"][0]["physicalLocation"]["region"];
+        assert_eq!((region["startLine"].clone(), region["startColumn"].clone()), (json!(1), json!(10)));
+        assert_eq!(result["relatedLocations"][0]["message"]["text"], "unexpected token");
+        let replacement = &result["fixes"][0]["artifactChanges"][0]["replacements"][0];
+        assert_eq!(replacement["insertedContent"]["text"], ";");
+    }
+
+    #[test]
+    fn test_sarif_regions_count_characters() {
+        let mut sources = SourceMap::new();
+        let file = sources.add_file("src/main.solo", "let é = \"naïve\" x;\n");
+        // `x` starts at byte 18 but character 16
+        let span = Span::new(file, 18, 19, 1, 17);
+        let diag = Diagnostic::error("unexpected `x`").with_primary(span, "");
+        let log = to_sarif(&[diag], &sources);
+
+        assert_eq!(log["runs"][0]["columnKind"], "unicodeCodePoints");
+        let region = &log["runs"][0]["results"][0]["locations"][0]["physicalLocation"]["region"];
+        assert_eq!((region["charOffset"].clone(), region["charLength"].clone()), (json!(16), json!(1)));
+        assert_eq!((region["startColumn"].clone(), region["endColumn"].clone()), (json!(17), json!(18)));
+    }
+}
@@ -1,6 +1,17 @@
 // My Language Compiler - Main Entry Point
 
-use clap::{Parser, Subcommand};
+mod driver;
+mod emit;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use emit::MessageFormat;
+use my_lang_ast::Span;
+use my_lang_codegen::bytecode::{self, Module};
+use my_lang_codegen::native::OptLevel;
+use my_lang_codegen::ContractMode;
+use my_lang_diagnostics::{codes, Diagnostic};
+use my_lang_runtime::{RuntimeError, RuntimeErrorKind};
+use my_lang_verify::{ClauseKind, Outcome, Solver, SolverError};
 use std::path::PathBuf;
 use anyhow::Result;
 
@@ -24,25 +35,54 @@ enum Commands {
         #[arg(short, long)]
         output: Option<PathBuf>,
 
-        /// Enable optimizations
+        /// Optimize native code for speed, as `--opt-level 2`
         #[arg(short = 'O', long)]
         optimize: bool,
 
+        /// How much to optimize native code: 0 for none, 1 to 3 for speed, s
+        /// for speed and size
+        #[arg(long, value_name = "LEVEL", value_parser = parse_opt_level, conflicts_with = "optimize")]
+        opt_level: Option<OptLevel>,
+
+        /// What to generate
+        #[arg(long, value_enum, default_value_t = Target::Bytecode)]
+        target: Target,
+
+        /// Also write intermediate forms beside the output (wat, obj, ir)
+        #[arg(long, value_enum, value_delimiter = ',')]
+        emit: Vec<Emit>,
+
         /// Target mode (solo, duet, ensemble)
         #[arg(short, long, default_value = "solo")]
         mode: String,
+
+        /// Contract clauses checked at run time (off, pre, all)
+        #[arg(long, default_value = "all")]
+        contracts: ContractMode,
+
+        /// Diagnostic output format
+        #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+        message_format: MessageFormat,
     },
 
-    /// Run a source file
+    /// Run a source file or a bytecode file `build` wrote
     Run {
         /// Input file
         #[arg(value_name = "FILE")]
         input: PathBuf,
 
+        /// Walk the syntax tree instead of compiling to bytecode
+        #[arg(long)]
+        interpret: bool,
+
         /// Run mode (solo, duet, ensemble)
         #[arg(long, default_value = "solo")]
         mode: String,
 
+        /// Contract clauses checked at run time (off, pre, all)
+        #[arg(long, default_value = "all")]
+        contracts: ContractMode,
+
         /// Arguments to pass to the program
         #[arg(last = true)]
         args: Vec<String>,
@@ -53,6 +93,29 @@ enum Commands {
         /// Input file
         #[arg(value_name = "FILE")]
         input: PathBuf,
+
+        /// Diagnostic output format
+        #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+        message_format: MessageFormat,
+    },
+
+    /// Prove contracts with an SMT solver
+    Verify {
+        /// Input file
+        #[arg(value_name = "FILE")]
+        input: PathBuf,
+
+        /// Solver binary; defaults to z3 or cvc5 on PATH
+        #[arg(long)]
+        solver: Option<PathBuf>,
+
+        /// Seconds the solver may spend on each clause
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+
+        /// Print the SMT-LIB2 queries instead of solving them
+        #[arg(long)]
+        emit_smt: bool,
     },
 
     /// Format source code
@@ -87,24 +150,85 @@ enum Commands {
     Version,
 }
 
+/// Output of `build`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Target {
+    /// Bytecode for `my-lang run`
+    Bytecode,
+    /// C99 source and header for the system C compiler
+    C,
+    /// A WebAssembly module importing WASI
+    Wasm32,
+    /// An executable for this machine, through Cranelift and the system linker
+    Native,
+}
+
+/// Intermediate forms `build` can write as well
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// The WebAssembly text format, for `--target wasm32`
+    Wat,
+    /// The object file the executable is linked from, for `--target native`
+    Obj,
+    /// The mid-level IR, for any target
+    Ir,
+}
+
+/// The optimization level of `--opt-level`, as C compilers take it
+fn parse_opt_level(level: &str) -> std::result::Result<OptLevel, String> {
+    match level {
+        "0" => Ok(OptLevel::None),
+        "1" | "2" | "3" => Ok(OptLevel::Speed),
+        "s" => Ok(OptLevel::SpeedAndSize),
+        _ => Err(format!("unknown optimization level `{}` (expected 0, 1, 2, 3 or s)", level)),
+    }
+}
+
+/// How `build` compiles
+#[derive(Debug, Clone)]
+struct BuildOptions {
+    optimize: OptLevel,
+    target: Target,
+    emit: Vec<Emit>,
+    contracts: ContractMode,
+}
+
 fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    // Cranelift logs every function it compiles at info
+    tracing_subscriber::fmt().with_max_level(tracing::Level::WARN).init();
 
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Build { input, output, optimize, mode } => {
-            println!("Building {:?} in {} mode", input, mode);
-            println!("Optimize: {}", optimize);
-            build_file(&input, output.as_deref(), optimize, &mode)?;
+        Commands::Build { input, output, optimize, opt_level, target, emit, mode, contracts, message_format } => {
+            let optimize = opt_level.unwrap_or(if optimize { OptLevel::Speed } else { OptLevel::None });
+            if message_format.is_human() {
+                println!("Building {:?} in {} mode", input, mode);
+                println!("Optimize: {}", optimize.setting());
+            }
+            let options = BuildOptions { optimize, target, emit, contracts };
+            if !build_file(&input, output.as_deref(), &options, &mode, message_format)? {
+                std::process::exit(1);
+            }
         }
-        Commands::Run { input, mode, args } => {
-            println!("Running {:?} in {} mode", input, mode);
-            run_file(&input, &mode, &args)?;
+        Commands::Run { input, interpret, mode, contracts, args } => {
+            let code = run_file(&input, interpret, &mode, contracts, &args)?;
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        Commands::Check { input, message_format } => {
+            if message_format.is_human() {
+                println!("Checking {:?}", input);
+            }
+            if !check_file(&input, message_format)? {
+                std::process::exit(1);
+            }
         }
-        Commands::Check { input } => {
-            println!("Checking {:?}", input);
-            check_file(&input)?;
+        Commands::Verify { input, solver, timeout, emit_smt } => {
+            if !verify_file(&input, solver, timeout, emit_smt)? {
+                std::process::exit(1);
+            }
         }
         Commands::Fmt { files, check } => {
             for file in files {
@@ -136,60 +260,357 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn build_file(input: &std::path::Path, output: Option<&std::path::Path>, optimize: bool, mode: &str) -> Result<()> {
-    use std::fs;
+fn build_file(
+    input: &std::path::Path,
+    output: Option<&std::path::Path>,
+    options: &BuildOptions,
+    _mode: &str,
+    format: MessageFormat,
+) -> Result<bool> {
+    // Progress goes to stdout only when it cannot be mistaken for diagnostics
+    let progress = |line: &str| {
+        if format.is_human() {
+            println!("{}", line);
+        }
+    };
+
+    // Lex, parse and check
+    progress("[1/3] Checking...");
+    let mut session = check_session(input)?;
+    // Every phase's diagnostics are written together, so SARIF output is a single log
+    let built = build_session(&mut session, input, output, options, &progress);
+    let built = finish(&session, format) && built?;
+    if built {
+        progress("\n✓ Build successful");
+    }
+    Ok(built)
+}
+
+/// Lower contracts in and generate code for a checked session, returning
+/// whether it was built. Diagnostics are left in the session.
+fn build_session(
+    session: &mut driver::Session,
+    input: &std::path::Path,
+    output: Option<&std::path::Path>,
+    options: &BuildOptions,
+    progress: &impl Fn(&str),
+) -> Result<bool> {
+    // Only one target writes each intermediate form but the IR
+    for emit in &options.emit {
+        let (form, target) = match emit {
+            Emit::Wat => ("wat", Target::Wasm32),
+            Emit::Obj => ("obj", Target::Native),
+            Emit::Ir => continue,
+        };
+        if options.target != target {
+            let name = |target: Target| target.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+            let message =
+                format!("`--emit {}` needs `--target {}`, not `--target {}`", form, name(target), name(options.target));
+            session.diagnostics.push(Diagnostic::error(message).with_code(codes::UNSUPPORTED_BY_TARGET));
+        }
+    }
+    if session.diagnostics.has_errors() || !lower_session(session, options.contracts) {
+        return Ok(false);
+    }
 
-    // Read source
-    let source = fs::read_to_string(input)?;
+    // Only Cranelift optimizes, as it generates native code
+    if options.optimize == OptLevel::None {
+        progress("[2/3] Skipping optimization");
+    } else if options.target == Target::Native {
+        progress(&format!("[2/3] Optimizing for {}", options.optimize.setting()));
+    } else {
+        progress("[2/3] Skipping optimization (only --target native optimizes)");
+    }
+    if options.emit.contains(&Emit::Ir) && !write_ir(session, output.unwrap_or(input), progress)? {
+        return Ok(false);
+    }
 
-    // Lex
-    println!("[1/5] Lexing...");
-    let mut lexer = my_lang_lexer::Lexer::new(&source);
-    let tokens = lexer.tokenize_all();
-    println!("  {} tokens", tokens.len());
+    // Code generation
+    progress("[3/3] Generating code...");
+    match options.target {
+        Target::Bytecode => {
+            let module = compile_session(session);
+            let output_path = output.map_or_else(|| input.with_extension("mbc"), std::path::Path::to_path_buf);
+            std::fs::write(&output_path, bytecode::encode(&module))?;
+            progress(&format!("  Output: {:?}", output_path));
+        }
+        Target::C => {
+            // `out.c` includes `out.h`, so both take the output's name
+            let base = output.unwrap_or(input);
+            let name = base.file_stem().map_or_else(|| "main".to_string(), |stem| stem.to_string_lossy().into_owned());
+            let programs: Vec<&my_lang_ast::Program> = session.modules.iter().map(|m| &m.program).collect();
+            let files: Vec<String> = session.sources.files().map(|file| file.name()).collect();
+            let (program, diagnostics) =
+                my_lang_codegen::c::emit(&programs, &session.resolution, &session.types, &files, &name);
+            session.diagnostics.extend(diagnostics);
+            if session.diagnostics.has_errors() {
+                return Ok(false);
+            }
+            let (source, header) = (base.with_extension("c"), base.with_extension("h"));
+            std::fs::write(&source, program.source)?;
+            std::fs::write(&header, program.header)?;
+            progress(&format!("  Output: {:?}, {:?}", source, header));
+        }
+        Target::Wasm32 => {
+            let base = output.map_or_else(|| input.with_extension("wasm"), std::path::Path::to_path_buf);
+            let programs: Vec<&my_lang_ast::Program> = session.modules.iter().map(|m| &m.program).collect();
+            let files: Vec<String> = session.sources.files().map(|file| file.name()).collect();
+            let (module, diagnostics) =
+                my_lang_codegen::wasm::emit(&programs, &session.resolution, &session.types, &files);
+            session.diagnostics.extend(diagnostics);
+            if session.diagnostics.has_errors() {
+                return Ok(false);
+            }
+            std::fs::write(&base, &module.binary)?;
+            progress(&format!("  Output: {:?}", base));
+            if options.emit.contains(&Emit::Wat) {
+                let text = base.with_extension("wat");
+                std::fs::write(&text, &module.text)?;
+                progress(&format!("  Text: {:?}", text));
+            }
+        }
+        Target::Native => {
+            let executable = match output {
+                Some(output) => output.to_path_buf(),
+                None if input.extension().is_some() => input.with_extension(""),
+                None => input.with_extension("out"),
+            };
+            let programs: Vec<&my_lang_ast::Program> = session.modules.iter().map(|m| &m.program).collect();
+            let files: Vec<String> = session.sources.files().map(|file| file.name()).collect();
+            let (object, diagnostics) = my_lang_codegen::native::emit(
+                &programs,
+                &session.resolution,
+                &session.types,
+                &files,
+                options.optimize,
+            );
+            session.diagnostics.extend(diagnostics);
+            if session.diagnostics.has_errors() {
+                return Ok(false);
+            }
+            let object_path = executable.with_extension("o");
+            std::fs::write(&object_path, &object)?;
+            let linked = my_lang_codegen::native::link(&object_path, &executable);
+            if options.emit.contains(&Emit::Obj) {
+                progress(&format!("  Object: {:?}", object_path));
+            } else {
+                std::fs::remove_file(&object_path)?;
+            }
+            if let Err(e) = linked {
+                session.diagnostics.push(Diagnostic::error(e.to_string()).with_code(codes::LINK_FAILED));
+                return Ok(false);
+            }
+            progress(&format!("  Output: {:?}", executable));
+        }
+    }
+    Ok(true)
+}
 
-    // Parse (stub)
-    println!("[2/5] Parsing...");
-    println!("  Parsing complete");
+/// Lower a checked session to the mid-level IR and write its text beside `base`
+fn write_ir(session: &mut driver::Session, base: &std::path::Path, progress: &impl Fn(&str)) -> Result<bool> {
+    let programs: Vec<&my_lang_ast::Program> = session.modules.iter().map(|m| &m.program).collect();
+    let (module, diagnostics) = my_lang_codegen::ir::lower(&programs, &session.resolution, &session.types);
+    session.diagnostics.extend(diagnostics);
+    if session.diagnostics.has_errors() {
+        return Ok(false);
+    }
+    // Lowering a checked program must give well-formed IR
+    if let Err(errors) = module.verify(&session.resolution, &session.types) {
+        for error in errors {
+            eprintln!("error: internal compiler error: malformed IR {}", error);
+        }
+        return Ok(false);
+    }
+    let path = base.with_extension("ir");
+    std::fs::write(&path, module.display(&session.resolution, &session.types).to_string())?;
+    progress(&format!("  IR: {:?}", path));
+    Ok(true)
+}
 
-    // Type check (stub)
-    println!("[3/5] Type checking...");
-    println!("  Type checking complete");
+/// Run `input`, returning the status the program exits with. Bytecode files
+/// run as they are; sources are checked and compiled first, or interpreted.
+fn run_file(
+    input: &std::path::Path,
+    interpret: bool,
+    mode: &str,
+    contracts: ContractMode,
+    args: &[String],
+) -> Result<i32> {
+    let argv: Vec<String> = std::iter::once(input.display().to_string()).chain(args.iter().cloned()).collect();
+    let (mut out, mut err) = (std::io::stdout(), std::io::stderr());
+    let bytes = std::fs::read(input)?;
+    if bytecode::is_bytecode(&bytes) {
+        let module = match bytecode::decode(&bytes) {
+            Ok(module) => module,
+            Err(e) => {
+                eprintln!("error: cannot load {}: {}", input.display(), e);
+                return Ok(1);
+            }
+        };
+        tracing::debug!(mode, "running bytecode");
+        let result = my_lang_runtime::run_bytecode(&module, &argv, &mut out, &mut err);
+        let describe = |span: Span| match module.files.get(span.file.0 as usize) {
+            Some(name) => format!("{}:{}:{}", name, span.line, span.column),
+            None => span.to_string(),
+        };
+        return Ok(result.unwrap_or_else(|e| report(&e, describe)));
+    }
 
-    // Optimize (stub)
-    if optimize {
-        println!("[4/5] Optimizing...");
-        println!("  Optimization complete");
+    let mut session = check_session(input)?;
+    let lowered = !session.diagnostics.has_errors() && lower_session(&mut session, contracts);
+    if !finish(&session, MessageFormat::Human) || !lowered {
+        return Ok(1);
+    }
+    let result = if interpret {
+        tracing::debug!(mode, "interpreting");
+        let programs: Vec<&my_lang_ast::Program> = session.modules.iter().map(|m| &m.program).collect();
+        my_lang_runtime::run(&programs, &session.resolution, &session.types, &argv, &mut out, &mut err)
     } else {
-        println!("[4/5] Skipping optimization");
+        tracing::debug!(mode, "compiling");
+        my_lang_runtime::run_bytecode(&compile_session(&session), &argv, &mut out, &mut err)
+    };
+    Ok(result.unwrap_or_else(|e| report(&e, |span| session.sources.describe(span))))
+}
+
+/// Bytecode for a checked session, naming its files for error locations
+fn compile_session(session: &driver::Session) -> Module {
+    let programs: Vec<&my_lang_ast::Program> = session.modules.iter().map(|m| &m.program).collect();
+    let mut module = my_lang_codegen::compile(&programs, &session.resolution, &session.types);
+    module.files = session.sources.files().map(|file| file.name()).collect();
+    module
+}
+
+/// Print a run's error as Rust would, returning the status to exit with
+fn report(err: &RuntimeError, describe: impl Fn(Span) -> String) -> i32 {
+    let location = describe(err.span);
+    match &err.kind {
+        RuntimeErrorKind::Panic(message) => {
+            eprintln!("thread 'main' panicked at {}:\n{}", location, message);
+        }
+        RuntimeErrorKind::StackOverflow => {
+            eprintln!("\nthread 'main' has overflowed its stack");
+        }
+        RuntimeErrorKind::Unsupported(message) if err.span.is_dummy() => eprintln!("error: {}", message),
+        RuntimeErrorKind::Unsupported(message) => {
+            eprintln!("error: {}\n  --> {}", message, location);
+        }
+    }
+    for note in &err.notes {
+        eprintln!("  {}", note);
     }
+    if !err.backtrace.is_empty() && !matches!(err.kind, RuntimeErrorKind::StackOverflow) {
+        eprintln!("stack backtrace:");
+        for (i, (function, call)) in err.backtrace.iter().enumerate() {
+            eprintln!("{:>4}: {}\n             at {}", i, function, describe(*call));
+        }
+    }
+    err.exit_code()
+}
 
-    // Code generation (stub)
-    println!("[5/5] Generating code...");
-    let output_path = output.unwrap_or_else(|| std::path::Path::new("output"));
-    println!("  Output: {:?}", output_path);
+/// Check a file and its imported modules, returning whether it is free of errors
+fn check_file(input: &std::path::Path, format: MessageFormat) -> Result<bool> {
+    let ok = finish(&check_session(input)?, format);
+    if ok && format.is_human() {
+        println!("✓ No errors found");
+    }
+    Ok(ok)
+}
 
-    println!("\n✓ Build successful");
-    Ok(())
+/// Prove the contracts in `input`, reporting each clause and whether none was refuted
+fn verify_file(input: &std::path::Path, solver: Option<PathBuf>, timeout: u64, emit_smt: bool) -> Result<bool> {
+    let session = check_session(input)?;
+    if !finish(&session, MessageFormat::Human) {
+        return Ok(false);
+    }
+    let obligations = session.obligations();
+    if emit_smt {
+        for obligation in &obligations {
+            match &obligation.query {
+                Ok(query) => println!("{}", query.script),
+                Err(reason) => {
+                    println!("; {} `{}` of `{}`: {}\n", obligation.kind, obligation.clause, obligation.function, reason)
+                }
+            }
+        }
+        return Ok(true);
+    }
+    let solver = match solver {
+        Some(path) => Solver::new(path, timeout),
+        None => Solver::find(timeout)
+            .ok_or_else(|| anyhow::anyhow!("no SMT solver found on PATH; install z3 or cvc5, or pass --solver"))?,
+    };
+
+    let (mut proven, mut refuted, mut unknown) = (0, 0, 0);
+    for obligation in &obligations {
+        let outcome = match &obligation.query {
+            // A solver that runs but answers nothing usable leaves only this clause open
+            Ok(query) => match solver.check(query) {
+                Err(err @ SolverError::NoVerdict { .. }) => Outcome::Unknown { reason: err.to_string() },
+                outcome => outcome?,
+            },
+            Err(reason) => Outcome::Unknown { reason: reason.clone() },
+        };
+        let mut clause = format!("{} `{}` of `{}`", obligation.kind, obligation.clause, obligation.function);
+        if obligation.kind == ClauseKind::Precondition {
+            clause.push_str(&format!(" at call in `{}`", obligation.caller));
+        }
+        let span = obligation.span;
+        let file = session.sources.get(span.file).map(|f| f.name()).unwrap_or_default();
+        let location = format!("{}:{}:{}", file, span.line, span.column);
+        match outcome {
+            Outcome::Proven => {
+                proven += 1;
+                println!("proven   {} ({})", clause, location);
+            }
+            Outcome::Refuted { counterexample } => {
+                refuted += 1;
+                println!("refuted  {} ({})", clause, location);
+                if !counterexample.is_empty() {
+                    let values: Vec<String> =
+                        counterexample.iter().map(|(name, value)| format!("{} = {}", name, value)).collect();
+                    println!("         counterexample: {}", values.join(", "));
+                }
+            }
+            Outcome::Unknown { reason } => {
+                unknown += 1;
+                println!("unknown  {} ({}): {}", clause, location, reason);
+            }
+        }
+    }
+    println!("\n{} proven, {} refuted, {} unknown", proven, refuted, unknown);
+    Ok(refuted == 0)
 }
 
-fn run_file(input: &std::path::Path, mode: &str, args: &[String]) -> Result<()> {
-    println!("Running program...");
-    println!("Mode: {}", mode);
-    println!("Args: {:?}", args);
-    // Stub: would execute the compiled program
-    Ok(())
+/// Load and check `input`, collecting its diagnostics in the session
+fn check_session(input: &std::path::Path) -> Result<driver::Session> {
+    let mut session = driver::Session::new();
+    session
+        .load(input)
+        .map_err(|err| anyhow::anyhow!("couldn't read {}: {}", input.display(), err))?;
+    session.check();
+    Ok(session)
 }
 
-fn check_file(input: &std::path::Path) -> Result<()> {
-    use std::fs;
+/// Lower contracts as `contracts` selects, reporting whether the lowered program still checks
+fn lower_session(session: &mut driver::Session, contracts: ContractMode) -> bool {
+    session.lower_contracts(contracts);
+    !session.diagnostics.has_errors()
+}
 
-    let source = fs::read_to_string(input)?;
-    let mut lexer = my_lang_lexer::Lexer::new(&source);
-    let _tokens = lexer.tokenize_all();
+/// Write every diagnostic the session collected at once, returning whether none was an error
+fn finish(session: &driver::Session, format: MessageFormat) -> bool {
+    emit::emit(format, session.diagnostics.as_slice(), &session.sources);
+    report_errors(session, format)
+}
 
-    println!("✓ No errors found");
-    Ok(())
+/// Print the closing error count, returning whether there were none
+fn report_errors(session: &driver::Session, format: MessageFormat) -> bool {
+    let errors = session.diagnostics.error_count();
+    if errors > 0 && format.is_human() {
+        let plural = if errors == 1 { "" } else { "s" };
+        eprintln!("error: aborting due to {} previous error{}", errors, plural);
+    }
+    errors == 0
 }
 
 fn start_repl(mode: &str) -> Result<()> {
@@ -0,0 +1,91 @@
+// Command-line behaviour of the `my-lang` binary
+
+use serde_json::Value;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn my_lang(args: &[&str], dir: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_my-lang")).args(args).current_dir(dir).output().expect("my-lang runs")
+}
+
+/// The whole of stdout as one JSON value; trailing documents fail to parse
+fn sarif_log(output: &Output) -> Value {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    serde_json::from_str(&stdout).unwrap_or_else(|e| panic!("not a single JSON value ({}):\n{}", e, stdout))
+}
+
+#[test]
+fn test_build_writes_one_sarif_log() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("ok.solo"), "fn main() { let x = 1; }").unwrap();
+    for target in ["bytecode", "c", "wasm32"] {
+        let output =
+            my_lang(&["build", "ok.solo", "--target", target, "--emit", "ir", "--message-format", "sarif"], dir.path());
+        assert!(output.status.success(), "{}: {}", target, String::from_utf8_lossy(&output.stderr));
+        let log = sarif_log(&output);
+        assert_eq!(log["runs"][0]["results"].as_array().map(Vec::len), Some(0), "{}", target);
+    }
+}
+
+#[test]
+fn test_build_sarif_log_holds_codegen_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = "fn main() { let counts: HashMap<String, i32> = HashMap::new(); }";
+    std::fs::write(dir.path().join("map.solo"), source).unwrap();
+    let output = my_lang(&["build", "map.solo", "--target", "c", "--message-format", "sarif"], dir.path());
+    assert!(!output.status.success());
+    let log = sarif_log(&output);
+    let results = log["runs"][0]["results"].as_array().unwrap();
+    assert!(results.iter().any(|r| r["message"]["text"] == "`HashMap` cannot be compiled to C"), "{:?}", results);
+}
+
+#[test]
+fn test_build_rejects_forms_the_target_does_not_write() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("ok.solo"), "fn main() { let x = 1; }").unwrap();
+    let output =
+        my_lang(&["build", "ok.solo", "--target", "c", "--emit", "wat", "--message-format", "sarif"], dir.path());
+    assert!(!output.status.success());
+    let log = sarif_log(&output);
+    let results = log["runs"][0]["results"].as_array().unwrap();
+    let messages: Vec<&Value> = results.iter().map(|r| &r["message"]["text"]).collect();
+    assert_eq!(messages, ["`--emit wat` needs `--target wasm32`, not `--target c`"]);
+    assert!(!dir.path().join("ok.c").exists());
+}
+
+#[test]
+fn test_build_reports_link_failures_as_diagnostics() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("ok.solo"), "fn main() { let x = 1; }").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_my-lang"))
+        .args(["build", "ok.solo", "--target", "native", "--message-format", "sarif"])
+        .env("CC", "no-such-linker")
+        .current_dir(dir.path())
+        .output()
+        .expect("my-lang runs");
+    assert!(!output.status.success());
+    let log = sarif_log(&output);
+    let results = log["runs"][0]["results"].as_array().unwrap();
+    assert_eq!(results.len(), 1, "{:?}", results);
+    assert_eq!(results[0]["ruleId"], "E0501");
+    assert!(results[0]["message"]["text"].as_str().unwrap().starts_with("cannot run the linker `no-such-linker`"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_verify_reports_solver_without_verdict_as_unknown() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let solver = dir.path().join("z3");
+    std::fs::write(&solver, "#!/bin/sh\ncat > /dev/null\necho '(error \"out of memory\")'\n").unwrap();
+    std::fs::set_permissions(&solver, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let source = "fn half(n: i32) -> i32 post result * 2 <= n { n / 2 }\nfn main() { half(4); }";
+    std::fs::write(dir.path().join("half.solo"), source).unwrap();
+    let output = my_lang(&["verify", "half.solo", "--solver", solver.to_str().unwrap()], dir.path());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("unknown  arithmetic check `n / 2` of `half`"), "{}", stdout);
+    assert!(stdout.contains("unknown  postcondition `result * 2 <= n` of `half`"), "{}", stdout);
+    assert!(stdout.contains("0 proven, 0 refuted, 2 unknown"), "{}", stdout);
+}
@@ -12,7 +12,7 @@ fn main() {
 
     // Should have tokens: fn, main, (, ), {, println, (, "Hello, World!", ), ;, }
     assert!(tokens.len() > 0);
-    assert_eq!(tokens[0].0, Token::Fn);
+    assert_eq!(tokens[0].token, Token::Fn);
 }
 
 #[test]
@@ -21,12 +21,12 @@ fn test_variables() {
     let mut lexer = Lexer::new(source);
     let tokens = lexer.tokenize_all();
 
-    assert_eq!(tokens[0].0, Token::Let);
-    assert_eq!(tokens[1].0, Token::Identifier);
-    assert_eq!(tokens[2].0, Token::Colon);
-    assert_eq!(tokens[3].0, Token::I32);
-    assert_eq!(tokens[4].0, Token::Equal);
-    assert_eq!(tokens[5].0, Token::IntLiteral);
+    assert_eq!(tokens[0].token, Token::Let);
+    assert_eq!(tokens[1].token, Token::Identifier);
+    assert_eq!(tokens[2].token, Token::Colon);
+    assert_eq!(tokens[3].token, Token::I32);
+    assert_eq!(tokens[4].token, Token::Equal);
+    assert_eq!(tokens[5].token, Token::IntLiteral);
 }
 
 #[test]
@@ -35,8 +35,8 @@ fn test_affine_keyword() {
     let mut lexer = Lexer::new(source);
     let tokens = lexer.tokenize_all();
 
-    assert_eq!(tokens[0].0, Token::Affine);
-    assert_eq!(tokens[1].0, Token::Identifier);
+    assert_eq!(tokens[0].token, Token::Affine);
+    assert_eq!(tokens[1].token, Token::Identifier);
 }
 
 #[test]
@@ -45,9 +45,9 @@ fn test_duet_keywords() {
     let mut lexer = Lexer::new(source);
     let tokens = lexer.tokenize_all();
 
-    assert_eq!(tokens[0].0, Token::Intent);
-    assert_eq!(tokens[1].0, Token::Synth);
-    assert_eq!(tokens[2].0, Token::Verify);
+    assert_eq!(tokens[0].token, Token::Intent);
+    assert_eq!(tokens[1].token, Token::Synth);
+    assert_eq!(tokens[2].token, Token::Verify);
 }
 
 #[test]
@@ -56,7 +56,7 @@ fn test_ensemble_keywords() {
     let mut lexer = Lexer::new(source);
     let tokens = lexer.tokenize_all();
 
-    assert_eq!(tokens[0].0, Token::Agent);
-    assert_eq!(tokens[1].0, Token::Workflow);
-    assert_eq!(tokens[2].0, Token::Spawn);
+    assert_eq!(tokens[0].token, Token::Agent);
+    assert_eq!(tokens[1].token, Token::Workflow);
+    assert_eq!(tokens[2].token, Token::Spawn);
 }